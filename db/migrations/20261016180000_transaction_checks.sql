@@ -0,0 +1,6 @@
+-- Backstop for the API's transaction validation. NOT VALID so that rows written before these
+-- constraints existed do not block the migration; new and updated rows are still checked.
+ALTER TABLE "transactions"
+    ADD CONSTRAINT "transactions_quantity_gt_zero" CHECK ("quantity" > 0) NOT VALID,
+    ADD CONSTRAINT "transactions_price_gte_zero" CHECK ("price" >= 0) NOT VALID,
+    ADD CONSTRAINT "transactions_amount_gte_zero" CHECK ("amount" >= 0) NOT VALID;
@@ -0,0 +1,8 @@
+ALTER TABLE "shops" ADD COLUMN "last_activity_at" timestamp(3);
+UPDATE "shops" SET "last_activity_at" = GREATEST(
+    "updated_at",
+    (SELECT MAX("created_at") FROM "transactions" WHERE "transactions"."shop_id" = "shops"."id")
+);
+ALTER TABLE "shops" ALTER COLUMN "last_activity_at" SET DEFAULT now();
+ALTER TABLE "shops" ALTER COLUMN "last_activity_at" SET NOT NULL;
+CREATE INDEX "shops_last_activity_at" ON "shops" ("last_activity_at");
@@ -0,0 +1,12 @@
+CREATE TYPE "merchandise_change_reason" AS ENUM ('transaction', 'restock', 'manual');
+CREATE TABLE "merchandise_changes" (
+    "id" SERIAL PRIMARY KEY NOT NULL,
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "mod_name" VARCHAR(260) NOT NULL,
+    "local_form_id" INTEGER NOT NULL,
+    "quantity_delta" INTEGER NOT NULL,
+    "reason" merchandise_change_reason NOT NULL,
+    "created_at" timestamp(3) NOT NULL
+);
+CREATE INDEX "merchandise_changes_shop_id_and_created_at" ON "merchandise_changes" ("shop_id", "created_at");
+CREATE INDEX "merchandise_changes_created_at" ON "merchandise_changes" ("created_at");
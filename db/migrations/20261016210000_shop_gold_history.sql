@@ -0,0 +1,7 @@
+CREATE TABLE "shop_gold_history" (
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "date" DATE NOT NULL,
+    "gold" INTEGER NOT NULL,
+    "created_at" timestamp(3) NOT NULL,
+    PRIMARY KEY ("shop_id", "date")
+);
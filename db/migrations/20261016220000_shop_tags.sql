@@ -0,0 +1,2 @@
+ALTER TABLE "shops" ADD COLUMN "tags" text[] NOT NULL DEFAULT '{}';
+CREATE INDEX "shops_tags" ON "shops" USING GIN ("tags");
@@ -0,0 +1,8 @@
+CREATE TABLE "shop_notification_settings" (
+    "shop_id" INTEGER PRIMARY KEY REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "on_sale" BOOLEAN NOT NULL,
+    "on_out_of_stock" BOOLEAN NOT NULL,
+    "on_low_stock_threshold" INTEGER CHECK ("on_low_stock_threshold" >= 0),
+    "created_at" timestamp(3) NOT NULL,
+    "updated_at" timestamp(3) NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Descriptions are capped at 4096 characters. Longer ones are cut down to fit, and each shop's
+-- full description is kept in "shop_description_migration_backup" so that nothing is lost and
+-- owners can be told or fixed up by hand.
+CREATE TABLE "shop_description_migration_backup" (
+    "shop_id" INTEGER NOT NULL,
+    "original_description" TEXT NOT NULL,
+    "created_at" timestamp(3) NOT NULL DEFAULT now()
+);
+
+INSERT INTO "shop_description_migration_backup" ("shop_id", "original_description")
+SELECT "id", "description" FROM "shops" WHERE char_length("description") > 4096;
+
+UPDATE "shops" SET "description" = left("description", 4096) WHERE char_length("description") > 4096;
+ALTER TABLE "shops" ADD CONSTRAINT "shops_description_length" CHECK (char_length("description") <= 4096);
@@ -0,0 +1 @@
+ALTER TYPE "merchandise_change_reason" ADD VALUE 'reconcile';
@@ -0,0 +1,5 @@
+ALTER TABLE "owners" ADD COLUMN "display_name" VARCHAR(255);
+ALTER TABLE "owners" ADD COLUMN "bio" TEXT;
+ALTER TABLE "owners" ADD COLUMN "avatar_url" TEXT;
+ALTER TABLE "owners" ADD CONSTRAINT "owners_bio_length" CHECK (char_length("bio") <= 2000);
+ALTER TABLE "owners" ADD CONSTRAINT "owners_avatar_url_length" CHECK (char_length("avatar_url") <= 2048);
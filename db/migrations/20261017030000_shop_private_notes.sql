@@ -0,0 +1,2 @@
+ALTER TABLE "shops" ADD COLUMN "private_notes" TEXT;
+ALTER TABLE "shops" ADD CONSTRAINT "shops_private_notes_length" CHECK (char_length("private_notes") <= 4096);
@@ -0,0 +1,33 @@
+-- Form ids are unsigned 32-bit values, so ids above 0x7FFFFFFF were stored as negative integers.
+-- Widen the columns and undo the sign extension.
+ALTER TABLE "transactions" ALTER COLUMN "local_form_id" TYPE BIGINT
+    USING CASE WHEN "local_form_id" < 0 THEN "local_form_id" + 4294967296 ELSE "local_form_id" END;
+ALTER TABLE "transactions" ADD CONSTRAINT "transactions_local_form_id_range"
+    CHECK ("local_form_id" BETWEEN 0 AND 4294967295);
+ALTER TABLE "merchandise_changes" ALTER COLUMN "local_form_id" TYPE BIGINT
+    USING CASE WHEN "local_form_id" < 0 THEN "local_form_id" + 4294967296 ELSE "local_form_id" END;
+ALTER TABLE "merchandise_changes" ADD CONSTRAINT "merchandise_changes_local_form_id_range"
+    CHECK ("local_form_id" BETWEEN 0 AND 4294967295);
+
+-- Form ids in the JSON lists are now stored in their canonical "0x0005ACE4" form.
+CREATE FUNCTION "canonical_form_id"(item JSONB, field TEXT) RETURNS JSONB AS $$
+    SELECT CASE WHEN jsonb_typeof(item -> field) = 'number' THEN jsonb_set(
+        item,
+        ARRAY[field],
+        to_jsonb('0x' || lpad(upper(to_hex((item ->> field)::bigint)), 8, '0'))
+    ) ELSE item END
+$$ LANGUAGE SQL IMMUTABLE;
+
+UPDATE "merchandise_lists" SET "form_list" = (
+    SELECT COALESCE(jsonb_agg(canonical_form_id(item, 'local_form_id') ORDER BY position), '[]')
+    FROM jsonb_array_elements("form_list") WITH ORDINALITY AS items(item, position)
+);
+UPDATE "interior_ref_lists" SET "ref_list" = (
+    SELECT COALESCE(jsonb_agg(
+        canonical_form_id(canonical_form_id(item, 'base_local_form_id'), 'ref_local_form_id')
+        ORDER BY position
+    ), '[]')
+    FROM jsonb_array_elements("ref_list") WITH ORDINALITY AS items(item, position)
+);
+
+DROP FUNCTION "canonical_form_id"(JSONB, TEXT);
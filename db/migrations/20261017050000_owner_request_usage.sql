@@ -0,0 +1,8 @@
+CREATE TABLE "owner_request_usage" (
+    "owner_id" INTEGER REFERENCES "owners"(id) ON DELETE CASCADE NOT NULL,
+    "period_start" DATE NOT NULL,
+    "reads" BIGINT NOT NULL DEFAULT 0,
+    "writes" BIGINT NOT NULL DEFAULT 0,
+    "updated_at" timestamp(3) NOT NULL,
+    PRIMARY KEY ("owner_id", "period_start")
+);
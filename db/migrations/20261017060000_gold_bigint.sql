@@ -0,0 +1,9 @@
+-- Gold and prices outgrow INTEGER on servers with inflated economies. Widening to BIGINT keeps
+-- every existing value as is. Merchandise prices live in the `form_list` JSONB and need no change.
+ALTER TABLE "shops"
+    ALTER COLUMN "gold" TYPE BIGINT;
+ALTER TABLE "shop_gold_history"
+    ALTER COLUMN "gold" TYPE BIGINT;
+ALTER TABLE "transactions"
+    ALTER COLUMN "price" TYPE BIGINT,
+    ALTER COLUMN "amount" TYPE BIGINT;
@@ -0,0 +1,65 @@
+-- `shop_type` used to be free text, so the same type was stored many ways ("General Store",
+-- "genral_store"). Rows are case-folded the way the API now parses posted types, common variants
+-- are mapped to the known types, and whatever is left becomes "misc". Every changed row's old
+-- value is kept in "shop_type_migration_log" so that owners can be told or fixed up by hand.
+CREATE TABLE "shop_type_migration_log" (
+    "shop_id" INTEGER NOT NULL,
+    "original_shop_type" VARCHAR(255) NOT NULL,
+    "shop_type" VARCHAR(255) NOT NULL,
+    "created_at" timestamp(3) NOT NULL DEFAULT now()
+);
+
+CREATE TEMPORARY TABLE "shop_type_aliases" (
+    "alias" TEXT PRIMARY KEY,
+    "shop_type" TEXT NOT NULL
+);
+INSERT INTO "shop_type_aliases" ("alias", "shop_type") VALUES
+    ('general_store', 'general_store'),
+    ('general', 'general_store'),
+    ('generalstore', 'general_store'),
+    ('genral_store', 'general_store'),
+    ('general_goods', 'general_store'),
+    ('general_goods_store', 'general_store'),
+    ('store', 'general_store'),
+    ('alchemist', 'alchemist'),
+    ('alchemy', 'alchemist'),
+    ('alchemy_shop', 'alchemist'),
+    ('alchemist_shop', 'alchemist'),
+    ('blacksmith', 'blacksmith'),
+    ('smith', 'blacksmith'),
+    ('smithy', 'blacksmith'),
+    ('blacksmithing', 'blacksmith'),
+    ('blacksmith_shop', 'blacksmith'),
+    ('apothecary', 'apothecary'),
+    ('apothecary_shop', 'apothecary'),
+    ('jeweler', 'jeweler'),
+    ('jeweller', 'jeweler'),
+    ('jewelry', 'jeweler'),
+    ('jewellery', 'jeweler'),
+    ('jewelry_store', 'jeweler'),
+    ('misc', 'misc'),
+    ('miscellaneous', 'misc'),
+    ('other', 'misc');
+
+CREATE TEMPORARY TABLE "shop_type_changes" AS
+SELECT
+    "shops"."id" AS "shop_id",
+    "shops"."shop_type" AS "original_shop_type",
+    COALESCE("shop_type_aliases"."shop_type", 'misc') AS "shop_type"
+FROM "shops"
+LEFT JOIN "shop_type_aliases" ON "shop_type_aliases"."alias" =
+    regexp_replace(lower(btrim("shops"."shop_type")), '[ -]', '_', 'g');
+
+INSERT INTO "shop_type_migration_log" ("shop_id", "original_shop_type", "shop_type")
+SELECT "shop_id", "original_shop_type", "shop_type"
+FROM "shop_type_changes"
+WHERE "original_shop_type" <> "shop_type";
+
+UPDATE "shops"
+SET "shop_type" = "shop_type_changes"."shop_type"
+FROM "shop_type_changes"
+WHERE "shops"."id" = "shop_type_changes"."shop_id"
+    AND "shops"."shop_type" <> "shop_type_changes"."shop_type";
+
+DROP TABLE "shop_type_changes";
+DROP TABLE "shop_type_aliases";
@@ -0,0 +1,8 @@
+ALTER TABLE "shops" ADD COLUMN "visits_count" BIGINT NOT NULL DEFAULT 0;
+CREATE TABLE "shop_visits" (
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "owner_id" INTEGER REFERENCES "owners"(id) ON DELETE CASCADE NOT NULL,
+    "day" DATE NOT NULL,
+    PRIMARY KEY ("shop_id", "owner_id", "day")
+);
+CREATE INDEX "shops_visits_count" ON "shops" ("visits_count");
@@ -0,0 +1,7 @@
+CREATE TABLE "shop_bans" (
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "banned_owner_id" INTEGER REFERENCES "owners"(id) ON DELETE CASCADE NOT NULL,
+    "reason" TEXT CHECK (char_length("reason") <= 1000),
+    "created_at" timestamp(3) NOT NULL,
+    PRIMARY KEY ("shop_id", "banned_owner_id")
+);
@@ -0,0 +1,56 @@
+-- Trims merchandise uploaded before item fields were capped: names to 256 characters, mod names
+-- to 128, and keywords to the first 25, each at most 64 characters. Only lists with an oversized
+-- item are rewritten, and each of those lists is kept whole in
+-- "merchandise_list_migration_backup" first so that dropped keywords and cut names can be put
+-- back by hand. Items whose `keywords` isn't an array are left for the API to reject on the next
+-- upload rather than failing the migration.
+CREATE TABLE "merchandise_list_migration_backup" (
+    "merchandise_list_id" INTEGER NOT NULL,
+    "shop_id" INTEGER NOT NULL,
+    "original_form_list" jsonb NOT NULL,
+    "created_at" timestamp(3) NOT NULL DEFAULT now()
+);
+
+INSERT INTO "merchandise_list_migration_backup"
+    ("merchandise_list_id", "shop_id", "original_form_list")
+SELECT "id", "shop_id", "form_list"
+FROM "merchandise_lists"
+WHERE jsonb_typeof("form_list") = 'array'
+    AND EXISTS (
+        SELECT 1
+        FROM jsonb_array_elements("form_list") AS "items"("item")
+        WHERE char_length("item"->>'mod_name') > 128
+            OR char_length("item"->>'name') > 256
+            -- `CASE` (unlike `AND`) is guaranteed to skip the array functions for non-arrays.
+            OR CASE WHEN jsonb_typeof("item"->'keywords') = 'array' THEN
+                jsonb_array_length("item"->'keywords') > 25
+                OR EXISTS (
+                    SELECT 1
+                    FROM jsonb_array_elements_text("item"->'keywords') AS "keywords"("keyword")
+                    WHERE char_length("keyword") > 64
+                )
+            ELSE false END
+    );
+
+UPDATE "merchandise_lists"
+SET "form_list" = (
+    SELECT jsonb_agg(
+        "item"
+            || jsonb_build_object(
+                'mod_name', left("item"->>'mod_name', 128),
+                'name', left("item"->>'name', 256)
+            )
+            || CASE WHEN jsonb_typeof("item"->'keywords') = 'array' THEN
+                jsonb_build_object('keywords', COALESCE((
+                    SELECT jsonb_agg(left("keyword", 64) ORDER BY "keyword_index")
+                    FROM jsonb_array_elements_text("item"->'keywords')
+                        WITH ORDINALITY AS "keywords"("keyword", "keyword_index")
+                    WHERE "keyword_index" <= 25
+                ), '[]'::jsonb))
+            ELSE '{}'::jsonb END
+        ORDER BY "item_index"
+    )
+    FROM jsonb_array_elements("form_list") WITH ORDINALITY AS "items"("item", "item_index")
+)
+FROM "merchandise_list_migration_backup"
+WHERE "merchandise_list_migration_backup"."merchandise_list_id" = "merchandise_lists"."id";
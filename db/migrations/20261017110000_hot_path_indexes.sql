@@ -0,0 +1,11 @@
+-- Composite indexes for the busiest list queries. Keep each one in step with the query it serves.
+--
+-- GET /v1/shops/{id}/transactions: filters by shop_id and pages by created_at (newest first, ties
+-- broken by id). It also covers everything the plain shop_id index did, so that one is dropped.
+CREATE INDEX "transactions_shop_id_and_created_at" ON "transactions" ("shop_id", "created_at" DESC, "id" DESC);
+DROP INDEX "transactions_shop_id";
+-- GET /v1/shops?owner_id=: filters by owner_id, sorted or searched by name. The unique index on
+-- (name, owner_id) leads with name, so it can't serve owner_id alone.
+CREATE INDEX "shops_owner_id_and_name" ON "shops" ("owner_id", "name");
+-- Authentication (api_key) and re-registration (name, api_key) are already served by the
+-- owners_api_key_key and owners_unique_name_and_api_key unique indexes.
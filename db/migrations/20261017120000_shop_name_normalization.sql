@@ -0,0 +1,39 @@
+-- Shop names are unique per owner ignoring case and Unicode normalization, so that "Händlerstube"
+-- typed with a combining umlaut (NFD) and "händlerstube" (NFC) can't both exist, and so that
+-- `GET /v1/shops?name=` matches either. lower() follows the database's LC_CTYPE, which must be a
+-- UTF-8 locale for non-ASCII letters to be case-folded.
+--
+-- Names that would collide are reported instead of failing on the new unique index. Rename all but
+-- one shop in each group and run the migration again.
+DO $$
+DECLARE
+    collisions TEXT;
+BEGIN
+    SELECT string_agg(format('owner %s: shops %s (%s)', "owner_id", "ids", "names"), '; ')
+    INTO collisions
+    FROM (
+        SELECT
+            "owner_id",
+            string_agg("id"::text, ', ' ORDER BY "id") AS "ids",
+            string_agg(quote_literal("name"), ', ' ORDER BY "id") AS "names"
+        FROM "shops"
+        GROUP BY "owner_id", lower(normalize("name", NFC))
+        HAVING count(*) > 1
+    ) AS "colliding";
+    IF collisions IS NOT NULL THEN
+        RAISE EXCEPTION 'shop names collide once case and Unicode normalization are ignored: %',
+            collisions
+            USING HINT = 'Rename all but one shop in each group, then run the migration again.';
+    END IF;
+END
+$$;
+
+-- The API stores NFC from now on.
+UPDATE "shops" SET "name" = normalize("name", NFC) WHERE "name" IS NOT NFC NORMALIZED;
+
+ALTER TABLE "shops"
+    ADD COLUMN "normalized_name" TEXT NOT NULL
+        GENERATED ALWAYS AS (lower(normalize("name", NFC))) STORED;
+DROP INDEX "shops_unique_name_and_owner_id";
+CREATE UNIQUE INDEX "shops_unique_normalized_name_and_owner_id"
+    ON "shops" ("normalized_name", "owner_id");
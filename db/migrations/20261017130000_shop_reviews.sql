@@ -0,0 +1,16 @@
+CREATE TABLE "shop_reviews" (
+    "id" SERIAL PRIMARY KEY NOT NULL,
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "reviewer_owner_id" INTEGER REFERENCES "owners"(id) ON DELETE CASCADE NOT NULL,
+    "rating" SMALLINT NOT NULL CONSTRAINT "shop_reviews_rating_range" CHECK ("rating" BETWEEN 1 AND 5),
+    "comment" TEXT CONSTRAINT "shop_reviews_comment_length" CHECK (char_length("comment") <= 2000),
+    "created_at" timestamp(3) NOT NULL,
+    "updated_at" timestamp(3) NOT NULL,
+    CONSTRAINT "shop_reviews_one_per_reviewer" UNIQUE ("shop_id", "reviewer_owner_id")
+);
+CREATE INDEX "shop_reviews_reviewer_owner_id" ON "shop_reviews" ("reviewer_owner_id");
+
+-- Kept up to date in the same transaction as every review write, so shop reads don't aggregate.
+ALTER TABLE "shops"
+    ADD COLUMN "review_count" BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN "average_rating" DOUBLE PRECISION;
@@ -0,0 +1,17 @@
+CREATE TYPE "shop_request_status" AS ENUM ('open', 'fulfilled', 'declined');
+CREATE TABLE "shop_requests" (
+    "id" SERIAL PRIMARY KEY NOT NULL,
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "requester_owner_id" INTEGER REFERENCES "owners"(id) ON DELETE CASCADE NOT NULL,
+    "mod_name" VARCHAR(260) NOT NULL,
+    "local_form_id" BIGINT NOT NULL
+        CONSTRAINT "shop_requests_local_form_id_range" CHECK ("local_form_id" BETWEEN 0 AND 4294967295),
+    "name" TEXT NOT NULL,
+    "quantity" INTEGER NOT NULL CONSTRAINT "shop_requests_quantity_gt_zero" CHECK ("quantity" > 0),
+    "status" shop_request_status NOT NULL,
+    "fulfillable" BOOLEAN NOT NULL DEFAULT false,
+    "created_at" timestamp(3) NOT NULL,
+    "updated_at" timestamp(3) NOT NULL
+);
+CREATE INDEX "shop_requests_shop_id_and_status" ON "shop_requests" ("shop_id", "status");
+CREATE INDEX "shop_requests_requester_owner_id" ON "shop_requests" ("requester_owner_id");
@@ -0,0 +1,4 @@
+-- Bumped by every write to the list, so clients can send back the revision they last saw as
+-- `expected_revision` and get a 409 instead of overwriting someone else's changes.
+ALTER TABLE "interior_ref_lists" ADD COLUMN "revision" INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE "merchandise_lists" ADD COLUMN "revision" INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,19 @@
+-- `form_type` is now `form_kind` in the current JSON schema. Version 1 payloads are still
+-- translated from and to the old name by the API (see `handlers::SchemaVersion`).
+ALTER TABLE "transactions" RENAME COLUMN "form_type" TO "form_kind";
+
+UPDATE "merchandise_lists" SET "form_list" = (
+    SELECT COALESCE(jsonb_agg(
+        CASE WHEN "item" ? 'form_type'
+            THEN ("item" - 'form_type') || jsonb_build_object('form_kind', "item"->'form_type')
+            ELSE "item"
+        END
+        ORDER BY "item_index"
+    ), '[]'::jsonb)
+    FROM jsonb_array_elements("form_list") WITH ORDINALITY AS "items"("item", "item_index")
+)
+WHERE EXISTS (
+    SELECT 1
+    FROM jsonb_array_elements("form_list") AS "items"("item")
+    WHERE "item" ? 'form_type'
+);
@@ -0,0 +1,9 @@
+CREATE TABLE "shop_translations" (
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "locale" VARCHAR(35) NOT NULL,
+    "name" VARCHAR(255) NOT NULL,
+    "description" TEXT,
+    "created_at" timestamp(3) NOT NULL,
+    "updated_at" timestamp(3) NOT NULL,
+    PRIMARY KEY ("shop_id", "locale")
+);
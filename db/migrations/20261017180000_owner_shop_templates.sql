@@ -0,0 +1,6 @@
+CREATE TABLE "owner_shop_templates" (
+    "owner_id" INTEGER PRIMARY KEY REFERENCES "owners"(id) ON DELETE CASCADE NOT NULL,
+    "template" jsonb NOT NULL,
+    "created_at" timestamp(3) NOT NULL,
+    "updated_at" timestamp(3) NOT NULL
+);
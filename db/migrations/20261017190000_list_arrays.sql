@@ -0,0 +1,11 @@
+-- Lists are always JSON arrays. The columns were already NOT NULL, but nothing stopped a JSON
+-- `null` from being stored, which clients fail to parse. Empty lists are `[]`.
+UPDATE "interior_ref_lists" SET "ref_list" = '[]'::jsonb WHERE "ref_list" = 'null'::jsonb;
+UPDATE "interior_ref_lists" SET "shelves" = '[]'::jsonb WHERE "shelves" = 'null'::jsonb;
+UPDATE "merchandise_lists" SET "form_list" = '[]'::jsonb WHERE "form_list" = 'null'::jsonb;
+
+ALTER TABLE "interior_ref_lists"
+    ADD CONSTRAINT "interior_ref_lists_ref_list_is_array" CHECK (jsonb_typeof("ref_list") = 'array'),
+    ADD CONSTRAINT "interior_ref_lists_shelves_is_array" CHECK (jsonb_typeof("shelves") = 'array');
+ALTER TABLE "merchandise_lists"
+    ADD CONSTRAINT "merchandise_lists_form_list_is_array" CHECK (jsonb_typeof("form_list") = 'array');
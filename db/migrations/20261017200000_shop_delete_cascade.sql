@@ -0,0 +1,19 @@
+-- Deleting a shop takes its lists, vendor and ledger with it, as `GET
+-- /v1/shops/{id}/deletion_preview` warns. These were the only references to "shops" left without
+-- a cascade, so every shop delete failed on its own interior ref and merchandise lists.
+ALTER TABLE "interior_ref_lists"
+    DROP CONSTRAINT "interior_ref_lists_shop_id_fkey",
+    ADD CONSTRAINT "interior_ref_lists_shop_id_fkey"
+        FOREIGN KEY ("shop_id") REFERENCES "shops"(id) ON DELETE CASCADE;
+ALTER TABLE "merchandise_lists"
+    DROP CONSTRAINT "merchandise_lists_shop_id_fkey",
+    ADD CONSTRAINT "merchandise_lists_shop_id_fkey"
+        FOREIGN KEY ("shop_id") REFERENCES "shops"(id) ON DELETE CASCADE;
+ALTER TABLE "vendors"
+    DROP CONSTRAINT "vendors_shop_id_fkey",
+    ADD CONSTRAINT "vendors_shop_id_fkey"
+        FOREIGN KEY ("shop_id") REFERENCES "shops"(id) ON DELETE CASCADE;
+ALTER TABLE "transactions"
+    DROP CONSTRAINT "transactions_shop_id_fkey",
+    ADD CONSTRAINT "transactions_shop_id_fkey"
+        FOREIGN KEY ("shop_id") REFERENCES "shops"(id) ON DELETE CASCADE;
@@ -0,0 +1,26 @@
+-- Every slug a shop has had. The current one is served at `GET /v1/shops/slug/{slug}`, and the
+-- ones its renames left behind redirect to it. A slug belongs to one shop for good, so an old link
+-- never starts pointing at someone else's shop.
+CREATE TABLE "shop_slugs" (
+    "slug" TEXT PRIMARY KEY,
+    "shop_id" INTEGER REFERENCES "shops"(id) ON DELETE CASCADE NOT NULL,
+    "is_current" BOOLEAN NOT NULL,
+    "created_at" timestamp(3) NOT NULL DEFAULT now()
+);
+CREATE UNIQUE INDEX "shop_slugs_current_shop_id" ON "shop_slugs" ("shop_id") WHERE "is_current";
+CREATE INDEX "shop_slugs_shop_id" ON "shop_slugs" ("shop_id");
+
+-- Like `ShopSlug::slugify`. Shops whose names slugify the same keep their id as a suffix.
+WITH "slugged" AS (
+    SELECT "id", COALESCE(NULLIF(trim(both '-' FROM regexp_replace(
+        lower(regexp_replace(normalize("name", NFKD), '[̀-ͯ]', '', 'g')),
+        '[^a-z0-9]+', '-', 'g'
+    )), ''), 'shop') AS "slug"
+    FROM "shops"
+)
+INSERT INTO "shop_slugs" ("slug", "shop_id", "is_current")
+SELECT CASE
+    WHEN row_number() OVER (PARTITION BY "slug" ORDER BY "id") = 1 THEN "slug"
+    ELSE "slug" || '-' || "id"
+END, "id", true
+FROM "slugged";
@@ -0,0 +1,283 @@
+//! Reports data that the schema and the rest of the code should have made impossible, e.g. a
+//! merchandise list that went missing from a shop that still exists.
+//!
+//! Anomalies are found deep in model code that has no `Environment`, so the counters and the
+//! webhook live here rather than on it, like the `notifications` they are modeled on.
+
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_rustls::HttpsConnector;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{error, warn};
+use url::Url;
+
+use crate::models::FormId;
+use crate::tasks::{TaskClass, TaskSpawner};
+
+// Webhook posts of the same code closer together than this are skipped. The counters and logs
+// still see every anomaly.
+const WEBHOOK_INTERVAL_SECS: i64 = 60;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyCode {
+    /// A stored merchandise list has a quantity that doesn't fit in a `u32`, i.e. a negative one,
+    /// despite the `merchandise_quantity_gt_zero` constraint.
+    NegativeQuantity,
+    /// A shop that exists has no merchandise list to apply a transaction to.
+    MissingMerchandiseList,
+    /// A buy named an item that isn't in the shop's merchandise list.
+    BuyOfUnlistedItem,
+    /// Reconciliation found quantities that disagree with the shop's transactions.
+    QuantityDrift,
+}
+
+impl AnomalyCode {
+    pub const ALL: [AnomalyCode; 4] = [
+        AnomalyCode::NegativeQuantity,
+        AnomalyCode::MissingMerchandiseList,
+        AnomalyCode::BuyOfUnlistedItem,
+        AnomalyCode::QuantityDrift,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnomalyCode::NegativeQuantity => "negative_quantity",
+            AnomalyCode::MissingMerchandiseList => "missing_merchandise_list",
+            AnomalyCode::BuyOfUnlistedItem => "buy_of_unlisted_item",
+            AnomalyCode::QuantityDrift => "quantity_drift",
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// One occurrence of an anomaly, as it is logged and posted to the webhook.
+#[derive(Debug, Clone, Serialize)]
+pub struct Anomaly {
+    pub code: AnomalyCode,
+    pub shop_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mod_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_form_id: Option<FormId>,
+    pub detail: String,
+}
+
+impl Anomaly {
+    pub fn new(code: AnomalyCode, shop_id: i32, detail: impl Into<String>) -> Self {
+        Self {
+            code,
+            shop_id,
+            mod_name: None,
+            local_form_id: None,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn item(mut self, mod_name: &str, local_form_id: FormId) -> Self {
+        self.mod_name = Some(mod_name.to_string());
+        self.local_form_id = Some(local_form_id);
+        self
+    }
+}
+
+/// How many times each anomaly was reported since startup, served by `GET /v1/status/metrics`.
+#[derive(Debug, Serialize)]
+pub struct AnomalyCount {
+    pub code: AnomalyCode,
+    pub count: u64,
+}
+
+struct Webhook {
+    uri: Uri,
+    client: Client<HttpsConnector<HttpConnector>>,
+    tasks: TaskSpawner,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NEVER: AtomicI64 = AtomicI64::new(i64::MIN);
+static COUNTS: [AtomicU64; AnomalyCode::ALL.len()] = [ZERO; AnomalyCode::ALL.len()];
+static LAST_POSTED: [AtomicI64; AnomalyCode::ALL.len()] = [NEVER; AnomalyCode::ALL.len()];
+static WEBHOOK: OnceLock<Webhook> = OnceLock::new();
+
+/// Posts every reported anomaly to `url` from now on (at most once a minute per code), as `tasks`
+/// of the `Webhook` class. Slack incoming webhooks show the `text` of the posted JSON; other
+/// receivers can use `anomaly`.
+pub fn set_webhook(url: &Url, tasks: &TaskSpawner) {
+    let uri = match url.as_str().parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(error) => {
+            warn!(%error, "anomaly webhook url is not a valid uri, not posting anomalies");
+            return;
+        }
+    };
+    let client = Client::builder().build(HttpsConnector::new());
+    let tasks = tasks.clone();
+    if WEBHOOK.set(Webhook { uri, client, tasks }).is_err() {
+        warn!("anomaly webhook was already set");
+    }
+}
+
+/// Logs the anomaly at error level, counts it, and posts it to the webhook if one is set. Never
+/// fails, so callers can report and carry on with whatever error or fallback they had before.
+pub fn report(anomaly: Anomaly) {
+    let count = COUNTS[anomaly.code.index()].fetch_add(1, Ordering::Relaxed) + 1;
+    match (&anomaly.mod_name, anomaly.local_form_id) {
+        (Some(mod_name), Some(local_form_id)) => error!(
+            code = anomaly.code.as_str(),
+            shop_id = anomaly.shop_id,
+            mod_name = mod_name.as_str(),
+            local_form_id = %local_form_id,
+            detail = %anomaly.detail,
+            count,
+            "data integrity anomaly"
+        ),
+        _ => error!(
+            code = anomaly.code.as_str(),
+            shop_id = anomaly.shop_id,
+            detail = %anomaly.detail,
+            count,
+            "data integrity anomaly"
+        ),
+    }
+    if let Some(webhook) = WEBHOOK.get() {
+        if claim_webhook_slot(anomaly.code, Utc::now().timestamp()) {
+            webhook
+                .tasks
+                .spawn(TaskClass::Webhook, post(webhook, anomaly));
+        }
+    }
+}
+
+pub fn counts() -> Vec<AnomalyCount> {
+    AnomalyCode::ALL
+        .iter()
+        .map(|&code| AnomalyCount {
+            code,
+            count: count(code),
+        })
+        .collect()
+}
+
+pub fn count(code: AnomalyCode) -> u64 {
+    COUNTS[code.index()].load(Ordering::Relaxed)
+}
+
+fn claim_webhook_slot(code: AnomalyCode, now: i64) -> bool {
+    let last_posted = &LAST_POSTED[code.index()];
+    let previous = last_posted.load(Ordering::Relaxed);
+    previous.saturating_add(WEBHOOK_INTERVAL_SECS) <= now
+        && last_posted
+            .compare_exchange(previous, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+}
+
+async fn post(webhook: &'static Webhook, anomaly: Anomaly) {
+    let body = json!({
+        "text": format!(
+            "Data integrity anomaly `{}` in shop {}: {}",
+            anomaly.code.as_str(),
+            anomaly.shop_id,
+            anomaly.detail
+        ),
+        "anomaly": anomaly,
+    });
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(webhook.uri.clone())
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("valid webhook request");
+    match tokio::time::timeout(WEBHOOK_TIMEOUT, webhook.client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => {}
+        Ok(Ok(response)) => warn!(status = %response.status(), "anomaly webhook rejected post"),
+        Ok(Err(error)) => warn!(%error, "failed to post anomaly to webhook"),
+        Err(_) => warn!("timed out posting anomaly to webhook"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+
+    use super::{claim_webhook_slot, count, report, Anomaly, AnomalyCode};
+    use crate::models::FormId;
+
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Reports the anomaly with a JSON log subscriber and returns the logged line.
+    fn report_and_capture(anomaly: Anomaly) -> Value {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || report(anomaly));
+        let output = captured.0.lock().unwrap().clone();
+        serde_json::from_slice(&output).expect("one JSON log line")
+    }
+
+    // Other tests can report anomalies at the same time, so counts are only checked to go up.
+    #[test]
+    fn reports_are_counted_and_logged_with_their_code() {
+        let before = count(AnomalyCode::MissingMerchandiseList);
+        let line = report_and_capture(Anomaly::new(
+            AnomalyCode::MissingMerchandiseList,
+            7,
+            "shop has no merchandise list",
+        ));
+        assert!(count(AnomalyCode::MissingMerchandiseList) > before);
+        assert_eq!(line["level"], "ERROR");
+        assert_eq!(line["fields"]["message"], "data integrity anomaly");
+        assert_eq!(line["fields"]["code"], "missing_merchandise_list");
+        assert_eq!(line["fields"]["shop_id"], 7);
+
+        let before = count(AnomalyCode::BuyOfUnlistedItem);
+        let line = report_and_capture(
+            Anomaly::new(AnomalyCode::BuyOfUnlistedItem, 8, "not in the list")
+                .item("Skyrim.esm", FormId::from(5)),
+        );
+        assert!(count(AnomalyCode::BuyOfUnlistedItem) > before);
+        assert_eq!(line["fields"]["code"], "buy_of_unlisted_item");
+        assert_eq!(line["fields"]["shop_id"], 8);
+        assert_eq!(line["fields"]["mod_name"], "Skyrim.esm");
+        assert_eq!(line["fields"]["local_form_id"], FormId::from(5).to_string());
+        assert_eq!(line["fields"]["detail"], "not in the list");
+    }
+
+    #[test]
+    fn webhook_posts_are_spaced_out_per_code() {
+        assert!(claim_webhook_slot(AnomalyCode::QuantityDrift, 1_000));
+        assert!(!claim_webhook_slot(AnomalyCode::QuantityDrift, 1_030));
+        assert!(claim_webhook_slot(AnomalyCode::NegativeQuantity, 1_030));
+        assert!(claim_webhook_slot(AnomalyCode::QuantityDrift, 1_060));
+    }
+}
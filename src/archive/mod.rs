@@ -0,0 +1,705 @@
+//! Whole-instance export and import, for moving an instance to a new host without `pg_dump`.
+//!
+//! An archive is newline-delimited JSON: a header line, a line for every row of every table in
+//! `schema::EXPECTED_COLUMNS` (in that order, so rows always come after the rows they reference),
+//! and an end line with each table's row count, which is how an import tells a complete archive
+//! from one cut short. Both directions stream. An export sends rows as Postgres returns them, from
+//! a single snapshot, and an import inserts them in batches that each commit on their own, so
+//! memory use does not grow with the size of the instance.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::mem;
+
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use futures::channel::mpsc::{self, Sender};
+use futures::{SinkExt, Stream, StreamExt, TryStreamExt};
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::postgres::PgPool;
+use sqlx::types::Json;
+use tracing::{error, info, instrument};
+use warp::Buf;
+
+use crate::problem::{import_target_not_empty, invalid_archive};
+use crate::schema::EXPECTED_COLUMNS;
+
+pub const FORMAT_VERSION: u32 = 1;
+pub const CONTENT_TYPE: &str = "application/x-ndjson";
+
+// Exported lines are sent in chunks of about this size, and at most this many chunks wait for a
+// slow client before the export stops reading rows.
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+const EXPORT_BUFFERED_CHUNKS: usize = 16;
+const IMPORT_BATCH_ROWS: usize = 500;
+const IMPORT_BATCH_BYTES: usize = 4 * 1024 * 1024;
+const MAX_LINE_BYTES: usize = 16 * 1024 * 1024;
+
+// Columns that Postgres computes from the others, which can't be inserted.
+const GENERATED_COLUMNS: &[(&str, &str)] = &[("shops", "normalized_name")];
+
+/// One line of an archive.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ArchiveLine {
+    Header {
+        format_version: u32,
+        /// Archives only import into a database migrated to the same version.
+        migration_version: Option<i64>,
+        exported_at: NaiveDateTime,
+    },
+    Row {
+        table: String,
+        row: Map<String, Value>,
+    },
+    End {
+        rows: BTreeMap<String, u64>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportSummary {
+    pub rows: BTreeMap<String, u64>,
+    pub batches: u64,
+}
+
+#[derive(Debug)]
+struct Table {
+    name: &'static str,
+    columns: Vec<&'static str>,
+}
+
+impl Table {
+    fn all() -> Vec<Table> {
+        EXPECTED_COLUMNS
+            .iter()
+            .map(|&(name, columns)| Table {
+                name,
+                columns: columns
+                    .iter()
+                    .copied()
+                    .filter(|&column| !GENERATED_COLUMNS.contains(&(name, column)))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    fn has_serial_id(&self) -> bool {
+        self.columns.first() == Some(&"id")
+    }
+
+    fn export_sql(&self) -> String {
+        format!(
+            "SELECT to_jsonb(r)::text FROM (SELECT {} FROM {}) AS r ORDER BY r.{}",
+            self.columns.join(", "),
+            self.name,
+            self.columns[0]
+        )
+    }
+
+    fn import_sql(&self) -> String {
+        let columns = self.columns.join(", ");
+        format!(
+            "INSERT INTO {table} ({columns}) SELECT {columns} FROM jsonb_populate_recordset(NULL::{table}, $1)",
+            table = self.name,
+            columns = columns
+        )
+    }
+
+    // Rows keep their ids, so the next id handed out has to come after the highest one imported.
+    fn reset_sequence_sql(&self) -> String {
+        format!(
+            "SELECT setval(pg_get_serial_sequence('{table}', 'id'), COALESCE(MAX(id), 0) + 1, false) FROM {table}",
+            table = self.name
+        )
+    }
+}
+
+/// Streams an archive of the whole database. Errors end the stream early, without the end line,
+/// so an import of what was received fails rather than silently leaving rows out.
+pub fn export(db: PgPool, migration_version: Option<i64>) -> impl Stream<Item = Result<Bytes>> {
+    let (mut sender, receiver) = mpsc::channel(EXPORT_BUFFERED_CHUNKS);
+    tokio::spawn(async move {
+        if let Err(error) = write_export(&db, migration_version, &mut sender).await {
+            error!(%error, "export failed");
+            let _ = sender.send(Err(error)).await;
+        }
+    });
+    receiver
+}
+
+async fn write_export(
+    db: &PgPool,
+    migration_version: Option<i64>,
+    sender: &mut Sender<Result<Bytes>>,
+) -> Result<()> {
+    let mut tx = db.begin().await?;
+    // Every table is read from the same snapshot, so rows written during the export can't
+    // reference rows of a table that was already exported.
+    sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY")
+        .execute(&mut *tx)
+        .await?;
+    let mut chunk = Vec::with_capacity(EXPORT_CHUNK_BYTES);
+    write_line(
+        &mut chunk,
+        &ArchiveLine::Header {
+            format_version: FORMAT_VERSION,
+            migration_version,
+            exported_at: Utc::now().naive_utc(),
+        },
+    )?;
+    let mut counts = BTreeMap::new();
+    for table in Table::all() {
+        let sql = table.export_sql();
+        let mut rows = sqlx::query_scalar::<_, String>(&sql).fetch(&mut *tx);
+        let mut count = 0;
+        while let Some(row) = rows.try_next().await? {
+            writeln!(
+                chunk,
+                r#"{{"type":"row","table":"{}","row":{}}}"#,
+                table.name, row
+            )?;
+            count += 1;
+            if chunk.len() >= EXPORT_CHUNK_BYTES {
+                send_chunk(sender, &mut chunk).await?;
+            }
+        }
+        info!(table = table.name, rows = count, "exported table");
+        counts.insert(table.name.to_string(), count);
+    }
+    write_line(&mut chunk, &ArchiveLine::End { rows: counts })?;
+    send_chunk(sender, &mut chunk).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+fn write_line(chunk: &mut Vec<u8>, line: &ArchiveLine) -> Result<()> {
+    serde_json::to_writer(&mut *chunk, line)?;
+    chunk.push(b'\n');
+    Ok(())
+}
+
+async fn send_chunk(sender: &mut Sender<Result<Bytes>>, chunk: &mut Vec<u8>) -> Result<()> {
+    let chunk = mem::replace(chunk, Vec::with_capacity(EXPORT_CHUNK_BYTES));
+    sender
+        .send(Ok(Bytes::from(chunk)))
+        .await
+        .map_err(|_| anyhow!("the client stopped reading the export"))
+}
+
+// Splits a request body into lines without holding more than one line (and a chunk) at a time.
+struct Lines<S> {
+    body: S,
+    buffer: Vec<u8>,
+    // How far into `buffer` has been searched for a newline.
+    searched: usize,
+    number: usize,
+    // The length of the last line read.
+    length: usize,
+    ended: bool,
+}
+
+impl<S, B> Lines<S>
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
+    fn new(body: S) -> Self {
+        Self {
+            body,
+            buffer: vec![],
+            searched: 0,
+            number: 0,
+            length: 0,
+            ended: false,
+        }
+    }
+
+    // Blank lines are skipped.
+    async fn read_line(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(newline) = self.buffer[self.searched..]
+                .iter()
+                .position(|&b| b == b'\n')
+            {
+                let mut line: Vec<u8> = self.buffer.drain(..=self.searched + newline).collect();
+                self.searched = 0;
+                self.number += 1;
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                if line.is_empty() {
+                    continue;
+                }
+                self.length = line.len();
+                return Ok(Some(line));
+            }
+            self.searched = self.buffer.len();
+            if self.buffer.len() > MAX_LINE_BYTES {
+                return Err(invalid_archive(
+                    self.number + 1,
+                    &format!("lines cannot be longer than {} bytes", MAX_LINE_BYTES),
+                ));
+            }
+            if self.ended {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                self.number += 1;
+                self.searched = 0;
+                self.length = self.buffer.len();
+                return Ok(Some(mem::take(&mut self.buffer)));
+            }
+            match self.body.next().await {
+                Some(chunk) => {
+                    let mut chunk = chunk.map_err(|error| anyhow!(error))?;
+                    while chunk.has_remaining() {
+                        let len = chunk.bytes().len();
+                        self.buffer.extend_from_slice(chunk.bytes());
+                        chunk.advance(len);
+                    }
+                }
+                None => self.ended = true,
+            }
+        }
+    }
+
+    async fn read_archive_line(&mut self) -> Result<Option<ArchiveLine>> {
+        match self.read_line().await? {
+            Some(line) => serde_json::from_slice(&line)
+                .map(Some)
+                .map_err(|error| invalid_archive(self.number, &error.to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+// Rows waiting to be inserted into one table.
+struct Batch<'a> {
+    table: &'a Table,
+    rows: Vec<Value>,
+    bytes: usize,
+}
+
+/// Imports an archive made by `export`. Unless `replace` is set the database has to be empty;
+/// with it, every archived table is emptied first. Every row is checked against its table's
+/// columns before it is inserted, but batches that were inserted before a bad line is reached
+/// stay inserted, so a failed import should be retried with `replace`.
+#[instrument(level = "debug", skip(db, body))]
+pub async fn import<S, B>(
+    db: &PgPool,
+    body: S,
+    migration_version: Option<i64>,
+    replace: bool,
+) -> Result<ImportSummary>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    futures::pin_mut!(body);
+    let mut lines = Lines::new(body);
+    match lines.read_archive_line().await? {
+        Some(ArchiveLine::Header {
+            format_version,
+            migration_version: archive_migration_version,
+            ..
+        }) => {
+            if format_version != FORMAT_VERSION {
+                return Err(invalid_archive(
+                    lines.number,
+                    &format!(
+                        "format version {} is not supported, only {} is",
+                        format_version, FORMAT_VERSION
+                    ),
+                ));
+            }
+            if archive_migration_version != migration_version {
+                return Err(invalid_archive(
+                    lines.number,
+                    &format!(
+                        "the archive was exported at migration version {:?} but this database is at {:?}. Export and import with the same version of the server",
+                        archive_migration_version, migration_version
+                    ),
+                ));
+            }
+        }
+        Some(_) => return Err(invalid_archive(lines.number, "expected the header line")),
+        None => return Err(invalid_archive(1, "the archive is empty")),
+    }
+
+    let tables = Table::all();
+    prepare_target(db, &tables, replace).await?;
+
+    let mut counts: BTreeMap<String, u64> = BTreeMap::new();
+    let mut batches = 0;
+    let mut batch: Option<Batch> = None;
+    let mut table_index = 0;
+    loop {
+        let line = match lines.read_archive_line().await? {
+            Some(line) => line,
+            None => {
+                return Err(invalid_archive(
+                    lines.number,
+                    "the archive ends without its end line, it may have been cut short",
+                ))
+            }
+        };
+        let (table, row) = match line {
+            ArchiveLine::Row { table, row } => (table, row),
+            ArchiveLine::End { rows } => {
+                if let Some(batch) = batch.take() {
+                    insert_batch(db, batch, &mut counts, lines.number).await?;
+                    batches += 1;
+                }
+                check_counts(&tables, &rows, &counts, lines.number)?;
+                if lines.read_line().await?.is_some() {
+                    return Err(invalid_archive(
+                        lines.number,
+                        "nothing can come after the end line",
+                    ));
+                }
+                break;
+            }
+            ArchiveLine::Header { .. } => {
+                return Err(invalid_archive(
+                    lines.number,
+                    "unexpected second header line",
+                ))
+            }
+        };
+        let index = match tables.iter().position(|known| known.name == table) {
+            Some(index) => index,
+            None => {
+                return Err(invalid_archive(
+                    lines.number,
+                    &format!("unknown table \"{}\"", table),
+                ))
+            }
+        };
+        if index < table_index {
+            return Err(invalid_archive(
+                lines.number,
+                &format!(
+                    "rows of {} have to come before rows of {}",
+                    table, tables[table_index].name
+                ),
+            ));
+        }
+        table_index = index;
+        check_columns(&tables[index], &row, lines.number)?;
+
+        let full = batch.as_ref().is_some_and(|batch| {
+            batch.table.name != table
+                || batch.rows.len() >= IMPORT_BATCH_ROWS
+                || batch.bytes >= IMPORT_BATCH_BYTES
+        });
+        if full {
+            if let Some(batch) = batch.take() {
+                insert_batch(db, batch, &mut counts, lines.number).await?;
+                batches += 1;
+            }
+        }
+        let batch = batch.get_or_insert_with(|| Batch {
+            table: &tables[index],
+            rows: Vec::with_capacity(IMPORT_BATCH_ROWS),
+            bytes: 0,
+        });
+        batch.bytes += lines.length;
+        batch.rows.push(Value::Object(row));
+    }
+
+    let mut tx = db.begin().await?;
+    for table in tables.iter().filter(|table| table.has_serial_id()) {
+        sqlx::query(&table.reset_sequence_sql())
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    info!(rows = ?counts, batches, "import finished");
+    Ok(ImportSummary {
+        rows: counts,
+        batches,
+    })
+}
+
+async fn prepare_target(db: &PgPool, tables: &[Table], replace: bool) -> Result<()> {
+    if replace {
+        let names: Vec<&str> = tables.iter().map(|table| table.name).collect();
+        sqlx::query(&format!(
+            "TRUNCATE {} RESTART IDENTITY CASCADE",
+            names.join(", ")
+        ))
+        .execute(db)
+        .await?;
+        info!("emptied the database for the import");
+        return Ok(());
+    }
+    let mut not_empty = vec![];
+    for table in tables {
+        let has_rows: bool =
+            sqlx::query_scalar(&format!("SELECT EXISTS (SELECT 1 FROM {})", table.name))
+                .fetch_one(db)
+                .await?;
+        if has_rows {
+            not_empty.push(table.name);
+        }
+    }
+    if !not_empty.is_empty() {
+        return Err(import_target_not_empty(&not_empty));
+    }
+    Ok(())
+}
+
+// Rows have to have exactly the table's columns: a missing one would silently become NULL (or the
+// column's default) and an unknown one would be dropped.
+fn check_columns(table: &Table, row: &Map<String, Value>, line: usize) -> Result<()> {
+    let expected: BTreeSet<&str> = table.columns.iter().copied().collect();
+    let actual: BTreeSet<&str> = row.keys().map(String::as_str).collect();
+    if let Some(missing) = expected.difference(&actual).next() {
+        return Err(invalid_archive(
+            line,
+            &format!("{} row is missing the column \"{}\"", table.name, missing),
+        ));
+    }
+    if let Some(unknown) = actual.difference(&expected).next() {
+        return Err(invalid_archive(
+            line,
+            &format!("{} row has the unknown column \"{}\"", table.name, unknown),
+        ));
+    }
+    Ok(())
+}
+
+fn check_counts(
+    tables: &[Table],
+    expected: &BTreeMap<String, u64>,
+    imported: &BTreeMap<String, u64>,
+    line: usize,
+) -> Result<()> {
+    if let Some(unknown) = expected
+        .keys()
+        .find(|name| !tables.iter().any(|table| table.name == name.as_str()))
+    {
+        return Err(invalid_archive(
+            line,
+            &format!("the end line counts the unknown table \"{}\"", unknown),
+        ));
+    }
+    for table in tables {
+        let expected = expected.get(table.name).copied().unwrap_or(0);
+        let imported = imported.get(table.name).copied().unwrap_or(0);
+        if expected != imported {
+            return Err(invalid_archive(
+                line,
+                &format!(
+                    "the archive should have {} rows of {} but has {}",
+                    expected, table.name, imported
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn insert_batch(
+    db: &PgPool,
+    batch: Batch<'_>,
+    counts: &mut BTreeMap<String, u64>,
+    line: usize,
+) -> Result<()> {
+    let mut tx = db.begin().await?;
+    let result = sqlx::query(&batch.table.import_sql())
+        .bind(Json(&batch.rows))
+        .execute(&mut *tx)
+        .await;
+    match result {
+        Ok(_) => {}
+        Err(sqlx::Error::Database(error)) => {
+            return Err(invalid_archive(
+                line,
+                &format!(
+                    "{} rows before this line could not be inserted: {}",
+                    batch.table.name, error
+                ),
+            ))
+        }
+        Err(error) => return Err(error.into()),
+    }
+    tx.commit().await?;
+    let count = counts.entry(batch.table.name.to_string()).or_default();
+    *count += batch.rows.len() as u64;
+    info!(table = batch.table.name, rows = *count, "imported batch");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use crate::test_support::{
+        assert_problem, json_body, json_request, request, TestEnv, OTHER_OWNER_API_KEY,
+        OWNER_API_KEY,
+    };
+
+    const ADMIN_API_KEY: &str = "33333333-3333-3333-3333-333333333333";
+
+    async fn admin_test_env() -> Option<TestEnv> {
+        TestEnv::with_config(&[("ADMIN_API_KEYS", ADMIN_API_KEY)]).await
+    }
+
+    fn import(archive: &[u8], query: &str) -> warp::test::RequestBuilder {
+        request(
+            "POST",
+            &format!("/v1/admin/import{}", query),
+            Some(ADMIN_API_KEY),
+        )
+        .header("content-type", super::CONTENT_TYPE)
+        .body(archive)
+    }
+
+    #[tokio::test]
+    async fn archives_round_trip_into_an_empty_database() {
+        let source = match admin_test_env().await {
+            Some(test) => test,
+            None => return,
+        };
+        let target = admin_test_env().await.unwrap();
+        source.create_owner(OWNER_API_KEY, "Owner").await;
+        source.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = source
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let response = source
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "form_list": [{
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "Cabbage",
+                    "quantity": 3,
+                    "form_type": 46,
+                    "is_food": true,
+                    "price": 4,
+                    "keywords": [],
+                }] }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = source
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 6,
+                    "name": "Leek",
+                    "form_type": 46,
+                    "is_food": true,
+                    "price": 100,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 100,
+                    "keywords": [],
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        let response = source
+            .send(request("GET", "/v1/admin/export", Some(OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = source
+            .send(request("GET", "/v1/admin/export", Some(ADMIN_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(response.headers()["content-type"], super::CONTENT_TYPE);
+        let archive = response.body().clone();
+
+        let response = target.send(import(&archive, "")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let rows = json_body(&response)["rows"].clone();
+        assert_eq!(rows["owners"], 2);
+        assert_eq!(rows["shops"], 1);
+        assert_eq!(rows["merchandise_lists"], 1);
+        assert_eq!(rows["interior_ref_lists"], 1);
+        assert_eq!(rows["transactions"], 1);
+
+        for path in &[
+            format!("/v1/shops/{}", shop_id),
+            format!("/v1/shops/{}/merchandise_list", shop_id),
+        ] {
+            let before = json_body(&source.send(request("GET", path, None)).await);
+            let after = json_body(&target.send(request("GET", path, None)).await);
+            assert_eq!(before, after, "{}", path);
+        }
+        // Api keys carry over, and new rows get fresh ids.
+        let shop = json!({ "name": "Second Shop" });
+        let second_shop_id = target.create_shop(OWNER_API_KEY, &shop).await;
+        assert!(second_shop_id > shop_id);
+
+        let response = target.send(import(&archive, "")).await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "import_target_not_empty");
+        let response = target.send(import(&archive, "?merge=replace")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = target
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}", second_shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn archives_that_were_cut_short_or_tampered_with_are_refused() {
+        let source = match admin_test_env().await {
+            Some(test) => test,
+            None => return,
+        };
+        let target = admin_test_env().await.unwrap();
+        source.create_owner(OWNER_API_KEY, "Owner").await;
+        source
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let response = source
+            .send(request("GET", "/v1/admin/export", Some(ADMIN_API_KEY)))
+            .await;
+        let archive = String::from_utf8(response.body().to_vec()).unwrap();
+        let lines: Vec<&str> = archive.lines().collect();
+
+        let cut_short = lines[..lines.len() - 1].join("\n");
+        let response = target
+            .send(import(cut_short.as_bytes(), "?merge=replace"))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["code"], "invalid_archive");
+
+        let mut row: Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(row["table"], "owners");
+        row["row"]["is_admin"] = json!(true);
+        let mut tampered = lines.clone();
+        let tampered_row = row.to_string();
+        tampered[1] = tampered_row.as_str();
+        let response = target
+            .send(import(tampered.join("\n").as_bytes(), "?merge=replace"))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["line"], 2);
+
+        let response = target
+            .send(import(archive.as_bytes(), "?merge=everything"))
+            .await;
+        assert_problem(&response, StatusCode::BAD_REQUEST);
+    }
+}
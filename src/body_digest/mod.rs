@@ -0,0 +1,170 @@
+//! Optional digests of request and response bodies.
+//!
+//! A body cut short in transit can still be valid JSON (an array truncated at an element
+//! boundary), so clients may send a digest of the bytes they meant to send, either as an RFC 9530
+//! `Content-Digest` (`sha-256=:<base64>:`) or as `X-Body-SHA256: <hex>`. The body is hashed
+//! before it is deserialized and rejected if it does not match, so the client can retry.
+//! Data responses carry `X-Body-SHA256` of their body before any compression.
+
+use anyhow::Result;
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::problem::{body_digest_mismatch, invalid_body_digest, unsupported_digest_algorithm};
+
+pub const CONTENT_DIGEST: &str = "content-digest";
+pub const BODY_SHA256: &str = "x-body-sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn from_token(token: &str) -> Option<Self> {
+        match token {
+            "sha-256" => Some(Algorithm::Sha256),
+            "sha-512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+
+    fn digest(self, bytes: &[u8]) -> Vec<u8> {
+        match self {
+            Algorithm::Sha256 => Sha256::digest(bytes).to_vec(),
+            Algorithm::Sha512 => Sha512::digest(bytes).to_vec(),
+        }
+    }
+}
+
+/// The hex-encoded SHA-256 of `bytes`, as sent in `X-Body-SHA256`.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Checks `body` against the digests in the `Content-Digest` and `X-Body-SHA256` headers. Bodies
+/// sent without either header are accepted as is.
+pub fn verify(body: &[u8], content_digest: Option<&str>, body_sha256: Option<&str>) -> Result<()> {
+    let mut expected = vec![];
+    if let Some(content_digest) = content_digest {
+        expected.extend(parse_content_digest(content_digest)?);
+    }
+    if let Some(body_sha256) = body_sha256 {
+        let digest = hex::decode(body_sha256.trim())
+            .ok()
+            .filter(|digest| digest.len() == 32)
+            .ok_or_else(|| invalid_body_digest("X-Body-SHA256 must be 64 hex characters"))?;
+        expected.push((Algorithm::Sha256, digest));
+    }
+    for (algorithm, digest) in expected {
+        if algorithm.digest(body) != digest {
+            return Err(body_digest_mismatch());
+        }
+    }
+    Ok(())
+}
+
+// Parses the dictionary of `algorithm=:base64:` members. Members with algorithms we don't
+// implement are skipped as RFC 9530 allows, but at least one must be one we do, or the client
+// would believe its body was checked when it wasn't.
+fn parse_content_digest(header: &str) -> Result<Vec<(Algorithm, Vec<u8>)>> {
+    let mut digests = vec![];
+    let mut unsupported = vec![];
+    for member in header.split(',') {
+        let (token, value) = match member.split_once('=') {
+            Some((token, value)) => (token.trim().to_ascii_lowercase(), value.trim()),
+            None => {
+                return Err(invalid_body_digest(
+                    "Content-Digest members must be algorithm=:base64:",
+                ))
+            }
+        };
+        let digest = value
+            .strip_prefix(':')
+            .and_then(|value| value.strip_suffix(':'))
+            .and_then(|value| base64::decode(value).ok())
+            .ok_or_else(|| {
+                invalid_body_digest("Content-Digest values must be base64 between colons")
+            })?;
+        match Algorithm::from_token(&token) {
+            Some(algorithm) => digests.push((algorithm, digest)),
+            None => unsupported.push(token),
+        }
+    }
+    if digests.is_empty() {
+        return Err(unsupported_digest_algorithm(&unsupported));
+    }
+    Ok(digests)
+}
+
+#[cfg(test)]
+mod tests {
+    use http_api_problem::HttpApiProblem;
+
+    use super::{sha256_hex, verify};
+
+    const BODY: &[u8] = b"{\"name\":\"Test Shop\"}";
+
+    fn code(error: anyhow::Error) -> serde_json::Value {
+        let problem = error.downcast::<HttpApiProblem>().unwrap();
+        serde_json::to_value(problem).unwrap()["code"].clone()
+    }
+
+    fn content_digest(bytes: &[u8]) -> String {
+        format!(
+            "sha-256=:{}:",
+            base64::encode(hex::decode(sha256_hex(bytes)).unwrap())
+        )
+    }
+
+    #[test]
+    fn accepts_missing_and_matching_digests() {
+        verify(BODY, None, None).unwrap();
+        verify(BODY, Some(&content_digest(BODY)), None).unwrap();
+        verify(BODY, None, Some(&sha256_hex(BODY))).unwrap();
+        verify(BODY, None, Some(&sha256_hex(BODY).to_uppercase())).unwrap();
+        verify(
+            BODY,
+            Some(&format!("unixsum=:AAAA:, {}", content_digest(BODY))),
+            Some(&sha256_hex(BODY)),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_body() {
+        let truncated = &BODY[..BODY.len() - 1];
+        assert_eq!(
+            code(verify(truncated, Some(&content_digest(BODY)), None).unwrap_err()),
+            "body_digest_mismatch"
+        );
+        assert_eq!(
+            code(verify(truncated, None, Some(&sha256_hex(BODY))).unwrap_err()),
+            "body_digest_mismatch"
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_algorithms() {
+        assert_eq!(
+            code(verify(BODY, Some("md5=:AAAA:"), None).unwrap_err()),
+            "unsupported_digest_algorithm"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_digests() {
+        for header in &["sha-256", "sha-256=abc", "sha-256=:not base64:"] {
+            assert_eq!(
+                code(verify(BODY, Some(header), None).unwrap_err()),
+                "invalid_body_digest",
+                "{}",
+                header
+            );
+        }
+        assert_eq!(
+            code(verify(BODY, None, Some("abc")).unwrap_err()),
+            "invalid_body_digest"
+        );
+    }
+}
@@ -0,0 +1,63 @@
+//! Checks that request bodies are exactly as long as they claim to be.
+//!
+//! A body cut short by a crashing client can still parse as valid, shorter JSON, so the bytes
+//! actually received are counted against the declared `Content-Length` before anything is
+//! deserialized. Bodies sent without a length (chunked) are read up to `MAX_BODY_BYTES`, except for
+//! bincode bodies, which have no closing bracket to catch truncation and so must declare their
+//! length unless `BINCODE_REQUIRE_CONTENT_LENGTH` is off.
+
+use anyhow::{anyhow, Result};
+use futures::{Stream, StreamExt};
+use hyper::body::Bytes;
+use warp::Buf;
+
+use crate::problem::{body_length_mismatch, content_length_required, payload_too_large};
+
+pub const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Checks the declared length of a body before reading it. `declared` is `None` when the body is
+/// chunked.
+pub fn check_declared(
+    declared: Option<u64>,
+    is_bincode: bool,
+    bincode_require_content_length: bool,
+) -> Result<()> {
+    match declared {
+        Some(length) if length > MAX_BODY_BYTES => Err(payload_too_large(MAX_BODY_BYTES)),
+        None if is_bincode && bincode_require_content_length => Err(content_length_required()),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the whole body, failing as soon as it is longer than declared (or than `MAX_BODY_BYTES`
+/// if it wasn't declared), or once it ends short of the declared length.
+pub async fn read<S, B>(body: S, declared: Option<u64>) -> Result<Bytes>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    futures::pin_mut!(body);
+    let mut bytes = Vec::with_capacity(declared.unwrap_or(0) as usize);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|error| anyhow!(error))?;
+        while chunk.has_remaining() {
+            let len = chunk.bytes().len();
+            bytes.extend_from_slice(chunk.bytes());
+            chunk.advance(len);
+        }
+        let received = bytes.len() as u64;
+        match declared {
+            Some(declared) if received > declared => {
+                return Err(body_length_mismatch(declared, received))
+            }
+            None if received > MAX_BODY_BYTES => return Err(payload_too_large(MAX_BODY_BYTES)),
+            _ => {}
+        }
+    }
+    if let Some(declared) = declared {
+        if (bytes.len() as u64) < declared {
+            return Err(body_length_mismatch(declared, bytes.len() as u64));
+        }
+    }
+    Ok(Bytes::from(bytes))
+}
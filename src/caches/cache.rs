@@ -1,99 +1,363 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::future::FutureExt;
+use http::header::ETAG;
+use http::{HeaderValue, StatusCode};
 use lru::LruCache;
-use std::fmt::Debug;
+use serde::Serialize;
+use std::any::Any;
 use std::future::Future;
 use std::hash::Hash;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
-use tracing::debug;
+use tracing::{debug, trace};
 use warp::{Rejection, Reply};
 
-use super::CachedResponse;
+use super::sized_lru::{Footprint, SizedLru, Weigh};
+use super::{CachedResponse, Redact};
 use crate::problem::{reject_anyhow, unpack_problem};
+use crate::tasks::{TaskClass, TaskSpawner};
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Cache<K, V>
 where
-    K: Eq + Hash + Debug,
+    K: Eq + Hash + Redact,
     V: Clone,
 {
     pub name: String,
-    pub lru_mutex: Arc<Mutex<LruCache<K, V>>>,
-    pub log_keys: bool,
+    pub lru_mutex: Arc<Mutex<SizedLru<K, V>>>,
+    pub capacity: usize,
+    pub log_hits: bool,
+    pub ttl: Option<Duration>,
+    etag_index: Option<Arc<Mutex<LruCache<K, IndexedETag>>>>,
+    etag_index_capacity: Option<usize>,
+    stats: Arc<Counters>,
+    footprint: Arc<Footprint>,
+    generation: Arc<AtomicU64>,
+    tasks: TaskSpawner,
+}
+
+/// The ETag of a response that was cached for a key, kept after the response itself is evicted.
+#[derive(Debug, Clone)]
+struct IndexedETag {
+    etag: HeaderValue,
+    cached_at: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// How a cache is configured, as shown by `GET /v1/admin/config`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CacheSettings {
+    pub name: String,
+    pub capacity: usize,
+    pub ttl_secs: Option<u64>,
+    pub etag_index_capacity: Option<usize>,
+}
+
+/// Lookups and writes since the server started, and what the cache holds now. Expired entries
+/// count as misses. `bytes` approximates the memory taken by the cached values (see `Weigh`), not
+/// counting the keys or the ETag index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub inserts: u64,
+    /// Entries dropped because the cache was full. A cache that evicts about as often as it
+    /// inserts is too small for what is read from it.
+    pub evictions: u64,
+    pub entries: u64,
+    pub capacity: usize,
+    pub bytes: u64,
+}
+
+// Not derived so that keys don't need to be `Clone`. Clones share the same underlying LRU.
+impl<K, V> Clone for Cache<K, V>
+where
+    K: Eq + Hash + Redact,
+    V: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            lru_mutex: self.lru_mutex.clone(),
+            capacity: self.capacity,
+            log_hits: self.log_hits,
+            ttl: self.ttl,
+            etag_index: self.etag_index.clone(),
+            etag_index_capacity: self.etag_index_capacity,
+            stats: self.stats.clone(),
+            footprint: self.footprint.clone(),
+            generation: self.generation.clone(),
+            tasks: self.tasks.clone(),
+        }
+    }
 }
 
 impl<K, V> Cache<K, V>
 where
-    K: Eq + Hash + Debug + Send,
-    V: Clone + Send,
+    K: Eq + Hash + Redact + Send + 'static,
+    V: Clone + Weigh + Send + 'static,
 {
     pub fn new(name: &str, capacity: usize) -> Self {
+        let lru = SizedLru::new(capacity);
         Cache {
             name: name.to_string(),
-            lru_mutex: Arc::new(Mutex::new(LruCache::new(capacity))),
-            log_keys: true,
+            footprint: lru.footprint(),
+            lru_mutex: Arc::new(Mutex::new(lru)),
+            capacity,
+            log_hits: false,
+            ttl: None,
+            etag_index: None,
+            etag_index_capacity: None,
+            stats: Arc::new(Counters::default()),
+            generation: Arc::new(AtomicU64::new(0)),
+            tasks: TaskSpawner::default(),
         }
     }
 
-    pub fn log_keys(mut self, value: bool) -> Self {
-        self.log_keys = value;
+    /// Fills the cache after a miss with `tasks`, so the fills are capped and counted with the
+    /// server's other background tasks. Caches made without it get their own `TaskSpawner`.
+    pub fn tasks(mut self, tasks: &TaskSpawner) -> Self {
+        self.tasks = tasks.clone();
         self
     }
 
-    pub fn log_with_key(&self, key: &K, message: &str) {
-        if self.log_keys {
-            debug!(cache = %self.name, key = ?key, message);
+    /// Logs every hit at trace level. Off by default since hits are by far the most common event
+    /// and would drown out everything else under load.
+    pub fn log_hits(mut self, value: bool) -> Self {
+        self.log_hits = value;
+        self
+    }
+
+    /// Treats entries as misses once they were cached `ttl` ago, so that an entry a missed
+    /// invalidation left stale is still reloaded eventually. Without it entries stay until evicted.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// Keeps the ETags of up to `capacity` cached responses, so that a conditional GET can be
+    /// answered with a 304 from `indexed_etag` even after the response was evicted. Entries are
+    /// removed along with their responses, and expire with them too.
+    pub fn etag_index(mut self, capacity: usize) -> Self {
+        self.etag_index = Some(Arc::new(Mutex::new(LruCache::new(capacity))));
+        self.etag_index_capacity = Some(capacity);
+        self
+    }
+
+    pub fn settings(&self) -> CacheSettings {
+        CacheSettings {
+            name: self.name.clone(),
+            capacity: self.capacity,
+            ttl_secs: self.ttl.map(|ttl| ttl.as_secs()),
+            etag_index_capacity: self.etag_index_capacity,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.stats.hits.load(Ordering::Relaxed),
+            misses: self.stats.misses.load(Ordering::Relaxed),
+            inserts: self.footprint.inserts(),
+            evictions: self.footprint.evictions(),
+            entries: self.footprint.entries(),
+            capacity: self.capacity,
+            bytes: self.footprint.bytes(),
+        }
+    }
+
+    fn count(&self, hit: bool) {
+        let counter = if hit {
+            &self.stats.hits
         } else {
-            debug!(cache = %self.name, message);
+            &self.stats.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Called with the LRU locked by everything that removes or replaces entries, so that a fill
+    /// holding the generation from before its getter ran can tell that it may have loaded what was
+    /// just evicted (see `is_stale_fill`).
+    fn bump_generation(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Whether the cache changed since `generation` was read before a miss ran its getter. Such a
+    /// fill is dropped rather than risk caching what a write evicted while the getter was still
+    /// reading it. This is checked across the whole cache rather than per key, so an unrelated
+    /// eviction costs at most one extra miss.
+    fn is_stale_fill(&self, key: &K, generation: u64) -> bool {
+        let stale = self.generation.load(Ordering::SeqCst) != generation;
+        if stale {
+            self.log_with_key(key, "fill raced an invalidation, not caching");
         }
+        stale
     }
 
-    pub async fn get<G, F>(&'static self, key: K, getter: G) -> Result<V>
+    pub fn log_with_key(&self, key: &K, message: &str) {
+        debug!(cache = %self.name, key = %key.redacted(), message);
+    }
+
+    fn log_hit(&self, key: &K, message: &str) {
+        if self.log_hits {
+            trace!(cache = %self.name, key = %key.redacted(), message);
+        }
+    }
+
+    pub async fn get<G, F>(&self, key: K, getter: G) -> Result<V>
     where
         G: Fn() -> F,
         F: Future<Output = Result<V>>,
     {
         let mut guard = self.lru_mutex.lock().await;
-        if let Some(value) = guard.get(&key) {
-            self.log_with_key(&key, "get: hit");
+        if guard.pop_expired(&key, self.ttl) {
+            self.log_with_key(&key, "get: expired");
+        } else if let Some(value) = guard.get(&key) {
+            self.log_hit(&key, "get: hit");
+            self.count(true);
             return Ok(value.clone());
         }
         drop(guard);
+        self.count(false);
 
         self.log_with_key(&key, "get: miss");
-        let value = getter().await?;
+        let generation = self.generation.load(Ordering::SeqCst);
+        let value = self.run_getter(getter).await?;
 
         let to_cache = value.clone();
-        tokio::spawn(async move {
-            let mut guard = self.lru_mutex.lock().await;
-            self.log_with_key(&key, "get: update cache");
+        let cache = self.clone();
+        self.tasks.spawn(TaskClass::CacheFill, async move {
+            let mut guard = cache.lru_mutex.lock().await;
+            if cache.is_stale_fill(&key, generation) {
+                return;
+            }
+            cache.log_with_key(&key, "get: update cache");
             guard.put(key, to_cache);
         });
 
         Ok(value)
     }
 
+    /// Runs `getter`, turning a panic into an error so that it becomes a 500 problem with an error
+    /// id like any other unhandled error. Nothing is cached for the key, so the next request for it
+    /// runs the getter again.
+    async fn run_getter<G, F, T>(&self, getter: G) -> Result<T>
+    where
+        G: Fn() -> F,
+        F: Future<Output = Result<T>>,
+    {
+        match AssertUnwindSafe(async { getter().await })
+            .catch_unwind()
+            .await
+        {
+            Ok(result) => result,
+            Err(payload) => Err(anyhow!(
+                "getter for cache {} panicked: {}",
+                self.name,
+                panic_message(payload.as_ref())
+            )),
+        }
+    }
+
+    /// Caches `value` before returning, for when the caller already has the value the getter
+    /// would load, e.g. the owner id of an api key that was just registered.
+    pub async fn insert(&self, key: K, value: V) {
+        let mut guard = self.lru_mutex.lock().await;
+        self.bump_generation();
+        self.forget_etag(&key).await;
+        self.log_with_key(&key, "insert");
+        guard.put(key, value);
+    }
+
     pub async fn delete(&self, key: K) -> Option<V> {
         let mut guard = self.lru_mutex.lock().await;
+        self.bump_generation();
         let value = guard.pop(&key);
+        self.forget_etag(&key).await;
         self.log_with_key(&key, "delete");
 
         value
     }
 
+    /// Removes every entry whose key matches, e.g. all pages cached for one shop in a cache keyed
+    /// by `(shop_id, TransactionListQuery)`.
+    pub async fn delete_where<P>(&self, predicate: P) -> usize
+    where
+        K: Clone,
+        P: Fn(&K) -> bool,
+    {
+        let mut guard = self.lru_mutex.lock().await;
+        self.bump_generation();
+        let keys: Vec<K> = guard
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| predicate(key))
+            .cloned()
+            .collect();
+        for key in &keys {
+            guard.pop(key);
+        }
+        if let Some(etag_index) = &self.etag_index {
+            let mut etag_index = etag_index.lock().await;
+            let indexed: Vec<K> = etag_index
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| predicate(key))
+                .cloned()
+                .collect();
+            for key in &indexed {
+                etag_index.pop(key);
+            }
+        }
+        debug!(cache = %self.name, deleted = keys.len(), "delete_where");
+        keys.len()
+    }
+
+    async fn forget_etag(&self, key: &K) {
+        if let Some(etag_index) = &self.etag_index {
+            etag_index.lock().await.pop(key);
+        }
+    }
+
     pub async fn clear(&self) {
         let mut guard = self.lru_mutex.lock().await;
+        self.bump_generation();
         guard.clear();
+        if let Some(etag_index) = &self.etag_index {
+            etag_index.lock().await.clear();
+        }
         debug!(cache = %self.name, "cache clear");
     }
 }
 
 impl<K> Cache<K, CachedResponse>
 where
-    K: Eq + Hash + Debug + Send,
+    K: Eq + Hash + Redact + Clone + Send + 'static,
 {
+    /// The ETag of the last response cached for `key`, if it is still current.
+    pub async fn indexed_etag(&self, key: &K) -> Option<HeaderValue> {
+        let mut etag_index = self.etag_index.as_ref()?.lock().await;
+        let indexed = etag_index.get(key)?;
+        if self
+            .ttl
+            .is_some_and(|ttl| indexed.cached_at.elapsed() >= ttl)
+        {
+            etag_index.pop(key);
+            return None;
+        }
+        self.log_hit(key, "indexed_etag: hit");
+        Some(indexed.etag.clone())
+    }
+
     pub async fn get_response<G, F, R>(
-        &'static self,
+        &self,
         key: K,
         getter: G,
     ) -> Result<CachedResponse, Rejection>
@@ -103,23 +367,33 @@ where
         R: Reply,
     {
         let mut guard = self.lru_mutex.lock().await;
-        if let Some(value) = guard.get(&key) {
-            self.log_with_key(&key, "get_response: hit");
+        if guard.pop_expired(&key, self.ttl) {
+            self.log_with_key(&key, "get_response: expired");
+        } else if let Some(value) = guard.get(&key) {
+            self.log_hit(&key, "get_response: hit");
+            self.count(true);
             return Ok(value.clone());
         }
         drop(guard);
+        self.count(false);
 
         self.log_with_key(&key, "get_response: miss");
-        let reply = getter().await.map_err(reject_anyhow);
+        let generation = self.generation.load(Ordering::SeqCst);
+        let reply = self.run_getter(getter).await.map_err(reject_anyhow);
         Ok(match reply {
             Ok(reply) => {
                 let cached_response = CachedResponse::from_reply(reply)
                     .await
                     .map_err(reject_anyhow)?;
                 let to_cache = cached_response.clone();
-                tokio::spawn(async move {
-                    let mut guard = self.lru_mutex.lock().await;
-                    self.log_with_key(&key, "get_response: update cache");
+                let cache = self.clone();
+                self.tasks.spawn(TaskClass::CacheFill, async move {
+                    let mut guard = cache.lru_mutex.lock().await;
+                    if cache.is_stale_fill(&key, generation) {
+                        return;
+                    }
+                    cache.log_with_key(&key, "get_response: update cache");
+                    cache.index_etag(key.clone(), &to_cache).await;
                     guard.put(key, to_cache);
                 });
                 cached_response
@@ -134,11 +408,269 @@ where
         })
     }
 
+    /// Caches a response rendered ahead of the first request for it, as `get_response` would
+    /// have after a miss.
+    pub async fn put_response(&self, key: K, response: CachedResponse) {
+        let mut guard = self.lru_mutex.lock().await;
+        self.bump_generation();
+        self.log_with_key(&key, "put_response");
+        self.index_etag(key.clone(), &response).await;
+        guard.put(key, response);
+    }
+
     pub async fn delete_response(&self, key: K) -> Option<CachedResponse> {
         let mut guard = self.lru_mutex.lock().await;
+        self.bump_generation();
         let cached_response = guard.pop(&key);
+        self.forget_etag(&key).await;
         self.log_with_key(&key, "delete_response");
 
         cached_response
     }
+
+    async fn index_etag(&self, key: K, response: &CachedResponse) {
+        if let (Some(etag_index), StatusCode::OK, Some(etag)) = (
+            &self.etag_index,
+            response.status,
+            response.headers.get(ETAG),
+        ) {
+            etag_index.lock().await.put(
+                key,
+                IndexedETag {
+                    etag: etag.clone(),
+                    cached_at: response.cached_at,
+                },
+            );
+        }
+    }
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "non-string panic payload"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use http::StatusCode;
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing::Level;
+    use uuid::Uuid;
+
+    use super::{Cache, CacheStats, CachedResponse};
+
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn panicking_getter_responds_with_problem_and_is_not_cached() {
+        let cache: Cache<i32, CachedResponse> = Cache::new("test", 10);
+        let response = cache
+            .get_response(1, || async {
+                let value: serde_json::Value = serde_json::from_str("{").unwrap();
+                Ok::<_, anyhow::Error>(warp::reply::json(&value))
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers["content-type"],
+            http_api_problem::PROBLEM_JSON_MEDIA_TYPE
+        );
+        let problem: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(problem["status"], 500);
+        assert!(problem["error_id"].is_string());
+        // The panic message may contain internals, so it is only logged.
+        assert!(!String::from_utf8_lossy(&response.body).contains("unwrap"));
+
+        let response = cache
+            .get_response(1, || async { Ok(warp::reply::json(&"recovered")) })
+            .await
+            .unwrap();
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(&response.body[..], b"\"recovered\"");
+    }
+
+    #[tokio::test]
+    async fn fills_that_raced_an_eviction_are_dropped() {
+        let cache: Cache<i32, CachedResponse> = Cache::new("test", 10);
+        let (evicted_tx, evicted_rx) = tokio::sync::oneshot::channel::<()>();
+        let evicted_rx = Mutex::new(Some(evicted_rx));
+        let evicting = cache.clone();
+        // The write lands and evicts the key while the getter is still reading the old data.
+        let getter = || async {
+            let evicted_rx = evicted_rx.lock().unwrap().take().unwrap();
+            evicted_rx.await.unwrap();
+            Ok(warp::reply::json(&"old"))
+        };
+        let (response, ()) = tokio::join!(cache.get_response(1, getter), async {
+            evicting.delete_response(1).await;
+            evicted_tx.send(()).unwrap();
+        });
+        assert_eq!(&response.unwrap().body[..], b"\"old\"");
+        // Give the spawned fill the time it would need to cache the old response.
+        tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+        assert!(cache.lru_mutex.lock().await.is_empty());
+
+        // A fill that started after the eviction is cached as usual.
+        cache
+            .get_response(1, || async { Ok(warp::reply::json(&"new")) })
+            .await
+            .unwrap();
+        while cache.lru_mutex.lock().await.is_empty() {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        let cached = cache
+            .get_response(1, || async { Ok(warp::reply::json(&"newer")) })
+            .await
+            .unwrap();
+        assert_eq!(&cached.body[..], b"\"new\"");
+    }
+
+    #[tokio::test]
+    async fn delete_where_only_removes_matching_keys() {
+        let cache: Cache<(i32, i32), i32> = Cache::new("test", 10);
+        for key in &[(1, 1), (1, 2), (2, 1)] {
+            cache.lru_mutex.lock().await.put(*key, key.0 + key.1);
+        }
+        assert_eq!(cache.delete_where(|(shop_id, _)| *shop_id == 1).await, 2);
+        let guard = cache.lru_mutex.lock().await;
+        assert_eq!(guard.len(), 1);
+        assert!(guard.contains(&(2, 1)));
+    }
+
+    #[tokio::test]
+    async fn counts_hits_and_misses() {
+        let cache: Cache<i32, i32> = Cache::new("test", 10);
+        cache.lru_mutex.lock().await.put(1, 1);
+        cache.get(1, || async { Ok(1) }).await.unwrap();
+        cache.get(2, || async { Ok(2) }).await.unwrap();
+        // `get` caches the missed value from a spawned task.
+        while cache.lru_mutex.lock().await.len() < 2 {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                inserts: 2,
+                evictions: 0,
+                entries: 2,
+                capacity: 10,
+                bytes: 8,
+            }
+        );
+        assert_eq!(cache.clone().stats(), cache.stats());
+    }
+
+    #[tokio::test]
+    async fn expired_entries_run_the_getter_again() {
+        let ttl = std::time::Duration::from_millis(50);
+        let values: Cache<i32, i32> = Cache::new("test", 10).ttl(ttl);
+        let responses: Cache<i32, CachedResponse> = Cache::new("test_responses", 10).ttl(ttl);
+        let response = |body: &'static str| async move { Ok(warp::reply::json(&body)) };
+        assert_eq!(values.get(1, || async { Ok(1) }).await.unwrap(), 1);
+        responses.get_response(1, || response("old")).await.unwrap();
+        // Both caches are filled from spawned tasks.
+        while values.lru_mutex.lock().await.is_empty()
+            || responses.lru_mutex.lock().await.is_empty()
+        {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+        assert_eq!(values.get(1, || async { Ok(2) }).await.unwrap(), 1);
+        let cached = responses.get_response(1, || response("new")).await.unwrap();
+        assert_eq!(&cached.body[..], b"\"old\"");
+
+        tokio::time::delay_for(ttl).await;
+        assert_eq!(values.get(1, || async { Ok(2) }).await.unwrap(), 2);
+        let reloaded = responses.get_response(1, || response("new")).await.unwrap();
+        assert_eq!(&reloaded.body[..], b"\"new\"");
+        assert_eq!(values.stats().misses, 2);
+        assert_eq!(responses.stats().misses, 2);
+    }
+
+    #[tokio::test]
+    async fn panicking_getter_returns_error_from_get() {
+        let cache: Cache<i32, i32> = Cache::new("test", 10);
+        let result: Result<i32> = cache.get(1, || async { panic!("getter exploded") }).await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("getter for cache test panicked: getter exploded"));
+        assert_eq!(cache.get(1, || async { Ok(2) }).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn api_keys_are_never_logged() {
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(Level::TRACE)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let api_key = Uuid::new_v4();
+        let cache: Cache<Uuid, i32> = Cache::new("test", 10).log_hits(true);
+        cache.lru_mutex.lock().await.put(api_key, 1);
+        cache.get(api_key, || async { Ok(1) }).await.unwrap();
+        cache.delete(api_key).await;
+        cache.get(api_key, || async { Ok(1) }).await.unwrap();
+        let composite: Cache<(i32, Uuid), i32> = Cache::new("composite", 10);
+        composite
+            .get((7, api_key), || async { Ok(1) })
+            .await
+            .unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(logs.contains("get: hit"), "{}", logs);
+        assert!(logs.contains("(7, <redacted>)"), "{}", logs);
+        assert!(!logs.contains(&api_key.to_string()), "{}", logs);
+        assert!(!logs.contains(&api_key.to_simple().to_string()), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn etag_index_outlives_evicted_responses() {
+        let cache: Cache<i32, CachedResponse> = Cache::new("test", 1).etag_index(10);
+        let response = |body: &'static str| async move {
+            Ok(warp::reply::with_header(
+                warp::reply::json(&body),
+                "etag",
+                body,
+            ))
+        };
+        for (key, body) in &[(1, "one"), (2, "two")] {
+            cache.get_response(*key, || response(body)).await.unwrap();
+            // `get_response` updates the cache from a spawned task.
+            while cache.indexed_etag(key).await.is_none() {
+                tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            }
+        }
+        assert!(!cache.lru_mutex.lock().await.contains(&1));
+        assert_eq!(cache.indexed_etag(&1).await.unwrap(), "one");
+
+        cache.delete_response(1).await;
+        assert!(cache.indexed_etag(&1).await.is_none());
+        cache.clear().await;
+        assert!(cache.indexed_etag(&2).await.is_none());
+    }
 }
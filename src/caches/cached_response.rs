@@ -2,6 +2,7 @@ use anyhow::Result;
 use http::header::ETAG;
 use http::{HeaderMap, HeaderValue, Response, StatusCode, Version};
 use hyper::body::{to_bytes, Body, Bytes};
+use std::time::Instant;
 use warp::Reply;
 
 #[derive(Debug, Clone)]
@@ -10,6 +11,7 @@ pub struct CachedResponse {
     pub version: Version,
     pub headers: HeaderMap<HeaderValue>,
     pub body: Bytes,
+    pub cached_at: Instant,
 }
 
 impl CachedResponse {
@@ -23,6 +25,7 @@ impl CachedResponse {
             version: response.version(),
             headers: response.headers().clone(),
             body: to_bytes(response.body_mut()).await?,
+            cached_at: Instant::now(),
         })
     }
 
@@ -34,6 +37,7 @@ impl CachedResponse {
             version: Version::HTTP_11,
             headers,
             body: Bytes::new(),
+            cached_at: Instant::now(),
         }
     }
 }
@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use http_api_problem::HttpApiProblem;
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Mutex;
+use tracing::debug;
+
+use crate::models::{OwnerShopListQuery, Shop, ShopListQuery, Transaction, TransactionListQuery};
+use crate::problem::from_anyhow;
+
+type SharedQuery<V> = Shared<BoxFuture<'static, Result<V, HttpApiProblem>>>;
+
+/// Runs at most one query per key at a time. Identical requests that arrive while a query is
+/// running await its result instead of running their own.
+///
+/// Unlike the response caches, nothing is kept once the query finishes, so this also covers the
+/// moment right after a mutation cleared a cache, when many clients tend to re-request the same
+/// list at once.
+pub struct InFlight<K, V> {
+    name: &'static str,
+    queries: Mutex<HashMap<K, SharedQuery<V>>>,
+}
+
+impl<K, V> Debug for InFlight<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InFlight")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+// Removes the leader's entry when its query finishes or its request is dropped, so a cancelled
+// request never leaves behind an entry that later requests would join.
+struct Leader<'a, K: Eq + Hash, V> {
+    in_flight: &'a InFlight<K, V>,
+    key: K,
+}
+
+impl<'a, K: Eq + Hash, V> Drop for Leader<'a, K, V> {
+    fn drop(&mut self) {
+        self.in_flight
+            .queries
+            .lock()
+            .expect("in-flight lock poisoned")
+            .remove(&self.key);
+    }
+}
+
+impl<K, V> InFlight<K, V>
+where
+    K: Eq + Hash + Clone + Debug,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            queries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F>(&self, key: K, query: F) -> Result<V>
+    where
+        F: Future<Output = Result<V>> + Send + 'static,
+    {
+        let (shared, _leader) = {
+            let mut queries = self.queries.lock().expect("in-flight lock poisoned");
+            match queries.get(&key) {
+                Some(shared) => {
+                    debug!(in_flight = self.name, key = ?key, "joining in-flight query");
+                    (shared.clone(), None)
+                }
+                None => {
+                    let shared = async move { query.await.map_err(from_anyhow) }
+                        .boxed()
+                        .shared();
+                    queries.insert(key.clone(), shared.clone());
+                    (
+                        shared,
+                        Some(Leader {
+                            in_flight: self,
+                            key,
+                        }),
+                    )
+                }
+            }
+        };
+        shared.await.map_err(|problem| anyhow!(problem))
+    }
+}
+
+/// Queries that identical concurrent requests share, layered under the response caches.
+#[derive(Debug)]
+pub struct InFlightQueries {
+    pub list_shops: InFlight<ShopListQuery, (Vec<Shop>, i64)>,
+    pub list_shops_by_owner_id: InFlight<(i32, OwnerShopListQuery), (Vec<Shop>, i64)>,
+    pub list_transactions_by_shop_id:
+        InFlight<(i32, TransactionListQuery), (Vec<Transaction>, i64)>,
+}
+
+impl Default for InFlightQueries {
+    fn default() -> Self {
+        Self {
+            list_shops: InFlight::new("list_shops"),
+            list_shops_by_owner_id: InFlight::new("list_shops_by_owner_id"),
+            list_transactions_by_shop_id: InFlight::new("list_transactions_by_shop_id"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future::join_all;
+    use http::StatusCode;
+    use sqlx::postgres::PgPool;
+
+    use super::InFlight;
+    use crate::problem::{from_anyhow, not_found};
+    use crate::test_support::TestEnv;
+
+    // Wraps the pool to count how many queries actually reach Postgres.
+    #[derive(Clone)]
+    struct Probe {
+        db: PgPool,
+        executed: Arc<AtomicUsize>,
+    }
+
+    impl Probe {
+        async fn count_shops(self) -> anyhow::Result<i64> {
+            self.executed.fetch_add(1, Ordering::SeqCst);
+            // Long enough that every request below arrives while the query is running.
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+            Ok(sqlx::query_scalar("SELECT COUNT(*) FROM shops")
+                .fetch_one(&self.db)
+                .await?)
+        }
+
+        fn executed(&self) -> usize {
+            self.executed.load(Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_concurrent_queries_run_once() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let probe = Probe {
+            db: test.env.db.clone(),
+            executed: Arc::new(AtomicUsize::new(0)),
+        };
+        let in_flight = InFlight::<i32, i64>::new("test");
+
+        let results =
+            join_all((0..200).map(|_| in_flight.run(1, probe.clone().count_shops()))).await;
+        assert!(results.iter().all(|result| matches!(result, Ok(0))));
+        assert_eq!(probe.executed(), 1);
+
+        // Each distinct key runs its own query.
+        let results =
+            join_all((0..200).map(|i| in_flight.run(i % 2, probe.clone().count_shops()))).await;
+        assert!(results.iter().all(|result| result.is_ok()));
+        assert_eq!(probe.executed(), 3);
+
+        // Nothing is kept once the query finishes.
+        in_flight.run(1, probe.clone().count_shops()).await.unwrap();
+        assert_eq!(probe.executed(), 4);
+    }
+
+    #[tokio::test]
+    async fn errors_are_shared_and_cancelled_leaders_are_not_joined() {
+        let in_flight = InFlight::<i32, i64>::new("test");
+        let executed = Arc::new(AtomicUsize::new(0));
+        let failing = || {
+            let executed = executed.clone();
+            async move {
+                executed.fetch_add(1, Ordering::SeqCst);
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+                Err(not_found("Shop not found"))
+            }
+        };
+        let results = join_all((0..10).map(|_| in_flight.run(1, failing()))).await;
+        assert_eq!(executed.load(Ordering::SeqCst), 1);
+        for result in results {
+            let problem = from_anyhow(result.unwrap_err());
+            assert_eq!(problem.status, Some(StatusCode::NOT_FOUND));
+        }
+
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(10), in_flight.run(2, failing())).await;
+        assert!(cancelled.is_err());
+        in_flight.run(2, async { Ok(1) }).await.unwrap();
+        assert_eq!(executed.load(Ordering::SeqCst), 2);
+    }
+}
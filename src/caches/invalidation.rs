@@ -0,0 +1,787 @@
+use anyhow::Result;
+use futures::future::{BoxFuture, FutureExt};
+use lru::LruCache;
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::mem;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tracing::{debug, error, warn};
+use warp::Reply;
+
+use super::{Cache, CachedResponse, Caches, ViewScope};
+
+// Warmed responses bigger than this are left for the next reader to render, so that one huge
+// list can't push many small entries out of a cache.
+const MAX_WARMED_BODY_BYTES: usize = 1024 * 1024;
+// How many (cache, key) pairs the worker remembers the last warmed revision of.
+const WARMED_REVISIONS_CAPACITY: usize = 1000;
+
+/// A write, and the ids it changed. `Caches::invalidate` evicts the entries keyed by those ids
+/// before returning, so that the writer reading back what it wrote never gets the old version.
+/// Clearing the list caches that might show what was written is left to the invalidation worker,
+/// since those are only eventually consistent anyway and don't depend on the ids.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvalidationEvent {
+    OwnerSaved {
+        owner_id: i32,
+    },
+    /// The owner's shops, and the reviews and requests they made, were deleted with them. Their
+    /// api key is evicted by the handler, so that it never shows up in a logged event.
+    OwnerDeleted {
+        owner_id: i32,
+    },
+    ShopCreated {
+        owner_id: i32,
+    },
+    /// `previous_owner_id` is who the shop belonged to before the write, which differs from
+    /// `owner_id` when the shop was given away.
+    ShopUpdated {
+        shop_id: i32,
+        owner_id: i32,
+        previous_owner_id: i32,
+    },
+    /// A review of the shop was written or deleted, changing its `review_count` and
+    /// `average_rating`.
+    ShopReviewed {
+        shop_id: i32,
+    },
+    ShopTranslated {
+        shop_id: i32,
+    },
+    /// Only the owner's views of the shop show its notification settings.
+    ShopNotificationSettingsSaved {
+        shop_id: i32,
+        owner_id: i32,
+    },
+    ShopDeleted {
+        shop_id: i32,
+        owner_id: i32,
+    },
+    InteriorRefListSaved {
+        list_id: i32,
+        shop_id: i32,
+        owner_id: i32,
+    },
+    InteriorRefListDeleted {
+        list_id: i32,
+        shop_id: i32,
+        owner_id: i32,
+    },
+    MerchandiseListSaved {
+        list_id: i32,
+        shop_id: i32,
+        owner_id: i32,
+    },
+    MerchandiseListDeleted {
+        list_id: i32,
+        shop_id: i32,
+        owner_id: i32,
+    },
+    TransactionCreated {
+        merchandise_list_id: i32,
+        shop_id: i32,
+        owner_id: i32,
+    },
+    /// Only the transaction itself is gone: its effect on the merchandise and gold is not undone.
+    TransactionDeleted {
+        transaction_id: i32,
+        shop_id: i32,
+    },
+    MerchandiseReconciled {
+        shop_id: i32,
+    },
+}
+
+impl InvalidationEvent {
+    // Applied by the handler itself, before it responds.
+    async fn evict(self, caches: &Caches) {
+        match self {
+            InvalidationEvent::OwnerSaved { owner_id } => {
+                caches.evict_owner(owner_id).await;
+                caches.owner_profile.delete_response(owner_id).await;
+                caches.owner_profile_bin.delete_response(owner_id).await;
+            }
+            InvalidationEvent::OwnerDeleted { owner_id } => {
+                caches.evict_owner(owner_id).await;
+                caches.owner_profile.delete_response(owner_id).await;
+                caches.owner_profile_bin.delete_response(owner_id).await;
+                // Their requests were deleted from whichever shops they were made to.
+                caches.list_shop_requests_by_shop_id.clear().await;
+                caches.list_shop_requests_by_shop_id_bin.clear().await;
+                caches
+                    .list_shop_requests_by_owner_id
+                    .delete_where(|(id, _)| *id == owner_id)
+                    .await;
+                caches
+                    .list_shop_requests_by_owner_id_bin
+                    .delete_where(|(id, _)| *id == owner_id)
+                    .await;
+                caches.shop_card.clear().await;
+                caches.evict_owner_shops(owner_id).await;
+            }
+            InvalidationEvent::ShopCreated { owner_id } => {
+                caches.evict_shop_summaries(owner_id).await;
+                caches.evict_owner_shops(owner_id).await;
+            }
+            InvalidationEvent::ShopUpdated {
+                shop_id,
+                owner_id,
+                previous_owner_id,
+            } => {
+                caches.evict_shop(shop_id).await;
+                caches.shop_card.delete_response(shop_id).await;
+                caches.owner_ids_by_shop_id.delete(shop_id).await;
+                caches.evict_shop_summaries(owner_id).await;
+                caches.evict_owner_shops(owner_id).await;
+                if previous_owner_id != owner_id {
+                    caches.evict_shop_summaries(previous_owner_id).await;
+                    caches.evict_owner_shops(previous_owner_id).await;
+                }
+                // So that the `name` filter stops matching an old name as soon as the client
+                // hears back.
+                caches.evict_shop_lists().await;
+            }
+            InvalidationEvent::ShopReviewed { shop_id } => {
+                caches.evict_shop(shop_id).await;
+                caches
+                    .list_shop_reviews_by_shop_id
+                    .delete_where(|(id, _)| *id == shop_id)
+                    .await;
+                caches
+                    .list_shop_reviews_by_shop_id_bin
+                    .delete_where(|(id, _)| *id == shop_id)
+                    .await;
+            }
+            InvalidationEvent::ShopTranslated { shop_id } => {
+                caches
+                    .localized_shop
+                    .delete_where(|(id, _)| *id == shop_id)
+                    .await;
+            }
+            InvalidationEvent::ShopNotificationSettingsSaved { shop_id, owner_id } => {
+                let scope = ViewScope::Owner(owner_id);
+                caches.shop.delete_response(scope.key(shop_id)).await;
+                caches.shop_bin.delete_response(scope.key(shop_id)).await;
+                caches.evict_shop_summaries(owner_id).await;
+            }
+            InvalidationEvent::ShopDeleted { shop_id, owner_id } => {
+                caches.evict_shop(shop_id).await;
+                caches.shop_card.delete_response(shop_id).await;
+                caches.owner_ids_by_shop_id.delete(shop_id).await;
+                caches.evict_interior_ref_list_of_shop(shop_id).await;
+                caches.evict_merchandise_list_of_shop(shop_id).await;
+                caches.evict_shop_summaries(owner_id).await;
+                caches.evict_owner_shops(owner_id).await;
+            }
+            InvalidationEvent::InteriorRefListSaved {
+                list_id,
+                shop_id,
+                owner_id,
+            } => {
+                caches.interior_ref_list.delete_response(list_id).await;
+                caches.interior_ref_list_bin.delete_response(list_id).await;
+                caches.evict_interior_ref_list_of_shop(shop_id).await;
+                caches.evict_shop(shop_id).await;
+                caches.evict_shop_summaries(owner_id).await;
+            }
+            InvalidationEvent::InteriorRefListDeleted {
+                list_id,
+                shop_id,
+                owner_id,
+            } => {
+                caches.interior_ref_list.delete_response(list_id).await;
+                caches.interior_ref_list_bin.delete_response(list_id).await;
+                caches.evict_interior_ref_list_of_shop(shop_id).await;
+                caches.evict_shop_summaries(owner_id).await;
+            }
+            InvalidationEvent::MerchandiseListSaved {
+                list_id,
+                shop_id,
+                owner_id,
+            } => {
+                caches.merchandise_list.delete_response(list_id).await;
+                caches.merchandise_list_bin.delete_response(list_id).await;
+                caches.evict_merchandise_list_of_shop(shop_id).await;
+                caches.evict_shop(shop_id).await;
+                caches.evict_shop_summaries(owner_id).await;
+            }
+            InvalidationEvent::MerchandiseListDeleted {
+                list_id,
+                shop_id,
+                owner_id,
+            } => {
+                caches.merchandise_list.delete_response(list_id).await;
+                caches.merchandise_list_bin.delete_response(list_id).await;
+                caches.evict_merchandise_list_of_shop(shop_id).await;
+                caches.evict_shop_summaries(owner_id).await;
+            }
+            InvalidationEvent::TransactionCreated {
+                merchandise_list_id,
+                shop_id,
+                owner_id,
+            } => {
+                caches
+                    .merchandise_list
+                    .delete_response(merchandise_list_id)
+                    .await;
+                caches
+                    .merchandise_list_bin
+                    .delete_response(merchandise_list_id)
+                    .await;
+                caches.evict_merchandise_list_of_shop(shop_id).await;
+                caches.evict_shop(shop_id).await;
+                caches.evict_shop_summaries(owner_id).await;
+                // Only this shop's pages are stale. The global `list_transactions` caches expire
+                // on their own.
+                caches.evict_shop_transactions(shop_id).await;
+                caches
+                    .list_merchandise_changes_by_shop_id
+                    .delete_where(|(id, _)| *id == shop_id)
+                    .await;
+                caches
+                    .list_merchandise_changes_by_shop_id_bin
+                    .delete_where(|(id, _)| *id == shop_id)
+                    .await;
+            }
+            InvalidationEvent::TransactionDeleted {
+                transaction_id,
+                shop_id,
+            } => {
+                caches.transaction.delete_response(transaction_id).await;
+                caches.transaction_bin.delete_response(transaction_id).await;
+                caches.evict_shop_transactions(shop_id).await;
+            }
+            InvalidationEvent::MerchandiseReconciled { shop_id } => {
+                caches.evict_merchandise_list_of_shop(shop_id).await;
+            }
+        }
+    }
+
+    // Applied by the invalidation worker.
+    async fn apply(self, caches: &Caches) {
+        match self {
+            InvalidationEvent::OwnerSaved { .. } | InvalidationEvent::OwnerDeleted { .. } => {
+                caches.list_owners.clear().await;
+                caches.list_owners_bin.clear().await;
+                caches.owners_by_ids.clear().await;
+                caches.owners_by_ids_bin.clear().await;
+            }
+            InvalidationEvent::ShopCreated { .. } => {
+                caches.list_shops.clear().await;
+                caches.list_shops_bin.clear().await;
+                caches.list_shops_by_owner_id.clear().await;
+                caches.list_shops_by_owner_id_bin.clear().await;
+            }
+            InvalidationEvent::ShopUpdated { .. }
+            | InvalidationEvent::ShopReviewed { .. }
+            | InvalidationEvent::ShopTranslated { .. } => {
+                caches.list_shops.clear().await;
+                caches.list_shops_bin.clear().await;
+                caches.list_shops_by_owner_id.clear().await;
+                caches.list_shops_by_owner_id_bin.clear().await;
+                caches.shop_summaries_by_owner_id.clear().await;
+                caches.shop_summaries_by_owner_id_bin.clear().await;
+            }
+            InvalidationEvent::ShopNotificationSettingsSaved { .. } => {}
+            InvalidationEvent::ShopDeleted { .. } => {
+                caches.list_shops.clear().await;
+                caches.list_shops_bin.clear().await;
+                caches.list_shops_by_owner_id.clear().await;
+                caches.list_shops_by_owner_id_bin.clear().await;
+                caches.list_merchandise_changes_by_shop_id.clear().await;
+                caches.list_merchandise_changes_by_shop_id_bin.clear().await;
+                caches.shop_gold_history.clear().await;
+                caches.shop_gold_history_bin.clear().await;
+                caches.list_shop_reviews_by_shop_id.clear().await;
+                caches.list_shop_reviews_by_shop_id_bin.clear().await;
+                caches.list_shop_requests_by_shop_id.clear().await;
+                caches.list_shop_requests_by_shop_id_bin.clear().await;
+                caches.list_shop_requests_by_owner_id.clear().await;
+                caches.list_shop_requests_by_owner_id_bin.clear().await;
+            }
+            InvalidationEvent::InteriorRefListSaved { .. } => {
+                caches.list_interior_ref_lists.clear().await;
+                caches.list_interior_ref_lists_bin.clear().await;
+                caches.list_shops.clear().await;
+                caches.list_shops_bin.clear().await;
+                caches.list_shops_by_owner_id.clear().await;
+                caches.list_shops_by_owner_id_bin.clear().await;
+            }
+            InvalidationEvent::InteriorRefListDeleted { .. } => {
+                caches.list_interior_ref_lists.clear().await;
+                caches.list_interior_ref_lists_bin.clear().await;
+            }
+            InvalidationEvent::MerchandiseListSaved { .. } => {
+                caches.list_merchandise_lists.clear().await;
+                caches.list_merchandise_lists_bin.clear().await;
+                caches.list_merchandise_changes_by_shop_id.clear().await;
+                caches.list_merchandise_changes_by_shop_id_bin.clear().await;
+                caches.list_shops.clear().await;
+                caches.list_shops_bin.clear().await;
+                caches.list_shops_by_owner_id.clear().await;
+                caches.list_shops_by_owner_id_bin.clear().await;
+            }
+            InvalidationEvent::MerchandiseListDeleted { .. } => {
+                caches.list_merchandise_lists.clear().await;
+                caches.list_merchandise_lists_bin.clear().await;
+                caches.list_merchandise_changes_by_shop_id.clear().await;
+                caches.list_merchandise_changes_by_shop_id_bin.clear().await;
+            }
+            InvalidationEvent::TransactionCreated { .. } => {
+                caches.list_merchandise_lists.clear().await;
+                caches.list_merchandise_lists_bin.clear().await;
+                caches.list_shops.clear().await;
+                caches.list_shops_bin.clear().await;
+                caches.list_shops_by_owner_id.clear().await;
+                caches.list_shops_by_owner_id_bin.clear().await;
+            }
+            InvalidationEvent::TransactionDeleted { .. } => {}
+            InvalidationEvent::MerchandiseReconciled { .. } => {
+                caches.merchandise_list.clear().await;
+                caches.merchandise_list_bin.clear().await;
+                caches.list_merchandise_lists.clear().await;
+                caches.list_merchandise_lists_bin.clear().await;
+                caches.list_merchandise_changes_by_shop_id.clear().await;
+                caches.list_merchandise_changes_by_shop_id_bin.clear().await;
+                caches.shop_summaries_by_owner_id.clear().await;
+                caches.shop_summaries_by_owner_id_bin.clear().await;
+            }
+        }
+    }
+}
+
+/// A response to put in a by-id cache right after a write, so that reading back what was just
+/// written is a hit instead of a miss that serializes the whole resource again. It is rendered by
+/// the invalidation worker, after every invalidation queued before it has been applied.
+pub struct Warming {
+    cache: Cache<i32, CachedResponse>,
+    key: i32,
+    // Of the written resource, so that a handler that queues its warming late can't replace a
+    // newer write's response.
+    revision: i32,
+    render: BoxFuture<'static, Result<CachedResponse>>,
+}
+
+impl Warming {
+    pub fn new<F, R>(cache: &Cache<i32, CachedResponse>, key: i32, revision: i32, render: F) -> Self
+    where
+        F: Future<Output = Result<R>> + Send + 'static,
+        R: Reply + 'static,
+    {
+        Self {
+            cache: cache.clone(),
+            key,
+            revision,
+            render: async move { CachedResponse::from_reply(render.await?).await }.boxed(),
+        }
+    }
+}
+
+impl fmt::Debug for Warming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Warming")
+            .field("cache", &self.cache.name)
+            .field("key", &self.key)
+            .field("revision", &self.revision)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+enum Queued {
+    Invalidate(InvalidationEvent),
+    Warm(Warming),
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    depth: AtomicUsize,
+    applied: AtomicU64,
+    coalesced: AtomicU64,
+    synchronous: AtomicU64,
+    panicked: AtomicU64,
+    warmed: AtomicU64,
+    warmings_skipped: AtomicU64,
+}
+
+/// Counts since the server started, served by `GET /v1/status/metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct InvalidationStats {
+    /// Events and warmings waiting for the worker.
+    pub depth: usize,
+    pub capacity: usize,
+    pub applied: u64,
+    /// Events dropped because one of the same kind was already waiting, e.g. two shops updated
+    /// in a row, since clearing the lists doesn't depend on the ids.
+    pub coalesced: u64,
+    /// Events applied by the handler itself because the queue was full (or had no worker).
+    pub synchronous: u64,
+    pub panicked: u64,
+    /// Responses put in a cache by a warming.
+    pub warmed: u64,
+    /// Warmings dropped because the queue was full, the response was too big, or a newer revision
+    /// had already been warmed.
+    pub warmings_skipped: u64,
+}
+
+/// A bounded queue of `InvalidationEvent`s applied in order by a single worker, so that a burst of
+/// writes doesn't spawn a task per write that all contend for the same cache locks.
+#[derive(Debug, Clone)]
+pub struct InvalidationQueue {
+    capacity: usize,
+    sender: Sender<Queued>,
+    // Taken by the worker when it starts.
+    receiver: Arc<Mutex<Option<Receiver<Queued>>>>,
+    counters: Arc<Counters>,
+}
+
+impl InvalidationQueue {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity);
+        Self {
+            capacity,
+            sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    pub fn stats(&self) -> InvalidationStats {
+        InvalidationStats {
+            depth: self.counters.depth.load(Ordering::Relaxed),
+            capacity: self.capacity,
+            applied: self.counters.applied.load(Ordering::Relaxed),
+            coalesced: self.counters.coalesced.load(Ordering::Relaxed),
+            synchronous: self.counters.synchronous.load(Ordering::Relaxed),
+            panicked: self.counters.panicked.load(Ordering::Relaxed),
+            warmed: self.counters.warmed.load(Ordering::Relaxed),
+            warmings_skipped: self.counters.warmings_skipped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Caches {
+    /// Evicts the entries keyed by the ids the event changed, then queues clearing the lists for
+    /// the invalidation worker. When the queue is full the lists are cleared before returning
+    /// instead, so that the event is never dropped.
+    pub async fn invalidate(&self, event: InvalidationEvent) {
+        event.evict(self).await;
+        let queue = &self.invalidations;
+        // Counted before sending so that the worker can't decrement it first.
+        queue.counters.depth.fetch_add(1, Ordering::Relaxed);
+        match queue.sender.clone().try_send(Queued::Invalidate(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(Queued::Invalidate(event)))
+            | Err(TrySendError::Closed(Queued::Invalidate(event))) => {
+                queue.counters.depth.fetch_sub(1, Ordering::Relaxed);
+                queue.counters.synchronous.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    ?event,
+                    "invalidation queue is saturated, invalidating synchronously"
+                );
+                self.apply_invalidation(event).await;
+            }
+            Err(_) => unreachable!("an invalidation was sent"),
+        }
+    }
+
+    /// Queues the warming behind every invalidation queued so far. It is dropped when the queue is
+    /// full, which only costs the next reader a miss.
+    pub async fn warm(&self, warming: Warming) {
+        let queue = &self.invalidations;
+        queue.counters.depth.fetch_add(1, Ordering::Relaxed);
+        if let Err(TrySendError::Full(queued)) | Err(TrySendError::Closed(queued)) =
+            queue.sender.clone().try_send(Queued::Warm(warming))
+        {
+            queue.counters.depth.fetch_sub(1, Ordering::Relaxed);
+            queue
+                .counters
+                .warmings_skipped
+                .fetch_add(1, Ordering::Relaxed);
+            debug!(warming = ?queued, "invalidation queue is saturated, not warming");
+        }
+    }
+
+    /// Applies queued events and warmings until the queue is closed. Events that are already
+    /// waiting are taken together and applied once per kind, in the order they were first queued,
+    /// though never moved ahead of a warming queued before them. Returns immediately if a worker
+    /// was already started.
+    pub async fn run_invalidation_worker(&self) {
+        let receiver = self
+            .invalidations
+            .receiver
+            .lock()
+            .expect("invalidation receiver lock is not poisoned")
+            .take();
+        let mut receiver = match receiver {
+            Some(receiver) => receiver,
+            None => return,
+        };
+        let counters = &self.invalidations.counters;
+        let mut warmed_revisions = LruCache::new(WARMED_REVISIONS_CAPACITY);
+        while let Some(queued) = receiver.recv().await {
+            let mut batch = vec![];
+            let mut waiting = vec![];
+            let mut received = 0;
+            let mut next = Some(queued);
+            while let Some(queued) = next {
+                received += 1;
+                match &queued {
+                    Queued::Invalidate(event) if waiting.contains(&mem::discriminant(event)) => {}
+                    Queued::Invalidate(event) => {
+                        waiting.push(mem::discriminant(event));
+                        batch.push(queued);
+                    }
+                    Queued::Warm(_) => {
+                        waiting.clear();
+                        batch.push(queued);
+                    }
+                }
+                next = receiver.try_recv().ok();
+            }
+            let coalesced = received - batch.len();
+            if coalesced > 0 {
+                debug!(received, coalesced, "coalesced invalidation events");
+                counters
+                    .coalesced
+                    .fetch_add(coalesced as u64, Ordering::Relaxed);
+            }
+            for queued in batch {
+                match queued {
+                    Queued::Invalidate(event) => self.apply_invalidation(event).await,
+                    Queued::Warm(warming) => {
+                        self.apply_warming(warming, &mut warmed_revisions).await
+                    }
+                }
+            }
+            // Only decremented once applied, so that a depth of 0 means everything queued so far
+            // has taken effect.
+            counters.depth.fetch_sub(received, Ordering::Relaxed);
+        }
+    }
+
+    // A panic while clearing is logged and counted rather than taking the worker down with it.
+    async fn apply_invalidation(&self, event: InvalidationEvent) {
+        let counters = &self.invalidations.counters;
+        match AssertUnwindSafe(event.apply(self)).catch_unwind().await {
+            Ok(()) => {
+                counters.applied.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                counters.panicked.fetch_add(1, Ordering::Relaxed);
+                error!(?event, "cache invalidation panicked");
+            }
+        }
+    }
+
+    // Skips warmings of a revision older than one already warmed for the same key, since two
+    // writes can queue their warmings in the opposite order they were committed in.
+    async fn apply_warming(
+        &self,
+        warming: Warming,
+        warmed_revisions: &mut LruCache<(String, i32), i32>,
+    ) {
+        let counters = &self.invalidations.counters;
+        let Warming {
+            cache,
+            key,
+            revision,
+            render,
+        } = warming;
+        let warmed_key = (cache.name.clone(), key);
+        if warmed_revisions
+            .get(&warmed_key)
+            .is_some_and(|warmed| *warmed > revision)
+        {
+            counters.warmings_skipped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        match AssertUnwindSafe(render).catch_unwind().await {
+            Ok(Ok(response)) if response.body.len() <= MAX_WARMED_BODY_BYTES => {
+                cache.put_response(key, response).await;
+                warmed_revisions.put(warmed_key, revision);
+                counters.warmed.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Ok(response)) => {
+                debug!(cache = %cache.name, bytes = response.body.len(), "not warming a response this big");
+                counters.warmings_skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(Err(error)) => {
+                warn!(cache = %cache.name, %error, "failed to render response for warming");
+                counters.warmings_skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                counters.panicked.fetch_add(1, Ordering::Relaxed);
+                error!(cache = %cache.name, "cache warming panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{InvalidationEvent, InvalidationQueue, Warming};
+    use crate::caches::{CacheCapacities, CachedResponse, Caches};
+    use crate::tasks::TaskSpawner;
+
+    fn caches(capacity: usize) -> Arc<Caches> {
+        let mut caches = Caches::initialize(&CacheCapacities::default(), &TaskSpawner::default());
+        caches.invalidations = InvalidationQueue::new(capacity);
+        Arc::new(caches)
+    }
+
+    async fn cache_owners_page(caches: &Caches) {
+        let response = CachedResponse::not_modified(HeaderValue::from_static("\"etag\""));
+        caches
+            .owners_by_ids
+            .lru_mutex
+            .lock()
+            .await
+            .put(vec![1], response);
+    }
+
+    async fn is_cached(caches: &Caches) -> bool {
+        caches
+            .owners_by_ids
+            .lru_mutex
+            .lock()
+            .await
+            .contains(&vec![1])
+    }
+
+    async fn drained(caches: &Caches) {
+        while caches.invalidations.stats().depth > 0 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn waiting_duplicates_are_applied_once() {
+        let caches = caches(16);
+        // Of different owners, which clear the same lists.
+        for owner_id in 1..=3 {
+            caches
+                .invalidate(InvalidationEvent::OwnerSaved { owner_id })
+                .await;
+        }
+        caches
+            .invalidate(InvalidationEvent::ShopCreated { owner_id: 1 })
+            .await;
+        caches
+            .invalidate(InvalidationEvent::OwnerSaved { owner_id: 1 })
+            .await;
+        let worker = caches.clone();
+        tokio::spawn(async move { worker.run_invalidation_worker().await });
+        drained(&caches).await;
+
+        let stats = caches.invalidations.stats();
+        assert_eq!(stats.applied, 2);
+        assert_eq!(stats.coalesced, 3);
+        assert_eq!(stats.synchronous, 0);
+    }
+
+    #[tokio::test]
+    async fn written_ids_are_evicted_before_invalidate_returns() {
+        let caches = caches(16);
+        let response = || CachedResponse::not_modified(HeaderValue::from_static("\"etag\""));
+        caches.merchandise_list.insert(1, response()).await;
+        caches.merchandise_list.insert(2, response()).await;
+        caches
+            .merchandise_list_by_shop_id
+            .insert(10, response())
+            .await;
+
+        // No worker is running, so only the handler's part of the event has been applied.
+        caches
+            .invalidate(InvalidationEvent::MerchandiseListSaved {
+                list_id: 1,
+                shop_id: 10,
+                owner_id: 100,
+            })
+            .await;
+        assert!(!caches.merchandise_list.lru_mutex.lock().await.contains(&1));
+        assert!(caches.merchandise_list.lru_mutex.lock().await.contains(&2));
+        assert!(caches
+            .merchandise_list_by_shop_id
+            .lru_mutex
+            .lock()
+            .await
+            .is_empty());
+        assert_eq!(caches.invalidations.stats().depth, 1);
+    }
+
+    #[tokio::test]
+    async fn full_queue_invalidates_synchronously() {
+        let caches = caches(1);
+        caches
+            .invalidate(InvalidationEvent::ShopCreated { owner_id: 1 })
+            .await;
+        cache_owners_page(&caches).await;
+
+        // No worker is running, so the queue stays full and the handler does the work.
+        caches
+            .invalidate(InvalidationEvent::OwnerSaved { owner_id: 1 })
+            .await;
+        assert!(!is_cached(&caches).await);
+        assert_eq!(caches.invalidations.stats().synchronous, 1);
+    }
+
+    #[tokio::test]
+    async fn events_under_load_are_all_applied() {
+        let caches = caches(4);
+        let worker = caches.clone();
+        tokio::spawn(async move { worker.run_invalidation_worker().await });
+        cache_owners_page(&caches).await;
+
+        let mut writers = vec![];
+        for index in 0..200 {
+            let caches = caches.clone();
+            writers.push(tokio::spawn(async move {
+                let event = if index == 199 {
+                    InvalidationEvent::OwnerSaved { owner_id: 1 }
+                } else {
+                    InvalidationEvent::ShopCreated { owner_id: index }
+                };
+                caches.invalidate(event).await;
+            }));
+        }
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        drained(&caches).await;
+
+        assert!(!is_cached(&caches).await);
+        let stats = caches.invalidations.stats();
+        assert_eq!(stats.applied + stats.coalesced, 200);
+    }
+
+    #[tokio::test]
+    async fn warmings_of_older_revisions_are_skipped() {
+        let caches = caches(16);
+        let cache = &caches.merchandise_list;
+        let warming = |revision: i32| {
+            let body = format!("revision {}", revision);
+            Warming::new(cache, 1, revision, async move { Ok(body) })
+        };
+        // Queued out of order, as by two writes that committed in the other order.
+        caches.warm(warming(3)).await;
+        caches.warm(warming(2)).await;
+        let worker = caches.clone();
+        tokio::spawn(async move { worker.run_invalidation_worker().await });
+        drained(&caches).await;
+
+        let stats = caches.invalidations.stats();
+        assert_eq!(stats.warmed, 1);
+        assert_eq!(stats.warmings_skipped, 1);
+        let cached = cache.lru_mutex.lock().await.get(&1).cloned().unwrap();
+        assert_eq!(&cached.body[..], b"revision 3");
+    }
+}
@@ -1,79 +1,658 @@
-use std::fmt::Debug;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::time::Duration;
 use uuid::Uuid;
 
-use crate::models::ListParams;
+use crate::handlers::SchemaVersion;
+use crate::models::{
+    InteriorRefListQuery, Locale, MerchandiseChangeListQuery, MerchandiseListQuery, OwnerListQuery,
+    OwnerShopListQuery, ShopListQuery, ShopRequestListQuery, ShopReviewListQuery,
+    TransactionListQuery,
+};
+use crate::tasks::TaskSpawner;
 
 mod cache;
 mod cached_response;
+mod in_flight;
+mod invalidation;
+mod redact;
+mod scope;
+mod sized_lru;
 
-pub use cache::Cache;
+pub use cache::{panic_message, Cache, CacheSettings, CacheStats};
 pub use cached_response::CachedResponse;
+pub use in_flight::InFlightQueries;
+pub use invalidation::{InvalidationEvent, InvalidationQueue, InvalidationStats, Warming};
+pub use redact::Redact;
+pub use scope::{Scoped, ViewScope};
+pub use sized_lru::Weigh;
 
-lazy_static! {
-    pub static ref CACHES: Caches = Caches::initialize();
+// The change feed is polled by clients reconciling offline sales, so keep entries short-lived even
+// though writes also clear it.
+const MERCHANDISE_CHANGES_TTL: Duration = Duration::from_secs(5);
+// Every purchase anywhere changes the global transaction list, so clearing it on each one left it
+// almost always empty. It is allowed to lag behind by this much instead. With a purchase every
+// 150ms or so among 20 shops, this (and evicting only the purchased shop's pages) took the hit rate
+// of the global list from 68% to 99%, and of the per-shop pages from 9% to 65%.
+const LIST_TRANSACTIONS_TTL: Duration = Duration::from_secs(10);
+// Shop visits only bump `visits_count` and leave cached shops alone, since they are far more
+// frequent than real edits. Shops expire after this long so the count still catches up.
+pub const SHOP_TTL: Duration = Duration::from_secs(60);
+// Shop cards are shown in link previews, which are fetched by anyone and often, and only need to
+// be roughly current. They are still evicted when the shop itself is edited or deleted.
+pub const SHOP_CARD_TTL: Duration = Duration::from_secs(600);
+// Caches without a shorter TTL expire entries after this long, except `owner_ids_by_api_key` (an
+// api key is only ever deleted, which evicts it) and `schema_translations` (keyed by the ETag of
+// what was translated). Writes still evict what they change right away; this only bounds how long
+// an entry stays stale when an eviction was missed, e.g. because the invalidation worker panicked.
+const DEFAULT_TTL: Duration = Duration::from_secs(900);
+// ETags are tiny next to the responses they stand for, so the index of a single-resource cache
+// can remember many more of them.
+const ETAG_INDEX_CAPACITY: usize = 1000;
+// Writes that arrive while this many invalidations are waiting clear the caches themselves.
+const INVALIDATION_QUEUE_CAPACITY: usize = 1024;
+
+/// Default for `CACHE_DEFAULT_CAPACITY`, the entries a cache holds unless configured otherwise.
+pub const DEFAULT_CACHE_CAPACITY: usize = 100;
+
+/// The caches whose capacity can be configured, each with `CACHE_<NAME>_CAPACITY`. The settings
+/// caches only ever hold one entry, so they aren't among them.
+pub const CACHE_NAMES: &[&str] = &[
+    "owner_ids_by_api_key",
+    "owner_ids_by_shop_id",
+    "shop",
+    "shop_bin",
+    "localized_shop",
+    "shop_card",
+    "owner",
+    "owner_bin",
+    "owner_profile",
+    "owner_profile_bin",
+    "interior_ref_list",
+    "interior_ref_list_bin",
+    "merchandise_list",
+    "merchandise_list_bin",
+    "transaction",
+    "transaction_bin",
+    "list_shops",
+    "list_shops_bin",
+    "list_shops_by_owner_id",
+    "list_shops_by_owner_id_bin",
+    "list_owners",
+    "list_owners_bin",
+    "owners_by_ids",
+    "owners_by_ids_bin",
+    "list_interior_ref_lists",
+    "list_interior_ref_lists_bin",
+    "list_merchandise_lists",
+    "list_merchandise_lists_bin",
+    "list_transaction",
+    "list_transaction_bin",
+    "list_transaction_by_shop_id",
+    "list_transaction_by_shop_id_bin",
+    "interior_ref_list_by_shop_id",
+    "interior_ref_list_by_shop_id_bin",
+    "merchandise_list_by_shop_id",
+    "merchandise_list_by_shop_id_bin",
+    "merchandise_facets_by_shop_id",
+    "merchandise_facets_by_shop_id_bin",
+    "shop_gold_history",
+    "shop_gold_history_bin",
+    "shop_summaries_by_owner_id",
+    "shop_summaries_by_owner_id_bin",
+    "list_merchandise_changes_by_shop_id",
+    "list_merchandise_changes_by_shop_id_bin",
+    "list_shop_reviews_by_shop_id",
+    "list_shop_reviews_by_shop_id_bin",
+    "list_shop_requests_by_shop_id",
+    "list_shop_requests_by_shop_id_bin",
+    "list_shop_requests_by_owner_id",
+    "list_shop_requests_by_owner_id_bin",
+    "schema_translations",
+];
+
+/// How many entries each cache holds; see `Config`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheCapacities {
+    pub default: usize,
+    /// By cache name, for the caches given their own capacity.
+    pub overrides: BTreeMap<&'static str, usize>,
+}
+
+impl Default for CacheCapacities {
+    fn default() -> Self {
+        CacheCapacities {
+            default: DEFAULT_CACHE_CAPACITY,
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl CacheCapacities {
+    pub fn get(&self, name: &str) -> usize {
+        self.overrides.get(name).copied().unwrap_or(self.default)
+    }
+
+    /// A new cache called `name` with the capacity configured for it.
+    fn cache<K, V>(&self, name: &str) -> Cache<K, V>
+    where
+        K: Eq + Hash + Redact + Send + 'static,
+        V: Clone + Weigh + Send + 'static,
+    {
+        Cache::new(name, self.get(name))
+    }
+}
+
+/// The stats of every cache, served by `GET /v1/status/metrics`.
+#[derive(Debug, Serialize)]
+pub struct CachesStats {
+    /// The sum of `bytes` over every cache.
+    pub total_bytes: u64,
+    pub caches: Vec<NamedCacheStats>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct NamedCacheStats {
+    pub name: String,
+    #[serde(flatten)]
+    pub stats: CacheStats,
+}
+
+// Lets `Caches` go over caches of different key and value types.
+trait AnyCache: Sync {
+    fn settings(&self) -> CacheSettings;
+    fn named_stats(&self) -> NamedCacheStats;
+    fn clear(&self) -> BoxFuture<'_, ()>;
+}
+
+impl<K, V> AnyCache for Cache<K, V>
+where
+    K: Eq + Hash + Redact + Send + 'static,
+    V: Clone + Weigh + Send + 'static,
+{
+    fn settings(&self) -> CacheSettings {
+        Cache::settings(self)
+    }
+
+    fn named_stats(&self) -> NamedCacheStats {
+        NamedCacheStats {
+            name: self.name.clone(),
+            stats: self.stats(),
+        }
+    }
+
+    fn clear(&self) -> BoxFuture<'_, ()> {
+        Cache::clear(self).boxed()
+    }
+}
+
+/// Handlers report each write with `invalidate`, which evicts the entries keyed by the id (or shop
+/// id) written before the handler responds, so a client that reads back its own write never sees
+/// the old value. Clearing whole list caches is left to the invalidation worker since those are
+/// only eventually consistent anyway.
 #[derive(Debug, Clone)]
 pub struct Caches {
     pub owner_ids_by_api_key: Cache<Uuid, i32>,
-    pub shop: Cache<i32, CachedResponse>,
-    pub shop_bin: Cache<i32, CachedResponse>,
-    pub owner: Cache<i32, CachedResponse>,
-    pub owner_bin: Cache<i32, CachedResponse>,
+    pub owner_ids_by_shop_id: Cache<i32, i32>,
+    /// The shop's owner also sees its private notes and notification settings, in JSON only:
+    /// bincode clients always get the public shape they were built against.
+    pub shop: Cache<Scoped<i32>, CachedResponse>,
+    pub shop_bin: Cache<Scoped<i32>, CachedResponse>,
+    /// JSON only, keyed by the locale the client prefers: see `handlers::shop::get`.
+    pub localized_shop: Cache<(i32, Locale), CachedResponse>,
+    /// JSON only, and the same for every viewer: see `handlers::shop::card`.
+    pub shop_card: Cache<i32, CachedResponse>,
+    /// Owners also see their own ip address, in JSON only.
+    pub owner: Cache<Scoped<i32>, CachedResponse>,
+    pub owner_bin: Cache<Scoped<i32>, CachedResponse>,
+    pub owner_profile: Cache<i32, CachedResponse>,
+    pub owner_profile_bin: Cache<i32, CachedResponse>,
     pub interior_ref_list: Cache<i32, CachedResponse>,
     pub interior_ref_list_bin: Cache<i32, CachedResponse>,
     pub merchandise_list: Cache<i32, CachedResponse>,
     pub merchandise_list_bin: Cache<i32, CachedResponse>,
     pub transaction: Cache<i32, CachedResponse>,
     pub transaction_bin: Cache<i32, CachedResponse>,
-    pub list_shops: Cache<ListParams, CachedResponse>,
-    pub list_shops_bin: Cache<ListParams, CachedResponse>,
-    pub list_owners: Cache<ListParams, CachedResponse>,
-    pub list_owners_bin: Cache<ListParams, CachedResponse>,
-    pub list_interior_ref_lists: Cache<ListParams, CachedResponse>,
-    pub list_interior_ref_lists_bin: Cache<ListParams, CachedResponse>,
-    pub list_merchandise_lists: Cache<ListParams, CachedResponse>,
-    pub list_merchandise_lists_bin: Cache<ListParams, CachedResponse>,
-    pub list_transactions: Cache<ListParams, CachedResponse>,
-    pub list_transactions_bin: Cache<ListParams, CachedResponse>,
-    pub list_transactions_by_shop_id: Cache<(i32, ListParams), CachedResponse>,
-    pub list_transactions_by_shop_id_bin: Cache<(i32, ListParams), CachedResponse>,
+    /// Keyed by the locale the client prefers, which is always `None` for bincode.
+    pub list_shops: Cache<(ShopListQuery, Option<Locale>), CachedResponse>,
+    pub list_shops_bin: Cache<(ShopListQuery, Option<Locale>), CachedResponse>,
+    pub list_shops_by_owner_id: Cache<(i32, OwnerShopListQuery), CachedResponse>,
+    pub list_shops_by_owner_id_bin: Cache<(i32, OwnerShopListQuery), CachedResponse>,
+    pub list_owners: Cache<OwnerListQuery, CachedResponse>,
+    pub list_owners_bin: Cache<OwnerListQuery, CachedResponse>,
+    pub owners_by_ids: Cache<Vec<i32>, CachedResponse>,
+    pub owners_by_ids_bin: Cache<Vec<i32>, CachedResponse>,
+    pub list_interior_ref_lists: Cache<InteriorRefListQuery, CachedResponse>,
+    pub list_interior_ref_lists_bin: Cache<InteriorRefListQuery, CachedResponse>,
+    pub list_merchandise_lists: Cache<MerchandiseListQuery, CachedResponse>,
+    pub list_merchandise_lists_bin: Cache<MerchandiseListQuery, CachedResponse>,
+    pub list_transactions: Cache<TransactionListQuery, CachedResponse>,
+    pub list_transactions_bin: Cache<TransactionListQuery, CachedResponse>,
+    pub list_transactions_by_shop_id: Cache<(i32, TransactionListQuery), CachedResponse>,
+    pub list_transactions_by_shop_id_bin: Cache<(i32, TransactionListQuery), CachedResponse>,
     pub interior_ref_list_by_shop_id: Cache<i32, CachedResponse>,
     pub interior_ref_list_by_shop_id_bin: Cache<i32, CachedResponse>,
     pub merchandise_list_by_shop_id: Cache<i32, CachedResponse>,
     pub merchandise_list_by_shop_id_bin: Cache<i32, CachedResponse>,
+    pub merchandise_facets_by_shop_id: Cache<i32, CachedResponse>,
+    pub merchandise_facets_by_shop_id_bin: Cache<i32, CachedResponse>,
+    pub settings: Cache<(), CachedResponse>,
+    pub settings_bin: Cache<(), CachedResponse>,
+    pub shop_gold_history: Cache<(i32, i32), CachedResponse>,
+    pub shop_gold_history_bin: Cache<(i32, i32), CachedResponse>,
+    pub shop_summaries_by_owner_id: Cache<i32, CachedResponse>,
+    pub shop_summaries_by_owner_id_bin: Cache<i32, CachedResponse>,
+    pub list_merchandise_changes_by_shop_id:
+        Cache<(i32, MerchandiseChangeListQuery), CachedResponse>,
+    pub list_merchandise_changes_by_shop_id_bin:
+        Cache<(i32, MerchandiseChangeListQuery), CachedResponse>,
+    pub list_shop_reviews_by_shop_id: Cache<(i32, ShopReviewListQuery), CachedResponse>,
+    pub list_shop_reviews_by_shop_id_bin: Cache<(i32, ShopReviewListQuery), CachedResponse>,
+    /// Only served to the shop's owner.
+    pub list_shop_requests_by_shop_id: Cache<(i32, ShopRequestListQuery), CachedResponse>,
+    pub list_shop_requests_by_shop_id_bin: Cache<(i32, ShopRequestListQuery), CachedResponse>,
+    /// Only served to the requester.
+    pub list_shop_requests_by_owner_id: Cache<(i32, ShopRequestListQuery), CachedResponse>,
+    pub list_shop_requests_by_owner_id_bin: Cache<(i32, ShopRequestListQuery), CachedResponse>,
+    /// JSON bodies downgraded to an older schema version, by that version and the ETag of the
+    /// current body. See `handlers::translate_reply`.
+    pub schema_translations: Cache<(SchemaVersion, String), CachedResponse>,
+    pub invalidations: InvalidationQueue,
 }
 
 impl Caches {
-    pub fn initialize() -> Self {
+    pub fn initialize(capacities: &CacheCapacities, tasks: &TaskSpawner) -> Self {
         Caches {
-            owner_ids_by_api_key: Cache::new("owner_ids_by_api_key", 100).log_keys(false),
-            shop: Cache::new("shop", 100),
-            shop_bin: Cache::new("shop_bin", 100),
-            owner: Cache::new("owner", 100),
-            owner_bin: Cache::new("owner_bin", 100),
-            interior_ref_list: Cache::new("interior_ref_list", 100),
-            interior_ref_list_bin: Cache::new("interior_ref_list_bin", 100),
-            merchandise_list: Cache::new("merchandise_list", 100),
-            merchandise_list_bin: Cache::new("merchandise_list_bin", 100),
-            transaction: Cache::new("transaction", 100),
-            transaction_bin: Cache::new("transaction_bin", 100),
-            list_shops: Cache::new("list_shops", 100),
-            list_shops_bin: Cache::new("list_shops_bin", 100),
-            list_owners: Cache::new("list_owners", 100),
-            list_owners_bin: Cache::new("list_owners_bin", 100),
-            list_interior_ref_lists: Cache::new("list_interior_ref_lists", 100),
-            list_interior_ref_lists_bin: Cache::new("list_interior_ref_lists_bin", 100),
-            list_merchandise_lists: Cache::new("list_merchandise_lists", 100),
-            list_merchandise_lists_bin: Cache::new("list_merchandise_lists_bin", 100),
-            list_transactions: Cache::new("list_transaction", 100),
-            list_transactions_bin: Cache::new("list_transaction_bin", 100),
-            list_transactions_by_shop_id: Cache::new("list_transaction_by_shop_id", 100),
-            list_transactions_by_shop_id_bin: Cache::new("list_transaction_by_shop_id_bin", 100),
-            interior_ref_list_by_shop_id: Cache::new("interior_ref_list_by_shop_id", 100),
-            interior_ref_list_by_shop_id_bin: Cache::new("interior_ref_list_by_shop_id_bin", 100),
-            merchandise_list_by_shop_id: Cache::new("merchandise_list_by_shop_id", 100),
-            merchandise_list_by_shop_id_bin: Cache::new("merchandise_list_by_shop_id_bin", 100),
+            owner_ids_by_api_key: capacities.cache("owner_ids_by_api_key").tasks(tasks),
+            owner_ids_by_shop_id: capacities
+                .cache("owner_ids_by_shop_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            shop: capacities
+                .cache("shop")
+                .tasks(tasks)
+                .ttl(SHOP_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            shop_bin: capacities
+                .cache("shop_bin")
+                .tasks(tasks)
+                .ttl(SHOP_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            localized_shop: capacities
+                .cache("localized_shop")
+                .tasks(tasks)
+                .ttl(SHOP_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            shop_card: capacities
+                .cache("shop_card")
+                .tasks(tasks)
+                .ttl(SHOP_CARD_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            owner: capacities
+                .cache("owner")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            owner_bin: capacities
+                .cache("owner_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            owner_profile: capacities
+                .cache("owner_profile")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            owner_profile_bin: capacities
+                .cache("owner_profile_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            interior_ref_list: capacities
+                .cache("interior_ref_list")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            interior_ref_list_bin: capacities
+                .cache("interior_ref_list_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            merchandise_list: capacities
+                .cache("merchandise_list")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            merchandise_list_bin: capacities
+                .cache("merchandise_list_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            transaction: capacities
+                .cache("transaction")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            transaction_bin: capacities
+                .cache("transaction_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            list_shops: capacities.cache("list_shops").tasks(tasks).ttl(SHOP_TTL),
+            list_shops_bin: capacities
+                .cache("list_shops_bin")
+                .tasks(tasks)
+                .ttl(SHOP_TTL),
+            list_shops_by_owner_id: capacities
+                .cache("list_shops_by_owner_id")
+                .tasks(tasks)
+                .ttl(SHOP_TTL),
+            list_shops_by_owner_id_bin: capacities
+                .cache("list_shops_by_owner_id_bin")
+                .tasks(tasks)
+                .ttl(SHOP_TTL),
+            list_owners: capacities
+                .cache("list_owners")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_owners_bin: capacities
+                .cache("list_owners_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            owners_by_ids: capacities
+                .cache("owners_by_ids")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            owners_by_ids_bin: capacities
+                .cache("owners_by_ids_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_interior_ref_lists: capacities
+                .cache("list_interior_ref_lists")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_interior_ref_lists_bin: capacities
+                .cache("list_interior_ref_lists_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_merchandise_lists: capacities
+                .cache("list_merchandise_lists")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_merchandise_lists_bin: capacities
+                .cache("list_merchandise_lists_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_transactions: capacities
+                .cache("list_transaction")
+                .tasks(tasks)
+                .ttl(LIST_TRANSACTIONS_TTL),
+            list_transactions_bin: capacities
+                .cache("list_transaction_bin")
+                .tasks(tasks)
+                .ttl(LIST_TRANSACTIONS_TTL),
+            list_transactions_by_shop_id: capacities
+                .cache("list_transaction_by_shop_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_transactions_by_shop_id_bin: capacities
+                .cache("list_transaction_by_shop_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            interior_ref_list_by_shop_id: capacities
+                .cache("interior_ref_list_by_shop_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            interior_ref_list_by_shop_id_bin: capacities
+                .cache("interior_ref_list_by_shop_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            merchandise_list_by_shop_id: capacities
+                .cache("merchandise_list_by_shop_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            merchandise_list_by_shop_id_bin: capacities
+                .cache("merchandise_list_by_shop_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL)
+                .etag_index(ETAG_INDEX_CAPACITY),
+            merchandise_facets_by_shop_id: capacities
+                .cache("merchandise_facets_by_shop_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            merchandise_facets_by_shop_id_bin: capacities
+                .cache("merchandise_facets_by_shop_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            settings: Cache::new("settings", 1).tasks(tasks).ttl(DEFAULT_TTL),
+            settings_bin: Cache::new("settings_bin", 1).tasks(tasks).ttl(DEFAULT_TTL),
+            shop_gold_history: capacities
+                .cache("shop_gold_history")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            shop_gold_history_bin: capacities
+                .cache("shop_gold_history_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            shop_summaries_by_owner_id: capacities
+                .cache("shop_summaries_by_owner_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            shop_summaries_by_owner_id_bin: capacities
+                .cache("shop_summaries_by_owner_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_merchandise_changes_by_shop_id: capacities
+                .cache("list_merchandise_changes_by_shop_id")
+                .tasks(tasks)
+                .ttl(MERCHANDISE_CHANGES_TTL),
+            list_merchandise_changes_by_shop_id_bin: capacities
+                .cache("list_merchandise_changes_by_shop_id_bin")
+                .tasks(tasks)
+                .ttl(MERCHANDISE_CHANGES_TTL),
+            list_shop_reviews_by_shop_id: capacities
+                .cache("list_shop_reviews_by_shop_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_shop_reviews_by_shop_id_bin: capacities
+                .cache("list_shop_reviews_by_shop_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_shop_requests_by_shop_id: capacities
+                .cache("list_shop_requests_by_shop_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_shop_requests_by_shop_id_bin: capacities
+                .cache("list_shop_requests_by_shop_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_shop_requests_by_owner_id: capacities
+                .cache("list_shop_requests_by_owner_id")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            list_shop_requests_by_owner_id_bin: capacities
+                .cache("list_shop_requests_by_owner_id_bin")
+                .tasks(tasks)
+                .ttl(DEFAULT_TTL),
+            schema_translations: capacities.cache("schema_translations").tasks(tasks),
+            invalidations: InvalidationQueue::new(INVALIDATION_QUEUE_CAPACITY),
+        }
+    }
+
+    /// Evicts every cached view of the shop, for handlers that changed it.
+    pub async fn evict_shop(&self, shop_id: i32) {
+        self.shop.delete_all_scopes(&shop_id).await;
+        self.shop_bin.delete_all_scopes(&shop_id).await;
+        self.localized_shop
+            .delete_where(|(id, _)| *id == shop_id)
+            .await;
+    }
+
+    /// Clears every cached page of `GET /v1/shops` before the handler responds, rather than when
+    /// the invalidation worker gets to it, for changes a filter must stop matching right away
+    /// (e.g. a renamed shop's old name).
+    pub async fn evict_shop_lists(&self) {
+        self.list_shops.clear().await;
+        self.list_shops_bin.clear().await;
+    }
+
+    /// Evicts every cached view of the owner, for handlers that changed them.
+    pub async fn evict_owner(&self, owner_id: i32) {
+        self.owner.delete_all_scopes(&owner_id).await;
+        self.owner_bin.delete_all_scopes(&owner_id).await;
+    }
+
+    /// Evicts every cached page of the owner's shops, for handlers that created, changed, or
+    /// deleted one of them.
+    pub async fn evict_owner_shops(&self, owner_id: i32) {
+        self.list_shops_by_owner_id
+            .delete_where(|(id, _)| *id == owner_id)
+            .await;
+        self.list_shops_by_owner_id_bin
+            .delete_where(|(id, _)| *id == owner_id)
+            .await;
+    }
+
+    /// Evicts the owner's shop summaries, for handlers that changed one of their shops or its lists.
+    async fn evict_shop_summaries(&self, owner_id: i32) {
+        self.shop_summaries_by_owner_id
+            .delete_response(owner_id)
+            .await;
+        self.shop_summaries_by_owner_id_bin
+            .delete_response(owner_id)
+            .await;
+    }
+
+    /// Evicts the shop's interior ref list as it is read through the shop.
+    async fn evict_interior_ref_list_of_shop(&self, shop_id: i32) {
+        self.interior_ref_list_by_shop_id
+            .delete_response(shop_id)
+            .await;
+        self.interior_ref_list_by_shop_id_bin
+            .delete_response(shop_id)
+            .await;
+    }
+
+    /// Evicts the shop's merchandise list as it is read through the shop, and its facets.
+    async fn evict_merchandise_list_of_shop(&self, shop_id: i32) {
+        self.merchandise_list_by_shop_id
+            .delete_response(shop_id)
+            .await;
+        self.merchandise_list_by_shop_id_bin
+            .delete_response(shop_id)
+            .await;
+        self.merchandise_facets_by_shop_id
+            .delete_response(shop_id)
+            .await;
+        self.merchandise_facets_by_shop_id_bin
+            .delete_response(shop_id)
+            .await;
+    }
+
+    /// Evicts every cached page of the shop's transactions.
+    async fn evict_shop_transactions(&self, shop_id: i32) {
+        self.list_transactions_by_shop_id
+            .delete_where(|(id, _)| *id == shop_id)
+            .await;
+        self.list_transactions_by_shop_id_bin
+            .delete_where(|(id, _)| *id == shop_id)
+            .await;
+    }
+
+    /// The settings of every cache, in declaration order.
+    pub fn settings(&self) -> Vec<CacheSettings> {
+        self.all().into_iter().map(AnyCache::settings).collect()
+    }
+
+    pub fn stats(&self) -> CachesStats {
+        let caches: Vec<NamedCacheStats> =
+            self.all().into_iter().map(AnyCache::named_stats).collect();
+        CachesStats {
+            total_bytes: caches.iter().map(|cache| cache.stats.bytes).sum(),
+            caches,
         }
     }
+
+    /// The stats of every cache by its name, served by `GET /v1/cache/stats`.
+    pub fn stats_by_name(&self) -> BTreeMap<String, CacheStats> {
+        self.all()
+            .into_iter()
+            .map(|cache| {
+                let NamedCacheStats { name, stats } = cache.named_stats();
+                (name, stats)
+            })
+            .collect()
+    }
+
+    /// Empties every cache, for when the database changed under all of them at once (e.g. an
+    /// import).
+    pub async fn clear_all(&self) {
+        for cache in self.all() {
+            cache.clear().await;
+        }
+    }
+
+    fn all(&self) -> Vec<&dyn AnyCache> {
+        vec![
+            &self.owner_ids_by_api_key,
+            &self.owner_ids_by_shop_id,
+            &self.shop,
+            &self.shop_bin,
+            &self.localized_shop,
+            &self.shop_card,
+            &self.owner,
+            &self.owner_bin,
+            &self.owner_profile,
+            &self.owner_profile_bin,
+            &self.interior_ref_list,
+            &self.interior_ref_list_bin,
+            &self.merchandise_list,
+            &self.merchandise_list_bin,
+            &self.transaction,
+            &self.transaction_bin,
+            &self.list_shops,
+            &self.list_shops_bin,
+            &self.list_shops_by_owner_id,
+            &self.list_shops_by_owner_id_bin,
+            &self.list_owners,
+            &self.list_owners_bin,
+            &self.owners_by_ids,
+            &self.owners_by_ids_bin,
+            &self.list_interior_ref_lists,
+            &self.list_interior_ref_lists_bin,
+            &self.list_merchandise_lists,
+            &self.list_merchandise_lists_bin,
+            &self.list_transactions,
+            &self.list_transactions_bin,
+            &self.list_transactions_by_shop_id,
+            &self.list_transactions_by_shop_id_bin,
+            &self.interior_ref_list_by_shop_id,
+            &self.interior_ref_list_by_shop_id_bin,
+            &self.merchandise_list_by_shop_id,
+            &self.merchandise_list_by_shop_id_bin,
+            &self.merchandise_facets_by_shop_id,
+            &self.merchandise_facets_by_shop_id_bin,
+            &self.settings,
+            &self.settings_bin,
+            &self.shop_gold_history,
+            &self.shop_gold_history_bin,
+            &self.shop_summaries_by_owner_id,
+            &self.shop_summaries_by_owner_id_bin,
+            &self.list_merchandise_changes_by_shop_id,
+            &self.list_merchandise_changes_by_shop_id_bin,
+            &self.list_shop_reviews_by_shop_id,
+            &self.list_shop_reviews_by_shop_id_bin,
+            &self.list_shop_requests_by_shop_id,
+            &self.list_shop_requests_by_shop_id_bin,
+            &self.list_shop_requests_by_owner_id,
+            &self.list_shop_requests_by_owner_id_bin,
+            &self.schema_translations,
+        ]
+    }
 }
@@ -0,0 +1,81 @@
+use uuid::Uuid;
+
+use crate::handlers::SchemaVersion;
+use crate::models::{
+    InteriorRefListQuery, Locale, MerchandiseChangeListQuery, MerchandiseListQuery, OwnerListQuery,
+    OwnerShopListQuery, ShopListQuery, ShopRequestListQuery, ShopReviewListQuery,
+    TransactionListQuery,
+};
+
+/// How a cache key is written to logs.
+///
+/// Every cache key type must implement this, so that a key which is (or contains) a credential
+/// such as an api key can't end up in the logs by accident. Most keys just log their `Debug`
+/// representation through `redact_with_debug!`.
+pub trait Redact {
+    fn redacted(&self) -> String;
+}
+
+macro_rules! redact_with_debug {
+    ($($key:ty),* $(,)?) => {
+        $(
+            impl Redact for $key {
+                fn redacted(&self) -> String {
+                    format!("{:?}", self)
+                }
+            }
+        )*
+    };
+}
+
+redact_with_debug!(
+    (),
+    i32,
+    Vec<i32>,
+    ShopListQuery,
+    OwnerListQuery,
+    OwnerShopListQuery,
+    InteriorRefListQuery,
+    MerchandiseListQuery,
+    TransactionListQuery,
+    MerchandiseChangeListQuery,
+    ShopReviewListQuery,
+    ShopRequestListQuery,
+    SchemaVersion,
+    Locale,
+    // Only ever an ETag.
+    String,
+);
+
+// Api keys are the only credential players have, so they are never logged.
+impl Redact for Uuid {
+    fn redacted(&self) -> String {
+        "<redacted>".to_string()
+    }
+}
+
+impl<A: Redact> Redact for Option<A> {
+    fn redacted(&self) -> String {
+        match self {
+            Some(value) => format!("Some({})", value.redacted()),
+            None => "None".to_string(),
+        }
+    }
+}
+
+impl<A: Redact, B: Redact> Redact for (A, B) {
+    fn redacted(&self) -> String {
+        format!("({}, {})", self.0.redacted(), self.1.redacted())
+    }
+}
+
+impl<A: Redact, B: Redact, C: Redact> Redact for (A, B, C) {
+    fn redacted(&self) -> String {
+        format!(
+            "({}, {}, {})",
+            self.0.redacted(),
+            self.1.redacted(),
+            self.2.redacted()
+        )
+    }
+}
@@ -0,0 +1,123 @@
+use std::future::Future;
+use std::hash::Hash;
+
+use anyhow::Result;
+
+use super::{Cache, Redact, Weigh};
+
+/// Whose view of a resource a cached response is. Responses that show the resource's owner more
+/// than everyone else (e.g. a shop's private notes) are cached per scope, so that the owner's view
+/// is never served to anyone else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ViewScope {
+    Public,
+    /// The view of the owner with this id, of a resource they own.
+    Owner(i32),
+}
+
+impl ViewScope {
+    /// The scope of `viewer_id`'s responses for a resource owned by `owner_id`.
+    pub fn of(viewer_id: Option<i32>, owner_id: i32) -> Self {
+        match viewer_id {
+            Some(viewer_id) if viewer_id == owner_id => ViewScope::Owner(viewer_id),
+            _ => ViewScope::Public,
+        }
+    }
+
+    /// Like `of`, but only looks up the resource's owner when there is a viewer to compare it to.
+    pub async fn resolve<G, F>(viewer_id: Option<i32>, owner_id: G) -> Result<Self>
+    where
+        G: FnOnce() -> F,
+        F: Future<Output = Result<i32>>,
+    {
+        match viewer_id {
+            Some(_) => Ok(Self::of(viewer_id, owner_id().await?)),
+            None => Ok(ViewScope::Public),
+        }
+    }
+
+    pub fn key<K>(self, key: K) -> Scoped<K> {
+        Scoped { key, scope: self }
+    }
+}
+
+/// The key of a response that depends on who is viewing it. See `ViewScope`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Scoped<K> {
+    pub key: K,
+    pub scope: ViewScope,
+}
+
+impl<K: Redact> Redact for Scoped<K> {
+    fn redacted(&self) -> String {
+        format!("{} as {:?}", self.key.redacted(), self.scope)
+    }
+}
+
+impl<K, V> Cache<Scoped<K>, V>
+where
+    K: Eq + Hash + Redact + Clone + Send + 'static,
+    V: Clone + Weigh + Send + 'static,
+{
+    /// Removes the entries for `key` in every scope, e.g. both the public and the owner's view of
+    /// a shop after the shop changed.
+    pub async fn delete_all_scopes(&self, key: &K) -> usize {
+        self.delete_where(|scoped| scoped.key == *key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use http::StatusCode;
+
+    use super::{Scoped, ViewScope};
+    use crate::caches::{Cache, CachedResponse};
+
+    async fn body(cache: &Cache<Scoped<i32>, CachedResponse>, key: Scoped<i32>) -> String {
+        let scope = key.scope;
+        let response = cache
+            .get_response(key, || async move {
+                let body = match scope {
+                    ViewScope::Public => "public".to_string(),
+                    ViewScope::Owner(owner_id) => format!("owner {}", owner_id),
+                };
+                Ok::<_, anyhow::Error>(warp::reply::with_status(body, StatusCode::OK))
+            })
+            .await
+            .unwrap();
+        String::from_utf8(response.body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn scopes_are_cached_apart_and_deleted_together() -> Result<()> {
+        let cache: Cache<Scoped<i32>, CachedResponse> = Cache::new("test", 10);
+        assert_eq!(body(&cache, ViewScope::Owner(7).key(1)).await, "owner 7");
+        // The owner's response was cached first, and still isn't what anyone else gets.
+        assert_eq!(body(&cache, ViewScope::Public.key(1)).await, "public");
+        assert_eq!(body(&cache, ViewScope::Owner(8).key(1)).await, "owner 8");
+        assert_eq!(body(&cache, ViewScope::Public.key(2)).await, "public");
+        // Responses are cached in the background after a miss.
+        tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        assert_eq!(cache.stats().entries, 4);
+
+        assert_eq!(cache.delete_all_scopes(&1).await, 3);
+        assert_eq!(cache.stats().entries, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_only_looks_up_the_owner_for_viewers() -> Result<()> {
+        let anonymous = ViewScope::resolve(None, || async { panic!("not looked up") }).await?;
+        assert_eq!(anonymous, ViewScope::Public);
+        assert_eq!(
+            ViewScope::resolve(Some(7), || async { Ok(7) }).await?,
+            ViewScope::Owner(7)
+        );
+        assert_eq!(
+            ViewScope::resolve(Some(8), || async { Ok(7) }).await?,
+            ViewScope::Public
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,255 @@
+use lru::LruCache;
+use std::fmt;
+use std::hash::Hash;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::CachedResponse;
+
+/// Roughly what a cached response takes up besides its body: the status, the header map (a
+/// content type, an ETag and a few more short headers) and the LRU's own bookkeeping.
+pub const RESPONSE_OVERHEAD_BYTES: usize = 512;
+
+/// Approximately how many bytes a cached value holds, for memory accounting.
+pub trait Weigh {
+    fn weight(&self) -> usize;
+}
+
+impl Weigh for i32 {
+    fn weight(&self) -> usize {
+        size_of::<i32>()
+    }
+}
+
+impl Weigh for CachedResponse {
+    fn weight(&self) -> usize {
+        self.body.len() + RESPONSE_OVERHEAD_BYTES
+    }
+}
+
+/// Running totals of a `SizedLru`, readable without taking the cache's lock. Only written while
+/// the lock is held.
+#[derive(Debug, Default)]
+pub struct Footprint {
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    inserts: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl Footprint {
+    pub fn entries(&self) -> u64 {
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Values put in since the cache was made, including ones that replaced a value for the same
+    /// key.
+    pub fn inserts(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed)
+    }
+
+    /// Least recently used entries dropped to make room for new ones. Deletes, clears and expired
+    /// entries don't count.
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+}
+
+/// An `LruCache` that keeps track of how many bytes its values weigh and when they were put in.
+/// `LruCache::put` drops the least recently used entry of a full cache without returning it, so
+/// `put` pops it first to take it off the total.
+pub struct SizedLru<K, V> {
+    lru: LruCache<K, Stamped<V>>,
+    footprint: Arc<Footprint>,
+}
+
+// By hand, since `LruCache` is only `Debug` for hashable keys. The entries are left out.
+impl<K: Eq + Hash, V> fmt::Debug for SizedLru<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SizedLru")
+            .field("len", &self.lru.len())
+            .field("cap", &self.lru.cap())
+            .field("footprint", &self.footprint)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct Stamped<V> {
+    value: V,
+    put_at: Instant,
+}
+
+impl<K, V> SizedLru<K, V>
+where
+    K: Eq + Hash,
+    V: Weigh,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            lru: LruCache::new(capacity),
+            footprint: Arc::new(Footprint::default()),
+        }
+    }
+
+    pub fn footprint(&self) -> Arc<Footprint> {
+        self.footprint.clone()
+    }
+
+    pub fn put(&mut self, key: K, value: V) {
+        if self.lru.cap() == 0 {
+            return;
+        }
+        if let Some(replaced) = self.lru.pop(&key) {
+            self.subtract(&replaced.value);
+        } else if self.lru.len() >= self.lru.cap() {
+            if let Some((_, evicted)) = self.lru.pop_lru() {
+                self.subtract(&evicted.value);
+                self.footprint.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.footprint
+            .bytes
+            .fetch_add(value.weight() as u64, Ordering::Relaxed);
+        self.footprint.inserts.fetch_add(1, Ordering::Relaxed);
+        self.lru.put(
+            key,
+            Stamped {
+                value,
+                put_at: Instant::now(),
+            },
+        );
+        self.sync_entries();
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.lru.get(key).map(|stamped| &stamped.value)
+    }
+
+    /// Removes the entry for `key` if it was put in at least `ttl` ago, and returns whether it did.
+    /// Without a `ttl` nothing expires.
+    pub fn pop_expired(&mut self, key: &K, ttl: Option<Duration>) -> bool {
+        let expired = match (ttl, self.lru.peek(key)) {
+            (Some(ttl), Some(stamped)) => stamped.put_at.elapsed() >= ttl,
+            _ => false,
+        };
+        if expired {
+            self.pop(key);
+        }
+        expired
+    }
+
+    pub fn pop(&mut self, key: &K) -> Option<V> {
+        let value = self.lru.pop(key)?.value;
+        self.subtract(&value);
+        self.sync_entries();
+        Some(value)
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.lru.contains(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lru.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.lru.iter().map(|(key, stamped)| (key, &stamped.value))
+    }
+
+    pub fn clear(&mut self) {
+        self.lru.clear();
+        self.footprint.bytes.store(0, Ordering::Relaxed);
+        self.sync_entries();
+    }
+
+    fn subtract(&self, value: &V) {
+        self.footprint
+            .bytes
+            .fetch_sub(value.weight() as u64, Ordering::Relaxed);
+    }
+
+    fn sync_entries(&self) {
+        self.footprint
+            .entries
+            .store(self.lru.len() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, StatusCode, Version};
+    use hyper::body::Bytes;
+    use std::time::{Duration, Instant};
+
+    use super::{SizedLru, RESPONSE_OVERHEAD_BYTES};
+    use crate::caches::CachedResponse;
+
+    fn response(body_len: usize) -> CachedResponse {
+        CachedResponse {
+            status: StatusCode::OK,
+            version: Version::HTTP_11,
+            headers: HeaderMap::new(),
+            body: Bytes::from(vec![b'x'; body_len]),
+            cached_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn totals_follow_puts_evictions_and_removals() {
+        let mut lru = SizedLru::new(2);
+        let footprint = lru.footprint();
+        let weight = |body_len: usize| (body_len + RESPONSE_OVERHEAD_BYTES) as u64;
+
+        lru.put(1, response(100));
+        lru.put(2, response(200));
+        assert_eq!(footprint.bytes(), weight(100) + weight(200));
+
+        // The cache is full, so this evicts key 1.
+        lru.put(3, response(300));
+        assert!(!lru.contains(&1));
+        assert_eq!(footprint.entries(), 2);
+        assert_eq!(footprint.bytes(), weight(200) + weight(300));
+        assert_eq!(footprint.evictions(), 1);
+
+        // Replacing a value doesn't evict anything.
+        lru.put(3, response(50));
+        assert!(lru.contains(&2));
+        assert_eq!(footprint.bytes(), weight(200) + weight(50));
+        assert_eq!(footprint.inserts(), 4);
+        assert_eq!(footprint.evictions(), 1);
+
+        lru.pop(&2);
+        assert_eq!(footprint.entries(), 1);
+        assert_eq!(footprint.bytes(), weight(50));
+        lru.clear();
+        assert_eq!(footprint.entries(), 0);
+        assert_eq!(footprint.bytes(), 0);
+    }
+    #[test]
+    fn only_entries_past_the_ttl_expire() {
+        let mut lru = SizedLru::new(2);
+        let footprint = lru.footprint();
+        lru.put(1, response(100));
+        assert!(!lru.pop_expired(&1, None));
+        assert!(!lru.pop_expired(&1, Some(Duration::from_secs(60))));
+        assert!(!lru.pop_expired(&2, Some(Duration::from_secs(0))));
+        assert!(lru.contains(&1));
+
+        assert!(lru.pop_expired(&1, Some(Duration::from_secs(0))));
+        assert!(!lru.contains(&1));
+        assert_eq!(footprint.entries(), 0);
+        assert_eq!(footprint.bytes(), 0);
+    }
+}
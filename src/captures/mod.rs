@@ -0,0 +1,256 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use http::header::HeaderMap;
+use http::Method;
+use hyper::body::{self, Body, Bytes};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+/// Bodies larger than this are truncated before being stored in a capture.
+pub const MAX_CAPTURED_BODY_BYTES: usize = 64 * 1024;
+pub const DEFAULT_CAPTURE_DURATION: Duration = Duration::from_secs(30 * 60);
+pub const MAX_CAPTURE_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+pub const DEFAULT_MAX_EVENTS: usize = 100;
+pub const MAX_EVENTS: usize = 1000;
+
+const REDACTED_HEADERS: &[&str] = &["api-key", "authorization", "cookie"];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedBody {
+    pub len: usize,
+    pub truncated: bool,
+    pub encoding: &'static str,
+    pub data: String,
+}
+
+impl CapturedBody {
+    fn new(bytes: &[u8]) -> Self {
+        let truncated = bytes.len() > MAX_CAPTURED_BODY_BYTES;
+        let kept = &bytes[..bytes.len().min(MAX_CAPTURED_BODY_BYTES)];
+        let (encoding, data) = match std::str::from_utf8(kept) {
+            Ok(text) => ("utf8", text.to_string()),
+            Err(_) => {
+                // bincode bodies are not valid UTF-8
+                let mut hex = String::with_capacity(kept.len() * 2);
+                for byte in kept {
+                    write!(hex, "{:02x}", byte).expect("writing to a String cannot fail");
+                }
+                ("hex", hex)
+            }
+        };
+        Self {
+            len: bytes.len(),
+            truncated,
+            encoding,
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CapturedEvent {
+    Request {
+        recorded_at: NaiveDateTime,
+        method: String,
+        path: String,
+        headers: BTreeMap<String, String>,
+        body: CapturedBody,
+    },
+    Response {
+        recorded_at: NaiveDateTime,
+        method: String,
+        path: String,
+        status: u16,
+        headers: BTreeMap<String, String>,
+        body: CapturedBody,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Capture {
+    pub owner_id: i32,
+    pub started_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub max_events: usize,
+    pub events: VecDeque<CapturedEvent>,
+    #[serde(skip)]
+    deadline: Instant,
+}
+
+impl Capture {
+    fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    fn push(&mut self, event: CapturedEvent) {
+        while self.events.len() >= self.max_events {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+/// In-memory ring buffers of request/response bodies for owners that an admin has asked to capture.
+///
+/// Captures expire on their own after their duration, so a forgotten capture does not keep
+/// recording a player's traffic indefinitely.
+#[derive(Debug, Default)]
+pub struct CaptureStore {
+    captures: Mutex<HashMap<i32, Capture>>,
+}
+
+impl CaptureStore {
+    pub async fn start(&self, owner_id: i32, duration: Duration, max_events: usize) -> Capture {
+        let now = Utc::now().naive_utc();
+        let capture = Capture {
+            owner_id,
+            started_at: now,
+            expires_at: now
+                + chrono::Duration::from_std(duration).expect("duration is bounded by handler"),
+            max_events,
+            events: VecDeque::with_capacity(max_events),
+            deadline: Instant::now() + duration,
+        };
+        info!(owner_id, ?duration, max_events, "starting capture");
+        self.captures.lock().await.insert(owner_id, capture.clone());
+        capture
+    }
+
+    pub async fn get(&self, owner_id: i32) -> Option<Capture> {
+        let mut captures = self.captures.lock().await;
+        captures.retain(|_, capture| !capture.is_expired());
+        captures.get(&owner_id).cloned()
+    }
+
+    pub async fn stop(&self, owner_id: i32) -> Option<Capture> {
+        info!(owner_id, "stopping capture");
+        self.captures.lock().await.remove(&owner_id)
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        let mut captures = self.captures.lock().await;
+        captures.retain(|_, capture| !capture.is_expired());
+        captures.is_empty()
+    }
+
+    pub async fn is_capturing(&self, owner_id: i32) -> bool {
+        self.captures
+            .lock()
+            .await
+            .get(&owner_id)
+            .is_some_and(|capture| !capture.is_expired())
+    }
+
+    async fn record(&self, owner_id: i32, event: CapturedEvent) {
+        let mut captures = self.captures.lock().await;
+        if let Some(capture) = captures.get_mut(&owner_id) {
+            if capture.is_expired() {
+                captures.remove(&owner_id);
+            } else {
+                debug!(owner_id, "recording captured event");
+                capture.push(event);
+            }
+        }
+    }
+}
+
+/// A request made by an owner who is currently being captured.
+#[derive(Debug, Clone)]
+pub struct CaptureContext {
+    pub store: Arc<CaptureStore>,
+    pub owner_id: i32,
+    pub method: Method,
+    pub path: String,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl CaptureContext {
+    pub fn new(
+        store: Arc<CaptureStore>,
+        owner_id: i32,
+        method: Method,
+        path: String,
+        headers: &HeaderMap,
+    ) -> Self {
+        Self {
+            store,
+            owner_id,
+            method,
+            path,
+            headers: redact_headers(headers),
+        }
+    }
+
+    pub async fn record_request(&self, body: &[u8]) {
+        self.store
+            .record(
+                self.owner_id,
+                CapturedEvent::Request {
+                    recorded_at: Utc::now().naive_utc(),
+                    method: self.method.to_string(),
+                    path: self.path.clone(),
+                    headers: self.headers.clone(),
+                    body: CapturedBody::new(body),
+                },
+            )
+            .await;
+    }
+}
+
+fn redact_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                "[redacted]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Buffers the response body so it can be recorded when the request belongs to a captured owner.
+/// Must run before compression so that the stored body is readable.
+pub async fn record_response(
+    context: Option<CaptureContext>,
+    reply: impl Reply,
+) -> Result<Response, Rejection> {
+    let response = reply.into_response();
+    let context = match context {
+        Some(context) => context,
+        None => return Ok(response),
+    };
+    let (parts, body) = response.into_parts();
+    let bytes = match body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            warn!(owner_id = context.owner_id, %error, "failed to buffer response body for capture");
+            Bytes::new()
+        }
+    };
+    context
+        .store
+        .record(
+            context.owner_id,
+            CapturedEvent::Response {
+                recorded_at: Utc::now().naive_utc(),
+                method: context.method.to_string(),
+                path: context.path.clone(),
+                status: parts.status.as_u16(),
+                headers: redact_headers(&parts.headers),
+                body: CapturedBody::new(&bytes),
+            },
+        )
+        .await;
+    Ok(Response::from_parts(parts, Body::from(bytes)))
+}
@@ -0,0 +1,86 @@
+//! The server's clock, as clients see it.
+//!
+//! Every response carries `X-Server-Time` (RFC 3339, UTC) so that clients can work out how far
+//! off their own clock is. Absolute timestamps clients send (e.g. a `since` filter) are checked
+//! against it: one further in the future than `CLOCK_SKEW_TOLERANCE_SECS` can only come from a
+//! wrong clock, and would otherwise quietly match nothing, like an incremental sync that never
+//! finds anything new.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::prelude::*;
+use http::header::HeaderValue;
+use warp::reply::Response;
+
+use crate::problem::clock_skew;
+
+pub const SERVER_TIME: &str = "x-server-time";
+
+/// The server's clock as sent in `X-Server-Time` and `clock_skew` problems.
+pub fn format_server_time(now: DateTime<Utc>) -> String {
+    now.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Stamps `response` with the time it is sent. Added to the finished response rather than when a
+/// reply is built, since cached replies are sent again long after they were built.
+pub fn with_server_time(mut response: Response) -> Response {
+    let now = format_server_time(Utc::now());
+    response.headers_mut().insert(
+        SERVER_TIME,
+        HeaderValue::from_str(&now).expect("RFC 3339 timestamps are valid header values"),
+    );
+    response
+}
+
+/// Fails with a 400 `clock_skew` problem if the `param` the client sent is more than `tolerance`
+/// ahead of the server's clock.
+pub fn check_not_ahead(
+    param: &'static str,
+    value: Option<NaiveDateTime>,
+    tolerance: Duration,
+) -> Result<()> {
+    check_not_ahead_of(Utc::now(), param, value, tolerance)
+}
+
+fn check_not_ahead_of(
+    now: DateTime<Utc>,
+    param: &'static str,
+    value: Option<NaiveDateTime>,
+    tolerance: Duration,
+) -> Result<()> {
+    let latest = now.naive_utc() + chrono::Duration::seconds(tolerance.as_secs() as i64);
+    match value {
+        Some(value) if value > latest => Err(clock_skew(param, value, now)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::prelude::*;
+    use http::StatusCode;
+
+    use super::check_not_ahead_of;
+    use crate::problem::from_anyhow;
+
+    #[test]
+    fn timestamps_past_the_tolerance_are_clock_skew() {
+        let now = Utc.ymd(2026, 10, 17).and_hms(12, 0, 0);
+        let tolerance = Duration::from_secs(300);
+        let at = |minutes| Some(now.naive_utc() + chrono::Duration::minutes(minutes));
+        assert!(check_not_ahead_of(now, "since", None, tolerance).is_ok());
+        assert!(check_not_ahead_of(now, "since", at(-60), tolerance).is_ok());
+        assert!(check_not_ahead_of(now, "since", at(5), tolerance).is_ok());
+
+        let error = check_not_ahead_of(now, "since", at(6), tolerance).unwrap_err();
+        let problem = from_anyhow(error);
+        assert_eq!(problem.status, Some(StatusCode::BAD_REQUEST));
+        let problem = serde_json::to_value(&problem).unwrap();
+        assert_eq!(problem["code"], "clock_skew");
+        assert_eq!(problem["param"], "since");
+        assert_eq!(problem["server_time"], "2026-10-17T12:00:00.000Z");
+    }
+}
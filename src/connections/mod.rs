@@ -0,0 +1,629 @@
+//! Serves accepted connections with the idle, lifetime, and header read limits of
+//! `ConnectionSettings`, which hyper 0.13 has no builder options for.
+//!
+//! Each connection's requests and socket reads are tracked as they happen, and a watchdog next to
+//! the connection checks them against the limits: idle connections and stalled request heads are
+//! dropped, and connections past their maximum age are drained with a graceful shutdown so that a
+//! request in flight still gets its response. On shutdown every open connection is drained the
+//! same way: HTTP/1.1 connections close after their current response, HTTP/2 ones send GOAWAY and
+//! close once their open streams finish.
+
+use std::convert::Infallible;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, Shared};
+use futures::{Future, FutureExt};
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::config::ConnectionSettings;
+use crate::tasks::SHUTDOWN_DRAIN_TIMEOUT;
+
+// How often the watchdog looks at a connection, as a fraction of its shortest limit.
+const CHECKS_PER_LIMIT: u32 = 4;
+const MIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0";
+// `Activity::request_started` while no request head is being read.
+const NOT_STARTED: u64 = u64::MAX;
+
+/// HTTP/1.1, plus HTTP/2 with prior knowledge (h2c) when `enable_h2c` is set, for the plaintext
+/// port. h2c is opt-in for when a reverse proxy in front of the API speaks h2 to it.
+pub fn plaintext_http(enable_h2c: bool) -> Http {
+    let mut http = Http::new();
+    http.http1_only(!enable_h2c);
+    http
+}
+
+/// Accepts plaintext connections and serves each one with `service` on its own task until
+/// `shutdown` resolves, then stops accepting and drains the open connections.
+pub async fn serve<S>(
+    mut listener: TcpListener,
+    http: Http,
+    settings: ConnectionSettings,
+    service: S,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let connections = OpenConnections::default();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    warn!("failed to accept TCP connection: {}", error);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        set_nodelay(&stream, &settings);
+        let http = http.clone();
+        let service = service.clone();
+        let settings = settings.clone();
+        let open = connections.open();
+        tokio::spawn(async move {
+            if let Err(error) =
+                serve_connection(stream, &http, service, &settings, false, open).await
+            {
+                debug!(%remote_addr, "error serving connection: {}", error);
+            }
+        });
+    }
+    drop(listener);
+    connections.drain(SHUTDOWN_DRAIN_TIMEOUT).await;
+    Ok(())
+}
+
+/// The connections a listener accepted, so that they can be drained on shutdown rather than
+/// dropped in the middle of a response.
+pub struct OpenConnections {
+    stop: oneshot::Sender<()>,
+    stopping: Shared<oneshot::Receiver<()>>,
+    open: mpsc::Sender<()>,
+    closed: mpsc::Receiver<()>,
+}
+
+impl Default for OpenConnections {
+    fn default() -> Self {
+        let (stop, stopping) = oneshot::channel();
+        let (open, closed) = mpsc::channel(1);
+        Self {
+            stop,
+            stopping: stopping.shared(),
+            open,
+            closed,
+        }
+    }
+}
+
+impl OpenConnections {
+    /// Held by a connection's task for as long as it serves the connection.
+    pub fn open(&self) -> OpenConnection {
+        OpenConnection {
+            stopping: self.stopping.clone(),
+            _open: self.open.clone(),
+        }
+    }
+
+    /// Starts a graceful shutdown of every open connection and waits up to `timeout` for them to
+    /// close. Connections still open after that are dropped along with the runtime.
+    pub async fn drain(self, timeout: Duration) {
+        let Self {
+            stop,
+            open,
+            mut closed,
+            ..
+        } = self;
+        let _ = stop.send(());
+        drop(open);
+        if tokio::time::timeout(timeout, closed.recv()).await.is_err() {
+            warn!(
+                timeout_secs = timeout.as_secs(),
+                "connections still open after the shutdown timeout"
+            );
+        } else {
+            info!("closed all connections");
+        }
+    }
+}
+
+pub struct OpenConnection {
+    stopping: Shared<oneshot::Receiver<()>>,
+    _open: mpsc::Sender<()>,
+}
+
+pub fn set_nodelay(stream: &TcpStream, settings: &ConnectionSettings) {
+    if settings.tcp_nodelay {
+        if let Err(error) = stream.set_nodelay(true) {
+            debug!("cannot set TCP_NODELAY: {}", error);
+        }
+    }
+}
+
+/// Serves one connection until the client closes it, it breaks one of the limits, or it has been
+/// drained after reaching its maximum age or because the server is shutting down. `is_h2` is for
+/// connections already known to be HTTP/2, e.g. from ALPN; HTTP/2 connections have no request heads
+/// to time out.
+pub async fn serve_connection<I, S>(
+    io: I,
+    http: &Http,
+    service: S,
+    settings: &ConnectionSettings,
+    is_h2: bool,
+    open: OpenConnection,
+) -> hyper::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let check_interval = settings.check_interval();
+    let activity = Arc::new(Activity::new(is_h2));
+    let io = TrackedIo {
+        inner: io,
+        activity: activity.clone(),
+    };
+    let service = TrackedService {
+        inner: service,
+        activity: activity.clone(),
+    };
+    let connection = http.serve_connection(io, service);
+    tokio::pin!(connection);
+    let mut stopping = open.stopping;
+    let mut draining = false;
+    loop {
+        tokio::select! {
+            result = &mut connection => return result,
+            _ = &mut stopping, if !draining => {
+                debug!("draining connection for shutdown");
+                connection.as_mut().graceful_shutdown();
+                draining = true;
+                continue;
+            }
+            _ = tokio::time::delay_for(check_interval.unwrap_or_default()),
+                if check_interval.is_some() => {}
+        }
+        match activity.check(settings) {
+            Check::Open => {}
+            Check::Drain if draining => {}
+            Check::Drain => {
+                debug!("draining connection past its maximum age");
+                connection.as_mut().graceful_shutdown();
+                draining = true;
+            }
+            Check::Close(reason) => {
+                debug!(reason, "closing connection");
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl ConnectionSettings {
+    // `None` when no limit is set, so connections are never checked.
+    fn check_interval(&self) -> Option<Duration> {
+        let shortest = [
+            self.keepalive_timeout,
+            self.max_connection_age,
+            self.header_read_timeout,
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied()?;
+        Some((shortest / CHECKS_PER_LIMIT).max(MIN_CHECK_INTERVAL))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Check {
+    Open,
+    Drain,
+    Close(&'static str),
+}
+
+// What a connection has been doing, in milliseconds since it was accepted.
+#[derive(Debug)]
+struct Activity {
+    accepted: Instant,
+    in_flight: AtomicUsize,
+    // When the last response finished or the last byte was written, whichever is later.
+    last_active: AtomicU64,
+    // When the first byte of a request head arrived, if one is being read.
+    request_started: AtomicU64,
+    read_any: AtomicBool,
+    is_h2: AtomicBool,
+}
+
+impl Activity {
+    fn new(is_h2: bool) -> Self {
+        Self {
+            accepted: Instant::now(),
+            in_flight: AtomicUsize::new(0),
+            last_active: AtomicU64::new(0),
+            request_started: AtomicU64::new(NOT_STARTED),
+            read_any: AtomicBool::new(false),
+            is_h2: AtomicBool::new(is_h2),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.accepted.elapsed().as_millis() as u64
+    }
+
+    fn read(&self, bytes: &[u8]) {
+        if !self.read_any.swap(true, Ordering::Relaxed) && bytes.starts_with(H2_PREFACE) {
+            // h2c with prior knowledge.
+            self.is_h2.store(true, Ordering::Relaxed);
+        }
+        if self.in_flight.load(Ordering::Relaxed) == 0 {
+            let _ = self.request_started.compare_exchange(
+                NOT_STARTED,
+                self.now(),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn wrote(&self) {
+        self.last_active.store(self.now(), Ordering::Relaxed);
+    }
+
+    fn request_received(&self) {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.request_started.store(NOT_STARTED, Ordering::Relaxed);
+    }
+
+    fn response_finished(&self) {
+        self.last_active.store(self.now(), Ordering::Relaxed);
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn check(&self, settings: &ConnectionSettings) -> Check {
+        let now = Duration::from_millis(self.now());
+        let since = |millis: u64| now.saturating_sub(Duration::from_millis(millis));
+        if self.in_flight.load(Ordering::Relaxed) == 0 {
+            let request_started = self.request_started.load(Ordering::Relaxed);
+            let reading_head =
+                request_started != NOT_STARTED && !self.is_h2.load(Ordering::Relaxed);
+            if reading_head {
+                if settings
+                    .header_read_timeout
+                    .is_some_and(|timeout| since(request_started) > timeout)
+                {
+                    return Check::Close("request head took too long to read");
+                }
+            } else if settings
+                .keepalive_timeout
+                .is_some_and(|timeout| since(self.last_active.load(Ordering::Relaxed)) > timeout)
+            {
+                return Check::Close("idle for longer than the keepalive timeout");
+            }
+        }
+        if settings.max_connection_age.is_some_and(|age| now > age) {
+            return Check::Drain;
+        }
+        Check::Open
+    }
+}
+
+struct TrackedIo<I> {
+    inner: I,
+    activity: Arc<Activity>,
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for TrackedIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = &poll {
+            if *read > 0 {
+                self.activity.read(&buf[..*read]);
+            }
+        }
+        poll
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for TrackedIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = &poll {
+            if *written > 0 {
+                self.activity.wrote();
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+struct TrackedService<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> Service<Request<Body>> for TrackedService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response<Body>, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        self.activity.request_received();
+        let activity = self.activity.clone();
+        let response = self.inner.call(request);
+        async move {
+            let response = response.await;
+            activity.response_finished();
+            response
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::io::Read;
+    use std::time::{Duration, Instant};
+
+    use flate2::read::GzDecoder;
+    use futures::channel::oneshot;
+    use futures::{future, FutureExt};
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::{Body, Client, Request, Response, StatusCode, Version};
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use warp::Filter;
+
+    use super::{plaintext_http, serve};
+    use crate::config::{Config, ConnectionSettings};
+    use crate::routes;
+    use crate::test_support::{TestEnv, OWNER_API_KEY};
+
+    const ADMIN_API_KEY: &str = "33333333-3333-3333-3333-333333333333";
+
+    async fn hello(_: Request<Body>) -> Result<Response<Body>, Infallible> {
+        Ok(Response::new(Body::from("hello")))
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed_after_the_keepalive_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let settings = ConnectionSettings {
+            keepalive_timeout: Some(Duration::from_millis(200)),
+            ..ConnectionSettings::default()
+        };
+        tokio::spawn(serve(
+            listener,
+            Http::new(),
+            settings,
+            service_fn(hello),
+            future::pending(),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut response = [0; 1024];
+        for _ in 0..2 {
+            // Kept alive between requests that come quicker than the timeout.
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let read = stream.read(&mut response).await.unwrap();
+            assert!(response[..read].starts_with(b"HTTP/1.1 200 OK"));
+            assert!(response[..read].ends_with(b"hello"));
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+
+        let closed = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut response))
+            .await
+            .expect("the server closes the idle connection");
+        assert_eq!(closed.unwrap(), 0);
+    }
+
+    // Configured like `main` does it, through `HTTP_KEEPALIVE_TIMEOUT_SECS`, and serving a warp
+    // filter rather than a bare hyper service.
+    #[tokio::test]
+    async fn configured_keepalive_closes_idle_warp_connections() {
+        let config = Config::from_lookup(|key| {
+            match key {
+                "DATABASE_URL" => Some("postgres://localhost/bazaar"),
+                "HOST" => Some("http://localhost:3030"),
+                "HTTP_KEEPALIVE_TIMEOUT_SECS" => Some("1"),
+                _ => None,
+            }
+            .map(str::to_string)
+        })
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let routes = warp::any().map(|| "hello");
+        tokio::spawn(serve(
+            listener,
+            Http::new(),
+            config.connections.clone(),
+            warp::service(routes),
+            future::pending(),
+        ));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut response = [0; 1024];
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let read = stream.read(&mut response).await.unwrap();
+        assert!(response[..read].starts_with(b"HTTP/1.1 200 OK"));
+
+        // Still open well within the timeout...
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), stream.read(&mut response))
+                .await
+                .is_err()
+        );
+        // ...and closed by the server once the connection has been idle for longer.
+        let closed = tokio::time::timeout(Duration::from_secs(3), stream.read(&mut response))
+            .await
+            .expect("the server closes the idle connection");
+        assert_eq!(closed.unwrap(), 0);
+    }
+
+    fn gunzip(bytes: &[u8]) -> String {
+        let mut body = String::new();
+        GzDecoder::new(bytes).read_to_string(&mut body).unwrap();
+        body
+    }
+
+    fn h2_client() -> Client<hyper::client::HttpConnector> {
+        Client::builder().http2_only(true).build_http()
+    }
+
+    // Served like `main` does it with `ENABLE_H2C` set, to a client that only speaks HTTP/2.
+    #[tokio::test]
+    async fn h2c_responses_are_compressed_and_exports_stream() {
+        let test = match TestEnv::with_config(&[
+            ("ENABLE_H2C", "true"),
+            ("ADMIN_API_KEYS", ADMIN_API_KEY),
+        ])
+        .await
+        {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        // Enough rows for the export to be sent in more than one chunk.
+        sqlx::query(
+            "INSERT INTO shops (name, owner_id, description, created_at, updated_at)
+            SELECT 'Shop ' || n, $1, repeat('x', 4000), now(), now()
+            FROM generate_series(1, 40) n",
+        )
+        .bind(owner_id as i32)
+        .execute(&test.env.db)
+        .await
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            plaintext_http(test.env.config.enable_h2c),
+            test.env.config.connections.clone(),
+            warp::service(routes(test.env.clone()).with(warp::compression::gzip())),
+            future::pending(),
+        ));
+        let client = h2_client();
+
+        let request = Request::get(format!("http://{}/v1/shops?limit=1", addr))
+            .header("accept-encoding", "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.version(), Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let shops: Value = serde_json::from_str(&gunzip(&body)).unwrap();
+        assert_eq!(shops.as_array().unwrap().len(), 1);
+
+        let request = Request::get(format!("http://{}/v1/admin/export", addr))
+            .header("api-key", ADMIN_API_KEY)
+            .header("accept-encoding", "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.version(), Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("content-length").is_none());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let archive = gunzip(&body);
+        let end: Value = serde_json::from_str(archive.lines().last().unwrap()).unwrap();
+        assert_eq!(end["type"], "end");
+        assert_eq!(end["rows"]["shops"], json!(40));
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_h2_requests_in_flight_finish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let slow = warp::path("slow")
+            .and_then(|| async {
+                tokio::time::delay_for(Duration::from_millis(300)).await;
+                Ok::<_, Infallible>("finished")
+            })
+            .with(warp::compression::gzip());
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            plaintext_http(true),
+            ConnectionSettings::default(),
+            warp::service(slow),
+            stopped.map(|_| ()),
+        ));
+
+        let started = Instant::now();
+        let response =
+            tokio::spawn(h2_client().get(format!("http://{}/slow", addr).parse().unwrap()));
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        stop.send(()).unwrap();
+
+        let response = response.await.unwrap().unwrap();
+        assert_eq!(response.version(), Version::HTTP_2);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(gunzip(&body), "finished");
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // `serve` returns once the drained connection has closed, and takes no new ones.
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("the server finishes draining")
+            .unwrap()
+            .unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,523 @@
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures::Stream;
+use hyper::body::{Body, Bytes};
+use mime::Mime;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::reply::{json, with_header, with_status};
+use warp::{Buf, Rejection, Reply};
+
+use http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use http::StatusCode;
+
+use crate::anomalies::{self, Anomaly, AnomalyCode};
+use crate::archive;
+use crate::caches::InvalidationEvent;
+use crate::captures::{
+    DEFAULT_CAPTURE_DURATION, DEFAULT_MAX_EVENTS, MAX_CAPTURE_DURATION, MAX_EVENTS,
+};
+use crate::maintenance::MaintenanceMode;
+use crate::models::{Deadline, ShopReconciliation};
+use crate::problem::{
+    invalid_query_param, not_found, reject_anyhow, unprocessable_entity, ValidationError,
+};
+use crate::Environment;
+
+use super::{authenticate_admin, DeserializedBody};
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PostedCapture {
+    pub duration_secs: Option<u64>,
+    pub max_events: Option<usize>,
+}
+
+pub async fn create_capture(
+    owner_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let posted = if bytes.is_empty() {
+        PostedCapture::default()
+    } else {
+        DeserializedBody::<PostedCapture>::from_bytes(bytes, content_type)
+            .map_err(reject_anyhow)?
+            .body
+    };
+    let duration = posted
+        .duration_secs
+        .map_or(DEFAULT_CAPTURE_DURATION, Duration::from_secs);
+    let max_events = posted.max_events.unwrap_or(DEFAULT_MAX_EVENTS);
+    let mut errors = vec![];
+    if duration.as_secs() == 0 || duration > MAX_CAPTURE_DURATION {
+        errors.push(ValidationError::new(
+            "duration_secs",
+            format!("must be between 1 and {}", MAX_CAPTURE_DURATION.as_secs()),
+        ));
+    }
+    if max_events == 0 || max_events > MAX_EVENTS {
+        errors.push(ValidationError::new(
+            "max_events",
+            format!("must be between 1 and {}", MAX_EVENTS),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(reject_anyhow(unprocessable_entity(errors)));
+    }
+    let capture = env.captures.start(owner_id, duration, max_events).await;
+    Ok(with_status(json(&capture), StatusCode::CREATED))
+}
+
+pub async fn get_capture(
+    owner_id: i32,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let capture = env
+        .captures
+        .get(owner_id)
+        .await
+        .ok_or_else(|| reject_anyhow(not_found("No active capture for that owner")))?;
+    Ok(json(&capture))
+}
+
+pub async fn delete_capture(
+    owner_id: i32,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    env.captures
+        .stop(owner_id)
+        .await
+        .ok_or_else(|| reject_anyhow(not_found("No active capture for that owner")))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ReconcileParams {
+    pub apply: Option<bool>,
+}
+
+pub async fn reconcile_shop(
+    shop_id: i32,
+    params: ReconcileParams,
+    api_key: Option<Uuid>,
+    deadline: Deadline,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reconciliation = ShopReconciliation::run(
+        &mut tx,
+        shop_id,
+        params.apply.unwrap_or(false),
+        Some(&deadline),
+    )
+    .await
+    .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    if !reconciliation.drift.is_empty() {
+        anomalies::report(Anomaly::new(
+            AnomalyCode::QuantityDrift,
+            shop_id,
+            format!(
+                "{} item(s) drifted from the shop's transactions, {} repairable, applied: {}",
+                reconciliation.drift.len(),
+                reconciliation
+                    .drift
+                    .iter()
+                    .filter(|item| item.repairable)
+                    .count(),
+                reconciliation.applied
+            ),
+        ));
+    }
+    if reconciliation.applied {
+        env.caches
+            .invalidate(InvalidationEvent::MerchandiseReconciled { shop_id })
+            .await;
+    }
+    Ok(json(&reconciliation))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MaintenanceSettings {
+    pub mode: MaintenanceMode,
+}
+
+pub async fn get_maintenance(
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    Ok(json(&MaintenanceSettings {
+        mode: env.maintenance.mode().await,
+    }))
+}
+
+pub async fn update_maintenance(
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let settings = DeserializedBody::<MaintenanceSettings>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?
+        .body;
+    env.maintenance.set_mode(settings.mode).await;
+    Ok(json(&settings))
+}
+
+pub async fn get_config(api_key: Option<Uuid>, env: Environment) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    Ok(json(&env.config.snapshot(
+        env.schema_status.migration_version,
+        env.maintenance.mode().await,
+        &env.caches,
+    )))
+}
+
+pub async fn export(api_key: Option<Uuid>, env: Environment) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let body = Body::wrap_stream(archive::export(
+        env.db.clone(),
+        env.schema_status.migration_version,
+    ));
+    let reply = with_header(
+        warp::reply::Response::new(body),
+        CONTENT_TYPE,
+        archive::CONTENT_TYPE,
+    );
+    let reply = with_header(
+        reply,
+        CONTENT_DISPOSITION,
+        "attachment; filename=\"bazaar_realm_export.ndjson\"",
+    );
+    Ok(reply)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportParams {
+    /// `replace` deletes everything in the database before importing.
+    pub merge: Option<String>,
+}
+
+pub async fn import<S, B>(
+    params: ImportParams,
+    api_key: Option<Uuid>,
+    body: S,
+    env: Environment,
+) -> Result<impl Reply, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let replace = match params.merge.as_deref() {
+        None => false,
+        Some("replace") => true,
+        Some(_) => {
+            return Err(reject_anyhow(invalid_query_param(
+                "merge",
+                "the only merge strategy is replace",
+            )))
+        }
+    };
+    let summary =
+        archive::import(&env.db, body, env.schema_status.migration_version, replace).await;
+    // Even a failed import may have changed rows that are cached.
+    env.caches.clear_all().await;
+    Ok(json(&summary.map_err(reject_anyhow)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::http::header::RETRY_AFTER;
+    use warp::http::StatusCode;
+
+    use crate::test_support::{
+        assert_problem, json_body, json_request, request, TestEnv, OTHER_OWNER_API_KEY,
+        OWNER_API_KEY,
+    };
+
+    const ADMIN_API_KEY: &str = "33333333-3333-3333-3333-333333333333";
+
+    #[tokio::test]
+    async fn maintenance_levels_are_toggled_at_runtime() {
+        let test = match TestEnv::with_config(&[
+            ("ADMIN_API_KEYS", ADMIN_API_KEY),
+            ("MAINTENANCE_RETRY_AFTER_SECS", "120"),
+        ])
+        .await
+        {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let shop_path = format!("/v1/shops/{}", shop_id);
+        let set_mode = |api_key, mode| {
+            json_request(
+                "PATCH",
+                "/v1/admin/maintenance",
+                Some(api_key),
+                &json!({ "mode": mode }),
+            )
+        };
+        let create_shop = || {
+            json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Another Shop" }),
+            )
+        };
+        let status_mode = || async {
+            let response = test.send(request("GET", "/v1/status", None)).await;
+            json_body(&response)["maintenance_mode"].clone()
+        };
+
+        let response = test.send(set_mode(OWNER_API_KEY, "full")).await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        assert_eq!(status_mode().await, "off");
+
+        let response = test.send(set_mode(ADMIN_API_KEY, "read_only")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(status_mode().await, "read_only");
+        let response = test.send(request("GET", &shop_path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        for write in [
+            create_shop(),
+            request("DELETE", &shop_path, Some(OWNER_API_KEY)),
+        ] {
+            let response = test.send(write).await;
+            let problem = assert_problem(&response, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(problem["code"], "maintenance_read_only");
+            assert_eq!(response.headers()[RETRY_AFTER], "120");
+        }
+
+        let response = test.send(set_mode(ADMIN_API_KEY, "full")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test.send(request("GET", &shop_path, None)).await;
+        let problem = assert_problem(&response, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(problem["code"], "maintenance_full");
+        assert_eq!(response.headers()[RETRY_AFTER], "120");
+        // Status and the maintenance endpoints stay up, so the mode can be turned back off.
+        assert_eq!(status_mode().await, "full");
+        let response = test
+            .send(request("GET", "/v1/admin/maintenance", Some(ADMIN_API_KEY)))
+            .await;
+        assert_eq!(json_body(&response)["mode"], "full");
+
+        let response = test.send(set_mode(ADMIN_API_KEY, "off")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test.send(create_shop()).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_starts_from_the_config() {
+        let test = match TestEnv::with_config(&[("MAINTENANCE_MODE", "read_only")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        let response = test.send(request("GET", "/v1/shops", None)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Owner", "mod_version": 1 }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(problem["code"], "maintenance_read_only");
+    }
+
+    #[tokio::test]
+    async fn reconciling_repairs_drift_and_keeps_manual_additions() {
+        let test = match TestEnv::with_config(&[("ADMIN_API_KEYS", ADMIN_API_KEY)]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let item = |local_form_id: i32, name: &str, quantity: i32| {
+            json!({
+                "mod_name": "Skyrim.esm",
+                "local_form_id": local_form_id,
+                "name": name,
+                "quantity": quantity,
+                "form_type": 46,
+                "is_food": true,
+                "price": 4,
+                "keywords": [],
+            })
+        };
+        let list_path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        // Added by hand rather than through transactions, so they are each item's baseline.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &list_path,
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "form_list": [
+                    item(1, "Cabbage", 3),
+                    item(2, "Potato", 5),
+                ] }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let mut transaction_ids = vec![];
+        for (local_form_id, name, is_sell, quantity) in
+            &[(1, "Cabbage", false, 1), (3, "Leek", true, 2)]
+        {
+            let mut transaction = item(*local_form_id, name, *quantity);
+            transaction["shop_id"] = json!(shop_id);
+            transaction["is_sell"] = json!(is_sell);
+            transaction["amount"] = json!(4 * quantity);
+            let response = test
+                .send(json_request(
+                    "POST",
+                    "/v1/transactions",
+                    Some(OTHER_OWNER_API_KEY),
+                    &transaction,
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            transaction_ids.push(json_body(&response)["transaction"]["id"].as_i64().unwrap());
+        }
+        let reconcile = |apply: bool| {
+            request(
+                "POST",
+                &format!("/v1/admin/shops/{}/reconcile?apply={}", shop_id, apply),
+                Some(ADMIN_API_KEY),
+            )
+        };
+        let quantities = |list: &Value| -> Vec<(Value, i64)> {
+            list["form_list"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| {
+                    (
+                        item["local_form_id"].clone(),
+                        item["quantity"].as_i64().unwrap(),
+                    )
+                })
+                .collect()
+        };
+
+        let response = test.send(reconcile(false)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let report = json_body(&response);
+        assert_eq!(report["transactions_replayed"], 2);
+        assert_eq!(report["drift"], json!([]));
+        let response = test
+            .send(request(
+                "POST",
+                &format!("/v1/admin/shops/{}/reconcile", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+
+        // Deleting transactions doesn't reverse them, so the list drifts from the history.
+        for id in &transaction_ids {
+            let response = test
+                .send(request(
+                    "DELETE",
+                    &format!("/v1/transactions/{}", id),
+                    Some(OTHER_OWNER_API_KEY),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT, "{:?}", response);
+        }
+        let response = test.send(reconcile(false)).await;
+        let report = json_body(&response);
+        assert_eq!(report["applied"], false);
+        assert_eq!(report["transactions_replayed"], 0);
+        let drift = report["drift"].as_array().unwrap();
+        assert_eq!(drift.len(), 2, "{:?}", drift);
+        assert_eq!(drift[0]["local_form_id"], "0x00000001");
+        assert_eq!(drift[0]["actual_quantity"], 2);
+        assert_eq!(drift[0]["recorded_quantity"], -1);
+        assert_eq!(drift[0]["transactions_quantity"], 0);
+        assert_eq!(drift[0]["expected_quantity"], 3);
+        assert_eq!(drift[1]["local_form_id"], "0x00000003");
+        assert_eq!(drift[1]["actual_quantity"], 2);
+        assert_eq!(drift[1]["expected_quantity"], 0);
+        assert!(drift.iter().all(|item| item["repairable"] == true));
+        let list = json_body(&test.send(request("GET", &list_path, None)).await);
+        assert_eq!(
+            quantities(&list),
+            vec![
+                (json!("0x00000001"), 2),
+                (json!("0x00000002"), 5),
+                (json!("0x00000003"), 2),
+            ]
+        );
+
+        let response = test.send(reconcile(true)).await;
+        let report = json_body(&response);
+        assert_eq!(report["applied"], true);
+        assert_eq!(report["drift"].as_array().unwrap().len(), 2);
+        // The manually added potatoes are left alone, and the leeks only came from transactions.
+        let list = json_body(&test.send(request("GET", &list_path, None)).await);
+        assert_eq!(
+            quantities(&list),
+            vec![(json!("0x00000001"), 3), (json!("0x00000002"), 5)]
+        );
+        // The corrections are in the change feed, which the next reconciliation counts.
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_changes", shop_id),
+                None,
+            ))
+            .await;
+        let mut corrections: Vec<(String, i64)> = json_body(&response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|change| change["reason"] == "reconcile")
+            .map(|change| {
+                (
+                    change["local_form_id"].as_str().unwrap().to_string(),
+                    change["quantity_delta"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        corrections.sort_unstable();
+        assert_eq!(
+            corrections,
+            vec![
+                ("0x00000001".to_string(), 1),
+                ("0x00000003".to_string(), -2)
+            ]
+        );
+        let report = json_body(&test.send(reconcile(true)).await);
+        assert_eq!(report["drift"], json!([]));
+        assert_eq!(report["applied"], false);
+    }
+}
@@ -1,21 +1,138 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::future::try_join;
 use http::StatusCode;
 use hyper::body::Bytes;
 use mime::Mime;
+use serde::Serialize;
+use std::sync::Arc;
+use url::Url;
 use uuid::Uuid;
-use warp::reply::{with_header, with_status};
+use warp::reply::{json, with_header, with_status};
 use warp::{Rejection, Reply};
 
-use crate::caches::{CachedResponse, CACHES};
-use crate::models::{InteriorRefList, ListParams, PostedInteriorRefList};
-use crate::problem::reject_anyhow;
+use crate::caches::{CachedResponse, InvalidationEvent, Warming};
+use crate::models::{
+    InteriorRefList, InteriorRefListQuery, PostedInteriorRefList, PostedShopInteriorRefList, Shop,
+    MAX_INTERIOR_REFS,
+};
+use crate::problem::{forbidden_permission, list_exists, reject_anyhow, unique_violation};
 use crate::Environment;
 
+use super::resource_usage::{with_resource_usage, ResourceUsage};
 use super::{
-    authenticate, check_etag, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody,
-    ETagReply, Json, TypedCache,
+    authenticate, canonical_etag, check_etag, check_etag_index, check_if_match, check_target_shop,
+    AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody, ETagReply, Json, MinimalReply,
+    PageReply, PreferHeader, TypedCache,
 };
 
+#[derive(Debug, Serialize)]
+struct ValidationSummary {
+    ref_count: usize,
+    shelf_count: usize,
+    content_etag: String,
+    warnings: Vec<String>,
+}
+
+// Deserialization and validation shared by the create, update, and validate handlers so that a dry
+// run accepts exactly what a real upload would.
+fn deserialize_and_validate(
+    bytes: Bytes,
+    content_type: Option<Mime>,
+) -> Result<(DeserializedBody<PostedInteriorRefList>, Vec<String>)> {
+    let body = DeserializedBody::<PostedInteriorRefList>::from_bytes(bytes, content_type)?;
+    let warnings = body.body.validate()?;
+    Ok((body, warnings))
+}
+
+fn ref_usage(interior_ref_list: &InteriorRefList) -> Option<ResourceUsage> {
+    ResourceUsage::new(
+        "interior_refs",
+        interior_ref_list.ref_list.len(),
+        Some(MAX_INTERIOR_REFS),
+    )
+}
+
+// The reply to a `GET` of the list by id or by shop id, which `warm_caches` also renders ahead of
+// time.
+fn get_reply(
+    interior_ref_list: &InteriorRefList,
+    content_type: &ContentType,
+    api_url: &Url,
+) -> Result<impl Reply> {
+    let reply: Box<dyn Reply> = match content_type {
+        ContentType::Bincode => {
+            Box::new(ETagReply::<Bincode>::from_serializable(interior_ref_list)?)
+        }
+        ContentType::Json => Box::new(ETagReply::<Json>::from_resource(
+            interior_ref_list,
+            api_url,
+        )?),
+    };
+    let reply = with_resource_usage(reply, ref_usage(interior_ref_list));
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+// The reply to a create, whether it saved `interior_ref_list` or found it already saved by an
+// earlier attempt of the same create.
+fn saved_reply(
+    interior_ref_list: &InteriorRefList,
+    content_type: ContentType,
+    return_minimal: bool,
+    url: &Url,
+    status: StatusCode,
+    api_url: &Url,
+) -> Result<impl Reply> {
+    let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => Box::new(MinimalReply::from_resource(interior_ref_list)?),
+        ContentType::Bincode => {
+            Box::new(ETagReply::<Bincode>::from_serializable(interior_ref_list)?)
+        }
+        ContentType::Json => Box::new(ETagReply::<Json>::from_resource(
+            interior_ref_list,
+            api_url,
+        )?),
+    };
+    let reply = with_resource_usage(reply, ref_usage(interior_ref_list));
+    let reply = with_header(reply, "Location", url.as_str());
+    Ok(with_status(reply, status))
+}
+
+// Queues the saved list's responses for the by-id and by-shop-id caches that the handler just
+// evicted, so that reading the list back after saving it is a hit.
+async fn warm_caches(env: &Environment, interior_ref_list: &InteriorRefList) {
+    let interior_ref_list = Arc::new(interior_ref_list.clone());
+    let caches = &env.caches;
+    for (cache, key, content_type) in [
+        (
+            &caches.interior_ref_list,
+            interior_ref_list.id,
+            ContentType::Json,
+        ),
+        (
+            &caches.interior_ref_list_bin,
+            interior_ref_list.id,
+            ContentType::Bincode,
+        ),
+        (
+            &caches.interior_ref_list_by_shop_id,
+            interior_ref_list.shop_id,
+            ContentType::Json,
+        ),
+        (
+            &caches.interior_ref_list_by_shop_id_bin,
+            interior_ref_list.shop_id,
+            ContentType::Bincode,
+        ),
+    ] {
+        let list = interior_ref_list.clone();
+        let api_url = env.api_url.clone();
+        let render = async move { get_reply(&list, &content_type, &api_url) };
+        caches
+            .warm(Warming::new(cache, key, interior_ref_list.revision, render))
+            .await;
+    }
+}
+
 pub async fn get(
     id: i32,
     etag: Option<String>,
@@ -27,22 +144,16 @@ pub async fn get(
         cache,
     } = TypedCache::<i32, CachedResponse>::pick_cache(
         accept,
-        &CACHES.interior_ref_list_bin,
-        &CACHES.interior_ref_list,
+        &env.caches.interior_ref_list_bin,
+        &env.caches.interior_ref_list,
     );
+    if let Some(response) = check_etag_index(&etag, cache, &id).await {
+        return Ok(response);
+    }
     let response = cache
         .get_response(id, || async {
             let interior_ref_list = InteriorRefList::get(&env.db, id).await?;
-            let reply: Box<dyn Reply> = match content_type {
-                ContentType::Bincode => {
-                    Box::new(ETagReply::<Bincode>::from_serializable(&interior_ref_list)?)
-                }
-                ContentType::Json => {
-                    Box::new(ETagReply::<Json>::from_serializable(&interior_ref_list)?)
-                }
-            };
-            let reply = with_status(reply, StatusCode::OK);
-            Ok(reply)
+            get_reply(&interior_ref_list, &content_type, &env.api_url)
         })
         .await?;
     Ok(check_etag(etag, response))
@@ -59,29 +170,23 @@ pub async fn get_by_shop_id(
         cache,
     } = TypedCache::<i32, CachedResponse>::pick_cache(
         accept,
-        &CACHES.interior_ref_list_by_shop_id_bin,
-        &CACHES.interior_ref_list_by_shop_id,
+        &env.caches.interior_ref_list_by_shop_id_bin,
+        &env.caches.interior_ref_list_by_shop_id,
     );
+    if let Some(response) = check_etag_index(&etag, cache, &shop_id).await {
+        return Ok(response);
+    }
     let response = cache
         .get_response(shop_id, || async {
             let interior_ref_list = InteriorRefList::get_by_shop_id(&env.db, shop_id).await?;
-            let reply: Box<dyn Reply> = match content_type {
-                ContentType::Bincode => {
-                    Box::new(ETagReply::<Bincode>::from_serializable(&interior_ref_list)?)
-                }
-                ContentType::Json => {
-                    Box::new(ETagReply::<Json>::from_serializable(&interior_ref_list)?)
-                }
-            };
-            let reply = with_status(reply, StatusCode::OK);
-            Ok(reply)
+            get_reply(&interior_ref_list, &content_type, &env.api_url)
         })
         .await?;
     Ok(check_etag(etag, response))
 }
 
 pub async fn list(
-    list_params: ListParams,
+    query: InteriorRefListQuery,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
     env: Environment,
@@ -89,14 +194,18 @@ pub async fn list(
     let TypedCache {
         content_type,
         cache,
-    } = TypedCache::<ListParams, CachedResponse>::pick_cache(
+    } = TypedCache::<InteriorRefListQuery, CachedResponse>::pick_cache(
         accept,
-        &CACHES.list_interior_ref_lists_bin,
-        &CACHES.list_interior_ref_lists,
+        &env.caches.list_interior_ref_lists_bin,
+        &env.caches.list_interior_ref_lists,
     );
     let response = cache
-        .get_response(list_params.clone(), || async {
-            let interior_ref_lists = InteriorRefList::list(&env.db, &list_params).await?;
+        .get_response(query.clone(), || async {
+            let (interior_ref_lists, total) = try_join(
+                InteriorRefList::list(&env.db, &query),
+                InteriorRefList::count(&env.db),
+            )
+            .await?;
             let reply: Box<dyn Reply> = match content_type {
                 ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(
                     &interior_ref_lists,
@@ -105,7 +214,8 @@ pub async fn list(
                     Box::new(ETagReply::<Json>::from_serializable(&interior_ref_lists)?)
                 }
             };
-            let reply = with_status(reply, StatusCode::OK);
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
             Ok(reply)
         })
         .await?;
@@ -117,177 +227,941 @@ pub async fn create(
     bytes: Bytes,
     api_key: Option<Uuid>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
+    let (
+        DeserializedBody {
+            body: mut interior_ref_list,
+            content_type,
+        },
+        _warnings,
+    ) = deserialize_and_validate(bytes, content_type).map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    interior_ref_list.owner_id = Some(owner_id);
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let shop_id = interior_ref_list.shop_id;
+    let saved_interior_ref_list =
+        match InteriorRefList::create(interior_ref_list.clone(), &mut tx).await {
+            Ok(saved_interior_ref_list) => saved_interior_ref_list,
+            Err(error) => {
+                // A create retried after its response was lost finds the list that the first
+                // attempt saved. Answer it with that list, as long as it really is the same list.
+                if unique_violation(&error) == Some("interior_ref_lists_shop_id_key") {
+                    tx.rollback()
+                        .await
+                        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+                    let existing = InteriorRefList::get_by_shop_id(&env.db, shop_id)
+                        .await
+                        .map_err(reject_anyhow)?;
+                    let url = existing.shop_url(&env.api_url).map_err(reject_anyhow)?;
+                    if !interior_ref_list.matches(&existing) {
+                        return Err(reject_anyhow(list_exists(
+                            InteriorRefList::resource_name(),
+                            shop_id,
+                            existing.id,
+                            &url,
+                        )));
+                    }
+                    return saved_reply(
+                        &existing,
+                        content_type,
+                        return_minimal,
+                        &url,
+                        StatusCode::OK,
+                        &env.api_url,
+                    )
+                    .map_err(reject_anyhow);
+                }
+                return Err(reject_anyhow(error));
+            }
+        };
+    Shop::record_activity(&mut tx, saved_interior_ref_list.shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let url = saved_interior_ref_list
+        .url(&env.api_url)
+        .map_err(reject_anyhow)?;
+    let reply = saved_reply(
+        &saved_interior_ref_list,
+        content_type,
+        return_minimal,
+        &url,
+        StatusCode::CREATED,
+        &env.api_url,
+    )
+    .map_err(reject_anyhow)?;
+    env.caches
+        .invalidate(InvalidationEvent::InteriorRefListSaved {
+            list_id: saved_interior_ref_list.id,
+            shop_id: saved_interior_ref_list.shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &saved_interior_ref_list).await;
+    Ok(reply)
+}
+
+// Creates the list of a shop that doesn't have one, e.g. after deleting it. Shops are created with
+// their lists, so this is otherwise a 409 pointing at the existing list.
+pub async fn create_by_shop_id(
+    shop_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
+    target_shop_id: Option<i32>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    check_target_shop(shop_id, target_shop_id).map_err(reject_anyhow)?;
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
     let DeserializedBody {
-        body: mut interior_ref_list,
+        body: interior_ref_list,
         content_type,
-    } = DeserializedBody::<PostedInteriorRefList>::from_bytes(bytes, content_type)
+    } = DeserializedBody::<PostedShopInteriorRefList>::from_bytes(bytes, content_type)
         .map_err(reject_anyhow)?;
+    let mut interior_ref_list = interior_ref_list
+        .into_posted(shop_id)
+        .map_err(reject_anyhow)?;
+    interior_ref_list.validate().map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
     interior_ref_list.owner_id = Some(owner_id);
-    let saved_interior_ref_list = InteriorRefList::create(interior_ref_list, &env.db)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    Shop::lock_for_key_share(&mut tx, shop_id)
         .await
         .map_err(reject_anyhow)?;
+    let saved_interior_ref_list = match InteriorRefList::create(interior_ref_list, &mut tx).await {
+        Ok(saved_interior_ref_list) => saved_interior_ref_list,
+        Err(error) => {
+            if unique_violation(&error) == Some("interior_ref_lists_shop_id_key") {
+                tx.rollback()
+                    .await
+                    .map_err(|error| reject_anyhow(anyhow!(error)))?;
+                let existing = InteriorRefList::get_by_shop_id(&env.db, shop_id)
+                    .await
+                    .map_err(reject_anyhow)?;
+                let url = existing.shop_url(&env.api_url).map_err(reject_anyhow)?;
+                return Err(reject_anyhow(list_exists(
+                    InteriorRefList::resource_name(),
+                    shop_id,
+                    existing.id,
+                    &url,
+                )));
+            }
+            return Err(reject_anyhow(error));
+        }
+    };
+    Shop::record_activity(&mut tx, shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
     let url = saved_interior_ref_list
-        .url(&env.api_url)
+        .shop_url(&env.api_url)
         .map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => {
+            Box::new(MinimalReply::from_resource(&saved_interior_ref_list).map_err(reject_anyhow)?)
+        }
         ContentType::Bincode => Box::new(
             ETagReply::<Bincode>::from_serializable(&saved_interior_ref_list)
                 .map_err(reject_anyhow)?,
         ),
         ContentType::Json => Box::new(
-            ETagReply::<Json>::from_serializable(&saved_interior_ref_list)
+            ETagReply::<Json>::from_resource(&saved_interior_ref_list, &env.api_url)
                 .map_err(reject_anyhow)?,
         ),
     };
+    let reply = with_resource_usage(reply, ref_usage(&saved_interior_ref_list));
     let reply = with_header(reply, "Location", url.as_str());
     let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES.list_interior_ref_lists.clear().await;
-        CACHES.list_interior_ref_lists_bin.clear().await;
-        CACHES
-            .interior_ref_list_by_shop_id
-            .delete_response(saved_interior_ref_list.shop_id)
-            .await;
-        CACHES
-            .interior_ref_list_by_shop_id_bin
-            .delete_response(saved_interior_ref_list.shop_id)
-            .await;
-    });
+    env.caches
+        .invalidate(InvalidationEvent::InteriorRefListSaved {
+            list_id: saved_interior_ref_list.id,
+            shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &saved_interior_ref_list).await;
     Ok(reply)
 }
 
+// Fails with a 412 if `If-Match` doesn't match the list, which the caller read with one of the
+// `*_for_update` methods so that it stays locked until their write. Anyone but the list's owner
+// gets the 403 their write would have.
+fn check_list_if_match(
+    interior_ref_list: &InteriorRefList,
+    owner_id: i32,
+    if_match: Option<&str>,
+) -> Result<()> {
+    if if_match.is_none() {
+        return Ok(());
+    }
+    if interior_ref_list.owner_id != owner_id {
+        return Err(forbidden_permission());
+    }
+    check_if_match(if_match, &[canonical_etag(interior_ref_list)?])
+}
+
 pub async fn update(
     id: i32,
     bytes: Bytes,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
-    let DeserializedBody {
-        body: interior_ref_list,
-        content_type,
-    } = DeserializedBody::<PostedInteriorRefList>::from_bytes(bytes, content_type)
-        .map_err(reject_anyhow)?;
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
+    let (
+        DeserializedBody {
+            body: interior_ref_list,
+            content_type,
+        },
+        _warnings,
+    ) = deserialize_and_validate(bytes, content_type).map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    if if_match.is_some() {
+        let current = InteriorRefList::get_for_update(&mut tx, id)
+            .await
+            .map_err(reject_anyhow)?;
+        check_list_if_match(&current, owner_id, if_match.as_deref()).map_err(reject_anyhow)?;
+    }
     let updated_interior_ref_list =
-        InteriorRefList::update(interior_ref_list, &env.db, owner_id, id)
+        InteriorRefList::update(interior_ref_list, &mut tx, owner_id, id)
             .await
             .map_err(reject_anyhow)?;
+    Shop::record_activity(&mut tx, updated_interior_ref_list.shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
     let url = updated_interior_ref_list
         .url(&env.api_url)
         .map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => Box::new(
+            MinimalReply::from_resource(&updated_interior_ref_list).map_err(reject_anyhow)?,
+        ),
         ContentType::Bincode => Box::new(
             ETagReply::<Bincode>::from_serializable(&updated_interior_ref_list)
                 .map_err(reject_anyhow)?,
         ),
         ContentType::Json => Box::new(
-            ETagReply::<Json>::from_serializable(&updated_interior_ref_list)
+            ETagReply::<Json>::from_resource(&updated_interior_ref_list, &env.api_url)
                 .map_err(reject_anyhow)?,
         ),
     };
+    let reply = with_resource_usage(reply, ref_usage(&updated_interior_ref_list));
     let reply = with_header(reply, "Location", url.as_str());
-    let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES.interior_ref_list.delete_response(id).await;
-        CACHES.interior_ref_list_bin.delete_response(id).await;
-        CACHES
-            .interior_ref_list_by_shop_id
-            .delete_response(updated_interior_ref_list.shop_id)
-            .await;
-        CACHES
-            .interior_ref_list_by_shop_id_bin
-            .delete_response(updated_interior_ref_list.shop_id)
-            .await;
-        CACHES.list_interior_ref_lists.clear().await;
-        CACHES.list_interior_ref_lists_bin.clear().await;
-    });
+    let status = if return_minimal {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    let reply = with_status(reply, status);
+    env.caches
+        .invalidate(InvalidationEvent::InteriorRefListSaved {
+            list_id: id,
+            shop_id: updated_interior_ref_list.shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &updated_interior_ref_list).await;
     Ok(reply)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update_by_shop_id(
     shop_id: i32,
     bytes: Bytes,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
+    target_shop_id: Option<i32>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
-    let DeserializedBody {
-        body: interior_ref_list,
-        content_type,
-    } = DeserializedBody::<PostedInteriorRefList>::from_bytes(bytes, content_type)
-        .map_err(reject_anyhow)?;
+    check_target_shop(shop_id, target_shop_id).map_err(reject_anyhow)?;
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
+    let (
+        DeserializedBody {
+            body: interior_ref_list,
+            content_type,
+        },
+        _warnings,
+    ) = deserialize_and_validate(bytes, content_type).map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    Shop::lock_for_key_share(&mut tx, shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    if if_match.is_some() {
+        let current = InteriorRefList::get_by_shop_id_for_update(&mut tx, shop_id)
+            .await
+            .map_err(reject_anyhow)?;
+        check_list_if_match(&current, owner_id, if_match.as_deref()).map_err(reject_anyhow)?;
+    }
     let updated_interior_ref_list =
-        InteriorRefList::update_by_shop_id(interior_ref_list, &env.db, owner_id, shop_id)
+        InteriorRefList::update_by_shop_id(interior_ref_list, &mut tx, owner_id, shop_id)
             .await
             .map_err(reject_anyhow)?;
+    Shop::record_activity(&mut tx, updated_interior_ref_list.shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
     let url = updated_interior_ref_list
         .url(&env.api_url)
         .map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => Box::new(
+            MinimalReply::from_resource(&updated_interior_ref_list).map_err(reject_anyhow)?,
+        ),
         ContentType::Bincode => Box::new(
             ETagReply::<Bincode>::from_serializable(&updated_interior_ref_list)
                 .map_err(reject_anyhow)?,
         ),
         ContentType::Json => Box::new(
-            ETagReply::<Json>::from_serializable(&updated_interior_ref_list)
+            ETagReply::<Json>::from_resource(&updated_interior_ref_list, &env.api_url)
                 .map_err(reject_anyhow)?,
         ),
     };
+    let reply = with_resource_usage(reply, ref_usage(&updated_interior_ref_list));
     let reply = with_header(reply, "Location", url.as_str());
-    let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES
-            .interior_ref_list
-            .delete_response(updated_interior_ref_list.id)
-            .await;
-        CACHES
-            .interior_ref_list_bin
-            .delete_response(updated_interior_ref_list.id)
-            .await;
-        CACHES
-            .interior_ref_list_by_shop_id
-            .delete_response(updated_interior_ref_list.shop_id)
-            .await;
-        CACHES
-            .interior_ref_list_by_shop_id_bin
-            .delete_response(updated_interior_ref_list.shop_id)
-            .await;
-        CACHES.list_interior_ref_lists.clear().await;
-        CACHES.list_interior_ref_lists_bin.clear().await;
-    });
+    let status = if return_minimal {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    let reply = with_status(reply, status);
+    env.caches
+        .invalidate(InvalidationEvent::InteriorRefListSaved {
+            list_id: updated_interior_ref_list.id,
+            shop_id: updated_interior_ref_list.shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &updated_interior_ref_list).await;
     Ok(reply)
 }
 
 pub async fn delete(
     id: i32,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
-    let interior_ref_list = InteriorRefList::get(&env.db, id)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let interior_ref_list = InteriorRefList::get_for_update(&mut tx, id)
         .await
         .map_err(reject_anyhow)?;
-    InteriorRefList::delete(&env.db, owner_id, id)
+    check_list_if_match(&interior_ref_list, owner_id, if_match.as_deref())
+        .map_err(reject_anyhow)?;
+    InteriorRefList::delete(&mut tx, owner_id, id)
         .await
         .map_err(reject_anyhow)?;
-    tokio::spawn(async move {
-        CACHES.interior_ref_list.delete_response(id).await;
-        CACHES.interior_ref_list_bin.delete_response(id).await;
-        CACHES
-            .interior_ref_list_by_shop_id
-            .delete_response(interior_ref_list.shop_id)
-            .await;
-        CACHES
-            .interior_ref_list_by_shop_id_bin
-            .delete_response(interior_ref_list.shop_id)
-            .await;
-        CACHES.list_interior_ref_lists.clear().await;
-        CACHES.list_interior_ref_lists_bin.clear().await;
-    });
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    env.caches
+        .invalidate(InvalidationEvent::InteriorRefListDeleted {
+            list_id: id,
+            shop_id: interior_ref_list.shop_id,
+            owner_id,
+        })
+        .await;
     Ok(StatusCode::NO_CONTENT)
 }
+
+// Runs the same pipeline as `update_by_shop_id`, including the ownership check, but never writes
+pub async fn validate_by_shop_id(
+    shop_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let (
+        DeserializedBody {
+            body: interior_ref_list,
+            content_type,
+        },
+        warnings,
+    ) = deserialize_and_validate(bytes, content_type).map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let shop = Shop::get(&env.db, shop_id).await.map_err(reject_anyhow)?;
+    if shop.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let content_etag = match content_type {
+        ContentType::Bincode => ETagReply::<Bincode>::from_serializable(&(
+            &interior_ref_list.ref_list,
+            &interior_ref_list.shelves,
+        ))
+        .map_err(reject_anyhow)?
+        .etag()
+        .to_string(),
+        ContentType::Json => ETagReply::<Json>::from_serializable(&(
+            &interior_ref_list.ref_list,
+            &interior_ref_list.shelves,
+        ))
+        .map_err(reject_anyhow)?
+        .etag()
+        .to_string(),
+    };
+    Ok(json(&ValidationSummary {
+        ref_count: interior_ref_list.ref_list.len(),
+        shelf_count: interior_ref_list.shelves.len(),
+        content_etag,
+        warnings,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use url::Url;
+    use warp::http::StatusCode;
+
+    use crate::test_support::{
+        assert_one_deleted, assert_problem, json_body, json_request, request, TestEnv,
+        OTHER_OWNER_API_KEY, OWNER_API_KEY,
+    };
+
+    fn interior_ref_list(shop_id: i64) -> Value {
+        json!({ "shop_id": shop_id, "ref_list": [], "shelves": [] })
+    }
+
+    #[tokio::test]
+    async fn shop_id_cannot_change_and_lists_only_attach_to_own_shops() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let other_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Other Shop" }))
+            .await;
+        let by_shop_path = format!("/v1/shops/{}/interior_ref_list", shop_id);
+        let response = test.send(request("GET", &by_shop_path, None)).await;
+        let list_id = json_body(&response)["id"].as_i64().unwrap();
+        let path = format!("/v1/interior_ref_lists/{}", list_id);
+
+        for path in &[&path, &by_shop_path] {
+            let response = test
+                .send(json_request(
+                    "PATCH",
+                    path,
+                    Some(OWNER_API_KEY),
+                    &interior_ref_list(other_shop_id),
+                ))
+                .await;
+            let problem = assert_problem(&response, StatusCode::CONFLICT);
+            assert_eq!(problem["code"], "shop_id_immutable");
+
+            let response = test
+                .send(json_request(
+                    "PATCH",
+                    path,
+                    Some(OWNER_API_KEY),
+                    &interior_ref_list(shop_id),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            assert_eq!(json_body(&response)["shop_id"], shop_id);
+        }
+
+        // Once the shop has no list, only its owner can create one for it.
+        let response = test
+            .send(request("DELETE", &path, Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/interior_ref_lists",
+                Some(OTHER_OWNER_API_KEY),
+                &interior_ref_list(shop_id),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/interior_ref_lists",
+                Some(OWNER_API_KEY),
+                &interior_ref_list(shop_id),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["shop_id"], shop_id);
+    }
+
+    #[tokio::test]
+    async fn lists_are_created_under_their_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/interior_ref_list", shop_id);
+        let body = json!({ "ref_list": [], "shelves": [] });
+        let response = test.send(request("GET", &path, None)).await;
+        let list_id = json_body(&response)["id"].as_i64().unwrap();
+
+        // The shop was created with its list.
+        let response = test
+            .send(json_request("POST", &path, Some(OWNER_API_KEY), &body))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "list_exists");
+        assert_eq!(problem["list_id"], list_id);
+        let list_url = Url::parse(problem["list_url"].as_str().unwrap()).unwrap();
+        assert_eq!(list_url.path(), path);
+
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/interior_ref_lists/{}", list_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(json_request(
+                "POST",
+                &path,
+                Some(OTHER_OWNER_API_KEY),
+                &body,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test
+            .send(json_request(
+                "POST",
+                &path,
+                Some(OWNER_API_KEY),
+                &interior_ref_list(shop_id + 1),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "shop_id_immutable");
+        let response = test
+            .send(json_request(
+                "POST",
+                &format!("/v1/shops/{}/interior_ref_list", shop_id + 1),
+                Some(OWNER_API_KEY),
+                &body,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+
+        let response = test
+            .send(json_request("POST", &path, Some(OWNER_API_KEY), &body))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let location = Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+        assert_eq!(location.path(), path);
+        let created = json_body(&response);
+        assert_eq!(created["shop_id"], shop_id);
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response)["id"], created["id"]);
+    }
+
+    #[tokio::test]
+    async fn retried_creates_get_the_list_the_first_attempt_saved() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let by_shop_path = format!("/v1/shops/{}/interior_ref_list", shop_id);
+        let response = test.send(request("GET", &by_shop_path, None)).await;
+        let list_id = json_body(&response)["id"].as_i64().unwrap();
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/interior_ref_lists/{}", list_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/interior_ref_lists",
+                Some(OWNER_API_KEY),
+                &interior_ref_list(shop_id),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let created = json_body(&response);
+
+        // The response to the first attempt was lost, so the client sends it again.
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/interior_ref_lists",
+                Some(OWNER_API_KEY),
+                &interior_ref_list(shop_id),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let location = Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+        assert_eq!(location.path(), by_shop_path);
+        assert_eq!(json_body(&response)["id"], created["id"]);
+
+        let mut different = interior_ref_list(shop_id);
+        different["ref_list"] = json!([{
+            "base_mod_name": "Skyrim.esm",
+            "base_local_form_id": 1,
+            "ref_mod_name": "Skyrim.esm",
+            "ref_local_form_id": 1,
+            "position_x": 1.5,
+            "position_y": 2.5,
+            "position_z": 3.5,
+            "angle_x": 0.0,
+            "angle_y": 0.0,
+            "angle_z": 0.0,
+            "scale": 1,
+        }]);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/interior_ref_lists",
+                Some(OWNER_API_KEY),
+                &different,
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "list_exists");
+        assert_eq!(problem["list_id"], created["id"]);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/interior_ref_lists",
+                Some(OTHER_OWNER_API_KEY),
+                &interior_ref_list(shop_id),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn prefer_return_minimal_skips_the_body() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/interior_ref_list", shop_id);
+        let refs: Vec<Value> = (1..=50)
+            .map(|id| {
+                json!({
+                    "base_mod_name": "Skyrim.esm",
+                    "base_local_form_id": id,
+                    "ref_mod_name": "Skyrim.esm",
+                    "ref_local_form_id": id,
+                    "position_x": 1.5,
+                    "position_y": 2.5,
+                    "position_z": 3.5,
+                    "angle_x": 0.0,
+                    "angle_y": 0.0,
+                    "angle_z": 0.0,
+                    "scale": 1,
+                })
+            })
+            .collect();
+        let body = json!({ "shop_id": shop_id, "ref_list": refs, "shelves": [] });
+
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &body))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert!(response.headers().get("preference-applied").is_none());
+        let full_len = response.body().len();
+
+        let response = test
+            .send(
+                json_request("PATCH", &path, Some(OWNER_API_KEY), &body)
+                    .header("prefer", "return=minimal"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT, "{:?}", response);
+        assert_eq!(response.headers()["preference-applied"], "return=minimal");
+        assert!(response.headers().contains_key("location"));
+        assert!(response.body().is_empty());
+        assert!(
+            full_len > 5_000,
+            "full response was only {} bytes",
+            full_len
+        );
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", &etag))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        // Creates still say 201, just without a body.
+        let response = test
+            .send(
+                json_request(
+                    "POST",
+                    "/v1/shops",
+                    Some(OWNER_API_KEY),
+                    &json!({ "name": "Other Shop" }),
+                )
+                .header("prefer", "respond-async, return=minimal"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert!(response.body().is_empty());
+        let location = Url::parse(response.headers()["location"].to_str().unwrap()).unwrap();
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let response = test
+            .send(request("GET", location.path(), None).header("if-none-match", &etag))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn stale_expected_revisions_are_rejected() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let by_shop_path = format!("/v1/shops/{}/interior_ref_list", shop_id);
+        let response = test.send(request("GET", &by_shop_path, None)).await;
+        let list = json_body(&response);
+        assert_eq!(list["revision"], 1);
+        let path = format!("/v1/interior_ref_lists/{}", list["id"]);
+        let with_revision = |expected_revision: i64| {
+            let mut body = interior_ref_list(shop_id);
+            body["expected_revision"] = json!(expected_revision);
+            body
+        };
+
+        let mut revision = 1;
+        for path in &[&path, &by_shop_path] {
+            let response = test
+                .send(json_request(
+                    "PATCH",
+                    path,
+                    Some(OWNER_API_KEY),
+                    &with_revision(revision),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            assert_eq!(json_body(&response)["revision"], revision + 1);
+
+            // Another client still holding the old revision can't overwrite the update.
+            let response = test
+                .send(json_request(
+                    "PATCH",
+                    path,
+                    Some(OWNER_API_KEY),
+                    &with_revision(revision),
+                ))
+                .await;
+            let problem = assert_problem(&response, StatusCode::CONFLICT);
+            assert_eq!(problem["code"], "revision_mismatch");
+            assert_eq!(problem["current_revision"], revision + 1);
+
+            // Without an expected revision, the last write wins.
+            let response = test
+                .send(json_request(
+                    "PATCH",
+                    path,
+                    Some(OWNER_API_KEY),
+                    &interior_ref_list(shop_id),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            assert_eq!(json_body(&response)["revision"], revision + 2);
+            revision += 2;
+        }
+        let response = test.send(request("GET", &by_shop_path, None)).await;
+        assert_eq!(json_body(&response)["revision"], revision);
+    }
+
+    #[tokio::test]
+    async fn concurrent_deletes_of_a_list_delete_it_once() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/interior_ref_list", shop_id),
+                None,
+            ))
+            .await;
+        let list_id = json_body(&response)["id"].clone();
+        let delete = || {
+            request(
+                "DELETE",
+                &format!("/v1/interior_ref_lists/{}", list_id),
+                Some(OWNER_API_KEY),
+            )
+        };
+        let (first, second) = test.send_concurrently(delete(), delete()).await;
+        assert_one_deleted(&first, &second);
+        let response = test.send(delete()).await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn validation_reports_counts_and_writes_nothing() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let by_shop_path = format!("/v1/shops/{}/interior_ref_list", shop_id);
+        let validate_path = format!("{}/validate", by_shop_path);
+        let before = test.send(request("GET", &by_shop_path, None)).await;
+        let interior_ref = |base_mod_name: &str, scale: i32| {
+            json!({
+                "base_mod_name": base_mod_name,
+                "base_local_form_id": 1,
+                "ref_mod_name": "Skyrim.esm",
+                "ref_local_form_id": 1,
+                "position_x": 1.5,
+                "position_y": 2.5,
+                "position_z": 3.5,
+                "angle_x": 0.0,
+                "angle_y": 0.0,
+                "angle_z": 0.0,
+                "scale": scale,
+            })
+        };
+        let mut list = interior_ref_list(shop_id);
+        list["ref_list"] = json!([interior_ref("Skyrim.esm", 1), interior_ref("Skyrim.esm", 0)]);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let summary = json_body(&response);
+        assert_eq!(summary["ref_count"], 2);
+        assert_eq!(summary["shelf_count"], 0);
+        assert_eq!(summary["warnings"], json!(["ref_list[1] has a scale of 0"]));
+        let content_etag = summary["content_etag"].as_str().unwrap().to_string();
+        assert!(!content_etag.is_empty());
+        // The ETag only depends on the content.
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_eq!(json_body(&response)["content_etag"], content_etag);
+        list["ref_list"] = json!([interior_ref("Skyrim.esm", 1)]);
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_ne!(json_body(&response)["content_etag"], content_etag);
+
+        let after = test.send(request("GET", &by_shop_path, None)).await;
+        assert_eq!(json_body(&after)["ref_list"], json!([]));
+        assert_eq!(after.headers()["etag"], before.headers()["etag"]);
+
+        // Invalid lists get the same problem an upload would.
+        list["ref_list"] = json!([interior_ref("", 1)]);
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "ref_list");
+        assert_eq!(problem["errors"][0]["index"], 0);
+        list["ref_list"] = json!([interior_ref("Skyrim.esm", 1)]);
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OTHER_OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,282 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Serialize;
+use url::Url;
+
+use crate::models::{
+    InteriorRefList, LocalizedShop, MerchandiseList, Owner, OwnerProfile, OwnerSelfView, OwnerShop,
+    PublicShop, Transaction,
+};
+
+#[derive(Debug, Serialize)]
+pub struct Link {
+    pub href: String,
+}
+
+pub type Links = BTreeMap<&'static str, Link>;
+
+// HAL-style wrapper that adds a `_links` object to the JSON serialization of a single resource.
+// Links are generated from the api url at response time so they follow the routes if they move.
+// Bincode responses serialize the bare model instead so the binary payload stays stable.
+#[derive(Debug, Serialize)]
+pub struct Linked<'a, T: Serialize> {
+    #[serde(flatten)]
+    resource: &'a T,
+    #[serde(rename = "_links")]
+    links: Links,
+}
+
+pub trait WithLinks: Serialize + Sized {
+    fn links(&self, api_url: &Url) -> Result<Links>;
+
+    fn linked(&self, api_url: &Url) -> Result<Linked<'_, Self>> {
+        Ok(Linked {
+            resource: self,
+            links: self.links(api_url)?,
+        })
+    }
+}
+
+fn link(api_url: &Url, path: &str) -> Result<Link> {
+    Ok(Link {
+        href: api_url.join(path)?.to_string(),
+    })
+}
+
+fn shop_links(api_url: &Url, id: i32, owner_id: i32) -> Result<Links> {
+    let mut links = Links::new();
+    links.insert("self", link(api_url, &format!("shops/{}", id))?);
+    links.insert("owner", link(api_url, &format!("owners/{}", owner_id))?);
+    links.insert(
+        "interior_ref_list",
+        link(api_url, &format!("shops/{}/interior_ref_list", id))?,
+    );
+    links.insert(
+        "merchandise_list",
+        link(api_url, &format!("shops/{}/merchandise_list", id))?,
+    );
+    links.insert(
+        "transactions",
+        link(api_url, &format!("shops/{}/transactions", id))?,
+    );
+    Ok(links)
+}
+
+impl WithLinks for PublicShop {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        shop_links(api_url, self.id, self.owner_id)
+    }
+}
+
+impl WithLinks for OwnerShop {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        shop_links(api_url, self.id, self.owner_id)
+    }
+}
+
+impl WithLinks for Owner {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        let mut links = Links::new();
+        links.insert("self", link(api_url, &format!("owners/{}", self.id))?);
+        links.insert(
+            "shops",
+            link(api_url, &format!("owners/{}/shops", self.id))?,
+        );
+        Ok(links)
+    }
+}
+
+impl WithLinks for LocalizedShop {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        self.shop.links(api_url)
+    }
+}
+
+impl WithLinks for OwnerSelfView {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        self.owner.links(api_url)
+    }
+}
+
+impl WithLinks for OwnerProfile {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        let mut links = Links::new();
+        links.insert(
+            "self",
+            link(api_url, &format!("owners/{}/profile", self.id))?,
+        );
+        links.insert(
+            "shops",
+            link(api_url, &format!("owners/{}/shops", self.id))?,
+        );
+        Ok(links)
+    }
+}
+
+impl WithLinks for InteriorRefList {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        let mut links = Links::new();
+        links.insert(
+            "self",
+            link(api_url, &format!("interior_ref_lists/{}", self.id))?,
+        );
+        links.insert("shop", link(api_url, &format!("shops/{}", self.shop_id))?);
+        links.insert(
+            "owner",
+            link(api_url, &format!("owners/{}", self.owner_id))?,
+        );
+        Ok(links)
+    }
+}
+
+impl WithLinks for MerchandiseList {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        let mut links = Links::new();
+        links.insert(
+            "self",
+            link(api_url, &format!("merchandise_lists/{}", self.id))?,
+        );
+        links.insert("shop", link(api_url, &format!("shops/{}", self.shop_id))?);
+        links.insert(
+            "owner",
+            link(api_url, &format!("owners/{}", self.owner_id))?,
+        );
+        Ok(links)
+    }
+}
+
+impl WithLinks for Transaction {
+    fn links(&self, api_url: &Url) -> Result<Links> {
+        let mut links = Links::new();
+        links.insert("self", link(api_url, &format!("transactions/{}", self.id))?);
+        links.insert("shop", link(api_url, &format!("shops/{}", self.shop_id))?);
+        Ok(links)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use crate::test_support::{json_body, json_request, request, TestEnv, OWNER_API_KEY};
+
+    async fn get(test: &TestEnv, path: &str) -> Value {
+        let response = test.send(request("GET", path, Some(OWNER_API_KEY))).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::OK,
+            "{}: {:?}",
+            path,
+            response
+        );
+        json_body(&response)
+    }
+
+    #[tokio::test]
+    async fn links_are_absolute_and_lead_to_their_resources() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 100 }))
+            .await;
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "Cabbage",
+                    "form_kind": 46,
+                    "is_food": true,
+                    "price": 1,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 1,
+                    "keywords": [],
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let transaction_id = json_body(&response)["transaction"]["id"].clone();
+        let url = |path: String| json!({ "href": format!("http://localhost/v1/{}", path) });
+
+        let shop = get(&test, &format!("/v1/shops/{}", shop_id)).await;
+        assert_eq!(
+            shop["_links"],
+            json!({
+                "self": url(format!("shops/{}", shop_id)),
+                "owner": url(format!("owners/{}", owner_id)),
+                "interior_ref_list": url(format!("shops/{}/interior_ref_list", shop_id)),
+                "merchandise_list": url(format!("shops/{}/merchandise_list", shop_id)),
+                "transactions": url(format!("shops/{}/transactions", shop_id)),
+            })
+        );
+        let owner = get(&test, &format!("/v1/owners/{}", owner_id)).await;
+        assert_eq!(
+            owner["_links"],
+            json!({
+                "self": url(format!("owners/{}", owner_id)),
+                "shops": url(format!("owners/{}/shops", owner_id)),
+            })
+        );
+        let profile = get(&test, &format!("/v1/owners/{}/profile", owner_id)).await;
+        assert_eq!(
+            profile["_links"],
+            json!({
+                "self": url(format!("owners/{}/profile", owner_id)),
+                "shops": url(format!("owners/{}/shops", owner_id)),
+            })
+        );
+        let interior_ref_list =
+            get(&test, &format!("/v1/shops/{}/interior_ref_list", shop_id)).await;
+        assert_eq!(
+            interior_ref_list["_links"],
+            json!({
+                "self": url(format!("interior_ref_lists/{}", interior_ref_list["id"])),
+                "shop": url(format!("shops/{}", shop_id)),
+                "owner": url(format!("owners/{}", owner_id)),
+            })
+        );
+        let merchandise_list = get(&test, &format!("/v1/shops/{}/merchandise_list", shop_id)).await;
+        assert_eq!(
+            merchandise_list["_links"],
+            json!({
+                "self": url(format!("merchandise_lists/{}", merchandise_list["id"])),
+                "shop": url(format!("shops/{}", shop_id)),
+                "owner": url(format!("owners/{}", owner_id)),
+            })
+        );
+        let transaction = get(&test, &format!("/v1/transactions/{}", transaction_id)).await;
+        assert_eq!(
+            transaction["_links"],
+            json!({
+                "self": url(format!("transactions/{}", transaction_id)),
+                "shop": url(format!("shops/{}", shop_id)),
+            })
+        );
+
+        // Every link is served by a route, so none of them is a dead end.
+        for resource in &[
+            shop,
+            owner,
+            profile,
+            interior_ref_list,
+            merchandise_list,
+            transaction,
+        ] {
+            let links = resource["_links"].as_object().unwrap();
+            for link in links.values() {
+                let href = link["href"].as_str().unwrap();
+                get(&test, href.trim_start_matches("http://localhost")).await;
+            }
+        }
+    }
+}
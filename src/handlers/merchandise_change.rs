@@ -0,0 +1,56 @@
+use anyhow::Result;
+use futures::future::try_join;
+use http::StatusCode;
+use warp::reply::with_status;
+use warp::{Rejection, Reply};
+
+use crate::caches::CachedResponse;
+use crate::clock::check_not_ahead;
+use crate::models::{MerchandiseChange, MerchandiseChangeListQuery};
+use crate::problem::reject_anyhow;
+use crate::Environment;
+
+use super::{
+    check_etag, AcceptHeader, Bincode, ContentType, DataReply, ETagReply, Json, PageReply,
+    TypedCache,
+};
+
+pub async fn list_by_shop_id(
+    shop_id: i32,
+    query: MerchandiseChangeListQuery,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    check_not_ahead("since", query.filter.since, env.config.clock_skew_tolerance)
+        .map_err(reject_anyhow)?;
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<(i32, MerchandiseChangeListQuery), CachedResponse>::pick_cache(
+        accept,
+        &env.caches.list_merchandise_changes_by_shop_id_bin,
+        &env.caches.list_merchandise_changes_by_shop_id,
+    );
+    let response = cache
+        .get_response((shop_id, query.clone()), || async {
+            let (merchandise_changes, total) = try_join(
+                MerchandiseChange::list_by_shop_id(&env.db, shop_id, &query),
+                MerchandiseChange::count_by_shop_id(&env.db, shop_id, &query),
+            )
+            .await?;
+            let reply: Box<dyn Reply> = match content_type {
+                ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(
+                    &merchandise_changes,
+                )?),
+                ContentType::Json => {
+                    Box::new(ETagReply::<Json>::from_serializable(&merchandise_changes)?)
+                }
+            };
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
+            Ok(reply)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
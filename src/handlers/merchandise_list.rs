@@ -1,21 +1,148 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::future::try_join;
 use http::StatusCode;
 use hyper::body::Bytes;
 use mime::Mime;
+use serde::Serialize;
+use std::sync::Arc;
+use url::Url;
 use uuid::Uuid;
-use warp::reply::{with_header, with_status};
+use warp::reply::{json, with_header, with_status};
 use warp::{Rejection, Reply};
 
-use crate::caches::{CachedResponse, CACHES};
-use crate::models::{ListParams, MerchandiseList, PostedMerchandiseList};
-use crate::problem::reject_anyhow;
+use crate::caches::{CachedResponse, InvalidationEvent, Warming};
+use crate::models::{
+    LegacyPostedMerchandiseList, LegacyPostedShopMerchandiseList, MerchandiseFacets,
+    MerchandiseList, MerchandiseListQuery, PostedMerchandiseList, PostedShopMerchandiseList, Shop,
+    ShopRequest,
+};
+use crate::problem::{forbidden_permission, list_exists, reject_anyhow, unique_violation};
 use crate::Environment;
 
+use super::resource_usage::{with_resource_usage, ResourceUsage};
+use super::shop_request::publish_flagged_requests;
 use super::{
-    authenticate, check_etag, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody,
-    ETagReply, Json, TypedCache,
+    authenticate, canonical_etag, check_etag, check_etag_index, check_if_match, check_target_shop,
+    AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody, ETagReply, Json, MinimalReply,
+    PageReply, PreferHeader, TypedCache,
 };
 
+#[derive(Debug, Serialize)]
+struct ValidationSummary {
+    item_count: usize,
+    total_quantity: u64,
+    content_etag: String,
+    warnings: Vec<String>,
+}
+
+// Deserialization and validation shared by the create, update, and validate handlers so that a dry
+// run accepts exactly what a real upload would.
+fn deserialize_and_validate(
+    bytes: Bytes,
+    content_type: Option<Mime>,
+    max_items: usize,
+) -> Result<(DeserializedBody<PostedMerchandiseList>, Vec<String>)> {
+    let body = DeserializedBody::<PostedMerchandiseList>::from_bytes_or_legacy::<
+        LegacyPostedMerchandiseList,
+    >(bytes, content_type)?;
+    let warnings = body.body.validate(max_items)?;
+    Ok((body, warnings))
+}
+
+fn merchandise_usage(
+    merchandise_list: &MerchandiseList,
+    max_items: usize,
+) -> Option<ResourceUsage> {
+    ResourceUsage::new(
+        "merchandise",
+        merchandise_list.form_list.len(),
+        Some(max_items),
+    )
+}
+
+// The reply to a `GET` of the list by id or by shop id, which `warm_caches` also renders ahead of
+// time.
+fn get_reply(
+    merchandise_list: &MerchandiseList,
+    content_type: &ContentType,
+    api_url: &Url,
+    max_items: usize,
+) -> Result<impl Reply> {
+    let reply: Box<dyn Reply> = match content_type {
+        ContentType::Bincode => {
+            Box::new(ETagReply::<Bincode>::from_serializable(merchandise_list)?)
+        }
+        ContentType::Json => Box::new(ETagReply::<Json>::from_resource(merchandise_list, api_url)?),
+    };
+    let reply = with_resource_usage(reply, merchandise_usage(merchandise_list, max_items));
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+// The reply to a create, whether it saved `merchandise_list` or found it already saved by an
+// earlier attempt of the same create.
+fn saved_reply(
+    merchandise_list: &MerchandiseList,
+    content_type: ContentType,
+    return_minimal: bool,
+    url: &Url,
+    status: StatusCode,
+    env: &Environment,
+) -> Result<impl Reply> {
+    let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => Box::new(MinimalReply::from_resource(merchandise_list)?),
+        ContentType::Bincode => {
+            Box::new(ETagReply::<Bincode>::from_serializable(merchandise_list)?)
+        }
+        ContentType::Json => Box::new(ETagReply::<Json>::from_resource(
+            merchandise_list,
+            &env.api_url,
+        )?),
+    };
+    let reply = with_resource_usage(
+        reply,
+        merchandise_usage(merchandise_list, env.config.max_merchandise_items),
+    );
+    let reply = with_header(reply, "Location", url.as_str());
+    Ok(with_status(reply, status))
+}
+
+// Queues the saved list's responses for the by-id and by-shop-id caches that the handler just
+// evicted, so that reading the list back after saving it is a hit.
+async fn warm_caches(env: &Environment, merchandise_list: &MerchandiseList) {
+    let merchandise_list = Arc::new(merchandise_list.clone());
+    let caches = &env.caches;
+    for (cache, key, content_type) in [
+        (
+            &caches.merchandise_list,
+            merchandise_list.id,
+            ContentType::Json,
+        ),
+        (
+            &caches.merchandise_list_bin,
+            merchandise_list.id,
+            ContentType::Bincode,
+        ),
+        (
+            &caches.merchandise_list_by_shop_id,
+            merchandise_list.shop_id,
+            ContentType::Json,
+        ),
+        (
+            &caches.merchandise_list_by_shop_id_bin,
+            merchandise_list.shop_id,
+            ContentType::Bincode,
+        ),
+    ] {
+        let list = merchandise_list.clone();
+        let api_url = env.api_url.clone();
+        let max_items = env.config.max_merchandise_items;
+        let render = async move { get_reply(&list, &content_type, &api_url, max_items) };
+        caches
+            .warm(Warming::new(cache, key, merchandise_list.revision, render))
+            .await;
+    }
+}
+
 pub async fn get(
     id: i32,
     etag: Option<String>,
@@ -27,22 +154,21 @@ pub async fn get(
         cache,
     } = TypedCache::<i32, CachedResponse>::pick_cache(
         accept,
-        &CACHES.merchandise_list_bin,
-        &CACHES.merchandise_list,
+        &env.caches.merchandise_list_bin,
+        &env.caches.merchandise_list,
     );
+    if let Some(response) = check_etag_index(&etag, cache, &id).await {
+        return Ok(response);
+    }
     let response = cache
         .get_response(id, || async {
             let merchandise_list = MerchandiseList::get(&env.db, id).await?;
-            let reply: Box<dyn Reply> = match content_type {
-                ContentType::Bincode => {
-                    Box::new(ETagReply::<Bincode>::from_serializable(&merchandise_list)?)
-                }
-                ContentType::Json => {
-                    Box::new(ETagReply::<Json>::from_serializable(&merchandise_list)?)
-                }
-            };
-            let reply = with_status(reply, StatusCode::OK);
-            Ok(reply)
+            get_reply(
+                &merchandise_list,
+                &content_type,
+                &env.api_url,
+                env.config.max_merchandise_items,
+            )
         })
         .await?;
     Ok(check_etag(etag, response))
@@ -59,19 +185,46 @@ pub async fn get_by_shop_id(
         cache,
     } = TypedCache::<i32, CachedResponse>::pick_cache(
         accept,
-        &CACHES.merchandise_list_by_shop_id_bin,
-        &CACHES.merchandise_list_by_shop_id,
+        &env.caches.merchandise_list_by_shop_id_bin,
+        &env.caches.merchandise_list_by_shop_id,
     );
+    if let Some(response) = check_etag_index(&etag, cache, &shop_id).await {
+        return Ok(response);
+    }
     let response = cache
         .get_response(shop_id, || async {
             let merchandise_list = MerchandiseList::get_by_shop_id(&env.db, shop_id).await?;
+            get_reply(
+                &merchandise_list,
+                &content_type,
+                &env.api_url,
+                env.config.max_merchandise_items,
+            )
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+pub async fn facets_by_shop_id(
+    shop_id: i32,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<i32, CachedResponse>::pick_cache(
+        accept,
+        &env.caches.merchandise_facets_by_shop_id_bin,
+        &env.caches.merchandise_facets_by_shop_id,
+    );
+    let response = cache
+        .get_response(shop_id, || async {
+            let facets = MerchandiseFacets::get_by_shop_id(&env.db, shop_id).await?;
             let reply: Box<dyn Reply> = match content_type {
-                ContentType::Bincode => {
-                    Box::new(ETagReply::<Bincode>::from_serializable(&merchandise_list)?)
-                }
-                ContentType::Json => {
-                    Box::new(ETagReply::<Json>::from_serializable(&merchandise_list)?)
-                }
+                ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(&facets)?),
+                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&facets)?),
             };
             let reply = with_status(reply, StatusCode::OK);
             Ok(reply)
@@ -81,7 +234,7 @@ pub async fn get_by_shop_id(
 }
 
 pub async fn list(
-    list_params: ListParams,
+    query: MerchandiseListQuery,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
     env: Environment,
@@ -89,14 +242,18 @@ pub async fn list(
     let TypedCache {
         content_type,
         cache,
-    } = TypedCache::<ListParams, CachedResponse>::pick_cache(
+    } = TypedCache::<MerchandiseListQuery, CachedResponse>::pick_cache(
         accept,
-        &CACHES.list_merchandise_lists_bin,
-        &CACHES.list_merchandise_lists,
+        &env.caches.list_merchandise_lists_bin,
+        &env.caches.list_merchandise_lists,
     );
     let response = cache
-        .get_response(list_params.clone(), || async {
-            let merchandise_lists = MerchandiseList::list(&env.db, &list_params).await?;
+        .get_response(query.clone(), || async {
+            let (merchandise_lists, total) = try_join(
+                MerchandiseList::list(&env.db, &query),
+                MerchandiseList::count(&env.db),
+            )
+            .await?;
             let reply: Box<dyn Reply> = match content_type {
                 ContentType::Bincode => {
                     Box::new(ETagReply::<Bincode>::from_serializable(&merchandise_lists)?)
@@ -105,7 +262,8 @@ pub async fn list(
                     Box::new(ETagReply::<Json>::from_serializable(&merchandise_lists)?)
                 }
             };
-            let reply = with_status(reply, StatusCode::OK);
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
             Ok(reply)
         })
         .await?;
@@ -116,175 +274,1508 @@ pub async fn create(
     bytes: Bytes,
     api_key: Option<Uuid>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
+    let (
+        DeserializedBody {
+            body: mut merchandise_list,
+            content_type,
+        },
+        _warnings,
+    ) = deserialize_and_validate(bytes, content_type, env.config.max_merchandise_items)
+        .map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    merchandise_list.owner_id = Some(owner_id);
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let shop_id = merchandise_list.shop_id;
+    let saved_merchandise_list =
+        match MerchandiseList::create(merchandise_list.clone(), &mut tx).await {
+            Ok(saved_merchandise_list) => saved_merchandise_list,
+            Err(error) => {
+                // A create retried after its response was lost finds the list that the first
+                // attempt saved. Answer it with that list, as long as it really is the same list.
+                if unique_violation(&error) == Some("merchandise_lists_shop_id_key") {
+                    tx.rollback()
+                        .await
+                        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+                    let existing = MerchandiseList::get_by_shop_id(&env.db, shop_id)
+                        .await
+                        .map_err(reject_anyhow)?;
+                    let url = existing.shop_url(&env.api_url).map_err(reject_anyhow)?;
+                    if !merchandise_list.matches(&existing) {
+                        return Err(reject_anyhow(list_exists(
+                            MerchandiseList::resource_name(),
+                            shop_id,
+                            existing.id,
+                            &url,
+                        )));
+                    }
+                    return saved_reply(
+                        &existing,
+                        content_type,
+                        return_minimal,
+                        &url,
+                        StatusCode::OK,
+                        &env,
+                    )
+                    .map_err(reject_anyhow);
+                }
+                return Err(reject_anyhow(error));
+            }
+        };
+    Shop::record_activity(&mut tx, saved_merchandise_list.shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    let flagged_requests = ShopRequest::flag_fulfillable(
+        &mut tx,
+        saved_merchandise_list.shop_id,
+        &saved_merchandise_list.form_list,
+    )
+    .await
+    .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    publish_flagged_requests(&env, saved_merchandise_list.shop_id, &flagged_requests).await;
+    let url = saved_merchandise_list
+        .url(&env.api_url)
+        .map_err(reject_anyhow)?;
+    let reply = saved_reply(
+        &saved_merchandise_list,
+        content_type,
+        return_minimal,
+        &url,
+        StatusCode::CREATED,
+        &env,
+    )
+    .map_err(reject_anyhow)?;
+    env.caches
+        .invalidate(InvalidationEvent::MerchandiseListSaved {
+            list_id: saved_merchandise_list.id,
+            shop_id: saved_merchandise_list.shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &saved_merchandise_list).await;
+    Ok(reply)
+}
+
+// Creates the list of a shop that doesn't have one, e.g. after deleting it. Shops are created with
+// their lists, so this is otherwise a 409 pointing at the existing list.
+pub async fn create_by_shop_id(
+    shop_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
+    target_shop_id: Option<i32>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
+    check_target_shop(shop_id, target_shop_id).map_err(reject_anyhow)?;
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
     let DeserializedBody {
-        body: mut merchandise_list,
+        body: merchandise_list,
         content_type,
-    } = DeserializedBody::<PostedMerchandiseList>::from_bytes(bytes, content_type)
+    } = DeserializedBody::<PostedShopMerchandiseList>::from_bytes_or_legacy::<
+        LegacyPostedShopMerchandiseList,
+    >(bytes, content_type)
+    .map_err(reject_anyhow)?;
+    let mut merchandise_list = merchandise_list
+        .into_posted(shop_id)
+        .map_err(reject_anyhow)?;
+    merchandise_list
+        .validate(env.config.max_merchandise_items)
         .map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
     merchandise_list.owner_id = Some(owner_id);
-    let saved_merchandise_list = MerchandiseList::create(merchandise_list, &env.db)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    Shop::lock_for_key_share(&mut tx, shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    let saved_merchandise_list = match MerchandiseList::create(merchandise_list, &mut tx).await {
+        Ok(saved_merchandise_list) => saved_merchandise_list,
+        Err(error) => {
+            if unique_violation(&error) == Some("merchandise_lists_shop_id_key") {
+                tx.rollback()
+                    .await
+                    .map_err(|error| reject_anyhow(anyhow!(error)))?;
+                let existing = MerchandiseList::get_by_shop_id(&env.db, shop_id)
+                    .await
+                    .map_err(reject_anyhow)?;
+                let url = existing.shop_url(&env.api_url).map_err(reject_anyhow)?;
+                return Err(reject_anyhow(list_exists(
+                    MerchandiseList::resource_name(),
+                    shop_id,
+                    existing.id,
+                    &url,
+                )));
+            }
+            return Err(reject_anyhow(error));
+        }
+    };
+    Shop::record_activity(&mut tx, shop_id)
         .await
         .map_err(reject_anyhow)?;
+    let flagged_requests = ShopRequest::flag_fulfillable(
+        &mut tx,
+        saved_merchandise_list.shop_id,
+        &saved_merchandise_list.form_list,
+    )
+    .await
+    .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    publish_flagged_requests(&env, saved_merchandise_list.shop_id, &flagged_requests).await;
     let url = saved_merchandise_list
-        .url(&env.api_url)
+        .shop_url(&env.api_url)
         .map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => {
+            Box::new(MinimalReply::from_resource(&saved_merchandise_list).map_err(reject_anyhow)?)
+        }
         ContentType::Bincode => Box::new(
             ETagReply::<Bincode>::from_serializable(&saved_merchandise_list)
                 .map_err(reject_anyhow)?,
         ),
         ContentType::Json => Box::new(
-            ETagReply::<Json>::from_serializable(&saved_merchandise_list).map_err(reject_anyhow)?,
+            ETagReply::<Json>::from_resource(&saved_merchandise_list, &env.api_url)
+                .map_err(reject_anyhow)?,
         ),
     };
+    let reply = with_resource_usage(
+        reply,
+        merchandise_usage(&saved_merchandise_list, env.config.max_merchandise_items),
+    );
     let reply = with_header(reply, "Location", url.as_str());
     let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES.list_merchandise_lists.clear().await;
-        CACHES.list_merchandise_lists_bin.clear().await;
-        CACHES
-            .merchandise_list_by_shop_id
-            .delete_response(saved_merchandise_list.shop_id)
-            .await;
-        CACHES
-            .merchandise_list_by_shop_id_bin
-            .delete_response(saved_merchandise_list.shop_id)
-            .await;
-    });
+    env.caches
+        .invalidate(InvalidationEvent::MerchandiseListSaved {
+            list_id: saved_merchandise_list.id,
+            shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &saved_merchandise_list).await;
     Ok(reply)
 }
 
+// Fails with a 412 if `If-Match` doesn't match the list, which the caller read with one of the
+// `*_for_update` methods so that it stays locked until their write. Anyone but the list's owner
+// gets the 403 their write would have.
+fn check_list_if_match(
+    merchandise_list: &MerchandiseList,
+    owner_id: i32,
+    if_match: Option<&str>,
+) -> Result<()> {
+    if if_match.is_none() {
+        return Ok(());
+    }
+    if merchandise_list.owner_id != owner_id {
+        return Err(forbidden_permission());
+    }
+    check_if_match(if_match, &[canonical_etag(merchandise_list)?])
+}
+
 pub async fn update(
     id: i32,
     bytes: Bytes,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
-    let DeserializedBody {
-        body: merchandise_list,
-        content_type,
-    } = DeserializedBody::<PostedMerchandiseList>::from_bytes(bytes, content_type)
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
+    let (
+        DeserializedBody {
+            body: merchandise_list,
+            content_type,
+        },
+        _warnings,
+    ) = deserialize_and_validate(bytes, content_type, env.config.max_merchandise_items)
         .map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
-    let updated_merchandise_list = MerchandiseList::update(merchandise_list, &env.db, owner_id, id)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    if if_match.is_some() {
+        let current = MerchandiseList::get_for_update(&mut tx, id)
+            .await
+            .map_err(reject_anyhow)?;
+        check_list_if_match(&current, owner_id, if_match.as_deref()).map_err(reject_anyhow)?;
+    }
+    let updated_merchandise_list = MerchandiseList::update(merchandise_list, &mut tx, owner_id, id)
+        .await
+        .map_err(reject_anyhow)?;
+    Shop::record_activity(&mut tx, updated_merchandise_list.shop_id)
         .await
         .map_err(reject_anyhow)?;
+    let flagged_requests = ShopRequest::flag_fulfillable(
+        &mut tx,
+        updated_merchandise_list.shop_id,
+        &updated_merchandise_list.form_list,
+    )
+    .await
+    .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    publish_flagged_requests(&env, updated_merchandise_list.shop_id, &flagged_requests).await;
     let url = updated_merchandise_list
         .url(&env.api_url)
         .map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => {
+            Box::new(MinimalReply::from_resource(&updated_merchandise_list).map_err(reject_anyhow)?)
+        }
         ContentType::Bincode => Box::new(
             ETagReply::<Bincode>::from_serializable(&updated_merchandise_list)
                 .map_err(reject_anyhow)?,
         ),
         ContentType::Json => Box::new(
-            ETagReply::<Json>::from_serializable(&updated_merchandise_list)
+            ETagReply::<Json>::from_resource(&updated_merchandise_list, &env.api_url)
                 .map_err(reject_anyhow)?,
         ),
     };
+    let reply = with_resource_usage(
+        reply,
+        merchandise_usage(&updated_merchandise_list, env.config.max_merchandise_items),
+    );
     let reply = with_header(reply, "Location", url.as_str());
-    let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES.merchandise_list.delete_response(id).await;
-        CACHES.merchandise_list_bin.delete_response(id).await;
-        CACHES
-            .merchandise_list_by_shop_id
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES
-            .merchandise_list_by_shop_id_bin
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES.list_merchandise_lists.clear().await;
-        CACHES.list_merchandise_lists_bin.clear().await;
-    });
+    let status = if return_minimal {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    let reply = with_status(reply, status);
+    env.caches
+        .invalidate(InvalidationEvent::MerchandiseListSaved {
+            list_id: id,
+            shop_id: updated_merchandise_list.shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &updated_merchandise_list).await;
     Ok(reply)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn update_by_shop_id(
     shop_id: i32,
     bytes: Bytes,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
+    target_shop_id: Option<i32>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
-    let DeserializedBody {
-        body: merchandise_list,
-        content_type,
-    } = DeserializedBody::<PostedMerchandiseList>::from_bytes(bytes, content_type)
+    check_target_shop(shop_id, target_shop_id).map_err(reject_anyhow)?;
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
+    let (
+        DeserializedBody {
+            body: merchandise_list,
+            content_type,
+        },
+        _warnings,
+    ) = deserialize_and_validate(bytes, content_type, env.config.max_merchandise_items)
         .map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    Shop::lock_for_key_share(&mut tx, shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    if if_match.is_some() {
+        let current = MerchandiseList::get_by_shop_id_for_update(&mut tx, shop_id)
+            .await
+            .map_err(reject_anyhow)?;
+        check_list_if_match(&current, owner_id, if_match.as_deref()).map_err(reject_anyhow)?;
+    }
     let updated_merchandise_list =
-        MerchandiseList::update_by_shop_id(merchandise_list, &env.db, owner_id, shop_id)
+        MerchandiseList::update_by_shop_id(merchandise_list, &mut tx, owner_id, shop_id)
             .await
             .map_err(reject_anyhow)?;
+    Shop::record_activity(&mut tx, updated_merchandise_list.shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    let flagged_requests = ShopRequest::flag_fulfillable(
+        &mut tx,
+        updated_merchandise_list.shop_id,
+        &updated_merchandise_list.form_list,
+    )
+    .await
+    .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    publish_flagged_requests(&env, updated_merchandise_list.shop_id, &flagged_requests).await;
     let url = updated_merchandise_list
         .url(&env.api_url)
         .map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => {
+            Box::new(MinimalReply::from_resource(&updated_merchandise_list).map_err(reject_anyhow)?)
+        }
         ContentType::Bincode => Box::new(
             ETagReply::<Bincode>::from_serializable(&updated_merchandise_list)
                 .map_err(reject_anyhow)?,
         ),
         ContentType::Json => Box::new(
-            ETagReply::<Json>::from_serializable(&updated_merchandise_list)
+            ETagReply::<Json>::from_resource(&updated_merchandise_list, &env.api_url)
                 .map_err(reject_anyhow)?,
         ),
     };
+    let reply = with_resource_usage(
+        reply,
+        merchandise_usage(&updated_merchandise_list, env.config.max_merchandise_items),
+    );
     let reply = with_header(reply, "Location", url.as_str());
-    let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES
-            .merchandise_list
-            .delete_response(updated_merchandise_list.id)
-            .await;
-        CACHES
-            .merchandise_list_bin
-            .delete_response(updated_merchandise_list.id)
-            .await;
-        CACHES
-            .merchandise_list_by_shop_id
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES
-            .merchandise_list_by_shop_id_bin
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES.list_merchandise_lists.clear().await;
-        CACHES.list_merchandise_lists_bin.clear().await;
-    });
+    let status = if return_minimal {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    let reply = with_status(reply, status);
+    env.caches
+        .invalidate(InvalidationEvent::MerchandiseListSaved {
+            list_id: updated_merchandise_list.id,
+            shop_id: updated_merchandise_list.shop_id,
+            owner_id,
+        })
+        .await;
+    warm_caches(&env, &updated_merchandise_list).await;
     Ok(reply)
 }
 
 pub async fn delete(
     id: i32,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
-    let merchandise_list = MerchandiseList::get(&env.db, id)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let merchandise_list = MerchandiseList::get_for_update(&mut tx, id)
         .await
         .map_err(reject_anyhow)?;
-    MerchandiseList::delete(&env.db, owner_id, id)
+    check_list_if_match(&merchandise_list, owner_id, if_match.as_deref()).map_err(reject_anyhow)?;
+    MerchandiseList::delete(&mut tx, owner_id, id)
         .await
         .map_err(reject_anyhow)?;
-    tokio::spawn(async move {
-        CACHES.merchandise_list.delete_response(id).await;
-        CACHES.merchandise_list_bin.delete_response(id).await;
-        CACHES
-            .merchandise_list_by_shop_id
-            .delete_response(merchandise_list.shop_id)
-            .await;
-        CACHES
-            .merchandise_list_by_shop_id_bin
-            .delete_response(merchandise_list.shop_id)
-            .await;
-        CACHES.list_merchandise_lists.clear().await;
-        CACHES.list_merchandise_lists_bin.clear().await;
-    });
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    env.caches
+        .invalidate(InvalidationEvent::MerchandiseListDeleted {
+            list_id: id,
+            shop_id: merchandise_list.shop_id,
+            owner_id,
+        })
+        .await;
     Ok(StatusCode::NO_CONTENT)
 }
+
+// Runs the same pipeline as `update_by_shop_id`, including the ownership check, but never writes
+pub async fn validate_by_shop_id(
+    shop_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let (
+        DeserializedBody {
+            body: merchandise_list,
+            content_type,
+        },
+        warnings,
+    ) = deserialize_and_validate(bytes, content_type, env.config.max_merchandise_items)
+        .map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let shop = Shop::get(&env.db, shop_id).await.map_err(reject_anyhow)?;
+    if shop.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let content_etag = match content_type {
+        ContentType::Bincode => {
+            ETagReply::<Bincode>::from_serializable(&merchandise_list.form_list)
+                .map_err(reject_anyhow)?
+                .etag()
+                .to_string()
+        }
+        ContentType::Json => ETagReply::<Json>::from_serializable(&merchandise_list.form_list)
+            .map_err(reject_anyhow)?
+            .etag()
+            .to_string(),
+    };
+    Ok(json(&ValidationSummary {
+        item_count: merchandise_list.form_list.len(),
+        total_quantity: merchandise_list
+            .form_list
+            .iter()
+            .map(|merchandise| merchandise.quantity as u64)
+            .sum(),
+        content_etag,
+        warnings,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use std::time::Duration;
+    use warp::http::StatusCode;
+
+    use crate::models::merchandise_list::LegacyMerchandise;
+    use crate::models::{LegacyPostedMerchandiseList, MerchandiseList, PostedMerchandiseList};
+    use crate::test_support::{
+        assert_one_deleted, assert_problem, json_body, json_request, request, TestEnv,
+        OTHER_OWNER_API_KEY, OWNER_API_KEY,
+    };
+
+    fn merchandise_list(shop_id: i64) -> Value {
+        json!({ "shop_id": shop_id, "form_list": [] })
+    }
+
+    #[tokio::test]
+    async fn shop_id_cannot_change_and_lists_only_attach_to_own_shops() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let other_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Other Shop" }))
+            .await;
+        let by_shop_path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let response = test.send(request("GET", &by_shop_path, None)).await;
+        let list_id = json_body(&response)["id"].as_i64().unwrap();
+        let path = format!("/v1/merchandise_lists/{}", list_id);
+
+        for path in &[&path, &by_shop_path] {
+            let response = test
+                .send(json_request(
+                    "PATCH",
+                    path,
+                    Some(OWNER_API_KEY),
+                    &merchandise_list(other_shop_id),
+                ))
+                .await;
+            let problem = assert_problem(&response, StatusCode::CONFLICT);
+            assert_eq!(problem["code"], "shop_id_immutable");
+
+            let response = test
+                .send(json_request(
+                    "PATCH",
+                    path,
+                    Some(OWNER_API_KEY),
+                    &merchandise_list(shop_id),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            assert_eq!(json_body(&response)["shop_id"], shop_id);
+        }
+
+        // Once the shop has no list, only its owner can create one for it.
+        let response = test
+            .send(request("DELETE", &path, Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/merchandise_lists",
+                Some(OTHER_OWNER_API_KEY),
+                &merchandise_list(shop_id),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/merchandise_lists",
+                Some(OWNER_API_KEY),
+                &merchandise_list(shop_id),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["shop_id"], shop_id);
+    }
+
+    #[tokio::test]
+    async fn bodies_are_refused_when_x_target_shop_names_another_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let other_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Other Shop" }))
+            .await;
+        let body = bincode::serialize(&PostedMerchandiseList {
+            shop_id: shop_id as i32,
+            owner_id: None,
+            form_list: sqlx::types::Json(vec![]),
+            expected_revision: None,
+        })
+        .unwrap();
+        let update = |path_shop_id: i64, target_shop_id: Option<i64>| {
+            let request = request(
+                "PATCH",
+                &format!("/v1/shops/{}/merchandise_list", path_shop_id),
+                Some(OWNER_API_KEY),
+            )
+            .header("content-type", "application/octet-stream")
+            .body(body.clone());
+            match target_shop_id {
+                Some(target_shop_id) => {
+                    request.header("x-target-shop", target_shop_id.to_string().as_str())
+                }
+                None => request,
+            }
+        };
+
+        let response = test.send(update(other_shop_id, Some(shop_id))).await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "target_shop_mismatch");
+        let response = test.send(update(shop_id, Some(shop_id))).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test.send(update(shop_id, None)).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn lists_are_created_under_their_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let body = json!({ "form_list": [] });
+        let response = test.send(request("GET", &path, None)).await;
+        let list_id = json_body(&response)["id"].as_i64().unwrap();
+
+        let response = test
+            .send(json_request("POST", &path, Some(OWNER_API_KEY), &body))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "list_exists");
+        assert_eq!(problem["list_id"], list_id);
+        assert!(problem["list_url"].as_str().unwrap().ends_with(&path));
+
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/merchandise_lists/{}", list_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(json_request(
+                "POST",
+                &path,
+                Some(OTHER_OWNER_API_KEY),
+                &body,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+
+        // A shop_id that agrees with the path is accepted.
+        let response = test
+            .send(json_request(
+                "POST",
+                &path,
+                Some(OWNER_API_KEY),
+                &merchandise_list(shop_id),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert!(response.headers()["location"]
+            .to_str()
+            .unwrap()
+            .ends_with(&path));
+        assert_eq!(json_body(&response)["shop_id"], shop_id);
+        let response = test
+            .send(json_request("POST", &path, Some(OWNER_API_KEY), &body))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "list_exists");
+    }
+
+    #[tokio::test]
+    async fn retried_creates_get_the_list_the_first_attempt_saved() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let by_shop_path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let response = test.send(request("GET", &by_shop_path, None)).await;
+        let list_id = json_body(&response)["id"].as_i64().unwrap();
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/merchandise_lists/{}", list_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let list = |price: i64| {
+            json!({ "shop_id": shop_id, "form_list": [{
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 1,
+                "name": "Iron Sword",
+                "quantity": 1,
+                "form_type": 41,
+                "is_food": false,
+                "price": price,
+                "keywords": [],
+            }] })
+        };
+        let create = |body: &Value, api_key| {
+            json_request("POST", "/v1/merchandise_lists", Some(api_key), body)
+        };
+
+        let response = test.send(create(&list(100), OWNER_API_KEY)).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let created = json_body(&response);
+
+        // The response to the first attempt was lost, so the client sends it again.
+        let response = test.send(create(&list(100), OWNER_API_KEY)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert!(response.headers()["location"]
+            .to_str()
+            .unwrap()
+            .ends_with(&by_shop_path));
+        assert_eq!(json_body(&response)["id"], created["id"]);
+
+        let response = test.send(create(&list(200), OWNER_API_KEY)).await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "list_exists");
+        assert_eq!(problem["list_id"], created["id"]);
+
+        let response = test.send(create(&list(100), OTHER_OWNER_API_KEY)).await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn oversized_items_and_lists_are_rejected() {
+        let test = match TestEnv::with_config(&[("MAX_MERCHANDISE_ITEMS", "2")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let item = |local_form_id: u32, name: String, keywords: Vec<String>| {
+            json!({
+                "mod_name": "Skyrim.esm",
+                "local_form_id": local_form_id,
+                "name": name,
+                "quantity": 1,
+                "form_type": 41,
+                "is_food": false,
+                "price": 100,
+                "keywords": keywords,
+            })
+        };
+        let keyword = "VendorItemWeapon".to_string();
+
+        let list = json!({
+            "shop_id": shop_id,
+            "form_list": [
+                item(1, "Iron Sword".to_string(), vec![keyword.clone()]),
+                item(2, "x".repeat(257), vec![keyword.clone(); 26]),
+            ],
+        });
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &list))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors = problem["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|error| error["index"] == 1));
+
+        let list = json!({
+            "shop_id": shop_id,
+            "form_list": [
+                item(1, "Iron Sword".to_string(), vec![keyword.clone()]),
+                item(2, "Steel Sword".to_string(), vec![keyword.clone()]),
+                item(3, "Ebony Sword".to_string(), vec![keyword.clone()]),
+            ],
+        });
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &list))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "form_list");
+
+        // Transactions carry the same fields, and are held to the same caps.
+        let mut transaction = item(1, "Iron Sword".to_string(), vec!["k".repeat(65)]);
+        transaction["shop_id"] = json!(shop_id);
+        transaction["is_sell"] = json!(false);
+        transaction["amount"] = json!(100);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction,
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "keywords");
+    }
+
+    #[tokio::test]
+    async fn conditional_gets_use_the_etag_index_until_a_mutation() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let response = test.send(request("GET", &path, None)).await;
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let cache = &test.env.caches.merchandise_list_by_shop_id;
+        while cache.indexed_etag(&(shop_id as i32)).await.is_none() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        // Only the response is dropped, as if it had been evicted.
+        cache.lru_mutex.lock().await.clear();
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", etag.as_str()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert!(cache.lru_mutex.lock().await.is_empty());
+
+        let mut list = merchandise_list(shop_id);
+        list["form_list"] = json!([{
+            "mod_name": "Skyrim.esm",
+            "local_form_id": 1,
+            "name": "Iron Sword",
+            "quantity": 1,
+            "form_type": 41,
+            "is_food": false,
+            "price": 100,
+            "keywords": [],
+        }]);
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &list))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", etag.as_str()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response.headers()["etag"], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn if_match_takes_the_etag_served_to_old_clients() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let mut list = merchandise_list(shop_id);
+        list["form_list"] = json!([{
+            "mod_name": "Skyrim.esm",
+            "local_form_id": 1,
+            "name": "Iron Sword",
+            "quantity": 1,
+            "form_type": 41,
+            "is_food": false,
+            "price": 100,
+            "keywords": [],
+        }]);
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &list))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        // No `X-Schema-Version`, so the body is downgraded to v1 but keeps the list's ETag.
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response)["form_list"][0]["form_type"], 41);
+        let read_etag = response.headers()["etag"].clone();
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", read_etag.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        list["form_list"][0]["price"] = json!(90);
+        let response = test
+            .send(
+                json_request("PATCH", &path, Some(OWNER_API_KEY), &list)
+                    .header("if-match", read_etag.clone()),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        // The write changed the ETag, so the one read before it is now stale.
+        list["form_list"][0]["price"] = json!(80);
+        let response = test
+            .send(
+                json_request("PATCH", &path, Some(OWNER_API_KEY), &list)
+                    .header("if-match", read_etag),
+            )
+            .await;
+        assert_problem(&response, StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn saved_lists_are_warmed_into_the_caches() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &path,
+                Some(OWNER_API_KEY),
+                &merchandise_list(shop_id),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        while test.env.caches.invalidations.stats().depth > 0 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert_eq!(test.env.caches.invalidations.stats().warmed, 4);
+
+        let cache = &test.env.caches.merchandise_list_by_shop_id;
+        let before = cache.stats();
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response)["revision"], 2);
+        let after = cache.stats();
+        assert_eq!(after.hits, before.hits + 1);
+        assert_eq!(after.misses, before.misses);
+    }
+
+    #[tokio::test]
+    async fn lists_round_trip_in_both_schema_versions() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let list = |form_field: &str| {
+            json!({ "shop_id": shop_id, "form_list": [{
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 1,
+                "name": "Iron Sword",
+                "quantity": 1,
+                form_field: 41,
+                "is_food": false,
+                "price": 100,
+                "keywords": [],
+            }] })
+        };
+
+        let response = test
+            .send(
+                json_request("PATCH", &path, Some(OWNER_API_KEY), &list("form_type"))
+                    .header("x-schema-version", "2"),
+            )
+            .await;
+        assert_problem(&response, StatusCode::BAD_REQUEST);
+        let response = test
+            .send(
+                json_request("PATCH", &path, Some(OWNER_API_KEY), &list("form_kind"))
+                    .header("x-schema-version", "2"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["form_list"][0]["form_kind"], 41);
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &path,
+                Some(OWNER_API_KEY),
+                &list("form_type"),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.headers()["x-schema-version"], "1");
+        let v1_etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let v1 = json_body(&response);
+        assert_eq!(v1["form_list"][0]["form_type"], 41);
+        assert!(v1["form_list"][0].get("form_kind").is_none());
+        let response = test
+            .send(request("GET", &path, None).header("x-schema-version", "2"))
+            .await;
+        assert_eq!(response.headers()["x-schema-version"], "2");
+        // Both versions are the same list, so they share its ETag.
+        assert_eq!(response.headers()["etag"], v1_etag.as_str());
+        let v2 = json_body(&response);
+        assert_eq!(v2["form_list"][0]["form_kind"], 41);
+        assert!(v2["form_list"][0].get("form_type").is_none());
+        // Everything but the renamed field is the same.
+        assert_eq!(v2["revision"], v1["revision"]);
+        assert_eq!(v2["form_list"][0]["name"], v1["form_list"][0]["name"]);
+
+        // The translated body is cached, and old clients can still revalidate their copy without
+        // it being translated at all.
+        let translations = &test.env.caches.schema_translations;
+        while translations.lru_mutex.lock().await.is_empty() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        let hits = translations.stats().hits;
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(json_body(&response), v1);
+        assert_eq!(translations.stats().hits, hits + 1);
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", v1_etag.as_str()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(translations.stats().hits, hits + 1);
+        let response = test
+            .send(request("GET", &path, None).header("x-schema-version", "3"))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "invalid_schema_version");
+    }
+
+    #[tokio::test]
+    async fn icon_keys_round_trip_and_old_clients_keep_them() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let list = |icon_key: Option<&str>| {
+            let mut item = json!({
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 1,
+                "name": "Iron Sword",
+                "quantity": 1,
+                "form_type": 41,
+                "is_food": false,
+                "price": 100,
+                "keywords": [],
+            });
+            if let Some(icon_key) = icon_key {
+                item["icon_key"] = json!(icon_key);
+            }
+            json!({ "shop_id": shop_id, "form_list": [item] })
+        };
+        let patch = |body: &Value| json_request("PATCH", &path, Some(OWNER_API_KEY), body);
+        let icon_key = "Weapons\\Iron\\LongSword.nif";
+
+        let response = test.send(patch(&list(None))).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(
+            json_body(&response)["form_list"][0]["icon_key"],
+            json!(null)
+        );
+        let response = test.send(patch(&list(Some(icon_key)))).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["form_list"][0]["icon_key"], icon_key);
+        // Clients that leave it out don't clear it.
+        let response = test.send(patch(&list(None))).await;
+        assert_eq!(json_body(&response)["form_list"][0]["icon_key"], icon_key);
+        let response = test.send(patch(&list(Some("../icon?.png")))).await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["index"], 0);
+
+        // Bincode bodies from before `icon_key` still decode, and keep it too.
+        let legacy = LegacyPostedMerchandiseList {
+            shop_id: shop_id as i32,
+            owner_id: None,
+            form_list: sqlx::types::Json(vec![LegacyMerchandise {
+                mod_name: "Skyrim.esm".to_string(),
+                local_form_id: crate::models::FormId(1),
+                name: "Iron Sword".to_string(),
+                quantity: 2,
+                form_kind: 41,
+                is_food: false,
+                price: 100,
+                keywords: vec![],
+            }]),
+            expected_revision: None,
+        };
+        let bincode_patch = |body: Vec<u8>| {
+            request("PATCH", &path, Some(OWNER_API_KEY))
+                .header("content-type", "application/octet-stream")
+                .header("accept", "application/octet-stream")
+                .body(body)
+        };
+        let response = test
+            .send(bincode_patch(bincode::serialize(&legacy).unwrap()))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let saved: MerchandiseList = bincode::deserialize(response.body()).unwrap();
+        assert_eq!(saved.form_list[0].quantity, 2);
+        assert_eq!(saved.form_list[0].icon_key.as_deref(), Some(icon_key));
+
+        let mut current: PostedMerchandiseList = legacy.into();
+        current.form_list[0].icon_key = Some("0a1b2c3d".to_string());
+        let response = test
+            .send(bincode_patch(bincode::serialize(&current).unwrap()))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let saved: MerchandiseList = bincode::deserialize(response.body()).unwrap();
+        assert_eq!(saved.form_list[0].icon_key.as_deref(), Some("0a1b2c3d"));
+
+        let response = test
+            .send(request("GET", &format!("{}/facets", path), None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(
+            json_body(&response)["icon_keys"],
+            json!([{ "value": "0a1b2c3d", "count": 1 }])
+        );
+    }
+
+    #[tokio::test]
+    async fn transactions_advance_the_revision() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 100 }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(json_body(&response)["revision"], 1);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OTHER_OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "Cabbage",
+                    "form_type": 46,
+                    "is_food": true,
+                    "price": 1,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 1,
+                    "keywords": [],
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        // The owner's copy from before the sale is stale.
+        let mut body = merchandise_list(shop_id);
+        body["expected_revision"] = json!(1);
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &body))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["current_revision"], 2);
+        body["expected_revision"] = json!(2);
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &body))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["revision"], 3);
+    }
+
+    #[tokio::test]
+    async fn concurrent_deletes_of_a_list_delete_it_once() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                None,
+            ))
+            .await;
+        let list_id = json_body(&response)["id"].clone();
+        let delete = || {
+            request(
+                "DELETE",
+                &format!("/v1/merchandise_lists/{}", list_id),
+                Some(OWNER_API_KEY),
+            )
+        };
+        let (first, second) = test.send_concurrently(delete(), delete()).await;
+        assert_one_deleted(&first, &second);
+        let response = test.send(delete()).await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn restocks_edits_and_transactions_record_signed_changes() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 100 }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let list = |quantity: i32| {
+            json!({
+                "shop_id": shop_id,
+                "form_list": [{
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 1,
+                    "name": "Iron Sword",
+                    "quantity": quantity,
+                    "form_type": 41,
+                    "is_food": false,
+                    "price": 10,
+                    "keywords": [],
+                }],
+            })
+        };
+        let transaction = |is_sell: bool, quantity: i32| {
+            json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 1,
+                "name": "Iron Sword",
+                "form_type": 41,
+                "is_food": false,
+                "price": 10,
+                "is_sell": is_sell,
+                "quantity": quantity,
+                "amount": 10 * quantity,
+                "keywords": [],
+            })
+        };
+
+        // Replace the empty list the shop was created with, so the stock arrives as a restock.
+        let response = test.send(request("GET", &path, None)).await;
+        let list_id = json_body(&response)["id"].as_i64().unwrap();
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/merchandise_lists/{}", list_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(json_request("POST", &path, Some(OWNER_API_KEY), &list(5)))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &list(3)))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        for (is_sell, quantity) in &[(false, 1), (true, 2)] {
+            let response = test
+                .send(json_request(
+                    "POST",
+                    "/v1/transactions",
+                    Some(OTHER_OWNER_API_KEY),
+                    &transaction(*is_sell, *quantity),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        }
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_changes", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let changes: Vec<(i64, String)> = json_body(&response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| {
+                assert_eq!(change["shop_id"], shop_id);
+                assert_eq!(change["local_form_id"], "0x00000001");
+                (
+                    change["quantity_delta"].as_i64().unwrap(),
+                    change["reason"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (5, "restock".to_string()),
+                (-2, "manual".to_string()),
+                (-1, "transaction".to_string()),
+                (2, "transaction".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn facets_count_hundreds_of_items_with_overlapping_keywords() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let facets_path = format!("{}/facets", path);
+        let form_kinds = [41, 26, 46];
+        let list = |items: u32| {
+            let form_list: Vec<Value> = (0..items)
+                .map(|i| {
+                    let mut keywords = vec!["Common"];
+                    if i % 2 == 0 {
+                        keywords.push("Even");
+                    }
+                    if i % 3 == 0 {
+                        keywords.push("Third");
+                    }
+                    // A keyword listed twice still only counts the item once.
+                    if i % 7 == 0 {
+                        keywords.push("Common");
+                    }
+                    json!({
+                        "mod_name": "Skyrim.esm",
+                        "local_form_id": i + 1,
+                        "name": format!("Item {}", i),
+                        "quantity": 1,
+                        "form_type": form_kinds[i as usize % 3],
+                        "is_food": i % 5 == 0,
+                        "price": 10,
+                        "keywords": keywords,
+                    })
+                })
+                .collect();
+            json!({ "shop_id": shop_id, "form_list": form_list })
+        };
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &path,
+                Some(OWNER_API_KEY),
+                &list(300),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test.send(request("GET", &facets_path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let facets = json_body(&response);
+        assert_eq!(
+            facets["keywords"],
+            json!([
+                { "value": "Common", "count": 300 },
+                { "value": "Even", "count": 150 },
+                { "value": "Third", "count": 100 },
+            ])
+        );
+        assert_eq!(
+            facets["form_types"],
+            json!([
+                { "value": 26, "count": 100 },
+                { "value": 41, "count": 100 },
+                { "value": 46, "count": 100 },
+            ])
+        );
+        assert_eq!(
+            facets["is_food_counts"],
+            json!({ "true": 60, "false": 240 })
+        );
+
+        // Saving the list evicts the cached facets.
+        let response = test
+            .send(json_request("PATCH", &path, Some(OWNER_API_KEY), &list(4)))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test.send(request("GET", &facets_path, None)).await;
+        let facets = json_body(&response);
+        assert_eq!(
+            facets["keywords"][0],
+            json!({ "value": "Common", "count": 4 })
+        );
+        assert_eq!(facets["is_food_counts"], json!({ "true": 1, "false": 3 }));
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_list/facets", shop_id + 100),
+                None,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn validation_reports_counts_and_writes_nothing() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let by_shop_path = format!("/v1/shops/{}/merchandise_list", shop_id);
+        let validate_path = format!("{}/validate", by_shop_path);
+        let before = test.send(request("GET", &by_shop_path, None)).await;
+        let item = |local_form_id: i32, quantity: i32, keywords: Value| {
+            json!({
+                "mod_name": "Skyrim.esm",
+                "local_form_id": local_form_id,
+                "name": "Iron Sword",
+                "quantity": quantity,
+                "form_type": 41,
+                "is_food": false,
+                "price": 100,
+                "keywords": keywords,
+            })
+        };
+        let mut list = merchandise_list(shop_id);
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(
+            json_body(&response)["warnings"],
+            json!(["form_list is empty"])
+        );
+
+        list["form_list"] = json!([
+            item(1, 2, json!(["WeapMaterialIron"])),
+            item(2, 3, json!([])),
+        ]);
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let summary = json_body(&response);
+        assert_eq!(summary["item_count"], 2);
+        assert_eq!(summary["total_quantity"], 5);
+        assert_eq!(summary["warnings"], json!(["form_list[1] has no keywords"]));
+        let content_etag = summary["content_etag"].as_str().unwrap().to_string();
+        assert!(!content_etag.is_empty());
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_eq!(json_body(&response)["content_etag"], content_etag);
+
+        let after = test.send(request("GET", &by_shop_path, None)).await;
+        assert_eq!(json_body(&after)["form_list"], json!([]));
+        assert_eq!(after.headers()["etag"], before.headers()["etag"]);
+
+        // Invalid lists get the same problem an upload would.
+        list["form_list"] = json!([item(1, 2, json!([])), item(2, 0, json!([]))]);
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "form_list");
+        assert_eq!(problem["errors"][0]["index"], 1);
+        list["form_list"] = json!([item(1, 2, json!([]))]);
+        let response = test
+            .send(json_request(
+                "POST",
+                &validate_path,
+                Some(OTHER_OWNER_API_KEY),
+                &list,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+    }
+}
@@ -1,37 +1,64 @@
-use std::fmt::Debug;
-use std::hash::Hash;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
 use std::marker::PhantomData;
 use std::str::FromStr;
 
 use anyhow::{anyhow, Error, Result};
-use http::header::{HeaderValue, CONTENT_TYPE, ETAG, SERVER};
-use http::StatusCode;
+use http::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, ETAG, SERVER};
+use http::{Method, StatusCode};
 use http_api_problem::HttpApiProblem;
-use hyper::body::Bytes;
+use hyper::body::{self, Body, Bytes, HttpBody};
 use mime::{FromStrError, Mime};
-use seahash::hash;
-use serde::{de::DeserializeOwned, Serialize};
+use seahash::SeaHasher;
+use serde::de::{DeserializeOwned, MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tracing::{debug, error, instrument, warn};
+use url::Url;
 use uuid::Uuid;
 use warp::reply::Response;
-use warp::Reply;
+use warp::{Filter, Rejection, Reply};
 
+pub mod admin;
 pub mod interior_ref_list;
+pub mod links;
+pub mod merchandise_change;
 pub mod merchandise_list;
 pub mod owner;
+pub mod replay;
+pub mod resource_usage;
+pub mod settings;
 pub mod shop;
+pub mod shop_deletion;
+pub mod shop_request;
+pub mod shop_review;
+pub mod status;
 pub mod transaction;
 
-use super::caches::{Cache, CachedResponse, CACHES};
-use super::problem::{unauthorized_no_api_key, unauthorized_no_owner};
+use super::body_digest::{sha256_hex, BODY_SHA256};
+use super::caches::{Cache, CachedResponse, Redact, Scoped, ViewScope};
+use super::json_format::JSON_CONTENT_TYPE;
+use super::models::{Locale, Pagination};
+use super::problem::{
+    forbidden_permission, invalid_schema_version, not_found, precondition_failed, reject_anyhow,
+    target_shop_mismatch, unauthorized_no_api_key, unauthorized_no_owner,
+};
 use super::Environment;
+use links::WithLinks;
 
 pub static SERVER_STRING: &str = "BazaarRealmAPI/0.1.0";
+static PREFERENCE_APPLIED: &str = "preference-applied";
+pub static TOTAL_COUNT: &str = "x-total-count";
+static PAGE_LIMIT: &str = "x-limit";
+static PAGE_OFFSET: &str = "x-offset";
 
 #[instrument(level = "debug", skip(env, api_key))]
 pub async fn authenticate(env: &Environment, api_key: Option<Uuid>) -> Result<i32> {
     if let Some(api_key) = api_key {
-        CACHES
+        env.caches
             .owner_ids_by_api_key
             .get(api_key, || async {
                 Ok(
@@ -53,7 +80,33 @@ pub async fn authenticate(env: &Environment, api_key: Option<Uuid>) -> Result<i3
     }
 }
 
-// Similar to `warp::reply::Json`, but stores hash of body content for the ETag header created in `into_response`.
+/// Like `authenticate`, but for public endpoints that personalize their response when the owner
+/// making the request is known. A missing api key is anonymous (`None`); an unknown one is still
+/// rejected so that clients notice a bad key instead of silently getting the anonymous view.
+#[instrument(level = "debug", skip(env, api_key))]
+pub async fn authenticate_optional(
+    env: &Environment,
+    api_key: Option<Uuid>,
+) -> Result<Option<i32>> {
+    match api_key {
+        Some(_) => Ok(Some(authenticate(env, api_key).await?)),
+        None => Ok(None),
+    }
+}
+
+// Admin endpoints are hidden entirely (404) unless `ADMIN_API_KEYS` is configured.
+pub fn authenticate_admin(env: &Environment, api_key: Option<Uuid>) -> Result<()> {
+    if env.config.admin_api_keys.is_empty() {
+        return Err(not_found("Admin endpoints are disabled"));
+    }
+    match api_key {
+        Some(api_key) if env.config.admin_api_keys.contains(&api_key) => Ok(()),
+        Some(_) => Err(forbidden_permission()),
+        None => Err(unauthorized_no_api_key()),
+    }
+}
+
+// Similar to `warp::reply::Json`, but stores the ETag header created in `into_response`.
 // Also, it does not store a serialize `Result`. Instead it returns the error to the caller immediately in `from_serializable`.
 // It's purpose is to avoid serializing the body content twice and to encapsulate ETag logic in one place.
 pub struct ETagReply<T> {
@@ -62,8 +115,25 @@ pub struct ETagReply<T> {
     content_type: PhantomData<T>,
 }
 
+impl<T> ETagReply<T> {
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    // Replaces the ETag with the canonical one of `val`, for bodies that embed linked resources.
+    pub fn with_etag_of<V: Serialize>(self, val: &V) -> Result<Self> {
+        Ok(Self {
+            etag: canonical_etag(val)?,
+            ..self
+        })
+    }
+}
+
 pub trait DataReply: Reply + Sized {
     fn from_serializable<T: Serialize>(val: &T) -> Result<Self>;
+    // For single resources, which JSON wraps with `_links`. The ETag is the same as for the bare
+    // resource, since the links only depend on the resource and the api url.
+    fn from_resource<T: WithLinks>(resource: &T, api_url: &Url) -> Result<Self>;
 }
 
 pub struct Json {}
@@ -77,11 +147,15 @@ pub enum ContentType {
 
 impl Reply for ETagReply<Json> {
     fn into_response(self) -> Response {
+        let body_sha256 = sha256_hex(&self.body);
         let mut res = Response::new(self.body.into());
         res.headers_mut()
-            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            .insert(CONTENT_TYPE, HeaderValue::from_static(JSON_CONTENT_TYPE));
         res.headers_mut()
             .insert(SERVER, HeaderValue::from_static(SERVER_STRING));
+        if let Ok(val) = HeaderValue::from_str(&body_sha256) {
+            res.headers_mut().insert(BODY_SHA256, val);
+        }
         if let Ok(val) = HeaderValue::from_str(&self.etag) {
             res.headers_mut().insert(ETAG, val);
         } else {
@@ -92,22 +166,33 @@ impl Reply for ETagReply<Json> {
     }
 }
 
+fn serialize_json<T: Serialize>(val: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(val).map_err(|err| {
+        error!("Failed to serialize database value to JSON: {}", err);
+        anyhow!(
+            HttpApiProblem::with_title_and_type_from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                .set_detail(format!(
+                    "Failed to serialize database value to JSON: {}",
+                    err
+                ))
+        )
+    })
+}
+
 impl DataReply for ETagReply<Json> {
     fn from_serializable<T: Serialize>(val: &T) -> Result<Self> {
-        let bytes = serde_json::to_vec(val).map_err(|err| {
-            error!("Failed to serialize database value to JSON: {}", err);
-            anyhow!(HttpApiProblem::with_title_and_type_from_status(
-                StatusCode::INTERNAL_SERVER_ERROR
-            )
-            .set_detail(format!(
-                "Failed to serialize database value to JSON: {}",
-                err
-            )))
-        })?;
-        let etag = format!("{:x}", hash(&bytes));
+        let bytes = serialize_json(val)?;
         Ok(Self {
+            etag: body_etag(&bytes),
             body: bytes,
-            etag,
+            content_type: PhantomData,
+        })
+    }
+
+    fn from_resource<T: WithLinks>(resource: &T, api_url: &Url) -> Result<Self> {
+        Ok(Self {
+            body: serialize_json(&resource.linked(api_url)?)?,
+            etag: canonical_etag(resource)?,
             content_type: PhantomData,
         })
     }
@@ -115,6 +200,7 @@ impl DataReply for ETagReply<Json> {
 
 impl Reply for ETagReply<Bincode> {
     fn into_response(self) -> Response {
+        let body_sha256 = sha256_hex(&self.body);
         let mut res = Response::new(self.body.into());
         res.headers_mut().insert(
             CONTENT_TYPE,
@@ -122,6 +208,9 @@ impl Reply for ETagReply<Bincode> {
         );
         res.headers_mut()
             .insert(SERVER, HeaderValue::from_static(SERVER_STRING));
+        if let Ok(val) = HeaderValue::from_str(&body_sha256) {
+            res.headers_mut().insert(BODY_SHA256, val);
+        }
         if let Ok(val) = HeaderValue::from_str(&self.etag) {
             res.headers_mut().insert(ETAG, val);
         } else {
@@ -144,26 +233,192 @@ impl DataReply for ETagReply<Bincode> {
                 err
             )))
         })?;
-        let etag = format!("{:x}", hash(&bytes));
         Ok(Self {
             body: bytes,
-            etag,
+            etag: canonical_etag(val)?,
             content_type: PhantomData,
         })
     }
+
+    fn from_resource<T: WithLinks>(resource: &T, _api_url: &Url) -> Result<Self> {
+        Self::from_serializable(resource)
+    }
+}
+
+#[derive(Default)]
+struct ETagHasher(SeaHasher);
+
+impl Write for ETagHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ETagHasher {
+    fn etag(&self) -> String {
+        format!("{:x}", self.0.finish())
+    }
+}
+
+/// The ETag of exactly these bytes, for bodies that aren't the canonical serialization.
+pub fn body_etag(bytes: &[u8]) -> String {
+    let mut hasher = ETagHasher::default();
+    hasher.0.write(bytes);
+    hasher.etag()
+}
+
+// ETags are a hash of the JSON serialization (without `_links`) whatever format the body is sent
+// in, so a client or CDN can revalidate a response fetched as JSON with one fetched as bincode.
+// The JSON is streamed into the hasher, so bincode replies never allocate it.
+pub fn canonical_etag<T: Serialize>(val: &T) -> Result<String> {
+    let mut hasher = ETagHasher::default();
+    serde_json::to_writer(&mut hasher, val).map_err(|err| {
+        error!("Failed to serialize database value for ETag: {}", err);
+        anyhow!(
+            HttpApiProblem::with_title_and_type_from_status(StatusCode::INTERNAL_SERVER_ERROR)
+                .set_detail(format!(
+                    "Failed to serialize database value for ETag: {}",
+                    err
+                ))
+        )
+    })?;
+    Ok(hasher.etag())
+}
+
+// Whether an `If-None-Match` header matches `etag`. The header may list several ETags, quoted or
+// not, and weak (`W/`) ones still match since CDNs weaken ETags when they recompress a body.
+pub fn if_none_match(header: &str, etag: &str) -> bool {
+    header.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        let candidate = candidate.strip_prefix("W/").unwrap_or(candidate);
+        let candidate = candidate
+            .strip_prefix('"')
+            .and_then(|candidate| candidate.strip_suffix('"'))
+            .unwrap_or(candidate);
+        candidate == "*" || candidate == etag
+    })
+}
+
+/// Fails with a 412 if the request has an `If-Match` header that matches none of `etags`, the
+/// ETags the resource currently has in its different views (e.g. the shop its owner sees and the
+/// one everyone else does). Without the header, the write goes ahead whatever it overwrites.
+pub fn check_if_match(if_match: Option<&str>, etags: &[String]) -> Result<()> {
+    match if_match {
+        Some(header) if !etags.iter().any(|etag| if_none_match(header, etag)) => {
+            Err(precondition_failed())
+        }
+        _ => Ok(()),
+    }
 }
 
 pub fn check_etag(etag: Option<String>, response: CachedResponse) -> CachedResponse {
     if let Some(request_etag) = etag {
         if let Some(response_etag) = response.headers.get("etag") {
-            if request_etag == *response_etag {
-                return CachedResponse::not_modified(response_etag.clone());
+            let matches = response_etag
+                .to_str()
+                .is_ok_and(|response_etag| if_none_match(&request_etag, response_etag));
+            if matches {
+                let mut not_modified = CachedResponse::not_modified(response_etag.clone());
+                copy_page_headers(&response.headers, &mut not_modified.headers);
+                return not_modified;
             }
         }
     }
     response
 }
 
+/// `GET`, or `HEAD` for clients that only want the headers, e.g. to compare a cached copy's ETag
+/// without downloading the body again. Read routes match with this instead of `warp::get()`, and
+/// run the same handler (cache and `check_etag` included) for both. `without_head_body` then drops
+/// the body of the response to a `HEAD`.
+pub fn get_or_head() -> impl Filter<Extract = (), Error = Rejection> + Copy {
+    warp::get().or(warp::head()).unify()
+}
+
+/// Drops the body of a response to a `HEAD`, keeping the `Content-Length` the `GET` would have
+/// had when the body's size is known up front.
+pub fn without_head_body(method: &Method, response: Response) -> Response {
+    if method != Method::HEAD {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    if !parts.headers.contains_key(CONTENT_LENGTH) {
+        if let Some(len) = HttpBody::size_hint(&body).exact() {
+            parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+/// Answers a conditional GET from the ETag index of `cache` when the client's copy is still current,
+/// without touching the cached response or the database.
+pub async fn check_etag_index<K>(
+    etag: &Option<String>,
+    cache: &Cache<K, CachedResponse>,
+    key: &K,
+) -> Option<CachedResponse>
+where
+    K: Eq + Hash + Redact + Clone + Send + 'static,
+{
+    let request_etag = etag.as_deref()?;
+    let indexed_etag = cache.indexed_etag(key).await?;
+    if indexed_etag
+        .to_str()
+        .is_ok_and(|indexed_etag| if_none_match(request_etag, indexed_etag))
+    {
+        Some(CachedResponse::not_modified(indexed_etag))
+    } else {
+        None
+    }
+}
+
+/// A page of a list endpoint. The body stays the bare list clients already parse, and the number of
+/// rows matching the filters across all pages goes in `X-Total-Count`, with the page's bounds
+/// echoed in `X-Limit` and `X-Offset`. Being headers, they are cached along with the body.
+pub struct PageReply<R> {
+    reply: R,
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+impl<R> PageReply<R> {
+    pub fn new(reply: R, total: i64, pagination: &Pagination) -> Self {
+        Self {
+            reply,
+            total,
+            limit: pagination.limit(),
+            offset: pagination.offset(),
+        }
+    }
+}
+
+impl<R: Reply> Reply for PageReply<R> {
+    fn into_response(self) -> Response {
+        let mut res = self.reply.into_response();
+        let headers = res.headers_mut();
+        headers.insert(TOTAL_COUNT, HeaderValue::from(self.total));
+        headers.insert(PAGE_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(PAGE_OFFSET, HeaderValue::from(self.offset));
+        res
+    }
+}
+
+// Rows can be added or removed on other pages without changing this one's body or ETag, so a 304
+// still carries the page headers for the client to refresh its stored copy with.
+pub fn copy_page_headers(from: &HeaderMap, to: &mut HeaderMap) {
+    for header in &[TOTAL_COUNT, PAGE_LIMIT, PAGE_OFFSET] {
+        if let Some(value) = from.get(*header) {
+            to.insert(*header, value.clone());
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct AcceptHeader {
     mimes: Vec<Mime>,
@@ -188,9 +443,103 @@ impl AcceptHeader {
     }
 }
 
+/// The `Prefer` request header (RFC 7240). Only `return=minimal` is understood, other preferences
+/// are ignored as the RFC allows.
+#[derive(Debug, Default, PartialEq)]
+pub struct PreferHeader {
+    pub return_minimal: bool,
+}
+
+impl FromStr for PreferHeader {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Infallible> {
+        Ok(Self {
+            return_minimal: s
+                .split(',')
+                .filter_map(|preference| preference.split(';').next())
+                .any(|preference| {
+                    let mut parts = preference.splitn(2, '=').map(str::trim);
+                    parts
+                        .next()
+                        .is_some_and(|token| token.eq_ignore_ascii_case("return"))
+                        && parts.next().is_some_and(|value| {
+                            value.trim_matches('"').eq_ignore_ascii_case("minimal")
+                        })
+                }),
+        })
+    }
+}
+
+/// The `Accept-Language` request header, reduced to the one locale the client prefers most. Ranges
+/// that are not locales, like `*`, are ignored, and so is a header with none.
+#[derive(Debug, Default, PartialEq)]
+pub struct AcceptLanguageHeader {
+    pub preferred: Option<Locale>,
+}
+
+impl FromStr for AcceptLanguageHeader {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Infallible> {
+        let mut preferred: Option<(Locale, f32)> = None;
+        for range in s.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let locale = match params.next().and_then(Locale::parse) {
+                Some(locale) => locale,
+                None => continue,
+            };
+            let quality = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |quality| quality.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if quality > 0.0 && preferred.as_ref().is_none_or(|(_, best)| quality > *best) {
+                preferred = Some((locale, quality));
+            }
+        }
+        Ok(Self {
+            preferred: preferred.map(|(locale, _)| locale),
+        })
+    }
+}
+
+// Reply to a create or update sent with `Prefer: return=minimal`: no body, which also skips
+// serializing one, but the same ETag a GET of the resource would have so the client can revalidate
+// its own copy of what it sent.
+pub struct MinimalReply {
+    etag: String,
+}
+
+impl MinimalReply {
+    pub fn from_resource<T: Serialize>(val: &T) -> Result<Self> {
+        Ok(Self {
+            etag: canonical_etag(val)?,
+        })
+    }
+}
+
+impl Reply for MinimalReply {
+    fn into_response(self) -> Response {
+        let mut res = Response::new(Vec::new().into());
+        res.headers_mut()
+            .insert(SERVER, HeaderValue::from_static(SERVER_STRING));
+        res.headers_mut().insert(
+            PREFERENCE_APPLIED,
+            HeaderValue::from_static("return=minimal"),
+        );
+        if let Ok(val) = HeaderValue::from_str(&self.etag) {
+            res.headers_mut().insert(ETAG, val);
+        } else {
+            // This should never happen in practice since etag values should only be hex-encoded strings
+            warn!("omitting etag header with invalid ASCII characters")
+        }
+        res
+    }
+}
+
 pub struct DeserializedBody<T> {
-    body: T,
-    content_type: ContentType,
+    pub body: T,
+    pub content_type: ContentType,
 }
 
 impl<T: DeserializeOwned> DeserializedBody<T> {
@@ -218,11 +567,312 @@ impl<T: DeserializeOwned> DeserializedBody<T> {
             }
         }
     }
+
+    /// Like `from_bytes`, but a bincode body that doesn't decode is tried again as `L`, the layout
+    /// `T` had before a field was added to it, since older clients keep sending that. JSON bodies
+    /// don't need this: missing fields there can default.
+    pub fn from_bytes_or_legacy<L>(bytes: Bytes, content_type: Option<Mime>) -> Result<Self>
+    where
+        L: DeserializeOwned + Into<T>,
+    {
+        let is_bincode = content_type
+            .as_ref()
+            .is_some_and(|content_type| *content_type == mime::APPLICATION_OCTET_STREAM);
+        match Self::from_bytes(bytes.clone(), content_type) {
+            Err(error) if is_bincode => match bincode::deserialize::<L>(&bytes) {
+                Ok(legacy) => {
+                    debug!("deserialized bincode body in its legacy layout");
+                    Ok(Self {
+                        content_type: ContentType::Bincode,
+                        body: legacy.into(),
+                    })
+                }
+                Err(_) => Err(error),
+            },
+            result => result,
+        }
+    }
+}
+
+/// Names the shop that a request body was made for, so that a body saved for one shop and
+/// replayed against another shop's url is refused. Optional, but clients should always send it
+/// to the by-shop-id list routes.
+pub static TARGET_SHOP_HEADER: &str = "x-target-shop";
+
+pub fn check_target_shop(shop_id: i32, target_shop_id: Option<i32>) -> Result<()> {
+    match target_shop_id {
+        Some(target_shop_id) if target_shop_id != shop_id => {
+            Err(target_shop_mismatch(shop_id, target_shop_id))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub static SCHEMA_VERSION_HEADER: &str = "x-schema-version";
+
+// Fields renamed since version 1 of the JSON schema, as (version 1 name, current name) pairs.
+const V1_RENAMED_FIELDS: &[(&str, &str)] =
+    &[("form_type", "form_kind"), ("form_types", "form_kinds")];
+
+/// The shape of JSON request and response bodies, picked by the client with `X-Schema-Version`.
+/// Handlers and models only know the current shape: bodies in an older one are upgraded on the
+/// way in (see `extract_body_bytes` in `main.rs`) and downgraded on the way out by
+/// `translate_reply`. Bincode bodies have no field names and are never translated.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SchemaVersion {
+    // Clients that predate the header are on version 1 until it is retired.
+    #[default]
+    V1,
+    V2,
+}
+
+impl FromStr for SchemaVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "1" => Ok(SchemaVersion::V1),
+            "2" => Ok(SchemaVersion::V2),
+            _ => Err(invalid_schema_version(s)),
+        }
+    }
+}
+
+impl SchemaVersion {
+    fn header_value(self) -> HeaderValue {
+        HeaderValue::from_static(match self {
+            SchemaVersion::V1 => "1",
+            SchemaVersion::V2 => "2",
+        })
+    }
+
+    fn renamed_fields(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            SchemaVersion::V1 => V1_RENAMED_FIELDS,
+            SchemaVersion::V2 => &[],
+        }
+    }
+
+    /// Rewrites a JSON request body in this version to the current shape. Bodies that aren't JSON
+    /// are passed through for the handler to reject as it would have anyway.
+    pub fn upgrade(self, bytes: Bytes) -> Bytes {
+        let renamed_fields = self.renamed_fields();
+        if !mentions_any(&bytes, renamed_fields.iter().map(|(old, _)| *old)) {
+            return bytes;
+        }
+        rename_fields(&bytes, |field| {
+            renamed_fields
+                .iter()
+                .find(|(old, _)| *old == field)
+                .map(|(_, current)| *current)
+        })
+        .map(Bytes::from)
+        .unwrap_or(bytes)
+    }
+
+    // Whether a response body in the current shape has anything to downgrade.
+    fn needs_downgrade(self, bytes: &[u8]) -> bool {
+        mentions_any(
+            bytes,
+            self.renamed_fields().iter().map(|(_, current)| *current),
+        )
+    }
+
+    // Rewrites a JSON response body in the current shape to this version.
+    fn downgrade(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let renamed_fields = self.renamed_fields();
+        rename_fields(bytes, |field| {
+            renamed_fields
+                .iter()
+                .find(|(_, current)| *current == field)
+                .map(|(old, _)| *old)
+        })
+    }
+}
+
+// Whether any of the fields appears in the body as a JSON string, a cheap check that skips
+// parsing the (many) bodies that have nothing to rename.
+fn mentions_any<'a>(bytes: &[u8], mut fields: impl Iterator<Item = &'a str>) -> bool {
+    fields.any(|field| {
+        let quoted = format!("\"{}\"", field);
+        bytes
+            .windows(quoted.len())
+            .any(|window| window == quoted.as_bytes())
+    })
+}
+
+fn rename_fields(bytes: &[u8], rename: impl Fn(&str) -> Option<&'static str>) -> Result<Vec<u8>> {
+    let mut json: OrderedJson = serde_json::from_slice(bytes)?;
+    json.rename_fields(&rename);
+    Ok(serde_json::to_vec(&json)?)
+}
+
+// A JSON value that keeps object fields in order, unlike `serde_json::Value` without the
+// `preserve_order` feature, so that a translated body only differs from the original in the
+// renamed fields.
+enum OrderedJson {
+    Object(Vec<(String, OrderedJson)>),
+    Array(Vec<OrderedJson>),
+    Scalar(serde_json::Value),
+}
+
+impl OrderedJson {
+    fn rename_fields(&mut self, rename: &impl Fn(&str) -> Option<&'static str>) {
+        match self {
+            OrderedJson::Object(fields) => {
+                for (field, value) in fields.iter_mut() {
+                    if let Some(renamed) = rename(field) {
+                        *field = renamed.to_string();
+                    }
+                    value.rename_fields(rename);
+                }
+            }
+            OrderedJson::Array(values) => {
+                for value in values.iter_mut() {
+                    value.rename_fields(rename);
+                }
+            }
+            OrderedJson::Scalar(_) => {}
+        }
+    }
+}
+
+impl Serialize for OrderedJson {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            OrderedJson::Object(fields) => {
+                let mut map = serializer.serialize_map(Some(fields.len()))?;
+                for (field, value) in fields {
+                    map.serialize_entry(field, value)?;
+                }
+                map.end()
+            }
+            OrderedJson::Array(values) => serializer.collect_seq(values),
+            OrderedJson::Scalar(value) => value.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for OrderedJson {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(OrderedJsonVisitor)
+    }
+}
+
+struct OrderedJsonVisitor;
+
+impl<'de> Visitor<'de> for OrderedJsonVisitor {
+    type Value = OrderedJson;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any JSON value")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<OrderedJson, E> {
+        Ok(OrderedJson::Scalar(value.into()))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<OrderedJson, E> {
+        Ok(OrderedJson::Scalar(value.into()))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<OrderedJson, E> {
+        Ok(OrderedJson::Scalar(value.into()))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<OrderedJson, E> {
+        Ok(OrderedJson::Scalar(value.into()))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<OrderedJson, E> {
+        Ok(OrderedJson::Scalar(value.into()))
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<OrderedJson, E> {
+        Ok(OrderedJson::Scalar(value.into()))
+    }
+
+    fn visit_unit<E>(self) -> Result<OrderedJson, E> {
+        Ok(OrderedJson::Scalar(serde_json::Value::Null))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<OrderedJson, A::Error> {
+        let mut values = vec![];
+        while let Some(value) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(OrderedJson::Array(values))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<OrderedJson, A::Error> {
+        let mut fields = vec![];
+        while let Some(entry) = map.next_entry()? {
+            fields.push(entry);
+        }
+        Ok(OrderedJson::Object(fields))
+    }
+}
+
+/// Downgrades JSON responses to the request's `X-Schema-Version` once the handler (and its
+/// caches) are done with the current shape. Translated bodies keep the handler's ETag, like
+/// bincode bodies do, so that `If-None-Match` and `If-Match` work the same in every version. They
+/// are cached by version and source body so that each body is only translated once however many
+/// old clients fetch it.
+pub async fn translate_reply(
+    version: SchemaVersion,
+    env: Environment,
+    reply: impl Reply,
+) -> Result<Response, Rejection> {
+    let mut response = reply.into_response();
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.parse::<Mime>().ok())
+        .is_some_and(|content_type| content_type.essence_str() == "application/json");
+    if !is_json {
+        return Ok(response);
+    }
+    response
+        .headers_mut()
+        .insert(SCHEMA_VERSION_HEADER, version.header_value());
+    if version.renamed_fields().is_empty() {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = body::to_bytes(body)
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    if !version.needs_downgrade(&bytes) {
+        return Ok(Response::from_parts(parts, bytes.into()));
+    }
+    let source_etag = parts
+        .headers
+        .get(ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map_or_else(|| body_etag(&bytes), str::to_string);
+    let translated = env
+        .caches
+        .schema_translations
+        .get_response((version, body_etag(&bytes)), || async {
+            let body = version.downgrade(&bytes)?;
+            Ok(ETagReply::<Json> {
+                etag: source_etag.clone(),
+                body,
+                content_type: PhantomData,
+            })
+        })
+        .await?;
+    if let Some(body_sha256) = translated.headers.get(BODY_SHA256) {
+        parts.headers.insert(BODY_SHA256, body_sha256.clone());
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, translated.body.into()))
 }
 
 pub struct TypedCache<'a, K, V>
 where
-    K: Eq + Hash + Debug,
+    K: Eq + Hash + Redact,
     V: Clone,
 {
     cache: &'a Cache<K, V>,
@@ -231,7 +881,7 @@ where
 
 impl<'a, K, V> TypedCache<'a, K, V>
 where
-    K: Eq + Hash + Debug,
+    K: Eq + Hash + Redact,
     V: Clone,
 {
     pub fn pick_cache(
@@ -260,3 +910,172 @@ where
         }
     }
 }
+
+/// A `TypedCache` of responses that differ by viewer, with the scope the viewer's responses are
+/// cached in. Handlers key every lookup with `scope.key(id)`, so that a response is only ever
+/// served in the scope it was cached for.
+pub struct ScopedTypedCache<'a, K, V>
+where
+    K: Eq + Hash + Redact,
+    V: Clone,
+{
+    cache: &'a Cache<Scoped<K>, V>,
+    content_type: ContentType,
+    scope: ViewScope,
+}
+
+impl<'a, K, V> ScopedTypedCache<'a, K, V>
+where
+    K: Eq + Hash + Redact,
+    V: Clone,
+{
+    /// Like `TypedCache::pick_cache`, in the scope `resolve_scope` returns, whatever the format:
+    /// an owner's bincode carries their private fields just like their JSON does.
+    pub async fn pick_cache<G, F>(
+        accept: Option<AcceptHeader>,
+        bincode_cache: &'a Cache<Scoped<K>, V>,
+        json_cache: &'a Cache<Scoped<K>, V>,
+        resolve_scope: G,
+    ) -> Result<Self>
+    where
+        G: FnOnce() -> F,
+        F: Future<Output = Result<ViewScope>>,
+    {
+        let TypedCache {
+            cache,
+            content_type,
+        } = TypedCache::pick_cache(accept, bincode_cache, json_cache);
+        Ok(Self {
+            cache,
+            content_type,
+            scope: resolve_scope().await?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::body::Bytes;
+    use serde_json::json;
+    use url::Url;
+
+    use super::{
+        canonical_etag, check_if_match, if_none_match, AcceptLanguageHeader, Bincode, DataReply,
+        ETagReply, Json, PreferHeader, SchemaVersion,
+    };
+    use crate::models::{Locale, MerchandiseList};
+
+    fn merchandise_list() -> MerchandiseList {
+        serde_json::from_value(json!({
+            "id": 1,
+            "shop_id": 2,
+            "owner_id": 3,
+            "form_list": [{
+                "mod_name": "Skyrim.esm",
+                "local_form_id": "0x80000001",
+                "name": "Thing",
+                "quantity": 1,
+                "form_kind": 41,
+                "is_food": false,
+                "price": 1,
+                "keywords": [],
+            }],
+            "created_at": "2026-10-16T12:00:00",
+            "updated_at": "2026-10-16T12:00:00",
+            "revision": 1,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn etag_is_the_same_in_both_formats() {
+        let api_url = Url::parse("http://localhost/v1/").unwrap();
+        let list = merchandise_list();
+        let json = ETagReply::<Json>::from_resource(&list, &api_url).unwrap();
+        let bincode = ETagReply::<Bincode>::from_resource(&list, &api_url).unwrap();
+        assert_eq!(json.etag(), bincode.etag());
+        assert_eq!(json.etag(), canonical_etag(&list).unwrap());
+
+        let lists = vec![list];
+        let json = ETagReply::<Json>::from_serializable(&lists).unwrap();
+        let bincode = ETagReply::<Bincode>::from_serializable(&lists).unwrap();
+        assert_eq!(json.etag(), bincode.etag());
+    }
+
+    #[test]
+    fn etag_changes_with_content() {
+        let list = merchandise_list();
+        let mut changed = merchandise_list();
+        changed.form_list[0].quantity = 2;
+        assert_ne!(
+            canonical_etag(&list).unwrap(),
+            canonical_etag(&changed).unwrap()
+        );
+    }
+
+    #[test]
+    fn if_none_match_forms() {
+        assert!(if_none_match("abc", "abc"));
+        assert!(if_none_match("\"abc\"", "abc"));
+        assert!(if_none_match("W/\"abc\"", "abc"));
+        assert!(if_none_match("\"def\", W/\"abc\"", "abc"));
+        assert!(if_none_match("*", "abc"));
+        assert!(!if_none_match("abcd", "abc"));
+        assert!(!if_none_match("\"def\", \"ghi\"", "abc"));
+        assert!(!if_none_match("", "abc"));
+    }
+
+    #[test]
+    fn if_match_passes_any_current_etag_or_no_header() {
+        let etags = vec!["public".to_string(), "owner".to_string()];
+        assert!(check_if_match(None, &etags).is_ok());
+        assert!(check_if_match(Some("\"owner\""), &etags).is_ok());
+        assert!(check_if_match(Some("\"stale\", \"public\""), &etags).is_ok());
+        assert!(check_if_match(Some("*"), &etags).is_ok());
+        assert!(check_if_match(Some("\"stale\""), &etags).is_err());
+    }
+
+    #[test]
+    fn prefer_header_forms() {
+        let parse = |value: &str| value.parse::<PreferHeader>().unwrap().return_minimal;
+        assert!(parse("return=minimal"));
+        assert!(parse("Return = \"minimal\""));
+        assert!(parse("respond-async, return=minimal; foo=bar"));
+        assert!(!parse("return=representation"));
+        assert!(!parse("minimal"));
+        assert!(!parse(""));
+    }
+
+    #[test]
+    fn accept_language_header_picks_the_most_preferred_locale() {
+        let parse = |value: &str| value.parse::<AcceptLanguageHeader>().unwrap().preferred;
+        let locale = |tag: &str| Locale::parse(tag);
+        assert_eq!(parse("de-AT"), locale("de-at"));
+        assert_eq!(parse("fr;q=0.8, pt-BR, en;q=0.9"), locale("pt-br"));
+        assert_eq!(parse("en;q=0.5, fr;q=0.5"), locale("en"));
+        assert_eq!(parse("*, es;q=0.1"), locale("es"));
+        assert_eq!(parse("de;q=0, en_US, fr;q=oops"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn schema_versions_rename_nested_fields_in_place() {
+        let v1 = br#"{"form_list":[{"name":"Thing","form_type":41,"price":1.5}],"form_type":null}"#;
+        let v2 = br#"{"form_list":[{"name":"Thing","form_kind":41,"price":1.5}],"form_kind":null}"#;
+        assert_eq!(
+            &SchemaVersion::V1.upgrade(Bytes::from_static(v1))[..],
+            &v2[..]
+        );
+        assert_eq!(SchemaVersion::V1.downgrade(v2).unwrap(), v1.to_vec());
+        assert!(!SchemaVersion::V1.needs_downgrade(br#"{"name":"Thing"}"#));
+        assert_eq!(
+            &SchemaVersion::V2.upgrade(Bytes::from_static(v1))[..],
+            &v1[..]
+        );
+        // Not JSON, so left for the handler to reject.
+        assert_eq!(
+            &SchemaVersion::V1.upgrade(Bytes::from_static(b"{\"form_type\""))[..],
+            b"{\"form_type\""
+        );
+    }
+}
@@ -1,24 +1,87 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use futures::future::try_join;
 use http::StatusCode;
 use hyper::body::Bytes;
 use ipnetwork::IpNetwork;
 use mime::Mime;
+use sqlx::postgres::PgConnection;
 use std::net::SocketAddr;
 use uuid::Uuid;
 use warp::reply::{with_header, with_status};
 use warp::{Rejection, Reply};
 
-use crate::caches::{CachedResponse, CACHES};
-use crate::models::{FullPostedOwner, ListParams, Owner, PostedOwner};
-use crate::problem::{reject_anyhow, unauthorized_no_api_key};
+use crate::caches::{CachedResponse, InvalidationEvent, ViewScope};
+use crate::models::{
+    Deadline, FullPostedOwner, Owner, OwnerListQuery, OwnerSelfView, OwnerShop, OwnerWithApiKey,
+    PostedOwner, ShopReview, ShopSummary, ShopTemplate, ShopWithLists, SubResourceETags,
+};
+use crate::problem::{
+    forbidden_permission, invalid_api_key, owner_exists, reject_anyhow, unique_violation,
+};
 use crate::Environment;
 
+use super::links::WithLinks;
+use super::shop::shop_etag_fields;
+use super::shop_review::evict_reviewed_shops;
 use super::{
-    authenticate, check_etag, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody,
-    ETagReply, Json, TypedCache,
+    authenticate, authenticate_optional, canonical_etag, check_etag, check_etag_index,
+    check_if_match, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody, ETagReply,
+    Json, MinimalReply, PageReply, PreferHeader, ScopedTypedCache, TypedCache,
 };
 
 pub async fn get(
+    id: i32,
+    api_key: Option<Uuid>,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let viewer_id = authenticate_optional(&env, api_key)
+        .await
+        .map_err(reject_anyhow)?;
+    let ScopedTypedCache {
+        content_type,
+        cache,
+        scope,
+    } = ScopedTypedCache::pick_cache(accept, &env.caches.owner_bin, &env.caches.owner, || async {
+        Ok(ViewScope::of(viewer_id, id))
+    })
+    .await
+    .map_err(reject_anyhow)?;
+    let key = scope.key(id);
+    if let Some(response) = check_etag_index(&etag, cache, &key).await {
+        return Ok(response);
+    }
+    let response = cache
+        .get_response(key, || async {
+            let owner = Owner::get(&env.db, id).await?;
+            let reply: Box<dyn Reply> = match (&content_type, scope) {
+                (ContentType::Bincode, _) => {
+                    Box::new(ETagReply::<Bincode>::from_serializable(&owner)?)
+                }
+                (ContentType::Json, ViewScope::Owner(_)) => {
+                    let owner_self_view = OwnerSelfView {
+                        ip_address: owner.ip_address,
+                        owner,
+                    };
+                    Box::new(ETagReply::<Json>::from_resource(
+                        &owner_self_view,
+                        &env.api_url,
+                    )?)
+                }
+                (ContentType::Json, ViewScope::Public) => {
+                    Box::new(ETagReply::<Json>::from_resource(&owner, &env.api_url)?)
+                }
+            };
+            let reply = with_status(reply, StatusCode::OK);
+            Ok(reply)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+pub async fn get_profile(
     id: i32,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
@@ -27,13 +90,24 @@ pub async fn get(
     let TypedCache {
         content_type,
         cache,
-    } = TypedCache::<i32, CachedResponse>::pick_cache(accept, &CACHES.owner_bin, &CACHES.owner);
+    } = TypedCache::<i32, CachedResponse>::pick_cache(
+        accept,
+        &env.caches.owner_profile_bin,
+        &env.caches.owner_profile,
+    );
+    if let Some(response) = check_etag_index(&etag, cache, &id).await {
+        return Ok(response);
+    }
     let response = cache
         .get_response(id, || async {
-            let owner = Owner::get(&env.db, id).await?;
+            let profile = Owner::get_profile(&env.db, id).await?;
             let reply: Box<dyn Reply> = match content_type {
-                ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(&owner)?),
-                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&owner)?),
+                ContentType::Bincode => {
+                    Box::new(ETagReply::<Bincode>::from_serializable(&profile)?)
+                }
+                ContentType::Json => {
+                    Box::new(ETagReply::<Json>::from_resource(&profile, &env.api_url)?)
+                }
             };
             let reply = with_status(reply, StatusCode::OK);
             Ok(reply)
@@ -42,27 +116,175 @@ pub async fn get(
     Ok(check_etag(etag, response))
 }
 
+// Not cached, since the counts change with every request, including this one.
+pub async fn get_usage(api_key: Option<Uuid>, env: Environment) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let snapshot = env.usage.snapshot(
+        owner_id,
+        env.config.owner_monthly_quota,
+        Utc::now().naive_utc(),
+    );
+    Ok(warp::reply::json(&snapshot))
+}
+
+pub async fn get_shop_template(
+    api_key: Option<Uuid>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let template = ShopTemplate::get_by_owner_id(&env.db, owner_id)
+        .await
+        .map_err(reject_anyhow)?;
+    let reply: Box<dyn Reply> = match accept {
+        Some(accept) if accept.accepts_bincode() => {
+            Box::new(ETagReply::<Bincode>::from_serializable(&template).map_err(reject_anyhow)?)
+        }
+        _ => Box::new(ETagReply::<Json>::from_serializable(&template).map_err(reject_anyhow)?),
+    };
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+/// Replaces the owner's shop template. Shops already created from it are left as they are.
+pub async fn save_shop_template(
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: mut template,
+        content_type,
+    } = DeserializedBody::<ShopTemplate>::from_bytes(bytes, content_type).map_err(reject_anyhow)?;
+    template
+        .validate(&env.config.shop_tags, &env.config.economy)
+        .map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let template = template
+        .save_by_owner_id(&env.db, owner_id)
+        .await
+        .map_err(reject_anyhow)?;
+    let reply: Box<dyn Reply> = match content_type {
+        ContentType::Bincode => {
+            Box::new(ETagReply::<Bincode>::from_serializable(&template).map_err(reject_anyhow)?)
+        }
+        ContentType::Json => {
+            Box::new(ETagReply::<Json>::from_serializable(&template).map_err(reject_anyhow)?)
+        }
+    };
+    Ok(with_status(reply, StatusCode::OK))
+}
+
 pub async fn list(
-    list_params: ListParams,
+    query: OwnerListQuery,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
+    if let Some(ids) = query.filter.ids().map_err(reject_anyhow)? {
+        let TypedCache {
+            content_type,
+            cache,
+        } = TypedCache::<Vec<i32>, CachedResponse>::pick_cache(
+            accept,
+            &env.caches.owners_by_ids_bin,
+            &env.caches.owners_by_ids,
+        );
+        let response = cache
+            .get_response(ids.clone(), || async {
+                let owners = Owner::get_many(&env.db, &ids).await?;
+                let reply: Box<dyn Reply> = match content_type {
+                    ContentType::Bincode => {
+                        Box::new(ETagReply::<Bincode>::from_serializable(&owners)?)
+                    }
+                    ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&owners)?),
+                };
+                let reply = with_status(reply, StatusCode::OK);
+                Ok(reply)
+            })
+            .await?;
+        return Ok(check_etag(etag, response));
+    }
     let TypedCache {
         content_type,
         cache,
-    } = TypedCache::<ListParams, CachedResponse>::pick_cache(
+    } = TypedCache::<OwnerListQuery, CachedResponse>::pick_cache(
         accept,
-        &CACHES.list_owners_bin,
-        &CACHES.list_owners,
+        &env.caches.list_owners_bin,
+        &env.caches.list_owners,
     );
     let response = cache
-        .get_response(list_params.clone(), || async {
-            let owners = Owner::list(&env.db, &list_params).await?;
+        .get_response(query.clone(), || async {
+            let (owners, total) =
+                try_join(Owner::list(&env.db, &query), Owner::count(&env.db)).await?;
             let reply: Box<dyn Reply> = match content_type {
                 ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(&owners)?),
                 ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&owners)?),
             };
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
+            Ok(reply)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+pub async fn shop_summaries(
+    api_key: Option<Uuid>,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    deadline: Deadline,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<i32, CachedResponse>::pick_cache(
+        accept,
+        &env.caches.shop_summaries_by_owner_id_bin,
+        &env.caches.shop_summaries_by_owner_id,
+    );
+    let response = cache
+        .get_response(owner_id, || async {
+            let mut tx = env.db.begin().await?;
+            let shops = ShopWithLists::list_by_owner_id(&mut tx, owner_id, Some(&deadline)).await?;
+            tx.commit().await?;
+            let summaries = shops
+                .iter()
+                .map(|shop_with_lists| {
+                    let shop = &shop_with_lists.shop;
+                    Ok(ShopSummary {
+                        id: shop.id,
+                        name: shop.name.clone(),
+                        gold: shop.gold,
+                        item_count: shop_with_lists.item_count(),
+                        last_activity_at: shop.last_activity_at,
+                        etags: SubResourceETags {
+                            shop: canonical_etag(&shop_etag_fields(&OwnerShop::new(
+                                shop.clone(),
+                                shop_with_lists.notification_settings.clone(),
+                            ))?)?,
+                            merchandise_list: shop_with_lists
+                                .merchandise_list
+                                .as_ref()
+                                .map(canonical_etag)
+                                .transpose()?,
+                            interior_ref_list: shop_with_lists
+                                .interior_ref_list
+                                .as_ref()
+                                .map(canonical_etag)
+                                .transpose()?,
+                        },
+                    })
+                })
+                .collect::<Result<Vec<ShopSummary>>>()?;
+            let reply: Box<dyn Reply> = match content_type {
+                ContentType::Bincode => {
+                    Box::new(ETagReply::<Bincode>::from_serializable(&summaries)?)
+                }
+                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&summaries)?),
+            };
             let reply = with_status(reply, StatusCode::OK);
             Ok(reply)
         })
@@ -70,102 +292,956 @@ pub async fn list(
     Ok(check_etag(etag, response))
 }
 
+// Clients used to have to generate their own api key and send it in the `api-key` header. That
+// still works, but when the header is left out a key is generated and returned in the body of this
+// response, which is the only time the server ever reveals it.
 pub async fn create(
     bytes: Bytes,
     remote_addr: Option<SocketAddr>,
-    api_key: Option<Uuid>,
+    api_key: Option<String>,
     real_ip: Option<IpNetwork>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
-    if let Some(api_key) = api_key {
-        let DeserializedBody {
-            body: owner,
-            content_type,
-        } = DeserializedBody::<PostedOwner>::from_bytes(bytes, content_type)
-            .map_err(reject_anyhow)?;
-        let owner = FullPostedOwner {
-            name: owner.name,
-            mod_version: owner.mod_version,
-            api_key,
-            ip_address: match remote_addr {
-                Some(addr) => Some(IpNetwork::from(addr.ip())),
-                None => real_ip,
-            },
-        };
-        let saved_owner = Owner::create(owner, &env.db).await.map_err(reject_anyhow)?;
-        let url = saved_owner.url(&env.api_url).map_err(reject_anyhow)?;
-        let reply: Box<dyn Reply> = match content_type {
-            ContentType::Bincode => Box::new(
-                ETagReply::<Bincode>::from_serializable(&saved_owner).map_err(reject_anyhow)?,
-            ),
-            ContentType::Json => {
-                Box::new(ETagReply::<Json>::from_serializable(&saved_owner).map_err(reject_anyhow)?)
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
+    let (api_key, generated) = match api_key {
+        Some(api_key) => (
+            api_key
+                .trim()
+                .parse::<Uuid>()
+                .map_err(|_| reject_anyhow(invalid_api_key()))?,
+            false,
+        ),
+        None => (Uuid::new_v4(), true),
+    };
+    let DeserializedBody {
+        body: owner,
+        content_type,
+    } = DeserializedBody::<PostedOwner>::from_bytes(bytes, content_type).map_err(reject_anyhow)?;
+    owner.validate().map_err(reject_anyhow)?;
+    let owner = FullPostedOwner {
+        name: owner.name,
+        mod_version: owner.mod_version,
+        display_name: owner.display_name,
+        bio: owner.bio,
+        avatar_url: owner.avatar_url,
+        api_key,
+        ip_address: match remote_addr {
+            Some(addr) => Some(IpNetwork::from(addr.ip())),
+            None => real_ip,
+        },
+    };
+    let name = owner.name.clone();
+    let saved_owner = match Owner::create(owner, &env.db).await {
+        Ok(saved_owner) => saved_owner,
+        Err(error) => {
+            // Players who reinstall the mod without wiping its config register again with their
+            // old name and api key. Tell them which owner is theirs so that they can recover.
+            if !generated && unique_violation(&error).is_some() {
+                if let Some(existing) = Owner::get_by_name_and_api_key(&env.db, &name, api_key)
+                    .await
+                    .map_err(reject_anyhow)?
+                {
+                    let url = existing.url(&env.api_url).map_err(reject_anyhow)?;
+                    return Err(reject_anyhow(owner_exists(existing.id, &url)));
+                }
             }
-        };
-        let reply = with_header(reply, "Location", url.as_str());
-        let reply = with_status(reply, StatusCode::CREATED);
-        tokio::spawn(async move {
-            CACHES.list_owners.clear().await;
-            CACHES.list_owners_bin.clear().await;
-        });
-        Ok(reply)
-    } else {
-        Err(reject_anyhow(unauthorized_no_api_key()))
+            return Err(reject_anyhow(error));
+        }
+    };
+    let url = saved_owner.url(&env.api_url).map_err(reject_anyhow)?;
+    // Clients make authenticated requests right after registering, so save them the lookup.
+    env.caches
+        .owner_ids_by_api_key
+        .insert(api_key, saved_owner.id)
+        .await;
+    // A generated api key is only ever sent in this response, so it can't be left out.
+    let reply: Box<dyn Reply> = match (content_type, generated) {
+        (_, false) if return_minimal => {
+            Box::new(MinimalReply::from_resource(&saved_owner).map_err(reject_anyhow)?)
+        }
+        (ContentType::Bincode, false) => {
+            Box::new(ETagReply::<Bincode>::from_serializable(&saved_owner).map_err(reject_anyhow)?)
+        }
+        (ContentType::Json, false) => Box::new(
+            ETagReply::<Json>::from_resource(&saved_owner, &env.api_url).map_err(reject_anyhow)?,
+        ),
+        (ContentType::Bincode, true) => Box::new(with_header(
+            ETagReply::<Bincode>::from_serializable(&(&saved_owner, api_key))
+                .map_err(reject_anyhow)?,
+            "Cache-Control",
+            "no-store",
+        )),
+        (ContentType::Json, true) => Box::new(with_header(
+            ETagReply::<Json>::from_serializable(&OwnerWithApiKey {
+                owner: &saved_owner.linked(&env.api_url).map_err(reject_anyhow)?,
+                api_key,
+            })
+            .and_then(|reply| {
+                reply.with_etag_of(&OwnerWithApiKey {
+                    owner: &saved_owner,
+                    api_key,
+                })
+            })
+            .map_err(reject_anyhow)?,
+            "Cache-Control",
+            "no-store",
+        )),
+    };
+    let reply = with_header(reply, "Location", url.as_str());
+    let reply = with_status(reply, StatusCode::CREATED);
+    env.caches
+        .invalidate(InvalidationEvent::OwnerSaved {
+            owner_id: saved_owner.id,
+        })
+        .await;
+    Ok(reply)
+}
+
+// Checks `If-Match` against both the owner as they see themselves and as everyone else does,
+// keeping the owner locked until `tx` ends so that no other write lands between the check and the
+// caller's.
+async fn check_owner_if_match(
+    tx: &mut PgConnection,
+    id: i32,
+    owner_id: i32,
+    if_match: Option<&str>,
+) -> Result<()> {
+    if if_match.is_none() {
+        return Ok(());
+    }
+    if id != owner_id {
+        return Err(forbidden_permission());
     }
+    let owner = Owner::get_for_update(tx, id).await?;
+    let owner_self_view = OwnerSelfView {
+        ip_address: owner.ip_address,
+        owner,
+    };
+    let etags = [
+        canonical_etag(&owner_self_view.owner)?,
+        canonical_etag(&owner_self_view)?,
+    ];
+    check_if_match(if_match, &etags)
 }
 
 pub async fn update(
     id: i32,
     bytes: Bytes,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
     let DeserializedBody {
         body: owner,
         content_type,
     } = DeserializedBody::<PostedOwner>::from_bytes(bytes, content_type).map_err(reject_anyhow)?;
+    owner.validate().map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
-    let updated_owner = Owner::update(owner, &env.db, owner_id, id)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    check_owner_if_match(&mut tx, id, owner_id, if_match.as_deref())
         .await
         .map_err(reject_anyhow)?;
+    let updated_owner = Owner::update(owner, &mut tx, owner_id, id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
     let url = updated_owner.url(&env.api_url).map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => {
+            Box::new(MinimalReply::from_resource(&updated_owner).map_err(reject_anyhow)?)
+        }
         ContentType::Bincode => Box::new(
             ETagReply::<Bincode>::from_serializable(&updated_owner).map_err(reject_anyhow)?,
         ),
-        ContentType::Json => {
-            Box::new(ETagReply::<Json>::from_serializable(&updated_owner).map_err(reject_anyhow)?)
-        }
+        ContentType::Json => Box::new(
+            ETagReply::<Json>::from_resource(&updated_owner, &env.api_url)
+                .map_err(reject_anyhow)?,
+        ),
     };
     let reply = with_header(reply, "Location", url.as_str());
-    let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES.owner.delete_response(id).await;
-        CACHES.owner_bin.delete_response(id).await;
-        CACHES.list_owners.clear().await;
-        CACHES.list_owners_bin.clear().await;
-    });
+    let status = if return_minimal {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    let reply = with_status(reply, status);
+    let api_key = api_key.expect("api-key has been validated during authenticate");
+    env.caches
+        .owner_ids_by_api_key
+        .insert(api_key, updated_owner.id)
+        .await;
+    env.caches
+        .invalidate(InvalidationEvent::OwnerSaved { owner_id: id })
+        .await;
     Ok(reply)
 }
 
 pub async fn delete(
     id: i32,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
-    Owner::delete(&env.db, owner_id, id)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    check_owner_if_match(&mut tx, id, owner_id, if_match.as_deref())
+        .await
+        .map_err(reject_anyhow)?;
+    // Rolled back along with the owner's deletion if they aren't allowed to delete it.
+    let reviewed_shop_ids = ShopReview::delete_by_reviewer(&mut tx, id)
+        .await
+        .map_err(reject_anyhow)?;
+    Owner::delete(&mut tx, owner_id, id)
         .await
         .map_err(reject_anyhow)?;
-    tokio::spawn(async move {
-        let api_key = api_key.expect("api-key has been validated during authenticate");
-        CACHES.owner.delete_response(id).await;
-        CACHES.owner_bin.delete_response(id).await;
-        CACHES.owner_ids_by_api_key.delete(api_key).await;
-        CACHES.list_owners.clear().await;
-        CACHES.list_owners_bin.clear().await;
-    });
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    evict_reviewed_shops(&env, &reviewed_shop_ids).await;
+    let api_key = api_key.expect("api-key has been validated during authenticate");
+    env.caches.owner_ids_by_api_key.delete(api_key).await;
+    env.caches
+        .invalidate(InvalidationEvent::OwnerDeleted { owner_id: id })
+        .await;
     Ok(StatusCode::NO_CONTENT)
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDateTime, Utc};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+    use warp::http::StatusCode;
+
+    use crate::metrics::queries::count_queries;
+    use crate::models::{OwnerRequestUsage, PostedOwner};
+    use crate::test_support::{
+        assert_one_deleted, assert_problem, json_body, json_request, request, TestEnv,
+        OTHER_OWNER_API_KEY, OWNER_API_KEY,
+    };
+    use crate::usage;
+
+    /// The bincode response to creating an owner without an api key.
+    #[derive(Debug, Deserialize)]
+    struct BincodeOwnerWithApiKey {
+        id: i32,
+        name: String,
+        _mod_version: i32,
+        _created_at: NaiveDateTime,
+        _updated_at: NaiveDateTime,
+        _display_name: Option<String>,
+        _bio: Option<String>,
+        _avatar_url: Option<String>,
+        api_key: Uuid,
+    }
+
+    fn bincode_owner_request(api_key: Option<&str>) -> warp::test::RequestBuilder {
+        let owner = PostedOwner {
+            name: "Bincode Owner".to_string(),
+            mod_version: 1,
+            display_name: None,
+            bio: None,
+            avatar_url: None,
+        };
+        request("POST", "/v1/owners", api_key)
+            .header("content-type", "application/octet-stream")
+            .header("accept", "application/octet-stream")
+            .body(bincode::serialize(&owner).unwrap())
+    }
+
+    #[tokio::test]
+    async fn create_generates_missing_api_key() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                None,
+                &json!({ "name": "Owner", "mod_version": 1 }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let owner = json_body(&response);
+        assert_eq!(owner["name"], "Owner");
+        assert!(owner["_links"]["self"].is_object());
+        let api_key = owner["api_key"].as_str().unwrap().to_string();
+        api_key.parse::<Uuid>().unwrap();
+
+        // The generated key authenticates as the new owner, and is never shown again.
+        let response = test
+            .send(request("GET", "/v1/owners/me/usage", Some(&api_key)))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response)["owner_id"], owner["id"]);
+        let path = format!("/v1/owners/{}", owner["id"]);
+        let response = test.send(request("GET", &path, Some(&api_key))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(json_body(&response).get("api_key").is_none());
+    }
+
+    #[tokio::test]
+    async fn registering_warms_the_auth_cache() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+
+        // Swap the key in the database behind the server's back. The first authenticated request
+        // still succeeds, so it was answered from the cache without looking the key up.
+        sqlx::query("UPDATE owners SET api_key = $1 WHERE id = $2")
+            .bind(Uuid::new_v4())
+            .bind(owner_id as i32)
+            .execute(&test.env.db)
+            .await
+            .unwrap();
+        let response = test
+            .send(request("GET", "/v1/owners/me/usage", Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(json_body(&response)["owner_id"], owner_id);
+
+        // Deleting the owner evicts the key before responding.
+        let path = format!("/v1/owners/{}", owner_id);
+        let response = test
+            .send(request("DELETE", &path, Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(request("GET", "/v1/owners/me/usage", Some(OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn create_generates_missing_api_key_over_bincode() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let response = test.send(bincode_owner_request(None)).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(response.headers()["cache-control"], "no-store");
+        let owner: BincodeOwnerWithApiKey = bincode::deserialize(response.body()).unwrap();
+        assert_eq!(owner.name, "Bincode Owner");
+
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/owners/me/usage",
+                Some(&owner.api_key.to_string()),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response)["owner_id"], owner.id);
+    }
+
+    #[tokio::test]
+    async fn create_keeps_supplied_api_key() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Owner", "mod_version": 1 }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(response.headers().get("cache-control").is_none());
+        let owner = json_body(&response);
+        assert!(owner.get("api_key").is_none());
+
+        let response = test
+            .send(bincode_owner_request(Some(OTHER_OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        // Without a generated key the bincode body is just the owner.
+        assert!(bincode::deserialize::<BincodeOwnerWithApiKey>(response.body()).is_err());
+
+        for api_key in &[OWNER_API_KEY, OTHER_OWNER_API_KEY] {
+            let response = test
+                .send(request("GET", "/v1/owners/me/usage", Some(api_key)))
+                .await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn re_registering_points_at_the_existing_owner() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner = json!({ "name": "Owner", "mod_version": 1 });
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some(OWNER_API_KEY),
+                &owner,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()["location"].to_str().unwrap().to_string();
+        let id = json_body(&response)["id"].clone();
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some(OWNER_API_KEY),
+                &owner,
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "owner_exists");
+        assert_eq!(problem["owner_id"], id);
+        assert_eq!(problem["owner_url"], location);
+
+        // Another player's key under the same name is a new owner, but a key that is already
+        // registered under another name is a genuine conflict and gives nothing away.
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some(OTHER_OWNER_API_KEY),
+                &owner,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Someone Else", "mod_version": 1 }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert!(problem.get("owner_id").is_none());
+    }
+
+    #[tokio::test]
+    async fn create_rejects_malformed_api_key() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some("not-a-uuid"),
+                &json!({ "name": "Owner", "mod_version": 1 }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "invalid_api_key");
+
+        let response = test.send(bincode_owner_request(Some(""))).await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "invalid_api_key");
+    }
+
+    #[tokio::test]
+    async fn monthly_quota_is_enforced_and_flushed() {
+        let test = match TestEnv::with_config(&[("OWNER_MONTHLY_QUOTA", "3")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let path = format!("/v1/owners/{}", owner_id);
+
+        for _ in 0..2 {
+            let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = test
+            .send(request("GET", "/v1/owners/me/usage", Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let snapshot = json_body(&response);
+        assert_eq!(snapshot["read"], 3);
+        assert_eq!(snapshot["quota"], 3);
+        assert_eq!(snapshot["remaining"], 0);
+
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        let problem = assert_problem(&response, StatusCode::FORBIDDEN);
+        assert_eq!(problem["code"], "quota_exceeded");
+        // Requests without an api key aren't attributed to the owner.
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // Usage stays visible past the quota.
+        let response = test
+            .send(request("GET", "/v1/owners/me/usage", Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(json_body(&response)["read"], 4);
+
+        let pending = test.env.usage.take_pending();
+        OwnerRequestUsage::add_many(&test.env.db, &pending)
+            .await
+            .unwrap();
+        OwnerRequestUsage::add_many(&test.env.db, &pending)
+            .await
+            .unwrap();
+        let stored = OwnerRequestUsage::list_by_period(
+            &test.env.db,
+            usage::period_start(Utc::now().naive_utc()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!((stored[0].owner_id, stored[0].reads), (owner_id as i32, 8));
+    }
+
+    #[tokio::test]
+    async fn shops_can_start_from_the_owner_template() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shelf = json!({
+            "shelf_type": 1,
+            "position_x": 1.0,
+            "position_y": 2.0,
+            "position_z": 3.0,
+            "angle_x": 0.0,
+            "angle_y": 0.0,
+            "angle_z": 0.0,
+            "scale": 100,
+            "page": 1,
+            "filter_form_type": null,
+            "filter_is_food": false,
+            "search": null,
+            "sort_on": null,
+            "sort_asc": true,
+        });
+
+        // Invalid templates are rejected when saved, not when a shop is created from one.
+        let response = test
+            .send(json_request(
+                "PUT",
+                "/v1/owners/me/shop_template",
+                Some(OWNER_API_KEY),
+                &json!({ "gold": -1 }),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = test
+            .send(json_request(
+                "PUT",
+                "/v1/owners/me/shop_template",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "description": "Fine wares",
+                    "gold": 5000,
+                    "tags": ["Smithing"],
+                    "shelves": [shelf],
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/owners/me/shop_template",
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(json_body(&response)["tags"], json!(["smithing"]));
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops?from_template=true",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Forge", "gold": 100 }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let shop = json_body(&response);
+        assert_eq!(shop["description"], "Fine wares");
+        assert_eq!(shop["gold"], 100);
+        assert_eq!(shop["tags"], json!(["smithing"]));
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/interior_ref_list", shop["id"]),
+                None,
+            ))
+            .await;
+        assert_eq!(json_body(&response)["shelves"], json!([shelf]));
+
+        // Without the flag the template is ignored.
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Plain" }))
+            .await;
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(json_body(&response)["description"], json!(null));
+
+        // A template saved with a field that has since been removed still applies.
+        sqlx::query("UPDATE owner_shop_templates SET template = $1 WHERE owner_id = $2")
+            .bind(json!({ "description": "Old wares", "greeting": "Welcome!" }))
+            .bind(owner_id as i32)
+            .execute(&test.env.db)
+            .await
+            .unwrap();
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops?from_template=true",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Old Forge" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["description"], "Old wares");
+    }
+
+    #[tokio::test]
+    async fn concurrent_deletes_of_an_owner_delete_it_once() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let delete = || {
+            request(
+                "DELETE",
+                &format!("/v1/owners/{}", owner_id),
+                Some(OWNER_API_KEY),
+            )
+        };
+        // Both authenticate before either commits, so the loser gets as far as the delete.
+        let (first, second) = test.send_concurrently(delete(), delete()).await;
+        assert_one_deleted(&first, &second);
+        // Later on, the deleted owner's api key doesn't authenticate at all.
+        let response = test.send(delete()).await;
+        assert_problem(&response, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_sees_their_self_view() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let path = format!("/v1/owners/{}", owner_id);
+
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let self_view = json_body(&response);
+        assert_eq!(self_view["name"], "Owner");
+        assert!(self_view.get("ip_address").is_some(), "{}", self_view);
+        for api_key in &[None, Some(OTHER_OWNER_API_KEY)] {
+            let response = test.send(request("GET", &path, *api_key)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let public_view = json_body(&response);
+            assert_eq!(public_view["name"], "Owner");
+            assert!(public_view.get("ip_address").is_none(), "{}", public_view);
+        }
+
+        // An unknown key is an error, not a fall back to the anonymous view.
+        let response = test
+            .send(request(
+                "GET",
+                &path,
+                Some("44444444-4444-4444-4444-444444444444"),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn owners_are_looked_up_in_bulk_by_unique_ids() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let other_owner_id = test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let ids = |ids: &[i64]| {
+            let ids: Vec<String> = ids.iter().map(i64::to_string).collect();
+            format!("/v1/owners?ids={}", ids.join(","))
+        };
+
+        // Duplicates are returned once, in ascending id order, and missing ids are left out.
+        let missing_id = other_owner_id + 100;
+        let response = test
+            .send(request(
+                "GET",
+                &ids(&[
+                    other_owner_id,
+                    owner_id,
+                    missing_id,
+                    other_owner_id,
+                    owner_id,
+                ]),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let owners = json_body(&response);
+        let owners = owners.as_array().unwrap();
+        assert_eq!(owners.len(), 2);
+        assert_eq!(owners[0]["id"], owner_id);
+        assert_eq!(owners[0]["name"], "Owner");
+        assert_eq!(owners[1]["id"], other_owner_id);
+        assert!(owners.iter().all(|owner| owner.get("ip_address").is_none()));
+
+        // The cap counts distinct ids.
+        let hundred: Vec<i64> = (1..=100).collect();
+        let response = test
+            .send(request(
+                "GET",
+                &ids(&[&hundred[..], &hundred[..]].concat()),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test
+            .send(request("GET", &ids(&(1..=101).collect::<Vec<_>>()), None))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert!(problem["detail"].as_str().unwrap().contains("100"));
+
+        for path in &["/v1/owners?ids=", "/v1/owners?ids=1,x"] {
+            let response = test.send(request("GET", path, None)).await;
+            assert_problem(&response, StatusCode::BAD_REQUEST);
+        }
+    }
+
+    // What a client that logs in has to make to know which of its shops to sync: one request for
+    // the summaries, or the three sub-resources of every shop.
+    async fn count_summary_and_naive_queries(test: &TestEnv, shop_ids: &[i64]) -> (usize, usize) {
+        test.env.caches.clear_all().await;
+        let (response, summary_queries) = count_queries(test.send(request(
+            "GET",
+            "/v1/owners/me/shop_summaries",
+            Some(OWNER_API_KEY),
+        )))
+        .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let summaries = json_body(&response);
+        assert_eq!(summaries.as_array().unwrap().len(), shop_ids.len());
+
+        test.env.caches.clear_all().await;
+        let mut naive_queries = 0;
+        for (shop_id, summary) in shop_ids.iter().zip(summaries.as_array().unwrap()) {
+            assert_eq!(summary["id"], *shop_id);
+            for (path, etag) in &[
+                ("", "shop"),
+                ("/merchandise_list", "merchandise_list"),
+                ("/interior_ref_list", "interior_ref_list"),
+            ] {
+                let path = format!("/v1/shops/{}{}", shop_id, path);
+                let (response, queries) =
+                    count_queries(test.send(request("GET", &path, Some(OWNER_API_KEY)))).await;
+                assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+                // The summary has the ETags the client would have gotten from the round trips.
+                assert_eq!(
+                    response.headers()["etag"].to_str().unwrap(),
+                    summary["etags"][etag],
+                    "{}",
+                    path
+                );
+                naive_queries += queries;
+            }
+        }
+        (summary_queries, naive_queries)
+    }
+
+    #[tokio::test]
+    async fn shop_summaries_take_the_same_queries_however_many_shops() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let mut shop_ids = vec![];
+        let mut counts = vec![];
+        for shop_count in &[1, 4, 8] {
+            while shop_ids.len() < *shop_count {
+                let name = format!("Shop {}", shop_ids.len());
+                shop_ids.push(
+                    test.create_shop(OWNER_API_KEY, &json!({ "name": name }))
+                        .await,
+                );
+            }
+            counts.push(count_summary_and_naive_queries(&test, &shop_ids).await);
+        }
+        println!(
+            "(summary, naive) queries for 1, 4, and 8 shops: {:?}",
+            counts
+        );
+        let (summary_queries, naive_queries) = counts[0];
+        assert!(summary_queries <= naive_queries);
+        for (shop_count, (summary, naive)) in [4, 8].iter().zip(&counts[1..]) {
+            assert_eq!(*summary, summary_queries);
+            assert!(*naive >= naive_queries + 3 * (shop_count - 1), "{}", naive);
+        }
+        // Cached until something changes.
+        cache_summaries(&test, owner_id).await;
+        let (_, queries) = count_queries(test.send(request(
+            "GET",
+            "/v1/owners/me/shop_summaries",
+            Some(OWNER_API_KEY),
+        )))
+        .await;
+        assert_eq!(queries, 0);
+    }
+
+    // Gets the owner's summaries until the response stays cached. A clear the invalidation worker
+    // has yet to apply for an earlier write can drop the first one.
+    async fn cache_summaries(test: &TestEnv, owner_id: i64) -> Value {
+        let cache = &test.env.caches.shop_summaries_by_owner_id;
+        let cached = async {
+            loop {
+                let response = test
+                    .send(request(
+                        "GET",
+                        "/v1/owners/me/shop_summaries",
+                        Some(OWNER_API_KEY),
+                    ))
+                    .await;
+                assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+                tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+                if cache
+                    .lru_mutex
+                    .lock()
+                    .await
+                    .get(&(owner_id as i32))
+                    .is_some()
+                {
+                    return json_body(&response)[0].clone();
+                }
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), cached)
+            .await
+            .expect("the summaries are cached")
+    }
+
+    #[tokio::test]
+    async fn shop_summaries_are_evicted_by_writes_to_the_owners_shops() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let item = |local_form_id: i32, name: &str| {
+            json!({
+                "mod_name": "Skyrim.esm",
+                "local_form_id": local_form_id,
+                "name": name,
+                "quantity": 3,
+                "form_type": 46,
+                "is_food": true,
+                "price": 4,
+                "keywords": [],
+            })
+        };
+        let writes = vec![
+            json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Renamed Shop" }),
+            ),
+            json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "form_list": [item(5, "Cabbage")] }),
+            ),
+            json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/interior_ref_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "ref_list": [{
+                    "base_mod_name": "Skyrim.esm",
+                    "base_local_form_id": 1,
+                    "ref_mod_name": "Skyrim.esm",
+                    "ref_local_form_id": 1,
+                    "position_x": 1.5,
+                    "position_y": 2.5,
+                    "position_z": 3.5,
+                    "angle_x": 0.0,
+                    "angle_y": 0.0,
+                    "angle_z": 0.0,
+                    "scale": 1,
+                }], "shelves": [] }),
+            ),
+            json_request("POST", "/v1/transactions", Some(OTHER_OWNER_API_KEY), &{
+                let mut transaction = item(6, "Leek");
+                transaction["shop_id"] = json!(shop_id);
+                transaction["is_sell"] = json!(true);
+                transaction["quantity"] = json!(1);
+                transaction["amount"] = json!(100);
+                transaction
+            }),
+        ];
+        let changed_fields = ["name", "item_count", "etags.interior_ref_list", "gold"];
+        let field = |summary: &Value, field: &str| {
+            field
+                .split('.')
+                .fold(summary.clone(), |value, key| value[key].clone())
+        };
+        for (write, changed) in writes.into_iter().zip(&changed_fields) {
+            let before = cache_summaries(&test, owner_id).await;
+            let response = test.send(write).await;
+            assert!(response.status().is_success(), "{:?}", response);
+            let after = json_body(
+                &test
+                    .send(request(
+                        "GET",
+                        "/v1/owners/me/shop_summaries",
+                        Some(OWNER_API_KEY),
+                    ))
+                    .await,
+            )[0]
+            .clone();
+            assert_ne!(
+                field(&after, changed),
+                field(&before, changed),
+                "{}",
+                changed
+            );
+            assert_ne!(after["etags"], before["etags"], "{}", changed);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use uuid::Uuid;
+use warp::reply::json;
+use warp::{Rejection, Reply};
+
+use crate::problem::{forbidden_permission, not_found, reject_anyhow};
+use crate::Environment;
+
+use super::authenticate;
+
+// Not cached, since the status changes when the write is replayed.
+pub async fn get(
+    id: Uuid,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let replay = env
+        .replays
+        .get(id)
+        .ok_or_else(|| reject_anyhow(not_found("No journaled write with that id")))?;
+    if replay.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    Ok(json(&replay))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use crate::problem::DATABASE_UNAVAILABLE_CODE;
+    use crate::replays::{self, IDEMPOTENCY_KEY_HEADER};
+    use crate::test_support::{
+        assert_problem, json_body, json_request, request, TestEnv, OTHER_OWNER_API_KEY,
+        OWNER_API_KEY,
+    };
+    use crate::{routes, Environment};
+
+    #[tokio::test]
+    async fn writes_made_during_an_outage_are_replayed_in_order() {
+        let dir =
+            std::env::temp_dir().join(format!("replays_{}", uuid::Uuid::new_v4().to_simple()));
+        let test = match TestEnv::with_config(&[
+            ("REPLAY_JOURNAL_ENABLED", "true"),
+            ("REPLAY_JOURNAL_DIR", dir.to_str().unwrap()),
+        ])
+        .await
+        {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let rename = |name: &str| {
+            json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({
+                    "name": name,
+                    "description": "for testing",
+                    "gold": 500,
+                    "shop_type": "general_store",
+                    "vendor_keywords_exclude": true,
+                }),
+            )
+        };
+
+        test.env.db.close().await;
+        // Without an idempotency key the client just gets the outage.
+        let response = test.send(rename("Not Journaled")).await;
+        let problem = assert_problem(&response, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(problem["code"], DATABASE_UNAVAILABLE_CODE);
+
+        let response = test
+            .send(rename("First Name").header(IDEMPOTENCY_KEY_HEADER, "first"))
+            .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED, "{:?}", response);
+        let first = json_body(&response);
+        assert_eq!(first["status"], "pending");
+        let status_path = format!("/v1/replays/{}", first["id"].as_str().unwrap());
+        assert!(response.headers()["location"]
+            .to_str()
+            .unwrap()
+            .ends_with(&status_path));
+
+        // The database is back, but the owner's later writes still queue behind the first one.
+        let recovered = Environment {
+            db: test.connect().await,
+            ..test.env.clone()
+        };
+        let response = rename("Second Name")
+            .header(IDEMPOTENCY_KEY_HEADER, "second")
+            .reply(&routes(recovered.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED, "{:?}", response);
+        let second = json_body(&response);
+
+        let response = request("GET", &status_path, Some(OTHER_OWNER_API_KEY))
+            .reply(&routes(recovered.clone()))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+
+        let replayed = replays::replay_pending(
+            &recovered.replays,
+            &recovered.db,
+            &mut warp::service(routes(recovered.clone())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(replayed, 2);
+
+        let response = request("GET", &format!("/v1/shops/{}", shop_id), None)
+            .reply(&routes(recovered.clone()))
+            .await;
+        assert_eq!(json_body(&response)["name"], "Second Name");
+        for replay in &[&first, &second] {
+            let response = request(
+                "GET",
+                &format!("/v1/replays/{}", replay["id"].as_str().unwrap()),
+                Some(OWNER_API_KEY),
+            )
+            .reply(&routes(recovered.clone()))
+            .await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let replay = json_body(&response);
+            assert_eq!(replay["status"], "applied");
+            assert_eq!(replay["response_status"], 201);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
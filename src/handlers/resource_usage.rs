@@ -0,0 +1,166 @@
+use std::fmt;
+
+use warp::reply::with_header;
+use warp::Reply;
+
+/// Advisory header telling clients how close a resource is to a limit, so they can warn players
+/// before a request is rejected.
+///
+/// The value is `<resource>=<count>;max=<max>`, e.g. `X-Resource-Usage: interior_refs=4620;max=5000`.
+/// The header is omitted when the resource has no limit configured.
+pub const RESOURCE_USAGE_HEADER: &str = "X-Resource-Usage";
+
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceUsage {
+    resource: &'static str,
+    count: usize,
+    max: usize,
+}
+
+impl ResourceUsage {
+    pub fn new(resource: &'static str, count: usize, max: Option<usize>) -> Option<Self> {
+        max.map(|max| Self {
+            resource,
+            count,
+            max,
+        })
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={};max={}", self.resource, self.count, self.max)
+    }
+}
+
+pub fn with_resource_usage(reply: Box<dyn Reply>, usage: Option<ResourceUsage>) -> Box<dyn Reply> {
+    match usage {
+        Some(usage) => Box::new(with_header(reply, RESOURCE_USAGE_HEADER, usage.to_string())),
+        None => reply,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+    use warp::Reply;
+
+    use super::{with_resource_usage, ResourceUsage, RESOURCE_USAGE_HEADER};
+    use crate::test_support::{json_request, request, TestEnv, OWNER_API_KEY};
+
+    #[test]
+    fn usage_is_only_reported_against_a_limit() {
+        let usage = ResourceUsage::new("interior_refs", 4620, Some(5000));
+        let response = with_resource_usage(Box::new(StatusCode::OK), usage).into_response();
+        assert_eq!(
+            response.headers()[RESOURCE_USAGE_HEADER],
+            "interior_refs=4620;max=5000"
+        );
+
+        let usage = ResourceUsage::new("shops", 3, None);
+        assert!(usage.is_none());
+        let response = with_resource_usage(Box::new(StatusCode::OK), usage).into_response();
+        assert!(!response.headers().contains_key(RESOURCE_USAGE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn shop_creates_report_usage_of_the_soft_limit() {
+        let test = match TestEnv::with_config(&[("SHOPS_PER_OWNER_SOFT_LIMIT", "3")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        for count in 1..=2 {
+            let response = test
+                .send(json_request(
+                    "POST",
+                    "/v1/shops",
+                    Some(OWNER_API_KEY),
+                    &json!({ "name": format!("Shop {}", count) }),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            assert_eq!(
+                response.headers()[RESOURCE_USAGE_HEADER],
+                format!("shops={};max=3", count).as_str()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn shop_creates_leave_out_usage_without_a_soft_limit() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Shop" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert!(!response.headers().contains_key(RESOURCE_USAGE_HEADER));
+    }
+
+    #[tokio::test]
+    async fn list_replies_report_their_item_counts() {
+        let test = match TestEnv::with_config(&[("MAX_MERCHANDISE_ITEMS", "10")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Shop" }))
+            .await;
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "form_list": [{
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 1,
+                    "name": "Cabbage",
+                    "quantity": 3,
+                    "form_type": 46,
+                    "is_food": true,
+                    "price": 4,
+                    "keywords": [],
+                }] }),
+            ))
+            .await;
+        assert_eq!(
+            response.headers()[RESOURCE_USAGE_HEADER],
+            "merchandise=1;max=10"
+        );
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(
+            response.headers()[RESOURCE_USAGE_HEADER],
+            "merchandise=1;max=10"
+        );
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/interior_ref_list", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(
+            response.headers()[RESOURCE_USAGE_HEADER],
+            "interior_refs=0;max=5000"
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::Result;
+use http::StatusCode;
+use serde::Serialize;
+use warp::reply::with_status;
+use warp::{Rejection, Reply};
+
+use crate::caches::CachedResponse;
+use crate::models::{EconomySettings, ShopTagRules, TransactionLimits};
+use crate::Environment;
+
+use super::{
+    check_etag, AcceptHeader, Bincode, ContentType, DataReply, ETagReply, Json, TypedCache,
+};
+
+#[derive(Debug, Serialize)]
+struct Settings<'a> {
+    economy: &'a EconomySettings,
+    transaction_limits: &'a TransactionLimits,
+    max_merchandise_items: usize,
+    shop_tags: &'a ShopTagRules,
+}
+
+// Settings only change on restart, so the cached response never needs to be invalidated.
+pub async fn get(
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<(), CachedResponse>::pick_cache(
+        accept,
+        &env.caches.settings_bin,
+        &env.caches.settings,
+    );
+    let response = cache
+        .get_response((), || async {
+            let settings = Settings {
+                economy: &env.config.economy,
+                transaction_limits: &env.config.transaction_limits,
+                max_merchandise_items: env.config.max_merchandise_items,
+                shop_tags: &env.config.shop_tags,
+            };
+            let reply: Box<dyn Reply> = match content_type {
+                ContentType::Bincode => {
+                    Box::new(ETagReply::<Bincode>::from_serializable(&settings)?)
+                }
+                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&settings)?),
+            };
+            let reply = with_status(reply, StatusCode::OK);
+            Ok(reply)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
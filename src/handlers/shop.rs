@@ -1,40 +1,180 @@
 use anyhow::{anyhow, Result};
+use chrono::Utc;
+use futures::future::try_join;
+use http::header::{HeaderValue, CACHE_CONTROL, LOCATION};
 use http::StatusCode;
 use hyper::body::Bytes;
+use ipnetwork::IpNetwork;
 use mime::Mime;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::postgres::PgConnection;
+use std::net::SocketAddr;
+use url::Url;
 use uuid::Uuid;
 use warp::reply::{with_header, with_status};
 use warp::{Rejection, Reply};
 
-use crate::caches::{CachedResponse, CACHES};
+use crate::caches::{CachedResponse, InvalidationEvent, ViewScope, SHOP_CARD_TTL};
+use crate::clock::check_not_ahead;
 use crate::models::{
-    InteriorRefList, ListParams, MerchandiseList, PostedInteriorRefList, PostedMerchandiseList,
-    PostedShop, Shop,
+    GoldHistoryParams, InteriorRefList, Locale, LocalizedShop, MerchandiseList,
+    NotificationSettings, OwnerFilter, OwnerShop, OwnerShopListQuery, PostedInteriorRefList,
+    PostedMerchandiseList, PostedNotificationSettings, PostedShop, PostedShopBan, PostedShopRename,
+    PostedShopTranslation, PublicShop, Shop, ShopBan, ShopCard, ShopGoldHistory, ShopListQuery,
+    ShopSlug, ShopTemplate, ShopTranslation, ShopVisit,
+};
+use crate::problem::{
+    forbidden_permission, not_found, rate_limited, reject_anyhow, shop_exists,
+    unauthorized_no_api_key, unique_violation,
 };
-use crate::problem::reject_anyhow;
 use crate::Environment;
 
+use super::links::WithLinks;
+use super::resource_usage::{with_resource_usage, ResourceUsage};
+use super::shop_deletion::{verify_confirmation_token, DeleteShopParams};
 use super::{
-    authenticate, check_etag, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody,
-    ETagReply, Json, TypedCache,
+    authenticate, authenticate_optional, canonical_etag, check_etag, check_etag_index,
+    check_if_match, AcceptHeader, AcceptLanguageHeader, Bincode, ContentType, DataReply,
+    DeserializedBody, ETagReply, Json, MinimalReply, PageReply, PreferHeader, ScopedTypedCache,
+    TypedCache,
 };
 
+/// JSON requests with an `Accept-Language` header get a `LocalizedShop`, except from the shop's
+/// owner, who always sees the canonical name and description they manage translations of.
 pub async fn get(
     id: i32,
+    api_key: Option<Uuid>,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
+    accept_language: Option<AcceptLanguageHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
-    let TypedCache {
+    let viewer_id = authenticate_optional(&env, api_key)
+        .await
+        .map_err(reject_anyhow)?;
+    let ScopedTypedCache {
         content_type,
         cache,
-    } = TypedCache::<i32, CachedResponse>::pick_cache(accept, &CACHES.shop_bin, &CACHES.shop);
+        scope,
+    } = ScopedTypedCache::pick_cache(accept, &env.caches.shop_bin, &env.caches.shop, || {
+        ViewScope::resolve(viewer_id, || {
+            env.caches
+                .owner_ids_by_shop_id
+                .get(id, || Shop::get_owner_id(&env.db, id))
+        })
+    })
+    .await
+    .map_err(reject_anyhow)?;
+    if let (ContentType::Json, ViewScope::Public, Some(locale)) = (
+        &content_type,
+        scope,
+        accept_language.and_then(|accept_language| accept_language.preferred),
+    ) {
+        let key = (id, locale);
+        if let Some(response) = check_etag_index(&etag, &env.caches.localized_shop, &key).await {
+            return Ok(response);
+        }
+        let response = env
+            .caches
+            .localized_shop
+            .get_response(key.clone(), || async {
+                let shop = Shop::get(&env.db, id).await?;
+                let mut localized =
+                    LocalizedShop::localize_all(&env.db, vec![shop], &key.1).await?;
+                let reply = ETagReply::<Json>::from_resource(&localized.remove(0), &env.api_url)?;
+                let reply = with_status(reply, StatusCode::OK);
+                Ok(reply)
+            })
+            .await?;
+        return Ok(check_etag(etag, response));
+    }
+    let key = scope.key(id);
+    if let Some(response) = check_etag_index(&etag, cache, &key).await {
+        return Ok(response);
+    }
     let response = cache
-        .get_response(id, || async {
+        .get_response(key, || async {
             let shop = Shop::get(&env.db, id).await?;
+            let reply = match scope {
+                ViewScope::Owner(_) => {
+                    let notification_settings =
+                        NotificationSettings::get_by_shop_id(&env.db, id).await?;
+                    let owner_shop = OwnerShop::new(shop, notification_settings);
+                    shop_view_body(&owner_shop, &content_type, false, &env.api_url)?
+                }
+                ViewScope::Public => {
+                    shop_view_body(&PublicShop::from(shop), &content_type, false, &env.api_url)?
+                }
+            };
+            let reply = with_status(reply, StatusCode::OK);
+            Ok(reply)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+/// Serves the shop whose current slug is `slug` like `get`, and redirects the slugs it had before
+/// a rename to that one.
+pub async fn get_by_slug(
+    slug: String,
+    api_key: Option<Uuid>,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    accept_language: Option<AcceptLanguageHeader>,
+    env: Environment,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let resolved = ShopSlug::resolve(&env.db, &slug)
+        .await
+        .map_err(reject_anyhow)?
+        .ok_or_else(|| reject_anyhow(not_found("No shop has or had that slug")))?;
+    if resolved.current_slug != slug {
+        let url = slug_url(&resolved.current_slug, &env.api_url).map_err(reject_anyhow)?;
+        let reply = with_status(warp::reply(), StatusCode::MOVED_PERMANENTLY);
+        return Ok(Box::new(with_header(reply, LOCATION, url.as_str())));
+    }
+    let reply = get(
+        resolved.shop_id,
+        api_key,
+        etag,
+        accept,
+        accept_language,
+        env,
+    )
+    .await?;
+    Ok(Box::new(with_header(reply, "X-Shop-Slug", slug)))
+}
+
+fn slug_url(slug: &str, api_url: &Url) -> Result<Url> {
+    Ok(api_url.join(&format!("shops/slug/{}", slug))?)
+}
+
+pub async fn gold_history(
+    id: i32,
+    params: GoldHistoryParams,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let days = params.days().map_err(reject_anyhow)?;
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<(i32, i32), CachedResponse>::pick_cache(
+        accept,
+        &env.caches.shop_gold_history_bin,
+        &env.caches.shop_gold_history,
+    );
+    let response = cache
+        .get_response((id, days), || async {
+            // 404 for shops that do not exist, as opposed to an empty history for new shops
+            Shop::get(&env.db, id).await?;
+            let gold_history = ShopGoldHistory::get_by_shop_id(&env.db, id, days).await?;
             let reply: Box<dyn Reply> = match content_type {
-                ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(&shop)?),
-                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&shop)?),
+                ContentType::Bincode => {
+                    Box::new(ETagReply::<Bincode>::from_serializable(&gold_history)?)
+                }
+                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&gold_history)?),
             };
             let reply = with_status(reply, StatusCode::OK);
             Ok(reply)
@@ -43,8 +183,120 @@ pub async fn get(
     Ok(check_etag(etag, response))
 }
 
+/// The public card of a shop, for previews of links shared outside the game. It is the same for
+/// everyone, so it is served without authentication, rate limited per IP address, and may be cached
+/// by anyone for `SHOP_CARD_TTL`. Its item count and rating can lag behind by as much.
+pub async fn card(
+    id: i32,
+    remote_addr: Option<SocketAddr>,
+    real_ip: Option<IpNetwork>,
+    etag: Option<String>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let ip = match remote_addr {
+        Some(addr) => Some(addr.ip()),
+        None => real_ip.map(|real_ip| real_ip.ip()),
+    };
+    env.card_rate_limiter
+        .check(ip)
+        .map_err(|retry_after| reject_anyhow(rate_limited(retry_after)))?;
+    let mut response = match check_etag_index(&etag, &env.caches.shop_card, &id).await {
+        Some(response) => response,
+        None => {
+            let response = env
+                .caches
+                .shop_card
+                .get_response(id, || async {
+                    let card = ShopCard::get(&env.db, id).await?;
+                    let reply = ETagReply::<Json>::from_serializable(&card)?;
+                    let reply = with_status(reply, StatusCode::OK);
+                    Ok(reply)
+                })
+                .await?;
+            check_etag(etag, response)
+        }
+    };
+    // Problems (e.g. a deleted shop's 404) are not for link preview services to keep.
+    if response.status.is_success() || response.status == StatusCode::NOT_MODIFIED {
+        response.headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&format!("public, max-age={}", SHOP_CARD_TTL.as_secs()))
+                .expect("valid cache-control header"),
+        );
+    }
+    Ok(response)
+}
+
+/// Localized like `get` for JSON requests with an `Accept-Language` header, each shop with its own
+/// best translation.
 pub async fn list(
-    list_params: ListParams,
+    mut query: ShopListQuery,
+    api_key: Option<Uuid>,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    accept_language: Option<AcceptLanguageHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    check_not_ahead(
+        "active_since",
+        query.filter.active_since,
+        env.config.clock_skew_tolerance,
+    )
+    .map_err(reject_anyhow)?;
+    let viewer_id = authenticate_optional(&env, api_key)
+        .await
+        .map_err(reject_anyhow)?;
+    if let Some(OwnerFilter::Me) = query.filter.owner_id {
+        let viewer_id = viewer_id.ok_or_else(|| reject_anyhow(unauthorized_no_api_key()))?;
+        query.filter.owner_id = Some(OwnerFilter::Id(viewer_id));
+    }
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<(ShopListQuery, Option<Locale>), CachedResponse>::pick_cache(
+        accept,
+        &env.caches.list_shops_bin,
+        &env.caches.list_shops,
+    );
+    let locale = match content_type {
+        ContentType::Bincode => None,
+        ContentType::Json => accept_language.and_then(|accept_language| accept_language.preferred),
+    };
+    let response = cache
+        .get_response((query.clone(), locale.clone()), || async {
+            let db = env.db.clone();
+            let params = query.clone();
+            let (shops, total) = env
+                .in_flight
+                .list_shops
+                .run(query.clone(), async move {
+                    try_join(Shop::list(&db, &params), Shop::count(&db, &params)).await
+                })
+                .await?;
+            let reply: Box<dyn Reply> = match (&content_type, &locale) {
+                (ContentType::Bincode, _) => Box::new(ETagReply::<Bincode>::from_serializable(
+                    &public_shops(shops),
+                )?),
+                (ContentType::Json, None) => {
+                    Box::new(ETagReply::<Json>::from_serializable(&public_shops(shops))?)
+                }
+                (ContentType::Json, Some(locale)) => {
+                    let shops = LocalizedShop::localize_all(&env.db, shops, locale).await?;
+                    Box::new(ETagReply::<Json>::from_serializable(&shops)?)
+                }
+            };
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
+            Ok(reply)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+/// The owner's shops, for clients that would otherwise page through every shop to find them.
+pub async fn list_by_owner_id(
+    owner_id: i32,
+    query: OwnerShopListQuery,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
     env: Environment,
@@ -52,35 +304,73 @@ pub async fn list(
     let TypedCache {
         content_type,
         cache,
-    } = TypedCache::<ListParams, CachedResponse>::pick_cache(
+    } = TypedCache::<(i32, OwnerShopListQuery), CachedResponse>::pick_cache(
         accept,
-        &CACHES.list_shops_bin,
-        &CACHES.list_shops,
+        &env.caches.list_shops_by_owner_id_bin,
+        &env.caches.list_shops_by_owner_id,
     );
     let response = cache
-        .get_response(list_params.clone(), || async {
-            let shops = Shop::list(&env.db, &list_params).await?;
+        .get_response((owner_id, query.clone()), || async {
+            let db = env.db.clone();
+            let params = query.clone();
+            let (shops, total) = env
+                .in_flight
+                .list_shops_by_owner_id
+                .run((owner_id, query.clone()), async move {
+                    try_join(
+                        Shop::list_by_owner_id(&db, owner_id, &params),
+                        Shop::count_by_owner_id(&db, owner_id),
+                    )
+                    .await
+                })
+                .await?;
+            let shops = public_shops(shops);
             let reply: Box<dyn Reply> = match content_type {
                 ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(&shops)?),
                 ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&shops)?),
             };
-            let reply = with_status(reply, StatusCode::OK);
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
             Ok(reply)
         })
         .await?;
     Ok(check_etag(etag, response))
 }
 
+/// Query parameters for `POST /v1/shops`.
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateShopParams {
+    /// Fill in the fields the posted shop leaves out from the owner's `ShopTemplate`.
+    #[serde(default)]
+    pub from_template: bool,
+}
+
 pub async fn create(
+    params: CreateShopParams,
     bytes: Bytes,
     api_key: Option<Uuid>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
     let DeserializedBody {
         body: mut shop,
         content_type,
     } = DeserializedBody::<PostedShop>::from_bytes(bytes, content_type).map_err(reject_anyhow)?;
+    // Merged before validating, so that a shop made from a template is checked like any other.
+    let shelves = if params.from_template {
+        let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+        let template = ShopTemplate::get_by_owner_id(&env.db, owner_id)
+            .await
+            .map_err(reject_anyhow)?;
+        template.apply_to(&mut shop);
+        template.shelves
+    } else {
+        vec![]
+    };
+    shop.validate(&env.config.shop_tags, &env.config.economy)
+        .map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
     shop.owner_id = Some(owner_id);
     let mut tx = env
@@ -88,115 +378,3150 @@ pub async fn create(
         .begin()
         .await
         .map_err(|error| reject_anyhow(anyhow!(error)))?;
-    let saved_shop = Shop::create(shop, &mut tx).await.map_err(reject_anyhow)?;
+    let saved_shop = match Shop::create(shop.clone(), &mut tx, &env.config.economy).await {
+        Ok(saved_shop) => saved_shop,
+        Err(error) => {
+            drop(tx);
+            // A double-clicked "create shop" sends the same shop twice, and the second insert
+            // waits on the first before hitting the unique name index. Answer it with the shop the
+            // first one created, as long as it really is the same shop.
+            if unique_violation(&error) == Some("shops_unique_normalized_name_and_owner_id") {
+                if let Some(existing) =
+                    Shop::get_by_owner_id_and_name(&env.db, owner_id, &shop.name)
+                        .await
+                        .map_err(reject_anyhow)?
+                {
+                    if !shop.matches(&existing, &env.config.economy) {
+                        let url = existing.url(&env.api_url).map_err(reject_anyhow)?;
+                        return Err(reject_anyhow(shop_exists(existing.id, &url)));
+                    }
+                    let interior_ref_list = InteriorRefList::get_by_shop_id(&env.db, existing.id)
+                        .await
+                        .map_err(reject_anyhow)?;
+                    let merchandise_list = MerchandiseList::get_by_shop_id(&env.db, existing.id)
+                        .await
+                        .map_err(reject_anyhow)?;
+                    let slug = ShopSlug::get_current(&env.db, existing.id)
+                        .await
+                        .map_err(reject_anyhow)?;
+                    let reply = shop_body(&existing, content_type, return_minimal, &env.api_url)
+                        .map_err(reject_anyhow)?;
+                    let reply = Box::new(with_header(reply, "X-Shop-Slug", slug));
+                    return with_seeded_lists(
+                        reply,
+                        &existing,
+                        &interior_ref_list,
+                        &merchandise_list,
+                        StatusCode::OK,
+                        &env.api_url,
+                    )
+                    .map_err(reject_anyhow);
+                }
+            }
+            return Err(reject_anyhow(error));
+        }
+    };
+    let slug = ShopSlug::assign(&mut tx, saved_shop.id, &saved_shop.name)
+        .await
+        .map_err(reject_anyhow)?;
 
-    // also save empty interior_ref_list and merchandise_list rows
+    // also save empty interior_ref_list and merchandise_list rows, whose lists are `[]` (the
+    // columns only accept JSON arrays)
     let interior_ref_list = PostedInteriorRefList {
         shop_id: saved_shop.id,
         owner_id: Some(owner_id),
-        ref_list: sqlx::types::Json::default(),
-        shelves: sqlx::types::Json::default(),
+        ref_list: sqlx::types::Json(Vec::new()),
+        shelves: sqlx::types::Json(shelves),
+        expected_revision: None,
     };
-    InteriorRefList::create(interior_ref_list, &mut tx)
+    let saved_interior_ref_list = InteriorRefList::create(interior_ref_list, &mut tx)
         .await
         .map_err(reject_anyhow)?;
     let merchandise_list = PostedMerchandiseList {
         shop_id: saved_shop.id,
         owner_id: Some(owner_id),
-        form_list: sqlx::types::Json::default(),
+        form_list: sqlx::types::Json(Vec::new()),
+        expected_revision: None,
     };
-    MerchandiseList::create(merchandise_list, &mut tx)
+    let saved_merchandise_list = MerchandiseList::create(merchandise_list, &mut tx)
         .await
         .map_err(reject_anyhow)?;
+    let shop_usage = match env.config.shops_per_owner_soft_limit {
+        Some(limit) => ResourceUsage::new(
+            "shops",
+            Shop::count_by_owner_id(&mut tx, owner_id)
+                .await
+                .map_err(reject_anyhow)? as usize,
+            Some(limit),
+        ),
+        None => None,
+    };
     tx.commit()
         .await
         .map_err(|error| reject_anyhow(anyhow!(error)))?;
 
-    let url = saved_shop.url(&env.api_url).map_err(reject_anyhow)?;
-    let reply: Box<dyn Reply> = match content_type {
+    let reply = shop_body(&saved_shop, content_type, return_minimal, &env.api_url)
+        .map_err(reject_anyhow)?;
+    let reply = Box::new(with_header(reply, "X-Shop-Slug", slug));
+    let reply = with_resource_usage(reply, shop_usage);
+    let reply = with_seeded_lists(
+        reply,
+        &saved_shop,
+        &saved_interior_ref_list,
+        &saved_merchandise_list,
+        StatusCode::CREATED,
+        &env.api_url,
+    )
+    .map_err(reject_anyhow)?;
+    env.caches
+        .invalidate(InvalidationEvent::ShopCreated { owner_id })
+        .await;
+    Ok(reply)
+}
+
+// Writes answer with the public view of the shop, whose ETag `If-Match` takes as well as the
+// owner's.
+fn shop_body(
+    shop: &Shop,
+    content_type: ContentType,
+    return_minimal: bool,
+    api_url: &Url,
+) -> Result<Box<dyn Reply>> {
+    shop_view_body(
+        &PublicShop::from(shop.clone()),
+        &content_type,
+        return_minimal,
+        api_url,
+    )
+}
+
+fn shop_view_body<T: WithLinks>(
+    shop: &T,
+    content_type: &ContentType,
+    return_minimal: bool,
+    api_url: &Url,
+) -> Result<Box<dyn Reply>> {
+    let etag_fields = shop_etag_fields(shop)?;
+    Ok(match content_type {
+        _ if return_minimal => Box::new(MinimalReply::from_resource(&etag_fields)?),
         ContentType::Bincode => {
-            Box::new(ETagReply::<Bincode>::from_serializable(&saved_shop).map_err(reject_anyhow)?)
+            Box::new(ETagReply::<Bincode>::from_serializable(shop)?.with_etag_of(&etag_fields)?)
         }
         ContentType::Json => {
-            Box::new(ETagReply::<Json>::from_serializable(&saved_shop).map_err(reject_anyhow)?)
+            Box::new(ETagReply::<Json>::from_resource(shop, api_url)?.with_etag_of(&etag_fields)?)
         }
-    };
+    })
+}
+
+fn public_shops(shops: Vec<Shop>) -> Vec<PublicShop> {
+    shops.into_iter().map(PublicShop::from).collect()
+}
+
+/// What a shop's ETag is computed from: a `PublicShop` or `OwnerShop`, less `visits_count`.
+/// Visits don't evict cached shops, so the ETag of a cached response has to still match the shop
+/// in `If-Match` after one.
+pub fn shop_etag_fields<T: Serialize>(shop: &T) -> Result<Value> {
+    let mut fields = serde_json::to_value(shop)?;
+    if let Some(fields) = fields.as_object_mut() {
+        fields.remove("visits_count");
+    }
+    Ok(fields)
+}
+
+// The lists seeded with the shop, so clients can use the id-based list routes and send
+// `If-None-Match` without fetching them first. Headers keep the bincode body unchanged.
+fn with_seeded_lists(
+    reply: Box<dyn Reply>,
+    shop: &Shop,
+    interior_ref_list: &InteriorRefList,
+    merchandise_list: &MerchandiseList,
+    status: StatusCode,
+    api_url: &Url,
+) -> Result<impl Reply> {
+    let url = shop.url(api_url)?;
+    let reply = with_header(
+        reply,
+        "X-Interior-Ref-List-Id",
+        interior_ref_list.id.to_string(),
+    );
+    let reply = with_header(
+        reply,
+        "X-Interior-Ref-List-ETag",
+        canonical_etag(interior_ref_list)?,
+    );
+    let reply = with_header(
+        reply,
+        "X-Merchandise-List-Id",
+        merchandise_list.id.to_string(),
+    );
+    let reply = with_header(
+        reply,
+        "X-Merchandise-List-ETag",
+        canonical_etag(merchandise_list)?,
+    );
     let reply = with_header(reply, "Location", url.as_str());
-    let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES.list_shops.clear().await;
-        CACHES.list_shops_bin.clear().await;
-    });
-    Ok(reply)
+    Ok(with_status(reply, status))
+}
+
+// Checks `If-Match` against both the shop its owner sees and the one everyone else does, keeping
+// the shop locked until `tx` ends so that no other write lands between the check and the caller's.
+async fn check_shop_if_match(
+    tx: &mut PgConnection,
+    id: i32,
+    owner_id: i32,
+    if_match: Option<&str>,
+) -> Result<()> {
+    if if_match.is_none() {
+        return Ok(());
+    }
+    let shop = Shop::get_for_update(&mut *tx, id).await?;
+    if shop.owner_id != owner_id {
+        return Err(forbidden_permission());
+    }
+    let notification_settings = NotificationSettings::get_by_shop_id(&mut *tx, id).await?;
+    let etags = [
+        canonical_etag(&shop_etag_fields(&PublicShop::from(shop.clone()))?)?,
+        canonical_etag(&shop_etag_fields(&OwnerShop::new(
+            shop,
+            notification_settings,
+        ))?)?,
+    ];
+    check_if_match(if_match, &etags)
 }
 
 pub async fn update(
     id: i32,
     bytes: Bytes,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
     let DeserializedBody {
         body: mut shop,
         content_type,
     } = DeserializedBody::<PostedShop>::from_bytes(bytes, content_type).map_err(reject_anyhow)?;
+    shop.validate(&env.config.shop_tags, &env.config.economy)
+        .map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
     shop.owner_id = match shop.owner_id {
         // allows an owner to transfer ownership of shop to another owner
         Some(posted_owner_id) => Some(posted_owner_id),
         None => Some(owner_id),
     };
-    let updated_shop = Shop::update(shop, &env.db, owner_id, id)
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    check_shop_if_match(&mut tx, id, owner_id, if_match.as_deref())
         .await
         .map_err(reject_anyhow)?;
+    // A new name goes through the same checks as `POST /v1/shops/{id}/rename`.
+    let (_, slug) = rename_shop(&mut tx, id, owner_id, &shop.name, &env.api_url)
+        .await
+        .map_err(reject_anyhow)?;
+    let updated_shop = Shop::update(shop, &mut tx, owner_id, id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
     let url = updated_shop.url(&env.api_url).map_err(reject_anyhow)?;
-    let reply: Box<dyn Reply> = match content_type {
-        ContentType::Bincode => {
-            Box::new(ETagReply::<Bincode>::from_serializable(&updated_shop).map_err(reject_anyhow)?)
-        }
-        ContentType::Json => {
-            Box::new(ETagReply::<Json>::from_serializable(&updated_shop).map_err(reject_anyhow)?)
-        }
-    };
+    let reply = shop_body(&updated_shop, content_type, return_minimal, &env.api_url)
+        .map_err(reject_anyhow)?;
     let reply = with_header(reply, "Location", url.as_str());
-    let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        CACHES.shop.delete_response(id).await;
-        CACHES.shop_bin.delete_response(id).await;
-        CACHES.list_shops.clear().await;
-        CACHES.list_shops_bin.clear().await;
-    });
+    let reply = with_header(reply, "X-Shop-Slug", slug);
+    let status = if return_minimal {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    let reply = with_status(reply, status);
+    // The writer owned the shop before, but may have given it to another owner.
+    env.caches
+        .invalidate(InvalidationEvent::ShopUpdated {
+            shop_id: updated_shop.id,
+            owner_id: updated_shop.owner_id,
+            previous_owner_id: owner_id,
+        })
+        .await;
+    Ok(reply)
+}
+
+pub async fn rename(
+    id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    if_match: Option<String>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: mut rename,
+        content_type,
+    } = DeserializedBody::<PostedShopRename>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    rename.validate().map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    check_shop_if_match(&mut tx, id, owner_id, if_match.as_deref())
+        .await
+        .map_err(reject_anyhow)?;
+    let (renamed_shop, slug) = rename_shop(&mut tx, id, owner_id, &rename.name, &env.api_url)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reply =
+        shop_body(&renamed_shop, content_type, false, &env.api_url).map_err(reject_anyhow)?;
+    let reply = with_header(reply, "X-Shop-Slug", slug);
+    env.caches
+        .invalidate(InvalidationEvent::ShopUpdated {
+            shop_id: renamed_shop.id,
+            owner_id: renamed_shop.owner_id,
+            previous_owner_id: owner_id,
+        })
+        .await;
     Ok(reply)
 }
 
+// Renames the shop inside the caller's transaction, for both `rename` and `update`, and returns it
+// with its current slug. The shop is locked and its owner checked first, and a name another of the
+// owner's shops has is a 409 naming that shop. The unique index still catches two shops renamed to
+// the same name at once. The new name's slug replaces the old one in the same transaction, which
+// keeps the old slug redirecting to the shop.
+async fn rename_shop(
+    tx: &mut PgConnection,
+    id: i32,
+    owner_id: i32,
+    name: &str,
+    api_url: &Url,
+) -> Result<(Shop, String)> {
+    let shop = Shop::get_for_update(&mut *tx, id).await?;
+    if shop.owner_id != owner_id {
+        return Err(forbidden_permission());
+    }
+    if shop.name == name {
+        let slug = ShopSlug::get_current(&mut *tx, id).await?;
+        return Ok((shop, slug));
+    }
+    if let Some(existing) = Shop::get_by_owner_id_and_name(&mut *tx, owner_id, name).await? {
+        if existing.id != id {
+            return Err(shop_exists(existing.id, &existing.url(api_url)?));
+        }
+    }
+    let renamed_shop = Shop::rename(&mut *tx, id, name).await?;
+    let slug = ShopSlug::assign(&mut *tx, id, &renamed_shop.name).await?;
+    Ok((renamed_shop, slug))
+}
+
 pub async fn delete(
     id: i32,
+    params: DeleteShopParams,
     api_key: Option<Uuid>,
+    if_match: Option<String>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
-    Shop::delete(&env.db, owner_id, id)
+    if env.config.shop_delete_confirmation {
+        verify_confirmation_token(
+            id,
+            params.confirmation_token.as_deref(),
+            Utc::now().timestamp(),
+        )
+        .map_err(reject_anyhow)?;
+    }
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    check_shop_if_match(&mut tx, id, owner_id, if_match.as_deref())
+        .await
+        .map_err(reject_anyhow)?;
+    Shop::delete(&mut tx, owner_id, id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    env.caches
+        .invalidate(InvalidationEvent::ShopDeleted {
+            shop_id: id,
+            owner_id,
+        })
+        .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Deliberately leaves every cache alone: visits are counted on every shop entry, and cached shops
+// expire on their own after `caches::SHOP_TTL`.
+pub async fn visit(
+    id: i32,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    ShopVisit::record(&env.db, id, owner_id)
         .await
         .map_err(reject_anyhow)?;
-    tokio::spawn(async move {
-        CACHES.shop.delete_response(id).await;
-        CACHES.shop_bin.delete_response(id).await;
-        CACHES.list_shops.clear().await;
-        CACHES.list_shops_bin.clear().await;
-        CACHES
-            .interior_ref_list_by_shop_id
-            .delete_response(id)
-            .await;
-        CACHES
-            .interior_ref_list_by_shop_id_bin
-            .delete_response(id)
-            .await;
-        CACHES.merchandise_list_by_shop_id.delete_response(id).await;
-        CACHES
-            .merchandise_list_by_shop_id_bin
-            .delete_response(id)
-            .await;
-    });
     Ok(StatusCode::NO_CONTENT)
 }
+
+pub async fn get_notification_settings(
+    id: i32,
+    api_key: Option<Uuid>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let shop = Shop::get(&env.db, id).await.map_err(reject_anyhow)?;
+    if shop.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let notification_settings = NotificationSettings::get_by_shop_id(&env.db, id)
+        .await
+        .map_err(reject_anyhow)?;
+    let reply: Box<dyn Reply> = match accept {
+        Some(accept) if accept.accepts_bincode() => Box::new(
+            ETagReply::<Bincode>::from_serializable(&notification_settings)
+                .map_err(reject_anyhow)?,
+        ),
+        _ => Box::new(
+            ETagReply::<Json>::from_serializable(&notification_settings).map_err(reject_anyhow)?,
+        ),
+    };
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+pub async fn update_notification_settings(
+    id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    if_match: Option<String>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: posted_notification_settings,
+        content_type,
+    } = DeserializedBody::<PostedNotificationSettings>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    // Locked so that two partial updates can't both apply to the same current settings.
+    let shop = Shop::get_for_update(&mut tx, id)
+        .await
+        .map_err(reject_anyhow)?;
+    if shop.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let current = NotificationSettings::get_by_shop_id(&mut tx, id)
+        .await
+        .map_err(reject_anyhow)?;
+    if if_match.is_some() {
+        // The settings' own ETag, or that of the owner's view of the shop, which shows them.
+        let etags = [
+            canonical_etag(&current).map_err(reject_anyhow)?,
+            canonical_etag(
+                &shop_etag_fields(&OwnerShop::new(shop, current.clone())).map_err(reject_anyhow)?,
+            )
+            .map_err(reject_anyhow)?,
+        ];
+        check_if_match(if_match.as_deref(), &etags).map_err(reject_anyhow)?;
+    }
+    let notification_settings = posted_notification_settings
+        .apply(current)
+        .map_err(reject_anyhow)?
+        .save_by_shop_id(&mut tx, id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reply: Box<dyn Reply> = match content_type {
+        ContentType::Bincode => Box::new(
+            ETagReply::<Bincode>::from_serializable(&notification_settings)
+                .map_err(reject_anyhow)?,
+        ),
+        ContentType::Json => Box::new(
+            ETagReply::<Json>::from_serializable(&notification_settings).map_err(reject_anyhow)?,
+        ),
+    };
+    env.caches
+        .invalidate(InvalidationEvent::ShopNotificationSettingsSaved {
+            shop_id: id,
+            owner_id,
+        })
+        .await;
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+pub async fn list_translations(
+    id: i32,
+    api_key: Option<Uuid>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let shop = Shop::get(&env.db, id).await.map_err(reject_anyhow)?;
+    if shop.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let translations = ShopTranslation::list_by_shop_id(&env.db, id)
+        .await
+        .map_err(reject_anyhow)?;
+    let reply: Box<dyn Reply> = match accept {
+        Some(accept) if accept.accepts_bincode() => {
+            Box::new(ETagReply::<Bincode>::from_serializable(&translations).map_err(reject_anyhow)?)
+        }
+        _ => Box::new(ETagReply::<Json>::from_serializable(&translations).map_err(reject_anyhow)?),
+    };
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+pub async fn save_translation(
+    id: i32,
+    locale: String,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let locale = Locale::validate(&locale).map_err(reject_anyhow)?;
+    let DeserializedBody {
+        body: mut translation,
+        content_type,
+    } = DeserializedBody::<PostedShopTranslation>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    translation.validate().map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let shop = Shop::get(&mut tx, id).await.map_err(reject_anyhow)?;
+    if shop.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let saved_translation = ShopTranslation::save(&mut tx, id, &locale, translation)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reply: Box<dyn Reply> = match content_type {
+        ContentType::Bincode => Box::new(
+            ETagReply::<Bincode>::from_serializable(&saved_translation).map_err(reject_anyhow)?,
+        ),
+        ContentType::Json => Box::new(
+            ETagReply::<Json>::from_serializable(&saved_translation).map_err(reject_anyhow)?,
+        ),
+    };
+    env.caches
+        .invalidate(InvalidationEvent::ShopTranslated { shop_id: id })
+        .await;
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+pub async fn delete_translation(
+    id: i32,
+    locale: String,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let locale = Locale::validate(&locale).map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let shop = Shop::get(&env.db, id).await.map_err(reject_anyhow)?;
+    if shop.owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    ShopTranslation::delete(&env.db, id, &locale)
+        .await
+        .map_err(reject_anyhow)?;
+    env.caches
+        .invalidate(InvalidationEvent::ShopTranslated { shop_id: id })
+        .await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Bans only affect transactions, which check them in the database, so no cache is evicted.
+pub async fn create_ban(
+    id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: ban,
+        content_type,
+    } = DeserializedBody::<PostedShopBan>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    ban.validate().map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let saved_ban = ShopBan::create(&mut tx, id, owner_id, ban)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reply: Box<dyn Reply> = match content_type {
+        ContentType::Bincode => {
+            Box::new(ETagReply::<Bincode>::from_serializable(&saved_ban).map_err(reject_anyhow)?)
+        }
+        ContentType::Json => {
+            Box::new(ETagReply::<Json>::from_serializable(&saved_ban).map_err(reject_anyhow)?)
+        }
+    };
+    Ok(with_status(reply, StatusCode::CREATED))
+}
+
+pub async fn delete_ban(
+    id: i32,
+    banned_owner_id: i32,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    ShopBan::delete(&mut tx, id, owner_id, banned_owner_id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::prelude::*;
+    use hyper::body::Bytes;
+    use serde_json::{json, Value};
+    use url::Url;
+    use warp::http::header::LOCATION;
+    use warp::http::{Response, StatusCode};
+
+    use crate::body_digest::sha256_hex;
+    use crate::clock::SERVER_TIME;
+    use crate::models::{
+        InteriorRefList, MerchandiseList, OwnerShop, PostedShop, PublicShop, Shop,
+    };
+    use crate::problem::from_anyhow;
+    use crate::test_support::{
+        assert_one_deleted, assert_problem, json_body, json_request, request, TestEnv,
+        OTHER_OWNER_API_KEY, OWNER_API_KEY,
+    };
+
+    #[tokio::test]
+    async fn create_get_and_update_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let shop = json_body(&response);
+        assert_eq!(shop["name"], "Test Shop");
+        assert_eq!(shop["owner_id"], owner_id);
+        // The GET caches the shop from a spawned task. Wait for it, so that the PATCH below has
+        // something to evict.
+        while test.env.caches.shop.lru_mutex.lock().await.is_empty() {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+        }
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({
+                    "name": "Renamed Shop",
+                    "description": "for testing",
+                    "gold": 500,
+                    "shop_type": "general_store",
+                    "vendor_keywords_exclude": true,
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(&response)["name"], "Renamed Shop");
+
+        // The first GET cached the shop, so this only passes if the PATCH evicted it before
+        // responding.
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response)["name"], "Renamed Shop");
+    }
+
+    #[tokio::test]
+    async fn patches_leave_omitted_fields_unchanged() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({
+                    "name": "Test Shop",
+                    "gold": 500,
+                    "shop_type": "blacksmith",
+                    // Only differ in the case of a non-ASCII letter.
+                    "vendor_keywords": ["VendorItemÉpée", " vendoritemépée ", "VendorItemKey"],
+                    "vendor_keywords_exclude": false,
+                }),
+            )
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(
+            json_body(&response)["vendor_keywords"],
+            json!(["VendorItemÉpée", "VendorItemKey"])
+        );
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &path,
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Renamed Shop" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test.send(request("GET", &path, None)).await;
+        let shop = json_body(&response);
+        assert_eq!(shop["name"], "Renamed Shop");
+        assert_eq!(shop["gold"], 500);
+        assert_eq!(shop["shop_type"], "blacksmith");
+        assert_eq!(
+            shop["vendor_keywords"],
+            json!(["VendorItemÉpée", "VendorItemKey"])
+        );
+        assert_eq!(shop["vendor_keywords_exclude"], false);
+
+        // An empty list is sent on purpose, and clears the keywords.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &path,
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Renamed Shop", "vendor_keywords": [] }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(json_body(&response)["vendor_keywords"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn seeded_lists_are_empty_arrays() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let interior_ref_list_path = format!("/v1/shops/{}/interior_ref_list", shop_id);
+        let merchandise_list_path = format!("/v1/shops/{}/merchandise_list", shop_id);
+
+        let response = test
+            .send(request("GET", &interior_ref_list_path, None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let interior_ref_list = json_body(&response);
+        assert_eq!(interior_ref_list["ref_list"], json!([]));
+        assert_eq!(interior_ref_list["shelves"], json!([]));
+        let response = test
+            .send(request("GET", &merchandise_list_path, None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(json_body(&response)["form_list"], json!([]));
+
+        let response = test
+            .send(
+                request("GET", &interior_ref_list_path, None)
+                    .header("accept", "application/octet-stream"),
+            )
+            .await;
+        let interior_ref_list: InteriorRefList = bincode::deserialize(response.body()).unwrap();
+        assert!(interior_ref_list.ref_list.is_empty());
+        assert!(interior_ref_list.shelves.is_empty());
+        let response = test
+            .send(
+                request("GET", &merchandise_list_path, None)
+                    .header("accept", "application/octet-stream"),
+            )
+            .await;
+        let merchandise_list: MerchandiseList = bincode::deserialize(response.body()).unwrap();
+        assert!(merchandise_list.form_list.is_empty());
+
+        // The columns refuse anything but an array.
+        for update in &[
+            "UPDATE interior_ref_lists SET ref_list = 'null'::jsonb",
+            "UPDATE interior_ref_lists SET shelves = '{}'::jsonb",
+            "UPDATE merchandise_lists SET form_list = 'null'::jsonb",
+        ] {
+            assert!(
+                sqlx::query(update).execute(&test.env.db).await.is_err(),
+                "{}",
+                update
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn owner_view_is_cached_apart_from_the_public_view() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Test Shop", "private_notes": "restock on Fridays" }),
+            )
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+        let views = |expected_name: &'static str, expected_notes: &'static str| {
+            let test = &test;
+            let path = path.clone();
+            async move {
+                // The owner's view is cached first, so a leak would show up in the other views.
+                let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+                let owner_view = json_body(&response);
+                assert_eq!(owner_view["name"], expected_name);
+                assert_eq!(owner_view["private_notes"], expected_notes);
+                let owner_etag = response.headers()["etag"].clone();
+                for api_key in &[None, Some(OTHER_OWNER_API_KEY)] {
+                    let response = test
+                        .send(
+                            request("GET", &path, *api_key)
+                                .header("if-none-match", owner_etag.clone()),
+                        )
+                        .await;
+                    assert_eq!(response.status(), StatusCode::OK);
+                    let public_view = json_body(&response);
+                    assert_eq!(public_view["name"], expected_name);
+                    assert!(
+                        public_view.get("private_notes").is_none(),
+                        "{}",
+                        public_view
+                    );
+                }
+            }
+        };
+        views("Test Shop", "restock on Fridays").await;
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &path,
+                Some(OWNER_API_KEY),
+                &json!({
+                    "name": "Renamed Shop",
+                    "description": "for testing",
+                    "gold": 500,
+                    "shop_type": "general_store",
+                    "vendor_keywords_exclude": true,
+                    "private_notes": "restock on Mondays",
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        // Both views were cached above, so this only passes if the PATCH evicted both.
+        views("Renamed Shop", "restock on Mondays").await;
+    }
+
+    #[tokio::test]
+    async fn translations_fall_back_from_exact_locale_to_language_to_canonical() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Trader", "description": "Fine wares" }),
+            )
+            .await;
+        let put = |api_key, locale: &str, name: &str| {
+            json_request(
+                "PUT",
+                &format!("/v1/shops/{}/translations/{}", shop_id, locale),
+                Some(api_key),
+                &json!({ "name": name, "description": format!("{} description", name) }),
+            )
+        };
+        let get = |path: &str, accept_language: &str| {
+            request("GET", path, None).header("accept-language", accept_language)
+        };
+        let shop_path = format!("/v1/shops/{}", shop_id);
+
+        let response = test.send(put(OTHER_OWNER_API_KEY, "pt", "Mercador")).await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test.send(put(OWNER_API_KEY, "pt_BR", "Mercador")).await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "locale");
+        let response = test.send(put(OWNER_API_KEY, "pt", "Mercador")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test.send(put(OWNER_API_KEY, "pt-BR", "Negociante")).await;
+        assert_eq!(json_body(&response)["locale"], "pt-br");
+
+        let shop = json_body(&test.send(get(&shop_path, "pt-BR, en;q=0.5")).await);
+        assert_eq!(shop["name"], "Negociante");
+        assert_eq!(shop["description"], "Negociante description");
+        assert_eq!(shop["locale"], "pt-br");
+        assert_eq!(shop["original_name"], "Trader");
+        assert_eq!(shop["original_description"], "Fine wares");
+        let shop = json_body(&test.send(get(&shop_path, "pt-PT")).await);
+        assert_eq!(shop["name"], "Mercador");
+        assert_eq!(shop["locale"], "pt");
+        let shop = json_body(&test.send(get(&shop_path, "fr-CA")).await);
+        assert_eq!(shop["name"], "Trader");
+        assert_eq!(shop["locale"], json!(null));
+        assert_eq!(shop["original_name"], "Trader");
+        // Without the header, and for the owner, the shop is unchanged.
+        let shop = json_body(&test.send(request("GET", &shop_path, None)).await);
+        assert_eq!(shop["name"], "Trader");
+        assert!(shop.get("original_name").is_none());
+        let response = test
+            .send(request("GET", &shop_path, Some(OWNER_API_KEY)).header("accept-language", "pt"))
+            .await;
+        assert_eq!(json_body(&response)["name"], "Trader");
+
+        // Cached per locale, and evicted when a translation changes.
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/shops/{}/translations/pt-br", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let shop = json_body(&test.send(get(&shop_path, "pt-BR")).await);
+        assert_eq!(shop["name"], "Mercador");
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/translations", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        let translations = json_body(&response);
+        assert_eq!(translations.as_array().unwrap().len(), 1);
+        assert_eq!(translations[0]["locale"], "pt");
+    }
+
+    #[tokio::test]
+    async fn shop_lists_are_localized_per_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let translated_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Trader" }))
+            .await;
+        test.create_shop(OWNER_API_KEY, &json!({ "name": "Smithy" }))
+            .await;
+        let response = test
+            .send(json_request(
+                "PUT",
+                &format!("/v1/shops/{}/translations/de", translated_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Händler" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let names = |response| {
+            let mut names: Vec<(String, String)> = json_body(&response)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|shop| {
+                    (
+                        shop["name"].as_str().unwrap().to_string(),
+                        shop["original_name"].as_str().unwrap_or("-").to_string(),
+                    )
+                })
+                .collect();
+            names.sort();
+            names
+        };
+
+        let localized = names(
+            test.send(request("GET", "/v1/shops", None).header("accept-language", "de-CH"))
+                .await,
+        );
+        assert_eq!(
+            localized,
+            vec![
+                ("Händler".to_string(), "Trader".to_string()),
+                ("Smithy".to_string(), "Smithy".to_string()),
+            ]
+        );
+        // The canonical list is cached separately from the localized one.
+        let canonical = names(test.send(request("GET", "/v1/shops", None)).await);
+        assert_eq!(
+            canonical,
+            vec![
+                ("Smithy".to_string(), "-".to_string()),
+                ("Trader".to_string(), "-".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn descriptions_longer_than_the_limit_are_rejected() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        // Counted in characters, so a limit's worth of multibyte ones still fits.
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Test Shop", "description": "ä".repeat(4096) }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let shop_id = json_body(&response)["id"].as_i64().unwrap();
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Other Shop", "description": "a".repeat(4097) }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "description");
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Test Shop", "description": "a".repeat(4097) }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "description");
+    }
+
+    #[tokio::test]
+    async fn negative_gold_is_rejected() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 100 }))
+            .await;
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Test Shop", "gold": -5000 }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "gold");
+
+        // Writes that skip validation are still stopped by the database.
+        let error = Shop::update_gold(&test.env.db, shop_id as i32, -101)
+            .await
+            .unwrap_err();
+        let problem = from_anyhow(error);
+        assert_eq!(problem.status, Some(StatusCode::BAD_REQUEST));
+        assert_eq!(
+            serde_json::to_value(&problem).unwrap()["code"],
+            "insufficient_shop_gold"
+        );
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(json_body(&response)["gold"], 100);
+    }
+
+    #[tokio::test]
+    async fn names_are_unique_ignoring_case_and_normalization() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let create = |api_key, name: &str| {
+            json_request("POST", "/v1/shops", Some(api_key), &json!({ "name": name }))
+        };
+
+        // "Händlerstube" with a combining diaeresis is stored precomposed.
+        let response = test
+            .send(create(OWNER_API_KEY, "Ha\u{308}ndlerstube"))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["name"], "H\u{e4}ndlerstube");
+        // The same shop posted again is a retry, but a different name is a conflict.
+        let response = test.send(create(OWNER_API_KEY, "H\u{e4}ndlerstube")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test
+            .send(create(OWNER_API_KEY, "HA\u{308}NDLERSTUBE"))
+            .await;
+        assert_problem(&response, StatusCode::CONFLICT);
+        let response = test
+            .send(create(OWNER_API_KEY, "\u{41b}\u{430}\u{432}\u{43a}\u{430}"))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        // "ЛАВКА" only differs from "Лавка" by case.
+        let response = test
+            .send(create(OWNER_API_KEY, "\u{41b}\u{410}\u{412}\u{41a}\u{410}"))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert!(problem["detail"]
+            .as_str()
+            .unwrap()
+            .contains("ignoring case"));
+        let response = test
+            .send(create(OTHER_OWNER_API_KEY, "h\u{e4}ndlerstube"))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test
+            .send(create(OWNER_API_KEY, "\u{1f34e} Apple Stand \u{1f34f}"))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let apple_stand_id = json_body(&response)["id"].clone();
+
+        for name in &["", "   ", "\u{1}\u{7}\t"] {
+            let response = test.send(create(OWNER_API_KEY, name)).await;
+            let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(problem["errors"][0]["field"], "name");
+        }
+
+        let search = |name: &str| {
+            let name: String = url::form_urlencoded::byte_serialize(name.as_bytes()).collect();
+            request("GET", &format!("/v1/shops?name={}", name), None)
+        };
+        let response = test.send(search("HA\u{308}NDLER")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response).as_array().map(Vec::len), Some(2));
+        let response = test.send(search("apple stand \u{1f34f}")).await;
+        let shops = json_body(&response);
+        assert_eq!(shops.as_array().map(Vec::len), Some(1));
+        assert_eq!(shops[0]["id"], apple_stand_id);
+        // LIKE wildcards are matched literally.
+        let response = test.send(search("%")).await;
+        assert_eq!(json_body(&response).as_array().map(Vec::len), Some(0));
+    }
+
+    #[tokio::test]
+    async fn renames_check_collisions_and_stop_matching_the_old_name() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Old Curiosities" }))
+            .await;
+        let other_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Taken Name" }))
+            .await;
+        let rename = |api_key, name: &str| {
+            json_request(
+                "POST",
+                &format!("/v1/shops/{}/rename", shop_id),
+                Some(api_key),
+                &json!({ "name": name }),
+            )
+        };
+        let search = |name: &str| request("GET", &format!("/v1/shops?name={}", name), None);
+        // Cached, so the rename has to evict it.
+        let response = test.send(search("curiosities")).await;
+        assert_eq!(json_body(&response).as_array().map(Vec::len), Some(1));
+
+        let response = test.send(rename(OWNER_API_KEY, "taken NAME")).await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "shop_exists");
+        assert_eq!(problem["shop_id"], other_shop_id);
+        // A PATCH that renames goes through the same check.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Taken Name" }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["shop_id"], other_shop_id);
+        let response = test.send(rename(OTHER_OWNER_API_KEY, "Stolen")).await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test.send(rename(OWNER_API_KEY, "  ")).await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = test.send(rename(OWNER_API_KEY, "New Oddities")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let renamed = json_body(&response);
+        assert_eq!(renamed["name"], "New Oddities");
+        assert_ne!(renamed["updated_at"], renamed["created_at"]);
+
+        let response = test.send(search("curiosities")).await;
+        assert_eq!(json_body(&response).as_array().map(Vec::len), Some(0));
+        let response = test.send(search("oddities")).await;
+        assert_eq!(json_body(&response)[0]["id"], shop_id);
+        // Renaming a shop to its own name, in another case, is not a collision.
+        let response = test.send(rename(OWNER_API_KEY, "NEW ODDITIES")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn renames_move_the_slug_and_old_slugs_redirect() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let create = |api_key, name: &str| {
+            json_request("POST", "/v1/shops", Some(api_key), &json!({ "name": name }))
+        };
+        let by_slug = |slug: &str| request("GET", &format!("/v1/shops/slug/{}", slug), None);
+        let redirect_path = |response: &Response<Bytes>| {
+            assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+            let location = response.headers()[LOCATION].to_str().unwrap();
+            Url::parse(location).unwrap().path().to_string()
+        };
+        let slug_of = |response: &Response<Bytes>| {
+            response.headers()["x-shop-slug"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let response = test.send(create(OWNER_API_KEY, "General Store")).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(slug_of(&response), "general-store");
+        let shop_id = json_body(&response)["id"].clone();
+        // Names are only unique per owner, but slugs are unique across all shops.
+        let response = test
+            .send(create(OTHER_OWNER_API_KEY, "General  Store!"))
+            .await;
+        assert_eq!(slug_of(&response), "general-store-2");
+        let other_shop_id = json_body(&response)["id"].clone();
+
+        let response = test.send(by_slug("general-store")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response)["id"], shop_id);
+        let etag = response.headers()["etag"].clone();
+
+        let response = test
+            .send(json_request(
+                "POST",
+                &format!("/v1/shops/{}/rename", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Händler Shop" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(slug_of(&response), "handler-shop");
+        let response = test.send(by_slug("general-store")).await;
+        assert_eq!(redirect_path(&response), "/v1/shops/slug/handler-shop");
+        let response = test.send(by_slug("handler-shop")).await;
+        assert_eq!(json_body(&response)["name"], "Händler Shop");
+        assert_eq!(slug_of(&response), "handler-shop");
+        assert_ne!(response.headers()["etag"], etag);
+
+        // The old slug still belongs to the first shop, so a shop named after it can't take it.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", other_shop_id),
+                Some(OTHER_OWNER_API_KEY),
+                &json!({ "name": "Handler Shop" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(slug_of(&response), "handler-shop-2");
+        let response = test.send(by_slug("general-store-2")).await;
+        assert_eq!(redirect_path(&response), "/v1/shops/slug/handler-shop-2");
+
+        // Renaming back takes the old slug back, and the one in between redirects to it.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "General Store" }),
+            ))
+            .await;
+        assert_eq!(slug_of(&response), "general-store");
+        let response = test.send(by_slug("general-store")).await;
+        assert_eq!(json_body(&response)["id"], shop_id);
+        let response = test.send(by_slug("handler-shop")).await;
+        assert_eq!(redirect_path(&response), "/v1/shops/slug/general-store");
+
+        // A PATCH that fails after the rename rolls the slug back with it.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Broke Store", "owner_id": 999_999 }),
+            ))
+            .await;
+        assert!(!response.status().is_success(), "{:?}", response);
+        let response = test.send(by_slug("broke-store")).await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+        let response = test.send(by_slug("general-store")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn duplicate_creates_return_the_existing_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop =
+            json!({ "name": "Test Shop", "description": "for testing", "tags": ["Alchemy"] });
+        let create =
+            |shop: &serde_json::Value| json_request("POST", "/v1/shops", Some(OWNER_API_KEY), shop);
+
+        let created = test.send(create(&shop)).await;
+        assert_eq!(created.status(), StatusCode::CREATED, "{:?}", created);
+        let retried = test.send(create(&shop)).await;
+        assert_eq!(retried.status(), StatusCode::OK, "{:?}", retried);
+        assert_eq!(json_body(&retried)["id"], json_body(&created)["id"]);
+        for header in &[
+            "location",
+            "etag",
+            "x-interior-ref-list-id",
+            "x-interior-ref-list-etag",
+            "x-merchandise-list-id",
+            "x-merchandise-list-etag",
+            "x-shop-slug",
+        ] {
+            assert_eq!(
+                retried.headers().get(*header),
+                created.headers().get(*header),
+                "{}",
+                header
+            );
+        }
+
+        let response = test
+            .send(create(
+                &json!({ "name": "Test Shop", "description": "different" }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "shop_exists");
+        assert_eq!(problem["shop_id"], json_body(&created)["id"]);
+        assert_eq!(
+            problem["shop_url"],
+            created.headers()["location"].to_str().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn concurrent_duplicate_creates_make_one_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop = json!({ "name": "Double Click Shop" });
+
+        // Each request gets its own pooled connection, so both inserts race on the unique index.
+        let (first, second) = tokio::join!(
+            test.send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &shop
+            )),
+            test.send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &shop
+            )),
+        );
+        let mut statuses = vec![first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, vec![StatusCode::OK, StatusCode::CREATED]);
+        assert_eq!(json_body(&first)["id"], json_body(&second)["id"]);
+
+        let response = test
+            .send(request("GET", "/v1/shops?name=double%20click", None))
+            .await;
+        assert_eq!(json_body(&response).as_array().map(Vec::len), Some(1));
+    }
+
+    #[tokio::test]
+    async fn update_other_owners_shop_is_forbidden() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OTHER_OWNER_API_KEY),
+                &json!({ "name": "Stolen Shop" }),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn delete_other_owners_shop_is_forbidden() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn stale_if_match_is_a_failed_precondition() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+        let response = test.send(request("GET", &path, None)).await;
+        let read_etag = response.headers()["etag"].clone();
+
+        // Someone else's write, without If-Match, goes ahead and changes the ETag.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &path,
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Renamed Shop" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let response = test
+            .send(
+                json_request(
+                    "PATCH",
+                    &path,
+                    Some(OWNER_API_KEY),
+                    &json!({ "name": "Lost Update" }),
+                )
+                .header("if-match", read_etag.clone()),
+            )
+            .await;
+        let problem = assert_problem(&response, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(problem["code"], "precondition_failed");
+        let response = test
+            .send(request("DELETE", &path, Some(OWNER_API_KEY)).header("if-match", read_etag))
+            .await;
+        assert_problem(&response, StatusCode::PRECONDITION_FAILED);
+
+        // The owner's view has its own ETag, which matches just as well as the public one.
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        assert_eq!(json_body(&response)["name"], "Renamed Shop");
+        let owner_etag = response.headers()["etag"].clone();
+        let response = test
+            .send(
+                json_request(
+                    "PATCH",
+                    &path,
+                    Some(OWNER_API_KEY),
+                    &json!({ "name": "Fresh Update" }),
+                )
+                .header("if-match", owner_etag),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(json_body(&response)["name"], "Fresh Update");
+    }
+
+    #[tokio::test]
+    async fn etag_revalidates_across_formats() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let json_etag = response.headers()["etag"].clone();
+        let response = test
+            .send(request("GET", &path, None).header("accept", "application/octet-stream"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["etag"], json_etag);
+
+        let response = test
+            .send(
+                request("GET", &path, None)
+                    .header("accept", "application/octet-stream")
+                    .header("if-none-match", json_etag.clone()),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = test
+            .send(request("GET", &path, None).header(
+                "if-none-match",
+                format!("W/\"{}\"", json_etag.to_str().unwrap()),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn head_has_the_get_headers_without_the_body() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+
+        let get = test.send(request("GET", &path, None)).await;
+        let head = test.send(request("HEAD", &path, None)).await;
+        assert_eq!(head.status(), StatusCode::OK);
+        assert!(head.body().is_empty());
+        for header in &["etag", "content-type"] {
+            assert_eq!(head.headers()[*header], get.headers()[*header]);
+        }
+        assert_eq!(
+            head.headers()["content-length"],
+            get.body().len().to_string().as_str()
+        );
+
+        let response = test
+            .send(
+                request("HEAD", &path, None).header("if-none-match", get.headers()["etag"].clone()),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = test.send(request("HEAD", "/v1/shops", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.body().is_empty());
+        assert_eq!(response.headers()["x-total-count"], "1");
+        // Writes still only answer their own methods.
+        let response = test
+            .send(request("HEAD", &format!("{}/rename", path), None))
+            .await;
+        assert_ne!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn request_body_digests_are_verified() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let body = br#"{"name":"Test Shop","description":"a long description"}"#;
+        let sha256 = sha256_hex(body);
+        let create = |body: &[u8]| {
+            request("POST", "/v1/shops", Some(OWNER_API_KEY))
+                .header("content-type", "application/json")
+                .body(body)
+        };
+
+        // Cut short at a point where the rest is still valid JSON.
+        let truncated = br#"{"name":"Test Shop"}"#;
+        let response = test
+            .send(create(truncated).header("x-body-sha256", sha256.as_str()))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "body_digest_mismatch");
+
+        let response = test
+            .send(create(body).header("content-digest", "md5=:AAAAAAAAAAAAAAAAAAAAAA==:"))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "unsupported_digest_algorithm");
+
+        let content_digest = format!(
+            "sha-256=:{}:",
+            base64::encode(hex::decode(&sha256).unwrap())
+        );
+        let response = test
+            .send(create(body).header("content-digest", content_digest.as_str()))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(
+            response.headers()["x-body-sha256"],
+            sha256_hex(response.body()).as_str()
+        );
+    }
+
+    #[tokio::test]
+    async fn request_body_lengths_are_verified() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let body = br#"{"name":"Test Shop"}"#;
+        let create = |content_type: &str, body: &[u8]| {
+            request("POST", "/v1/shops", Some(OWNER_API_KEY))
+                .header("content-type", content_type)
+                .body(body)
+        };
+
+        for declared in &[body.len() + 10, body.len() - 1] {
+            let response = test
+                .send(create("application/json", body).header("content-length", *declared))
+                .await;
+            let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+            assert_eq!(problem["code"], "body_length_mismatch");
+        }
+
+        // Chunked bodies have no length to check, which is only allowed for JSON.
+        let bincode_body = bincode::serialize(&PostedShop {
+            name: "Bincode Shop".to_string(),
+            owner_id: None,
+            description: None,
+            gold: None,
+            shop_type: None,
+            vendor_keywords: None,
+            vendor_keywords_exclude: None,
+            tags: None,
+            private_notes: None,
+        })
+        .unwrap();
+        let response = test
+            .send(
+                create("application/octet-stream", &bincode_body)
+                    .header("transfer-encoding", "chunked"),
+            )
+            .await;
+        let problem = assert_problem(&response, StatusCode::LENGTH_REQUIRED);
+        assert_eq!(problem["code"], "content_length_required");
+        let response = test
+            .send(create("application/octet-stream", &bincode_body))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test
+            .send(create("application/json", body).header("transfer-encoding", "chunked"))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn shop_types_are_normalized_and_filtered() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let general_store_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "General", "shop_type": "General Store" }),
+            )
+            .await;
+        let alchemist_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Potions", "shop_type": "alchemist" }),
+            )
+            .await;
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}", general_store_id),
+                None,
+            ))
+            .await;
+        assert_eq!(json_body(&response)["shop_type"], "general_store");
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Typo", "shop_type": "genral_store" }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "shop_type");
+
+        let response = test
+            .send(request("GET", "/v1/shops?shop_type=Alchemist", None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let shops = json_body(&response);
+        assert_eq!(shops.as_array().map(Vec::len), Some(1));
+        assert_eq!(shops[0]["id"], alchemist_id);
+    }
+
+    #[tokio::test]
+    async fn lists_count_the_rows_matching_their_filters() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        for (name, shop_type) in &[
+            ("First", "alchemist"),
+            ("Second", "general_store"),
+            ("Third", "alchemist"),
+        ] {
+            test.create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": name, "shop_type": shop_type }),
+            )
+            .await;
+        }
+
+        let path = "/v1/shops?limit=1&offset=1&shop_type=alchemist";
+        let response = test.send(request("GET", path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response).as_array().map(Vec::len), Some(1));
+        assert_eq!(response.headers()["x-total-count"], "2");
+        assert_eq!(response.headers()["x-limit"], "1");
+        assert_eq!(response.headers()["x-offset"], "1");
+        let etag = response.headers()["etag"].clone();
+
+        let response = test
+            .send(request("GET", path, None).header("accept", "application/octet-stream"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-count"], "2");
+
+        let response = test
+            .send(request("GET", path, None).header("if-none-match", etag))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["x-total-count"], "2");
+
+        let response = test.send(request("GET", "/v1/shops", None)).await;
+        assert_eq!(response.headers()["x-total-count"], "3");
+        assert_eq!(response.headers()["x-limit"], "10");
+        assert_eq!(response.headers()["x-offset"], "0");
+    }
+
+    #[tokio::test]
+    async fn active_since_ahead_of_the_server_clock_is_clock_skew() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let at = |offset: chrono::Duration| {
+            (Utc::now() + offset)
+                .naive_utc()
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string()
+        };
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!(
+                    "/v1/shops?active_since={}",
+                    at(chrono::Duration::minutes(1))
+                ),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().contains_key(SERVER_TIME));
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops?active_since={}", at(chrono::Duration::days(1))),
+                None,
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "clock_skew");
+        assert_eq!(problem["param"], "active_since");
+        // Problems are stamped too, so the client can tell how far off its clock is either way.
+        let server_time = response.headers()[SERVER_TIME].to_str().unwrap();
+        assert!(DateTime::parse_from_rfc3339(server_time).is_ok());
+        assert!(DateTime::parse_from_rfc3339(problem["server_time"].as_str().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn shops_are_filtered_by_owner() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Mine" }))
+            .await;
+        test.create_shop(OTHER_OWNER_API_KEY, &json!({ "name": "Theirs" }))
+            .await;
+
+        let path = format!("/v1/shops?owner_id={}", owner_id);
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let shops = json_body(&response);
+        assert_eq!(shops.as_array().map(Vec::len), Some(1));
+        assert_eq!(shops[0]["id"], shop_id);
+        let response = test
+            .send(request("GET", &path, None).header("accept", "application/octet-stream"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-count"], "1");
+
+        let response = test
+            .send(request("GET", "/v1/shops?owner_id=me", Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(json_body(&response), shops);
+
+        // An owner that doesn't exist just has no shops.
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops?owner_id={}", owner_id + 100),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(json_body(&response), json!([]));
+        let response = test
+            .send(request("GET", "/v1/shops?owner_id=someone", None))
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn owners_shops_are_listed_and_evicted_on_writes() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "First" }))
+            .await;
+        test.create_shop(OTHER_OWNER_API_KEY, &json!({ "name": "Theirs" }))
+            .await;
+        let path = format!("/v1/owners/{}/shops?sort=name", owner_id);
+        let names = |response: &Response<Bytes>| -> Vec<String> {
+            assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+            json_body(response)
+                .as_array()
+                .expect("shops")
+                .iter()
+                .map(|shop| shop["name"].as_str().expect("name").to_string())
+                .collect()
+        };
+
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(names(&response), ["First"]);
+        assert_eq!(response.headers()["x-total-count"], "1");
+
+        // The first GET cached the page, so each of these only passes if the write evicted it.
+        let second_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Second" }))
+            .await;
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(names(&response), ["First", "Second"]);
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Third" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(names(&response), ["Second", "Third"]);
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/shops/{}", second_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(request("GET", &path, None).header("accept", "application/octet-stream"))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["x-total-count"], "1");
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(names(&response), ["Third"]);
+    }
+
+    #[tokio::test]
+    async fn custom_shop_types_when_enabled() {
+        let test = match TestEnv::with_config(&[("CUSTOM_SHOP_TYPES", "true")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Arrows", "shop_type": "Fletcher" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["shop_type"], "fletcher");
+    }
+
+    #[tokio::test]
+    async fn visits_are_counted_once_per_player_per_day() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Visitor").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Popular Shop" }))
+            .await;
+        let quiet_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Quiet Shop" }))
+            .await;
+        let visit = |id: i64, api_key: Option<&'static str>| {
+            request("POST", &format!("/v1/shops/{}/visit", id), api_key)
+        };
+
+        for _ in 0..3 {
+            let response = test.send(visit(shop_id, Some(OTHER_OWNER_API_KEY))).await;
+            assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        }
+        // Owners visiting their own shop are not counted.
+        let response = test.send(visit(shop_id, Some(OWNER_API_KEY))).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test.send(visit(shop_id, None)).await;
+        assert_problem(&response, StatusCode::UNAUTHORIZED);
+        let response = test.send(visit(999_999, Some(OTHER_OWNER_API_KEY))).await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(json_body(&response)["visits_count"], 1);
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/shops?order_by=visits_count&order=Desc",
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let shops = json_body(&response);
+        assert_eq!(shops[0]["id"], shop_id);
+        assert_eq!(shops[1]["id"], quiet_shop_id);
+        assert_eq!(shops[1]["visits_count"], 0);
+
+        // The next day's visit counts again.
+        sqlx::query("UPDATE shop_visits SET day = day - 1")
+            .execute(&test.env.db)
+            .await
+            .unwrap();
+        let response = test.send(visit(shop_id, Some(OTHER_OWNER_API_KEY))).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let visits_count: (i64,) = sqlx::query_as("SELECT visits_count FROM shops WHERE id = $1")
+            .bind(shop_id as i32)
+            .fetch_one(&test.env.db)
+            .await
+            .unwrap();
+        assert_eq!(visits_count.0, 2);
+    }
+
+    #[tokio::test]
+    async fn visits_leave_the_etag_if_match_takes_alone() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Visitor").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        let owner_etag = response.headers()["etag"].clone();
+        let response = test.send(request("GET", &path, None)).await;
+        let public_etag = response.headers()["etag"].clone();
+
+        let response = test
+            .send(request(
+                "POST",
+                &format!("{}/visit", path),
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        // Still served from the cache, so the count lags but the ETag is the shop's all the same.
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(json_body(&response)["visits_count"], 0);
+        assert_eq!(response.headers()["etag"], public_etag);
+        test.env.caches.shop.clear().await;
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(json_body(&response)["visits_count"], 1);
+        assert_eq!(response.headers()["etag"], public_etag);
+
+        let response = test
+            .send(
+                json_request(
+                    "PATCH",
+                    &path,
+                    Some(OWNER_API_KEY),
+                    &json!({ "name": "Renamed Shop" }),
+                )
+                .header("if-match", public_etag),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["visits_count"], 1);
+        let fresh_etag = response.headers()["etag"].clone();
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.headers()["etag"], fresh_etag);
+
+        // The owner's ETag from before the rename is stale, visit or not.
+        let response = test
+            .send(request("DELETE", &path, Some(OWNER_API_KEY)).header("if-match", owner_etag))
+            .await;
+        assert_problem(&response, StatusCode::PRECONDITION_FAILED);
+    }
+
+    #[tokio::test]
+    async fn banned_customers_cannot_trade_until_unbanned() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        let customer_id = test.create_owner(OTHER_OWNER_API_KEY, "Griefer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let bans_path = format!("/v1/shops/{}/bans", shop_id);
+        let sell = || {
+            json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OTHER_OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "Cabbage",
+                    "form_type": 46,
+                    "is_food": true,
+                    "price": 1,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 1,
+                    "keywords": [],
+                }),
+            )
+        };
+
+        // Only the shop's owner can ban, and not themselves.
+        let response = test
+            .send(json_request(
+                "POST",
+                &bans_path,
+                Some(OTHER_OWNER_API_KEY),
+                &json!({ "banned_owner_id": owner_id }),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test
+            .send(json_request(
+                "POST",
+                &bans_path,
+                Some(OWNER_API_KEY),
+                &json!({ "banned_owner_id": owner_id }),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                &bans_path,
+                Some(OWNER_API_KEY),
+                &json!({ "banned_owner_id": customer_id, "reason": "Stole the cabbages" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let ban = json_body(&response);
+        assert_eq!(ban["banned_owner_id"], customer_id);
+        assert_eq!(ban["reason"], "Stole the cabbages");
+
+        let response = test.send(sell()).await;
+        let problem = assert_problem(&response, StatusCode::FORBIDDEN);
+        assert_eq!(problem["code"], "banned_from_shop");
+        // Reads are unaffected.
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let ban_path = format!("{}/{}", bans_path, customer_id);
+        let response = test
+            .send(request("DELETE", &ban_path, Some(OTHER_OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test
+            .send(request("DELETE", &ban_path, Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(request("DELETE", &ban_path, Some(OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+
+        let response = test.send(sell()).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn sort_shops_by_several_columns() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        for (name, shop_type) in &[
+            ("Anvil", "blacksmith"),
+            ("Brews", "alchemist"),
+            ("Coals", "blacksmith"),
+        ] {
+            test.create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": name, "shop_type": shop_type }),
+            )
+            .await;
+        }
+        let names = |response: &warp::http::Response<hyper::body::Bytes>| -> Vec<String> {
+            json_body(response)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|shop| shop["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/shops?sort=shop_type.asc,name.desc",
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(names(&response), vec!["Brews", "Coals", "Anvil"]);
+
+        let response = test
+            .send(request("GET", "/v1/shops?order_by=name&order=asc", None))
+            .await;
+        assert_eq!(names(&response), vec!["Anvil", "Brews", "Coals"]);
+
+        let response = test
+            .send(request("GET", "/v1/shops?sort=name&order_by=name", None))
+            .await;
+        assert_problem(&response, StatusCode::BAD_REQUEST);
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/shops?sort=name,gold,id,shop_type",
+                None,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::BAD_REQUEST);
+        let response = test
+            .send(request("GET", "/v1/shops?sort=name,private_notes", None))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert!(problem["supported_columns"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("shop_type")));
+    }
+
+    #[tokio::test]
+    async fn shop_card_is_public_cacheable_and_gone_with_the_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({
+                    "name": "Test Shop",
+                    "description": "a".repeat(300),
+                    "gold": 500,
+                    "vendor_keywords": ["VendorItemFood"],
+                }),
+            )
+            .await;
+        let path = format!("/v1/shops/{}/card", shop_id);
+
+        let response = test.send(request("GET", &path, None)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(response.headers()["cache-control"], "public, max-age=600");
+        let card = json_body(&response);
+        assert_eq!(card["name"], "Test Shop");
+        assert_eq!(card["owner_name"], "Owner");
+        assert_eq!(card["item_count"], 0);
+        assert!(card["description"].as_str().unwrap().ends_with('…'));
+        for field in &["gold", "vendor_keywords", "owner_id", "average_rating"] {
+            assert!(card.get(field).is_none(), "card has {}", field);
+        }
+
+        let etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", &etag))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["cache-control"], "public, max-age=600");
+
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", &etag))
+            .await;
+        let response_headers = response.headers().clone();
+        assert_problem(&response, StatusCode::NOT_FOUND);
+        assert!(response_headers.get("cache-control").is_none());
+    }
+
+    #[tokio::test]
+    async fn shop_card_is_rate_limited_per_address() {
+        let test = match TestEnv::with_config(&[("CARD_RATE_LIMIT_PER_MINUTE", "2")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/card", shop_id);
+        let card_request = |ip| request("GET", &path, None).header("x-real-ip", ip);
+
+        for _ in 0..2 {
+            let response = test.send(card_request("10.0.0.1")).await;
+            assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        }
+        let response = test.send(card_request("10.0.0.1")).await;
+        assert!(response.headers().contains_key("retry-after"));
+        let problem = assert_problem(&response, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(problem["code"], "rate_limited");
+        let response = test.send(card_request("10.0.0.2")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn expensive_routes_run_out_before_cheap_ones() {
+        let test = match TestEnv::with_config(&[("EXPENSIVE_RPM", "2")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let history_path = format!("/v1/shops/{}/gold_history", shop_id);
+
+        for _ in 0..2 {
+            let response = test
+                .send(request("GET", &history_path, Some(OWNER_API_KEY)))
+                .await;
+            assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        }
+        let response = test
+            .send(request("GET", &history_path, Some(OWNER_API_KEY)))
+            .await;
+        assert!(response.headers().contains_key("retry-after"));
+        let problem = assert_problem(&response, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(problem["code"], "expensive_rate_limited");
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn pretty_json_has_its_own_etag() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+        let pretty_path = format!("{}?pretty=true", path);
+
+        let compact = test.send(request("GET", &path, None)).await;
+        assert_eq!(
+            compact.headers()["content-type"],
+            "application/json; charset=utf-8"
+        );
+        assert!(!compact.body().contains(&b'\n'));
+        let pretty = test.send(request("GET", &pretty_path, None)).await;
+        assert_eq!(pretty.status(), StatusCode::OK, "{:?}", pretty);
+        assert_eq!(
+            pretty.headers()["content-type"],
+            "application/json; charset=utf-8"
+        );
+        let body = std::str::from_utf8(pretty.body()).unwrap();
+        assert!(body.starts_with("{\n  \""), "{}", body);
+        assert_eq!(json_body(&pretty)["name"], "Test Shop");
+        let compact_etag = compact.headers()["etag"].to_str().unwrap().to_string();
+        let pretty_etag = pretty.headers()["etag"].to_str().unwrap().to_string();
+        assert_ne!(compact_etag, pretty_etag);
+
+        let response = test
+            .send(request("GET", &pretty_path, None).header("if-none-match", pretty_etag.as_str()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", compact_etag.as_str()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = test
+            .send(request("GET", &pretty_path, None).header("accept", "application/octet-stream"))
+            .await;
+        assert_problem(&response, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn concurrent_deletes_of_a_shop_delete_it_once() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let delete = || {
+            request(
+                "DELETE",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+            )
+        };
+        let (first, second) = test.send_concurrently(delete(), delete()).await;
+        assert_one_deleted(&first, &second);
+        // So is a retry once the dust has settled.
+        let response = test.send(delete()).await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn unknown_list_query_params_are_rejected_unless_lenient() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        for name in &["Alpha", "Bravo", "Charlie"] {
+            test.create_shop(OWNER_API_KEY, &json!({ "name": name }))
+                .await;
+        }
+
+        let response = test.send(request("GET", "/v1/shops?foo=1", None)).await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["unknown_params"], json!(["foo"]));
+        let supported = problem["supported_params"].as_array().unwrap();
+        for param in &["limit", "order_by", "order", "name", "active_since"] {
+            assert!(supported.contains(&json!(param)), "{}", param);
+        }
+
+        let response = test
+            .send(request("GET", "/v1/shops?order=desc&odrer_by=name", None))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["unknown_params"], json!(["odrer_by"]));
+
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/shops?order_by=name&order=desc&limit=2&pretty=true",
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let names: Vec<Value> = json_body(&response)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|shop| shop["name"].clone())
+            .collect();
+        assert_eq!(names, vec![json!("Charlie"), json!("Bravo")]);
+
+        let lenient = match TestEnv::with_config(&[("STRICT_QUERY_PARAMS", "false")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        lenient.create_owner(OWNER_API_KEY, "Owner").await;
+        lenient
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Alpha" }))
+            .await;
+        let response = lenient
+            .send(request("GET", "/v1/shops?order=desc&odrer_by=name", None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(json_body(&response)[0]["name"], "Alpha");
+    }
+
+    #[tokio::test]
+    async fn trailing_slashes_and_upper_case_versions_redirect_to_the_route() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let location = |response: &Response<Bytes>| {
+            assert_eq!(
+                response.status(),
+                StatusCode::PERMANENT_REDIRECT,
+                "{:?}",
+                response
+            );
+            response.headers()[LOCATION].to_str().unwrap().to_string()
+        };
+
+        for (path, canonical) in &[
+            ("/v1/shops/", "/v1/shops"),
+            ("/V1/shops", "/v1/shops"),
+            ("/V1/shops/", "/v1/shops"),
+            ("/v1/shops/?limit=1", "/v1/shops?limit=1"),
+        ] {
+            let response = test.send(request("GET", path, None)).await;
+            assert_eq!(&location(&response), canonical);
+            let response = test.send(request("GET", canonical, None)).await;
+            assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        }
+
+        // A client following the 308 repeats the method and body, which the route accepts as is.
+        let shop = json!({ "name": "Redirected Shop" });
+        for path in &["/v1/shops/", "/V1/shops"] {
+            let response = test
+                .send(json_request("POST", path, Some(OWNER_API_KEY), &shop))
+                .await;
+            assert_eq!(location(&response), "/v1/shops");
+        }
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &shop,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["name"], "Redirected Shop");
+        // Redirecting didn't create anything by itself. (The unfiltered list may still be cached.)
+        let response = test.send(request("GET", "/v1/shops?limit=50", None)).await;
+        assert_eq!(json_body(&response).as_array().unwrap().len(), 1);
+
+        // Other versions are not ours to normalize.
+        let response = test.send(request("GET", "/V2/shops", None)).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn activity_moves_last_activity_at_and_stale_shops_are_filtered_out() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Busy Shop", "gold": 100 }))
+            .await;
+        let stale_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Stale Shop" }))
+            .await;
+        let long_ago = NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0);
+        let db = &test.env.db;
+        let set_last_activity = |id: i64| async move {
+            sqlx::query("UPDATE shops SET last_activity_at = $2 WHERE id = $1")
+                .bind(id as i32)
+                .bind(long_ago)
+                .execute(db)
+                .await
+                .unwrap();
+        };
+        let last_activity = |id: i64| async move {
+            sqlx::query_scalar::<_, NaiveDateTime>(
+                "SELECT last_activity_at FROM shops WHERE id = $1",
+            )
+            .bind(id as i32)
+            .fetch_one(db)
+            .await
+            .unwrap()
+        };
+
+        let activities = vec![
+            json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/interior_ref_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "ref_list": [], "shelves": [] }),
+            ),
+            json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "form_list": [] }),
+            ),
+            json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 7,
+                    "name": "Iron Ingot",
+                    "form_kind": 32,
+                    "is_food": false,
+                    "price": 10,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 10,
+                    "keywords": [],
+                }),
+            ),
+        ];
+        for activity in activities {
+            set_last_activity(shop_id).await;
+            let response = test.send(activity).await;
+            assert!(response.status().is_success(), "{:?}", response);
+            assert!(last_activity(shop_id).await > long_ago);
+        }
+        // Renaming a shop isn't activity.
+        set_last_activity(stale_shop_id).await;
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", stale_shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Still Stale Shop" }),
+            ))
+            .await;
+        assert!(response.status().is_success(), "{:?}", response);
+        assert_eq!(last_activity(stale_shop_id).await, long_ago);
+
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/shops?active_since=2020-01-01T00:00:00",
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let shops = json_body(&response);
+        assert_eq!(shops.as_array().unwrap().len(), 1);
+        assert_eq!(shops[0]["id"], shop_id);
+        assert!(shops[0]["last_activity_at"].is_string());
+
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/shops?active_since=1999-01-01T00:00:00&order_by=last_activity_at&order=asc",
+                None,
+            ))
+            .await;
+        let shops = json_body(&response);
+        assert_eq!(shops[0]["id"], stale_shop_id);
+        assert_eq!(shops[1]["id"], shop_id);
+    }
+
+    #[tokio::test]
+    async fn pages_of_shops_neither_overlap_nor_skip() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let mut ids = vec![];
+        for index in 0..5 {
+            ids.push(
+                test.create_shop(OWNER_API_KEY, &json!({ "name": format!("Shop {}", index) }))
+                    .await,
+            );
+        }
+        // Ties on the default sort column, and on `gold` below.
+        sqlx::query("UPDATE shops SET updated_at = '2020-01-01'")
+            .execute(&test.env.db)
+            .await
+            .unwrap();
+
+        for query in &["", "order_by=gold&", "order_by=gold&order=desc&"] {
+            let mut paged = vec![];
+            for offset in &[0, 2, 4] {
+                let response = test
+                    .send(request(
+                        "GET",
+                        &format!("/v1/shops?{}limit=2&offset={}", query, offset),
+                        None,
+                    ))
+                    .await;
+                assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+                for shop in json_body(&response).as_array().unwrap() {
+                    paged.push(shop["id"].as_i64().unwrap());
+                }
+            }
+            let mut sorted = paged.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, ids, "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn tags_match_any_of_the_filter_and_keep_to_the_vocabulary() {
+        let test = match TestEnv::with_config(&[("SHOP_TAG_VOCABULARY", "alchemy,blacksmith,misc")])
+            .await
+        {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let potions_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Potions", "tags": ["Alchemy"] }),
+            )
+            .await;
+        let forge_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Forge", "tags": ["blacksmith", "misc"] }),
+            )
+            .await;
+        let junk_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Junk", "tags": ["misc"] }))
+            .await;
+        test.create_shop(OWNER_API_KEY, &json!({ "name": "Untagged" }))
+            .await;
+        let ids = |response| -> Vec<i64> {
+            let mut ids: Vec<i64> = json_body(&response)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|shop| shop["id"].as_i64().unwrap())
+                .collect();
+            ids.sort_unstable();
+            ids
+        };
+
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", potions_id), None))
+            .await;
+        assert_eq!(json_body(&response)["tags"], json!(["alchemy"]));
+
+        // Several tags are ORed: a shop with any one of them matches.
+        let response = test
+            .send(request("GET", "/v1/shops?tag=Alchemy,blacksmith", None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(ids(response), vec![potions_id, forge_id]);
+        let response = test.send(request("GET", "/v1/shops?tag=misc", None)).await;
+        assert_eq!(ids(response), vec![forge_id, junk_id]);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/shops",
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Rings", "tags": ["misc", "jewelry"] }),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors = problem["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["field"], "tags");
+        assert_eq!(errors[0]["index"], 1);
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", junk_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Junk", "tags": ["jewelry"] }),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Retagging evicts the cached filtered lists.
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}", junk_id),
+                Some(OWNER_API_KEY),
+                &json!({ "name": "Junk", "tags": ["alchemy"] }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test.send(request("GET", "/v1/shops?tag=misc", None)).await;
+        assert_eq!(ids(response), vec![forge_id]);
+        let response = test
+            .send(request("GET", "/v1/shops?tag=Alchemy,blacksmith", None))
+            .await;
+        assert_eq!(ids(response), vec![potions_id, forge_id, junk_id]);
+    }
+
+    #[tokio::test]
+    async fn notification_settings_default_on_and_update_partially() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/notification_settings", shop_id);
+        let patch = |api_key, body: Value| json_request("PATCH", &path, Some(api_key), &body);
+
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            json_body(&response),
+            json!({ "on_sale": true, "on_out_of_stock": true, "on_low_stock_threshold": null })
+        );
+        let response = test
+            .send(request("GET", &path, Some(OTHER_OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test
+            .send(patch(OTHER_OWNER_API_KEY, json!({ "on_sale": false })))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+
+        for threshold in &[json!(-1), json!(i64::from(i32::MAX) + 1)] {
+            let response = test
+                .send(patch(
+                    OWNER_API_KEY,
+                    json!({ "on_low_stock_threshold": threshold }),
+                ))
+                .await;
+            let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(problem["errors"][0]["field"], "on_low_stock_threshold");
+        }
+
+        let response = test
+            .send(patch(
+                OWNER_API_KEY,
+                json!({ "on_sale": false, "on_low_stock_threshold": 0 }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(
+            json_body(&response),
+            json!({ "on_sale": false, "on_out_of_stock": true, "on_low_stock_threshold": 0 })
+        );
+
+        // Omitted fields are kept, and an explicit null turns low stock notifications off.
+        let response = test
+            .send(patch(OWNER_API_KEY, json!({ "on_out_of_stock": false })))
+            .await;
+        assert_eq!(json_body(&response)["on_low_stock_threshold"], 0);
+        assert_eq!(json_body(&response)["on_sale"], false);
+        let response = test
+            .send(patch(
+                OWNER_API_KEY,
+                json!({ "on_low_stock_threshold": null }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        assert_eq!(
+            json_body(&response),
+            json!({ "on_sale": false, "on_out_of_stock": false, "on_low_stock_threshold": null })
+        );
+    }
+
+    #[tokio::test]
+    async fn notification_settings_writes_honor_if_match() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let path = format!("/v1/shops/{}/notification_settings", shop_id);
+        let shop_path = format!("/v1/shops/{}", shop_id);
+        let patch = |body: Value| json_request("PATCH", &path, Some(OWNER_API_KEY), &body);
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        let settings_etag = response.headers()["etag"].clone();
+        let response = test
+            .send(request("GET", &shop_path, Some(OWNER_API_KEY)))
+            .await;
+        let shop_etag = response.headers()["etag"].clone();
+        let response = test.send(request("GET", &shop_path, None)).await;
+        let public_etag = response.headers()["etag"].clone();
+
+        // The public view of the shop doesn't show the settings, so its ETag can't vouch for them.
+        let response = test
+            .send(patch(json!({ "on_sale": false })).header("if-match", public_etag.clone()))
+            .await;
+        assert_problem(&response, StatusCode::PRECONDITION_FAILED);
+        let response = test
+            .send(patch(json!({ "on_sale": false })).header("if-match", settings_etag.clone()))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_ne!(response.headers()["etag"], settings_etag);
+        for stale_etag in &[settings_etag, shop_etag] {
+            let response = test
+                .send(
+                    patch(json!({ "on_out_of_stock": false }))
+                        .header("if-match", stale_etag.clone()),
+                )
+                .await;
+            assert_problem(&response, StatusCode::PRECONDITION_FAILED);
+        }
+
+        // The owner's view of the shop is evicted, so it shows the new settings and its new ETag
+        // is current.
+        let response = test
+            .send(request("GET", &shop_path, Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(
+            json_body(&response)["notification_settings"]["on_sale"],
+            false
+        );
+        let shop_etag = response.headers()["etag"].clone();
+        let response = test
+            .send(patch(json!({ "on_out_of_stock": false })).header("if-match", shop_etag))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let response = test.send(request("GET", &shop_path, None)).await;
+        assert_eq!(response.headers()["etag"], public_etag);
+
+        // Writes without If-Match are serialized on the shop, so neither change is lost.
+        let (first, second) = test
+            .send_concurrently(
+                patch(json!({ "on_sale": true })),
+                patch(json!({ "on_out_of_stock": true })),
+            )
+            .await;
+        assert_eq!(first.status(), StatusCode::OK, "{:?}", first);
+        assert_eq!(second.status(), StatusCode::OK, "{:?}", second);
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        assert_eq!(
+            json_body(&response),
+            json!({ "on_sale": true, "on_out_of_stock": true, "on_low_stock_threshold": null })
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_owner_sees_their_shops_self_view() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Mine" }))
+            .await;
+        test.create_shop(OTHER_OWNER_API_KEY, &json!({ "name": "Theirs" }))
+            .await;
+        let path = format!("/v1/shops/{}", shop_id);
+        let unknown_api_key = "44444444-4444-4444-4444-444444444444";
+
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let self_view = json_body(&response);
+        assert_eq!(self_view["name"], "Mine");
+        assert_eq!(self_view["notification_settings"]["on_sale"], true);
+        for api_key in &[None, Some(OTHER_OWNER_API_KEY)] {
+            let response = test.send(request("GET", &path, *api_key)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let public_view = json_body(&response);
+            assert_eq!(public_view["name"], "Mine");
+            assert!(
+                public_view.get("notification_settings").is_none(),
+                "{}",
+                public_view
+            );
+        }
+
+        // `me` is whoever the api key belongs to, and needs one.
+        for (api_key, name) in &[(OWNER_API_KEY, "Mine"), (OTHER_OWNER_API_KEY, "Theirs")] {
+            let response = test
+                .send(request("GET", "/v1/shops?owner_id=me", Some(api_key)))
+                .await;
+            let shops = json_body(&response);
+            assert_eq!(shops.as_array().map(Vec::len), Some(1));
+            assert_eq!(shops[0]["name"], *name);
+        }
+        let response = test
+            .send(request("GET", "/v1/shops?owner_id=me", None))
+            .await;
+        assert_problem(&response, StatusCode::UNAUTHORIZED);
+
+        // An unknown key is an error, not a fall back to the anonymous view.
+        for path in &[path.as_str(), "/v1/shops"] {
+            let response = test.send(request("GET", path, Some(unknown_api_key))).await;
+            assert_problem(&response, StatusCode::UNAUTHORIZED);
+        }
+    }
+
+    #[tokio::test]
+    async fn private_notes_bytes_only_reach_the_owner() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Other Owner").await;
+        let notes = "restock the frost salts";
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Test Shop", "private_notes": notes }),
+            )
+            .await;
+        let contains_notes = |body: &[u8]| {
+            body.windows(notes.len())
+                .any(|window| window == notes.as_bytes())
+        };
+
+        let shop_path = format!("/v1/shops/{}", shop_id);
+        let response = test
+            .send(request("GET", &shop_path, Some(OWNER_API_KEY)))
+            .await;
+        assert!(contains_notes(response.body()));
+
+        let public_paths = [
+            shop_path.clone(),
+            format!("/v1/shops/{}/card", shop_id),
+            "/v1/shops".to_string(),
+            format!("/v1/shops?owner_id={}", owner_id),
+            format!("/v1/owners/{}/shops", owner_id),
+        ];
+        for path in &public_paths {
+            for api_key in &[None, Some(OTHER_OWNER_API_KEY)] {
+                for accept in &["application/json", "application/octet-stream"] {
+                    let response = test
+                        .send(request("GET", path, *api_key).header("accept", *accept))
+                        .await;
+                    assert_eq!(response.status(), StatusCode::OK, "{} {:?}", path, response);
+                    assert!(
+                        !contains_notes(response.body()),
+                        "{} as {:?} ({})",
+                        path,
+                        api_key,
+                        accept
+                    );
+                }
+            }
+        }
+        // Lists never carry them, not even for the owner.
+        for path in &public_paths[2..] {
+            let response = test.send(request("GET", path, Some(OWNER_API_KEY))).await;
+            assert!(!contains_notes(response.body()), "{}", path);
+        }
+        // The owner's bincode has them like the owner's JSON does.
+        let response = test
+            .send(
+                request("GET", &shop_path, Some(OWNER_API_KEY))
+                    .header("accept", "application/octet-stream"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let owner_shop: OwnerShop = bincode::deserialize(response.body()).unwrap();
+        assert_eq!(owner_shop.private_notes.as_deref(), Some(notes));
+        assert!(owner_shop.notification_settings.on_sale);
+        let public_shop: PublicShop = bincode::deserialize(
+            test.send(
+                request("GET", &shop_path, None).header("accept", "application/octet-stream"),
+            )
+            .await
+            .body(),
+        )
+        .unwrap();
+        assert_eq!(public_shop.name, owner_shop.name);
+    }
+
+    #[tokio::test]
+    async fn created_shops_return_their_seeded_lists_ids_and_etags() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        for (name, accept) in &[
+            ("Json Shop", "application/json"),
+            ("Bincode Shop", "application/octet-stream"),
+        ] {
+            let response = test
+                .send(
+                    json_request(
+                        "POST",
+                        "/v1/shops",
+                        Some(OWNER_API_KEY),
+                        &json!({ "name": name }),
+                    )
+                    .header("accept", *accept),
+                )
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            let header = |name: &str| response.headers()[name].to_str().unwrap().to_string();
+            for (resource, shop_path) in &[
+                ("interior_ref_list", "interior_ref_lists"),
+                ("merchandise_list", "merchandise_lists"),
+            ] {
+                let id = header(&format!("x-{}-id", resource.replace('_', "-")));
+                let etag = header(&format!("x-{}-etag", resource.replace('_', "-")));
+                let path = format!("/v1/{}/{}", shop_path, id);
+                let list = test
+                    .send(request("GET", &path, None).header("accept", *accept))
+                    .await;
+                assert_eq!(list.status(), StatusCode::OK, "{:?}", list);
+                assert_eq!(list.headers()["etag"].to_str().unwrap(), etag);
+                let list = test
+                    .send(request("GET", &path, None).header("accept", "application/json"))
+                    .await;
+                let list = json_body(&list);
+                assert_eq!(list["id"].to_string(), id);
+                let shop_id = &list["shop_id"];
+                let shop = test
+                    .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+                    .await;
+                assert_eq!(json_body(&shop)["name"], *name);
+                let by_shop = test
+                    .send(request(
+                        "GET",
+                        &format!("/v1/shops/{}/{}", shop_id, resource),
+                        None,
+                    ))
+                    .await;
+                assert_eq!(json_body(&by_shop)["id"].to_string(), id);
+
+                let not_modified = test
+                    .send(
+                        request("GET", &path, None)
+                            .header("accept", *accept)
+                            .header("if-none-match", etag.as_str()),
+                    )
+                    .await;
+                assert_eq!(not_modified.status(), StatusCode::NOT_MODIFIED);
+            }
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::OnceLock;
+
+use anyhow::Result;
+use chrono::prelude::*;
+use hmac::{Hmac, Mac, NewMac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+use crate::models::{Shop, ShopDeletionPreview};
+use crate::problem::{deletion_confirmation_required, forbidden_permission, reject_anyhow};
+use crate::Environment;
+
+use super::authenticate;
+
+/// How long a deletion preview's confirmation token can be used to delete the shop.
+pub const CONFIRMATION_TOKEN_TTL_SECS: i64 = 600;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Tokens are signed with a key made up at startup, so a restart (or another instance behind a
+// load balancer) just means previewing the deletion again.
+static TOKEN_KEY: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// Query parameters for `DELETE /v1/shops/{id}`.
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteShopParams {
+    pub confirmation_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletionPreviewReply {
+    pub preview: ShopDeletionPreview,
+    /// Sent back as `?confirmation_token=` to delete the shop when `SHOP_DELETE_CONFIRMATION` is
+    /// on.
+    pub confirmation_token: String,
+    pub confirmation_expires_at: NaiveDateTime,
+}
+
+pub async fn preview(
+    id: i32,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let shop_owner_id = env
+        .caches
+        .owner_ids_by_shop_id
+        .get(id, || Shop::get_owner_id(&env.db, id))
+        .await
+        .map_err(reject_anyhow)?;
+    if shop_owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let preview = ShopDeletionPreview::get(&env.db, id)
+        .await
+        .map_err(reject_anyhow)?;
+    let expires_at = Utc::now() + chrono::Duration::seconds(CONFIRMATION_TOKEN_TTL_SECS);
+    Ok(warp::reply::json(&DeletionPreviewReply {
+        preview,
+        confirmation_token: confirmation_token(id, expires_at.timestamp()),
+        confirmation_expires_at: expires_at.naive_utc(),
+    }))
+}
+
+/// Fails unless `token` is a deletion preview token for the shop that hasn't expired by `now` (a
+/// unix timestamp).
+pub fn verify_confirmation_token(shop_id: i32, token: Option<&str>, now: i64) -> Result<()> {
+    let token = token.ok_or_else(|| {
+        deletion_confirmation_required(
+            "Deleting a shop requires the confirmation_token from its deletion preview",
+        )
+    })?;
+    let invalid = || {
+        deletion_confirmation_required(
+            "The confirmation_token is not from this shop's deletion preview",
+        )
+    };
+    let (expires_at, signature) = token.split_once('.').ok_or_else(invalid)?;
+    let expires_at: i64 = expires_at.parse().map_err(|_| invalid())?;
+    let signature = hex::decode(signature).map_err(|_| invalid())?;
+    token_mac(shop_id, expires_at)
+        .verify(&signature)
+        .map_err(|_| invalid())?;
+    if expires_at <= now {
+        return Err(deletion_confirmation_required(
+            "The confirmation_token has expired. Preview the deletion again for a new one",
+        ));
+    }
+    Ok(())
+}
+
+/// A token for deleting the shop until `expires_at` (a unix timestamp): the expiry and an HMAC of
+/// it with the shop id.
+pub fn confirmation_token(shop_id: i32, expires_at: i64) -> String {
+    let signature = token_mac(shop_id, expires_at).finalize().into_bytes();
+    format!("{}.{}", expires_at, hex::encode(signature))
+}
+
+fn token_mac(shop_id: i32, expires_at: i64) -> HmacSha256 {
+    let key = TOKEN_KEY.get_or_init(|| {
+        let mut key = [0; 32];
+        key[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+        key[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+        key
+    });
+    let mut mac = HmacSha256::new_varkey(key).expect("HMAC takes keys of any length");
+    mac.update(format!("{}:{}", shop_id, expires_at).as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use super::confirmation_token;
+    use crate::test_support::{
+        assert_problem, json_body, json_request, request, TestEnv, OTHER_OWNER_API_KEY,
+        OWNER_API_KEY,
+    };
+
+    #[tokio::test]
+    async fn preview_counts_what_deleting_the_shop_removes() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "form_list": [{
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "Cabbage",
+                    "quantity": 3,
+                    "form_type": 46,
+                    "is_food": true,
+                    "price": 4,
+                    "keywords": [],
+                }] }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test
+            .send(json_request(
+                "POST",
+                &format!("/v1/shops/{}/requests", shop_id),
+                Some(OTHER_OWNER_API_KEY),
+                &json!({
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 6,
+                    "name": "Leek",
+                    "quantity": 1,
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let path = format!("/v1/shops/{}/deletion_preview", shop_id);
+
+        let response = test
+            .send(request("GET", &path, Some(OTHER_OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test.send(request("GET", &path, Some(OWNER_API_KEY))).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let body = json_body(&response);
+        assert_eq!(
+            body["preview"],
+            json!({
+                "item_count": 1,
+                "stock_value": 12,
+                "transaction_count": 0,
+                "interior_ref_count": 0,
+                "open_request_count": 1,
+            })
+        );
+        assert!(body["confirmation_token"].is_string());
+    }
+
+    #[tokio::test]
+    async fn delete_requires_a_current_token_when_confirmation_is_on() {
+        let test = match TestEnv::with_config(&[("SHOP_DELETE_CONFIRMATION", "true")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let other_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Other Shop" }))
+            .await;
+        let delete = |token: &str| {
+            request(
+                "DELETE",
+                &format!("/v1/shops/{}?confirmation_token={}", shop_id, token),
+                Some(OWNER_API_KEY),
+            )
+        };
+
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::PRECONDITION_REQUIRED);
+        assert_eq!(problem["code"], "deletion_confirmation_required");
+        let expired = confirmation_token(shop_id as i32, Utc::now().timestamp() - 1);
+        let response = test.send(delete(&expired)).await;
+        assert_problem(&response, StatusCode::PRECONDITION_REQUIRED);
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/deletion_preview", other_shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        let other_token = json_body(&response)["confirmation_token"].clone();
+        let response = test.send(delete(other_token.as_str().unwrap())).await;
+        assert_problem(&response, StatusCode::PRECONDITION_REQUIRED);
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/deletion_preview", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        let token = json_body(&response)["confirmation_token"].clone();
+        let response = test.send(delete(token.as_str().unwrap())).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT, "{:?}", response);
+    }
+
+    #[tokio::test]
+    async fn delete_needs_no_token_when_confirmation_is_off() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT, "{:?}", response);
+    }
+}
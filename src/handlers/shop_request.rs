@@ -0,0 +1,347 @@
+use anyhow::{anyhow, Result};
+use futures::future::try_join;
+use http::StatusCode;
+use hyper::body::Bytes;
+use mime::Mime;
+use uuid::Uuid;
+use warp::reply::with_status;
+use warp::{Rejection, Reply};
+
+use crate::caches::CachedResponse;
+use crate::models::{
+    PostedShopRequest, PostedShopRequestStatus, Shop, ShopRequest, ShopRequestListQuery,
+};
+use crate::notifications;
+use crate::problem::{forbidden_permission, reject_anyhow};
+use crate::Environment;
+
+use super::{
+    authenticate, check_etag, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody,
+    ETagReply, Json, PageReply, TypedCache,
+};
+
+pub async fn list_by_shop_id(
+    shop_id: i32,
+    query: ShopRequestListQuery,
+    api_key: Option<Uuid>,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let shop_owner_id = env
+        .caches
+        .owner_ids_by_shop_id
+        .get(shop_id, || Shop::get_owner_id(&env.db, shop_id))
+        .await
+        .map_err(reject_anyhow)?;
+    if shop_owner_id != owner_id {
+        return Err(reject_anyhow(forbidden_permission()));
+    }
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<(i32, ShopRequestListQuery), CachedResponse>::pick_cache(
+        accept,
+        &env.caches.list_shop_requests_by_shop_id_bin,
+        &env.caches.list_shop_requests_by_shop_id,
+    );
+    let response = cache
+        .get_response((shop_id, query.clone()), || async {
+            let (requests, total) = try_join(
+                ShopRequest::list_by_shop_id(&env.db, shop_id, &query),
+                ShopRequest::count_by_shop_id(&env.db, shop_id, &query),
+            )
+            .await?;
+            list_body(&requests, total, &query, &content_type)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+/// The requesting owner's own requests, to any shop.
+pub async fn list_mine(
+    query: ShopRequestListQuery,
+    api_key: Option<Uuid>,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<(i32, ShopRequestListQuery), CachedResponse>::pick_cache(
+        accept,
+        &env.caches.list_shop_requests_by_owner_id_bin,
+        &env.caches.list_shop_requests_by_owner_id,
+    );
+    let response = cache
+        .get_response((owner_id, query.clone()), || async {
+            let (requests, total) = try_join(
+                ShopRequest::list_by_requester_owner_id(&env.db, owner_id, &query),
+                ShopRequest::count_by_requester_owner_id(&env.db, owner_id, &query),
+            )
+            .await?;
+            list_body(&requests, total, &query, &content_type)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+pub async fn create(
+    shop_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: request,
+        content_type,
+    } = DeserializedBody::<PostedShopRequest>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    request
+        .validate(&env.config.transaction_limits)
+        .map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let saved_request = ShopRequest::create(&mut tx, shop_id, owner_id, request)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    notifications::emit(vec![notifications::request_created_event(&saved_request)]);
+    let reply = request_body(&saved_request, content_type).map_err(reject_anyhow)?;
+    evict_shop_requests(&env, shop_id, &[owner_id]).await;
+    Ok(with_status(reply, StatusCode::CREATED))
+}
+
+pub async fn update_status(
+    id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: PostedShopRequestStatus { status },
+        content_type,
+    } = DeserializedBody::<PostedShopRequestStatus>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let saved_request = ShopRequest::update_status(&mut tx, owner_id, id, status)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reply = request_body(&saved_request, content_type).map_err(reject_anyhow)?;
+    evict_shop_requests(
+        &env,
+        saved_request.shop_id,
+        &[saved_request.requester_owner_id],
+    )
+    .await;
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+fn request_body(request: &ShopRequest, content_type: ContentType) -> Result<Box<dyn Reply>> {
+    Ok(match content_type {
+        ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(request)?),
+        ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(request)?),
+    })
+}
+
+fn list_body(
+    requests: &[ShopRequest],
+    total: i64,
+    query: &ShopRequestListQuery,
+    content_type: &ContentType,
+) -> Result<impl Reply> {
+    let reply: Box<dyn Reply> = match content_type {
+        ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(&requests)?),
+        ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&requests)?),
+    };
+    Ok(PageReply::new(
+        with_status(reply, StatusCode::OK),
+        total,
+        &query.pagination,
+    ))
+}
+
+/// Evicts the shop's request lists and those of the requesters whose requests changed.
+pub async fn evict_shop_requests(env: &Environment, shop_id: i32, requester_ids: &[i32]) {
+    env.caches
+        .list_shop_requests_by_shop_id
+        .delete_where(|(id, _)| *id == shop_id)
+        .await;
+    env.caches
+        .list_shop_requests_by_shop_id_bin
+        .delete_where(|(id, _)| *id == shop_id)
+        .await;
+    env.caches
+        .list_shop_requests_by_owner_id
+        .delete_where(|(id, _)| requester_ids.contains(id))
+        .await;
+    env.caches
+        .list_shop_requests_by_owner_id_bin
+        .delete_where(|(id, _)| requester_ids.contains(id))
+        .await;
+}
+
+/// Publishes the requests that a merchandise list save made fulfillable, and evicts the lists of
+/// every request whose flag changed.
+pub async fn publish_flagged_requests(env: &Environment, shop_id: i32, flagged: &[ShopRequest]) {
+    if flagged.is_empty() {
+        return;
+    }
+    notifications::emit(notifications::requests_fulfillable_events(flagged));
+    let requester_ids: Vec<i32> = flagged
+        .iter()
+        .map(|request| request.requester_owner_id)
+        .collect();
+    evict_shop_requests(env, shop_id, &requester_ids).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use crate::test_support::{
+        assert_problem, json_body, json_request, request, TestEnv, OTHER_OWNER_API_KEY,
+        OWNER_API_KEY,
+    };
+
+    #[tokio::test]
+    async fn requests_are_flagged_when_stocked_and_fulfilled_once() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let customer_id = test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let requests_path = format!("/v1/shops/{}/requests", shop_id);
+        let body = json!({
+            "mod_name": "Skyrim.esm",
+            "local_form_id": 5,
+            "name": "Cabbage",
+            "quantity": 3,
+        });
+
+        let response = test
+            .send(json_request(
+                "POST",
+                &requests_path,
+                Some(OWNER_API_KEY),
+                &body,
+            ))
+            .await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        let response = test
+            .send(json_request(
+                "POST",
+                &requests_path,
+                Some(OTHER_OWNER_API_KEY),
+                &body,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let shop_request = json_body(&response);
+        assert_eq!(shop_request["requester_owner_id"], customer_id);
+        assert_eq!(shop_request["status"], "open");
+        assert_eq!(shop_request["fulfillable"], false);
+        let request_path = format!("/v1/shop_requests/{}", shop_request["id"]);
+
+        // Only the shop's owner sees the shop's requests, and only the requester their own.
+        let response = test
+            .send(request("GET", &requests_path, Some(OTHER_OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test
+            .send(request("GET", &requests_path, Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(json_body(&response)[0]["fulfillable"], false);
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/owners/me/requests",
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(json_body(&response).as_array().unwrap().len(), 1);
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                Some(OWNER_API_KEY),
+                &json!({ "shop_id": shop_id, "form_list": [{
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "Cabbage",
+                    "quantity": 3,
+                    "form_type": 46,
+                    "is_food": true,
+                    "price": 1,
+                    "keywords": [],
+                }] }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test
+            .send(request("GET", &requests_path, Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(json_body(&response)[0]["fulfillable"], true);
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/owners/me/requests?status=open",
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(json_body(&response)[0]["fulfillable"], true);
+
+        let transition = |api_key, status| {
+            json_request(
+                "PATCH",
+                &request_path,
+                Some(api_key),
+                &json!({ "status": status }),
+            )
+        };
+        let response = test
+            .send(transition(OTHER_OWNER_API_KEY, "fulfilled"))
+            .await;
+        assert_problem(&response, StatusCode::FORBIDDEN);
+        let response = test.send(transition(OWNER_API_KEY, "fulfilled")).await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(json_body(&response)["status"], "fulfilled");
+        assert_eq!(json_body(&response)["fulfillable"], false);
+        let response = test.send(transition(OWNER_API_KEY, "open")).await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let response = test
+            .send(request(
+                "GET",
+                "/v1/owners/me/requests?status=open",
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(json_body(&response), json!([]));
+    }
+}
@@ -0,0 +1,325 @@
+use anyhow::{anyhow, Result};
+use futures::future::try_join;
+use http::StatusCode;
+use hyper::body::Bytes;
+use mime::Mime;
+use uuid::Uuid;
+use warp::reply::with_status;
+use warp::{Rejection, Reply};
+
+use crate::caches::{CachedResponse, InvalidationEvent};
+use crate::models::{PostedShopReview, Shop, ShopReview, ShopReviewListQuery};
+use crate::problem::reject_anyhow;
+use crate::Environment;
+
+use super::{
+    authenticate, check_etag, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody,
+    ETagReply, Json, PageReply, TypedCache,
+};
+
+pub async fn list_by_shop_id(
+    shop_id: i32,
+    query: ShopReviewListQuery,
+    etag: Option<String>,
+    accept: Option<AcceptHeader>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let TypedCache {
+        content_type,
+        cache,
+    } = TypedCache::<(i32, ShopReviewListQuery), CachedResponse>::pick_cache(
+        accept,
+        &env.caches.list_shop_reviews_by_shop_id_bin,
+        &env.caches.list_shop_reviews_by_shop_id,
+    );
+    let response = cache
+        .get_response((shop_id, query.clone()), || async {
+            // 404 for shops that do not exist, as opposed to an empty list for unreviewed shops
+            Shop::get_owner_id(&env.db, shop_id).await?;
+            let (reviews, total) = try_join(
+                ShopReview::list_by_shop_id(&env.db, shop_id, &query),
+                ShopReview::count_by_shop_id(&env.db, shop_id),
+            )
+            .await?;
+            let reply: Box<dyn Reply> = match content_type {
+                ContentType::Bincode => {
+                    Box::new(ETagReply::<Bincode>::from_serializable(&reviews)?)
+                }
+                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&reviews)?),
+            };
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
+            Ok(reply)
+        })
+        .await?;
+    Ok(check_etag(etag, response))
+}
+
+pub async fn create(
+    shop_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: review,
+        content_type,
+    } = DeserializedBody::<PostedShopReview>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    review.validate(true).map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let saved_review = ShopReview::create(&mut tx, shop_id, owner_id, review)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reply = review_body(&saved_review, content_type).map_err(reject_anyhow)?;
+    evict_reviewed_shops(&env, &[shop_id]).await;
+    Ok(with_status(reply, StatusCode::CREATED))
+}
+
+pub async fn update(
+    shop_id: i32,
+    bytes: Bytes,
+    api_key: Option<Uuid>,
+    content_type: Option<Mime>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let DeserializedBody {
+        body: review,
+        content_type,
+    } = DeserializedBody::<PostedShopReview>::from_bytes(bytes, content_type)
+        .map_err(reject_anyhow)?;
+    review.validate(false).map_err(reject_anyhow)?;
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let saved_review = ShopReview::update(&mut tx, shop_id, owner_id, review)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let reply = review_body(&saved_review, content_type).map_err(reject_anyhow)?;
+    evict_reviewed_shops(&env, &[shop_id]).await;
+    Ok(with_status(reply, StatusCode::OK))
+}
+
+pub async fn delete(
+    shop_id: i32,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
+    let mut tx = env
+        .db
+        .begin()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    ShopReview::delete(&mut tx, shop_id, owner_id)
+        .await
+        .map_err(reject_anyhow)?;
+    tx.commit()
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    evict_reviewed_shops(&env, &[shop_id]).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn review_body(review: &ShopReview, content_type: ContentType) -> Result<Box<dyn Reply>> {
+    Ok(match content_type {
+        ContentType::Bincode => Box::new(ETagReply::<Bincode>::from_serializable(review)?),
+        ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(review)?),
+    })
+}
+
+/// Evicts the cached shops, whose `review_count` and `average_rating` changed, and their review
+/// lists. Lists of shops are left to the invalidation worker, which clears them once however many
+/// shops there were.
+pub async fn evict_reviewed_shops(env: &Environment, shop_ids: &[i32]) {
+    for &shop_id in shop_ids {
+        env.caches
+            .invalidate(InvalidationEvent::ShopReviewed { shop_id })
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use warp::http::StatusCode;
+    use warp::test::RequestBuilder;
+
+    use crate::test_support::{
+        assert_problem, json_body, json_request, request, TestEnv, OTHER_OWNER_API_KEY,
+        OWNER_API_KEY,
+    };
+
+    // The customer sells the shop a cabbage, which is enough to be allowed to review it.
+    fn sell_cabbage(shop_id: i64) -> RequestBuilder {
+        json_request(
+            "POST",
+            "/v1/transactions",
+            Some(OTHER_OWNER_API_KEY),
+            &json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 5,
+                "name": "Cabbage",
+                "form_type": 46,
+                "is_food": true,
+                "price": 1,
+                "is_sell": true,
+                "quantity": 1,
+                "amount": 1,
+                "keywords": [],
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn only_customers_can_review_once_each() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let customer_id = test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let shop_path = format!("/v1/shops/{}", shop_id);
+        let reviews_path = format!("{}/reviews", shop_path);
+        let review = |api_key, body| json_request("POST", &reviews_path, Some(api_key), &body);
+
+        // No transactions at the shop yet.
+        let response = test
+            .send(review(OTHER_OWNER_API_KEY, json!({ "rating": 4 })))
+            .await;
+        let problem = assert_problem(&response, StatusCode::FORBIDDEN);
+        assert_eq!(problem["code"], "review_requires_purchase");
+
+        let response = test.send(sell_cabbage(shop_id)).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        let response = test
+            .send(review(OTHER_OWNER_API_KEY, json!({ "rating": 6 })))
+            .await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        let response = test
+            .send(review(
+                OTHER_OWNER_API_KEY,
+                json!({ "rating": 4, "comment": "Fresh cabbages" }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let saved_review = json_body(&response);
+        assert_eq!(saved_review["reviewer_owner_id"], customer_id);
+        assert_eq!(saved_review["rating"], 4);
+        // Owners can't review their own shop.
+        let response = test
+            .send(review(OWNER_API_KEY, json!({ "rating": 5 })))
+            .await;
+        assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Fetched now so that the edit below has to evict the cached shop.
+        let response = test.send(request("GET", &shop_path, None)).await;
+        assert_eq!(json_body(&response)["review_count"], 1);
+        assert_eq!(json_body(&response)["average_rating"], 4.0);
+
+        let response = test
+            .send(review(OTHER_OWNER_API_KEY, json!({ "rating": 1 })))
+            .await;
+        let problem = assert_problem(&response, StatusCode::CONFLICT);
+        assert_eq!(problem["code"], "review_exists");
+
+        let response = test
+            .send(json_request(
+                "PATCH",
+                &reviews_path,
+                Some(OTHER_OWNER_API_KEY),
+                &json!({ "rating": 2 }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        assert_eq!(json_body(&response)["comment"], "Fresh cabbages");
+        let response = test.send(request("GET", &shop_path, None)).await;
+        assert_eq!(json_body(&response)["review_count"], 1);
+        assert_eq!(json_body(&response)["average_rating"], 2.0);
+        let response = test.send(request("GET", &reviews_path, None)).await;
+        let reviews = json_body(&response);
+        assert_eq!(reviews.as_array().unwrap().len(), 1);
+        assert_eq!(reviews[0]["rating"], 2);
+
+        let response = test
+            .send(request("DELETE", &reviews_path, Some(OTHER_OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(request("DELETE", &reviews_path, Some(OTHER_OWNER_API_KEY)))
+            .await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+        let response = test.send(request("GET", &shop_path, None)).await;
+        assert_eq!(json_body(&response)["review_count"], 0);
+        assert!(json_body(&response)["average_rating"].is_null());
+        let response = test.send(request("GET", &reviews_path, None)).await;
+        assert_eq!(json_body(&response), json!([]));
+    }
+
+    #[tokio::test]
+    async fn deleting_the_reviewer_updates_the_rating() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let customer_id = test.create_owner(OTHER_OWNER_API_KEY, "Customer").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let shop_path = format!("/v1/shops/{}", shop_id);
+        let response = test.send(sell_cabbage(shop_id)).await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let transaction_id = json_body(&response)["transaction"]["id"].as_i64().unwrap();
+        let response = test
+            .send(json_request(
+                "POST",
+                &format!("{}/reviews", shop_path),
+                Some(OTHER_OWNER_API_KEY),
+                &json!({ "rating": 3 }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        // Owners with transactions can't be deleted, but their reviews outlive the transactions.
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/transactions/{}", transaction_id),
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/owners/{}", customer_id),
+                Some(OTHER_OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test.send(request("GET", &shop_path, None)).await;
+        assert_eq!(json_body(&response)["review_count"], 0);
+        assert!(json_body(&response)["average_rating"].is_null());
+    }
+}
@@ -0,0 +1,271 @@
+use http::header::SERVER;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use warp::reply::{json, with_header, with_status};
+use warp::{Rejection, Reply};
+
+use crate::anomalies::{self, AnomalyCount};
+use crate::caches::{CachesStats, InvalidationStats};
+use crate::config::ConnectionsSnapshot;
+use crate::maintenance::MaintenanceMode;
+use crate::metrics::queries::{self, QueriesSnapshot};
+use crate::metrics::MetricsSnapshot;
+use crate::problem::reject_anyhow;
+use crate::schema::SchemaStatus;
+use crate::tasks::TaskClassStats;
+use crate::Environment;
+
+use super::{authenticate_admin, SERVER_STRING};
+
+#[derive(Debug, Serialize)]
+struct Status<'a> {
+    status: &'static str,
+    migration_version: Option<i64>,
+    maintenance_mode: MaintenanceMode,
+    schema: &'a SchemaStatus,
+    connections: ConnectionsSnapshot,
+}
+
+pub async fn get(env: Environment) -> Result<impl Reply, Rejection> {
+    let schema = env.schema_status.as_ref();
+    let (status, code) = if schema.ok {
+        ("ok", StatusCode::OK)
+    } else {
+        ("degraded", StatusCode::SERVICE_UNAVAILABLE)
+    };
+    let reply = json(&Status {
+        status,
+        migration_version: schema.migration_version,
+        maintenance_mode: env.maintenance.mode().await,
+        schema,
+        connections: env.config.connections.snapshot(),
+    });
+    let reply = with_header(reply, SERVER, SERVER_STRING);
+    let reply = with_status(reply, code);
+    Ok(reply)
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct MetricsParams {
+    /// Clears the recorded metrics after taking the snapshot, so that the next read only covers
+    /// requests made since this one.
+    pub reset: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+struct MetricsReply {
+    #[serde(flatten)]
+    requests: MetricsSnapshot,
+    invalidation_queue: InvalidationStats,
+    caches: CachesStats,
+    anomalies: Vec<AnomalyCount>,
+    queries: QueriesSnapshot,
+    /// Background tasks by class, since startup. Not reset with the rest.
+    tasks: Vec<TaskClassStats>,
+}
+
+/// Every cache's hits, misses, inserts and evictions since startup, with how full it is now.
+pub async fn cache_stats(api_key: Option<Uuid>, env: Environment) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let reply = json(&env.caches.stats_by_name());
+    let reply = with_header(reply, SERVER, SERVER_STRING);
+    Ok(reply)
+}
+
+pub async fn metrics(
+    params: MetricsParams,
+    api_key: Option<Uuid>,
+    env: Environment,
+) -> Result<impl Reply, Rejection> {
+    authenticate_admin(&env, api_key).map_err(reject_anyhow)?;
+    let reset = params.reset.unwrap_or(false);
+    let reply = json(&MetricsReply {
+        requests: env.metrics.snapshot(reset),
+        invalidation_queue: env.caches.invalidations.stats(),
+        caches: env.caches.stats(),
+        anomalies: anomalies::counts(),
+        queries: queries::snapshot(reset),
+        tasks: env.tasks.stats(),
+    });
+    let reply = with_header(reply, SERVER, SERVER_STRING);
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use warp::http::StatusCode;
+
+    use crate::test_support::{json_body, request, TestEnv, OWNER_API_KEY};
+
+    const ADMIN_API_KEY: &str = "33333333-3333-3333-3333-333333333333";
+
+    fn query_count(metrics: &Value, model: &str, operation: &str) -> u64 {
+        metrics["queries"]["queries"]
+            .as_array()
+            .expect("queries")
+            .iter()
+            .find(|query| query["model"] == model && query["operation"] == operation)
+            .map_or(0, |query| query["count"].as_u64().expect("count"))
+    }
+
+    #[tokio::test]
+    async fn metrics_have_histograms_of_the_queries_endpoints_made() {
+        let test = match TestEnv::with_config(&[("ADMIN_API_KEYS", ADMIN_API_KEY)]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+
+        let response = test
+            .send(request("GET", "/v1/status/metrics", Some(ADMIN_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let metrics = json_body(&response);
+        // Other tests share the histograms, so these can be higher than one.
+        assert!(query_count(&metrics, "owner", "create") >= 1);
+        assert!(query_count(&metrics, "shop", "create") >= 1);
+        assert!(query_count(&metrics, "shop", "get") >= 1);
+        assert_eq!(query_count(&metrics, "shop", "no_such_operation"), 0);
+
+        // The miss on the shop was cached by a background task, which may still be running.
+        let cache_fill = metrics["tasks"]
+            .as_array()
+            .expect("tasks")
+            .iter()
+            .find(|tasks| tasks["class"] == "cache_fill")
+            .expect("cache_fill tasks");
+        let spawned = cache_fill["active"].as_u64().expect("active")
+            + cache_fill["completed"].as_u64().expect("completed");
+        assert!(spawned >= 1);
+        assert_eq!(cache_fill["panicked"], 0);
+    }
+
+    #[tokio::test]
+    async fn metrics_count_routes_and_order_their_percentiles() {
+        let test = match TestEnv::with_config(&[("ADMIN_API_KEYS", ADMIN_API_KEY)]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        for _ in 0..200 {
+            test.send_recorded(request("GET", &format!("/v1/shops/{}", shop_id), None))
+                .await;
+        }
+        for missing_id in 0..100 {
+            test.send_recorded(request(
+                "GET",
+                &format!("/v1/shops/{}", shop_id + 1 + missing_id),
+                None,
+            ))
+            .await;
+        }
+        let response = test
+            .send_recorded(request(
+                "PATCH",
+                &format!("/v1/shops/{}", shop_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert!(response.status().is_client_error());
+
+        let response = test
+            .send_recorded(request("GET", "/v1/status/metrics", Some(OWNER_API_KEY)))
+            .await;
+        assert!(response.status().is_client_error());
+        let response = test
+            .send_recorded(request(
+                "GET",
+                "/v1/status/metrics?reset=true",
+                Some(ADMIN_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let metrics = json_body(&response);
+        let routes = metrics["routes"].as_array().expect("routes");
+        let shop = routes
+            .iter()
+            .find(|route| route["method"] == "GET" && route["route"] == "/v1/shops/{id}")
+            .expect("GET /v1/shops/{id}");
+        assert_eq!(shop["kind"], "read");
+        assert_eq!(shop["count"], 300);
+        assert_eq!(shop["client_errors"], 100);
+        assert_eq!(shop["server_errors"], 0);
+        let percentiles: Vec<f64> = ["p50_ms", "p95_ms", "p99_ms", "max_ms"]
+            .iter()
+            .map(|percentile| shop[percentile].as_f64().expect(percentile))
+            .collect();
+        assert!(percentiles[0] > 0.0);
+        assert!(
+            percentiles.windows(2).all(|pair| pair[0] <= pair[1]),
+            "{:?}",
+            percentiles
+        );
+        assert_eq!(metrics["write"]["count"], 1);
+        assert_eq!(metrics["write"]["client_errors"], 1);
+        assert!(metrics["read"]["count"].as_u64().unwrap() >= 301);
+
+        // The reset left only the requests made since.
+        let response = test
+            .send(request("GET", "/v1/status/metrics", Some(ADMIN_API_KEY)))
+            .await;
+        let metrics = json_body(&response);
+        let routes = metrics["routes"].as_array().expect("routes");
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0]["route"], "/v1/status/metrics");
+        assert_eq!(routes[0]["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn cache_stats_are_keyed_by_name_and_admin_only() {
+        let test = match TestEnv::with_config(&[("ADMIN_API_KEYS", ADMIN_API_KEY)]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await;
+        for _ in 0..2 {
+            let response = test
+                .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+                .await;
+            assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        }
+
+        let response = test
+            .send(request("GET", "/v1/cache/stats", Some(OWNER_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN, "{:?}", response);
+        let response = test.send(request("GET", "/v1/cache/stats", None)).await;
+        assert_eq!(
+            response.status(),
+            StatusCode::UNAUTHORIZED,
+            "{:?}",
+            response
+        );
+
+        let response = test
+            .send(request("GET", "/v1/cache/stats", Some(ADMIN_API_KEY)))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+        let stats = json_body(&response);
+        let shop = &stats["shop"];
+        assert_eq!(shop["capacity"], 100);
+        // The first read missed and the second may have too, if it beat the fill.
+        assert!(shop["misses"].as_u64().expect("misses") >= 1);
+        assert!(shop["hits"].as_u64().expect("hits") + shop["misses"].as_u64().unwrap() >= 2);
+        assert_eq!(stats["owner_ids_by_api_key"]["evictions"], 0);
+    }
+}
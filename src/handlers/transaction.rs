@@ -1,20 +1,27 @@
 use anyhow::{anyhow, Result};
+use futures::future::try_join;
 use http::StatusCode;
 use http_api_problem::HttpApiProblem;
 use hyper::body::Bytes;
 use mime::Mime;
+use serde::Serialize;
 use uuid::Uuid;
 use warp::reply::{with_header, with_status};
 use warp::{reject, Rejection, Reply};
 
-use crate::caches::{CachedResponse, CACHES};
-use crate::models::{ListParams, MerchandiseList, PostedTransaction, Shop, Transaction};
-use crate::problem::reject_anyhow;
+use crate::caches::{CachedResponse, InvalidationEvent};
+use crate::models::{
+    LegacyPostedTransaction, Merchandise, MerchandiseList, NotificationSettings, PostedTransaction,
+    Shop, Transaction, TransactionListQuery,
+};
+use crate::notifications;
+use crate::problem::{reject_anyhow, unprocessable_entity, ValidationError};
 use crate::Environment;
 
+use super::links::WithLinks;
 use super::{
-    authenticate, check_etag, AcceptHeader, Bincode, ContentType, DataReply, DeserializedBody,
-    ETagReply, Json, TypedCache,
+    authenticate, check_etag, check_etag_index, AcceptHeader, Bincode, ContentType, DataReply,
+    DeserializedBody, ETagReply, Json, MinimalReply, PageReply, PreferHeader, TypedCache,
 };
 
 pub async fn get(
@@ -28,9 +35,12 @@ pub async fn get(
         cache,
     } = TypedCache::<i32, CachedResponse>::pick_cache(
         accept,
-        &CACHES.transaction_bin,
-        &CACHES.transaction,
+        &env.caches.transaction_bin,
+        &env.caches.transaction,
     );
+    if let Some(response) = check_etag_index(&etag, cache, &id).await {
+        return Ok(response);
+    }
     let response = cache
         .get_response(id, || async {
             let transaction = Transaction::get(&env.db, id).await?;
@@ -38,7 +48,10 @@ pub async fn get(
                 ContentType::Bincode => {
                     Box::new(ETagReply::<Bincode>::from_serializable(&transaction)?)
                 }
-                ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&transaction)?),
+                ContentType::Json => Box::new(ETagReply::<Json>::from_resource(
+                    &transaction,
+                    &env.api_url,
+                )?),
             };
             let reply = with_status(reply, StatusCode::OK);
             Ok(reply)
@@ -48,7 +61,7 @@ pub async fn get(
 }
 
 pub async fn list(
-    list_params: ListParams,
+    query: TransactionListQuery,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
     env: Environment,
@@ -56,21 +69,26 @@ pub async fn list(
     let TypedCache {
         content_type,
         cache,
-    } = TypedCache::<ListParams, CachedResponse>::pick_cache(
+    } = TypedCache::<TransactionListQuery, CachedResponse>::pick_cache(
         accept,
-        &CACHES.list_transactions_bin,
-        &CACHES.list_transactions,
+        &env.caches.list_transactions_bin,
+        &env.caches.list_transactions,
     );
     let response = cache
-        .get_response(list_params.clone(), || async {
-            let transactions = Transaction::list(&env.db, &list_params).await?;
+        .get_response(query.clone(), || async {
+            let (transactions, total) = try_join(
+                Transaction::list(&env.db, &query),
+                Transaction::count(&env.db),
+            )
+            .await?;
             let reply: Box<dyn Reply> = match content_type {
                 ContentType::Bincode => {
                     Box::new(ETagReply::<Bincode>::from_serializable(&transactions)?)
                 }
                 ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&transactions)?),
             };
-            let reply = with_status(reply, StatusCode::OK);
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
             Ok(reply)
         })
         .await?;
@@ -79,7 +97,7 @@ pub async fn list(
 
 pub async fn list_by_shop_id(
     shop_id: i32,
-    list_params: ListParams,
+    query: TransactionListQuery,
     etag: Option<String>,
     accept: Option<AcceptHeader>,
     env: Environment,
@@ -87,37 +105,69 @@ pub async fn list_by_shop_id(
     let TypedCache {
         content_type,
         cache,
-    } = TypedCache::<(i32, ListParams), CachedResponse>::pick_cache(
+    } = TypedCache::<(i32, TransactionListQuery), CachedResponse>::pick_cache(
         accept,
-        &CACHES.list_transactions_by_shop_id_bin,
-        &CACHES.list_transactions_by_shop_id,
+        &env.caches.list_transactions_by_shop_id_bin,
+        &env.caches.list_transactions_by_shop_id,
     );
     let response = cache
-        .get_response((shop_id, list_params.clone()), || async {
-            let transactions = Transaction::list_by_shop_id(&env.db, shop_id, &list_params).await?;
+        .get_response((shop_id, query.clone()), || async {
+            let db = env.db.clone();
+            let params = query.clone();
+            let (transactions, total) = env
+                .in_flight
+                .list_transactions_by_shop_id
+                .run((shop_id, query.clone()), async move {
+                    try_join(
+                        Transaction::list_by_shop_id(&db, shop_id, &params),
+                        Transaction::count_by_shop_id(&db, shop_id),
+                    )
+                    .await
+                })
+                .await?;
             let reply: Box<dyn Reply> = match content_type {
                 ContentType::Bincode => {
                     Box::new(ETagReply::<Bincode>::from_serializable(&transactions)?)
                 }
                 ContentType::Json => Box::new(ETagReply::<Json>::from_serializable(&transactions)?),
             };
-            let reply = with_status(reply, StatusCode::OK);
+            let reply =
+                PageReply::new(with_status(reply, StatusCode::OK), total, &query.pagination);
             Ok(reply)
         })
         .await?;
     Ok(check_etag(etag, response))
 }
 
+// Response to creating a transaction. Includes the post-transaction state of the bought or sold
+// item so that clients do not need to re-fetch the whole merchandise list. The JSON response also
+// includes the shop's new gold; it is left out of bincode, whose layout clients depend on.
+#[derive(Debug, Serialize)]
+struct TransactionResult<'a, T: Serialize> {
+    transaction: &'a T,
+    merchandise: &'a Merchandise,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shop_gold: Option<i64>,
+}
+
 pub async fn create(
     bytes: Bytes,
     api_key: Option<Uuid>,
     content_type: Option<Mime>,
+    prefer: Option<PreferHeader>,
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
+    let return_minimal = prefer.is_some_and(|prefer| prefer.return_minimal);
     let DeserializedBody {
         body: mut transaction,
         content_type,
-    } = DeserializedBody::<PostedTransaction>::from_bytes(bytes, content_type)
+    } = DeserializedBody::<PostedTransaction>::from_bytes_or_legacy::<LegacyPostedTransaction>(
+        bytes,
+        content_type,
+    )
+    .map_err(reject_anyhow)?;
+    transaction
+        .validate(&env.config.transaction_limits)
         .map_err(reject_anyhow)?;
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
     transaction.owner_id = Some(owner_id);
@@ -126,6 +176,10 @@ pub async fn create(
         .begin()
         .await
         .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    Shop::lock_for_transaction(&mut tx, transaction.shop_id, owner_id)
+        .await
+        .map_err(reject_anyhow)?;
+    let icon_key = transaction.icon_key.clone();
     let saved_transaction = Transaction::create(transaction, &mut tx)
         .await
         .map_err(reject_anyhow)?;
@@ -143,9 +197,36 @@ pub async fn create(
                 .set_detail("Shop does not accept that kind of merchandise"),
         ));
     }
+    if saved_transaction.is_sell && env.config.economy.sell_price_percent.is_some() {
+        let merchandise_list = MerchandiseList::get_by_shop_id(&mut tx, saved_transaction.shop_id)
+            .await
+            .map_err(reject_anyhow)?;
+        let listed_price = merchandise_list
+            .find_merchandise(&saved_transaction.mod_name, saved_transaction.local_form_id)
+            .map(|merchandise| merchandise.price);
+        if let Some((listed_price, max_price)) = listed_price.and_then(|listed_price| {
+            env.config
+                .economy
+                .max_sell_price(listed_price)
+                .map(|max_price| (listed_price, max_price))
+        }) {
+            if saved_transaction.price > max_price {
+                return Err(reject_anyhow(unprocessable_entity(vec![
+                    ValidationError::new(
+                        "price",
+                        format!(
+                            "cannot sell for more than {} since the shop lists it for {}",
+                            max_price, listed_price
+                        ),
+                    ),
+                ])));
+            }
+        }
+    }
+    // `amount` is `price * quantity`, checked by `PostedTransaction::validate`.
     let (quantity_delta, shop_gold_delta) = match saved_transaction.is_sell {
-        true => (saved_transaction.quantity, saved_transaction.price * -1),
-        false => (saved_transaction.quantity * -1, saved_transaction.price),
+        true => (saved_transaction.quantity, -saved_transaction.amount),
+        false => (-saved_transaction.quantity, saved_transaction.amount),
     };
     let updated_merchandise_list = MerchandiseList::update_merchandise_quantity(
         &mut tx,
@@ -153,66 +234,88 @@ pub async fn create(
         &(saved_transaction.mod_name),
         saved_transaction.local_form_id,
         &(saved_transaction.name),
-        saved_transaction.form_type,
+        saved_transaction.form_kind,
         saved_transaction.is_food,
         saved_transaction.price,
         quantity_delta,
         &saved_transaction.keywords,
+        icon_key.as_deref(),
     )
     .await
     .map_err(reject_anyhow)?;
-    Shop::update_gold(&mut tx, saved_transaction.shop_id, shop_gold_delta)
+    let shop_gold = Shop::update_gold(&mut tx, saved_transaction.shop_id, shop_gold_delta)
         .await
         .map_err(reject_anyhow)?;
+    Shop::record_activity(&mut tx, saved_transaction.shop_id)
+        .await
+        .map_err(reject_anyhow)?;
+    let notification_settings =
+        NotificationSettings::get_by_shop_id(&mut tx, saved_transaction.shop_id)
+            .await
+            .map_err(reject_anyhow)?;
     tx.commit()
         .await
         .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    // Items bought down to zero are removed from the list, so report them with a quantity of 0
+    let merchandise = updated_merchandise_list
+        .find_merchandise(&saved_transaction.mod_name, saved_transaction.local_form_id)
+        .cloned()
+        .unwrap_or_else(|| Merchandise {
+            mod_name: saved_transaction.mod_name.clone(),
+            local_form_id: saved_transaction.local_form_id,
+            name: saved_transaction.name.clone(),
+            quantity: 0,
+            form_kind: saved_transaction.form_kind as u32,
+            is_food: saved_transaction.is_food,
+            price: saved_transaction.price,
+            keywords: saved_transaction.keywords.clone(),
+            icon_key,
+        });
+    notifications::emit(notifications::transaction_events(
+        &notification_settings,
+        &saved_transaction,
+        merchandise.quantity as i32,
+    ));
     let url = saved_transaction.url(&env.api_url).map_err(reject_anyhow)?;
     let reply: Box<dyn Reply> = match content_type {
+        _ if return_minimal => {
+            Box::new(MinimalReply::from_resource(&saved_transaction).map_err(reject_anyhow)?)
+        }
         ContentType::Bincode => Box::new(
-            ETagReply::<Bincode>::from_serializable(&saved_transaction).map_err(reject_anyhow)?,
+            ETagReply::<Bincode>::from_serializable(&TransactionResult {
+                transaction: &saved_transaction,
+                merchandise: &merchandise,
+                shop_gold: None,
+            })
+            .map_err(reject_anyhow)?,
         ),
         ContentType::Json => Box::new(
-            ETagReply::<Json>::from_serializable(&saved_transaction).map_err(reject_anyhow)?,
+            ETagReply::<Json>::from_serializable(&TransactionResult {
+                transaction: &saved_transaction
+                    .linked(&env.api_url)
+                    .map_err(reject_anyhow)?,
+                merchandise: &merchandise,
+                shop_gold: Some(shop_gold),
+            })
+            .and_then(|reply| {
+                reply.with_etag_of(&TransactionResult {
+                    transaction: &saved_transaction,
+                    merchandise: &merchandise,
+                    shop_gold: Some(shop_gold),
+                })
+            })
+            .map_err(reject_anyhow)?,
         ),
     };
     let reply = with_header(reply, "Location", url.as_str());
     let reply = with_status(reply, StatusCode::CREATED);
-    tokio::spawn(async move {
-        // TODO: will this make these caches effectively useless?
-        CACHES
-            .merchandise_list
-            .delete_response(updated_merchandise_list.id)
-            .await;
-        CACHES
-            .merchandise_list_bin
-            .delete_response(updated_merchandise_list.id)
-            .await;
-        CACHES
-            .merchandise_list_by_shop_id
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES
-            .merchandise_list_by_shop_id_bin
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES.list_transactions.clear().await;
-        CACHES.list_transactions_bin.clear().await;
-        CACHES.list_transactions_by_shop_id.clear().await;
-        CACHES.list_transactions_by_shop_id_bin.clear().await;
-        CACHES.list_merchandise_lists.clear().await;
-        CACHES.list_merchandise_lists_bin.clear().await;
-        CACHES
-            .shop
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES
-            .shop_bin
-            .delete_response(updated_merchandise_list.shop_id)
-            .await;
-        CACHES.list_shops.clear().await;
-        CACHES.list_shops_bin.clear().await;
-    });
+    env.caches
+        .invalidate(InvalidationEvent::TransactionCreated {
+            merchandise_list_id: updated_merchandise_list.id,
+            shop_id: updated_merchandise_list.shop_id,
+            owner_id: updated_merchandise_list.owner_id,
+        })
+        .await;
     Ok(reply)
 }
 
@@ -223,16 +326,1018 @@ pub async fn delete(
     env: Environment,
 ) -> Result<impl Reply, Rejection> {
     let owner_id = authenticate(&env, api_key).await.map_err(reject_anyhow)?;
-    Transaction::delete(&env.db, owner_id, id)
+    let shop_id = Transaction::delete(&env.db, owner_id, id)
         .await
         .map_err(reject_anyhow)?;
-    tokio::spawn(async move {
-        CACHES.transaction.delete_response(id).await;
-        CACHES.transaction_bin.delete_response(id).await;
-        CACHES.list_transactions.clear().await;
-        CACHES.list_transactions_bin.clear().await;
-        CACHES.list_transactions_by_shop_id.clear().await;
-        CACHES.list_transactions_by_shop_id_bin.clear().await;
-    });
+    env.caches
+        .invalidate(InvalidationEvent::TransactionDeleted {
+            transaction_id: id,
+            shop_id,
+        })
+        .await;
     Ok(StatusCode::NO_CONTENT)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use warp::http::StatusCode;
+
+    use crate::anomalies::{self, AnomalyCode};
+    use crate::models::{FormId, LegacyPostedTransaction, PostedTransaction, Shop};
+    use crate::test_support::{
+        assert_one_deleted, assert_problem, json_body, json_request, request, TestEnv,
+        OWNER_API_KEY,
+    };
+
+    #[tokio::test]
+    async fn transactions_round_trip_in_both_schema_versions() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let transaction = |form_field: &str| {
+            json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 5,
+                "name": "New Thing",
+                form_field: 41,
+                "is_food": false,
+                "price": 100,
+                "is_sell": true,
+                "quantity": 1,
+                "amount": 100,
+                "keywords": [],
+            })
+        };
+
+        let response = test
+            .send(
+                json_request(
+                    "POST",
+                    "/v1/transactions",
+                    Some(OWNER_API_KEY),
+                    &transaction("form_type"),
+                )
+                .header("x-schema-version", "2"),
+            )
+            .await;
+        assert_problem(&response, StatusCode::BAD_REQUEST);
+        let response = test
+            .send(
+                json_request(
+                    "POST",
+                    "/v1/transactions",
+                    Some(OWNER_API_KEY),
+                    &transaction("form_kind"),
+                )
+                .header("x-schema-version", "2"),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(response.headers()["x-schema-version"], "2");
+        let result = json_body(&response);
+        assert_eq!(result["transaction"]["form_kind"], 41);
+        assert_eq!(result["merchandise"]["form_kind"], 41);
+        let path = format!("/v1/transactions/{}", result["transaction"]["id"]);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction("form_type"),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(response.headers()["x-schema-version"], "1");
+        assert_eq!(json_body(&response)["transaction"]["form_type"], 41);
+
+        let response = test.send(request("GET", &path, None)).await;
+        let v1 = json_body(&response);
+        assert_eq!(v1["form_type"], 41);
+        assert!(v1.get("form_kind").is_none());
+        let v1_etag = response.headers()["etag"].to_str().unwrap().to_string();
+        let response = test
+            .send(request("GET", &path, None).header("x-schema-version", "2"))
+            .await;
+        let v2 = json_body(&response);
+        assert_eq!(v2["form_kind"], 41);
+        assert!(v2.get("form_type").is_none());
+        // Both versions are the same transaction, so they share its ETag.
+        assert_eq!(response.headers()["etag"], v1_etag.as_str());
+        let response = test
+            .send(request("GET", &path, None).header("if-none-match", v1_etag.as_str()))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn sells_add_items_with_their_icon_key_in_either_bincode_layout() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let legacy = LegacyPostedTransaction {
+            shop_id: shop_id as i32,
+            owner_id: None,
+            mod_name: "Skyrim.esm".to_string(),
+            local_form_id: FormId(1),
+            name: "Ruby".to_string(),
+            form_kind: 32,
+            is_food: false,
+            price: 10,
+            is_sell: true,
+            quantity: 1,
+            amount: 10,
+            keywords: vec![],
+        };
+        let current = PostedTransaction {
+            local_form_id: FormId(2),
+            name: "Sapphire".to_string(),
+            icon_key: Some("Clutter\\Gems\\Sapphire.nif".to_string()),
+            ..PostedTransaction::from(legacy.clone())
+        };
+        let sell = |body: Vec<u8>| {
+            request("POST", "/v1/transactions", Some(OWNER_API_KEY))
+                .header("content-type", "application/octet-stream")
+                .body(body)
+        };
+        for body in [
+            bincode::serialize(&legacy).unwrap(),
+            bincode::serialize(&current).unwrap(),
+        ] {
+            let response = test.send(sell(body)).await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        }
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                None,
+            ))
+            .await;
+        let form_list = json_body(&response)["form_list"].clone();
+        assert_eq!(form_list[0]["name"], "Ruby");
+        assert_eq!(form_list[0]["icon_key"], json!(null));
+        assert_eq!(form_list[1]["icon_key"], "Clutter\\Gems\\Sapphire.nif");
+
+        let mut invalid = json!(current);
+        invalid["icon_key"] = json!("<img>");
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &invalid,
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem["errors"][0]["field"], "icon_key");
+    }
+
+    #[tokio::test]
+    async fn create_and_list_transactions() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        // Cache the empty merchandise list so the GET below checks that it was evicted.
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(json_body(&response)["form_list"], json!([]));
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "New Thing",
+                    "form_type": 41,
+                    "is_food": false,
+                    "price": 100,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 100,
+                    "keywords": ["VendorItemMisc"],
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let result = json_body(&response);
+        assert_eq!(result["merchandise"]["quantity"], 1);
+        let transaction_id = result["transaction"]["id"].clone();
+        assert!(transaction_id.is_i64());
+
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/transactions", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let transactions = json_body(&response);
+        assert_eq!(transactions.as_array().map(Vec::len), Some(1));
+        assert_eq!(transactions[0]["id"], transaction_id);
+
+        // The player selling to the shop adds the item to the shop's merchandise.
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/merchandise_list", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let form_list = &json_body(&response)["form_list"];
+        assert_eq!(form_list[0]["local_form_id"], "0x00000005");
+        assert_eq!(form_list[0]["quantity"], 1);
+    }
+
+    #[tokio::test]
+    async fn buying_an_unlisted_item_is_a_reported_404() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let before = anomalies::count(AnomalyCode::BuyOfUnlistedItem);
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "Nothing",
+                    "form_type": 41,
+                    "is_food": false,
+                    "price": 100,
+                    "is_sell": false,
+                    "quantity": 1,
+                    "amount": 100,
+                    "keywords": ["VendorItemMisc"],
+                }),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+        assert!(anomalies::count(AnomalyCode::BuyOfUnlistedItem) > before);
+    }
+
+    #[tokio::test]
+    async fn form_ids_above_i32_max_match_merchandise() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let transaction = |local_form_id: serde_json::Value, is_sell: bool| {
+            json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": local_form_id,
+                "name": "Modded Thing",
+                "form_type": 41,
+                "is_food": false,
+                "price": 10,
+                "is_sell": is_sell,
+                "quantity": 1,
+                "amount": 10,
+                "keywords": ["VendorItemMisc"],
+            })
+        };
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(json!("0x80000001"), true),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let result = json_body(&response);
+        assert_eq!(result["transaction"]["local_form_id"], "0x80000001");
+        assert_eq!(result["merchandise"]["local_form_id"], "0x80000001");
+
+        // The same id sign-extended by an older client still finds the item and buys it out.
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(json!(0x8000_0001u32 as i32), false),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let result = json_body(&response);
+        assert_eq!(result["transaction"]["local_form_id"], "0x80000001");
+        assert_eq!(result["merchandise"]["quantity"], 0);
+    }
+
+    #[tokio::test]
+    async fn gold_and_prices_above_i32_max() {
+        let test = match TestEnv::with_config(&[("TRANSACTION_MAX_PRICE", "5000000000")]).await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Guild Bank", "gold": 5_000_000_000i64 }),
+            )
+            .await;
+        let transaction = |price: i64, is_sell: bool| {
+            json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 7,
+                "name": "Crown",
+                "form_type": 26,
+                "is_food": false,
+                "price": price,
+                "is_sell": is_sell,
+                "quantity": 1,
+                "amount": price,
+                "keywords": ["VendorItemClothing"],
+            })
+        };
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(3_000_000_000, true),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let result = json_body(&response);
+        assert_eq!(result["transaction"]["price"], 3_000_000_000i64);
+        assert_eq!(result["transaction"]["amount"], 3_000_000_000i64);
+        assert_eq!(result["merchandise"]["price"], 3_000_000_000i64);
+
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(json_body(&response)["gold"], 2_000_000_000i64);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(3_000_000_000, false),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(json_body(&response)["gold"], 5_000_000_000i64);
+    }
+
+    #[tokio::test]
+    async fn gold_moves_by_the_amount_of_multi_quantity_transactions() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let transaction = |quantity: i32, is_sell: bool| {
+            json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 7,
+                "name": "Iron Ingot",
+                "form_kind": 32,
+                "is_food": false,
+                "price": 10,
+                "is_sell": is_sell,
+                "quantity": quantity,
+                // Overwritten with price * quantity by the server.
+                "amount": 10,
+                "keywords": [],
+            })
+        };
+        let gold = || async {
+            let response = test
+                .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+                .await;
+            json_body(&response)["gold"].clone()
+        };
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(3, true),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["transaction"]["amount"], 30);
+        assert_eq!(gold().await, 970);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(2, false),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(gold().await, 990);
+    }
+
+    #[tokio::test]
+    async fn overflowing_quantities_and_amounts_are_rejected_in_both_formats() {
+        let test = match TestEnv::with_config(&[
+            ("TRANSACTION_MAX_QUANTITY", "2147483647"),
+            ("TRANSACTION_MAX_PRICE", "9223372036854775807"),
+        ])
+        .await
+        {
+            Some(test) => test,
+            None => return,
+        };
+        let default_limits = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        for test in &[&test, &default_limits] {
+            test.create_owner(OWNER_API_KEY, "Owner").await;
+        }
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let default_shop_id = default_limits
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 1000 }))
+            .await;
+        let transaction = |shop_id: i64, price: i64, quantity: i32| -> PostedTransaction {
+            serde_json::from_value(json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 7,
+                "name": "Iron Ingot",
+                "form_kind": 32,
+                "is_food": false,
+                "price": price,
+                "is_sell": false,
+                "quantity": quantity,
+                "amount": 0,
+                "keywords": [],
+            }))
+            .unwrap()
+        };
+        let post = |transaction: &PostedTransaction, bincode_body: bool| {
+            let request = request("POST", "/v1/transactions", Some(OWNER_API_KEY));
+            if bincode_body {
+                request
+                    .header("content-type", "application/octet-stream")
+                    .body(bincode::serialize(transaction).unwrap())
+            } else {
+                request
+                    .header("content-type", "application/json")
+                    .body(serde_json::to_vec(transaction).unwrap())
+            }
+        };
+
+        for &bincode_body in &[false, true] {
+            // Past the default quantity limit, long before the amount could overflow.
+            let response = default_limits
+                .send(post(
+                    &transaction(default_shop_id, 10, 2_000_000_000),
+                    bincode_body,
+                ))
+                .await;
+            let problem = assert_problem(&response, StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(problem["errors"][0]["field"], "quantity");
+
+            // Within raised limits, but price * quantity doesn't fit in an i64.
+            let response = test
+                .send(post(
+                    &transaction(shop_id, i64::MAX / 2 + 1, 2),
+                    bincode_body,
+                ))
+                .await;
+            assert_problem(&response, StatusCode::BAD_REQUEST);
+        }
+        for (test, shop_id) in &[(&test, shop_id), (&default_limits, default_shop_id)] {
+            let response = test
+                .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+                .await;
+            assert_eq!(json_body(&response)["gold"], 1000);
+        }
+    }
+
+    #[tokio::test]
+    async fn gold_overflow_is_rejected() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Test Shop", "gold": i64::MAX - 1 }),
+            )
+            .await;
+        let transaction = |price: i64, is_sell: bool| {
+            json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 7,
+                "name": "Crown",
+                "form_type": 26,
+                "is_food": false,
+                "price": price,
+                "is_sell": is_sell,
+                "quantity": 1,
+                "amount": price,
+                "keywords": ["VendorItemClothing"],
+            })
+        };
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(0, true),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(2, false),
+            ))
+            .await;
+        assert_problem(&response, StatusCode::BAD_REQUEST);
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(json_body(&response)["gold"], i64::MAX - 1);
+    }
+
+    #[tokio::test]
+    async fn sells_cannot_overdraw_the_shop() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 100 }))
+            .await;
+        let transaction = |price: i64| {
+            json!({
+                "shop_id": shop_id,
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 7,
+                "name": "Crown",
+                "form_type": 26,
+                "is_food": false,
+                "price": price,
+                "is_sell": true,
+                "quantity": 1,
+                "amount": price,
+                "keywords": ["VendorItemClothing"],
+            })
+        };
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(60),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        assert_eq!(json_body(&response)["shop_gold"], 40);
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &transaction(5000),
+            ))
+            .await;
+        let problem = assert_problem(&response, StatusCode::BAD_REQUEST);
+        assert_eq!(problem["code"], "insufficient_shop_gold");
+        let response = test
+            .send(request("GET", &format!("/v1/shops/{}", shop_id), None))
+            .await;
+        assert_eq!(json_body(&response)["gold"], 40);
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/transactions", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(json_body(&response).as_array().map(Vec::len), Some(1));
+    }
+
+    #[tokio::test]
+    async fn hot_list_queries_use_their_indexes() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let mut tx = test.env.db.begin().await.unwrap();
+        // The test tables are tiny, so make sequential scans look expensive to see which index
+        // the planner would pick on a real table.
+        sqlx::query("SET LOCAL enable_seqscan = off")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        let queries = [
+            (
+                "SELECT * FROM transactions WHERE shop_id = 1
+                ORDER BY created_at DESC, id DESC LIMIT 10",
+                "transactions_shop_id_and_created_at",
+            ),
+            (
+                "SELECT * FROM shops WHERE owner_id = 1 ORDER BY name LIMIT 10",
+                "shops_owner_id_and_name",
+            ),
+        ];
+        let mut plans = vec![];
+        for (query, _) in &queries {
+            let plan: Vec<(String,)> = sqlx::query_as(&format!("EXPLAIN {}", query))
+                .fetch_all(&mut tx)
+                .await
+                .unwrap();
+            plans.push(plan.into_iter().map(|(line,)| line).collect::<Vec<_>>());
+        }
+        // Rolled back before asserting, since the schema can't be dropped while it is open.
+        tx.rollback().await.unwrap();
+        for ((query, index), plan) in queries.iter().zip(plans) {
+            assert!(
+                plan.iter().any(|line| line.contains(index)),
+                "{} does not use {}: {:#?}",
+                query,
+                index,
+                plan
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn purchases_only_evict_their_own_shops_transaction_pages() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Busy Shop", "gold": 1000 }))
+            .await;
+        let other_shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Quiet Shop", "gold": 1000 }),
+            )
+            .await;
+        let cache = &test.env.caches.list_transactions_by_shop_id;
+        for id in &[shop_id, other_shop_id] {
+            let response = test
+                .send(request(
+                    "GET",
+                    &format!("/v1/shops/{}/transactions", id),
+                    None,
+                ))
+                .await;
+            assert_eq!(json_body(&response), json!([]));
+        }
+        // Responses are put in the cache by a spawned task.
+        while cache.lru_mutex.lock().await.len() < 2 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 5,
+                    "name": "New Thing",
+                    "form_type": 41,
+                    "is_food": false,
+                    "price": 100,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 100,
+                    "keywords": ["VendorItemMisc"],
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let transaction_id = json_body(&response)["transaction"]["id"].clone();
+
+        let before = cache.stats();
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/transactions", other_shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(json_body(&response), json!([]));
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/transactions", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(json_body(&response)[0]["id"], transaction_id);
+        let after = cache.stats();
+        assert_eq!(after.hits - before.hits, 1);
+        assert_eq!(after.misses - before.misses, 1);
+
+        while cache.lru_mutex.lock().await.len() < 2 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        let response = test
+            .send(request(
+                "DELETE",
+                &format!("/v1/transactions/{}", transaction_id),
+                Some(OWNER_API_KEY),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(cache.lru_mutex.lock().await.len(), 1);
+        let response = test
+            .send(request(
+                "GET",
+                &format!("/v1/shops/{}/transactions", shop_id),
+                None,
+            ))
+            .await;
+        assert_eq!(json_body(&response), json!([]));
+    }
+
+    #[tokio::test]
+    async fn concurrent_deletes_of_a_transaction_delete_it_once() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop", "gold": 100 }))
+            .await;
+        let response = test
+            .send(json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 7,
+                    "name": "Iron Ingot",
+                    "form_kind": 32,
+                    "is_food": false,
+                    "price": 10,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 10,
+                    "keywords": [],
+                }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        let transaction_id = json_body(&response)["transaction"]["id"].clone();
+        let delete = || {
+            request(
+                "DELETE",
+                &format!("/v1/transactions/{}", transaction_id),
+                Some(OWNER_API_KEY),
+            )
+        };
+        let (first, second) = test.send_concurrently(delete(), delete()).await;
+        assert_one_deleted(&first, &second);
+        let response = test.send(delete()).await;
+        assert_problem(&response, StatusCode::NOT_FOUND);
+    }
+
+    // Each side of the race holds its own connection, with the first one's transaction kept open
+    // until the other request is blocked on it.
+    #[tokio::test]
+    async fn purchases_and_shop_deletes_wait_for_each_other() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let owner_id = test.create_owner(OWNER_API_KEY, "Owner").await as i32;
+        let purchase = |shop_id: i64| {
+            json_request(
+                "POST",
+                "/v1/transactions",
+                Some(OWNER_API_KEY),
+                &json!({
+                    "shop_id": shop_id,
+                    "mod_name": "Skyrim.esm",
+                    "local_form_id": 7,
+                    "name": "Iron Ingot",
+                    "form_kind": 32,
+                    "is_food": false,
+                    "price": 10,
+                    "is_sell": true,
+                    "quantity": 1,
+                    "amount": 10,
+                    "keywords": [],
+                }),
+            )
+        };
+        let db = &test.env.db;
+        let shop_exists = |shop_id: i64| async move {
+            sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM shops WHERE id = $1)")
+                .bind(shop_id as i32)
+                .fetch_one(db)
+                .await
+                .unwrap()
+        };
+
+        // The delete commits first: the purchase waits for it, then fails with a 404 rather than
+        // a foreign key violation.
+        let shop_id = test
+            .create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Deleted Shop", "gold": 100 }),
+            )
+            .await;
+        let mut deleting = test.env.db.begin().await.unwrap();
+        Shop::delete(&mut deleting, owner_id, shop_id as i32)
+            .await
+            .unwrap();
+        let (response, ()) = tokio::join!(test.send(purchase(shop_id)), async {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            deleting.commit().await.unwrap();
+        });
+        let problem = assert_problem(&response, StatusCode::NOT_FOUND);
+        assert_eq!(problem["detail"], "Shop does not exist or has been deleted");
+
+        // The purchase locks the shop first: the delete waits until the purchase is done.
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Busy Shop", "gold": 100 }))
+            .await;
+        let mut purchasing = test.env.db.begin().await.unwrap();
+        Shop::lock_for_transaction(&mut purchasing, shop_id as i32, owner_id)
+            .await
+            .unwrap();
+        let delete = request(
+            "DELETE",
+            &format!("/v1/shops/{}", shop_id),
+            Some(OWNER_API_KEY),
+        );
+        let (response, ()) = tokio::join!(test.send(delete), async {
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            assert!(shop_exists(shop_id).await);
+            purchasing.commit().await.unwrap();
+        });
+        assert_eq!(response.status(), StatusCode::NO_CONTENT, "{:?}", response);
+        assert!(!shop_exists(shop_id).await);
+    }
+
+    #[tokio::test]
+    async fn pages_of_transactions_neither_overlap_nor_skip() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_ids = [
+            test.create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "First Shop", "gold": 1000 }),
+            )
+            .await,
+            test.create_shop(
+                OWNER_API_KEY,
+                &json!({ "name": "Second Shop", "gold": 1000 }),
+            )
+            .await,
+        ];
+        // Interleaved between the shops.
+        let mut ids = vec![];
+        for index in 0..7 {
+            let response = test
+                .send(json_request(
+                    "POST",
+                    "/v1/transactions",
+                    Some(OWNER_API_KEY),
+                    &json!({
+                        "shop_id": shop_ids[index % 2],
+                        "mod_name": "Skyrim.esm",
+                        "local_form_id": index + 1,
+                        "name": "Iron Ingot",
+                        "form_kind": 32,
+                        "is_food": false,
+                        "price": 10,
+                        "is_sell": true,
+                        "quantity": 1,
+                        "amount": 10,
+                        "keywords": [],
+                    }),
+                ))
+                .await;
+            assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+            ids.push(json_body(&response)["transaction"]["id"].as_i64().unwrap());
+        }
+        // Ties on every timestamp, so only the id can order them.
+        sqlx::query("UPDATE transactions SET created_at = '2020-01-01', updated_at = '2020-01-01'")
+            .execute(&test.env.db)
+            .await
+            .unwrap();
+
+        let paged_ids = |path: String| {
+            let test = &test;
+            async move {
+                let mut paged = vec![];
+                for offset in (0..8).step_by(3) {
+                    let separator = if path.contains('?') { '&' } else { '?' };
+                    let response = test
+                        .send(request(
+                            "GET",
+                            &format!("{}{}limit=3&offset={}", path, separator, offset),
+                            None,
+                        ))
+                        .await;
+                    assert_eq!(response.status(), StatusCode::OK, "{:?}", response);
+                    for transaction in json_body(&response).as_array().unwrap() {
+                        paged.push(transaction["id"].as_i64().unwrap());
+                    }
+                }
+                paged
+            }
+        };
+        let mut newest_first = ids.clone();
+        newest_first.reverse();
+        assert_eq!(
+            paged_ids("/v1/transactions".to_string()).await,
+            newest_first
+        );
+        assert_eq!(
+            paged_ids("/v1/transactions?order_by=created_at".to_string()).await,
+            newest_first
+        );
+        // Every other transaction was made in the first shop.
+        let first_shop: Vec<i64> = ids.iter().step_by(2).rev().copied().collect();
+        assert_eq!(
+            paged_ids(format!("/v1/shops/{}/transactions", shop_ids[0])).await,
+            first_shop
+        );
+    }
+}
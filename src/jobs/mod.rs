@@ -0,0 +1,115 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use sqlx::postgres::PgPool;
+use tracing::{debug, error, info};
+
+use crate::caches::Caches;
+use crate::models::{MerchandiseChange, OwnerRequestUsage, ShopGoldHistory};
+use crate::replays::{self, ReplayJournal};
+use crate::usage::Usage;
+
+const MERCHANDISE_CHANGES_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const USAGE_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+const REPLAY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Periodically deletes merchandise changes older than `retention_days` so the change feed does
+/// not grow without bound.
+pub fn spawn_merchandise_changes_pruner(db: PgPool, retention_days: i64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(MERCHANDISE_CHANGES_PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let cutoff = Utc::now().naive_utc() - chrono::Duration::days(retention_days);
+            match MerchandiseChange::delete_older_than(&db, cutoff).await {
+                Ok(deleted) => info!(deleted, %cutoff, "pruned merchandise changes"),
+                Err(error) => error!(%error, "failed to prune merchandise changes"),
+            }
+        }
+    });
+}
+
+/// Snapshots every shop's gold once at startup and then at each UTC midnight. Snapshots are
+/// idempotent per day, so restarts do not create duplicates.
+pub fn spawn_shop_gold_snapshots(db: PgPool, caches: Arc<Caches>) {
+    tokio::spawn(async move {
+        loop {
+            match ShopGoldHistory::snapshot_all(&db).await {
+                Ok(inserted) => {
+                    info!(inserted, "snapshotted shop gold");
+                    caches.shop_gold_history.clear().await;
+                    caches.shop_gold_history_bin.clear().await;
+                }
+                Err(error) => error!(%error, "failed to snapshot shop gold"),
+            }
+            tokio::time::delay_for(until_next_utc_midnight()).await;
+        }
+    });
+}
+
+/// Periodically writes the request counts `Usage` has collected in memory to the database, so at
+/// most a minute of counts is lost when the server stops. Counts that fail to write are retried on
+/// the next flush.
+pub fn spawn_usage_flusher(db: PgPool, usage: Arc<Usage>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(USAGE_FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let pending = usage.take_pending();
+            if pending.is_empty() {
+                continue;
+            }
+            match OwnerRequestUsage::add_many(&db, &pending).await {
+                Ok(flushed) => debug!(flushed, "flushed owner request usage"),
+                Err(error) => {
+                    error!(%error, "failed to flush owner request usage");
+                    usage.restore_pending(pending);
+                }
+            }
+        }
+    });
+}
+
+/// Applies the cache invalidations queued by handlers (see `Caches::invalidate`).
+pub fn spawn_invalidation_worker(caches: Arc<Caches>) {
+    tokio::spawn(async move {
+        caches.run_invalidation_worker().await;
+    });
+}
+
+/// Expires old journaled writes and replays the rest through `service` once the database answers
+/// again. Replays that find it unavailable again are retried on the next tick.
+pub fn spawn_replayer<S>(journal: Arc<ReplayJournal>, db: PgPool, mut service: S)
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Send + 'static,
+    S::Future: Send,
+{
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REPLAY_INTERVAL);
+        loop {
+            interval.tick().await;
+            journal.expire(Utc::now().naive_utc());
+            if let Err(error) = sqlx::query("SELECT 1").execute(&db).await {
+                debug!(%error, "database is still unavailable, not replaying");
+                continue;
+            }
+            match replays::replay_pending(&journal, &db, &mut service).await {
+                Ok(0) => {}
+                Ok(replayed) => info!(replayed, "replayed journaled writes"),
+                Err(error) => error!(%error, "failed to replay journaled writes"),
+            }
+        }
+    });
+}
+
+fn until_next_utc_midnight() -> Duration {
+    let now = Utc::now();
+    let next_midnight = (now.date() + chrono::Duration::days(1)).and_hms(0, 0, 0);
+    (next_midnight - now)
+        .to_std()
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
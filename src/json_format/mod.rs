@@ -0,0 +1,136 @@
+//! How JSON responses are written out.
+//!
+//! Every JSON body is labelled `application/json; charset=utf-8` for parsers that insist on the
+//! parameter. With `?pretty=true` the body is re-serialized with indentation after the handler
+//! (and its caches) are done with the compact one, so pretty bodies are never cached. The ETag
+//! and `X-Body-SHA256` are then recomputed from the pretty bytes so that the two forms never
+//! share a validator.
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use http::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, ETAG};
+use hyper::body::{self, Body};
+use tracing::warn;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::body_digest::{sha256_hex, BODY_SHA256};
+use crate::caches::CachedResponse;
+use crate::handlers::{body_etag, copy_page_headers, if_none_match, AcceptHeader};
+use crate::problem::{invalid_query_param, reject_anyhow};
+
+pub const PRETTY_PARAM: &str = "pretty";
+pub static JSON_CONTENT_TYPE: &str = "application/json; charset=utf-8";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct JsonFormat {
+    pub pretty: bool,
+}
+
+/// Reads `?pretty=` off any request. Asking for pretty bincode is a 400 rather than being quietly
+/// ignored, since the client evidently expected to read the body.
+pub fn extract() -> impl Filter<Extract = (JsonFormat,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(
+            |params: HashMap<String, String>, accept: Option<String>| async move {
+                let pretty = match params.get(PRETTY_PARAM).map(String::as_str) {
+                    None | Some("false") => false,
+                    Some("true") => true,
+                    Some(_) => {
+                        return Err(reject_anyhow(invalid_query_param(
+                            PRETTY_PARAM,
+                            "must be true or false",
+                        )))
+                    }
+                };
+                let accepts_bincode = accept
+                    .and_then(|accept| accept.parse::<AcceptHeader>().ok())
+                    .is_some_and(|accept| accept.accepts_bincode());
+                if pretty && accepts_bincode {
+                    return Err(reject_anyhow(invalid_query_param(
+                        PRETTY_PARAM,
+                        "pretty output is only available for JSON, not bincode",
+                    )));
+                }
+                Ok(JsonFormat { pretty })
+            },
+        )
+}
+
+/// Labels JSON responses with their charset and pretty-prints them if asked to. `etag` is the
+/// request's `If-None-Match`, checked again against the pretty body's own ETag.
+pub async fn apply(
+    format: JsonFormat,
+    etag: Option<String>,
+    reply: impl Reply,
+) -> Result<Response, Rejection> {
+    let mut response = reply.into_response();
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok())
+        .is_some_and(|content_type| content_type.essence_str() == "application/json");
+    if !is_json {
+        return Ok(response);
+    }
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(JSON_CONTENT_TYPE));
+    if !format.pretty {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = body::to_bytes(body)
+        .await
+        .map_err(|error| reject_anyhow(anyhow!(error)))?;
+    let pretty = match prettify(&bytes) {
+        Ok(pretty) => pretty,
+        Err(error) => {
+            warn!(%error, "failed to pretty-print JSON response, sending it compact");
+            return Ok(Response::from_parts(parts, Body::from(bytes)));
+        }
+    };
+    let pretty_etag = body_etag(&pretty);
+    if etag.is_some_and(|etag| if_none_match(&etag, &pretty_etag)) {
+        if let Ok(pretty_etag) = HeaderValue::from_str(&pretty_etag) {
+            let mut not_modified = CachedResponse::not_modified(pretty_etag);
+            copy_page_headers(&parts.headers, &mut not_modified.headers);
+            return Ok(not_modified.into_response());
+        }
+    }
+    if let Ok(val) = HeaderValue::from_str(&pretty_etag) {
+        parts.headers.insert(ETAG, val);
+    }
+    if let Ok(val) = HeaderValue::from_str(&sha256_hex(&pretty)) {
+        parts.headers.insert(BODY_SHA256, val);
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, Body::from(pretty)))
+}
+
+// Transcodes rather than going through `serde_json::Value`, which would sort object keys.
+fn prettify(compact: &[u8]) -> serde_json::Result<Vec<u8>> {
+    let mut pretty = Vec::with_capacity(compact.len() * 2);
+    let mut deserializer = serde_json::Deserializer::from_slice(compact);
+    let mut serializer = serde_json::Serializer::pretty(&mut pretty);
+    serde_transcode::transcode(&mut deserializer, &mut serializer)?;
+    deserializer.end()?;
+    Ok(pretty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prettify;
+
+    #[test]
+    fn prettify_indents_and_keeps_key_order() {
+        let pretty = prettify(br#"{"name":"Test Shop","id":1,"tags":[]}"#).unwrap();
+        assert_eq!(
+            String::from_utf8(pretty).unwrap(),
+            "{\n  \"name\": \"Test Shop\",\n  \"id\": 1,\n  \"tags\": []\n}"
+        );
+    }
+}
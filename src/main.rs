@@ -1,44 +1,124 @@
-#[macro_use]
-extern crate lazy_static;
-
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use chrono::Utc;
 use dotenv::dotenv;
-use http::header::SERVER;
-use hyper::{body::Bytes, server::Server};
+use hyper::body::Bytes;
+use ipnetwork::IpNetwork;
 use listenfd::ListenFd;
+use mime::Mime;
 use sqlx::postgres::PgPoolOptions;
-use sqlx::{migrate, Pool, Postgres};
+use sqlx::{Pool, Postgres};
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::env;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::info;
 use tracing_subscriber::fmt::format::FmtSpan;
 use url::Url;
-use warp::http::Response;
-use warp::Filter;
+use uuid::Uuid;
+use warp::http::header::{HeaderMap, LOCATION};
+use warp::http::Method;
+use warp::http::StatusCode;
+use warp::path::FullPath;
+use warp::reply::{with_header, with_status};
+use warp::{Filter, Rejection, Reply};
 
+mod anomalies;
+mod archive;
+mod body_digest;
+mod body_length;
 mod caches;
+mod captures;
+mod clock;
+mod config;
+mod connections;
 mod handlers;
+mod jobs;
+mod json_format;
 #[macro_use]
 mod macros;
+mod maintenance;
+mod metrics;
+mod migrations;
 mod models;
+mod notifications;
 mod problem;
+mod rate_limit;
+mod replays;
+mod schema;
+mod tasks;
+#[cfg(test)]
+mod test_support;
+mod tls;
+mod usage;
 
-use handlers::SERVER_STRING;
-use models::ListParams;
+use caches::{Caches, InFlightQueries};
+use captures::{CaptureContext, CaptureStore};
+use config::Config;
+use handlers::admin::{ImportParams, ReconcileParams};
+use handlers::shop::CreateShopParams;
+use handlers::shop_deletion::DeleteShopParams;
+use handlers::status::MetricsParams;
+use handlers::{SchemaVersion, SCHEMA_VERSION_HEADER, TARGET_SHOP_HEADER};
+use maintenance::Maintenance;
+use metrics::{Metrics, RouteKind};
+use models::{
+    Deadline, GoldHistoryParams, InteriorRefListQuery, ListQuery, MerchandiseChangeListQuery,
+    MerchandiseListQuery, OwnerListQuery, OwnerRequestUsage, OwnerShopListQuery, Pagination,
+    ShopListQuery, ShopRequestListQuery, ShopReviewListQuery, TransactionListQuery,
+};
+use problem::{
+    expensive_rate_limited, maintenance, quota_exceeded, reject_anyhow, schema_mismatch,
+    unknown_query_params,
+};
+use rate_limit::{RateLimiter, Requester, TokenBuckets};
+use replays::{ReplayContext, ReplayJournal};
+use schema::SchemaStatus;
+use tasks::{TaskSpawner, SHUTDOWN_DRAIN_TIMEOUT};
+use usage::Usage;
 
 #[derive(Debug, Clone)]
 pub struct Environment {
     pub db: Pool<Postgres>,
     pub api_url: Url,
+    pub schema_status: Arc<SchemaStatus>,
+    pub config: Arc<Config>,
+    pub captures: Arc<CaptureStore>,
+    pub metrics: Arc<Metrics>,
+    pub maintenance: Arc<Maintenance>,
+    pub in_flight: Arc<InFlightQueries>,
+    pub caches: Arc<Caches>,
+    pub usage: Arc<Usage>,
+    pub card_rate_limiter: Arc<RateLimiter>,
+    pub expensive_limiter: Arc<TokenBuckets<Requester>>,
+    pub replays: Arc<ReplayJournal>,
+    pub tasks: TaskSpawner,
 }
 
 impl Environment {
-    async fn new(api_url: Url) -> Result<Environment> {
+    async fn new(config: Arc<Config>) -> Result<Environment> {
+        let tasks = TaskSpawner::default();
         Ok(Environment {
             db: PgPoolOptions::new()
-                .max_connections(5)
-                .connect(&env::var("DATABASE_URL")?)
+                .max_connections(config.db_max_connections)
+                .connect(&config.database_url)
                 .await?,
-            api_url,
+            api_url: config.api_url()?,
+            schema_status: Arc::new(SchemaStatus::default()),
+            maintenance: Arc::new(Maintenance::new(config.maintenance_mode)),
+            captures: Arc::new(CaptureStore::default()),
+            metrics: Arc::new(Metrics::default()),
+            in_flight: Arc::new(InFlightQueries::default()),
+            caches: Arc::new(Caches::initialize(&config.cache_capacities, &tasks)),
+            usage: Arc::new(Usage::default()),
+            card_rate_limiter: Arc::new(RateLimiter::per_minute(config.card_rate_limit_per_minute)),
+            expensive_limiter: Arc::new(TokenBuckets::per_minute(config.expensive_rpm)),
+            replays: Arc::new(ReplayJournal::open(config.replay_journal.clone())?),
+            tasks,
+            config,
         })
     }
 }
@@ -47,52 +127,514 @@ fn with_env(env: Environment) -> impl Filter<Extract = (Environment,), Error = I
     warp::any().map(move || env.clone())
 }
 
-fn extract_body_bytes() -> impl Filter<Extract = (Bytes,), Error = warp::Rejection> + Clone {
-    warp::body::content_length_limit(1024 * 1024).and(warp::body::bytes())
+// Starts the request's time budget for handlers that pass a `Deadline` to heavy queries.
+fn with_deadline(
+    env: Environment,
+) -> impl Filter<Extract = (Deadline,), Error = Infallible> + Clone {
+    let timeout = env.config.request_timeout;
+    warp::any().map(move || Deadline::after(timeout))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    openssl_probe::init_ssl_cert_env_vars();
-    dotenv().ok();
-    let env_log_filter =
-        env::var("RUST_LOG").unwrap_or_else(|_| "warp=info,bazaar_realm_api=info".to_owned());
+// Rejects every request with a 503 problem when the startup schema check failed, so that clients get
+// a clear explanation instead of an opaque 500 from the first query that touches a missing column.
+fn require_valid_schema(env: Environment) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_env(env)
+        .and_then(|env: Environment| async move {
+            if env.schema_status.ok {
+                Ok(())
+            } else {
+                Err(reject_anyhow(schema_mismatch()))
+            }
+        })
+        .untuple_one()
+}
 
-    let (non_blocking_writer, _guard) = tracing_appender::non_blocking(std::io::stdout());
-    tracing_subscriber::fmt()
-        .with_env_filter(env_log_filter)
-        .with_span_events(FmtSpan::CLOSE)
-        .with_writer(non_blocking_writer)
-        .init();
+// Rejects requests that the current maintenance mode doesn't allow with a 503 and `Retry-After`.
+fn require_available(env: Environment) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(with_env(env))
+        .and_then(|method: Method, env: Environment| async move {
+            let mode = env.maintenance.mode().await;
+            if mode.allows(&method) {
+                Ok(())
+            } else {
+                Err(reject_anyhow(maintenance(
+                    mode,
+                    env.config.maintenance_retry_after,
+                )))
+            }
+        })
+        .untuple_one()
+}
 
-    let host = env::var("HOST").expect("`HOST` environment variable not defined");
-    let host_url = Url::parse(&host).expect("Cannot parse URL from `HOST` environment variable");
-    let api_url = host_url.join("/v1/")?;
-    let env = Environment::new(api_url).await?;
+// Counts requests made with an owner's api key toward their monthly usage, and rejects them with a
+// 403 once `OWNER_MONTHLY_QUOTA` is used up. The owner is resolved through the cached api key lookup
+// and counts are flushed to the database by a background job, so no query is added per request.
+// Requests with a missing or unknown api key are left for the handler to authenticate (or not).
+fn track_usage(env: Environment) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("api-key"))
+        .and(with_env(env))
+        .and_then(
+            |method: Method, path: FullPath, api_key: Option<String>, env: Environment| async move {
+                let api_key = match api_key.and_then(|value| Uuid::parse_str(&value).ok()) {
+                    Some(api_key) => api_key,
+                    None => return Ok(()),
+                };
+                let owner_id = match handlers::authenticate(&env, Some(api_key)).await {
+                    Ok(owner_id) => owner_id,
+                    Err(_) => return Ok(()),
+                };
+                // Owners over their quota can still check their usage.
+                let quota = match path.as_str() {
+                    "/v1/owners/me/usage" => None,
+                    _ => env.config.owner_monthly_quota,
+                };
+                let now = Utc::now().naive_utc();
+                if env
+                    .usage
+                    .record(owner_id, RouteKind::from_method(&method), quota, now)
+                {
+                    Ok(())
+                } else {
+                    Err(reject_anyhow(quota_exceeded(
+                        quota.unwrap_or_default(),
+                        Usage::resets_on(now),
+                    )))
+                }
+            },
+        )
+        .untuple_one()
+}
 
-    migrate!("db/migrations").run(&env.db).await?;
+// Takes a token from the requester's bucket for an expensive endpoint, or rejects with a 429 and
+// `Retry-After` once `EXPENSIVE_RPM` is used up. Routes opt in by adding `.and(expensive(env))`
+// after their method filter. Requests are charged to the owner of a valid api key, otherwise to
+// the remote address, so cheap routes are never held back by traffic to expensive ones.
+fn expensive(env: Environment) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("api-key")
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<IpNetwork>("x-real-ip"))
+        .and(with_env(env))
+        .and_then(
+            |api_key: Option<String>,
+             remote_addr: Option<SocketAddr>,
+             real_ip: Option<IpNetwork>,
+             env: Environment| async move {
+                let api_key = api_key.and_then(|value| Uuid::parse_str(&value).ok());
+                let requester = match api_key {
+                    Some(api_key) => handlers::authenticate(&env, Some(api_key)).await.ok(),
+                    None => None,
+                }
+                .map(Requester::Owner)
+                .unwrap_or_else(|| {
+                    Requester::Ip(match remote_addr {
+                        Some(addr) => Some(addr.ip()),
+                        None => real_ip.map(|real_ip| real_ip.ip()),
+                    })
+                });
+                env.expensive_limiter
+                    .take(requester)
+                    .map_err(|retry_after| reject_anyhow(expensive_rate_limited(retry_after)))
+            },
+        )
+        .untuple_one()
+}
+
+// Parses the query string into a map first so that unknown keys (e.g. a misspelled `odrer_by`) can be
+// rejected with a 400 instead of being silently ignored. The known keys are then deserialized into
+// the endpoint's `ListQuery`. Strictness can be turned off with the
+// `STRICT_QUERY_PARAMS` environment variable for clients that still send extra parameters.
+fn extract_list_query<Q: ListQuery + Send + 'static>(
+    strict: bool,
+) -> impl Filter<Extract = (Q,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .and_then(move |params: HashMap<String, String>| async move {
+            if strict {
+                let is_supported = |key: &str| {
+                    Pagination::SUPPORTED_PARAMS.contains(&key)
+                        || Q::FILTER_PARAMS.contains(&key)
+                        || key == json_format::PRETTY_PARAM
+                };
+                let mut unknown: Vec<String> = params
+                    .into_keys()
+                    .filter(|key| !is_supported(key))
+                    .collect();
+                if !unknown.is_empty() {
+                    unknown.sort();
+                    let supported: Vec<&str> = Pagination::SUPPORTED_PARAMS
+                        .iter()
+                        .chain(Q::FILTER_PARAMS)
+                        .copied()
+                        .collect();
+                    return Err(reject_anyhow(unknown_query_params(&unknown, &supported)));
+                }
+            }
+            Ok(())
+        })
+        .untuple_one()
+        .and(warp::query::<Pagination>())
+        .and(warp::query::<Q::Filter>())
+        .and_then(|pagination: Pagination, filter: Q::Filter| async move {
+            pagination
+                .normalized()
+                .and_then(|pagination| Q::new(pagination, filter))
+                .map_err(reject_anyhow)
+        })
+}
+
+// Redirects requests with a trailing slash (`/v1/shops/`) or an upper-case version prefix
+// (`/V1/shops`) to the canonical path. Uses 308 so that clients repeat the same method and body.
+fn normalize_path() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and_then(|full_path: FullPath, query: String| async move {
+            let path = full_path.as_str();
+            let mut normalized = path.trim_end_matches('/').to_string();
+            if normalized.starts_with("/V1") && matches!(normalized.get(3..4), None | Some("/")) {
+                normalized.replace_range(..3, "/v1");
+            }
+            if normalized.is_empty() || normalized == path {
+                return Err(warp::reject::not_found());
+            }
+            if !query.is_empty() {
+                normalized = format!("{}?{}", normalized, query);
+            }
+            let reply = with_status(warp::reply(), StatusCode::PERMANENT_REDIRECT);
+            Ok(with_header(reply, LOCATION, normalized))
+        })
+}
+
+// Resolves the owner making the request when an admin has started a capture for them. Never
+// rejects: requests that can't be attributed to a captured owner are simply not recorded.
+fn capture_context(
+    env: Environment,
+) -> impl Filter<Extract = (Option<CaptureContext>,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::headers_cloned())
+        .and(with_env(env))
+        .and_then(
+            |method: Method, path: FullPath, headers: HeaderMap, env: Environment| async move {
+                if env.captures.is_empty().await {
+                    return Ok::<_, Rejection>(None);
+                }
+                let api_key = headers
+                    .get("api-key")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| Uuid::parse_str(value).ok());
+                if api_key.is_none() {
+                    return Ok(None);
+                }
+                match handlers::authenticate(&env, api_key).await {
+                    Ok(owner_id) if env.captures.is_capturing(owner_id).await => {
+                        Ok(Some(CaptureContext::new(
+                            env.captures.clone(),
+                            owner_id,
+                            method,
+                            path.as_str().to_string(),
+                            &headers,
+                        )))
+                    }
+                    _ => Ok(None),
+                }
+            },
+        )
+}
+
+// Resolves the write as one that can be journaled if the database turns out to be unavailable
+// (see `replays`). Never rejects: requests that can't be journaled get `None`.
+fn replay_context(
+    env: Environment,
+) -> impl Filter<Extract = (Option<ReplayContext>,), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::headers_cloned())
+        .and(with_env(env))
+        .and_then(
+            |method: Method,
+             path: FullPath,
+             query: String,
+             headers: HeaderMap,
+             env: Environment| async move {
+                if !env.replays.is_enabled()
+                    || !replays::is_journaled(&method, path.as_str())
+                    || env.replays.is_replay(&headers)
+                {
+                    return Ok::<_, Rejection>(None);
+                }
+                let idempotency_key = match replays::idempotency_key(&headers) {
+                    Some(idempotency_key) => idempotency_key,
+                    None => return Ok(None),
+                };
+                let api_key = headers
+                    .get("api-key")
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| Uuid::parse_str(value).ok());
+                if api_key.is_none() {
+                    return Ok(None);
+                }
+                let mut path = path.as_str().to_string();
+                if !query.is_empty() {
+                    path = format!("{}?{}", path, query);
+                }
+                match handlers::authenticate(&env, api_key).await {
+                    Ok(owner_id) => Ok(Some(ReplayContext::new(
+                        env.replays.clone(),
+                        owner_id,
+                        idempotency_key,
+                        method,
+                        path,
+                        &headers,
+                        env.api_url.clone(),
+                    ))),
+                    Err(_) => Ok(None),
+                }
+            },
+        )
+}
+
+// Journals writes from owners who still have writes waiting to be replayed instead of applying
+// them, so that an owner's writes are applied in the order they were made. Rejects every other
+// request before reading its body, leaving it to the routes.
+fn divert_to_replay_journal(
+    env: Environment,
+) -> impl Filter<Extract = (warp::reply::Response,), Error = Rejection> + Clone {
+    replay_context(env)
+        .and_then(|context: Option<ReplayContext>| async move {
+            match context {
+                Some(context) if context.journal.has_pending(context.owner_id) => Ok(context),
+                _ => Err(warp::reject::not_found()),
+            }
+        })
+        .and(warp::header::optional::<u64>("content-length"))
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and(warp::body::stream())
+        .and_then(
+            |context: ReplayContext,
+             content_length: Option<u64>,
+             transfer_encoding: Option<String>,
+             body| async move {
+                let declared = content_length.filter(|_| transfer_encoding.is_none());
+                let journaled = match body_length::read(body, declared).await {
+                    Ok(bytes) => context.journal(bytes),
+                    Err(error) => Err(error),
+                };
+                // Answered here rather than rejected: the body is gone, so no other route could
+                // handle the request.
+                match journaled {
+                    Ok(response) => Ok(response),
+                    Err(error) => problem::unpack_problem(reject_anyhow(error))
+                        .await
+                        .map(Reply::into_response),
+                }
+            },
+        )
+}
+
+fn extract_body_bytes(
+    env: Environment,
+) -> impl Filter<Extract = (Bytes,), Error = warp::Rejection> + Clone {
+    let bincode_require_content_length = env.config.bincode_require_content_length;
+    warp::header::optional::<u64>("content-length")
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and(warp::header::optional::<Mime>("content-type"))
+        .and_then(
+            move |content_length: Option<u64>,
+                  transfer_encoding: Option<String>,
+                  content_type: Option<Mime>| async move {
+                // A Transfer-Encoding overrides any Content-Length sent alongside it.
+                let declared = content_length.filter(|_| transfer_encoding.is_none());
+                let is_bincode = content_type == Some(mime::APPLICATION_OCTET_STREAM);
+                body_length::check_declared(declared, is_bincode, bincode_require_content_length)
+                    .map_err(reject_anyhow)?;
+                Ok::<_, Rejection>(declared)
+            },
+        )
+        .and(warp::body::stream())
+        .and_then(|declared: Option<u64>, body| async move {
+            body_length::read(body, declared)
+                .await
+                .map_err(reject_anyhow)
+        })
+        .and(warp::header::optional::<String>(
+            body_digest::CONTENT_DIGEST,
+        ))
+        .and(warp::header::optional::<String>(body_digest::BODY_SHA256))
+        .and(capture_context(env.clone()))
+        .and(replay_context(env))
+        .and_then(
+            |bytes: Bytes,
+             content_digest: Option<String>,
+             body_sha256: Option<String>,
+             context: Option<CaptureContext>,
+             replay_context: Option<ReplayContext>| async move {
+                if let Some(context) = context {
+                    context.record_request(&bytes).await;
+                }
+                if let Some(replay_context) = replay_context {
+                    replay_context.stash_body(&bytes);
+                }
+                body_digest::verify(&bytes, content_digest.as_deref(), body_sha256.as_deref())
+                    .map_err(reject_anyhow)?;
+                Ok::<_, Rejection>(bytes)
+            },
+        )
+        .and(schema_version())
+        .and(warp::header::optional::<Mime>("content-type"))
+        .map(
+            |bytes: Bytes, version: SchemaVersion, content_type: Option<Mime>| {
+                // Bincode bodies have no field names to translate.
+                if content_type == Some(mime::APPLICATION_OCTET_STREAM) {
+                    bytes
+                } else {
+                    version.upgrade(bytes)
+                }
+            },
+        )
+}
+
+// The `X-Schema-Version` of a request, version 1 when it isn't sent. See `handlers::SchemaVersion`.
+fn schema_version() -> impl Filter<Extract = (SchemaVersion,), Error = Rejection> + Clone {
+    warp::header::optional::<String>(SCHEMA_VERSION_HEADER).and_then(
+        |version: Option<String>| async move {
+            version
+                .map_or(Ok(SchemaVersion::default()), |version| version.parse())
+                .map_err(reject_anyhow)
+        },
+    )
+}
+
+// Records each response's route, status, and latency for `GET /v1/status/metrics`.
+fn record_metrics(
+    metrics: Arc<Metrics>,
+) -> warp::log::Log<impl Fn(warp::log::Info) + Clone + Send + Sync> {
+    warp::log::custom(move |info| {
+        metrics.record(info.method(), info.path(), info.status(), info.elapsed())
+    })
+}
+
+// Builds every route with problem recovery, capture recording, and replay journaling, but without
+// the compression, tracing, and metrics wrappers that `main` adds around it, so that tests can
+// drive it directly.
+fn routes(env: Environment) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let strict_query_params = env.config.strict_query_params;
 
     let status_handler = warp::path::path("status")
         .and(warp::path::end())
-        .and(warp::get())
-        .map(|| Response::builder().header(SERVER, SERVER_STRING).body("Ok"));
+        .and(handlers::get_or_head())
+        .and(with_env(env.clone()))
+        .and_then(handlers::status::get);
+    let status_metrics_handler = warp::path::path("status")
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(handlers::get_or_head())
+        .and(warp::query::<MetricsParams>())
+        .and(warp::header::optional("api-key"))
+        .and(with_env(env.clone()))
+        .and_then(handlers::status::metrics);
+    let cache_stats_handler = warp::path::path("cache")
+        .and(warp::path("stats"))
+        .and(warp::path::end())
+        .and(handlers::get_or_head())
+        .and(warp::header::optional("api-key"))
+        .and(with_env(env.clone()))
+        .and_then(handlers::status::cache_stats);
+    let get_settings_handler = warp::path("settings")
+        .and(warp::path::end())
+        .and(handlers::get_or_head())
+        .and(warp::header::optional("if-none-match"))
+        .and(warp::header::optional("accept"))
+        .and(with_env(env.clone()))
+        .and_then(handlers::settings::get);
     let get_owner_handler = warp::path("owners").and(
         warp::path::param()
             .and(warp::path::end())
-            .and(warp::get())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
             .and_then(handlers::owner::get),
     );
+    let get_owner_profile_handler = warp::path("owners").and(
+        warp::path::param()
+            .and(warp::path("profile"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::owner::get_profile),
+    );
+    let get_owner_usage_handler = warp::path("owners").and(
+        warp::path("me")
+            .and(warp::path("usage"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::owner::get_usage),
+    );
+    let get_owner_shop_template_handler = warp::path("owners").and(
+        warp::path("me")
+            .and(warp::path("shop_template"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::owner::get_shop_template),
+    );
+    let save_owner_shop_template_handler = warp::path("owners").and(
+        warp::path("me")
+            .and(warp::path("shop_template"))
+            .and(warp::path::end())
+            .and(warp::put())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::owner::save_shop_template),
+    );
+    let list_shop_summaries_handler = warp::path("owners").and(
+        warp::path("me")
+            .and(warp::path("shop_summaries"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(expensive(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_deadline(env.clone()))
+            .and(with_env(env.clone()))
+            .and_then(handlers::owner::shop_summaries),
+    );
+    let list_my_shop_requests_handler = warp::path("owners").and(
+        warp::path("me")
+            .and(warp::path("requests"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<ShopRequestListQuery>(
+                strict_query_params,
+            ))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_request::list_mine),
+    );
     let create_owner_handler = warp::path("owners").and(
         warp::path::end()
             .and(warp::post())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::addr::remote())
             .and(warp::header::optional("api-key"))
             .and(warp::header::optional("x-real-ip"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::owner::create),
     );
@@ -101,6 +643,7 @@ async fn main() -> Result<()> {
             .and(warp::path::end())
             .and(warp::delete())
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(with_env(env.clone()))
             .and_then(handlers::owner::delete),
     );
@@ -108,16 +651,18 @@ async fn main() -> Result<()> {
         warp::path::param()
             .and(warp::path::end())
             .and(warp::patch())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::owner::update),
     );
     let list_owners_handler = warp::path("owners").and(
         warp::path::end()
-            .and(warp::get())
-            .and(warp::query::<ListParams>())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<OwnerListQuery>(strict_query_params))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -126,18 +671,34 @@ async fn main() -> Result<()> {
     let get_shop_handler = warp::path("shops").and(
         warp::path::param()
             .and(warp::path::end())
-            .and(warp::get())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
+            .and(warp::header::optional("accept-language"))
             .and(with_env(env.clone()))
             .and_then(handlers::shop::get),
     );
+    let get_shop_by_slug_handler = warp::path("shops").and(
+        warp::path("slug")
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(warp::header::optional("accept-language"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::get_by_slug),
+    );
     let create_shop_handler = warp::path("shops").and(
         warp::path::end()
             .and(warp::post())
-            .and(extract_body_bytes())
+            .and(warp::query::<CreateShopParams>())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::shop::create),
     );
@@ -145,33 +706,48 @@ async fn main() -> Result<()> {
         warp::path::param()
             .and(warp::path::end())
             .and(warp::delete())
+            .and(warp::query::<DeleteShopParams>())
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(with_env(env.clone()))
             .and_then(handlers::shop::delete),
     );
+    let get_shop_deletion_preview_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("deletion_preview"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_deletion::preview),
+    );
     let update_shop_handler = warp::path("shops").and(
         warp::path::param()
             .and(warp::path::end())
             .and(warp::patch())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::shop::update),
     );
     let list_shops_handler = warp::path("shops").and(
         warp::path::end()
-            .and(warp::get())
-            .and(warp::query::<ListParams>())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<ShopListQuery>(strict_query_params))
+            .and(warp::header::optional("api-key"))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
+            .and(warp::header::optional("accept-language"))
             .and(with_env(env.clone()))
             .and_then(handlers::shop::list),
     );
     let get_interior_ref_list_handler = warp::path("interior_ref_lists").and(
         warp::path::param()
             .and(warp::path::end())
-            .and(warp::get())
+            .and(handlers::get_or_head())
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -180,9 +756,10 @@ async fn main() -> Result<()> {
     let create_interior_ref_list_handler = warp::path("interior_ref_lists").and(
         warp::path::end()
             .and(warp::post())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::interior_ref_list::create),
     );
@@ -191,6 +768,7 @@ async fn main() -> Result<()> {
             .and(warp::path::end())
             .and(warp::delete())
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(with_env(env.clone()))
             .and_then(handlers::interior_ref_list::delete),
     );
@@ -198,27 +776,59 @@ async fn main() -> Result<()> {
         warp::path::param()
             .and(warp::path::end())
             .and(warp::patch())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::interior_ref_list::update),
     );
+    let validate_interior_ref_list_by_shop_id_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("interior_ref_list"))
+            .and(warp::path("validate"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::interior_ref_list::validate_by_shop_id),
+    );
+    let create_interior_ref_list_by_shop_id_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("interior_ref_list"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
+            .and(warp::header::optional(TARGET_SHOP_HEADER))
+            .and(with_env(env.clone()))
+            .and_then(handlers::interior_ref_list::create_by_shop_id),
+    );
     let update_interior_ref_list_by_shop_id_handler = warp::path("shops").and(
         warp::path::param()
             .and(warp::path("interior_ref_list"))
             .and(warp::path::end())
             .and(warp::patch())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
+            .and(warp::header::optional(TARGET_SHOP_HEADER))
             .and(with_env(env.clone()))
             .and_then(handlers::interior_ref_list::update_by_shop_id),
     );
     let list_interior_ref_lists_handler = warp::path("interior_ref_lists").and(
         warp::path::end()
-            .and(warp::get())
-            .and(warp::query::<ListParams>())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<InteriorRefListQuery>(
+                strict_query_params,
+            ))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -228,7 +838,7 @@ async fn main() -> Result<()> {
         warp::path::param()
             .and(warp::path("interior_ref_list"))
             .and(warp::path::end())
-            .and(warp::get())
+            .and(handlers::get_or_head())
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -237,7 +847,7 @@ async fn main() -> Result<()> {
     let get_merchandise_list_handler = warp::path("merchandise_lists").and(
         warp::path::param()
             .and(warp::path::end())
-            .and(warp::get())
+            .and(handlers::get_or_head())
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -246,9 +856,10 @@ async fn main() -> Result<()> {
     let create_merchandise_list_handler = warp::path("merchandise_lists").and(
         warp::path::end()
             .and(warp::post())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::merchandise_list::create),
     );
@@ -257,6 +868,7 @@ async fn main() -> Result<()> {
             .and(warp::path::end())
             .and(warp::delete())
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(with_env(env.clone()))
             .and_then(handlers::merchandise_list::delete),
     );
@@ -264,27 +876,59 @@ async fn main() -> Result<()> {
         warp::path::param()
             .and(warp::path::end())
             .and(warp::patch())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::merchandise_list::update),
     );
+    let validate_merchandise_list_by_shop_id_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("merchandise_list"))
+            .and(warp::path("validate"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::merchandise_list::validate_by_shop_id),
+    );
+    let create_merchandise_list_by_shop_id_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("merchandise_list"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
+            .and(warp::header::optional(TARGET_SHOP_HEADER))
+            .and(with_env(env.clone()))
+            .and_then(handlers::merchandise_list::create_by_shop_id),
+    );
     let update_merchandise_list_by_shop_id_handler = warp::path("shops").and(
         warp::path::param()
             .and(warp::path("merchandise_list"))
             .and(warp::path::end())
             .and(warp::patch())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
+            .and(warp::header::optional(TARGET_SHOP_HEADER))
             .and(with_env(env.clone()))
             .and_then(handlers::merchandise_list::update_by_shop_id),
     );
     let list_merchandise_lists_handler = warp::path("merchandise_lists").and(
         warp::path::end()
-            .and(warp::get())
-            .and(warp::query::<ListParams>())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<MerchandiseListQuery>(
+                strict_query_params,
+            ))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -294,16 +938,28 @@ async fn main() -> Result<()> {
         warp::path::param()
             .and(warp::path("merchandise_list"))
             .and(warp::path::end())
-            .and(warp::get())
+            .and(handlers::get_or_head())
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
             .and_then(handlers::merchandise_list::get_by_shop_id),
     );
+    let get_merchandise_facets_by_shop_id_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("merchandise_list"))
+            .and(warp::path("facets"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(expensive(env.clone()))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::merchandise_list::facets_by_shop_id),
+    );
     let get_transaction_handler = warp::path("transactions").and(
         warp::path::param()
             .and(warp::path::end())
-            .and(warp::get())
+            .and(handlers::get_or_head())
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -312,9 +968,10 @@ async fn main() -> Result<()> {
     let create_transaction_handler = warp::path("transactions").and(
         warp::path::end()
             .and(warp::post())
-            .and(extract_body_bytes())
+            .and(extract_body_bytes(env.clone()))
             .and(warp::header::optional("api-key"))
             .and(warp::header::optional("content-type"))
+            .and(warp::header::optional("prefer"))
             .and(with_env(env.clone()))
             .and_then(handlers::transaction::create),
     );
@@ -328,8 +985,10 @@ async fn main() -> Result<()> {
     );
     let list_transactions_handler = warp::path("transactions").and(
         warp::path::end()
-            .and(warp::get())
-            .and(warp::query::<ListParams>())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<TransactionListQuery>(
+                strict_query_params,
+            ))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
@@ -339,83 +998,589 @@ async fn main() -> Result<()> {
         warp::path::param()
             .and(warp::path("transactions"))
             .and(warp::path::end())
-            .and(warp::get())
-            .and(warp::query::<ListParams>())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<TransactionListQuery>(
+                strict_query_params,
+            ))
             .and(warp::header::optional("if-none-match"))
             .and(warp::header::optional("accept"))
             .and(with_env(env.clone()))
             .and_then(handlers::transaction::list_by_shop_id),
     );
+    let list_shops_by_owner_id_handler = warp::path("owners").and(
+        warp::path::param()
+            .and(warp::path("shops"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<OwnerShopListQuery>(
+                strict_query_params,
+            ))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::list_by_owner_id),
+    );
+    let get_shop_gold_history_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("gold_history"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(expensive(env.clone()))
+            .and(warp::query::<GoldHistoryParams>())
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::gold_history),
+    );
+    let get_shop_card_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("card"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::addr::remote())
+            .and(warp::header::optional("x-real-ip"))
+            .and(warp::header::optional("if-none-match"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::card),
+    );
+    let rename_shop_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("rename"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::rename),
+    );
+    let visit_shop_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("visit"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::visit),
+    );
+    let create_shop_ban_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("bans"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::create_ban),
+    );
+    let delete_shop_ban_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("bans"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::delete())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::delete_ban),
+    );
+    let list_shop_reviews_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("reviews"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<ShopReviewListQuery>(
+                strict_query_params,
+            ))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_review::list_by_shop_id),
+    );
+    let create_shop_review_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("reviews"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_review::create),
+    );
+    let update_shop_review_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("reviews"))
+            .and(warp::path::end())
+            .and(warp::patch())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_review::update),
+    );
+    let delete_shop_review_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("reviews"))
+            .and(warp::path::end())
+            .and(warp::delete())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_review::delete),
+    );
+    let list_shop_requests_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("requests"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<ShopRequestListQuery>(
+                strict_query_params,
+            ))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_request::list_by_shop_id),
+    );
+    let create_shop_request_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("requests"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_request::create),
+    );
+    let update_shop_request_handler = warp::path("shop_requests").and(
+        warp::path::param()
+            .and(warp::path::end())
+            .and(warp::patch())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop_request::update_status),
+    );
+    let get_shop_notification_settings_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("notification_settings"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::get_notification_settings),
+    );
+    let update_shop_notification_settings_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("notification_settings"))
+            .and(warp::path::end())
+            .and(warp::patch())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("if-match"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::update_notification_settings),
+    );
+    let list_shop_translations_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("translations"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::list_translations),
+    );
+    let save_shop_translation_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("translations"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::put())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::save_translation),
+    );
+    let delete_shop_translation_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("translations"))
+            .and(warp::path::param())
+            .and(warp::path::end())
+            .and(warp::delete())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::shop::delete_translation),
+    );
+    let get_replay_handler = warp::path("replays").and(
+        warp::path::param()
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::replay::get),
+    );
+    let list_merchandise_changes_by_shop_id_handler = warp::path("shops").and(
+        warp::path::param()
+            .and(warp::path("merchandise_changes"))
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(extract_list_query::<MerchandiseChangeListQuery>(
+                strict_query_params,
+            ))
+            .and(warp::header::optional("if-none-match"))
+            .and(warp::header::optional("accept"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::merchandise_change::list_by_shop_id),
+    );
 
-    let routes = warp::path("v1")
-        .and(balanced_or_tree!(
-            status_handler,
-            get_owner_handler,
-            delete_owner_handler,
-            update_owner_handler,
-            create_owner_handler,
-            list_owners_handler,
-            get_shop_handler,
-            delete_shop_handler,
-            update_shop_handler,
-            create_shop_handler,
-            list_shops_handler,
-            get_interior_ref_list_by_shop_id_handler,
-            get_merchandise_list_by_shop_id_handler,
-            update_interior_ref_list_by_shop_id_handler,
-            update_merchandise_list_by_shop_id_handler,
-            list_transactions_by_shop_id_handler,
-            get_interior_ref_list_handler,
-            delete_interior_ref_list_handler,
-            update_interior_ref_list_handler,
-            create_interior_ref_list_handler,
-            list_interior_ref_lists_handler,
-            get_merchandise_list_handler,
-            delete_merchandise_list_handler,
-            update_merchandise_list_handler,
-            create_merchandise_list_handler,
-            list_merchandise_lists_handler,
-            get_transaction_handler,
-            delete_transaction_handler,
-            create_transaction_handler,
-            list_transactions_handler,
-            // warp::any().map(|| StatusCode::NOT_FOUND),
-        ))
-        .recover(problem::unpack_problem)
-        .with(warp::compression::gzip())
-        .with(warp::trace::request());
+    let create_capture_handler = warp::path("admin").and(warp::path("captures")).and(
+        warp::path::param()
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::create_capture),
+    );
+    let get_capture_handler = warp::path("admin").and(warp::path("captures")).and(
+        warp::path::param()
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::get_capture),
+    );
+    let delete_capture_handler = warp::path("admin").and(warp::path("captures")).and(
+        warp::path::param()
+            .and(warp::path::end())
+            .and(warp::delete())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::delete_capture),
+    );
+    let reconcile_shop_handler = warp::path("admin").and(warp::path("shops")).and(
+        warp::path::param()
+            .and(warp::path("reconcile"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::query::<ReconcileParams>())
+            .and(warp::header::optional("api-key"))
+            .and(with_deadline(env.clone()))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::reconcile_shop),
+    );
+    let get_maintenance_handler = warp::path("admin").and(
+        warp::path("maintenance")
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::get_maintenance),
+    );
+    let update_maintenance_handler = warp::path("admin").and(
+        warp::path("maintenance")
+            .and(warp::path::end())
+            .and(warp::patch())
+            .and(extract_body_bytes(env.clone()))
+            .and(warp::header::optional("api-key"))
+            .and(warp::header::optional("content-type"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::update_maintenance),
+    );
+    let get_config_handler = warp::path("admin").and(
+        warp::path("config")
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::get_config),
+    );
+    let export_handler = warp::path("admin").and(
+        warp::path("export")
+            .and(warp::path::end())
+            .and(handlers::get_or_head())
+            .and(warp::header::optional("api-key"))
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::export),
+    );
+    let import_handler = warp::path("admin").and(
+        warp::path("import")
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::query::<ImportParams>())
+            .and(warp::header::optional("api-key"))
+            .and(warp::body::stream())
+            .and(with_env(env.clone()))
+            .and_then(handlers::admin::import),
+    );
+    capture_context(env.clone())
+        .and(replay_context(env.clone()))
+        .and(warp::method())
+        .and(
+            normalize_path()
+                .or(warp::path("v1")
+                    .and(json_format::extract())
+                    .and(warp::header::optional("if-none-match"))
+                    .and(
+                        schema_version()
+                            .and(with_env(env.clone()))
+                            .and(
+                                status_handler
+                                    .or(status_metrics_handler)
+                                    .or(cache_stats_handler)
+                                    .or(get_maintenance_handler)
+                                    .or(update_maintenance_handler)
+                                    .or(get_config_handler)
+                                    // Not held back by maintenance mode, which is how an operator
+                                    // would keep clients out during a migration.
+                                    .or(require_valid_schema(env.clone())
+                                        .and(export_handler.or(import_handler)))
+                                    .or(require_valid_schema(env.clone())
+                                        .and(require_available(env.clone()))
+                                        .and(track_usage(env.clone()))
+                                        .and(balanced_or_tree!(
+                                            // First, so that no route applies a write that
+                                            // belongs behind the owner's journaled ones.
+                                            divert_to_replay_journal(env.clone()),
+                                            get_owner_handler,
+                                            get_owner_profile_handler,
+                                            get_owner_usage_handler,
+                                            get_owner_shop_template_handler,
+                                            save_owner_shop_template_handler,
+                                            list_shop_summaries_handler,
+                                            list_shops_by_owner_id_handler,
+                                            delete_owner_handler,
+                                            update_owner_handler,
+                                            create_owner_handler,
+                                            list_owners_handler,
+                                            get_shop_handler,
+                                            get_shop_by_slug_handler,
+                                            delete_shop_handler,
+                                            update_shop_handler,
+                                            create_shop_handler,
+                                            list_shops_handler,
+                                            get_interior_ref_list_by_shop_id_handler,
+                                            get_merchandise_list_by_shop_id_handler,
+                                            get_merchandise_facets_by_shop_id_handler,
+                                            create_interior_ref_list_by_shop_id_handler,
+                                            update_interior_ref_list_by_shop_id_handler,
+                                            validate_interior_ref_list_by_shop_id_handler,
+                                            create_merchandise_list_by_shop_id_handler,
+                                            update_merchandise_list_by_shop_id_handler,
+                                            validate_merchandise_list_by_shop_id_handler,
+                                            list_transactions_by_shop_id_handler,
+                                            list_merchandise_changes_by_shop_id_handler,
+                                            get_shop_gold_history_handler,
+                                            get_shop_card_handler,
+                                            get_shop_deletion_preview_handler,
+                                            rename_shop_handler,
+                                            visit_shop_handler,
+                                            create_shop_ban_handler,
+                                            delete_shop_ban_handler,
+                                            list_shop_reviews_handler,
+                                            create_shop_review_handler,
+                                            update_shop_review_handler,
+                                            delete_shop_review_handler,
+                                            list_shop_requests_handler,
+                                            create_shop_request_handler,
+                                            update_shop_request_handler,
+                                            list_my_shop_requests_handler,
+                                            get_shop_notification_settings_handler,
+                                            update_shop_notification_settings_handler,
+                                            list_shop_translations_handler,
+                                            save_shop_translation_handler,
+                                            delete_shop_translation_handler,
+                                            get_replay_handler,
+                                            get_settings_handler,
+                                            get_interior_ref_list_handler,
+                                            delete_interior_ref_list_handler,
+                                            update_interior_ref_list_handler,
+                                            create_interior_ref_list_handler,
+                                            list_interior_ref_lists_handler,
+                                            get_merchandise_list_handler,
+                                            delete_merchandise_list_handler,
+                                            update_merchandise_list_handler,
+                                            create_merchandise_list_handler,
+                                            list_merchandise_lists_handler,
+                                            get_transaction_handler,
+                                            delete_transaction_handler,
+                                            create_transaction_handler,
+                                            list_transactions_handler,
+                                            create_capture_handler,
+                                            get_capture_handler,
+                                            delete_capture_handler,
+                                            reconcile_shop_handler,
+                                            // warp::any().map(|| StatusCode::NOT_FOUND),
+                                        ))),
+                            )
+                            .and_then(handlers::translate_reply),
+                    )
+                    .and_then(json_format::apply))
+                .recover(problem::unpack_problem),
+        )
+        .and_then(finish_response)
+}
 
-    if let Ok(tls_cert) = env::var("TLS_CERT") {
-        if let Ok(tls_key) = env::var("TLS_KEY") {
-            let port = env::var("PORT")
-                .unwrap_or_else(|_| "443".to_owned())
-                .parse()?;
-            warp::serve(routes)
-                .tls()
-                .cert_path(tls_cert)
-                .key_path(tls_key)
-                .run(([0, 0, 0, 0], port))
-                .await;
-            return Ok(());
+// Journals the write if it failed because the database was unavailable, drops the body of a
+// response to a `HEAD`, then records the final response if its owner is being captured, and stamps
+// it with `X-Server-Time`.
+async fn finish_response(
+    capture_context: Option<CaptureContext>,
+    replay_context: Option<ReplayContext>,
+    method: Method,
+    reply: impl Reply,
+) -> Result<warp::reply::Response, Rejection> {
+    let response = replays::respond(replay_context, reply).await?;
+    let response = handlers::without_head_body(&method, response);
+    let response = captures::record_response(capture_context, response).await?;
+    Ok(clock::with_server_time(response))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    openssl_probe::init_ssl_cert_env_vars();
+    dotenv().ok();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config_check = match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => false,
+        ["config", "check"] => true,
+        _ => {
+            return Err(anyhow!(
+                "Unknown arguments: {}. Usage: bazaar_realm_api [config check]",
+                args.join(" ")
+            ))
         }
+    };
+    let config = Config::from_env()?;
+    if config_check {
+        print!("{}", config);
+        return Ok(());
     }
+    let config = Arc::new(config);
+
+    let (non_blocking_writer, _guard) = tracing_appender::non_blocking(std::io::stdout());
+    tracing_subscriber::fmt()
+        .with_env_filter(config.rust_log.as_str())
+        .with_span_events(FmtSpan::CLOSE)
+        .with_writer(non_blocking_writer)
+        .init();
 
-    let svc = warp::service(routes);
-    let make_svc = hyper::service::make_service_fn(|_: _| {
-        let svc = svc.clone();
-        async move { Ok::<_, Infallible>(svc) }
-    });
+    let mut env = Environment::new(config.clone()).await?;
 
-    let mut listenfd = ListenFd::from_env();
-    let server = if let Some(l) = listenfd.take_tcp_listener(0)? {
-        Server::from_tcp(l)?
+    if config.migrate_on_startup {
+        migrations::run(
+            &env.db,
+            &migrations::MIGRATOR,
+            migrations::MIGRATION_LOCK_KEY,
+            config.migration_lock_timeout,
+        )
+        .await?;
     } else {
-        let port = env::var("PORT")
-            .unwrap_or_else(|_| "3030".to_owned())
-            .parse()?;
-        Server::bind(&([0, 0, 0, 0], port).into())
-    };
+        migrations::verify(&env.db, &migrations::MIGRATOR).await?;
+    }
+    env.schema_status = Arc::new(schema::check(&env.db).await);
+    if env.schema_status.ok {
+        let period_start = usage::period_start(Utc::now().naive_utc());
+        env.usage
+            .load(OwnerRequestUsage::list_by_period(&env.db, period_start).await?);
+    }
+    info!(
+        config = %serde_json::to_string(&config.snapshot(
+            env.schema_status.migration_version,
+            env.maintenance.mode().await,
+            &env.caches,
+        ))?,
+        "starting with configuration"
+    );
 
-    server.serve(make_svc).await?;
+    jobs::spawn_merchandise_changes_pruner(
+        env.db.clone(),
+        config.merchandise_changes_retention_days,
+    );
+    jobs::spawn_shop_gold_snapshots(env.db.clone(), env.caches.clone());
+    jobs::spawn_usage_flusher(env.db.clone(), env.usage.clone());
+    jobs::spawn_invalidation_worker(env.caches.clone());
+    if env.replays.is_enabled() {
+        jobs::spawn_replayer(
+            env.replays.clone(),
+            env.db.clone(),
+            warp::service(routes(env.clone())),
+        );
+    }
+    if let Some(url) = &config.anomaly_webhook_url {
+        anomalies::set_webhook(url, &env.tasks);
+    }
+    metrics::queries::set_slow_threshold(config.slow_query_threshold);
+
+    let metrics = env.metrics.clone();
+    let tasks = env.tasks.clone();
+    let routes = routes(env)
+        .with(warp::compression::gzip())
+        .with(warp::trace::request())
+        .with(record_metrics(metrics));
+
+    // Both listeners stop accepting on a shutdown signal and let open connections finish the
+    // requests they are serving before returning.
+    let shutdown = shutdown_signal()?;
+    let serve = async {
+        if let Some(tls) = &config.tls {
+            let tls_config = Arc::new(tls::ReloadableTlsConfig::new(&tls.cert, &tls.key)?);
+            tls_config.spawn_watchers(tls.reload_interval);
+            let listener = TcpListener::bind(("0.0.0.0", config.listen_port())).await?;
+            return tls::serve(
+                listener,
+                tls_config,
+                config.connections.clone(),
+                warp::service(routes),
+                shutdown,
+            )
+            .await;
+        }
+
+        let mut listenfd = ListenFd::from_env();
+        let listener = if let Some(l) = listenfd.take_tcp_listener(0)? {
+            l.set_nonblocking(true)?;
+            TcpListener::from_std(l)?
+        } else {
+            TcpListener::bind(("0.0.0.0", config.listen_port())).await?
+        };
+        connections::serve(
+            listener,
+            connections::plaintext_http(config.enable_h2c),
+            config.connections.clone(),
+            warp::service(routes),
+            shutdown,
+        )
+        .await
+    };
+    serve.await?;
+    // Let cache fills and webhook posts that are already running finish before exiting.
+    tasks.drain(SHUTDOWN_DRAIN_TIMEOUT).await;
     Ok(())
 }
+
+/// Resolves on Ctrl-C or SIGTERM (what `docker stop` and systemd send).
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+        info!("shutting down");
+    })
+}
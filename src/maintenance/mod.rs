@@ -0,0 +1,86 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::info;
+
+/// How much of the API is available, e.g. while running schema migrations.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceMode {
+    #[default]
+    Off,
+    /// Reads are served, but every POST, PUT, PATCH, and DELETE responds with a 503.
+    ReadOnly,
+    /// Everything except the status and maintenance endpoints responds with a 503.
+    Full,
+}
+
+impl MaintenanceMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaintenanceMode::Off => "off",
+            MaintenanceMode::ReadOnly => "read_only",
+            MaintenanceMode::Full => "full",
+        }
+    }
+
+    pub fn allows(&self, method: &Method) -> bool {
+        match self {
+            MaintenanceMode::Off => true,
+            MaintenanceMode::ReadOnly => {
+                matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            }
+            MaintenanceMode::Full => false,
+        }
+    }
+}
+
+impl fmt::Display for MaintenanceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for MaintenanceMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(MaintenanceMode::Off),
+            "read_only" => Ok(MaintenanceMode::ReadOnly),
+            "full" => Ok(MaintenanceMode::Full),
+            _ => Err(anyhow!("must be one of: off, read_only, full")),
+        }
+    }
+}
+
+/// The current maintenance mode, starting from `MAINTENANCE_MODE` and changeable at runtime
+/// through `PATCH /v1/admin/maintenance`.
+#[derive(Debug)]
+pub struct Maintenance {
+    mode: RwLock<MaintenanceMode>,
+}
+
+impl Maintenance {
+    pub fn new(mode: MaintenanceMode) -> Self {
+        Self {
+            mode: RwLock::new(mode),
+        }
+    }
+
+    pub async fn mode(&self) -> MaintenanceMode {
+        *self.mode.read().await
+    }
+
+    pub async fn set_mode(&self, mode: MaintenanceMode) {
+        let mut current = self.mode.write().await;
+        if *current != mode {
+            info!(from = %*current, to = %mode, "changed maintenance mode");
+            *current = mode;
+        }
+    }
+}
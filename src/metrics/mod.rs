@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use hdrhistogram::Histogram;
+use http::{Method, StatusCode};
+use serde::Serialize;
+
+pub mod queries;
+
+/// Latencies above this are recorded as this value.
+const MAX_TRACKED_LATENCY_MICROS: u64 = 60 * 1_000_000;
+/// Once this many distinct routes have been seen, further routes are tracked under `OTHER_ROUTE`
+/// so that scanners requesting random paths can't grow the map without bound.
+const MAX_ROUTES: usize = 200;
+const OTHER_ROUTE: &str = "(other)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteKind {
+    Read,
+    Write,
+}
+
+impl RouteKind {
+    pub fn from_method(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => RouteKind::Read,
+            _ => RouteKind::Write,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RouteKey {
+    method: Method,
+    route: String,
+}
+
+#[derive(Debug)]
+struct RouteMetrics {
+    count: u64,
+    client_errors: u64,
+    server_errors: u64,
+    latency_micros: Histogram<u64>,
+}
+
+impl RouteMetrics {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            client_errors: 0,
+            server_errors: 0,
+            latency_micros: Histogram::new_with_bounds(1, MAX_TRACKED_LATENCY_MICROS, 3)
+                .expect("latency histogram bounds are valid"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct KindTotals {
+    pub count: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouteSnapshot {
+    pub method: String,
+    pub route: String,
+    pub kind: RouteKind,
+    pub count: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub since: NaiveDateTime,
+    pub read: KindTotals,
+    pub write: KindTotals,
+    pub routes: Vec<RouteSnapshot>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    since: NaiveDateTime,
+    routes: HashMap<RouteKey, RouteMetrics>,
+}
+
+/// In-process per-route request counters and latency histograms, recorded by a wrapping filter
+/// around all routes and served by `GET /v1/status/metrics`.
+#[derive(Debug)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                since: Utc::now().naive_utc(),
+                routes: HashMap::new(),
+            }),
+        }
+    }
+}
+
+/// Collapses numeric path segments so that e.g. `/v1/shops/1/merchandise_list` and
+/// `/v1/shops/2/merchandise_list` are tracked as one `/v1/shops/{id}/merchandise_list` route.
+fn route_template(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if !segment.is_empty() && segment.bytes().all(|byte| byte.is_ascii_digit()) {
+                "{id}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn millis(micros: u64) -> f64 {
+    micros as f64 / 1000.0
+}
+
+impl Metrics {
+    pub fn record(&self, method: &Method, path: &str, status: StatusCode, elapsed: Duration) {
+        let mut inner = self.inner.lock().expect("metrics lock poisoned");
+        let mut key = RouteKey {
+            method: method.clone(),
+            route: route_template(path),
+        };
+        if !inner.routes.contains_key(&key) && inner.routes.len() >= MAX_ROUTES {
+            key.route = OTHER_ROUTE.to_string();
+        }
+        let route = inner.routes.entry(key).or_insert_with(RouteMetrics::new);
+        route.count += 1;
+        if status.is_client_error() {
+            route.client_errors += 1;
+        } else if status.is_server_error() {
+            route.server_errors += 1;
+        }
+        route
+            .latency_micros
+            .saturating_record(elapsed.as_micros() as u64);
+    }
+
+    /// Returns the metrics recorded since startup or the last reset, optionally resetting them.
+    pub fn snapshot(&self, reset: bool) -> MetricsSnapshot {
+        let mut inner = self.inner.lock().expect("metrics lock poisoned");
+        let mut read = KindTotals::default();
+        let mut write = KindTotals::default();
+        let mut routes: Vec<RouteSnapshot> = inner
+            .routes
+            .iter()
+            .map(|(key, route)| {
+                let kind = RouteKind::from_method(&key.method);
+                let totals = match kind {
+                    RouteKind::Read => &mut read,
+                    RouteKind::Write => &mut write,
+                };
+                totals.count += route.count;
+                totals.client_errors += route.client_errors;
+                totals.server_errors += route.server_errors;
+                RouteSnapshot {
+                    method: key.method.to_string(),
+                    route: key.route.clone(),
+                    kind,
+                    count: route.count,
+                    client_errors: route.client_errors,
+                    server_errors: route.server_errors,
+                    p50_ms: millis(route.latency_micros.value_at_quantile(0.5)),
+                    p95_ms: millis(route.latency_micros.value_at_quantile(0.95)),
+                    p99_ms: millis(route.latency_micros.value_at_quantile(0.99)),
+                    max_ms: millis(route.latency_micros.max()),
+                }
+            })
+            .collect();
+        routes.sort_by(|a, b| a.route.cmp(&b.route).then(a.method.cmp(&b.method)));
+        let snapshot = MetricsSnapshot {
+            since: inner.since,
+            read,
+            write,
+            routes,
+        };
+        if reset {
+            inner.since = Utc::now().naive_utc();
+            inner.routes.clear();
+        }
+        snapshot
+    }
+}
@@ -0,0 +1,291 @@
+//! Latency histograms of model queries, per model and operation (e.g. `shop.get`), served by
+//! `GET /v1/status/metrics` next to the per-route ones. Comparing the two shows whether a slow
+//! route is waiting on Postgres or on everything around it.
+//!
+//! Models have no `Environment`, so like `anomalies` the histograms live in a static.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{millis, MAX_TRACKED_LATENCY_MICROS};
+use crate::models::{FormId, Locale};
+
+pub const DEFAULT_SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(500);
+
+// The queries made on the current task, for tests that compare how many queries routes make. The
+// histograms are shared by every test running at once, so they can't be used for that.
+#[cfg(test)]
+tokio::task_local! {
+    static COUNTED: std::cell::Cell<usize>;
+}
+
+/// Runs `future`, returning its output and how many model queries it made on its own task.
+#[cfg(test)]
+pub async fn count_queries<F: std::future::Future>(future: F) -> (F::Output, usize) {
+    COUNTED
+        .scope(std::cell::Cell::new(0), async {
+            let output = future.await;
+            (output, COUNTED.with(std::cell::Cell::get))
+        })
+        .await
+}
+
+/// The shape of a bound parameter, logged with slow queries in place of its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamShape {
+    Null,
+    Bool,
+    Int,
+    Uuid,
+    Time,
+    Text { len: usize },
+    List { len: usize },
+}
+
+impl fmt::Display for ParamShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamShape::Null => write!(f, "null"),
+            ParamShape::Bool => write!(f, "bool"),
+            ParamShape::Int => write!(f, "int"),
+            ParamShape::Uuid => write!(f, "uuid"),
+            ParamShape::Time => write!(f, "time"),
+            ParamShape::Text { len } => write!(f, "text({})", len),
+            ParamShape::List { len } => write!(f, "list({})", len),
+        }
+    }
+}
+
+pub trait Shape {
+    fn shape(&self) -> ParamShape;
+}
+
+macro_rules! impl_shape {
+    ($shape:expr => $($ty:ty),+) => {
+        $(impl Shape for $ty {
+            fn shape(&self) -> ParamShape {
+                $shape
+            }
+        })+
+    };
+}
+
+impl_shape!(ParamShape::Bool => bool);
+impl_shape!(ParamShape::Int => i32, i64, u32, FormId);
+impl_shape!(ParamShape::Uuid => Uuid);
+impl_shape!(ParamShape::Time => NaiveDateTime, NaiveDate);
+
+impl Shape for str {
+    fn shape(&self) -> ParamShape {
+        ParamShape::Text { len: self.len() }
+    }
+}
+
+impl Shape for Locale {
+    fn shape(&self) -> ParamShape {
+        self.as_str().shape()
+    }
+}
+
+impl<T> Shape for [T] {
+    fn shape(&self) -> ParamShape {
+        ParamShape::List { len: self.len() }
+    }
+}
+
+impl<T: Shape> Shape for Option<T> {
+    fn shape(&self) -> ParamShape {
+        self.as_ref().map_or(ParamShape::Null, Shape::shape)
+    }
+}
+
+impl<T: Shape + ?Sized> Shape for &T {
+    fn shape(&self) -> ParamShape {
+        (**self).shape()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct QueryKey {
+    model: &'static str,
+    operation: &'static str,
+}
+
+#[derive(Debug)]
+struct QueryMetrics {
+    slow: u64,
+    latency_micros: Histogram<u64>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    since: NaiveDateTime,
+    queries: HashMap<QueryKey, QueryMetrics>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuerySnapshot {
+    pub model: &'static str,
+    pub operation: &'static str,
+    pub count: u64,
+    /// Queries that took at least `SLOW_QUERY_THRESHOLD_MS`, which are also logged.
+    pub slow: u64,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueriesSnapshot {
+    pub since: NaiveDateTime,
+    pub slow_threshold_ms: f64,
+    pub queries: Vec<QuerySnapshot>,
+}
+
+static SLOW_THRESHOLD_MICROS: AtomicU64 =
+    AtomicU64::new(DEFAULT_SLOW_QUERY_THRESHOLD.as_micros() as u64);
+static INNER: OnceLock<Mutex<Inner>> = OnceLock::new();
+
+fn inner() -> &'static Mutex<Inner> {
+    INNER.get_or_init(|| {
+        Mutex::new(Inner {
+            since: Utc::now().naive_utc(),
+            queries: HashMap::new(),
+        })
+    })
+}
+
+/// Queries that take at least `threshold` are logged at warn level from now on.
+pub fn set_slow_threshold(threshold: Duration) {
+    SLOW_THRESHOLD_MICROS.store(threshold.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Starts timing a model's query, which is recorded when the returned timer is dropped. Hold it
+/// for the whole model function, e.g. `let _timer = time_query("shop", "get", [("id", id.shape())]);`,
+/// so that a query that fails or is cancelled by a request timeout is still counted.
+pub fn time_query<const N: usize>(
+    model: &'static str,
+    operation: &'static str,
+    params: [(&'static str, ParamShape); N],
+) -> QueryTimer<N> {
+    #[cfg(test)]
+    let _ = COUNTED.try_with(|counted| counted.set(counted.get() + 1));
+    QueryTimer {
+        key: QueryKey { model, operation },
+        params,
+        started: Instant::now(),
+    }
+}
+
+pub struct QueryTimer<const N: usize> {
+    key: QueryKey,
+    params: [(&'static str, ParamShape); N],
+    started: Instant,
+}
+
+impl<const N: usize> Drop for QueryTimer<N> {
+    fn drop(&mut self) {
+        let micros = self.started.elapsed().as_micros() as u64;
+        let is_slow = micros >= SLOW_THRESHOLD_MICROS.load(Ordering::Relaxed);
+        if is_slow {
+            let params = self
+                .params
+                .iter()
+                .map(|(name, shape)| format!("{}: {}", name, shape))
+                .collect::<Vec<_>>()
+                .join(", ");
+            warn!(
+                model = self.key.model,
+                operation = self.key.operation,
+                elapsed_ms = micros / 1000,
+                params = %params,
+                "slow query"
+            );
+        }
+        record(self.key, micros, is_slow);
+    }
+}
+
+fn record(key: QueryKey, micros: u64, is_slow: bool) {
+    let mut inner = inner().lock().expect("query metrics lock poisoned");
+    let query = inner.queries.entry(key).or_insert_with(|| QueryMetrics {
+        slow: 0,
+        latency_micros: Histogram::new_with_bounds(1, MAX_TRACKED_LATENCY_MICROS, 3)
+            .expect("latency histogram bounds are valid"),
+    });
+    if is_slow {
+        query.slow += 1;
+    }
+    query.latency_micros.saturating_record(micros);
+}
+
+/// Returns the query metrics recorded since startup or the last reset, optionally resetting them.
+pub fn snapshot(reset: bool) -> QueriesSnapshot {
+    let mut inner = inner().lock().expect("query metrics lock poisoned");
+    let mut queries: Vec<QuerySnapshot> = inner
+        .queries
+        .iter()
+        .map(|(key, query)| QuerySnapshot {
+            model: key.model,
+            operation: key.operation,
+            count: query.latency_micros.len(),
+            slow: query.slow,
+            p50_ms: millis(query.latency_micros.value_at_quantile(0.5)),
+            p95_ms: millis(query.latency_micros.value_at_quantile(0.95)),
+            p99_ms: millis(query.latency_micros.value_at_quantile(0.99)),
+            max_ms: millis(query.latency_micros.max()),
+        })
+        .collect();
+    queries.sort_by(|a, b| a.model.cmp(b.model).then(a.operation.cmp(b.operation)));
+    let snapshot = QueriesSnapshot {
+        since: inner.since,
+        slow_threshold_ms: millis(SLOW_THRESHOLD_MICROS.load(Ordering::Relaxed)),
+        queries,
+    };
+    if reset {
+        inner.since = Utc::now().naive_utc();
+        inner.queries.clear();
+    }
+    snapshot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{snapshot, time_query, ParamShape, Shape};
+
+    #[test]
+    fn shapes_leave_out_values() {
+        assert_eq!("secret".shape(), ParamShape::Text { len: 6 });
+        assert_eq!(Some(7).shape(), ParamShape::Int);
+        assert_eq!(None::<&str>.shape(), ParamShape::Null);
+        assert_eq!([1, 2, 3][..].shape(), ParamShape::List { len: 3 });
+        assert_eq!(ParamShape::Text { len: 6 }.to_string(), "text(6)");
+    }
+
+    #[test]
+    fn timers_record_when_dropped() {
+        let count = || {
+            snapshot(false)
+                .queries
+                .iter()
+                .find(|query| query.model == "test" && query.operation == "timed")
+                .map_or(0, |query| query.count)
+        };
+        let before = count();
+        {
+            let _timer = time_query("test", "timed", [("id", 1.shape())]);
+            assert_eq!(count(), before);
+        }
+        assert_eq!(count(), before + 1);
+    }
+}
@@ -0,0 +1,385 @@
+//! Runs the migrations in `db/migrations` at startup, one instance at a time.
+//!
+//! `Migrator::run` waits on its advisory lock without a timeout and applies migrations without
+//! logging anything, so instances deployed together either hang or start silently. This takes its
+//! own advisory lock, gives up after `MIGRATION_LOCK_TIMEOUT_SECS`, and logs each migration it
+//! applies. With `MIGRATE_ON_STARTUP=false` the migrations are only checked, for operators who run
+//! them out of band.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use sqlx::migrate::{Migrate, Migration, Migrator};
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::PgPool;
+use sqlx::Postgres;
+use tracing::{info, info_span, warn};
+use tracing_futures::Instrument;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("db/migrations");
+
+/// Held by whichever instance is migrating. Any fixed key works as long as nothing else in the
+/// database uses it.
+pub const MIGRATION_LOCK_KEY: i64 = 0x6261_7a61_6172_6d67;
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const LOCK_WAIT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Applies every migration newer than the database's version while holding the migration lock.
+/// Fails if the lock isn't acquired within `lock_timeout`.
+pub async fn run(
+    db: &PgPool,
+    migrator: &Migrator,
+    lock_key: i64,
+    lock_timeout: Duration,
+) -> Result<()> {
+    let mut conn = db.acquire().await?;
+    lock(&mut conn, lock_key, lock_timeout).await?;
+    let result = apply_pending(&mut conn, migrator).await;
+    // The lock belongs to the connection, which goes back to the pool, so it is released even
+    // when a migration failed.
+    sqlx::query("SELECT pg_advisory_unlock($1)")
+        .bind(lock_key)
+        .execute(&mut conn)
+        .await?;
+    result
+}
+
+/// Checks that every migration has been applied, without applying any. Fails with the list of
+/// pending migrations otherwise.
+pub async fn verify(db: &PgPool, migrator: &Migrator) -> Result<()> {
+    let mut conn = db.acquire().await?;
+    let version = applied_version(&mut conn).await?;
+    let pending = pending(migrator, version);
+    if !pending.is_empty() {
+        return Err(anyhow!(
+            "MIGRATE_ON_STARTUP is off but the database (at version {}) is missing {} \
+            migration(s): {}",
+            version,
+            pending.len(),
+            describe(&pending)
+        ));
+    }
+    for migration in migrator.iter() {
+        conn.validate(migration).await?;
+    }
+    info!(version, "database migrations are up to date");
+    Ok(())
+}
+
+async fn lock(conn: &mut PoolConnection<Postgres>, lock_key: i64, timeout: Duration) -> Result<()> {
+    let started = Instant::now();
+    let mut logged = None;
+    loop {
+        let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+            .bind(lock_key)
+            .fetch_one(&mut *conn)
+            .await?;
+        if locked {
+            if logged.is_some() {
+                info!(
+                    waited_ms = started.elapsed().as_millis() as u64,
+                    "acquired the migration lock"
+                );
+            }
+            return Ok(());
+        }
+        let waited = started.elapsed();
+        if waited >= timeout {
+            return Err(anyhow!(
+                "timed out after {}s waiting for the migration lock, another instance may be stuck \
+                migrating (see MIGRATION_LOCK_TIMEOUT_SECS)",
+                timeout.as_secs()
+            ));
+        }
+        if logged.is_none_or(|logged: Instant| logged.elapsed() >= LOCK_WAIT_LOG_INTERVAL) {
+            warn!(
+                waited_secs = waited.as_secs(),
+                timeout_secs = timeout.as_secs(),
+                "another instance is running migrations, waiting for it to finish"
+            );
+            logged = Some(Instant::now());
+        }
+        tokio::time::delay_for(LOCK_POLL_INTERVAL).await;
+    }
+}
+
+async fn apply_pending(conn: &mut PoolConnection<Postgres>, migrator: &Migrator) -> Result<()> {
+    conn.ensure_migrations_table().await?;
+    let version = applied_version(conn).await?;
+    let pending = pending(migrator, version);
+    if pending.is_empty() {
+        info!(version, "database migrations are up to date");
+    } else {
+        info!(
+            version,
+            pending = %describe(&pending),
+            "applying database migrations"
+        );
+    }
+    for migration in migrator.iter() {
+        if migration.version > version {
+            let span = info_span!(
+                "migration",
+                version = migration.version,
+                description = %migration.description
+            );
+            let elapsed = conn.apply(migration).instrument(span).await?;
+            info!(
+                version = migration.version,
+                description = %migration.description,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "applied migration"
+            );
+        } else {
+            conn.validate(migration).await?;
+        }
+    }
+    Ok(())
+}
+
+// The version of the last applied migration, or 0 for a new database. A migration that failed
+// part way (dirty) has to be fixed by hand.
+async fn applied_version(conn: &mut PoolConnection<Postgres>) -> Result<i64> {
+    match conn.version().await? {
+        Some((version, true)) => Err(anyhow!(
+            "migration {} failed part way and must be repaired by hand before the server can start",
+            version
+        )),
+        Some((version, false)) => Ok(version),
+        None => Ok(0),
+    }
+}
+
+fn pending(migrator: &Migrator, version: i64) -> Vec<&Migration> {
+    migrator
+        .iter()
+        .filter(|migration| migration.version > version)
+        .collect()
+}
+
+fn describe(migrations: &[&Migration]) -> String {
+    migrations
+        .iter()
+        .map(|migration| format!("{} {}", migration.version, migration.description))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use sqlx::migrate::{Migration, Migrator};
+    use sqlx::types::Json;
+    use sqlx::Executor;
+
+    use super::run;
+    use crate::test_support::{TestEnv, OWNER_API_KEY};
+
+    const TEST_LOCK_KEY: i64 = 0x6d69_6772_6174_6574;
+
+    // Migrates on top of the test schema, which `TestEnv` has already brought up to date.
+    fn test_migrator() -> Migrator {
+        Migrator {
+            migrations: Cow::Owned(vec![Migration::new(
+                99_999_999_999_999,
+                Cow::Borrowed("lock_test"),
+                Cow::Borrowed("CREATE TABLE lock_test (id INTEGER)"),
+            )]),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_runners_take_turns() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let migrator = test_migrator();
+        let timeout = Duration::from_secs(30);
+
+        // Without the lock, the second runner would also try to create the table and fail.
+        let (first, second) = tokio::join!(
+            run(&test.env.db, &migrator, TEST_LOCK_KEY, timeout),
+            run(&test.env.db, &migrator, TEST_LOCK_KEY, timeout),
+        );
+        first.unwrap();
+        second.unwrap();
+        sqlx::query("SELECT id FROM lock_test")
+            .execute(&test.env.db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_for_the_lock_times_out() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let mut holder = test.env.db.acquire().await.unwrap();
+        sqlx::query("SELECT pg_advisory_lock($1)")
+            .bind(TEST_LOCK_KEY + 1)
+            .execute(&mut holder)
+            .await
+            .unwrap();
+
+        let error = run(
+            &test.env.db,
+            &test_migrator(),
+            TEST_LOCK_KEY + 1,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+
+        sqlx::query("SELECT pg_advisory_unlock($1)")
+            .bind(TEST_LOCK_KEY + 1)
+            .execute(&mut holder)
+            .await
+            .unwrap();
+        run(
+            &test.env.db,
+            &test_migrator(),
+            TEST_LOCK_KEY + 1,
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_descriptions_are_backed_up_before_they_are_cut() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await as i32;
+        // Back to before the migration, with a description it has to cut.
+        test.env
+            .db
+            .execute(
+                "ALTER TABLE shops DROP CONSTRAINT shops_description_length;
+                DROP TABLE shop_description_migration_backup",
+            )
+            .await
+            .unwrap();
+        let description = "é".repeat(5000);
+        sqlx::query("UPDATE shops SET description = $1 WHERE id = $2")
+            .bind(&description)
+            .bind(shop_id)
+            .execute(&test.env.db)
+            .await
+            .unwrap();
+
+        test.env
+            .db
+            .execute(include_str!(
+                "../../db/migrations/20261017000000_shop_description_length.sql"
+            ))
+            .await
+            .unwrap();
+        let (cut,): (String,) = sqlx::query_as("SELECT description FROM shops WHERE id = $1")
+            .bind(shop_id)
+            .fetch_one(&test.env.db)
+            .await
+            .unwrap();
+        assert_eq!(cut, "é".repeat(4096));
+        let backups: Vec<(i32, String)> = sqlx::query_as(
+            "SELECT shop_id, original_description FROM shop_description_migration_backup",
+        )
+        .fetch_all(&test.env.db)
+        .await
+        .unwrap();
+        assert_eq!(backups, vec![(shop_id, description)]);
+    }
+
+    #[tokio::test]
+    async fn oversized_merchandise_is_backed_up_before_it_is_trimmed() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.create_owner(OWNER_API_KEY, "Owner").await;
+        let shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Test Shop" }))
+            .await as i32;
+        let other_shop_id = test
+            .create_shop(OWNER_API_KEY, &json!({ "name": "Other Shop" }))
+            .await as i32;
+        let item = |name: &str, keywords: Value| {
+            json!({
+                "mod_name": "Skyrim.esm",
+                "local_form_id": 1,
+                "name": name,
+                "quantity": 1,
+                "form_kind": 41,
+                "is_food": false,
+                "price": 1,
+                "keywords": keywords,
+            })
+        };
+        let mut keywords: Vec<String> = (0..30).map(|i| format!("Keyword{}", i)).collect();
+        keywords[0] = "k".repeat(70);
+        let oversized = json!([
+            item(&"n".repeat(300), json!(keywords)),
+            // Not an array, which the migration has to step around.
+            item("Cabbage", json!("VendorItemFood")),
+        ]);
+        let within_limits = json!([item("Leek", json!(["VendorItemFood"]))]);
+        test.env
+            .db
+            .execute("DROP TABLE merchandise_list_migration_backup")
+            .await
+            .unwrap();
+        for (shop_id, form_list) in &[(shop_id, &oversized), (other_shop_id, &within_limits)] {
+            sqlx::query("UPDATE merchandise_lists SET form_list = $1 WHERE shop_id = $2")
+                .bind(Json(form_list))
+                .bind(shop_id)
+                .execute(&test.env.db)
+                .await
+                .unwrap();
+        }
+
+        test.env
+            .db
+            .execute(include_str!(
+                "../../db/migrations/20261017100000_merchandise_field_caps.sql"
+            ))
+            .await
+            .unwrap();
+        let backups: Vec<(i32, Json<Value>)> = sqlx::query_as(
+            "SELECT shop_id, original_form_list FROM merchandise_list_migration_backup",
+        )
+        .fetch_all(&test.env.db)
+        .await
+        .unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].0, shop_id);
+        assert_eq!(backups[0].1 .0, oversized);
+
+        let db = &test.env.db;
+        let form_list = |shop_id: i32| async move {
+            let (form_list,): (Json<Value>,) =
+                sqlx::query_as("SELECT form_list FROM merchandise_lists WHERE shop_id = $1")
+                    .bind(shop_id)
+                    .fetch_one(db)
+                    .await
+                    .unwrap();
+            form_list.0
+        };
+        let trimmed = form_list(shop_id).await;
+        assert_eq!(trimmed[0]["name"], "n".repeat(256));
+        let mut expected_keywords = keywords[..25].to_vec();
+        expected_keywords[0] = "k".repeat(64);
+        assert_eq!(trimmed[0]["keywords"], json!(expected_keywords));
+        assert_eq!(trimmed[1], oversized[1]);
+        assert_eq!(form_list(other_shop_id).await, within_limits);
+    }
+}
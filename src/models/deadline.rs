@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use sqlx::postgres::PgConnection;
+use sqlx::Executor;
+
+use crate::problem::gateway_timeout;
+
+/// The point in time by which a request should have been answered.
+///
+/// Heavy queries pass it to `set_statement_timeout` so that Postgres gives up on work whose
+/// result the client will never see.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+}
+
+/// Limits every following statement in the current DB transaction to the time left before
+/// `deadline`. Does nothing without a deadline. Fails right away with a 504 if the deadline has
+/// already passed.
+///
+/// Uses `SET LOCAL`, so `db` must be inside a transaction or the setting has no effect.
+pub async fn set_statement_timeout(
+    db: &mut PgConnection,
+    deadline: Option<&Deadline>,
+) -> Result<()> {
+    if let Some(deadline) = deadline {
+        let remaining = deadline.remaining().as_millis();
+        if remaining == 0 {
+            return Err(gateway_timeout());
+        }
+        // SET does not accept bind parameters, but the value is always an integer
+        db.execute(&*format!("SET LOCAL statement_timeout = {}", remaining))
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use http::StatusCode;
+    use sqlx::{Connection, Row};
+
+    use super::{set_statement_timeout, Deadline};
+    use crate::problem::from_anyhow;
+    use crate::test_support::TestEnv;
+
+    #[tokio::test]
+    async fn statements_past_the_deadline_are_cancelled_as_gateway_timeouts() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let mut conn = test.env.db.acquire().await.unwrap();
+        let started = Instant::now();
+        let mut tx = conn.begin().await.unwrap();
+        set_statement_timeout(&mut tx, Some(&Deadline::after(Duration::from_millis(100))))
+            .await
+            .unwrap();
+        let error = sqlx::query("SELECT pg_sleep(5)")
+            .execute(&mut tx)
+            .await
+            .unwrap_err();
+        tx.rollback().await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(
+            from_anyhow(error.into()).status,
+            Some(StatusCode::GATEWAY_TIMEOUT)
+        );
+
+        // The timeout was local to the transaction.
+        let row = sqlx::query("SHOW statement_timeout")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, _>(0), "0");
+    }
+
+    #[tokio::test]
+    async fn expired_deadlines_fail_before_querying_and_no_deadline_sets_nothing() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let mut tx = test.env.db.begin().await.unwrap();
+        let error = set_statement_timeout(&mut tx, Some(&Deadline::after(Duration::from_secs(0))))
+            .await
+            .unwrap_err();
+        assert_eq!(from_anyhow(error).status, Some(StatusCode::GATEWAY_TIMEOUT));
+
+        set_statement_timeout(&mut tx, None).await.unwrap();
+        sqlx::query("SELECT pg_sleep(0.2)")
+            .execute(&mut tx)
+            .await
+            .unwrap();
+        tx.commit().await.unwrap();
+    }
+}
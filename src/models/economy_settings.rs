@@ -0,0 +1,57 @@
+use serde::Serialize;
+
+use super::ShopType;
+
+/// Server-wide defaults for new shops and rules for transactions.
+///
+/// Configured by `DEFAULT_SHOP_GOLD`, `DEFAULT_SHOP_TYPE`, `CUSTOM_SHOP_TYPES`,
+/// `DEFAULT_VENDOR_KEYWORDS` (comma-separated), and `SELL_PRICE_PERCENT`; see `Config`. Changes
+/// require a restart.
+#[derive(Debug, Clone, Serialize)]
+pub struct EconomySettings {
+    pub default_shop_gold: i64,
+    pub default_shop_type: ShopType,
+    /// Whether shops may have a `shop_type` other than the known ones. Unknown types are a 422
+    /// when off.
+    pub custom_shop_types: bool,
+    pub default_vendor_keywords: Vec<String>,
+    /// When set, an item sold to a shop that already stocks it cannot be priced above this
+    /// percentage of the shop's own listed price.
+    pub sell_price_percent: Option<u32>,
+}
+
+impl Default for EconomySettings {
+    fn default() -> Self {
+        Self {
+            default_shop_gold: 0,
+            default_shop_type: ShopType::GeneralStore,
+            custom_shop_types: false,
+            default_vendor_keywords: vec!["VendorItemKey".to_string(), "VendorNoSale".to_string()],
+            sell_price_percent: None,
+        }
+    }
+}
+
+impl EconomySettings {
+    /// The highest price a shop listing an item at `listed_price` will pay for it, if limited.
+    pub fn max_sell_price(&self, listed_price: i64) -> Option<i64> {
+        self.sell_price_percent.map(|percent| {
+            (listed_price as i128 * percent as i128 / 100).min(i64::MAX as i128) as i64
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EconomySettings;
+
+    #[test]
+    fn max_sell_price_does_not_overflow() {
+        let economy = EconomySettings {
+            sell_price_percent: Some(1000),
+            ..EconomySettings::default()
+        };
+        assert_eq!(economy.max_sell_price(3_000_000_000), Some(30_000_000_000));
+        assert_eq!(economy.max_sell_price(i64::MAX), Some(i64::MAX));
+    }
+}
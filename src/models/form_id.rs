@@ -0,0 +1,306 @@
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::decode::Decode;
+use sqlx::encode::{Encode, IsNull};
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef, Postgres};
+use sqlx::types::Type;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A form id local to its mod, i.e. without the load order byte (`0x0005ACE4`).
+///
+/// Form ids are unsigned, so ids above `0x7FFFFFFF` must never pass through an `i32`. In JSON they
+/// are written as zero-padded hex strings and read from either hex strings or integers. Bincode
+/// reads and writes a plain `u32`. Postgres stores them as a `BIGINT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct FormId(pub u32);
+
+impl fmt::Display for FormId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010X}", self.0)
+    }
+}
+
+/// Parses a hex string with a `0x` prefix (`"0x5ACE4"`, any case, padding optional) or a
+/// decimal string (`"371940"`).
+impl FromStr for FormId {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (digits, radix) = match value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            Some(hex) => (hex, 16),
+            None => (value, 10),
+        };
+        let is_valid = !digits.is_empty()
+            && (radix == 10 || digits.len() <= 8)
+            && digits.chars().all(|digit| digit.is_digit(radix));
+        if !is_valid {
+            return Err(format!("{:?} is not a valid form id", value));
+        }
+        u32::from_str_radix(digits, radix)
+            .map(FormId)
+            .map_err(|_| format!("{:?} is not a valid form id", value))
+    }
+}
+
+impl From<u32> for FormId {
+    fn from(id: u32) -> Self {
+        FormId(id)
+    }
+}
+
+impl From<FormId> for i64 {
+    fn from(id: FormId) -> Self {
+        id.0 as i64
+    }
+}
+
+impl Serialize for FormId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_u32(self.0)
+        }
+    }
+}
+
+struct FormIdVisitor;
+
+impl<'de> Visitor<'de> for FormIdVisitor {
+    type Value = FormId;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a form id as a hex string (\"0x0005ACE4\") or an unsigned 32-bit integer")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<FormId, E> {
+        u32::try_from(value)
+            .map(FormId)
+            .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(value), &self))
+    }
+
+    // Older clients converted form ids through an `i32`, so ids above `0x7FFFFFFF` arrive as
+    // negative numbers. Those are reinterpreted as the unsigned id they were sign-extended from.
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<FormId, E> {
+        if value >= 0 {
+            self.visit_u64(value as u64)
+        } else if value >= i32::MIN as i64 {
+            Ok(FormId(value as i32 as u32))
+        } else {
+            Err(E::invalid_value(de::Unexpected::Signed(value), &self))
+        }
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<FormId, E> {
+        value
+            .parse()
+            .map_err(|_| E::invalid_value(de::Unexpected::Str(value), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for FormId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(FormIdVisitor)
+        } else {
+            deserializer.deserialize_u32(FormIdVisitor)
+        }
+    }
+}
+
+impl Type<Postgres> for FormId {
+    fn type_info() -> PgTypeInfo {
+        <i64 as Type<Postgres>>::type_info()
+    }
+}
+
+impl Encode<'_, Postgres> for FormId {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <i64 as Encode<Postgres>>::encode_by_ref(&i64::from(*self), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for FormId {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let id = <i64 as Decode<Postgres>>::decode(value)?;
+        Ok(FormId(u32::try_from(id)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::FormId;
+    use crate::models::interior_ref_list::InteriorRef;
+    use crate::models::{Merchandise, PostedTransaction};
+
+    #[test]
+    fn displays_as_padded_upper_hex() {
+        assert_eq!(FormId(0x5ACE4).to_string(), "0x0005ACE4");
+        assert_eq!(FormId(0).to_string(), "0x00000000");
+        assert_eq!(FormId(u32::MAX).to_string(), "0xFFFFFFFF");
+    }
+
+    #[test]
+    fn parses_hex_and_decimal_strings() {
+        assert_eq!("0x0005ACE4".parse(), Ok(FormId(0x5ACE4)));
+        assert_eq!("0x5ace4".parse(), Ok(FormId(0x5ACE4)));
+        assert_eq!("0X5ACE4".parse(), Ok(FormId(0x5ACE4)));
+        assert_eq!("371940".parse(), Ok(FormId(371940)));
+        assert_eq!("0xFFFFFFFF".parse(), Ok(FormId(u32::MAX)));
+    }
+
+    #[test]
+    fn rejects_invalid_strings() {
+        for value in &[
+            "",
+            "0x",
+            "0x100000000",
+            "0x000000001",
+            "0xG",
+            "5ACE4",
+            "-1",
+            "4294967296",
+            " 0x1",
+            "0x+1",
+        ] {
+            assert!(value.parse::<FormId>().is_err(), "{:?} parsed", value);
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        for &id in &[0, 1, 0x5ACE4, 0x7FFF_FFFF, 0x8000_0000, u32::MAX] {
+            let json = serde_json::to_value(FormId(id)).unwrap();
+            assert_eq!(json, json!(format!("{:#010X}", id)));
+            assert_eq!(serde_json::from_value::<FormId>(json).unwrap(), FormId(id));
+        }
+    }
+
+    #[test]
+    fn json_accepts_integers() {
+        assert_eq!(
+            serde_json::from_value::<FormId>(json!(371940)).unwrap(),
+            FormId(0x5ACE4)
+        );
+        assert_eq!(
+            serde_json::from_value::<FormId>(json!(4294967295u64)).unwrap(),
+            FormId(u32::MAX)
+        );
+    }
+
+    #[test]
+    fn json_accepts_sign_extended_integers() {
+        assert_eq!(
+            serde_json::from_value::<FormId>(json!(-1)).unwrap(),
+            FormId(u32::MAX)
+        );
+        assert_eq!(
+            serde_json::from_value::<FormId>(json!(i32::MIN)).unwrap(),
+            FormId(0x8000_0000)
+        );
+    }
+
+    #[test]
+    fn json_rejects_out_of_range_and_other_types() {
+        for value in &[
+            json!(4294967296u64),
+            json!(i32::MIN as i64 - 1),
+            json!(1.5),
+            json!(true),
+            json!(null),
+            json!([1]),
+            json!("not a form id"),
+        ] {
+            assert!(
+                serde_json::from_value::<FormId>(value.clone()).is_err(),
+                "{} parsed",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn bincode_round_trip_matches_u32() {
+        for &id in &[0, 0x5ACE4, 0x8000_0000, u32::MAX] {
+            let bytes = bincode::serialize(&FormId(id)).unwrap();
+            assert_eq!(bytes, bincode::serialize(&id).unwrap());
+            assert_eq!(bincode::deserialize::<FormId>(&bytes).unwrap(), FormId(id));
+        }
+    }
+
+    #[test]
+    fn bincode_reads_i32_sent_by_older_clients() {
+        let bytes = bincode::serialize(&(0x8000_0001u32 as i32)).unwrap();
+        assert_eq!(
+            bincode::deserialize::<FormId>(&bytes).unwrap(),
+            FormId(0x8000_0001)
+        );
+    }
+
+    #[test]
+    fn merchandise_and_transaction_ids_compare_equal() {
+        let merchandise: Merchandise = serde_json::from_value(json!({
+            "mod_name": "Skyrim.esm",
+            "local_form_id": "0x80000001",
+            "name": "Thing",
+            "quantity": 1,
+            "form_kind": 41,
+            "is_food": false,
+            "price": 1,
+            "keywords": [],
+        }))
+        .unwrap();
+        let transaction: PostedTransaction = serde_json::from_value(json!({
+            "shop_id": 1,
+            "owner_id": null,
+            "mod_name": "Skyrim.esm",
+            "local_form_id": -2147483647,
+            "name": "Thing",
+            "form_kind": 41,
+            "is_food": false,
+            "price": 1,
+            "is_sell": true,
+            "quantity": 1,
+            "amount": 1,
+            "keywords": [],
+        }))
+        .unwrap();
+        assert_eq!(merchandise.local_form_id, transaction.local_form_id);
+        assert_eq!(
+            serde_json::to_value(&merchandise).unwrap()["local_form_id"],
+            "0x80000001"
+        );
+    }
+
+    #[test]
+    fn interior_ref_round_trip() {
+        let interior_ref: InteriorRef = serde_json::from_value(json!({
+            "base_mod_name": "Skyrim.esm",
+            "base_local_form_id": 7,
+            "ref_mod_name": null,
+            "ref_local_form_id": "0xFF000801",
+            "position_x": 0.0,
+            "position_y": 0.0,
+            "position_z": 0.0,
+            "angle_x": 0.0,
+            "angle_y": 0.0,
+            "angle_z": 0.0,
+            "scale": 100,
+        }))
+        .unwrap();
+        let json = serde_json::to_value(&interior_ref).unwrap();
+        assert_eq!(json["base_local_form_id"], "0x00000007");
+        assert_eq!(json["ref_local_form_id"], "0xFF000801");
+        let bytes = bincode::serialize(&interior_ref).unwrap();
+        let decoded: InteriorRef = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(decoded.ref_local_form_id.0, 0xFF00_0801);
+    }
+}
@@ -1,20 +1,29 @@
 use anyhow::{Error, Result};
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgConnection;
 use sqlx::types::Json;
 use sqlx::{Done, Executor, Postgres};
 use tracing::instrument;
 use url::Url;
 
-use super::ListParams;
-use crate::problem::forbidden_permission;
+use super::{check_revision, FormId, ListQuery, NoFilter, Pagination, Shop};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{
+    forbidden_permission, not_found, revision_mismatch, shop_id_immutable, unprocessable_entity,
+    ValidationError,
+};
 
-#[derive(sqlx::FromRow, Debug, Serialize, Deserialize, Clone)]
+pub const MAX_INTERIOR_REFS: usize = 5000;
+const MAX_SHELVES: usize = 100;
+
+#[derive(sqlx::FromRow, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct InteriorRef {
     pub base_mod_name: String,
-    pub base_local_form_id: u32,
+    pub base_local_form_id: FormId,
     pub ref_mod_name: Option<String>,
-    pub ref_local_form_id: u32,
+    pub ref_local_form_id: FormId,
     pub position_x: f32,
     pub position_y: f32,
     pub position_z: f32,
@@ -24,7 +33,8 @@ pub struct InteriorRef {
     pub scale: u16,
 }
 
-#[derive(sqlx::FromRow, Debug, Serialize, Deserialize, Clone)]
+#[derive(sqlx::FromRow, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Shelf {
     pub shelf_type: u32,
     pub position_x: f32,
@@ -51,17 +61,156 @@ pub struct InteriorRefList {
     pub shelves: Json<Vec<Shelf>>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// Starts at 1 and goes up by one with every update.
+    pub revision: i32,
+}
+
+/// Query parameters of `GET /v1/interior_ref_lists`, which only page and sort.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct InteriorRefListQuery {
+    pub pagination: Pagination,
+}
+
+impl ListQuery for InteriorRefListQuery {
+    type Filter = NoFilter;
+    const FILTER_PARAMS: &'static [&'static str] = &[];
+
+    fn new(pagination: Pagination, _filter: NoFilter) -> Result<Self> {
+        Ok(Self { pagination })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PostedInteriorRefList {
     pub shop_id: i32,
     pub owner_id: Option<i32>,
     pub ref_list: Json<Vec<InteriorRef>>,
     pub shelves: Json<Vec<Shelf>>,
+    /// Updates are rejected with a 409 unless the list is still at this revision. Left out, the
+    /// update always wins. Ignored when creating.
+    #[serde(default)]
+    pub expected_revision: Option<i32>,
+}
+
+/// The body of `POST /v1/shops/{id}/interior_ref_list`, where the path already names the shop.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostedShopInteriorRefList {
+    #[serde(default)]
+    pub shop_id: Option<i32>,
+    pub ref_list: Json<Vec<InteriorRef>>,
+    pub shelves: Json<Vec<Shelf>>,
+    #[serde(default)]
+    pub expected_revision: Option<i32>,
+}
+
+impl PostedShopInteriorRefList {
+    // A shop_id in the body is redundant, but one naming a different shop is a client bug
+    pub fn into_posted(self, shop_id: i32) -> Result<PostedInteriorRefList> {
+        match self.shop_id {
+            Some(posted_shop_id) if posted_shop_id != shop_id => {
+                Err(shop_id_immutable(shop_id, posted_shop_id))
+            }
+            _ => Ok(PostedInteriorRefList {
+                shop_id,
+                owner_id: None,
+                ref_list: self.ref_list,
+                shelves: self.shelves,
+                expected_revision: self.expected_revision,
+            }),
+        }
+    }
+}
+
+// bincode can encode NaN and infinity even though JSON can't, and the game engine misbehaves on them
+fn non_finite_transform(values: [(&'static str, f32); 6]) -> Option<&'static str> {
+    values
+        .iter()
+        .find(|(_, value)| !value.is_finite())
+        .map(|(name, _)| *name)
+}
+
+impl PostedInteriorRefList {
+    /// Whether creating this list would store exactly `list`'s refs and shelves. Used to recognize
+    /// a retried create of a list that already exists.
+    pub fn matches(&self, list: &InteriorRefList) -> bool {
+        self.ref_list.0 == list.ref_list.0 && self.shelves.0 == list.shelves.0
+    }
+
+    // Returns warnings about suspicious but allowed refs/shelves, or a 422 listing every invalid one
+    pub fn validate(&self) -> Result<Vec<String>> {
+        let mut errors = vec![];
+        let mut warnings = vec![];
+        if self.ref_list.len() > MAX_INTERIOR_REFS {
+            errors.push(ValidationError::new(
+                "ref_list",
+                format!("cannot contain more than {} refs", MAX_INTERIOR_REFS),
+            ));
+        }
+        if self.shelves.len() > MAX_SHELVES {
+            errors.push(ValidationError::new(
+                "shelves",
+                format!("cannot contain more than {} shelves", MAX_SHELVES),
+            ));
+        }
+        for (index, interior_ref) in self.ref_list.iter().enumerate() {
+            if let Some(name) = non_finite_transform([
+                ("position_x", interior_ref.position_x),
+                ("position_y", interior_ref.position_y),
+                ("position_z", interior_ref.position_z),
+                ("angle_x", interior_ref.angle_x),
+                ("angle_y", interior_ref.angle_y),
+                ("angle_z", interior_ref.angle_z),
+            ]) {
+                errors.push(ValidationError::at_index(
+                    "ref_list",
+                    index,
+                    format!("{} must be a finite number", name),
+                ));
+            }
+            if interior_ref.base_mod_name.is_empty() {
+                errors.push(ValidationError::at_index(
+                    "ref_list",
+                    index,
+                    "base_mod_name cannot be empty",
+                ));
+            }
+            if interior_ref.scale == 0 {
+                warnings.push(format!("ref_list[{}] has a scale of 0", index));
+            }
+        }
+        for (index, shelf) in self.shelves.iter().enumerate() {
+            if let Some(name) = non_finite_transform([
+                ("position_x", shelf.position_x),
+                ("position_y", shelf.position_y),
+                ("position_z", shelf.position_z),
+                ("angle_x", shelf.angle_x),
+                ("angle_y", shelf.angle_y),
+                ("angle_z", shelf.angle_z),
+            ]) {
+                errors.push(ValidationError::at_index(
+                    "shelves",
+                    index,
+                    format!("{} must be a finite number", name),
+                ));
+            }
+            if shelf.scale == 0 {
+                warnings.push(format!("shelves[{}] has a scale of 0", index));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(unprocessable_entity(errors));
+        }
+        Ok(warnings)
+    }
 }
 
 impl InteriorRefList {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] =
+        &["id", "shop_id", "owner_id", "created_at", "updated_at"];
+    pub const DEFAULT_ORDER_BY: &'static str = "updated_at DESC, id DESC";
+
     pub fn resource_name() -> &'static str {
         "interior_ref_list"
     }
@@ -74,12 +223,17 @@ impl InteriorRefList {
         Ok(api_url.join(&format!("{}s/{}", Self::resource_name(), self.pk()))?)
     }
 
+    pub fn shop_url(&self, api_url: &Url) -> Result<Url> {
+        Ok(api_url.join(&format!("shops/{}/{}", self.shop_id, Self::resource_name()))?)
+    }
+
     // TODO: this model will probably never need to be accessed through it's ID, should these methods be removed/unimplemented?
     #[instrument(level = "debug", skip(db))]
     pub async fn get(db: impl Executor<'_, Database = Postgres>, id: i32) -> Result<Self> {
+        let _timer = time_query("interior_ref_list", "get", [("id", id.shape())]);
         sqlx::query_as!(
             Self,
-            r#"SELECT id, shop_id, owner_id, created_at, updated_at,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
                    ref_list as "ref_list: Json<Vec<InteriorRef>>",
                    shelves as "shelves: Json<Vec<Shelf>>"
                FROM interior_ref_lists WHERE id = $1"#,
@@ -90,17 +244,43 @@ impl InteriorRefList {
         .map_err(Error::new)
     }
 
+    /// Like `get`, also locking the row until the end of the DB transaction, so that a
+    /// precondition checked against it (e.g. `If-Match`) still holds when the caller writes it.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_for_update(db: &mut PgConnection, id: i32) -> Result<Self> {
+        let _timer = time_query("interior_ref_list", "get_for_update", [("id", id.shape())]);
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
+                   ref_list as "ref_list: Json<Vec<InteriorRef>>",
+                   shelves as "shelves: Json<Vec<Shelf>>"
+               FROM interior_ref_lists WHERE id = $1
+               FOR UPDATE"#,
+            id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
     #[instrument(level = "debug", skip(interior_ref_list, db))]
     pub async fn create(
         interior_ref_list: PostedInteriorRefList,
-        db: impl Executor<'_, Database = Postgres>,
+        db: &mut PgConnection,
     ) -> Result<Self> {
+        let _timer = time_query("interior_ref_list", "create", []);
+        Shop::check_owner(
+            &mut *db,
+            interior_ref_list.shop_id,
+            interior_ref_list.owner_id,
+        )
+        .await?;
         Ok(sqlx::query_as!(
             Self,
             r#"INSERT INTO interior_ref_lists
                 (shop_id, owner_id, ref_list, shelves, created_at, updated_at)
             VALUES ($1, $2, $3, $4, now(), now())
-            RETURNING id, shop_id, owner_id, created_at, updated_at,
+            RETURNING id, shop_id, owner_id, created_at, updated_at, revision,
                 ref_list as "ref_list: Json<Vec<InteriorRef>>",
                 shelves as "shelves: Json<Vec<Shelf>>""#,
             interior_ref_list.shop_id,
@@ -108,99 +288,136 @@ impl InteriorRefList {
             serde_json::json!(interior_ref_list.ref_list),
             serde_json::json!(interior_ref_list.shelves),
         )
-        .fetch_one(db)
+        .fetch_one(&mut *db)
         .await?)
     }
 
     #[instrument(level = "debug", skip(db))]
-    pub async fn delete(
-        db: impl Executor<'_, Database = Postgres> + Copy,
-        owner_id: i32,
-        id: i32,
-    ) -> Result<u64> {
-        let interior_ref_list =
-            sqlx::query!("SELECT owner_id FROM interior_ref_lists WHERE id = $1", id)
-                .fetch_one(db)
-                .await?;
-        if interior_ref_list.owner_id == owner_id {
-            return Ok(
-                sqlx::query!("DELETE FROM interior_ref_lists WHERE id = $1", id)
-                    .execute(db)
-                    .await?
-                    .rows_affected(),
-            );
-        } else {
-            return Err(forbidden_permission());
+    pub async fn delete(db: &mut PgConnection, owner_id: i32, id: i32) -> Result<u64> {
+        let _timer = time_query(
+            "interior_ref_list",
+            "delete",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        let rows_affected = sqlx::query!(
+            "DELETE FROM interior_ref_lists WHERE id = $1 AND owner_id = $2",
+            id,
+            owner_id
+        )
+        .execute(&mut *db)
+        .await?
+        .rows_affected();
+        if rows_affected == 0 {
+            let exists = sqlx::query!("SELECT id FROM interior_ref_lists WHERE id = $1", id)
+                .fetch_optional(&mut *db)
+                .await?
+                .is_some();
+            return Err(if exists {
+                forbidden_permission()
+            } else {
+                not_found("Interior ref list does not exist or has already been deleted")
+            });
         }
+        Ok(rows_affected)
     }
 
     #[instrument(level = "debug", skip(db))]
     pub async fn list(
         db: impl Executor<'_, Database = Postgres>,
-        list_params: &ListParams,
+        query: &InteriorRefListQuery,
     ) -> Result<Vec<Self>> {
-        let result = if let Some(order_by) = list_params.get_order_by() {
-            sqlx::query_as!(
-                Self,
-                r#"SELECT id, shop_id, owner_id, created_at, updated_at,
-                    ref_list as "ref_list: Json<Vec<InteriorRef>>",
-                    shelves as "shelves: Json<Vec<Shelf>>" FROM interior_ref_lists
-                ORDER BY $1
-                LIMIT $2
-                OFFSET $3"#,
-                order_by,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        } else {
-            sqlx::query_as!(
-                Self,
-                r#"SELECT id, shop_id, owner_id, created_at, updated_at,
-                    ref_list as "ref_list: Json<Vec<InteriorRef>>",
-                    shelves as "shelves: Json<Vec<Shelf>>" FROM interior_ref_lists
-                LIMIT $1
-                OFFSET $2"#,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        };
-        Ok(result)
+        let _timer = time_query("interior_ref_list", "list", []);
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM interior_ref_lists
+            ORDER BY {}
+            LIMIT $1
+            OFFSET $2",
+            order_by
+        ))
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of interior ref lists `list` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count(db: impl Executor<'_, Database = Postgres>) -> Result<i64> {
+        let _timer = time_query("interior_ref_list", "count", []);
+        Ok(
+            sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM interior_ref_lists"#)
+                .fetch_one(db)
+                .await?
+                .count,
+        )
     }
 
     #[instrument(level = "debug", skip(interior_ref_list, db))]
     pub async fn update(
         interior_ref_list: PostedInteriorRefList,
-        db: impl Executor<'_, Database = Postgres> + Copy,
+        db: &mut PgConnection,
         owner_id: i32,
         id: i32,
     ) -> Result<Self> {
-        let existing_interior_ref_list =
-            sqlx::query!("SELECT owner_id FROM interior_ref_lists WHERE id = $1", id)
-                .fetch_one(db)
-                .await?;
+        let _timer = time_query(
+            "interior_ref_list",
+            "update",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        let existing_interior_ref_list = sqlx::query!(
+            "SELECT owner_id, shop_id FROM interior_ref_lists WHERE id = $1",
+            id
+        )
+        .fetch_one(&mut *db)
+        .await?;
         if existing_interior_ref_list.owner_id == owner_id {
-            Ok(sqlx::query_as!(
+            if interior_ref_list.shop_id != existing_interior_ref_list.shop_id {
+                return Err(shop_id_immutable(
+                    existing_interior_ref_list.shop_id,
+                    interior_ref_list.shop_id,
+                ));
+            }
+            // Not locked beforehand since this isn't run in a transaction, so the revision is
+            // compared in the update itself.
+            let updated_interior_ref_list = sqlx::query_as!(
                 Self,
                 r#"UPDATE interior_ref_lists SET
                 ref_list = $2,
                 shelves = $3,
-                updated_at = now()
-                WHERE id = $1
-                RETURNING id, shop_id, owner_id, created_at, updated_at,
+                updated_at = now(),
+                revision = revision + 1
+                WHERE id = $1 AND ($4::integer IS NULL OR revision = $4)
+                RETURNING id, shop_id, owner_id, created_at, updated_at, revision,
                     ref_list as "ref_list: Json<Vec<InteriorRef>>",
                     shelves as "shelves: Json<Vec<Shelf>>""#,
                 id,
                 serde_json::json!(interior_ref_list.ref_list),
                 serde_json::json!(interior_ref_list.shelves),
+                interior_ref_list.expected_revision,
             )
-            .fetch_one(db)
-            .await?)
+            .fetch_optional(&mut *db)
+            .await?;
+            match updated_interior_ref_list {
+                Some(updated_interior_ref_list) => Ok(updated_interior_ref_list),
+                None => {
+                    let current =
+                        sqlx::query!("SELECT revision FROM interior_ref_lists WHERE id = $1", id)
+                            .fetch_optional(&mut *db)
+                            .await?;
+                    Err(match (interior_ref_list.expected_revision, current) {
+                        (Some(expected_revision), Some(current)) => {
+                            revision_mismatch(expected_revision, current.revision)
+                        }
+                        _ => not_found("Interior ref list does not exist or has been deleted"),
+                    })
+                }
+            }
         } else {
-            return Err(forbidden_permission());
+            Err(forbidden_permission())
         }
     }
 
@@ -209,9 +426,14 @@ impl InteriorRefList {
         db: impl Executor<'_, Database = Postgres>,
         shop_id: i32,
     ) -> Result<Self> {
+        let _timer = time_query(
+            "interior_ref_list",
+            "get_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
         sqlx::query_as!(
             Self,
-            r#"SELECT id, shop_id, owner_id, created_at, updated_at,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
                 ref_list as "ref_list: Json<Vec<InteriorRef>>",
                 shelves as "shelves: Json<Vec<Shelf>>" FROM interior_ref_lists
             WHERE shop_id = $1"#,
@@ -222,38 +444,74 @@ impl InteriorRefList {
         .map_err(Error::new)
     }
 
+    /// Like `get_by_shop_id`, also locking the row until the end of the DB transaction, so that a
+    /// precondition checked against it (e.g. `If-Match`) still holds when the caller writes it.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_by_shop_id_for_update(db: &mut PgConnection, shop_id: i32) -> Result<Self> {
+        let _timer = time_query(
+            "interior_ref_list",
+            "get_by_shop_id_for_update",
+            [("shop_id", shop_id.shape())],
+        );
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
+                ref_list as "ref_list: Json<Vec<InteriorRef>>",
+                shelves as "shelves: Json<Vec<Shelf>>" FROM interior_ref_lists
+            WHERE shop_id = $1
+            FOR UPDATE"#,
+            shop_id,
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
     #[instrument(level = "debug", skip(interior_ref_list, db))]
     pub async fn update_by_shop_id(
         interior_ref_list: PostedInteriorRefList,
-        db: impl Executor<'_, Database = Postgres> + Copy,
+        db: &mut PgConnection,
         owner_id: i32,
         shop_id: i32,
     ) -> Result<Self> {
+        let _timer = time_query(
+            "interior_ref_list",
+            "update_by_shop_id",
+            [("owner_id", owner_id.shape()), ("shop_id", shop_id.shape())],
+        );
         let existing_interior_ref_list = sqlx::query!(
-            "SELECT owner_id FROM interior_ref_lists WHERE shop_id = $1",
+            "SELECT owner_id, revision FROM interior_ref_lists WHERE shop_id = $1 FOR UPDATE",
             shop_id
         )
-        .fetch_one(db)
+        .fetch_one(&mut *db)
         .await?;
         if existing_interior_ref_list.owner_id == owner_id {
+            if interior_ref_list.shop_id != shop_id {
+                return Err(shop_id_immutable(shop_id, interior_ref_list.shop_id));
+            }
+            check_revision(
+                interior_ref_list.expected_revision,
+                existing_interior_ref_list.revision,
+            )?;
             Ok(sqlx::query_as!(
                 Self,
                 r#"UPDATE interior_ref_lists SET
                 ref_list = $2,
                 shelves = $3,
-                updated_at = now()
+                updated_at = now(),
+                revision = revision + 1
                 WHERE shop_id = $1
-                RETURNING id, shop_id, owner_id, created_at, updated_at,
+                RETURNING id, shop_id, owner_id, created_at, updated_at, revision,
                     ref_list as "ref_list: Json<Vec<InteriorRef>>",
                     shelves as "shelves: Json<Vec<Shelf>>""#,
                 shop_id,
                 serde_json::json!(interior_ref_list.ref_list),
                 serde_json::json!(interior_ref_list.shelves),
             )
-            .fetch_one(db)
+            .fetch_one(&mut *db)
             .await?)
         } else {
-            return Err(forbidden_permission());
+            Err(forbidden_permission())
         }
     }
 }
@@ -0,0 +1,221 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{Done, Executor, Postgres};
+use std::collections::BTreeMap;
+use tracing::instrument;
+
+use super::merchandise_list::Merchandise;
+use super::{FormId, ListQuery, Pagination};
+use crate::metrics::queries::{time_query, Shape};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(rename = "merchandise_change_reason", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MerchandiseChangeReason {
+    Transaction,
+    Restock,
+    Manual,
+    /// A correction written by `POST /v1/admin/shops/{id}/reconcile`.
+    Reconcile,
+}
+
+impl MerchandiseChangeReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MerchandiseChangeReason::Transaction => "transaction",
+            MerchandiseChangeReason::Restock => "restock",
+            MerchandiseChangeReason::Manual => "manual",
+            MerchandiseChangeReason::Reconcile => "reconcile",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
+pub struct MerchandiseChange {
+    pub id: i32,
+    pub shop_id: i32,
+    pub mod_name: String,
+    pub local_form_id: FormId,
+    pub quantity_delta: i32,
+    pub reason: MerchandiseChangeReason,
+    pub created_at: NaiveDateTime,
+}
+
+/// Query parameters specific to `GET /v1/shops/{id}/merchandise_changes`, in addition to `Pagination`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Default, Deserialize)]
+pub struct MerchandiseChangeFilter {
+    pub since: Option<NaiveDateTime>,
+}
+
+impl MerchandiseChangeFilter {
+    pub const SUPPORTED_PARAMS: &'static [&'static str] = &["since"];
+}
+
+/// Query parameters of `GET /v1/shops/{id}/merchandise_changes`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct MerchandiseChangeListQuery {
+    pub pagination: Pagination,
+    pub filter: MerchandiseChangeFilter,
+}
+
+impl ListQuery for MerchandiseChangeListQuery {
+    type Filter = MerchandiseChangeFilter;
+    const FILTER_PARAMS: &'static [&'static str] = MerchandiseChangeFilter::SUPPORTED_PARAMS;
+
+    fn new(pagination: Pagination, filter: MerchandiseChangeFilter) -> Result<Self> {
+        Ok(Self { pagination, filter })
+    }
+}
+
+/// A change in quantity of one item, keyed by `(mod_name, local_form_id)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuantityDelta {
+    pub mod_name: String,
+    pub local_form_id: FormId,
+    pub quantity_delta: i32,
+}
+
+impl QuantityDelta {
+    /// Computes the per-item quantity changes needed to go from `old` to `new`.
+    pub fn diff(old: &[Merchandise], new: &[Merchandise]) -> Vec<Self> {
+        let mut deltas: BTreeMap<(&str, FormId), i64> = BTreeMap::new();
+        for merchandise in old {
+            *deltas
+                .entry((&merchandise.mod_name, merchandise.local_form_id))
+                .or_default() -= merchandise.quantity as i64;
+        }
+        for merchandise in new {
+            *deltas
+                .entry((&merchandise.mod_name, merchandise.local_form_id))
+                .or_default() += merchandise.quantity as i64;
+        }
+        deltas
+            .into_iter()
+            .filter(|(_, delta)| *delta != 0)
+            .map(|((mod_name, local_form_id), delta)| Self {
+                mod_name: mod_name.to_string(),
+                local_form_id,
+                quantity_delta: delta.clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+            })
+            .collect()
+    }
+}
+
+impl MerchandiseChange {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] = &["id", "created_at", "quantity_delta"];
+    pub const DEFAULT_ORDER_BY: &'static str = "id ASC";
+
+    /// Records `deltas` for a shop. Should be called with the same DB transaction that mutated the
+    /// quantities so that the feed never disagrees with the merchandise list.
+    #[instrument(level = "debug", skip(db, deltas))]
+    pub async fn create_many(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        deltas: &[QuantityDelta],
+        reason: MerchandiseChangeReason,
+    ) -> Result<()> {
+        let _timer = time_query(
+            "merchandise_change",
+            "create_many",
+            [("shop_id", shop_id.shape()), ("deltas", deltas.shape())],
+        );
+        if deltas.is_empty() {
+            return Ok(());
+        }
+        let mod_names: Vec<String> = deltas.iter().map(|d| d.mod_name.clone()).collect();
+        let local_form_ids: Vec<i64> = deltas.iter().map(|d| d.local_form_id.into()).collect();
+        let quantity_deltas: Vec<i32> = deltas.iter().map(|d| d.quantity_delta).collect();
+        sqlx::query!(
+            "INSERT INTO merchandise_changes
+            (shop_id, mod_name, local_form_id, quantity_delta, reason, created_at)
+            SELECT $1, mod_name, local_form_id, quantity_delta,
+                $5::text::merchandise_change_reason, now()
+            FROM UNNEST($2::varchar[], $3::bigint[], $4::int[])
+                AS t(mod_name, local_form_id, quantity_delta)",
+            shop_id,
+            &mod_names,
+            &local_form_ids,
+            &quantity_deltas,
+            reason.as_str(),
+        )
+        .execute(db)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        query: &MerchandiseChangeListQuery,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "merchandise_change",
+            "list_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM merchandise_changes
+            WHERE shop_id = $1
+                AND ($2::timestamp(3) IS NULL OR created_at > $2)
+            ORDER BY {}
+            LIMIT $3
+            OFFSET $4",
+            order_by
+        ))
+        .bind(shop_id)
+        .bind(query.filter.since)
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of changes `list_by_shop_id` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        query: &MerchandiseChangeListQuery,
+    ) -> Result<i64> {
+        let _timer = time_query(
+            "merchandise_change",
+            "count_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM merchandise_changes
+            WHERE shop_id = $1
+                AND ($2::timestamp(3) IS NULL OR created_at > $2)"#,
+            shop_id,
+            query.filter.since,
+        )
+        .fetch_one(db)
+        .await?
+        .count)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn delete_older_than(
+        db: impl Executor<'_, Database = Postgres>,
+        cutoff: NaiveDateTime,
+    ) -> Result<u64> {
+        let _timer = time_query(
+            "merchandise_change",
+            "delete_older_than",
+            [("cutoff", cutoff.shape())],
+        );
+        Ok(sqlx::query!(
+            "DELETE FROM merchandise_changes WHERE created_at < $1",
+            cutoff
+        )
+        .execute(db)
+        .await?
+        .rows_affected())
+    }
+}
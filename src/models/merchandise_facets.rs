@@ -0,0 +1,104 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::not_found;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FacetCount<T> {
+    pub value: T,
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IsFoodCounts {
+    #[serde(rename = "true")]
+    pub food: i64,
+    #[serde(rename = "false")]
+    pub not_food: i64,
+}
+
+/// The distinct keywords and form kinds in a shop's merchandise list with the number of items
+/// that have each, for populating shelf filters. Sorted by count, most common first.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MerchandiseFacets {
+    pub keywords: Vec<FacetCount<String>>,
+    pub form_kinds: Vec<FacetCount<i32>>,
+    pub is_food_counts: IsFoodCounts,
+    /// The icons the shop's items use, so the web directory can fetch each one once. Items
+    /// without an `icon_key` aren't counted.
+    pub icon_keys: Vec<FacetCount<String>>,
+}
+
+impl MerchandiseFacets {
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_by_shop_id(
+        db: impl Executor<'_, Database = Postgres> + Copy,
+        shop_id: i32,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "merchandise_facets",
+            "get_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        // Also the existence check: lists with no items still produce a row through the LEFT JOIN.
+        let is_food_counts = sqlx::query_as!(
+            IsFoodCounts,
+            r#"SELECT
+                COUNT(item) FILTER (WHERE (item->>'is_food')::boolean) as "food!",
+                COUNT(item) FILTER (WHERE NOT (item->>'is_food')::boolean) as "not_food!"
+            FROM merchandise_lists
+            LEFT JOIN LATERAL jsonb_array_elements(form_list) AS item ON true
+            WHERE shop_id = $1
+            GROUP BY merchandise_lists.id"#,
+            shop_id
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| not_found("Shop does not exist or has no merchandise list"))?;
+        // Items are counted once per keyword even if they list it more than once.
+        let keywords = sqlx::query_as!(
+            FacetCount::<String>,
+            r#"SELECT keyword as "value!", COUNT(DISTINCT item_index) as "count!"
+            FROM merchandise_lists,
+                jsonb_array_elements(form_list) WITH ORDINALITY AS items(item, item_index),
+                jsonb_array_elements_text(item->'keywords') AS keyword
+            WHERE shop_id = $1
+            GROUP BY keyword
+            ORDER BY 2 DESC, 1"#,
+            shop_id
+        )
+        .fetch_all(db)
+        .await?;
+        let form_kinds = sqlx::query_as!(
+            FacetCount::<i32>,
+            r#"SELECT (item->>'form_kind')::integer as "value!", COUNT(*) as "count!"
+            FROM merchandise_lists, jsonb_array_elements(form_list) AS item
+            WHERE shop_id = $1
+            GROUP BY 1
+            ORDER BY 2 DESC, 1"#,
+            shop_id
+        )
+        .fetch_all(db)
+        .await?;
+        let icon_keys = sqlx::query_as!(
+            FacetCount::<String>,
+            r#"SELECT item->>'icon_key' as "value!", COUNT(*) as "count!"
+            FROM merchandise_lists, jsonb_array_elements(form_list) AS item
+            WHERE shop_id = $1 AND item->>'icon_key' IS NOT NULL
+            GROUP BY 1
+            ORDER BY 2 DESC, 1"#,
+            shop_id
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(Self {
+            keywords,
+            form_kinds,
+            is_food_counts,
+            icon_keys,
+        })
+    }
+}
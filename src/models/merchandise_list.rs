@@ -4,27 +4,169 @@ use http::StatusCode;
 use http_api_problem::HttpApiProblem;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sqlx::postgres::PgConnection;
 use sqlx::types::Json;
 use sqlx::{Done, Executor, Postgres};
+use std::collections::HashMap;
 use tracing::instrument;
 use url::Url;
 
-use super::ListParams;
-use crate::problem::forbidden_permission;
+use super::{
+    check_revision, FormId, ListQuery, MerchandiseChange, MerchandiseChangeReason, NoFilter,
+    Pagination, QuantityDelta, Shop,
+};
+use crate::anomalies::{self, Anomaly, AnomalyCode};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{
+    forbidden_permission, not_found, shop_id_immutable, unprocessable_entity, ValidationError,
+};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Default for `MAX_MERCHANDISE_ITEMS`, the most items a merchandise list can hold; see `Config`.
+pub const MAX_MERCHANDISE_ITEMS: usize = 2000;
+const MAX_KEYWORDS: usize = 25;
+const MAX_KEYWORD_LENGTH: usize = 64;
+const MAX_NAME_LENGTH: usize = 256;
+const MAX_MOD_NAME_LENGTH: usize = 128;
+const MAX_ICON_KEY_LENGTH: usize = 128;
+
+/// Checks the free-form fields of an item against their caps, returning the field and message of
+/// each violation. Shared by merchandise lists and transactions, which both store these fields.
+pub fn item_field_errors(
+    mod_name: &str,
+    name: &str,
+    keywords: &[String],
+) -> Vec<(&'static str, String)> {
+    let mut errors = vec![];
+    if mod_name.chars().count() > MAX_MOD_NAME_LENGTH {
+        errors.push((
+            "mod_name",
+            format!("must be at most {} characters", MAX_MOD_NAME_LENGTH),
+        ));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        errors.push((
+            "name",
+            format!("must be at most {} characters", MAX_NAME_LENGTH),
+        ));
+    }
+    if keywords.len() > MAX_KEYWORDS {
+        errors.push((
+            "keywords",
+            format!("cannot contain more than {} keywords", MAX_KEYWORDS),
+        ));
+    }
+    if let Some(index) = keywords
+        .iter()
+        .position(|keyword| keyword.chars().count() > MAX_KEYWORD_LENGTH)
+    {
+        errors.push((
+            "keywords",
+            format!(
+                "keyword at index {} must be at most {} characters",
+                index, MAX_KEYWORD_LENGTH
+            ),
+        ));
+    }
+    errors
+}
+
+/// Checks an item's `icon_key`, which ends up in image urls of the web directory: a mesh path
+/// like `Weapons\Iron\LongSword.nif` or a texture hash is fine, anything else is refused.
+pub fn icon_key_error(icon_key: Option<&str>) -> Option<String> {
+    let icon_key = icon_key?;
+    if icon_key.is_empty() {
+        Some("cannot be empty, leave it out instead".to_string())
+    } else if icon_key.len() > MAX_ICON_KEY_LENGTH {
+        Some(format!(
+            "must be at most {} characters",
+            MAX_ICON_KEY_LENGTH
+        ))
+    } else if !icon_key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-./\\".contains(c))
+    {
+        Some("can only contain ASCII letters, digits, and _ - . / \\".to_string())
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct Merchandise {
     pub mod_name: String,
-    pub local_form_id: u32,
+    pub local_form_id: FormId,
     pub name: String,
     pub quantity: u32,
-    pub form_type: u32,
+    pub form_kind: u32,
     pub is_food: bool,
-    pub price: u32,
+    pub price: i64,
     pub keywords: Vec<String>,
+    /// Names the item's icon for the web directory, as derived by the client. Lists saved before
+    /// it existed, and clients that don't send it, leave it out.
+    #[serde(default)]
+    pub icon_key: Option<String>,
 }
 
+/// The bincode layout of `Merchandise` before `icon_key` was added, which older clients still
+/// send. Bincode has no field names, so a missing trailing field can't just default; bodies that
+/// fail to decode are tried again in this layout (see `DeserializedBody::from_bytes_or_legacy`).
 #[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LegacyMerchandise {
+    pub mod_name: String,
+    pub local_form_id: FormId,
+    pub name: String,
+    pub quantity: u32,
+    pub form_kind: u32,
+    pub is_food: bool,
+    pub price: i64,
+    pub keywords: Vec<String>,
+}
+
+impl From<LegacyMerchandise> for Merchandise {
+    fn from(legacy: LegacyMerchandise) -> Self {
+        Merchandise {
+            mod_name: legacy.mod_name,
+            local_form_id: legacy.local_form_id,
+            name: legacy.name,
+            quantity: legacy.quantity,
+            form_kind: legacy.form_kind,
+            is_food: legacy.is_food,
+            price: legacy.price,
+            keywords: legacy.keywords,
+            icon_key: None,
+        }
+    }
+}
+
+fn from_legacy_form_list(form_list: Json<Vec<LegacyMerchandise>>) -> Json<Vec<Merchandise>> {
+    Json(form_list.0.into_iter().map(Merchandise::from).collect())
+}
+
+/// Items updated without an `icon_key` keep the one they had, so that a client which doesn't know
+/// about icons doesn't wipe out the ones another client set.
+fn keep_icon_keys(form_list: &mut [Merchandise], existing: &[Merchandise]) {
+    let icon_keys: HashMap<(&str, FormId), &String> = existing
+        .iter()
+        .filter_map(|merchandise| {
+            let icon_key = merchandise.icon_key.as_ref()?;
+            Some((
+                (merchandise.mod_name.as_str(), merchandise.local_form_id),
+                icon_key,
+            ))
+        })
+        .collect();
+    for merchandise in form_list
+        .iter_mut()
+        .filter(|merchandise| merchandise.icon_key.is_none())
+    {
+        merchandise.icon_key = icon_keys
+            .get(&(merchandise.mod_name.as_str(), merchandise.local_form_id))
+            .map(|icon_key| icon_key.to_string());
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
 pub struct MerchandiseList {
     pub id: i32,
     pub shop_id: i32,
@@ -32,16 +174,195 @@ pub struct MerchandiseList {
     pub form_list: Json<Vec<Merchandise>>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// Starts at 1 and goes up by one with every update, including purchases.
+    pub revision: i32,
+}
+
+/// Query parameters of `GET /v1/merchandise_lists`, which only page and sort.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct MerchandiseListQuery {
+    pub pagination: Pagination,
+}
+
+impl ListQuery for MerchandiseListQuery {
+    type Filter = NoFilter;
+    const FILTER_PARAMS: &'static [&'static str] = &[];
+
+    fn new(pagination: Pagination, _filter: NoFilter) -> Result<Self> {
+        Ok(Self { pagination })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct PostedMerchandiseList {
     pub shop_id: i32,
     pub owner_id: Option<i32>,
     pub form_list: Json<Vec<Merchandise>>,
+    /// Updates are rejected with a 409 unless the list is still at this revision. Left out, the
+    /// update always wins. Ignored when creating.
+    #[serde(default)]
+    pub expected_revision: Option<i32>,
+}
+
+/// The body of `POST /v1/shops/{id}/merchandise_list`, where the path already names the shop.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostedShopMerchandiseList {
+    #[serde(default)]
+    pub shop_id: Option<i32>,
+    pub form_list: Json<Vec<Merchandise>>,
+    #[serde(default)]
+    pub expected_revision: Option<i32>,
+}
+
+/// A `PostedMerchandiseList` in the bincode layout from before `icon_key`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LegacyPostedMerchandiseList {
+    pub shop_id: i32,
+    pub owner_id: Option<i32>,
+    pub form_list: Json<Vec<LegacyMerchandise>>,
+    pub expected_revision: Option<i32>,
+}
+
+impl From<LegacyPostedMerchandiseList> for PostedMerchandiseList {
+    fn from(legacy: LegacyPostedMerchandiseList) -> Self {
+        PostedMerchandiseList {
+            shop_id: legacy.shop_id,
+            owner_id: legacy.owner_id,
+            form_list: from_legacy_form_list(legacy.form_list),
+            expected_revision: legacy.expected_revision,
+        }
+    }
+}
+
+/// A `PostedShopMerchandiseList` in the bincode layout from before `icon_key`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LegacyPostedShopMerchandiseList {
+    pub shop_id: Option<i32>,
+    pub form_list: Json<Vec<LegacyMerchandise>>,
+    pub expected_revision: Option<i32>,
+}
+
+impl From<LegacyPostedShopMerchandiseList> for PostedShopMerchandiseList {
+    fn from(legacy: LegacyPostedShopMerchandiseList) -> Self {
+        PostedShopMerchandiseList {
+            shop_id: legacy.shop_id,
+            form_list: from_legacy_form_list(legacy.form_list),
+            expected_revision: legacy.expected_revision,
+        }
+    }
+}
+
+impl PostedShopMerchandiseList {
+    // A shop_id in the body is redundant, but one naming a different shop is a client bug
+    pub fn into_posted(self, shop_id: i32) -> Result<PostedMerchandiseList> {
+        match self.shop_id {
+            Some(posted_shop_id) if posted_shop_id != shop_id => {
+                Err(shop_id_immutable(shop_id, posted_shop_id))
+            }
+            _ => Ok(PostedMerchandiseList {
+                shop_id,
+                owner_id: None,
+                form_list: self.form_list,
+                expected_revision: self.expected_revision,
+            }),
+        }
+    }
+}
+
+impl PostedMerchandiseList {
+    /// Whether creating this list would store exactly `list`'s merchandise. Used to recognize a
+    /// retried create of a list that already exists.
+    pub fn matches(&self, list: &MerchandiseList) -> bool {
+        self.form_list.0 == list.form_list.0
+    }
+
+    // Returns warnings about suspicious but allowed items, or a 422 listing every invalid item
+    pub fn validate(&self, max_items: usize) -> Result<Vec<String>> {
+        let mut errors = vec![];
+        let mut warnings = vec![];
+        if self.form_list.len() > max_items {
+            errors.push(ValidationError::new(
+                "form_list",
+                format!("cannot contain more than {} items", max_items),
+            ));
+        }
+        if self.form_list.is_empty() {
+            warnings.push("form_list is empty".to_string());
+        }
+        let mut seen: HashMap<(&str, FormId), usize> = HashMap::new();
+        for (index, merchandise) in self.form_list.iter().enumerate() {
+            if merchandise.mod_name.is_empty() {
+                errors.push(ValidationError::at_index(
+                    "form_list",
+                    index,
+                    "mod_name cannot be empty",
+                ));
+            }
+            for (field, message) in item_field_errors(
+                &merchandise.mod_name,
+                &merchandise.name,
+                &merchandise.keywords,
+            ) {
+                errors.push(ValidationError::at_index(
+                    "form_list",
+                    index,
+                    format!("{} {}", field, message),
+                ));
+            }
+            if let Some(message) = icon_key_error(merchandise.icon_key.as_deref()) {
+                errors.push(ValidationError::at_index(
+                    "form_list",
+                    index,
+                    format!("icon_key {}", message),
+                ));
+            }
+            if merchandise.quantity == 0 {
+                errors.push(ValidationError::at_index(
+                    "form_list",
+                    index,
+                    "quantity must be greater than zero",
+                ));
+            }
+            if let Some(first_index) =
+                seen.insert((&merchandise.mod_name, merchandise.local_form_id), index)
+            {
+                errors.push(ValidationError::at_index(
+                    "form_list",
+                    index,
+                    format!(
+                        "duplicate of item at index {} ({} {})",
+                        first_index, merchandise.mod_name, merchandise.local_form_id
+                    ),
+                ));
+            }
+            if merchandise.price < 0 {
+                errors.push(ValidationError::at_index(
+                    "form_list",
+                    index,
+                    "price cannot be negative",
+                ));
+            }
+            if merchandise.price == 0 {
+                warnings.push(format!("form_list[{}] has a price of 0", index));
+            }
+            if merchandise.keywords.is_empty() {
+                warnings.push(format!("form_list[{}] has no keywords", index));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(unprocessable_entity(errors));
+        }
+        Ok(warnings)
+    }
 }
 
 impl MerchandiseList {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] =
+        &["id", "shop_id", "owner_id", "created_at", "updated_at"];
+    pub const DEFAULT_ORDER_BY: &'static str = "updated_at DESC, id DESC";
+
     pub fn resource_name() -> &'static str {
         "merchandise_list"
     }
@@ -54,12 +375,23 @@ impl MerchandiseList {
         Ok(api_url.join(&format!("{}s/{}", Self::resource_name(), self.pk()))?)
     }
 
+    pub fn shop_url(&self, api_url: &Url) -> Result<Url> {
+        Ok(api_url.join(&format!("shops/{}/{}", self.shop_id, Self::resource_name()))?)
+    }
+
+    pub fn find_merchandise(&self, mod_name: &str, local_form_id: FormId) -> Option<&Merchandise> {
+        self.form_list.iter().find(|merchandise| {
+            merchandise.mod_name == mod_name && merchandise.local_form_id == local_form_id
+        })
+    }
+
     // TODO: this model will probably never need to be accessed through it's ID, should these methods be removed/unimplemented?
     #[instrument(level = "debug", skip(db))]
     pub async fn get(db: impl Executor<'_, Database = Postgres>, id: i32) -> Result<Self> {
+        let _timer = time_query("merchandise_list", "get", [("id", id.shape())]);
         sqlx::query_as!(
             Self,
-            r#"SELECT id, shop_id, owner_id, created_at, updated_at,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
                 form_list as "form_list: Json<Vec<Merchandise>>"
             FROM merchandise_lists
             WHERE id = $1"#,
@@ -70,112 +402,188 @@ impl MerchandiseList {
         .map_err(Error::new)
     }
 
+    /// Like `get`, also locking the row until the end of the DB transaction, so that a
+    /// precondition checked against it (e.g. `If-Match`) still holds when the caller writes it.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_for_update(db: &mut PgConnection, id: i32) -> Result<Self> {
+        let _timer = time_query("merchandise_list", "get_for_update", [("id", id.shape())]);
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
+                form_list as "form_list: Json<Vec<Merchandise>>"
+            FROM merchandise_lists
+            WHERE id = $1
+            FOR UPDATE"#,
+            id,
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
     #[instrument(level = "debug", skip(merchandise_list, db))]
     pub async fn create(
         merchandise_list: PostedMerchandiseList,
-        db: impl Executor<'_, Database = Postgres>,
+        db: &mut PgConnection,
     ) -> Result<Self> {
-        Ok(sqlx::query_as!(
+        let _timer = time_query("merchandise_list", "create", []);
+        Shop::check_owner(
+            &mut *db,
+            merchandise_list.shop_id,
+            merchandise_list.owner_id,
+        )
+        .await?;
+        let saved_merchandise_list = sqlx::query_as!(
             Self,
             r#"INSERT INTO merchandise_lists
             (shop_id, owner_id, form_list, created_at, updated_at)
             VALUES ($1, $2, $3, now(), now())
-            RETURNING id, shop_id, owner_id, created_at, updated_at,
+            RETURNING id, shop_id, owner_id, created_at, updated_at, revision,
                 form_list as "form_list: Json<Vec<Merchandise>>""#,
             merchandise_list.shop_id,
             merchandise_list.owner_id,
             serde_json::json!(merchandise_list.form_list),
         )
-        .fetch_one(db)
-        .await?)
+        .fetch_one(&mut *db)
+        .await?;
+        MerchandiseChange::create_many(
+            &mut *db,
+            saved_merchandise_list.shop_id,
+            &QuantityDelta::diff(&[], &saved_merchandise_list.form_list),
+            MerchandiseChangeReason::Restock,
+        )
+        .await?;
+        Ok(saved_merchandise_list)
     }
 
     #[instrument(level = "debug", skip(db))]
-    pub async fn delete(
-        db: impl Executor<'_, Database = Postgres> + Copy,
-        owner_id: i32,
-        id: i32,
-    ) -> Result<u64> {
-        let merchandise_list =
-            sqlx::query!("SELECT owner_id FROM merchandise_lists WHERE id = $1", id)
-                .fetch_one(db)
-                .await?;
-        if merchandise_list.owner_id == owner_id {
-            return Ok(
-                sqlx::query!("DELETE FROM merchandise_lists WHERE id = $1", id)
-                    .execute(db)
-                    .await?
-                    .rows_affected(),
-            );
-        } else {
-            return Err(forbidden_permission());
+    pub async fn delete(db: &mut PgConnection, owner_id: i32, id: i32) -> Result<u64> {
+        let _timer = time_query(
+            "merchandise_list",
+            "delete",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        let rows_affected = sqlx::query!(
+            "DELETE FROM merchandise_lists WHERE id = $1 AND owner_id = $2",
+            id,
+            owner_id
+        )
+        .execute(&mut *db)
+        .await?
+        .rows_affected();
+        if rows_affected == 0 {
+            let exists = sqlx::query!("SELECT id FROM merchandise_lists WHERE id = $1", id)
+                .fetch_optional(&mut *db)
+                .await?
+                .is_some();
+            return Err(if exists {
+                forbidden_permission()
+            } else {
+                not_found("Merchandise list does not exist or has already been deleted")
+            });
         }
+        Ok(rows_affected)
     }
 
     #[instrument(level = "debug", skip(db))]
     pub async fn list(
         db: impl Executor<'_, Database = Postgres>,
-        list_params: &ListParams,
+        query: &MerchandiseListQuery,
     ) -> Result<Vec<Self>> {
-        let result = if let Some(order_by) = list_params.get_order_by() {
-            sqlx::query_as!(
-                Self,
-                r#"SELECT id, shop_id, owner_id, created_at, updated_at,
-                    form_list as "form_list: Json<Vec<Merchandise>>"
-                FROM merchandise_lists
-                ORDER BY $1
-                LIMIT $2
-                OFFSET $3"#,
-                order_by,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        } else {
-            sqlx::query_as!(
-                Self,
-                r#"SELECT id, shop_id, owner_id, created_at, updated_at,
-                    form_list as "form_list: Json<Vec<Merchandise>>"
-                FROM merchandise_lists
-                LIMIT $1
-                OFFSET $2"#,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        };
-        Ok(result)
+        let _timer = time_query("merchandise_list", "list", []);
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM merchandise_lists
+            ORDER BY {}
+            LIMIT $1
+            OFFSET $2",
+            order_by
+        ))
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of merchandise lists `list` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count(db: impl Executor<'_, Database = Postgres>) -> Result<i64> {
+        let _timer = time_query("merchandise_list", "count", []);
+        Ok(
+            sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM merchandise_lists"#)
+                .fetch_one(db)
+                .await?
+                .count,
+        )
     }
 
     #[instrument(level = "debug", skip(merchandise_list, db))]
     pub async fn update(
-        merchandise_list: PostedMerchandiseList,
-        db: impl Executor<'_, Database = Postgres> + Copy,
+        mut merchandise_list: PostedMerchandiseList,
+        db: &mut PgConnection,
         owner_id: i32,
         id: i32,
     ) -> Result<Self> {
-        let existing_merchandise_list =
-            sqlx::query!("SELECT owner_id FROM merchandise_lists WHERE id = $1", id)
-                .fetch_one(db)
-                .await?;
+        let _timer = time_query(
+            "merchandise_list",
+            "update",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        let existing_merchandise_list = sqlx::query!(
+            r#"SELECT owner_id, shop_id, revision,
+                form_list as "form_list: Json<Vec<Merchandise>>"
+            FROM merchandise_lists
+            WHERE id = $1
+            FOR UPDATE"#,
+            id
+        )
+        .fetch_one(&mut *db)
+        .await?;
         if existing_merchandise_list.owner_id == owner_id {
-            Ok(sqlx::query_as!(
+            if merchandise_list.shop_id != existing_merchandise_list.shop_id {
+                return Err(shop_id_immutable(
+                    existing_merchandise_list.shop_id,
+                    merchandise_list.shop_id,
+                ));
+            }
+            check_revision(
+                merchandise_list.expected_revision,
+                existing_merchandise_list.revision,
+            )?;
+            keep_icon_keys(
+                &mut merchandise_list.form_list,
+                &existing_merchandise_list.form_list,
+            );
+            let updated_merchandise_list = sqlx::query_as!(
                 Self,
                 r#"UPDATE merchandise_lists SET
                 form_list = $2,
-                updated_at = now()
+                updated_at = now(),
+                revision = revision + 1
                 WHERE id = $1
-                RETURNING id, shop_id, owner_id, created_at, updated_at,
+                RETURNING id, shop_id, owner_id, created_at, updated_at, revision,
                     form_list as "form_list: Json<Vec<Merchandise>>""#,
                 id,
                 serde_json::json!(merchandise_list.form_list),
             )
-            .fetch_one(db)
-            .await?)
+            .fetch_one(&mut *db)
+            .await?;
+            MerchandiseChange::create_many(
+                &mut *db,
+                updated_merchandise_list.shop_id,
+                &QuantityDelta::diff(
+                    &existing_merchandise_list.form_list,
+                    &updated_merchandise_list.form_list,
+                ),
+                MerchandiseChangeReason::Manual,
+            )
+            .await?;
+            Ok(updated_merchandise_list)
         } else {
-            return Err(forbidden_permission());
+            Err(forbidden_permission())
         }
     }
 
@@ -184,9 +592,14 @@ impl MerchandiseList {
         db: impl Executor<'_, Database = Postgres>,
         shop_id: i32,
     ) -> Result<Self> {
+        let _timer = time_query(
+            "merchandise_list",
+            "get_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
         sqlx::query_as!(
             Self,
-            r#"SELECT id, shop_id, owner_id, created_at, updated_at,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
                 form_list as "form_list: Json<Vec<Merchandise>>"
             FROM merchandise_lists
             WHERE shop_id = $1"#,
@@ -197,62 +610,181 @@ impl MerchandiseList {
         .map_err(Error::new)
     }
 
+    /// Like `get_by_shop_id`, also locking the row until the end of the DB transaction, so that a
+    /// precondition checked against it (e.g. `If-Match`) still holds when the caller writes it.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_by_shop_id_for_update(db: &mut PgConnection, shop_id: i32) -> Result<Self> {
+        let _timer = time_query(
+            "merchandise_list",
+            "get_by_shop_id_for_update",
+            [("shop_id", shop_id.shape())],
+        );
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, shop_id, owner_id, created_at, updated_at, revision,
+                form_list as "form_list: Json<Vec<Merchandise>>"
+            FROM merchandise_lists
+            WHERE shop_id = $1
+            FOR UPDATE"#,
+            shop_id,
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
     #[instrument(level = "debug", skip(merchandise_list, db))]
     pub async fn update_by_shop_id(
-        merchandise_list: PostedMerchandiseList,
-        db: impl Executor<'_, Database = Postgres> + Copy,
+        mut merchandise_list: PostedMerchandiseList,
+        db: &mut PgConnection,
         owner_id: i32,
         shop_id: i32,
     ) -> Result<Self> {
+        let _timer = time_query(
+            "merchandise_list",
+            "update_by_shop_id",
+            [("owner_id", owner_id.shape()), ("shop_id", shop_id.shape())],
+        );
         let existing_merchandise_list = sqlx::query!(
-            "SELECT owner_id FROM merchandise_lists WHERE shop_id = $1",
+            r#"SELECT owner_id, revision, form_list as "form_list: Json<Vec<Merchandise>>"
+            FROM merchandise_lists
+            WHERE shop_id = $1
+            FOR UPDATE"#,
             shop_id
         )
-        .fetch_one(db)
+        .fetch_one(&mut *db)
         .await?;
         if existing_merchandise_list.owner_id == owner_id {
-            Ok(sqlx::query_as!(
+            if merchandise_list.shop_id != shop_id {
+                return Err(shop_id_immutable(shop_id, merchandise_list.shop_id));
+            }
+            check_revision(
+                merchandise_list.expected_revision,
+                existing_merchandise_list.revision,
+            )?;
+            keep_icon_keys(
+                &mut merchandise_list.form_list,
+                &existing_merchandise_list.form_list,
+            );
+            let updated_merchandise_list = sqlx::query_as!(
                 Self,
                 r#"UPDATE merchandise_lists SET
                 form_list = $2,
-                updated_at = now()
+                updated_at = now(),
+                revision = revision + 1
                 WHERE shop_id = $1
-                RETURNING id, shop_id, owner_id, created_at, updated_at,
+                RETURNING id, shop_id, owner_id, created_at, updated_at, revision,
                     form_list as "form_list: Json<Vec<Merchandise>>""#,
                 shop_id,
                 serde_json::json!(merchandise_list.form_list),
             )
-            .fetch_one(db)
-            .await?)
+            .fetch_one(&mut *db)
+            .await?;
+            MerchandiseChange::create_many(
+                &mut *db,
+                shop_id,
+                &QuantityDelta::diff(
+                    &existing_merchandise_list.form_list,
+                    &updated_merchandise_list.form_list,
+                ),
+                MerchandiseChangeReason::Manual,
+            )
+            .await?;
+            Ok(updated_merchandise_list)
         } else {
-            return Err(forbidden_permission());
+            Err(forbidden_permission())
         }
     }
 
+    /// Overwrites a shop's form_list without an ownership check, recording the difference from
+    /// `previous_form_list` in the change feed. Used for admin corrections, so the caller must
+    /// already hold the merchandise list row lock it read `previous_form_list` under.
+    #[instrument(level = "debug", skip(db, previous_form_list, form_list))]
+    pub async fn replace_form_list_by_shop_id(
+        db: &mut PgConnection,
+        shop_id: i32,
+        previous_form_list: &[Merchandise],
+        form_list: &[Merchandise],
+        reason: MerchandiseChangeReason,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "merchandise_list",
+            "replace_form_list_by_shop_id",
+            [
+                ("shop_id", shop_id.shape()),
+                ("previous_form_list", previous_form_list.shape()),
+                ("form_list", form_list.shape()),
+            ],
+        );
+        let updated_merchandise_list = sqlx::query_as!(
+            Self,
+            r#"UPDATE merchandise_lists SET
+            form_list = $2,
+            updated_at = now(),
+            revision = revision + 1
+            WHERE shop_id = $1
+            RETURNING id, shop_id, owner_id, created_at, updated_at, revision,
+                form_list as "form_list: Json<Vec<Merchandise>>""#,
+            shop_id,
+            serde_json::json!(form_list),
+        )
+        .fetch_one(&mut *db)
+        .await?;
+        MerchandiseChange::create_many(
+            &mut *db,
+            shop_id,
+            &QuantityDelta::diff(previous_form_list, &updated_merchandise_list.form_list),
+            reason,
+        )
+        .await?;
+        Ok(updated_merchandise_list)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     #[instrument(level = "debug", skip(db))]
     pub async fn update_merchandise_quantity(
-        db: impl Executor<'_, Database = Postgres>,
+        db: &mut PgConnection,
         shop_id: i32,
         mod_name: &str,
-        local_form_id: i32,
+        local_form_id: FormId,
         name: &str,
-        form_type: i32,
+        form_kind: i32,
         is_food: bool,
-        price: i32,
+        price: i64,
         quantity_delta: i32,
         keywords: &[String],
+        icon_key: Option<&str>,
     ) -> Result<Self> {
+        let _timer = time_query(
+            "merchandise_list",
+            "update_merchandise_quantity",
+            [
+                ("shop_id", shop_id.shape()),
+                ("mod_name", mod_name.shape()),
+                ("local_form_id", local_form_id.shape()),
+                ("name", name.shape()),
+                ("form_kind", form_kind.shape()),
+                ("is_food", is_food.shape()),
+                ("price", price.shape()),
+                ("quantity_delta", quantity_delta.shape()),
+                ("keywords", keywords.shape()),
+                ("icon_key", icon_key.shape()),
+            ],
+        );
         let add_item = json!([{
             "mod_name": mod_name,
             "local_form_id": local_form_id,
             "name": name,
             "quantity": quantity_delta,
-            "form_type": form_type,
+            "form_kind": form_kind,
             "is_food": is_food,
             "price": price,
             "keywords": keywords,
+            "icon_key": icon_key,
         }]);
-        Ok(sqlx::query_as!(
+        // Form ids are stored in the list in their canonical `0x0005ACE4` form (see `FormId`), so
+        // the item can be matched on the text of its `local_form_id`.
+        let updated_merchandise_list = sqlx::query_as!(
             Self,
             r#"UPDATE
                 merchandise_lists
@@ -270,7 +802,8 @@ impl MerchandiseList {
                             true
                         )
                     ELSE NULL
-                END
+                END,
+                revision = revision + 1
             FROM (
                 SELECT
                     pos - 1 as elem_index,
@@ -295,6 +828,7 @@ impl MerchandiseList {
                 merchandise_lists.owner_id,
                 merchandise_lists.created_at,
                 merchandise_lists.updated_at,
+                merchandise_lists.revision,
                 merchandise_lists.form_list as "form_list: Json<Vec<Merchandise>>""#,
             shop_id,
             mod_name,
@@ -302,24 +836,64 @@ impl MerchandiseList {
             quantity_delta,
             add_item,
         )
-        .fetch_one(db)
+        .fetch_one(&mut *db)
         .await
         .map_err(|error| {
-            let anyhow_error = anyhow!(error);
-            if let Some(db_error) =
-                anyhow_error.downcast_ref::<sqlx::postgres::PgDatabaseError>()
-            {
-                if db_error.code() == "23502" && db_error.column() == Some("form_list") {
-                    return anyhow!(HttpApiProblem::with_title_and_type_from_status(
-                        StatusCode::NOT_FOUND
-                    )
-                    .set_detail(format!(
-                        "Cannot find merchandise to buy with mod_name: {} and local_form_id: {:#010X}",
-                        mod_name, local_form_id
-                    )));
+            match &error {
+                // Transactions lock the shop first, so the shop exists but its list doesn't.
+                sqlx::Error::RowNotFound => {
+                    anomalies::report(Anomaly::new(
+                        AnomalyCode::MissingMerchandiseList,
+                        shop_id,
+                        "transaction found no merchandise list to update",
+                    ));
+                    return not_found("Shop has no merchandise list");
+                }
+                sqlx::Error::ColumnDecode { source, .. } => {
+                    anomalies::report(Anomaly::new(
+                        AnomalyCode::NegativeQuantity,
+                        shop_id,
+                        format!("stored merchandise list could not be decoded: {}", source),
+                    ));
                 }
+                sqlx::Error::Database(db_error) => {
+                    let pg_error = db_error.downcast_ref::<sqlx::postgres::PgDatabaseError>();
+                    // The `ELSE NULL` case above, e.g. a buy of an item that isn't in the list.
+                    if pg_error.code() == "23502" && pg_error.column() == Some("form_list") {
+                        if quantity_delta < 0 {
+                            anomalies::report(
+                                Anomaly::new(
+                                    AnomalyCode::BuyOfUnlistedItem,
+                                    shop_id,
+                                    "buy of an item that is not in the merchandise list",
+                                )
+                                .item(mod_name, local_form_id),
+                            );
+                        }
+                        return anyhow!(HttpApiProblem::with_title_and_type_from_status(
+                            StatusCode::NOT_FOUND
+                        )
+                        .set_detail(format!(
+                            "Cannot find merchandise to buy with mod_name: {} and local_form_id: {}",
+                            mod_name, local_form_id
+                        )));
+                    }
+                }
+                _ => {}
             }
-            anyhow_error
-        })?)
+            anyhow!(error)
+        })?;
+        MerchandiseChange::create_many(
+            &mut *db,
+            shop_id,
+            &[QuantityDelta {
+                mod_name: mod_name.to_string(),
+                local_form_id,
+                quantity_delta,
+            }],
+            MerchandiseChangeReason::Transaction,
+        )
+        .await?;
+        Ok(updated_merchandise_list)
     }
 }
@@ -1,27 +1,152 @@
-use serde::Deserialize;
+use anyhow::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::{de, Deserialize, Deserializer};
 use std::fmt;
 use std::hash::Hash;
+use std::str::FromStr;
 
+use crate::problem::{
+    invalid_order_by, invalid_query_param, revision_mismatch, truncate, MAX_ECHOED_VALUE_CHARS,
+};
+
+pub mod deadline;
+pub mod economy_settings;
+pub mod form_id;
 pub mod interior_ref_list;
+pub mod merchandise_change;
+pub mod merchandise_facets;
 pub mod merchandise_list;
 pub mod model;
+pub mod notification_settings;
 pub mod owner;
+pub mod owner_request_usage;
 pub mod shop;
+pub mod shop_ban;
+pub mod shop_card;
+pub mod shop_deletion_preview;
+pub mod shop_gold_history;
+pub mod shop_reconciliation;
+pub mod shop_request;
+pub mod shop_review;
+pub mod shop_slug;
+pub mod shop_summary;
+pub mod shop_template;
+pub mod shop_translation;
+pub mod shop_type;
+pub mod shop_visit;
 pub mod transaction;
 
-pub use interior_ref_list::{InteriorRefList, PostedInteriorRefList};
-pub use merchandise_list::{MerchandiseList, PostedMerchandiseList};
+pub use deadline::{set_statement_timeout, Deadline};
+pub use economy_settings::EconomySettings;
+pub use form_id::FormId;
+pub use interior_ref_list::{
+    InteriorRefList, InteriorRefListQuery, PostedInteriorRefList, PostedShopInteriorRefList,
+    MAX_INTERIOR_REFS,
+};
+pub use merchandise_change::{
+    MerchandiseChange, MerchandiseChangeListQuery, MerchandiseChangeReason, QuantityDelta,
+};
+pub use merchandise_facets::MerchandiseFacets;
+pub use merchandise_list::{
+    LegacyPostedMerchandiseList, LegacyPostedShopMerchandiseList, Merchandise, MerchandiseList,
+    MerchandiseListQuery, PostedMerchandiseList, PostedShopMerchandiseList, MAX_MERCHANDISE_ITEMS,
+};
+// Unused until the models implement them again (see the TODO in `model.rs`).
+#[allow(unused_imports)]
 pub use model::{Model, UpdateableModel};
-pub use owner::{FullPostedOwner, Owner, PostedOwner};
-pub use shop::{PostedShop, Shop};
-pub use transaction::{PostedTransaction, Transaction};
+pub use notification_settings::{NotificationSettings, PostedNotificationSettings};
+pub use owner::{
+    FullPostedOwner, Owner, OwnerListQuery, OwnerProfile, OwnerSelfView, OwnerWithApiKey,
+    PostedOwner,
+};
+pub use owner_request_usage::OwnerRequestUsage;
+pub use shop::{
+    OwnerFilter, OwnerShop, OwnerShopListQuery, PostedShop, PostedShopRename, PublicShop, Shop,
+    ShopListQuery, ShopTagRules,
+};
+pub use shop_ban::{PostedShopBan, ShopBan};
+pub use shop_card::ShopCard;
+pub use shop_deletion_preview::ShopDeletionPreview;
+pub use shop_gold_history::{GoldHistoryParams, ShopGoldHistory};
+pub use shop_reconciliation::ShopReconciliation;
+pub use shop_request::{
+    PostedShopRequest, PostedShopRequestStatus, ShopRequest, ShopRequestListQuery,
+};
+pub use shop_review::{PostedShopReview, ShopReview, ShopReviewListQuery};
+pub use shop_slug::ShopSlug;
+pub use shop_summary::{ShopSummary, ShopWithLists, SubResourceETags};
+pub use shop_template::ShopTemplate;
+pub use shop_translation::{Locale, LocalizedShop, PostedShopTranslation, ShopTranslation};
+pub use shop_type::ShopType;
+pub use shop_visit::ShopVisit;
+pub use transaction::{
+    LegacyPostedTransaction, PostedTransaction, Transaction, TransactionLimits,
+    TransactionListQuery,
+};
+
+/// Fails with a 409 if the client expected a list to be at a different revision than `current`.
+pub fn check_revision(expected_revision: Option<i32>, current: i32) -> Result<()> {
+    match expected_revision {
+        Some(expected_revision) if expected_revision != current => {
+            Err(revision_mismatch(expected_revision, current))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The query parameters of a list endpoint: the `Pagination` every list shares, plus the filters
+/// of one resource. Each implementor is the whole cache key of its endpoint, so that filters can
+/// be added to one resource without touching the others.
+pub trait ListQuery: Sized {
+    type Filter: DeserializeOwned + Send + 'static;
+    /// Query parameters accepted besides `Pagination::SUPPORTED_PARAMS`.
+    const FILTER_PARAMS: &'static [&'static str];
+
+    /// Combines the normalized pagination with the filter, canonicalizing and validating the
+    /// filter so that equivalent requests share a cache key.
+    fn new(pagination: Pagination, filter: Self::Filter) -> Result<Self>;
+}
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Deserialize)]
+/// The filter of list endpoints that only take `Pagination`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Default, Deserialize)]
+pub struct NoFilter {}
+
+/// Most sort keys `Pagination` accepts in `sort`.
+const MAX_SORT_KEYS: usize = 3;
+
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
 pub enum Order {
     Asc,
     Desc,
 }
 
+impl FromStr for Order {
+    type Err = Error;
+
+    // Case-insensitive, so that `order=desc` works as well as the documented `order=Desc`.
+    fn from_str(s: &str) -> Result<Self> {
+        if s.eq_ignore_ascii_case("asc") {
+            Ok(Order::Asc)
+        } else if s.eq_ignore_ascii_case("desc") {
+            Ok(Order::Desc)
+        } else {
+            Err(invalid_query_param(
+                "order",
+                &format!("\"{}\" is not a direction. Use asc or desc", s),
+            ))
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Order {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value
+            .parse()
+            .map_err(|_| de::Error::custom("order must be asc or desc"))
+    }
+}
+
 impl fmt::Display for Order {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -35,20 +160,250 @@ impl fmt::Display for Order {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Hash, Clone, Deserialize)]
-pub struct ListParams {
+/// One column of a validated sort, e.g. `name.asc` in `?sort=shop_type.asc,name.asc`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct SortKey {
+    pub column: String,
+    pub order: Order,
+}
+
+/// Paging and sorting, shared by the `ListQuery` of every list endpoint.
+//
+// `Debug` is implemented by hand so that instrument spans and cache logs don't echo an
+// arbitrarily long `order_by` or `sort` from the query string.
+//
+// `normalized` parses both the legacy `order_by`/`order` pair and `sort` into `sort_keys` and
+// clears the raw strings, so that the two syntaxes for the same sort share a cache key.
+#[derive(Eq, PartialEq, Hash, Clone, Deserialize)]
+pub struct Pagination {
     limit: Option<i64>,
     offset: Option<i64>,
     order_by: Option<String>,
     order: Option<Order>,
+    sort: Option<String>,
+    #[serde(skip)]
+    sort_keys: Vec<SortKey>,
+}
+
+impl fmt::Debug for Pagination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pagination")
+            .field("limit", &self.limit)
+            .field("offset", &self.offset)
+            .field(
+                "order_by",
+                &self
+                    .order_by
+                    .as_deref()
+                    .map(|order_by| truncate(order_by, MAX_ECHOED_VALUE_CHARS)),
+            )
+            .field("order", &self.order)
+            .field(
+                "sort",
+                &self
+                    .sort
+                    .as_deref()
+                    .map(|sort| truncate(sort, MAX_ECHOED_VALUE_CHARS)),
+            )
+            .field("sort_keys", &self.sort_keys)
+            .finish()
+    }
+}
+
+impl Pagination {
+    pub const SUPPORTED_PARAMS: &'static [&'static str] =
+        &["limit", "offset", "order_by", "order", "sort"];
+
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(10)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0)
+    }
+
+    /// Builds an `ORDER BY` expression from the sort keys, only allowing columns in `columns` since
+    /// the result is interpolated into the query string. Falls back to `default` when there are no
+    /// sort keys, and always breaks ties by `id` so that pages never overlap.
+    pub fn order_by_clause(&self, columns: &[&str], default: &str) -> Result<String> {
+        if self.sort_keys.is_empty() {
+            return Ok(default.to_string());
+        }
+        let mut clause = Vec::with_capacity(self.sort_keys.len() + 1);
+        for SortKey { column, order } in &self.sort_keys {
+            if column != "id" && !columns.contains(&column.as_str()) {
+                return Err(invalid_order_by(column, columns));
+            }
+            clause.push(format!("{} {}", column, order));
+        }
+        if !self.sort_keys.iter().any(|key| key.column == "id") {
+            clause.push("id DESC".to_string());
+        }
+        Ok(clause.join(", "))
+    }
+
+    /// Fills in defaults, parses the sort, and drops parameters that have no effect so that
+    /// equivalent requests share a cache key. Fails with a 400 if the sort is malformed, but
+    /// leaves checking its columns to `order_by_clause`, which knows the model.
+    pub fn normalized(mut self) -> Result<Self> {
+        self.limit.get_or_insert(10);
+        self.offset.get_or_insert(0);
+        if let Some(sort) = self.sort.take() {
+            if self.order_by.is_some() || self.order.is_some() {
+                return Err(invalid_query_param(
+                    "sort",
+                    "cannot be combined with order_by or order",
+                ));
+            }
+            self.sort_keys = parse_sort(&sort)?;
+        } else if let Some(order_by) = self.order_by.take() {
+            // The legacy pair has always sorted descending unless told otherwise.
+            self.sort_keys = vec![SortKey {
+                column: order_by,
+                order: self.order.unwrap_or(Order::Desc),
+            }];
+        }
+        self.order = None;
+        Ok(self)
+    }
 }
 
-impl ListParams {
-    pub fn get_order_by(&self) -> Option<String> {
-        if let Some(order_by) = self.order_by.as_ref() {
-            let order = self.order.as_ref().unwrap_or(&Order::Desc);
-            return Some(format!("{} {}", order_by, order));
+// Parses `column[.asc|.desc],...`. A column without a direction sorts ascending.
+fn parse_sort(sort: &str) -> Result<Vec<SortKey>> {
+    let mut keys: Vec<SortKey> = vec![];
+    for part in sort.split(',') {
+        if keys.len() == MAX_SORT_KEYS {
+            return Err(invalid_query_param(
+                "sort",
+                &format!("cannot have more than {} keys", MAX_SORT_KEYS),
+            ));
+        }
+        let mut pieces = part.trim().splitn(2, '.');
+        let column = pieces.next().unwrap_or_default();
+        if column.is_empty() {
+            return Err(invalid_query_param(
+                "sort",
+                "must be a comma-separated list of column or column.asc or column.desc",
+            ));
         }
-        None
+        let order = match pieces.next() {
+            Some(direction) => direction.parse::<Order>().map_err(|_| {
+                invalid_query_param(
+                    "sort",
+                    &format!(
+                        "\"{}\" is not a direction. Use asc or desc",
+                        truncate(direction, MAX_ECHOED_VALUE_CHARS)
+                    ),
+                )
+            })?,
+            None => Order::Asc,
+        };
+        if keys.iter().any(|key| key.column == column) {
+            return Err(invalid_query_param(
+                "sort",
+                &format!(
+                    "\"{}\" is listed more than once",
+                    truncate(column, MAX_ECHOED_VALUE_CHARS)
+                ),
+            ));
+        }
+        keys.push(SortKey {
+            column: column.to_string(),
+            order,
+        });
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{ListQuery, Pagination, ShopListQuery};
+
+    const COLUMNS: &[&str] = &["name", "shop_type", "gold"];
+
+    fn params(value: serde_json::Value) -> anyhow::Result<Pagination> {
+        serde_json::from_value::<Pagination>(value)
+            .unwrap()
+            .normalized()
+    }
+
+    fn clause(value: serde_json::Value) -> String {
+        params(value)
+            .unwrap()
+            .order_by_clause(COLUMNS, "default")
+            .unwrap()
+    }
+
+    #[test]
+    fn sort_with_mixed_directions() {
+        assert_eq!(
+            clause(json!({ "sort": "shop_type.asc,name.DESC" })),
+            "shop_type ASC, name DESC, id DESC"
+        );
+        assert_eq!(clause(json!({ "sort": "gold" })), "gold ASC, id DESC");
+        assert_eq!(clause(json!({ "sort": "name,id.asc" })), "name ASC, id ASC");
+        assert_eq!(clause(json!({})), "default");
+    }
+
+    #[test]
+    fn legacy_order_by_shares_the_cache_key() {
+        assert_eq!(clause(json!({ "order_by": "gold" })), "gold DESC, id DESC");
+        assert_eq!(
+            clause(json!({ "order_by": "gold", "order": "asc" })),
+            "gold ASC, id DESC"
+        );
+        assert_eq!(
+            clause(json!({ "order_by": "id", "order": "Asc" })),
+            "id ASC"
+        );
+        assert_eq!(
+            params(json!({ "order_by": "gold", "order": "Asc" })).unwrap(),
+            params(json!({ "sort": "gold.asc" })).unwrap()
+        );
+        // `order` alone has no effect.
+        assert_eq!(
+            params(json!({ "order": "Asc" })).unwrap(),
+            params(json!({})).unwrap()
+        );
+    }
+
+    #[test]
+    fn malformed_sorts_are_rejected() {
+        assert!(params(json!({ "sort": "name", "order_by": "gold" })).is_err());
+        assert!(params(json!({ "sort": "name", "order": "Asc" })).is_err());
+        assert!(params(json!({ "sort": "name.up" })).is_err());
+        assert!(params(json!({ "sort": "name,,gold" })).is_err());
+        assert!(params(json!({ "sort": "name,name.desc" })).is_err());
+        assert!(params(json!({ "sort": "name,gold,shop_type,id" })).is_err());
+        assert!(params(json!({ "sort": "name,gold,shop_type" })).is_ok());
+        let unknown = params(json!({ "sort": "name,secret" }))
+            .unwrap()
+            .order_by_clause(COLUMNS, "default");
+        assert!(unknown.is_err());
+    }
+
+    #[test]
+    fn equivalent_shop_filters_share_the_cache_key() {
+        let query = |value: serde_json::Value| {
+            ShopListQuery::new(
+                params(json!({})).unwrap(),
+                serde_json::from_value(value).unwrap(),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            query(json!({ "tag": "Smithing, alchemy,smithing" })),
+            query(json!({ "tag": "alchemy,smithing" }))
+        );
+        assert_eq!(
+            query(json!({ "shop_type": "General Store,alchemist" })),
+            query(json!({ "shop_type": "alchemist,general_store" }))
+        );
+        assert_ne!(
+            query(json!({ "tag": "alchemy" })),
+            query(json!({ "tag": "smithing" }))
+        );
     }
 }
@@ -3,7 +3,7 @@ use async_trait::async_trait;
 use sqlx::postgres::PgPool;
 use url::Url;
 
-use super::ListParams;
+use super::Pagination;
 
 // TODO: I stopped using this because I needed to accept a transaction instead of a &PgPool for these methods on certain models.
 // It would be nice to find a way to impl this trait for all my models so I don't have to keep redoing the `url` function on
@@ -13,6 +13,7 @@ use super::ListParams;
 // &PgPool, &mut PgConnection and &mut Transaction"
 //
 // I attempted to use `impl Executor<Database = Postgres>` in 0.3.5 but it created a recursive type error :(
+#[allow(dead_code)]
 #[async_trait]
 pub trait Model
 where
@@ -33,9 +34,10 @@ where
     async fn get(db: &PgPool, id: i32) -> Result<Self>;
     async fn create(self, db: &PgPool) -> Result<Self>;
     async fn delete(db: &PgPool, owner_id: i32, id: i32) -> Result<u64>;
-    async fn list(db: &PgPool, list_params: &ListParams) -> Result<Vec<Self>>;
+    async fn list(db: &PgPool, list_params: &Pagination) -> Result<Vec<Self>>;
 }
 
+#[allow(dead_code)]
 #[async_trait]
 pub trait UpdateableModel
 where
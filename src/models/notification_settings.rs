@@ -0,0 +1,127 @@
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{Executor, Postgres};
+use std::convert::TryFrom;
+use tracing::instrument;
+
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{unprocessable_entity, ValidationError};
+
+/// Which events a shop's owner wants to be notified of. Shops without a saved row get the default,
+/// which notifies of everything.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct NotificationSettings {
+    pub on_sale: bool,
+    pub on_out_of_stock: bool,
+    /// Notify when an item's quantity drops to or below this many after a sale.
+    pub on_low_stock_threshold: Option<i32>,
+}
+
+impl Default for NotificationSettings {
+    fn default() -> Self {
+        Self {
+            on_sale: true,
+            on_out_of_stock: true,
+            on_low_stock_threshold: None,
+        }
+    }
+}
+
+/// A partial update. Omitted fields keep their current value, and `on_low_stock_threshold` can be
+/// explicitly set to `null` to turn low stock notifications off.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct PostedNotificationSettings {
+    pub on_sale: Option<bool>,
+    pub on_out_of_stock: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    pub on_low_stock_threshold: Option<Option<i64>>,
+}
+
+// Distinguishes a field set to `null` (`Some(None)`) from a missing field (`None`).
+fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
+impl PostedNotificationSettings {
+    /// Applies the update to `current`, rejecting thresholds that are negative or too large.
+    pub fn apply(self, current: NotificationSettings) -> Result<NotificationSettings> {
+        let on_low_stock_threshold = match self.on_low_stock_threshold {
+            None => current.on_low_stock_threshold,
+            Some(None) => None,
+            Some(Some(threshold)) => match i32::try_from(threshold) {
+                Ok(threshold) if threshold >= 0 => Some(threshold),
+                _ => {
+                    return Err(unprocessable_entity(vec![ValidationError::new(
+                        "on_low_stock_threshold",
+                        format!("must be between 0 and {}", i32::MAX),
+                    )]))
+                }
+            },
+        };
+        Ok(NotificationSettings {
+            on_sale: self.on_sale.unwrap_or(current.on_sale),
+            on_out_of_stock: self.on_out_of_stock.unwrap_or(current.on_out_of_stock),
+            on_low_stock_threshold,
+        })
+    }
+}
+
+impl NotificationSettings {
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "notification_settings",
+            "get_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        Ok(sqlx::query_as!(
+            Self,
+            "SELECT on_sale, on_out_of_stock, on_low_stock_threshold
+            FROM shop_notification_settings
+            WHERE shop_id = $1",
+            shop_id
+        )
+        .fetch_optional(db)
+        .await?
+        .unwrap_or_default())
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn save_by_shop_id(
+        &self,
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "notification_settings",
+            "save_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        Ok(sqlx::query_as!(
+            Self,
+            "INSERT INTO shop_notification_settings
+            (shop_id, on_sale, on_out_of_stock, on_low_stock_threshold, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, now(), now())
+            ON CONFLICT (shop_id) DO UPDATE SET
+                on_sale = EXCLUDED.on_sale,
+                on_out_of_stock = EXCLUDED.on_out_of_stock,
+                on_low_stock_threshold = EXCLUDED.on_low_stock_threshold,
+                updated_at = now()
+            RETURNING on_sale, on_out_of_stock, on_low_stock_threshold",
+            shop_id,
+            self.on_sale,
+            self.on_out_of_stock,
+            self.on_low_stock_threshold,
+        )
+        .fetch_one(db)
+        .await?)
+    }
+}
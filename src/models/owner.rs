@@ -2,31 +2,198 @@ use anyhow::{Error, Result};
 use chrono::prelude::*;
 use ipnetwork::IpNetwork;
 use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgConnection;
 use sqlx::{Done, Executor, Postgres};
 use tracing::instrument;
 use url::Url;
 use uuid::Uuid;
 
-use super::ListParams;
-use crate::problem::forbidden_permission;
+use super::{ListQuery, Pagination};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{
+    forbidden_permission, invalid_query_param, not_found, truncate, unprocessable_entity,
+    ValidationError, MAX_ECHOED_VALUE_CHARS,
+};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_DISPLAY_NAME_LENGTH: usize = 255;
+const MAX_BIO_LENGTH: usize = 2000;
+const MAX_AVATAR_URL_LENGTH: usize = 2048;
+const MAX_IDS: usize = 100;
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
 pub struct Owner {
     pub id: i32,
     pub name: String,
+    #[allow(dead_code)]
     #[serde(skip_serializing)]
     pub api_key: Uuid,
+    #[allow(dead_code)]
     #[serde(skip_serializing)]
     pub ip_address: Option<IpNetwork>,
     pub mod_version: i32,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// Query parameters specific to `GET /v1/owners`, in addition to `Pagination`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Default, Deserialize)]
+pub struct OwnerListFilter {
+    /// Comma-separated owner ids to look up in one request, e.g. `?ids=1,2,3`. Pagination params
+    /// are ignored when it is given.
+    pub ids: Option<String>,
+}
+
+impl OwnerListFilter {
+    pub const SUPPORTED_PARAMS: &'static [&'static str] = &["ids"];
+
+    /// The requested ids, sorted and without duplicates so that equivalent requests share a
+    /// cache entry.
+    pub fn ids(&self) -> Result<Option<Vec<i32>>> {
+        let ids = match &self.ids {
+            Some(ids) => ids,
+            None => return Ok(None),
+        };
+        let mut parsed = ids
+            .split(',')
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                id.parse::<i32>().map_err(|_| {
+                    invalid_query_param(
+                        "ids",
+                        &format!("\"{}\" is not an id", truncate(id, MAX_ECHOED_VALUE_CHARS)),
+                    )
+                })
+            })
+            .collect::<Result<Vec<i32>>>()?;
+        parsed.sort_unstable();
+        parsed.dedup();
+        if parsed.is_empty() {
+            return Err(invalid_query_param("ids", "must contain at least one id"));
+        }
+        if parsed.len() > MAX_IDS {
+            return Err(invalid_query_param(
+                "ids",
+                &format!("cannot contain more than {} ids", MAX_IDS),
+            ));
+        }
+        Ok(Some(parsed))
+    }
+}
+
+/// Query parameters of `GET /v1/owners`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct OwnerListQuery {
+    pub pagination: Pagination,
+    pub filter: OwnerListFilter,
+}
+
+impl ListQuery for OwnerListQuery {
+    type Filter = OwnerListFilter;
+    const FILTER_PARAMS: &'static [&'static str] = OwnerListFilter::SUPPORTED_PARAMS;
+
+    // Rejects malformed `ids` before the handler runs.
+    fn new(pagination: Pagination, filter: OwnerListFilter) -> Result<Self> {
+        filter.ids()?;
+        Ok(Self { pagination, filter })
+    }
+}
+
+/// An owner as they see themselves, with fields that are never shown to anyone else.
+#[derive(Debug, Serialize)]
+pub struct OwnerSelfView {
+    #[serde(flatten)]
+    pub owner: Owner,
+    /// The address the owner registered from.
+    pub ip_address: Option<IpNetwork>,
+}
+
+/// The public subset of an owner served by `GET /v1/owners/{id}/profile` for community pages.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OwnerProfile {
+    pub id: i32,
+    pub name: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+    pub created_at: NaiveDateTime,
 }
 
+/// The profile fields are optional so that clients that don't know about them leave them
+/// unchanged on update. An empty string clears a field.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PostedOwner {
     pub name: String,
     pub mod_version: i32,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+impl PostedOwner {
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = vec![];
+        if self.name.chars().count() > MAX_NAME_LENGTH {
+            errors.push(ValidationError::new(
+                "name",
+                format!("cannot be longer than {} characters", MAX_NAME_LENGTH),
+            ));
+        }
+        if let Some(display_name) = &self.display_name {
+            if display_name.chars().count() > MAX_DISPLAY_NAME_LENGTH {
+                errors.push(ValidationError::new(
+                    "display_name",
+                    format!(
+                        "cannot be longer than {} characters",
+                        MAX_DISPLAY_NAME_LENGTH
+                    ),
+                ));
+            }
+        }
+        if let Some(bio) = &self.bio {
+            if bio.chars().count() > MAX_BIO_LENGTH {
+                errors.push(ValidationError::new(
+                    "bio",
+                    format!("cannot be longer than {} characters", MAX_BIO_LENGTH),
+                ));
+            }
+        }
+        if let Some(avatar_url) = self.avatar_url.as_deref().filter(|url| !url.is_empty()) {
+            if avatar_url.chars().count() > MAX_AVATAR_URL_LENGTH {
+                errors.push(ValidationError::new(
+                    "avatar_url",
+                    format!("cannot be longer than {} characters", MAX_AVATAR_URL_LENGTH),
+                ));
+            } else {
+                match Url::parse(avatar_url) {
+                    Ok(url) if url.scheme() == "https" && url.host().is_some() => {}
+                    Ok(_) => {
+                        errors.push(ValidationError::new("avatar_url", "must be an https URL"))
+                    }
+                    Err(_) => errors.push(ValidationError::new("avatar_url", "is not a valid URL")),
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(unprocessable_entity(errors))
+        }
+    }
+}
+
+/// The JSON response to creating an owner without an `api-key` header: the owner with the key the
+/// server generated for it. This is the only response that ever contains an api key. Bincode
+/// responses are the owner followed by the key, serialized as `(&Owner, Uuid)`.
+#[derive(Debug, Serialize)]
+pub struct OwnerWithApiKey<'a, T: Serialize> {
+    #[serde(flatten)]
+    pub owner: &'a T,
+    pub api_key: Uuid,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -35,9 +202,16 @@ pub struct FullPostedOwner {
     pub api_key: Uuid,
     pub ip_address: Option<IpNetwork>,
     pub mod_version: i32,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>,
 }
 
 impl Owner {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] =
+        &["id", "name", "mod_version", "created_at", "updated_at"];
+    pub const DEFAULT_ORDER_BY: &'static str = "updated_at DESC, id DESC";
+
     pub fn resource_name() -> &'static str {
         "owner"
     }
@@ -52,27 +226,84 @@ impl Owner {
 
     #[instrument(level = "debug", skip(db))]
     pub async fn get(db: impl Executor<'_, Database = Postgres>, id: i32) -> Result<Self> {
+        let _timer = time_query("owner", "get", [("id", id.shape())]);
         sqlx::query_as!(Self, "SELECT * FROM owners WHERE id = $1", id)
             .fetch_one(db)
             .await
             .map_err(Error::new)
     }
 
+    /// Like `get`, also locking the row until the end of the DB transaction, so that a
+    /// precondition checked against it (e.g. `If-Match`) still holds when the caller writes it.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_for_update(db: &mut PgConnection, id: i32) -> Result<Self> {
+        let _timer = time_query("owner", "get_for_update", [("id", id.shape())]);
+        sqlx::query_as!(Self, "SELECT * FROM owners WHERE id = $1 FOR UPDATE", id)
+            .fetch_one(db)
+            .await
+            .map_err(Error::new)
+    }
+
+    /// Finds the owner a player registered before, for telling them their id when they register
+    /// again with the same name and api key.
+    #[instrument(level = "debug", skip(db, api_key))]
+    pub async fn get_by_name_and_api_key(
+        db: impl Executor<'_, Database = Postgres>,
+        name: &str,
+        api_key: Uuid,
+    ) -> Result<Option<Self>> {
+        let _timer = time_query(
+            "owner",
+            "get_by_name_and_api_key",
+            [("name", name.shape()), ("api_key", api_key.shape())],
+        );
+        sqlx::query_as!(
+            Self,
+            "SELECT * FROM owners WHERE name = $1 AND api_key = $2",
+            name,
+            api_key
+        )
+        .fetch_optional(db)
+        .await
+        .map_err(Error::new)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_profile(
+        db: impl Executor<'_, Database = Postgres>,
+        id: i32,
+    ) -> Result<OwnerProfile> {
+        let _timer = time_query("owner", "get_profile", [("id", id.shape())]);
+        sqlx::query_as!(
+            OwnerProfile,
+            "SELECT id, name, display_name, bio, avatar_url, created_at FROM owners WHERE id = $1",
+            id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
     #[instrument(level = "debug", skip(owner, db))]
     pub async fn create(
         owner: FullPostedOwner,
         db: impl Executor<'_, Database = Postgres>,
     ) -> Result<Self> {
+        let _timer = time_query("owner", "create", []);
         Ok(sqlx::query_as!(
             Self,
             "INSERT INTO owners
-                (name, api_key, ip_address, mod_version, created_at, updated_at)
-                VALUES ($1, $2, $3, $4, now(), now())
+                (name, api_key, ip_address, mod_version, display_name, bio, avatar_url,
+                created_at, updated_at)
+                VALUES ($1, $2, $3, $4, NULLIF($5, ''), NULLIF($6, ''), NULLIF($7, ''), now(), now())
                 RETURNING *",
             owner.name,
             owner.api_key,
             owner.ip_address,
             owner.mod_version,
+            owner.display_name,
+            owner.bio,
+            owner.avatar_url,
         )
         .fetch_one(db)
         .await?)
@@ -80,65 +311,93 @@ impl Owner {
 
     #[instrument(level = "debug", skip(db))]
     pub async fn delete(
-        db: impl Executor<'_, Database = Postgres> + Copy,
+        db: impl Executor<'_, Database = Postgres>,
         owner_id: i32,
         id: i32,
     ) -> Result<u64> {
-        let owner = sqlx::query!("SELECT id FROM owners WHERE id = $1", id)
-            .fetch_one(db)
-            .await?;
-        if owner.id == owner_id {
-            Ok(sqlx::query!("DELETE FROM owners WHERE id = $1", id)
-                .execute(db)
-                .await?
-                .rows_affected())
-        } else {
+        let _timer = time_query(
+            "owner",
+            "delete",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        if id != owner_id {
             return Err(forbidden_permission());
         }
+        let rows_affected = sqlx::query!("DELETE FROM owners WHERE id = $1", id)
+            .execute(db)
+            .await?
+            .rows_affected();
+        if rows_affected == 0 {
+            return Err(not_found(
+                "Owner does not exist or has already been deleted",
+            ));
+        }
+        Ok(rows_affected)
     }
 
     #[instrument(level = "debug", skip(db))]
     pub async fn list(
         db: impl Executor<'_, Database = Postgres>,
-        list_params: &ListParams,
+        query: &OwnerListQuery,
     ) -> Result<Vec<Self>> {
-        let result = if let Some(order_by) = list_params.get_order_by() {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM owners
-                ORDER BY $1
-                LIMIT $2
-                OFFSET $3",
-                order_by,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        } else {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM owners
-                LIMIT $1
-                OFFSET $2",
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
+        let _timer = time_query("owner", "list", []);
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM owners
+            ORDER BY {}
+            LIMIT $1
+            OFFSET $2",
+            order_by
+        ))
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of owners `list` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count(db: impl Executor<'_, Database = Postgres>) -> Result<i64> {
+        let _timer = time_query("owner", "count", []);
+        Ok(sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM owners"#)
+            .fetch_one(db)
             .await?
-        };
-        Ok(result)
+            .count)
+    }
+
+    /// Owners with any of the given ids, in ascending id order. Ids that don't exist are left out.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_many(
+        db: impl Executor<'_, Database = Postgres>,
+        ids: &[i32],
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query("owner", "get_many", [("ids", ids.shape())]);
+        Ok(sqlx::query_as!(
+            Self,
+            "SELECT * FROM owners WHERE id = ANY($1) ORDER BY id",
+            ids
+        )
+        .fetch_all(db)
+        .await?)
     }
 
     #[instrument(level = "debug", skip(owner, db))]
     pub async fn update(
         owner: PostedOwner,
-        db: impl Executor<'_, Database = Postgres> + Copy,
+        db: &mut PgConnection,
         owner_id: i32,
         id: i32,
     ) -> Result<Self> {
+        let _timer = time_query(
+            "owner",
+            "update",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
         let existing_owner = sqlx::query!("SELECT id FROM owners WHERE id = $1", id)
-            .fetch_one(db)
+            .fetch_one(&mut *db)
             .await?;
         if existing_owner.id == owner_id {
             Ok(sqlx::query_as!(
@@ -146,17 +405,23 @@ impl Owner {
                 "UPDATE owners SET
                 name = $2,
                 mod_version = $3,
+                display_name = NULLIF(COALESCE($4, display_name), ''),
+                bio = NULLIF(COALESCE($5, bio), ''),
+                avatar_url = NULLIF(COALESCE($6, avatar_url), ''),
                 updated_at = now()
                 WHERE id = $1
                 RETURNING *",
                 id,
                 owner.name,
                 owner.mod_version,
+                owner.display_name,
+                owner.bio,
+                owner.avatar_url,
             )
-            .fetch_one(db)
+            .fetch_one(&mut *db)
             .await?)
         } else {
-            return Err(forbidden_permission());
+            Err(forbidden_permission())
         }
     }
 }
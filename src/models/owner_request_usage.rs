@@ -0,0 +1,74 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use sqlx::{Done, Executor, Postgres};
+use tracing::instrument;
+
+use crate::metrics::queries::{time_query, Shape};
+
+/// An owner's request counts for one monthly quota period, as flushed from `usage::Usage`.
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct OwnerRequestUsage {
+    pub owner_id: i32,
+    pub period_start: NaiveDate,
+    pub reads: i64,
+    pub writes: i64,
+}
+
+impl OwnerRequestUsage {
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_period(
+        db: impl Executor<'_, Database = Postgres>,
+        period_start: NaiveDate,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "owner_request_usage",
+            "list_by_period",
+            [("period_start", period_start.shape())],
+        );
+        Ok(sqlx::query_as!(
+            Self,
+            "SELECT owner_id, period_start, reads, writes
+            FROM owner_request_usage
+            WHERE period_start = $1",
+            period_start
+        )
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// Adds each row's counts to the stored counts for its owner and period. Rows for owners that
+    /// have been deleted since the requests were counted are dropped.
+    #[instrument(level = "debug", skip(db, usage))]
+    pub async fn add_many(
+        db: impl Executor<'_, Database = Postgres>,
+        usage: &[Self],
+    ) -> Result<u64> {
+        let _timer = time_query(
+            "owner_request_usage",
+            "add_many",
+            [("usage", usage.shape())],
+        );
+        let owner_ids: Vec<i32> = usage.iter().map(|row| row.owner_id).collect();
+        let period_starts: Vec<NaiveDate> = usage.iter().map(|row| row.period_start).collect();
+        let reads: Vec<i64> = usage.iter().map(|row| row.reads).collect();
+        let writes: Vec<i64> = usage.iter().map(|row| row.writes).collect();
+        Ok(sqlx::query!(
+            "INSERT INTO owner_request_usage (owner_id, period_start, reads, writes, updated_at)
+            SELECT t.owner_id, t.period_start, t.reads, t.writes, now()
+            FROM UNNEST($1::int[], $2::date[], $3::bigint[], $4::bigint[])
+                AS t(owner_id, period_start, reads, writes)
+            JOIN owners ON owners.id = t.owner_id
+            ON CONFLICT (owner_id, period_start) DO UPDATE SET
+                reads = owner_request_usage.reads + EXCLUDED.reads,
+                writes = owner_request_usage.writes + EXCLUDED.writes,
+                updated_at = now()",
+            &owner_ids,
+            &period_starts,
+            &reads,
+            &writes,
+        )
+        .execute(db)
+        .await?
+        .rows_affected())
+    }
+}
@@ -1,25 +1,55 @@
 use anyhow::{Error, Result};
 use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sqlx::postgres::PgConnection;
 use sqlx::{Done, Executor, Postgres};
+use std::collections::BTreeSet;
 use tracing::instrument;
+use unicode_normalization::UnicodeNormalization;
 use url::Url;
 
-use super::ListParams;
-use crate::problem::forbidden_permission;
+use super::shop_type::MAX_CUSTOM_SHOP_TYPE_LENGTH;
+use super::{EconomySettings, ListQuery, NoFilter, NotificationSettings, Pagination, ShopType};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{
+    banned_from_shop, forbidden_permission, not_found, shop_gone, truncate, unprocessable_entity,
+    ValidationError, MAX_ECHOED_VALUE_CHARS,
+};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(super) const MAX_NAME_LENGTH: usize = 255;
+pub(super) const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_PRIVATE_NOTES_LENGTH: usize = 4096;
+const MAX_VENDOR_KEYWORDS: usize = 50;
+const MAX_VENDOR_KEYWORD_LENGTH: usize = 128;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LENGTH: usize = 32;
+
+/// A shop as it is stored. It is never serialized itself: responses send a `PublicShop`, or an
+/// `OwnerShop` to the shop's owner, so a field only the owner may see can't reach anyone else.
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct Shop {
     pub id: i32,
     pub name: String,
     pub owner_id: i32,
     pub description: Option<String>,
-    pub gold: i32,
-    pub shop_type: String,
+    pub gold: i64,
+    pub shop_type: ShopType,
     pub vendor_keywords: Vec<String>,
     pub vendor_keywords_exclude: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub last_activity_at: NaiveDateTime,
+    pub tags: Vec<String>,
+    /// Only sent to the shop's owner, in an `OwnerShop`.
+    pub private_notes: Option<String>,
+    /// Distinct players who visited the shop, each counted at most once a day. Cached shop
+    /// responses are not evicted when it changes, so it can lag behind by
+    /// `caches::SHOP_TTL`, and it is left out of the shop's ETag (see
+    /// `handlers::shop::shop_etag_fields`).
+    pub visits_count: i64,
+    pub review_count: i64,
+    /// The mean of the shop's review ratings, or `None` until it has been reviewed.
+    pub average_rating: Option<f64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -27,13 +57,520 @@ pub struct PostedShop {
     pub name: String,
     pub owner_id: Option<i32>,
     pub description: Option<String>,
-    pub gold: Option<i32>,
-    pub shop_type: Option<String>,
+    pub gold: Option<i64>,
+    pub shop_type: Option<ShopType>,
     pub vendor_keywords: Option<Vec<String>>,
     pub vendor_keywords_exclude: Option<bool>,
+    pub tags: Option<Vec<String>>,
+    /// Left unchanged on update when omitted. An empty string clears the notes.
+    pub private_notes: Option<String>,
+}
+
+/// Body of `POST /v1/shops/{id}/rename`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PostedShopRename {
+    pub name: String,
+}
+
+impl PostedShopRename {
+    /// Normalizes the name to NFC and checks it like `PostedShop::validate` does.
+    pub fn validate(&mut self) -> Result<()> {
+        self.name = self.name.nfc().collect();
+        match name_error(&self.name) {
+            Some(error) => Err(unprocessable_entity(vec![error])),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A shop as anyone but its owner sees it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PublicShop {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: i32,
+    pub description: Option<String>,
+    pub gold: i64,
+    pub shop_type: ShopType,
+    pub vendor_keywords: Vec<String>,
+    pub vendor_keywords_exclude: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub last_activity_at: NaiveDateTime,
+    pub tags: Vec<String>,
+    pub visits_count: i64,
+    pub review_count: i64,
+    pub average_rating: Option<f64>,
+}
+
+impl From<Shop> for PublicShop {
+    fn from(shop: Shop) -> Self {
+        Self {
+            id: shop.id,
+            name: shop.name,
+            owner_id: shop.owner_id,
+            description: shop.description,
+            gold: shop.gold,
+            shop_type: shop.shop_type,
+            vendor_keywords: shop.vendor_keywords,
+            vendor_keywords_exclude: shop.vendor_keywords_exclude,
+            created_at: shop.created_at,
+            updated_at: shop.updated_at,
+            last_activity_at: shop.last_activity_at,
+            tags: shop.tags,
+            visits_count: shop.visits_count,
+            review_count: shop.review_count,
+            average_rating: shop.average_rating,
+        }
+    }
+}
+
+/// A shop as its owner sees it, in JSON and bincode alike: the fields of a `PublicShop`, followed
+/// by the ones that are never shown to anyone else.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OwnerShop {
+    pub id: i32,
+    pub name: String,
+    pub owner_id: i32,
+    pub description: Option<String>,
+    pub gold: i64,
+    pub shop_type: ShopType,
+    pub vendor_keywords: Vec<String>,
+    pub vendor_keywords_exclude: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub last_activity_at: NaiveDateTime,
+    pub tags: Vec<String>,
+    pub visits_count: i64,
+    pub review_count: i64,
+    pub average_rating: Option<f64>,
+    pub private_notes: Option<String>,
+    pub notification_settings: NotificationSettings,
+}
+
+impl OwnerShop {
+    pub fn new(shop: Shop, notification_settings: NotificationSettings) -> Self {
+        Self {
+            id: shop.id,
+            name: shop.name,
+            owner_id: shop.owner_id,
+            description: shop.description,
+            gold: shop.gold,
+            shop_type: shop.shop_type,
+            vendor_keywords: shop.vendor_keywords,
+            vendor_keywords_exclude: shop.vendor_keywords_exclude,
+            created_at: shop.created_at,
+            updated_at: shop.updated_at,
+            last_activity_at: shop.last_activity_at,
+            tags: shop.tags,
+            visits_count: shop.visits_count,
+            review_count: shop.review_count,
+            average_rating: shop.average_rating,
+            private_notes: shop.private_notes,
+            notification_settings,
+        }
+    }
+}
+
+/// Which owner's shops `GET /v1/shops?owner_id=` lists. `me` is resolved to the requesting owner
+/// before the filter is used as a cache key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OwnerFilter {
+    Me,
+    Id(i32),
+}
+
+impl<'de> Deserialize<'de> for OwnerFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        if value == "me" {
+            return Ok(OwnerFilter::Me);
+        }
+        value
+            .parse()
+            .map(OwnerFilter::Id)
+            .map_err(|_| de::Error::custom("owner_id must be an owner id or \"me\""))
+    }
+}
+
+/// Query parameters specific to `GET /v1/shops`, in addition to `Pagination`.
+///
+/// Like `Pagination`, `Debug` truncates the player-provided `tag` and `name`.
+#[derive(Eq, PartialEq, Hash, Clone, Default, Deserialize)]
+pub struct ShopListFilter {
+    pub active_since: Option<NaiveDateTime>,
+    /// Part of the shop name, matched ignoring case and Unicode normalization, e.g. `?name=händler`.
+    pub name: Option<String>,
+    /// Comma-separated tags. Shops with ANY of the tags match (OR), e.g. `?tag=alchemy,blacksmith`.
+    pub tag: Option<String>,
+    pub owner_id: Option<OwnerFilter>,
+    /// Comma-separated shop types, matched like `tag`, e.g. `?shop_type=alchemist,apothecary`.
+    pub shop_type: Option<String>,
+}
+
+impl std::fmt::Debug for ShopListFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ShopListFilter")
+            .field("active_since", &self.active_since)
+            .field(
+                "name",
+                &self
+                    .name
+                    .as_deref()
+                    .map(|name| truncate(name, MAX_ECHOED_VALUE_CHARS)),
+            )
+            .field(
+                "tag",
+                &self
+                    .tag
+                    .as_deref()
+                    .map(|tag| truncate(tag, MAX_ECHOED_VALUE_CHARS)),
+            )
+            .field("owner_id", &self.owner_id)
+            .field(
+                "shop_type",
+                &self
+                    .shop_type
+                    .as_deref()
+                    .map(|shop_type| truncate(shop_type, MAX_ECHOED_VALUE_CHARS)),
+            )
+            .finish()
+    }
+}
+
+impl ShopListFilter {
+    pub const SUPPORTED_PARAMS: &'static [&'static str] =
+        &["active_since", "name", "tag", "owner_id", "shop_type"];
+
+    /// The `name` filter as a `LIKE` pattern for the `normalized_name` column.
+    pub fn name_pattern(&self) -> Option<String> {
+        self.name.as_ref().map(|name| {
+            let mut pattern = String::with_capacity(name.len() + 2);
+            pattern.push('%');
+            for c in name.chars() {
+                if matches!(c, '%' | '_' | '\\') {
+                    pattern.push('\\');
+                }
+                pattern.push(c);
+            }
+            pattern.push('%');
+            pattern
+        })
+    }
+
+    pub fn tags(&self) -> Option<Vec<String>> {
+        self.tag.as_ref().map(|tag| {
+            tag.split(',')
+                .map(|tag| tag.trim().to_lowercase())
+                .filter(|tag| !tag.is_empty())
+                .collect()
+        })
+    }
+
+    /// The shop types to filter by, normalized the same way posted ones are so that
+    /// `?shop_type=General Store` finds `general_store` shops.
+    pub fn shop_types(&self) -> Option<Vec<String>> {
+        self.shop_type.as_ref().map(|shop_type| {
+            shop_type
+                .split(',')
+                .filter_map(|shop_type| shop_type.parse::<ShopType>().ok())
+                .map(|shop_type| shop_type.to_string())
+                .collect()
+        })
+    }
+
+    /// The owner to filter by, once `me` has been resolved.
+    pub fn owner_id(&self) -> Option<i32> {
+        match self.owner_id {
+            Some(OwnerFilter::Id(id)) => Some(id),
+            _ => None,
+        }
+    }
+}
+
+/// Query parameters of `GET /v1/shops`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct ShopListQuery {
+    pub pagination: Pagination,
+    pub filter: ShopListFilter,
+}
+
+impl ListQuery for ShopListQuery {
+    type Filter = ShopListFilter;
+    const FILTER_PARAMS: &'static [&'static str] = ShopListFilter::SUPPORTED_PARAMS;
+
+    // Both `tag` and `shop_type` match ANY of their values, so they are rewritten to their
+    // normalized values, sorted, so that `?tag=Smithing,alchemy` and `?tag=alchemy,smithing`
+    // share a cache key. `name` is normalized like the `normalized_name` column for the same reason.
+    fn new(pagination: Pagination, mut filter: ShopListFilter) -> Result<Self> {
+        filter.name = filter
+            .name
+            .map(|name| normalized_name(&name))
+            .filter(|name| !name.is_empty());
+        filter.tag = filter.tags().map(canonical_list);
+        filter.shop_type = filter.shop_types().map(canonical_list);
+        Ok(Self { pagination, filter })
+    }
+}
+
+/// Query parameters of `GET /v1/owners/{id}/shops`, which only page and sort.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct OwnerShopListQuery {
+    pub pagination: Pagination,
+}
+
+impl ListQuery for OwnerShopListQuery {
+    type Filter = NoFilter;
+    const FILTER_PARAMS: &'static [&'static str] = &[];
+
+    fn new(pagination: Pagination, _filter: NoFilter) -> Result<Self> {
+        Ok(Self { pagination })
+    }
+}
+
+// Shop names are stored as NFC, and the database compares them by `lower(normalize(name, NFC))`.
+fn normalized_name(name: &str) -> String {
+    name.trim().nfc().collect::<String>().to_lowercase()
+}
+
+fn canonical_list(mut values: Vec<String>) -> String {
+    values.sort();
+    values.dedup();
+    values.join(",")
+}
+
+/// The tags shops may be given.
+///
+/// When a vocabulary is configured (`SHOP_TAG_VOCABULARY`), tags must be one of those listed.
+/// Otherwise any tag is allowed, subject to the count and length caps.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShopTagRules {
+    pub vocabulary: Option<BTreeSet<String>>,
+    pub max_tags: usize,
+    pub max_tag_length: usize,
+}
+
+impl Default for ShopTagRules {
+    fn default() -> Self {
+        Self {
+            vocabulary: None,
+            max_tags: MAX_TAGS,
+            max_tag_length: MAX_TAG_LENGTH,
+        }
+    }
+}
+
+// Checks an NFC-normalized shop name, for both `PostedShop` and `PostedShopRename`.
+fn name_error(name: &str) -> Option<ValidationError> {
+    if name.trim().is_empty() {
+        Some(ValidationError::new("name", "cannot be empty"))
+    } else if name.chars().all(|c| c.is_control() || c.is_whitespace()) {
+        Some(ValidationError::new(
+            "name",
+            "cannot contain only control characters",
+        ))
+    } else if name.chars().count() > MAX_NAME_LENGTH {
+        Some(ValidationError::new(
+            "name",
+            format!("cannot be longer than {} characters", MAX_NAME_LENGTH),
+        ))
+    } else {
+        None
+    }
+}
+
+impl PostedShop {
+    // Validates the posted name, description, shop_type, vendor_keywords, and tags and then trims and case-insensitively
+    // deduplicates them so that `Shop::accepts_keywords` comparisons and tag filters behave
+    // predictably. Tags are also lowercased. The name is normalized to NFC first, so that names
+    // typed with combining characters are stored the same way as precomposed ones.
+    pub fn validate(&mut self, tag_rules: &ShopTagRules, economy: &EconomySettings) -> Result<()> {
+        let mut errors = vec![];
+        self.name = self.name.nfc().collect();
+        errors.extend(name_error(&self.name));
+        if let Some(gold) = self.gold {
+            if gold < 0 {
+                errors.push(ValidationError::new("gold", "cannot be negative"));
+            }
+        }
+        if let Some(description) = &self.description {
+            if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+                errors.push(ValidationError::new(
+                    "description",
+                    format!(
+                        "cannot be longer than {} characters",
+                        MAX_DESCRIPTION_LENGTH
+                    ),
+                ));
+            }
+        }
+        if let Some(ShopType::Custom(custom)) = &self.shop_type {
+            if !economy.custom_shop_types {
+                errors.push(ValidationError::new(
+                    "shop_type",
+                    format!(
+                        "unknown shop type \"{}\", must be one of: {}",
+                        truncate(custom, MAX_ECHOED_VALUE_CHARS),
+                        ShopType::known_names()
+                    ),
+                ));
+            } else if custom.chars().count() > MAX_CUSTOM_SHOP_TYPE_LENGTH {
+                errors.push(ValidationError::new(
+                    "shop_type",
+                    format!(
+                        "cannot be longer than {} characters",
+                        MAX_CUSTOM_SHOP_TYPE_LENGTH
+                    ),
+                ));
+            }
+        }
+        if let Some(private_notes) = &self.private_notes {
+            if private_notes.chars().count() > MAX_PRIVATE_NOTES_LENGTH {
+                errors.push(ValidationError::new(
+                    "private_notes",
+                    format!(
+                        "cannot be longer than {} characters",
+                        MAX_PRIVATE_NOTES_LENGTH
+                    ),
+                ));
+            }
+        }
+        if let Some(vendor_keywords) = &self.vendor_keywords {
+            if vendor_keywords.len() > MAX_VENDOR_KEYWORDS {
+                errors.push(ValidationError::new(
+                    "vendor_keywords",
+                    format!("cannot contain more than {} keywords", MAX_VENDOR_KEYWORDS),
+                ));
+            }
+            for (index, keyword) in vendor_keywords.iter().enumerate() {
+                let keyword = keyword.trim();
+                if keyword.is_empty() {
+                    errors.push(ValidationError::at_index(
+                        "vendor_keywords",
+                        index,
+                        "keyword cannot be empty",
+                    ));
+                } else if keyword.chars().count() > MAX_VENDOR_KEYWORD_LENGTH {
+                    errors.push(ValidationError::at_index(
+                        "vendor_keywords",
+                        index,
+                        format!(
+                            "keyword cannot be longer than {} characters",
+                            MAX_VENDOR_KEYWORD_LENGTH
+                        ),
+                    ));
+                }
+            }
+        }
+        if let Some(tags) = &self.tags {
+            if tags.len() > tag_rules.max_tags {
+                errors.push(ValidationError::new(
+                    "tags",
+                    format!("cannot contain more than {} tags", tag_rules.max_tags),
+                ));
+            }
+            for (index, tag) in tags.iter().enumerate() {
+                let tag = tag.trim().to_lowercase();
+                if tag.is_empty() {
+                    errors.push(ValidationError::at_index(
+                        "tags",
+                        index,
+                        "tag cannot be empty",
+                    ));
+                } else if tag.chars().count() > tag_rules.max_tag_length {
+                    errors.push(ValidationError::at_index(
+                        "tags",
+                        index,
+                        format!(
+                            "tag cannot be longer than {} characters",
+                            tag_rules.max_tag_length
+                        ),
+                    ));
+                } else if let Some(vocabulary) = &tag_rules.vocabulary {
+                    if !vocabulary.contains(&tag) {
+                        errors.push(ValidationError::at_index(
+                            "tags",
+                            index,
+                            format!(
+                                "unknown tag \"{}\", must be one of: {}",
+                                truncate(&tag, MAX_ECHOED_VALUE_CHARS),
+                                vocabulary.iter().cloned().collect::<Vec<_>>().join(", ")
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(unprocessable_entity(errors));
+        }
+
+        if let Some(tags) = self.tags.take() {
+            let mut normalized: Vec<String> = Vec::with_capacity(tags.len());
+            for tag in tags {
+                let tag = tag.trim().to_lowercase();
+                if !normalized.contains(&tag) {
+                    normalized.push(tag);
+                }
+            }
+            self.tags = Some(normalized);
+        }
+        if let Some(vendor_keywords) = self.vendor_keywords.take() {
+            let mut normalized: Vec<String> = Vec::with_capacity(vendor_keywords.len());
+            let mut seen = BTreeSet::new();
+            for keyword in vendor_keywords {
+                let keyword = keyword.trim();
+                // Full Unicode lowercasing, so that keywords from mods with non-ASCII editor IDs
+                // are deduplicated too.
+                if seen.insert(keyword.to_lowercase()) {
+                    normalized.push(keyword.to_string());
+                }
+            }
+            self.vendor_keywords = Some(normalized);
+        }
+        Ok(())
+    }
+
+    /// Whether creating this (validated) shop would store exactly `shop`, once defaults are
+    /// filled in from `settings`. Used to recognize a retried create of a shop that already exists.
+    pub fn matches(&self, shop: &Shop, settings: &EconomySettings) -> bool {
+        self.name == shop.name
+            && self.description == shop.description
+            && self.gold.unwrap_or(settings.default_shop_gold) == shop.gold
+            && self
+                .shop_type
+                .as_ref()
+                .unwrap_or(&settings.default_shop_type)
+                == &shop.shop_type
+            && self
+                .vendor_keywords
+                .as_ref()
+                .unwrap_or(&settings.default_vendor_keywords)
+                == &shop.vendor_keywords
+            && self.vendor_keywords_exclude.unwrap_or(true) == shop.vendor_keywords_exclude
+            && self.tags.as_deref().unwrap_or_default() == shop.tags.as_slice()
+            && self
+                .private_notes
+                .as_deref()
+                .filter(|notes| !notes.is_empty())
+                == shop.private_notes.as_deref()
+    }
 }
 
 impl Shop {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] = &[
+        "id",
+        "name",
+        "owner_id",
+        "gold",
+        "shop_type",
+        "created_at",
+        "updated_at",
+        "last_activity_at",
+        "visits_count",
+    ];
+    pub const DEFAULT_ORDER_BY: &'static str = "updated_at DESC, id DESC";
+
     pub fn resource_name() -> &'static str {
         "shop"
     }
@@ -48,136 +585,309 @@ impl Shop {
 
     #[instrument(level = "debug", skip(db))]
     pub async fn get(db: impl Executor<'_, Database = Postgres>, id: i32) -> Result<Self> {
-        sqlx::query_as!(Self, "SELECT * FROM shops WHERE id = $1", id)
+        let _timer = time_query("shop", "get", [("id", id.shape())]);
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, name, owner_id, description, gold, shop_type as "shop_type: ShopType",
+                vendor_keywords, vendor_keywords_exclude, created_at, updated_at, last_activity_at,
+                tags, private_notes, visits_count, review_count, average_rating
+            FROM shops WHERE id = $1"#,
+            id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
+    /// Like `get`, also locking the row until the end of the DB transaction, so that a
+    /// precondition checked against it (e.g. `If-Match`) still holds when the caller writes it.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_for_update(db: &mut PgConnection, id: i32) -> Result<Self> {
+        let _timer = time_query("shop", "get_for_update", [("id", id.shape())]);
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, name, owner_id, description, gold, shop_type as "shop_type: ShopType",
+                vendor_keywords, vendor_keywords_exclude, created_at, updated_at, last_activity_at,
+                tags, private_notes, visits_count, review_count, average_rating
+            FROM shops WHERE id = $1
+            FOR UPDATE"#,
+            id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_owner_id(db: impl Executor<'_, Database = Postgres>, id: i32) -> Result<i32> {
+        let _timer = time_query("shop", "get_owner_id", [("id", id.shape())]);
+        Ok(sqlx::query!("SELECT owner_id FROM shops WHERE id = $1", id)
             .fetch_one(db)
-            .await
-            .map_err(Error::new)
+            .await?
+            .owner_id)
     }
 
-    #[instrument(level = "debug", skip(shop, db))]
+    /// The owner's shop named `name`, compared ignoring case and Unicode normalization like the
+    /// `shops_unique_normalized_name_and_owner_id` index.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_by_owner_id_and_name(
+        db: impl Executor<'_, Database = Postgres>,
+        owner_id: i32,
+        name: &str,
+    ) -> Result<Option<Self>> {
+        let _timer = time_query(
+            "shop",
+            "get_by_owner_id_and_name",
+            [("owner_id", owner_id.shape()), ("name", name.shape())],
+        );
+        Ok(sqlx::query_as!(
+            Self,
+            r#"SELECT id, name, owner_id, description, gold, shop_type as "shop_type: ShopType",
+                vendor_keywords, vendor_keywords_exclude, created_at, updated_at, last_activity_at,
+                tags, private_notes, visits_count, review_count, average_rating
+            FROM shops WHERE owner_id = $1 AND normalized_name = lower(normalize($2, NFC))"#,
+            owner_id,
+            name,
+        )
+        .fetch_optional(db)
+        .await?)
+    }
+
+    /// Fails with 403 unless the shop belongs to `owner_id`, and keeps the shop from changing hands
+    /// until the transaction ends. A shop that doesn't exist passes so that the caller's insert
+    /// reports it through the `shop_id` foreign key.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn check_owner(db: &mut PgConnection, id: i32, owner_id: Option<i32>) -> Result<()> {
+        let _timer = time_query(
+            "shop",
+            "check_owner",
+            [("id", id.shape()), ("owner_id", owner_id.shape())],
+        );
+        let shop = sqlx::query!("SELECT owner_id FROM shops WHERE id = $1 FOR SHARE", id)
+            .fetch_optional(&mut *db)
+            .await?;
+        match shop {
+            Some(shop) if Some(shop.owner_id) != owner_id => Err(forbidden_permission()),
+            _ => Ok(()),
+        }
+    }
+
+    #[instrument(level = "debug", skip(shop, db, settings))]
     pub async fn create(
         shop: PostedShop,
         db: impl Executor<'_, Database = Postgres>,
+        settings: &EconomySettings,
     ) -> Result<Self> {
+        let _timer = time_query("shop", "create", []);
         Ok(sqlx::query_as!(
             Self,
-            "INSERT INTO shops
+            r#"INSERT INTO shops
             (name, owner_id, description, gold, shop_type, vendor_keywords,
-             vendor_keywords_exclude, created_at, updated_at)
-            VALUES ($1, $2, $3, $4, $5, $6, $7, now(), now())
-            RETURNING *",
+             vendor_keywords_exclude, tags, private_notes, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, NULLIF($9, ''), now(), now())
+            RETURNING id, name, owner_id, description, gold, shop_type as "shop_type: ShopType",
+                vendor_keywords, vendor_keywords_exclude, created_at, updated_at, last_activity_at,
+                tags, private_notes, visits_count, review_count, average_rating"#,
             shop.name,
             shop.owner_id,
             shop.description,
-            shop.gold.unwrap_or(0),
-            shop.shop_type.unwrap_or("general_store".to_string()),
+            shop.gold.unwrap_or(settings.default_shop_gold),
+            shop.shop_type
+                .as_ref()
+                .unwrap_or(&settings.default_shop_type)
+                .as_str(),
             &shop
                 .vendor_keywords
-                .unwrap_or_else(|| vec!["VendorItemKey".to_string(), "VendorNoSale".to_string()]),
+                .unwrap_or_else(|| settings.default_vendor_keywords.clone()),
             shop.vendor_keywords_exclude.unwrap_or(true),
+            &shop.tags.unwrap_or_default(),
+            shop.private_notes,
         )
         .fetch_one(db)
         .await?)
     }
 
+    /// Deletes in one ownership-scoped statement, so that of two concurrent deletes, the one that
+    /// waited on the other's row lock finds nothing left and gets a 404 rather than a 500. The
+    /// 404 (not a 204) tells the caller that its request didn't delete anything.
     #[instrument(level = "debug", skip(db))]
-    pub async fn delete(
-        db: impl Executor<'_, Database = Postgres> + Copy,
-        owner_id: i32,
-        id: i32,
-    ) -> Result<u64> {
-        let shop = sqlx::query!("SELECT owner_id FROM shops WHERE id = $1", id)
-            .fetch_one(db)
-            .await?;
-        if shop.owner_id == owner_id {
-            return Ok(sqlx::query!("DELETE FROM shops WHERE shops.id = $1", id)
-                .execute(db)
+    pub async fn delete(db: &mut PgConnection, owner_id: i32, id: i32) -> Result<u64> {
+        let _timer = time_query(
+            "shop",
+            "delete",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        let rows_affected = sqlx::query!(
+            "DELETE FROM shops WHERE id = $1 AND owner_id = $2",
+            id,
+            owner_id
+        )
+        .execute(&mut *db)
+        .await?
+        .rows_affected();
+        if rows_affected == 0 {
+            let exists = sqlx::query!("SELECT id FROM shops WHERE id = $1", id)
+                .fetch_optional(&mut *db)
                 .await?
-                .rows_affected());
-        } else {
-            return Err(forbidden_permission());
+                .is_some();
+            return Err(if exists {
+                forbidden_permission()
+            } else {
+                not_found("Shop does not exist or has already been deleted")
+            });
         }
+        Ok(rows_affected)
     }
 
+    // The `owner_id` filter is served by the `shops_owner_id_and_name` index. `name` is matched
+    // against `normalized_name`, so it ignores case and Unicode normalization like the unique index.
     #[instrument(level = "debug", skip(db))]
     pub async fn list(
         db: impl Executor<'_, Database = Postgres>,
-        list_params: &ListParams,
+        query: &ShopListQuery,
     ) -> Result<Vec<Self>> {
-        let result = if let Some(order_by) = list_params.get_order_by() {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM shops
-                ORDER BY $1
-                LIMIT $2
-                OFFSET $3",
-                order_by,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        } else {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM shops
-                LIMIT $1
-                OFFSET $2",
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        };
-        Ok(result)
+        let _timer = time_query("shop", "list", []);
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM shops
+            WHERE ($1::timestamp(3) IS NULL OR last_activity_at >= $1)
+                AND ($2::text[] IS NULL OR tags && $2)
+                AND ($5::integer IS NULL OR owner_id = $5)
+                AND ($6::text[] IS NULL OR shop_type = ANY($6))
+                AND ($7::text IS NULL OR normalized_name LIKE $7)
+            ORDER BY {}
+            LIMIT $3
+            OFFSET $4",
+            order_by
+        ))
+        .bind(query.filter.active_since)
+        .bind(query.filter.tags())
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .bind(query.filter.owner_id())
+        .bind(query.filter.shop_types())
+        .bind(query.filter.name_pattern())
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of shops `list` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count(
+        db: impl Executor<'_, Database = Postgres>,
+        query: &ShopListQuery,
+    ) -> Result<i64> {
+        let _timer = time_query("shop", "count", []);
+        let tags = query.filter.tags();
+        let shop_types = query.filter.shop_types();
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM shops
+            WHERE ($1::timestamp(3) IS NULL OR last_activity_at >= $1)
+                AND ($2::text[] IS NULL OR tags && $2)
+                AND ($3::integer IS NULL OR owner_id = $3)
+                AND ($4::text[] IS NULL OR shop_type = ANY($4))
+                AND ($5::text IS NULL OR normalized_name LIKE $5)"#,
+            query.filter.active_since,
+            tags.as_deref(),
+            query.filter.owner_id(),
+            shop_types.as_deref(),
+            query.filter.name_pattern(),
+        )
+        .fetch_one(db)
+        .await?
+        .count)
     }
 
     #[instrument(level = "debug", skip(shop, db))]
     pub async fn update(
         shop: PostedShop,
-        db: impl Executor<'_, Database = Postgres> + Copy,
+        db: &mut PgConnection,
         owner_id: i32,
         id: i32,
     ) -> Result<Self> {
+        let _timer = time_query(
+            "shop",
+            "update",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
         let existing_shop = sqlx::query!("SELECT owner_id FROM shops WHERE id = $1", id)
-            .fetch_one(db)
+            .fetch_one(&mut *db)
             .await?;
         if existing_shop.owner_id == owner_id {
             Ok(sqlx::query_as!(
                 Self,
-                "UPDATE shops SET
+                r#"UPDATE shops SET
                 name = $2,
                 owner_id = $3,
                 description = $4,
-                gold = $5,
-                shop_type = $6,
-                vendor_keywords = $7,
-                vendor_keywords_exclude = $8,
+                gold = COALESCE($5, gold),
+                shop_type = COALESCE($6, shop_type),
+                vendor_keywords = COALESCE($7, vendor_keywords),
+                vendor_keywords_exclude = COALESCE($8, vendor_keywords_exclude),
+                tags = COALESCE($9, tags),
+                private_notes = NULLIF(COALESCE($10, private_notes), ''),
                 updated_at = now()
                 WHERE id = $1
-                RETURNING *",
+                RETURNING id, name, owner_id, description, gold, shop_type as "shop_type: ShopType",
+                vendor_keywords, vendor_keywords_exclude, created_at, updated_at, last_activity_at,
+                tags, private_notes, visits_count, review_count, average_rating"#,
                 id,
                 shop.name,
                 shop.owner_id,
                 shop.description,
                 shop.gold,
-                shop.shop_type,
-                &shop.vendor_keywords.unwrap_or_else(|| vec![]),
+                shop.shop_type.as_ref().map(ShopType::as_str),
+                shop.vendor_keywords.as_deref(),
                 shop.vendor_keywords_exclude,
+                shop.tags.as_deref(),
+                shop.private_notes,
             )
-            .fetch_one(db)
+            .fetch_one(&mut *db)
             .await?)
         } else {
-            return Err(forbidden_permission());
+            Err(forbidden_permission())
         }
     }
 
+    /// Sets the shop's name, leaving everything else as is. The caller checks ownership and name
+    /// collisions with the shop locked (see `handlers::shop::rename_shop`), so that the
+    /// `shops_unique_normalized_name_and_owner_id` index is only a backstop.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn rename(db: &mut PgConnection, id: i32, name: &str) -> Result<Self> {
+        let _timer = time_query(
+            "shop",
+            "rename",
+            [("id", id.shape()), ("name", name.shape())],
+        );
+        sqlx::query_as!(
+            Self,
+            r#"UPDATE shops SET name = $2, updated_at = now()
+            WHERE id = $1
+            RETURNING id, name, owner_id, description, gold, shop_type as "shop_type: ShopType",
+            vendor_keywords, vendor_keywords_exclude, created_at, updated_at, last_activity_at,
+            tags, private_notes, visits_count, review_count, average_rating"#,
+            id,
+            name,
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
+    }
+
     #[instrument(level = "debug", skip(db))]
     pub async fn accepts_keywords(
         db: impl Executor<'_, Database = Postgres>,
         id: i32,
         keywords: &[String],
     ) -> Result<bool> {
+        let _timer = time_query(
+            "shop",
+            "accepts_keywords",
+            [("id", id.shape()), ("keywords", keywords.shape())],
+        );
         // Macro not available, see: https://github.com/launchbadge/sqlx/issues/428
         Ok(sqlx::query_scalar(
             "SELECT EXISTS (
@@ -198,21 +908,140 @@ impl Shop {
         .await?)
     }
 
+    /// Locks the shop row until the end of the DB transaction so that the shop cannot be deleted
+    /// while rows referencing it are being written. Fails with a 404 if the shop is already gone.
     #[instrument(level = "debug", skip(db))]
-    pub async fn update_gold(
+    pub async fn lock_for_key_share(
         db: impl Executor<'_, Database = Postgres>,
         id: i32,
-        gold_delta: i32,
     ) -> Result<()> {
+        let _timer = time_query("shop", "lock_for_key_share", [("id", id.shape())]);
+        sqlx::query!("SELECT id FROM shops WHERE id = $1 FOR KEY SHARE", id)
+            .fetch_optional(db)
+            .await?
+            .ok_or_else(shop_gone)?;
+        Ok(())
+    }
+
+    /// Like `lock_for_key_share`, for a transaction by `customer_id` at the shop. Fails with a 403
+    /// if the shop's owner has banned the customer, which is checked in the same query.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn lock_for_transaction(
+        db: impl Executor<'_, Database = Postgres>,
+        id: i32,
+        customer_id: i32,
+    ) -> Result<()> {
+        let _timer = time_query(
+            "shop",
+            "lock_for_transaction",
+            [("id", id.shape()), ("customer_id", customer_id.shape())],
+        );
+        let shop = sqlx::query!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM shop_bans
+                WHERE shop_id = shops.id AND banned_owner_id = $2 AND shops.owner_id <> $2
+            ) as "banned!"
+            FROM shops WHERE id = $1 FOR KEY SHARE"#,
+            id,
+            customer_id
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(shop_gone)?;
+        if shop.banned {
+            return Err(banned_from_shop());
+        }
+        Ok(())
+    }
+
+    /// The owner's shops, served by the `shops_owner_id_and_name` index. Counted by
+    /// `count_by_owner_id`.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_owner_id(
+        db: impl Executor<'_, Database = Postgres>,
+        owner_id: i32,
+        query: &OwnerShopListQuery,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query("shop", "list_by_owner_id", [("owner_id", owner_id.shape())]);
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM shops
+            WHERE owner_id = $1
+            ORDER BY {}
+            LIMIT $2
+            OFFSET $3",
+            order_by
+        ))
+        .bind(owner_id)
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count_by_owner_id(
+        db: impl Executor<'_, Database = Postgres>,
+        owner_id: i32,
+    ) -> Result<i64> {
+        let _timer = time_query(
+            "shop",
+            "count_by_owner_id",
+            [("owner_id", owner_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM shops WHERE owner_id = $1"#,
+            owner_id
+        )
+        .fetch_one(db)
+        .await?
+        .count)
+    }
+
+    /// Marks the shop as active now. Called on transactions and merchandise/interior changes.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn record_activity(
+        db: impl Executor<'_, Database = Postgres>,
+        id: i32,
+    ) -> Result<()> {
+        let _timer = time_query("shop", "record_activity", [("id", id.shape())]);
         sqlx::query!(
             "UPDATE shops SET
-                gold = gold + $2
+                last_activity_at = now()
             WHERE id = $1",
             id,
-            gold_delta,
         )
         .execute(db)
         .await?;
         Ok(())
     }
+
+    /// Adds `gold_delta` to the shop's gold and returns the new balance. Fails with
+    /// `insufficient_shop_gold` if the shop can't afford it.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn update_gold(
+        db: impl Executor<'_, Database = Postgres>,
+        id: i32,
+        gold_delta: i64,
+    ) -> Result<i64> {
+        let _timer = time_query(
+            "shop",
+            "update_gold",
+            [("id", id.shape()), ("gold_delta", gold_delta.shape())],
+        );
+        Ok(sqlx::query!(
+            "UPDATE shops SET
+                gold = gold + $2
+            WHERE id = $1
+            RETURNING gold",
+            id,
+            gold_delta,
+        )
+        .fetch_one(db)
+        .await?
+        .gold)
+    }
 }
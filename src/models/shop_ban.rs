@@ -0,0 +1,111 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgConnection;
+use tracing::instrument;
+
+use super::Shop;
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{forbidden_permission, not_found, unprocessable_entity, ValidationError};
+
+const MAX_REASON_LENGTH: usize = 1000;
+
+/// A player (owner) that a shop's owner has banned from buying or selling at the shop. Bans only
+/// apply to transactions; banned players can still see the shop.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShopBan {
+    pub shop_id: i32,
+    pub banned_owner_id: i32,
+    pub reason: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostedShopBan {
+    pub banned_owner_id: i32,
+    pub reason: Option<String>,
+}
+
+impl PostedShopBan {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(reason) = &self.reason {
+            if reason.chars().count() > MAX_REASON_LENGTH {
+                return Err(unprocessable_entity(vec![ValidationError::new(
+                    "reason",
+                    format!("must be at most {} characters", MAX_REASON_LENGTH),
+                )]));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ShopBan {
+    /// Bans the player from the shop, or updates the reason of an existing ban. Only the shop's
+    /// owner can ban, and they can't ban themselves.
+    #[instrument(level = "debug", skip(db, ban))]
+    pub async fn create(
+        db: &mut PgConnection,
+        shop_id: i32,
+        owner_id: i32,
+        ban: PostedShopBan,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_ban",
+            "create",
+            [("shop_id", shop_id.shape()), ("owner_id", owner_id.shape())],
+        );
+        if Shop::get_owner_id(&mut *db, shop_id).await? != owner_id {
+            return Err(forbidden_permission());
+        }
+        if ban.banned_owner_id == owner_id {
+            return Err(unprocessable_entity(vec![ValidationError::new(
+                "banned_owner_id",
+                "cannot ban the shop's own owner",
+            )]));
+        }
+        Ok(sqlx::query_as!(
+            Self,
+            "INSERT INTO shop_bans (shop_id, banned_owner_id, reason, created_at)
+            VALUES ($1, $2, $3, now())
+            ON CONFLICT (shop_id, banned_owner_id) DO UPDATE SET reason = EXCLUDED.reason
+            RETURNING shop_id, banned_owner_id, reason, created_at",
+            shop_id,
+            ban.banned_owner_id,
+            ban.reason,
+        )
+        .fetch_one(&mut *db)
+        .await?)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn delete(
+        db: &mut PgConnection,
+        shop_id: i32,
+        owner_id: i32,
+        banned_owner_id: i32,
+    ) -> Result<()> {
+        let _timer = time_query(
+            "shop_ban",
+            "delete",
+            [
+                ("shop_id", shop_id.shape()),
+                ("owner_id", owner_id.shape()),
+                ("banned_owner_id", banned_owner_id.shape()),
+            ],
+        );
+        if Shop::get_owner_id(&mut *db, shop_id).await? != owner_id {
+            return Err(forbidden_permission());
+        }
+        sqlx::query!(
+            "DELETE FROM shop_bans WHERE shop_id = $1 AND banned_owner_id = $2 RETURNING shop_id",
+            shop_id,
+            banned_owner_id,
+        )
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| not_found("Owner is not banned from this shop"))?;
+        Ok(())
+    }
+}
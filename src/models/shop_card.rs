@@ -0,0 +1,85 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use super::ShopType;
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::not_found;
+
+/// Descriptions longer than this are cut short on the card.
+pub const MAX_CARD_DESCRIPTION_CHARS: usize = 200;
+
+/// The public snapshot of a shop served by `GET /v1/shops/{id}/card` for sharing links. It must
+/// never grow fields that aren't meant for anyone on the internet to see, like the shop's gold,
+/// vendor keywords or anything about its owner besides their name.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShopCard {
+    pub name: String,
+    /// The owner's display name, or their name if they haven't set one.
+    pub owner_name: String,
+    pub description: Option<String>,
+    pub shop_type: ShopType,
+    pub item_count: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_rating: Option<f64>,
+    pub last_activity_at: NaiveDateTime,
+}
+
+impl ShopCard {
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get(db: impl Executor<'_, Database = Postgres>, shop_id: i32) -> Result<Self> {
+        let _timer = time_query("shop_card", "get", [("shop_id", shop_id.shape())]);
+        let card = sqlx::query_as!(
+            Self,
+            r#"SELECT shops.name,
+                COALESCE(owners.display_name, owners.name) as "owner_name!",
+                shops.description,
+                shops.shop_type as "shop_type: ShopType",
+                COALESCE(jsonb_array_length(merchandise_lists.form_list), 0) as "item_count!",
+                shops.average_rating,
+                shops.last_activity_at
+            FROM shops
+            JOIN owners ON owners.id = shops.owner_id
+            LEFT JOIN merchandise_lists ON merchandise_lists.shop_id = shops.id
+            WHERE shops.id = $1"#,
+            shop_id
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| not_found("Shop does not exist or has been deleted"))?;
+        Ok(Self {
+            description: card.description.map(|description| shorten(&description)),
+            ..card
+        })
+    }
+}
+
+fn shorten(description: &str) -> String {
+    if description.chars().count() <= MAX_CARD_DESCRIPTION_CHARS {
+        return description.to_string();
+    }
+    let mut shortened: String = description
+        .chars()
+        .take(MAX_CARD_DESCRIPTION_CHARS)
+        .collect::<String>()
+        .trim_end()
+        .to_string();
+    shortened.push('…');
+    shortened
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{shorten, MAX_CARD_DESCRIPTION_CHARS};
+
+    #[test]
+    fn long_descriptions_are_shortened_on_a_char_boundary() {
+        assert_eq!(shorten("A fine shop"), "A fine shop");
+        let long = "é".repeat(MAX_CARD_DESCRIPTION_CHARS + 1);
+        let shortened = shorten(&long);
+        assert_eq!(shortened.chars().count(), MAX_CARD_DESCRIPTION_CHARS + 1);
+        assert!(shortened.ends_with('…'));
+    }
+}
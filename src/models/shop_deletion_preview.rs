@@ -0,0 +1,53 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::not_found;
+
+/// Everything that goes away with a shop, served by `GET /v1/shops/{id}/deletion_preview` so that
+/// clients can warn the player before they delete it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ShopDeletionPreview {
+    pub item_count: i32,
+    /// The merchandise list's quantities times their prices.
+    pub stock_value: i64,
+    pub transaction_count: i64,
+    pub interior_ref_count: i32,
+    pub open_request_count: i64,
+}
+
+impl ShopDeletionPreview {
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get(db: impl Executor<'_, Database = Postgres>, shop_id: i32) -> Result<Self> {
+        let _timer = time_query(
+            "shop_deletion_preview",
+            "get",
+            [("shop_id", shop_id.shape())],
+        );
+        sqlx::query_as!(
+            Self,
+            r#"SELECT
+                COALESCE(jsonb_array_length(merchandise_lists.form_list), 0) as "item_count!",
+                COALESCE((
+                    SELECT SUM((item->>'quantity')::bigint * (item->>'price')::bigint)
+                    FROM jsonb_array_elements(merchandise_lists.form_list) AS item
+                ), 0)::bigint as "stock_value!",
+                (SELECT COUNT(*) FROM transactions
+                    WHERE transactions.shop_id = shops.id) as "transaction_count!",
+                COALESCE(jsonb_array_length(interior_ref_lists.ref_list), 0) as "interior_ref_count!",
+                (SELECT COUNT(*) FROM shop_requests
+                    WHERE shop_requests.shop_id = shops.id
+                    AND shop_requests.status = 'open') as "open_request_count!"
+            FROM shops
+            LEFT JOIN merchandise_lists ON merchandise_lists.shop_id = shops.id
+            LEFT JOIN interior_ref_lists ON interior_ref_lists.shop_id = shops.id
+            WHERE shops.id = $1"#,
+            shop_id
+        )
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| not_found("Shop does not exist or has been deleted"))
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{Done, Executor, Postgres};
+use tracing::instrument;
+
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::invalid_query_param;
+
+const DEFAULT_DAYS: i32 = 30;
+const MAX_DAYS: i32 = 365;
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
+pub struct ShopGoldSnapshot {
+    pub date: NaiveDate,
+    pub gold: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShopGoldHistory {
+    pub shop_id: i32,
+    pub days: i32,
+    pub net_change: i64,
+    pub snapshots: Vec<ShopGoldSnapshot>,
+}
+
+/// Query parameters for `GET /v1/shops/{id}/gold_history`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Default, Deserialize)]
+pub struct GoldHistoryParams {
+    pub days: Option<i32>,
+}
+
+impl GoldHistoryParams {
+    pub fn days(&self) -> Result<i32> {
+        match self.days {
+            None => Ok(DEFAULT_DAYS),
+            Some(days) if (1..=MAX_DAYS).contains(&days) => Ok(days),
+            Some(_) => Err(invalid_query_param(
+                "days",
+                &format!("must be between 1 and {}", MAX_DAYS),
+            )),
+        }
+    }
+}
+
+impl ShopGoldHistory {
+    /// Records today's (UTC) gold for every shop. Only the first snapshot of a day is kept, so this
+    /// is safe to run more than once a day, e.g. on every server start.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn snapshot_all(db: impl Executor<'_, Database = Postgres>) -> Result<u64> {
+        let _timer = time_query("shop_gold_history", "snapshot_all", []);
+        Ok(sqlx::query!(
+            "INSERT INTO shop_gold_history (shop_id, date, gold, created_at)
+            SELECT id, (now() AT TIME ZONE 'UTC')::date, gold, now()
+            FROM shops
+            ON CONFLICT (shop_id, date) DO NOTHING"
+        )
+        .execute(db)
+        .await?
+        .rows_affected())
+    }
+
+    /// Returns one snapshot per day for the last `days` days, carrying the previous day's gold
+    /// forward over days that have no snapshot. The series starts no earlier than the day the shop
+    /// was created, and is empty until the shop's first snapshot has been taken.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        days: i32,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_gold_history",
+            "get_by_shop_id",
+            [("shop_id", shop_id.shape()), ("days", days.shape())],
+        );
+        let snapshots = sqlx::query_as!(
+            ShopGoldSnapshot,
+            r#"SELECT series.date::date as "date!", history.gold as "gold!"
+            FROM shops
+            CROSS JOIN LATERAL generate_series(
+                GREATEST(
+                    (now() AT TIME ZONE 'UTC')::date - ($2::int - 1),
+                    shops.created_at::date
+                ),
+                (now() AT TIME ZONE 'UTC')::date,
+                interval '1 day'
+            ) AS series(date)
+            CROSS JOIN LATERAL (
+                SELECT gold FROM shop_gold_history
+                WHERE shop_gold_history.shop_id = shops.id
+                    AND shop_gold_history.date <= series.date::date
+                ORDER BY shop_gold_history.date DESC
+                LIMIT 1
+            ) AS history
+            WHERE shops.id = $1
+            ORDER BY series.date"#,
+            shop_id,
+            days,
+        )
+        .fetch_all(db)
+        .await?;
+        let net_change = match (snapshots.first(), snapshots.last()) {
+            (Some(first), Some(last)) => last.gold.saturating_sub(first.gold),
+            _ => 0,
+        };
+        Ok(Self {
+            shop_id,
+            days,
+            net_change,
+            snapshots,
+        })
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::Serialize;
+use sqlx::postgres::PgConnection;
+use sqlx::types::Json;
+use tracing::{info, instrument};
+
+use super::merchandise_list::Merchandise;
+use super::{set_statement_timeout, Deadline, FormId, MerchandiseChangeReason, MerchandiseList};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::not_found;
+
+/// An item whose stored quantity disagrees with what the shop's transactions imply.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuantityDrift {
+    pub mod_name: String,
+    pub local_form_id: FormId,
+    pub actual_quantity: i64,
+    pub expected_quantity: i64,
+    /// Net quantity the shop's transactions imply (sells add, buys subtract).
+    pub transactions_quantity: i64,
+    /// Net quantity that was actually applied to the list for transactions (and earlier
+    /// reconciliations), according to the change feed.
+    pub recorded_quantity: i64,
+    /// False when the item is missing from the list and there is no transaction to copy its
+    /// name, price, and form type from, so it can't be re-added.
+    pub repairable: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShopReconciliation {
+    pub shop_id: i32,
+    /// Only transactions made at or after the shop's oldest retained merchandise change are
+    /// replayed. Earlier history (before the change feed existed, or pruned since) is treated as
+    /// part of each item's baseline.
+    pub window_start: Option<NaiveDateTime>,
+    pub transactions_replayed: i64,
+    pub drift: Vec<QuantityDrift>,
+    pub applied: bool,
+}
+
+struct TransactionTotals {
+    quantity: i64,
+    count: i64,
+    latest: Merchandise,
+}
+
+impl ShopReconciliation {
+    /// Compares a shop's merchandise quantities against its transaction history and, if `apply`
+    /// is set, corrects the quantities and records the corrections in the change feed.
+    ///
+    /// Each item's baseline is its current quantity minus the transaction deltas the change feed
+    /// recorded for it, which covers items added or restocked outside of transactions. The
+    /// expected quantity is that baseline plus the deltas the transactions table implies, so
+    /// drift shows up when the two disagree, e.g. when a transaction was deleted after its
+    /// quantity change was applied.
+    ///
+    /// Must be run inside a DB transaction since it locks the merchandise list row.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn run(
+        db: &mut PgConnection,
+        shop_id: i32,
+        apply: bool,
+        deadline: Option<&Deadline>,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_reconciliation",
+            "run",
+            [("shop_id", shop_id.shape()), ("apply", apply.shape())],
+        );
+        set_statement_timeout(&mut *db, deadline).await?;
+        let merchandise_list = sqlx::query!(
+            r#"SELECT form_list as "form_list: Json<Vec<Merchandise>>"
+            FROM merchandise_lists
+            WHERE shop_id = $1
+            FOR UPDATE"#,
+            shop_id
+        )
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| not_found("Shop does not exist or has no merchandise list"))?;
+        let window_start = sqlx::query!(
+            "SELECT MIN(created_at) as window_start FROM merchandise_changes WHERE shop_id = $1",
+            shop_id
+        )
+        .fetch_one(&mut *db)
+        .await?
+        .window_start;
+
+        let mut recorded: BTreeMap<(String, FormId), i64> = BTreeMap::new();
+        let mut transactions: BTreeMap<(String, FormId), TransactionTotals> = BTreeMap::new();
+        if let Some(window_start) = window_start {
+            for row in sqlx::query!(
+                r#"SELECT mod_name, local_form_id as "local_form_id: FormId",
+                    SUM(quantity_delta) as "quantity!"
+                FROM merchandise_changes
+                WHERE shop_id = $1 AND reason IN ('transaction', 'reconcile')
+                GROUP BY mod_name, local_form_id"#,
+                shop_id
+            )
+            .fetch_all(&mut *db)
+            .await?
+            {
+                recorded.insert((row.mod_name, row.local_form_id), row.quantity);
+            }
+            for row in sqlx::query!(
+                r#"SELECT DISTINCT ON (mod_name, local_form_id)
+                    mod_name, local_form_id as "local_form_id: FormId", name, form_kind, is_food,
+                    price, keywords,
+                    SUM(CASE WHEN is_sell THEN quantity ELSE -quantity END)
+                        OVER (PARTITION BY mod_name, local_form_id) as "quantity!",
+                    COUNT(*) OVER (PARTITION BY mod_name, local_form_id) as "count!"
+                FROM transactions
+                WHERE shop_id = $1 AND created_at >= $2
+                ORDER BY mod_name, local_form_id, created_at DESC"#,
+                shop_id,
+                window_start
+            )
+            .fetch_all(&mut *db)
+            .await?
+            {
+                transactions.insert(
+                    (row.mod_name.clone(), row.local_form_id),
+                    TransactionTotals {
+                        quantity: row.quantity,
+                        count: row.count,
+                        latest: Merchandise {
+                            mod_name: row.mod_name,
+                            local_form_id: row.local_form_id,
+                            name: row.name,
+                            quantity: 0,
+                            form_kind: row.form_kind as u32,
+                            is_food: row.is_food,
+                            price: row.price,
+                            keywords: row.keywords,
+                            icon_key: None,
+                        },
+                    },
+                );
+            }
+        }
+
+        let actual: BTreeMap<(String, FormId), i64> = merchandise_list
+            .form_list
+            .iter()
+            .map(|merchandise| {
+                (
+                    (merchandise.mod_name.clone(), merchandise.local_form_id),
+                    merchandise.quantity as i64,
+                )
+            })
+            .collect();
+        let keys: BTreeSet<&(String, FormId)> =
+            recorded.keys().chain(transactions.keys()).collect();
+        let mut drift = vec![];
+        for key in keys {
+            let recorded_quantity = recorded.get(key).copied().unwrap_or(0);
+            let totals = transactions.get(key);
+            let transactions_quantity = totals.map_or(0, |totals| totals.quantity);
+            if recorded_quantity == transactions_quantity {
+                continue;
+            }
+            let actual_quantity = actual.get(key).copied().unwrap_or(0);
+            let expected_quantity = actual_quantity - recorded_quantity + transactions_quantity;
+            let (mod_name, local_form_id) = key.clone();
+            drift.push(QuantityDrift {
+                mod_name,
+                local_form_id,
+                actual_quantity,
+                expected_quantity,
+                transactions_quantity,
+                recorded_quantity,
+                repairable: actual.contains_key(key) || expected_quantity <= 0 || totals.is_some(),
+            });
+        }
+        let transactions_replayed = transactions.values().map(|totals| totals.count).sum();
+
+        let applied = apply && drift.iter().any(|item| item.repairable);
+        if applied {
+            let mut form_list = merchandise_list.form_list.0.clone();
+            for item in drift.iter().filter(|item| item.repairable) {
+                let quantity = item.expected_quantity.clamp(0, u32::MAX as i64) as u32;
+                let position = form_list.iter().position(|merchandise| {
+                    merchandise.mod_name == item.mod_name
+                        && merchandise.local_form_id == item.local_form_id
+                });
+                match position {
+                    Some(index) if quantity == 0 => {
+                        form_list.remove(index);
+                    }
+                    Some(index) => form_list[index].quantity = quantity,
+                    None if quantity > 0 => {
+                        let key = (item.mod_name.clone(), item.local_form_id);
+                        let mut merchandise = transactions[&key].latest.clone();
+                        merchandise.quantity = quantity;
+                        form_list.push(merchandise);
+                    }
+                    None => {}
+                }
+            }
+            MerchandiseList::replace_form_list_by_shop_id(
+                &mut *db,
+                shop_id,
+                &merchandise_list.form_list,
+                &form_list,
+                MerchandiseChangeReason::Reconcile,
+            )
+            .await?;
+            info!(
+                shop_id,
+                corrected = drift.iter().filter(|item| item.repairable).count(),
+                "reconciled merchandise quantities"
+            );
+        }
+
+        Ok(Self {
+            shop_id,
+            window_start,
+            transactions_replayed,
+            drift,
+            applied,
+        })
+    }
+}
@@ -0,0 +1,413 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgConnection;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use super::merchandise_list::item_field_errors;
+use super::{FormId, ListQuery, Merchandise, Pagination, Shop, TransactionLimits};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{forbidden_permission, not_found, unprocessable_entity, ValidationError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(rename = "shop_request_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ShopRequestStatus {
+    Open,
+    Fulfilled,
+    Declined,
+}
+
+impl ShopRequestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShopRequestStatus::Open => "open",
+            ShopRequestStatus::Fulfilled => "fulfilled",
+            ShopRequestStatus::Declined => "declined",
+        }
+    }
+
+    /// Open requests can be fulfilled or declined, and declined ones reopened. Fulfilled is final.
+    /// Setting the current status again is allowed so that retries succeed.
+    pub fn can_become(self, next: ShopRequestStatus) -> bool {
+        use ShopRequestStatus::*;
+        matches!(
+            (self, next),
+            (Open, _) | (Declined, Open) | (Declined, Declined) | (Fulfilled, Fulfilled)
+        )
+    }
+}
+
+/// An item a player (owner) asked a shop to stock. `fulfillable` is set while the request is open
+/// and the shop's merchandise list has at least `quantity` of the item.
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
+pub struct ShopRequest {
+    pub id: i32,
+    pub shop_id: i32,
+    pub requester_owner_id: i32,
+    pub mod_name: String,
+    pub local_form_id: FormId,
+    pub name: String,
+    pub quantity: i32,
+    pub status: ShopRequestStatus,
+    pub fulfillable: bool,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostedShopRequest {
+    pub mod_name: String,
+    pub local_form_id: FormId,
+    pub name: String,
+    pub quantity: i32,
+}
+
+impl PostedShopRequest {
+    /// Requested quantities are capped like the quantity of a single transaction.
+    pub fn validate(&self, limits: &TransactionLimits) -> Result<()> {
+        let mut errors = vec![];
+        if !limits.quantity.contains(&self.quantity) {
+            errors.push(ValidationError::new(
+                "quantity",
+                format!(
+                    "must be between {} and {}",
+                    limits.quantity.start(),
+                    limits.quantity.end()
+                ),
+            ));
+        }
+        for (field, message) in item_field_errors(&self.mod_name, &self.name, &[]) {
+            errors.push(ValidationError::new(field, message));
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(unprocessable_entity(errors))
+        }
+    }
+}
+
+/// The body of `PATCH /v1/shop_requests/{id}`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostedShopRequestStatus {
+    pub status: ShopRequestStatus,
+}
+
+/// Query parameters specific to the shop request lists, in addition to `Pagination`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Default, Deserialize)]
+pub struct ShopRequestFilter {
+    pub status: Option<ShopRequestStatus>,
+}
+
+impl ShopRequestFilter {
+    pub const SUPPORTED_PARAMS: &'static [&'static str] = &["status"];
+}
+
+/// Query parameters of `GET /v1/shops/{id}/requests` and `GET /v1/owners/me/requests`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct ShopRequestListQuery {
+    pub pagination: Pagination,
+    pub filter: ShopRequestFilter,
+}
+
+impl ListQuery for ShopRequestListQuery {
+    type Filter = ShopRequestFilter;
+    const FILTER_PARAMS: &'static [&'static str] = ShopRequestFilter::SUPPORTED_PARAMS;
+
+    fn new(pagination: Pagination, filter: ShopRequestFilter) -> Result<Self> {
+        Ok(Self { pagination, filter })
+    }
+}
+
+impl ShopRequest {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] =
+        &["created_at", "updated_at", "status", "quantity"];
+    pub const DEFAULT_ORDER_BY: &'static str = "created_at DESC, id DESC";
+
+    /// Asks the shop for the item as `owner_id`, who can't be the shop's owner. The request starts
+    /// out fulfillable if the shop already stocks enough of the item.
+    #[instrument(level = "debug", skip(db, request))]
+    pub async fn create(
+        db: &mut PgConnection,
+        shop_id: i32,
+        owner_id: i32,
+        request: PostedShopRequest,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_request",
+            "create",
+            [("shop_id", shop_id.shape()), ("owner_id", owner_id.shape())],
+        );
+        if Shop::get_owner_id(&mut *db, shop_id).await? == owner_id {
+            return Err(unprocessable_entity(vec![ValidationError::new(
+                "shop_id",
+                "cannot request items from your own shop",
+            )]));
+        }
+        Ok(sqlx::query_as!(
+            Self,
+            r#"INSERT INTO shop_requests
+            (shop_id, requester_owner_id, mod_name, local_form_id, name, quantity, status,
+             fulfillable, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, 'open', EXISTS (
+                SELECT 1 FROM merchandise_lists, jsonb_array_elements(form_list) AS item
+                WHERE shop_id = $1
+                    AND item->>'mod_name' = $3
+                    AND item->>'local_form_id' = $7
+                    AND (item->>'quantity')::integer >= $6
+            ), now(), now())
+            RETURNING id, shop_id, requester_owner_id, mod_name,
+                local_form_id as "local_form_id: FormId", name, quantity,
+                status as "status: ShopRequestStatus", fulfillable, created_at, updated_at"#,
+            shop_id,
+            owner_id,
+            request.mod_name,
+            i64::from(request.local_form_id),
+            request.name,
+            request.quantity,
+            // Form ids are stored in merchandise lists in their canonical JSON form.
+            request.local_form_id.to_string(),
+        )
+        .fetch_one(&mut *db)
+        .await?)
+    }
+
+    /// Moves the request to `status`. Only the owner of the shop it was made to can.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn update_status(
+        db: &mut PgConnection,
+        owner_id: i32,
+        id: i32,
+        status: ShopRequestStatus,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_request",
+            "update_status",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        let current = sqlx::query!(
+            r#"SELECT shops.owner_id, shop_requests.status as "status: ShopRequestStatus"
+            FROM shop_requests JOIN shops ON shops.id = shop_requests.shop_id
+            WHERE shop_requests.id = $1
+            FOR UPDATE OF shop_requests"#,
+            id
+        )
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| not_found("Shop request does not exist or has been deleted"))?;
+        if current.owner_id != owner_id {
+            return Err(forbidden_permission());
+        }
+        if !current.status.can_become(status) {
+            return Err(unprocessable_entity(vec![ValidationError::new(
+                "status",
+                format!(
+                    "cannot change from {} to {}",
+                    current.status.as_str(),
+                    status.as_str()
+                ),
+            )]));
+        }
+        Ok(sqlx::query_as!(
+            Self,
+            r#"UPDATE shop_requests SET
+            status = $2::text::shop_request_status,
+            fulfillable = fulfillable AND $2 = 'open',
+            updated_at = now()
+            WHERE id = $1
+            RETURNING id, shop_id, requester_owner_id, mod_name,
+                local_form_id as "local_form_id: FormId", name, quantity,
+                status as "status: ShopRequestStatus", fulfillable, created_at, updated_at"#,
+            id,
+            status.as_str(),
+        )
+        .fetch_one(&mut *db)
+        .await?)
+    }
+
+    /// Re-checks the open requests of the shop against its new merchandise and returns the ones
+    /// that became fulfillable. Should be called in the transaction that saved the merchandise.
+    #[instrument(level = "debug", skip(db, merchandise))]
+    pub async fn flag_fulfillable(
+        db: &mut PgConnection,
+        shop_id: i32,
+        merchandise: &[Merchandise],
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "shop_request",
+            "flag_fulfillable",
+            [
+                ("shop_id", shop_id.shape()),
+                ("merchandise", merchandise.shape()),
+            ],
+        );
+        let open = sqlx::query!(
+            r#"SELECT id, mod_name, local_form_id as "local_form_id: FormId", quantity
+            FROM shop_requests
+            WHERE shop_id = $1 AND status = 'open'
+            FOR UPDATE"#,
+            shop_id
+        )
+        .fetch_all(&mut *db)
+        .await?;
+        if open.is_empty() {
+            return Ok(vec![]);
+        }
+        let fulfillable_ids: Vec<i32> = open
+            .iter()
+            .filter(|request| {
+                merchandise.iter().any(|item| {
+                    item.mod_name == request.mod_name
+                        && item.local_form_id == request.local_form_id
+                        && item.quantity as i64 >= request.quantity as i64
+                })
+            })
+            .map(|request| request.id)
+            .collect();
+        let changed = sqlx::query_as!(
+            Self,
+            r#"UPDATE shop_requests SET fulfillable = id = ANY($2), updated_at = now()
+            WHERE shop_id = $1 AND status = 'open' AND fulfillable <> (id = ANY($2))
+            RETURNING id, shop_id, requester_owner_id, mod_name,
+                local_form_id as "local_form_id: FormId", name, quantity,
+                status as "status: ShopRequestStatus", fulfillable, created_at, updated_at"#,
+            shop_id,
+            &fulfillable_ids,
+        )
+        .fetch_all(&mut *db)
+        .await?;
+        Ok(changed)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        query: &ShopRequestListQuery,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "shop_request",
+            "list_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM shop_requests
+            WHERE shop_id = $1
+                AND ($2::text IS NULL OR status = $2::text::shop_request_status)
+            ORDER BY {}
+            LIMIT $3
+            OFFSET $4",
+            order_by
+        ))
+        .bind(shop_id)
+        .bind(query.filter.status.map(|status| status.as_str()))
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of requests `list_by_shop_id` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        query: &ShopRequestListQuery,
+    ) -> Result<i64> {
+        let _timer = time_query(
+            "shop_request",
+            "count_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM shop_requests
+            WHERE shop_id = $1
+                AND ($2::text IS NULL OR status = $2::text::shop_request_status)"#,
+            shop_id,
+            query.filter.status.map(|status| status.as_str()),
+        )
+        .fetch_one(db)
+        .await?
+        .count)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_requester_owner_id(
+        db: impl Executor<'_, Database = Postgres>,
+        owner_id: i32,
+        query: &ShopRequestListQuery,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "shop_request",
+            "list_by_requester_owner_id",
+            [("owner_id", owner_id.shape())],
+        );
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM shop_requests
+            WHERE requester_owner_id = $1
+                AND ($2::text IS NULL OR status = $2::text::shop_request_status)
+            ORDER BY {}
+            LIMIT $3
+            OFFSET $4",
+            order_by
+        ))
+        .bind(owner_id)
+        .bind(query.filter.status.map(|status| status.as_str()))
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of requests `list_by_requester_owner_id` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count_by_requester_owner_id(
+        db: impl Executor<'_, Database = Postgres>,
+        owner_id: i32,
+        query: &ShopRequestListQuery,
+    ) -> Result<i64> {
+        let _timer = time_query(
+            "shop_request",
+            "count_by_requester_owner_id",
+            [("owner_id", owner_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM shop_requests
+            WHERE requester_owner_id = $1
+                AND ($2::text IS NULL OR status = $2::text::shop_request_status)"#,
+            owner_id,
+            query.filter.status.map(|status| status.as_str()),
+        )
+        .fetch_one(db)
+        .await?
+        .count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShopRequestStatus::*;
+
+    #[test]
+    fn fulfilled_requests_stay_fulfilled() {
+        assert!(Open.can_become(Fulfilled));
+        assert!(Open.can_become(Declined));
+        assert!(Declined.can_become(Open));
+        assert!(Fulfilled.can_become(Fulfilled));
+        assert!(!Fulfilled.can_become(Open));
+        assert!(!Fulfilled.can_become(Declined));
+        assert!(!Declined.can_become(Fulfilled));
+    }
+}
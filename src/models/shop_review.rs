@@ -0,0 +1,312 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgConnection;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use super::{ListQuery, NoFilter, Pagination};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{
+    not_found, review_requires_purchase, shop_gone, unprocessable_entity, ValidationError,
+};
+
+const MIN_RATING: i16 = 1;
+const MAX_RATING: i16 = 5;
+const MAX_COMMENT_LENGTH: usize = 2000;
+
+/// A player's (owner's) rating of a shop they have traded at. Each player has at most one review
+/// per shop, which they can edit or delete.
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
+pub struct ShopReview {
+    pub id: i32,
+    pub shop_id: i32,
+    pub reviewer_owner_id: i32,
+    pub rating: i16,
+    pub comment: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// The body of `POST` (which requires `rating`) and `PATCH /v1/shops/{id}/reviews`. Fields left
+/// out of a `PATCH` are unchanged, and an empty `comment` clears it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostedShopReview {
+    pub rating: Option<i16>,
+    pub comment: Option<String>,
+}
+
+impl PostedShopReview {
+    pub fn validate(&self, creating: bool) -> Result<()> {
+        let mut errors = vec![];
+        match self.rating {
+            None if creating => errors.push(ValidationError::new("rating", "is required")),
+            Some(rating) if !(MIN_RATING..=MAX_RATING).contains(&rating) => {
+                errors.push(ValidationError::new(
+                    "rating",
+                    format!("must be between {} and {}", MIN_RATING, MAX_RATING),
+                ))
+            }
+            _ => {}
+        }
+        if let Some(comment) = &self.comment {
+            if comment.chars().count() > MAX_COMMENT_LENGTH {
+                errors.push(ValidationError::new(
+                    "comment",
+                    format!("must be at most {} characters", MAX_COMMENT_LENGTH),
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(unprocessable_entity(errors))
+        }
+    }
+}
+
+/// Query parameters of `GET /v1/shops/{id}/reviews`, which only page and sort.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct ShopReviewListQuery {
+    pub pagination: Pagination,
+}
+
+impl ListQuery for ShopReviewListQuery {
+    type Filter = NoFilter;
+    const FILTER_PARAMS: &'static [&'static str] = &[];
+
+    fn new(pagination: Pagination, _filter: NoFilter) -> Result<Self> {
+        Ok(Self { pagination })
+    }
+}
+
+impl ShopReview {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] = &["created_at", "updated_at", "rating"];
+    pub const DEFAULT_ORDER_BY: &'static str = "updated_at DESC, id DESC";
+
+    /// Reviews the shop as `owner_id`, who must have at least one transaction at the shop and must
+    /// not own it. A second review of the same shop fails with 409 through the
+    /// `shop_reviews_one_per_reviewer` constraint.
+    #[instrument(level = "debug", skip(db, review))]
+    pub async fn create(
+        db: &mut PgConnection,
+        shop_id: i32,
+        owner_id: i32,
+        review: PostedShopReview,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_review",
+            "create",
+            [("shop_id", shop_id.shape()), ("owner_id", owner_id.shape())],
+        );
+        Self::check_reviewer(&mut *db, shop_id, owner_id).await?;
+        let saved_review = sqlx::query_as!(
+            Self,
+            "INSERT INTO shop_reviews
+            (shop_id, reviewer_owner_id, rating, comment, created_at, updated_at)
+            VALUES ($1, $2, $3, NULLIF($4, ''), now(), now())
+            RETURNING id, shop_id, reviewer_owner_id, rating, comment, created_at, updated_at",
+            shop_id,
+            owner_id,
+            review.rating,
+            review.comment,
+        )
+        .fetch_one(&mut *db)
+        .await?;
+        Self::update_shop_ratings(&mut *db, &[shop_id]).await?;
+        Ok(saved_review)
+    }
+
+    /// Edits `owner_id`'s review of the shop. The purchase requirement is checked again so that the
+    /// rules are the same as when the review was written.
+    #[instrument(level = "debug", skip(db, review))]
+    pub async fn update(
+        db: &mut PgConnection,
+        shop_id: i32,
+        owner_id: i32,
+        review: PostedShopReview,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_review",
+            "update",
+            [("shop_id", shop_id.shape()), ("owner_id", owner_id.shape())],
+        );
+        Self::check_reviewer(&mut *db, shop_id, owner_id).await?;
+        let saved_review = sqlx::query_as!(
+            Self,
+            "UPDATE shop_reviews SET
+            rating = COALESCE($3, rating),
+            comment = NULLIF(COALESCE($4, comment), ''),
+            updated_at = now()
+            WHERE shop_id = $1 AND reviewer_owner_id = $2
+            RETURNING id, shop_id, reviewer_owner_id, rating, comment, created_at, updated_at",
+            shop_id,
+            owner_id,
+            review.rating,
+            review.comment,
+        )
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| not_found("You have not reviewed this shop"))?;
+        Self::update_shop_ratings(&mut *db, &[shop_id]).await?;
+        Ok(saved_review)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn delete(db: &mut PgConnection, shop_id: i32, owner_id: i32) -> Result<()> {
+        let _timer = time_query(
+            "shop_review",
+            "delete",
+            [("shop_id", shop_id.shape()), ("owner_id", owner_id.shape())],
+        );
+        Self::lock_shop(&mut *db, shop_id).await?;
+        sqlx::query!(
+            "DELETE FROM shop_reviews WHERE shop_id = $1 AND reviewer_owner_id = $2 RETURNING id",
+            shop_id,
+            owner_id,
+        )
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| not_found("You have not reviewed this shop"))?;
+        Self::update_shop_ratings(&mut *db, &[shop_id]).await?;
+        Ok(())
+    }
+
+    /// Deletes every review written by `owner_id` and returns the ids of the shops they reviewed.
+    /// Must run in the same transaction that deletes the owner, since the `ON DELETE CASCADE` on
+    /// `reviewer_owner_id` would otherwise remove the reviews without updating the shops' ratings.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn delete_by_reviewer(db: &mut PgConnection, owner_id: i32) -> Result<Vec<i32>> {
+        let _timer = time_query(
+            "shop_review",
+            "delete_by_reviewer",
+            [("owner_id", owner_id.shape())],
+        );
+        let shop_ids: Vec<i32> = sqlx::query!(
+            "SELECT id FROM shops
+            WHERE id IN (SELECT shop_id FROM shop_reviews WHERE reviewer_owner_id = $1)
+            ORDER BY id
+            FOR NO KEY UPDATE",
+            owner_id,
+        )
+        .fetch_all(&mut *db)
+        .await?
+        .into_iter()
+        .map(|row| row.id)
+        .collect();
+        if shop_ids.is_empty() {
+            return Ok(shop_ids);
+        }
+        sqlx::query!(
+            "DELETE FROM shop_reviews WHERE reviewer_owner_id = $1",
+            owner_id
+        )
+        .execute(&mut *db)
+        .await?;
+        Self::update_shop_ratings(&mut *db, &shop_ids).await?;
+        Ok(shop_ids)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        query: &ShopReviewListQuery,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "shop_review",
+            "list_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM shop_reviews
+            WHERE shop_id = $1
+            ORDER BY {}
+            LIMIT $2
+            OFFSET $3",
+            order_by
+        ))
+        .bind(shop_id)
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of reviews `list_by_shop_id` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+    ) -> Result<i64> {
+        let _timer = time_query(
+            "shop_review",
+            "count_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM shop_reviews WHERE shop_id = $1"#,
+            shop_id
+        )
+        .fetch_one(db)
+        .await?
+        .count)
+    }
+
+    // Locks the shop, then fails unless `owner_id` may review it: they don't own it and have at
+    // least one transaction there.
+    async fn check_reviewer(db: &mut PgConnection, shop_id: i32, owner_id: i32) -> Result<()> {
+        if Self::lock_shop(&mut *db, shop_id).await? == owner_id {
+            return Err(unprocessable_entity(vec![ValidationError::new(
+                "shop_id",
+                "cannot review your own shop",
+            )]));
+        }
+        let purchased = sqlx::query!(
+            r#"SELECT EXISTS (
+                SELECT 1 FROM transactions WHERE shop_id = $1 AND owner_id = $2
+            ) as "purchased!""#,
+            shop_id,
+            owner_id,
+        )
+        .fetch_one(&mut *db)
+        .await?
+        .purchased;
+        if !purchased {
+            return Err(review_requires_purchase());
+        }
+        Ok(())
+    }
+
+    // Review writes to the same shop take turns on the shop's row, so each one recomputes the
+    // ratings from every review the others committed. Returns the shop's owner.
+    async fn lock_shop(db: &mut PgConnection, shop_id: i32) -> Result<i32> {
+        Ok(sqlx::query!(
+            "SELECT owner_id FROM shops WHERE id = $1 FOR NO KEY UPDATE",
+            shop_id
+        )
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(shop_gone)?
+        .owner_id)
+    }
+
+    // Recomputes `review_count` and `average_rating` of the shops, which must already be locked.
+    async fn update_shop_ratings(db: &mut PgConnection, shop_ids: &[i32]) -> Result<()> {
+        sqlx::query!(
+            "UPDATE shops SET
+            review_count = (SELECT COUNT(*) FROM shop_reviews WHERE shop_id = shops.id),
+            average_rating = (SELECT AVG(rating)::float8 FROM shop_reviews WHERE shop_id = shops.id)
+            WHERE id = ANY($1)",
+            shop_ids
+        )
+        .execute(&mut *db)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+use anyhow::Result;
+use sqlx::postgres::PgConnection;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::metrics::queries::{time_query, Shape};
+
+/// The slug `slug` was resolved to: the shop it belongs to and that shop's current slug, which
+/// differs from `slug` when it was left behind by a rename.
+#[derive(Debug)]
+pub struct ResolvedShopSlug {
+    pub shop_id: i32,
+    pub current_slug: String,
+}
+
+pub struct ShopSlug;
+
+impl ShopSlug {
+    /// Lowercase ASCII letters and digits of the name, with accents dropped and every other run of
+    /// characters turned into one `-`, e.g. "Händler's Shop" is `handler-s-shop`. Names with none
+    /// of those (e.g. only Cyrillic) are `shop`.
+    pub fn slugify(name: &str) -> String {
+        let mut slug = String::with_capacity(name.len());
+        for c in name.nfkd().filter(|c| !is_combining_mark(*c)) {
+            if c.is_ascii_alphanumeric() {
+                slug.push(c.to_ascii_lowercase());
+            } else if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            }
+        }
+        let slug = slug.trim_end_matches('-');
+        if slug.is_empty() {
+            "shop".to_string()
+        } else {
+            slug.to_string()
+        }
+    }
+
+    /// Makes the slug of `name` the shop's current one, in the caller's transaction so that it
+    /// changes with the name or not at all. The shop's previous slugs are kept to redirect to it.
+    /// Slugs that another shop has (or had) get a numbered suffix instead, e.g. `general-store-2`,
+    /// and a name the shop had before takes its old slug back. Returns the current slug.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn assign(db: &mut PgConnection, shop_id: i32, name: &str) -> Result<String> {
+        let _timer = time_query("shop_slug", "assign", [("shop_id", shop_id.shape())]);
+        let base = Self::slugify(name);
+        sqlx::query!(
+            "UPDATE shop_slugs SET is_current = false WHERE shop_id = $1 AND is_current",
+            shop_id
+        )
+        .execute(&mut *db)
+        .await?;
+        let mut suffix = 1;
+        loop {
+            let candidate = match suffix {
+                1 => base.clone(),
+                _ => format!("{}-{}", base, suffix),
+            };
+            // Claims the slug unless another shop already has it. Two shops renamed to the same
+            // name at once are serialized on the slug's row, and the second moves on to the next.
+            let claimed = sqlx::query!(
+                "INSERT INTO shop_slugs (slug, shop_id, is_current) VALUES ($1, $2, true)
+                ON CONFLICT (slug) DO UPDATE SET is_current = true
+                WHERE shop_slugs.shop_id = EXCLUDED.shop_id
+                RETURNING slug",
+                candidate,
+                shop_id,
+            )
+            .fetch_optional(&mut *db)
+            .await?;
+            if let Some(claimed) = claimed {
+                return Ok(claimed.slug);
+            }
+            suffix += 1;
+        }
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_current(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+    ) -> Result<String> {
+        let _timer = time_query("shop_slug", "get_current", [("shop_id", shop_id.shape())]);
+        Ok(sqlx::query!(
+            "SELECT slug FROM shop_slugs WHERE shop_id = $1 AND is_current",
+            shop_id
+        )
+        .fetch_one(db)
+        .await?
+        .slug)
+    }
+
+    /// The shop `slug` belongs to, whether it is the shop's current slug or an old one.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn resolve(
+        db: impl Executor<'_, Database = Postgres>,
+        slug: &str,
+    ) -> Result<Option<ResolvedShopSlug>> {
+        let _timer = time_query("shop_slug", "resolve", [("slug", slug.shape())]);
+        Ok(sqlx::query_as!(
+            ResolvedShopSlug,
+            "SELECT current.shop_id, current.slug AS current_slug
+            FROM shop_slugs
+            JOIN shop_slugs current ON current.shop_id = shop_slugs.shop_id AND current.is_current
+            WHERE shop_slugs.slug = $1",
+            slug
+        )
+        .fetch_optional(db)
+        .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShopSlug;
+
+    #[test]
+    fn slugs_keep_ascii_letters_and_digits_of_the_name() {
+        assert_eq!(ShopSlug::slugify("General Store"), "general-store");
+        assert_eq!(
+            ShopSlug::slugify("  Händler's Shop #2!  "),
+            "handler-s-shop-2"
+        );
+        assert_eq!(ShopSlug::slugify("Ｆｕｌｌｗｉｄｔｈ"), "fullwidth");
+        assert_eq!(ShopSlug::slugify("Лавка"), "shop");
+        assert_eq!(ShopSlug::slugify("---"), "shop");
+    }
+}
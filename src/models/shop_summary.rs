@@ -0,0 +1,186 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgConnection;
+use sqlx::types::Json;
+use tracing::instrument;
+
+use super::interior_ref_list::{InteriorRef, Shelf};
+use super::merchandise_list::Merchandise;
+use super::{
+    set_statement_timeout, Deadline, InteriorRefList, MerchandiseList, NotificationSettings, Shop,
+    ShopType,
+};
+use crate::metrics::queries::{time_query, Shape};
+
+/// The ETags that `GET /v1/shops/{id}`, `GET /v1/shops/{id}/merchandise_list`, and
+/// `GET /v1/shops/{id}/interior_ref_list` would currently return to the shop's owner, in either
+/// content type.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SubResourceETags {
+    pub shop: String,
+    pub merchandise_list: Option<String>,
+    pub interior_ref_list: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShopSummary {
+    pub id: i32,
+    pub name: String,
+    pub gold: i64,
+    pub item_count: i32,
+    pub last_activity_at: NaiveDateTime,
+    pub etags: SubResourceETags,
+}
+
+/// A shop with its lists, from which a `ShopSummary` is built. The notification settings are for
+/// the ETag of the shop as its owner sees it.
+#[derive(Debug, Clone)]
+pub struct ShopWithLists {
+    pub shop: Shop,
+    pub merchandise_list: Option<MerchandiseList>,
+    pub interior_ref_list: Option<InteriorRefList>,
+    pub notification_settings: NotificationSettings,
+}
+
+impl ShopWithLists {
+    /// Fetches every shop an owner has along with its lists in a single query.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_owner_id(
+        db: &mut PgConnection,
+        owner_id: i32,
+        deadline: Option<&Deadline>,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "shop_summary",
+            "list_by_owner_id",
+            [("owner_id", owner_id.shape())],
+        );
+        set_statement_timeout(&mut *db, deadline).await?;
+        let rows = sqlx::query!(
+            r#"SELECT
+                shops.id, shops.name, shops.owner_id, shops.description, shops.gold,
+                shops.shop_type as "shop_type: ShopType", shops.vendor_keywords, shops.vendor_keywords_exclude,
+                shops.created_at, shops.updated_at, shops.last_activity_at, shops.tags,
+                shops.private_notes, shops.visits_count, shops.review_count, shops.average_rating,
+                merchandise_lists.id as "merchandise_list_id?",
+                merchandise_lists.created_at as "merchandise_list_created_at?",
+                merchandise_lists.updated_at as "merchandise_list_updated_at?",
+                merchandise_lists.revision as "merchandise_list_revision?",
+                merchandise_lists.form_list as "form_list?: Json<Vec<Merchandise>>",
+                interior_ref_lists.id as "interior_ref_list_id?",
+                interior_ref_lists.created_at as "interior_ref_list_created_at?",
+                interior_ref_lists.updated_at as "interior_ref_list_updated_at?",
+                interior_ref_lists.revision as "interior_ref_list_revision?",
+                interior_ref_lists.ref_list as "ref_list?: Json<Vec<InteriorRef>>",
+                interior_ref_lists.shelves as "shelves?: Json<Vec<Shelf>>",
+                shop_notification_settings.on_sale as "on_sale?",
+                shop_notification_settings.on_out_of_stock as "on_out_of_stock?",
+                shop_notification_settings.on_low_stock_threshold
+            FROM shops
+            LEFT JOIN merchandise_lists ON merchandise_lists.shop_id = shops.id
+            LEFT JOIN interior_ref_lists ON interior_ref_lists.shop_id = shops.id
+            LEFT JOIN shop_notification_settings ON shop_notification_settings.shop_id = shops.id
+            WHERE shops.owner_id = $1
+            ORDER BY shops.id"#,
+            owner_id
+        )
+        .fetch_all(&mut *db)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let merchandise_list = match (
+                    row.merchandise_list_id,
+                    row.merchandise_list_created_at,
+                    row.merchandise_list_updated_at,
+                    row.merchandise_list_revision,
+                    row.form_list,
+                ) {
+                    (
+                        Some(id),
+                        Some(created_at),
+                        Some(updated_at),
+                        Some(revision),
+                        Some(form_list),
+                    ) => Some(MerchandiseList {
+                        id,
+                        shop_id: row.id,
+                        owner_id: row.owner_id,
+                        form_list,
+                        created_at,
+                        updated_at,
+                        revision,
+                    }),
+                    _ => None,
+                };
+                let interior_ref_list = match (
+                    row.interior_ref_list_id,
+                    row.interior_ref_list_created_at,
+                    row.interior_ref_list_updated_at,
+                    row.interior_ref_list_revision,
+                    row.ref_list,
+                    row.shelves,
+                ) {
+                    (
+                        Some(id),
+                        Some(created_at),
+                        Some(updated_at),
+                        Some(revision),
+                        Some(ref_list),
+                        Some(shelves),
+                    ) => Some(InteriorRefList {
+                        id,
+                        shop_id: row.id,
+                        owner_id: row.owner_id,
+                        ref_list,
+                        shelves,
+                        created_at,
+                        updated_at,
+                        revision,
+                    }),
+                    _ => None,
+                };
+                let notification_settings = match (row.on_sale, row.on_out_of_stock) {
+                    (Some(on_sale), Some(on_out_of_stock)) => NotificationSettings {
+                        on_sale,
+                        on_out_of_stock,
+                        on_low_stock_threshold: row.on_low_stock_threshold,
+                    },
+                    _ => NotificationSettings::default(),
+                };
+                Self {
+                    shop: Shop {
+                        id: row.id,
+                        name: row.name,
+                        owner_id: row.owner_id,
+                        description: row.description,
+                        gold: row.gold,
+                        shop_type: row.shop_type,
+                        vendor_keywords: row.vendor_keywords,
+                        vendor_keywords_exclude: row.vendor_keywords_exclude,
+                        created_at: row.created_at,
+                        updated_at: row.updated_at,
+                        last_activity_at: row.last_activity_at,
+                        tags: row.tags,
+                        private_notes: row.private_notes,
+                        visits_count: row.visits_count,
+                        review_count: row.review_count,
+                        average_rating: row.average_rating,
+                    },
+                    merchandise_list,
+                    interior_ref_list,
+                    notification_settings,
+                }
+            })
+            .collect())
+    }
+
+    pub fn item_count(&self) -> i32 {
+        self.merchandise_list
+            .as_ref()
+            .map_or(0, |merchandise_list| {
+                merchandise_list.form_list.len() as i32
+            })
+    }
+}
@@ -0,0 +1,202 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use super::interior_ref_list::Shelf;
+use super::{EconomySettings, PostedInteriorRefList, PostedShop, ShopTagRules, ShopType};
+use crate::metrics::queries::{time_query, Shape};
+
+/// The settings an owner's new shops start from when created with
+/// `POST /v1/shops?from_template=true`. Owners without a saved template get an empty one, which
+/// leaves posted shops as they are.
+///
+/// Like `PostedShop`, unknown fields are ignored, so a template saved with a field that has since
+/// been removed still loads.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ShopTemplate {
+    pub description: Option<String>,
+    pub gold: Option<i64>,
+    pub shop_type: Option<ShopType>,
+    pub vendor_keywords: Option<Vec<String>>,
+    pub vendor_keywords_exclude: Option<bool>,
+    pub tags: Option<Vec<String>>,
+    /// Seeded into the interior ref list created with the shop.
+    #[serde(default)]
+    pub shelves: Vec<Shelf>,
+}
+
+impl ShopTemplate {
+    /// Checks the template like the shop and shelves it stands in for, so that a bad template is
+    /// rejected when it is saved rather than every time a shop is created from it. Keywords and
+    /// tags are normalized like `PostedShop::validate` does.
+    pub fn validate(&mut self, tag_rules: &ShopTagRules, economy: &EconomySettings) -> Result<()> {
+        let mut shop = PostedShop {
+            name: "template".to_string(),
+            owner_id: None,
+            description: None,
+            gold: None,
+            shop_type: None,
+            vendor_keywords: None,
+            vendor_keywords_exclude: None,
+            tags: None,
+            private_notes: None,
+        };
+        self.apply_to(&mut shop);
+        shop.validate(tag_rules, economy)?;
+        PostedInteriorRefList {
+            shop_id: 0,
+            owner_id: None,
+            ref_list: Json(Vec::new()),
+            shelves: Json(self.shelves.clone()),
+            expected_revision: None,
+        }
+        .validate()?;
+        self.vendor_keywords = shop.vendor_keywords;
+        self.tags = shop.tags;
+        Ok(())
+    }
+
+    /// Fills in the fields `shop` leaves out (or sets to `null`), so posted fields always win.
+    pub fn apply_to(&self, shop: &mut PostedShop) {
+        shop.description = shop.description.take().or_else(|| self.description.clone());
+        shop.gold = shop.gold.or(self.gold);
+        shop.shop_type = shop.shop_type.take().or_else(|| self.shop_type.clone());
+        shop.vendor_keywords = shop
+            .vendor_keywords
+            .take()
+            .or_else(|| self.vendor_keywords.clone());
+        shop.vendor_keywords_exclude = shop
+            .vendor_keywords_exclude
+            .or(self.vendor_keywords_exclude);
+        shop.tags = shop.tags.take().or_else(|| self.tags.clone());
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn get_by_owner_id(
+        db: impl Executor<'_, Database = Postgres>,
+        owner_id: i32,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_template",
+            "get_by_owner_id",
+            [("owner_id", owner_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"SELECT template as "template: Json<ShopTemplate>"
+            FROM owner_shop_templates
+            WHERE owner_id = $1"#,
+            owner_id
+        )
+        .fetch_optional(db)
+        .await?
+        .map(|row| row.template.0)
+        .unwrap_or_default())
+    }
+
+    #[instrument(level = "debug", skip(self, db))]
+    pub async fn save_by_owner_id(
+        &self,
+        db: impl Executor<'_, Database = Postgres>,
+        owner_id: i32,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_template",
+            "save_by_owner_id",
+            [("owner_id", owner_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"INSERT INTO owner_shop_templates (owner_id, template, created_at, updated_at)
+            VALUES ($1, $2, now(), now())
+            ON CONFLICT (owner_id) DO UPDATE SET
+                template = EXCLUDED.template,
+                updated_at = now()
+            RETURNING template as "template: Json<ShopTemplate>""#,
+            owner_id,
+            serde_json::json!(self),
+        )
+        .fetch_one(db)
+        .await?
+        .template
+        .0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::ShopTemplate;
+    use crate::models::{EconomySettings, PostedShop, ShopTagRules, ShopType};
+
+    #[test]
+    fn posted_fields_win_over_the_template() {
+        let template: ShopTemplate = serde_json::from_value(json!({
+            "description": "Fine wares",
+            "gold": 5000,
+            "shop_type": "blacksmith",
+            "vendor_keywords": ["VendorItemWeapon"],
+            "tags": ["smithing"],
+        }))
+        .unwrap();
+        let mut shop: PostedShop = serde_json::from_value(json!({
+            "name": "Forge",
+            "gold": 100,
+            "tags": null,
+            "vendor_keywords": [],
+        }))
+        .unwrap();
+        template.apply_to(&mut shop);
+
+        assert_eq!(shop.name, "Forge");
+        assert_eq!(shop.description.as_deref(), Some("Fine wares"));
+        assert_eq!(shop.gold, Some(100));
+        assert_eq!(
+            shop.shop_type,
+            Some("blacksmith".parse::<ShopType>().unwrap())
+        );
+        // An explicitly empty list is posted, unlike `null`.
+        assert_eq!(shop.vendor_keywords, Some(vec![]));
+        assert_eq!(shop.tags, Some(vec!["smithing".to_string()]));
+        assert_eq!(shop.vendor_keywords_exclude, None);
+    }
+
+    #[test]
+    fn templates_saved_with_removed_fields_still_load() {
+        let template: ShopTemplate = serde_json::from_value(json!({
+            "description": "Fine wares",
+            "greeting": "Welcome!",
+            "private_notes": "restock on Fridays",
+        }))
+        .unwrap();
+        assert_eq!(
+            template,
+            ShopTemplate {
+                description: Some("Fine wares".to_string()),
+                ..ShopTemplate::default()
+            }
+        );
+    }
+
+    #[test]
+    fn validation_checks_and_normalizes_like_a_posted_shop() {
+        let economy = EconomySettings::default();
+        let mut template = ShopTemplate {
+            tags: Some(vec![" Smithing ".to_string(), "smithing".to_string()]),
+            ..ShopTemplate::default()
+        };
+        template
+            .validate(&ShopTagRules::default(), &economy)
+            .unwrap();
+        assert_eq!(template.tags, Some(vec!["smithing".to_string()]));
+
+        let mut template = ShopTemplate {
+            gold: Some(-1),
+            ..ShopTemplate::default()
+        };
+        assert!(template
+            .validate(&ShopTagRules::default(), &economy)
+            .is_err());
+    }
+}
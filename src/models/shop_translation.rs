@@ -0,0 +1,352 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::{Done, Executor, Postgres};
+use std::fmt;
+use tracing::instrument;
+use unicode_normalization::UnicodeNormalization;
+
+use super::shop::{MAX_DESCRIPTION_LENGTH, MAX_NAME_LENGTH};
+use super::{PublicShop, Shop};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{not_found, truncate, unprocessable_entity, ValidationError};
+
+const MAX_LOCALE_LENGTH: usize = 35;
+
+/// A language tag like `de` or `pt-BR`, loosely following BCP 47: a language of 2 or 3 letters,
+/// then any number of subtags of 1 to 8 letters or digits. Tags are compared ignoring case, so
+/// they are kept in lowercase.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct Locale(String);
+
+impl Locale {
+    pub fn parse(tag: &str) -> Option<Self> {
+        if tag.len() > MAX_LOCALE_LENGTH {
+            return None;
+        }
+        let mut subtags = tag.split('-');
+        let language = subtags.next()?;
+        if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            return None;
+        }
+        if !subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        }) {
+            return None;
+        }
+        Some(Self(tag.to_ascii_lowercase()))
+    }
+
+    /// Fails with a 422 that names the `locale` field if `tag` is not a locale.
+    pub fn validate(tag: &str) -> Result<Self> {
+        Self::parse(tag).ok_or_else(|| {
+            unprocessable_entity(vec![ValidationError::new(
+                "locale",
+                format!(
+                    "\"{}\" is not a language tag like \"de\" or \"pt-BR\"",
+                    truncate(tag, MAX_LOCALE_LENGTH)
+                ),
+            )])
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The locale without its subtags, e.g. `pt` for `pt-br`.
+    pub fn language(&self) -> &str {
+        self.0.split('-').next().unwrap_or(&self.0)
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A shop's name and description in another language, shown in place of the canonical ones to
+/// clients that prefer that language (see `ShopTranslation::best_match`).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, sqlx::FromRow)]
+pub struct ShopTranslation {
+    pub shop_id: i32,
+    pub locale: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// The body of `PUT /v1/shops/{id}/translations/{locale}`, which replaces the whole translation.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PostedShopTranslation {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+impl PostedShopTranslation {
+    pub fn validate(&mut self) -> Result<()> {
+        let mut errors = vec![];
+        self.name = self.name.nfc().collect();
+        if self.name.trim().is_empty() {
+            errors.push(ValidationError::new("name", "cannot be empty"));
+        } else if self.name.chars().count() > MAX_NAME_LENGTH {
+            errors.push(ValidationError::new(
+                "name",
+                format!("cannot be longer than {} characters", MAX_NAME_LENGTH),
+            ));
+        }
+        if let Some(description) = &self.description {
+            if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+                errors.push(ValidationError::new(
+                    "description",
+                    format!(
+                        "cannot be longer than {} characters",
+                        MAX_DESCRIPTION_LENGTH
+                    ),
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(unprocessable_entity(errors))
+        }
+    }
+}
+
+/// A shop as a client that sent `Accept-Language` sees it: `name` and `description` are from the
+/// best matching translation, if there is one, and the canonical ones are kept alongside.
+#[derive(Debug, Serialize)]
+pub struct LocalizedShop {
+    #[serde(flatten)]
+    pub shop: PublicShop,
+    /// The locale of the translation shown, or `None` when the shop has none that matches.
+    pub locale: Option<String>,
+    pub original_name: String,
+    pub original_description: Option<String>,
+}
+
+impl LocalizedShop {
+    pub fn new(shop: Shop, translation: Option<&ShopTranslation>) -> Self {
+        let mut shop = PublicShop::from(shop);
+        let original_name = shop.name.clone();
+        let original_description = shop.description.clone();
+        let locale = translation.map(|translation| {
+            shop.name = translation.name.clone();
+            shop.description = translation.description.clone();
+            translation.locale.clone()
+        });
+        Self {
+            shop,
+            locale,
+            original_name,
+            original_description,
+        }
+    }
+
+    /// Localizes every shop in `shops` with its best translation for `locale`.
+    pub async fn localize_all(
+        db: impl Executor<'_, Database = Postgres>,
+        shops: Vec<Shop>,
+        locale: &Locale,
+    ) -> Result<Vec<Self>> {
+        let shop_ids: Vec<i32> = shops.iter().map(|shop| shop.id).collect();
+        let translations = ShopTranslation::candidates(db, &shop_ids, locale).await?;
+        Ok(shops
+            .into_iter()
+            .map(|shop| {
+                let translation = ShopTranslation::best_match(
+                    translations
+                        .iter()
+                        .filter(|translation| translation.shop_id == shop.id),
+                    locale,
+                );
+                Self::new(shop, translation)
+            })
+            .collect())
+    }
+}
+
+impl ShopTranslation {
+    /// Picks the translation to show a client that prefers `locale`: one for exactly that locale,
+    /// else one for its language alone (`pt` for `pt-br`), else none, for the canonical name.
+    pub fn best_match<'a>(
+        translations: impl IntoIterator<Item = &'a ShopTranslation>,
+        locale: &Locale,
+    ) -> Option<&'a ShopTranslation> {
+        let mut language_match = None;
+        for translation in translations {
+            if translation.locale == locale.as_str() {
+                return Some(translation);
+            }
+            if translation.locale == locale.language() {
+                language_match = Some(translation);
+            }
+        }
+        language_match
+    }
+
+    /// The translations of `shop_ids` that `best_match` could pick for `locale`.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn candidates(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_ids: &[i32],
+        locale: &Locale,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "shop_translation",
+            "candidates",
+            [("shop_ids", shop_ids.shape()), ("locale", locale.shape())],
+        );
+        Ok(sqlx::query_as!(
+            Self,
+            "SELECT * FROM shop_translations
+            WHERE shop_id = ANY($1) AND locale IN ($2, $3)",
+            shop_ids,
+            locale.as_str(),
+            locale.language(),
+        )
+        .fetch_all(db)
+        .await?)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn list_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+    ) -> Result<Vec<Self>> {
+        let _timer = time_query(
+            "shop_translation",
+            "list_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        Ok(sqlx::query_as!(
+            Self,
+            "SELECT * FROM shop_translations
+            WHERE shop_id = $1
+            ORDER BY locale",
+            shop_id
+        )
+        .fetch_all(db)
+        .await?)
+    }
+
+    #[instrument(level = "debug", skip(db, translation))]
+    pub async fn save(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        locale: &Locale,
+        translation: PostedShopTranslation,
+    ) -> Result<Self> {
+        let _timer = time_query(
+            "shop_translation",
+            "save",
+            [("shop_id", shop_id.shape()), ("locale", locale.shape())],
+        );
+        Ok(sqlx::query_as!(
+            Self,
+            "INSERT INTO shop_translations
+            (shop_id, locale, name, description, created_at, updated_at)
+            VALUES ($1, $2, $3, $4, now(), now())
+            ON CONFLICT (shop_id, locale) DO UPDATE SET
+                name = EXCLUDED.name,
+                description = EXCLUDED.description,
+                updated_at = now()
+            RETURNING *",
+            shop_id,
+            locale.as_str(),
+            translation.name,
+            translation.description,
+        )
+        .fetch_one(db)
+        .await?)
+    }
+
+    #[instrument(level = "debug", skip(db))]
+    pub async fn delete(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        locale: &Locale,
+    ) -> Result<()> {
+        let _timer = time_query(
+            "shop_translation",
+            "delete",
+            [("shop_id", shop_id.shape()), ("locale", locale.shape())],
+        );
+        let deleted = sqlx::query!(
+            "DELETE FROM shop_translations WHERE shop_id = $1 AND locale = $2",
+            shop_id,
+            locale.as_str(),
+        )
+        .execute(db)
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            return Err(not_found(&format!(
+                "The shop has no translation for {}",
+                locale
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{Locale, ShopTranslation};
+
+    fn translation(locale: &str) -> ShopTranslation {
+        let now = Utc::now().naive_utc();
+        ShopTranslation {
+            shop_id: 1,
+            locale: locale.to_string(),
+            name: format!("name in {}", locale),
+            description: None,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn locales_are_validated_and_lowercased() {
+        assert_eq!(Locale::parse("pt-BR").unwrap().as_str(), "pt-br");
+        assert_eq!(Locale::parse("zh-Hant-TW").unwrap().language(), "zh");
+        assert_eq!(Locale::parse("de").unwrap().language(), "de");
+        for invalid in &[
+            "",
+            "d",
+            "deutsch",
+            "de_DE",
+            "de-",
+            "de--at",
+            "1x",
+            "en-toolongtag",
+        ] {
+            assert_eq!(Locale::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn best_match_falls_back_from_exact_locale_to_language_to_none() {
+        let translations = vec![translation("pt"), translation("pt-br"), translation("de")];
+        let pick = |tag: &str| {
+            ShopTranslation::best_match(&translations, &Locale::parse(tag).unwrap())
+                .map(|translation| translation.locale.as_str())
+        };
+        assert_eq!(pick("pt-br"), Some("pt-br"));
+        assert_eq!(pick("pt-pt"), Some("pt"));
+        assert_eq!(pick("de-at"), Some("de"));
+        assert_eq!(pick("fr-ca"), None);
+        // A regional translation is not shown to clients that only ask for the language.
+        assert_eq!(
+            ShopTranslation::best_match(&[translation("pt-br")], &Locale::parse("pt").unwrap()),
+            None
+        );
+    }
+}
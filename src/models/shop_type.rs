@@ -0,0 +1,169 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::decode::Decode;
+use sqlx::encode::{Encode, IsNull};
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef, Postgres};
+use sqlx::types::Type;
+use std::fmt;
+use std::str::FromStr;
+
+pub const MAX_CUSTOM_SHOP_TYPE_LENGTH: usize = 64;
+
+/// The kind of shop, used by the directory's `shop_type` filter.
+///
+/// Written as its snake_case name (`"general_store"`) in JSON, in bincode, and in the `shop_type`
+/// column, so reordering or adding variants never changes an existing payload. Posted values are
+/// case-folded with spaces and dashes read as underscores (`"General Store"` is `general_store`).
+/// Anything else is `Custom`, which is only accepted when `CUSTOM_SHOP_TYPES` is on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ShopType {
+    GeneralStore,
+    Alchemist,
+    Blacksmith,
+    Apothecary,
+    Jeweler,
+    Misc,
+    Custom(String),
+}
+
+impl ShopType {
+    pub const KNOWN: &'static [ShopType] = &[
+        ShopType::GeneralStore,
+        ShopType::Alchemist,
+        ShopType::Blacksmith,
+        ShopType::Apothecary,
+        ShopType::Jeweler,
+        ShopType::Misc,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            ShopType::GeneralStore => "general_store",
+            ShopType::Alchemist => "alchemist",
+            ShopType::Blacksmith => "blacksmith",
+            ShopType::Apothecary => "apothecary",
+            ShopType::Jeweler => "jeweler",
+            ShopType::Misc => "misc",
+            ShopType::Custom(custom) => custom,
+        }
+    }
+
+    pub fn is_custom(&self) -> bool {
+        matches!(self, ShopType::Custom(_))
+    }
+
+    /// The known names, for error messages.
+    pub fn known_names() -> String {
+        Self::KNOWN
+            .iter()
+            .map(ShopType::as_str)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for ShopType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ShopType {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let normalized = value.trim().to_lowercase().replace([' ', '-'], "_");
+        if normalized.is_empty() {
+            return Err("shop type cannot be empty".to_string());
+        }
+        Ok(Self::KNOWN
+            .iter()
+            .find(|known| known.as_str() == normalized)
+            .cloned()
+            .unwrap_or(ShopType::Custom(normalized)))
+    }
+}
+
+impl Serialize for ShopType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for ShopType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Type<Postgres> for ShopType {
+    fn type_info() -> PgTypeInfo {
+        <String as Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <String as Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl Encode<'_, Postgres> for ShopType {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        <&str as Encode<Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for ShopType {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(<&str as Decode<Postgres>>::decode(value)?.parse()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::ShopType;
+
+    #[test]
+    fn parses_known_types_case_insensitively() {
+        assert_eq!("general_store".parse(), Ok(ShopType::GeneralStore));
+        assert_eq!("General Store".parse(), Ok(ShopType::GeneralStore));
+        assert_eq!(" general-store ".parse(), Ok(ShopType::GeneralStore));
+        assert_eq!("JEWELER".parse(), Ok(ShopType::Jeweler));
+        assert_eq!(
+            "Fletcher's Hut".parse(),
+            Ok(ShopType::Custom("fletcher's_hut".to_string()))
+        );
+        assert!("  ".parse::<ShopType>().is_err());
+    }
+
+    #[test]
+    fn json_is_the_snake_case_name() {
+        assert_eq!(
+            serde_json::to_value(ShopType::GeneralStore).unwrap(),
+            json!("general_store")
+        );
+        assert_eq!(
+            serde_json::to_value(ShopType::Custom("fletcher".to_string())).unwrap(),
+            json!("fletcher")
+        );
+        assert_eq!(
+            serde_json::from_value::<ShopType>(json!("Alchemist")).unwrap(),
+            ShopType::Alchemist
+        );
+    }
+
+    #[test]
+    fn bincode_is_the_same_as_a_string() {
+        for shop_type in ShopType::KNOWN {
+            let bytes = bincode::serialize(shop_type).unwrap();
+            assert_eq!(bytes, bincode::serialize(shop_type.as_str()).unwrap());
+            assert_eq!(
+                &bincode::deserialize::<ShopType>(&bytes).unwrap(),
+                shop_type
+            );
+        }
+    }
+}
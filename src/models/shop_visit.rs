@@ -0,0 +1,52 @@
+use anyhow::Result;
+use sqlx::{Executor, Postgres};
+use tracing::instrument;
+
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::not_found;
+
+pub struct ShopVisit;
+
+impl ShopVisit {
+    /// Counts a visit to the shop by the owner (a player), at most once per shop, owner, and UTC
+    /// day. Visits to your own shop are never counted. Returns whether this visit was counted.
+    ///
+    /// The dedup insert and the shop's `visits_count` increment are one statement, so the count
+    /// always matches the rows in `shop_visits`.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn record(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+        owner_id: i32,
+    ) -> Result<bool> {
+        let _timer = time_query(
+            "shop_visit",
+            "record",
+            [("shop_id", shop_id.shape()), ("owner_id", owner_id.shape())],
+        );
+        let row = sqlx::query!(
+            r#"WITH shop AS (
+                SELECT id, owner_id FROM shops WHERE id = $1
+            ), visit AS (
+                INSERT INTO shop_visits (shop_id, owner_id, day)
+                SELECT id, $2, (now() AT TIME ZONE 'UTC')::date FROM shop WHERE owner_id <> $2
+                ON CONFLICT DO NOTHING
+                RETURNING shop_id
+            ), counted AS (
+                UPDATE shops SET visits_count = visits_count + 1
+                WHERE id IN (SELECT shop_id FROM visit)
+                RETURNING id
+            )
+            SELECT EXISTS(SELECT 1 FROM shop) as "found!",
+                EXISTS(SELECT 1 FROM counted) as "counted!""#,
+            shop_id,
+            owner_id,
+        )
+        .fetch_one(db)
+        .await?;
+        if !row.found {
+            return Err(not_found("Shop does not exist"));
+        }
+        Ok(row.counted)
+    }
+}
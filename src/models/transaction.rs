@@ -1,49 +1,198 @@
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
 use chrono::prelude::*;
+use http::StatusCode;
+use http_api_problem::HttpApiProblem;
 use serde::{Deserialize, Serialize};
-use sqlx::{Done, Executor, Postgres};
+use sqlx::{Executor, Postgres};
+use std::ops::RangeInclusive;
 use tracing::instrument;
 use url::Url;
 
-use super::ListParams;
-use crate::problem::forbidden_permission;
+use super::merchandise_list::{icon_key_error, item_field_errors};
+use super::{FormId, ListQuery, NoFilter, Pagination};
+use crate::metrics::queries::{time_query, Shape};
+use crate::problem::{forbidden_permission, not_found, unprocessable_entity, ValidationError};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Bounds on the quantity and price of a single posted transaction.
+///
+/// Configured by `TRANSACTION_MIN_QUANTITY`, `TRANSACTION_MAX_QUANTITY`, `TRANSACTION_MIN_PRICE`,
+/// and `TRANSACTION_MAX_PRICE`; see `Config`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TransactionLimits {
+    pub quantity: RangeInclusive<i32>,
+    pub price: RangeInclusive<i64>,
+}
+
+impl Default for TransactionLimits {
+    fn default() -> Self {
+        Self {
+            quantity: 1..=10_000,
+            price: 0..=1_000_000,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, sqlx::FromRow)]
 pub struct Transaction {
     pub id: i32,
     pub shop_id: i32,
     pub owner_id: i32,
     pub mod_name: String,
-    pub local_form_id: i32,
+    pub local_form_id: FormId,
     pub name: String,
-    pub form_type: i32,
+    pub form_kind: i32,
     pub is_food: bool,
-    pub price: i32,
+    pub price: i64,
     pub is_sell: bool,
     pub quantity: i32,
-    pub amount: i32,
+    pub amount: i64,
     pub keywords: Vec<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
 
+/// Query parameters of `GET /v1/transactions` and `GET /v1/shops/{id}/transactions`, which only page and sort.
+#[derive(Debug, Eq, PartialEq, Hash, Clone)]
+pub struct TransactionListQuery {
+    pub pagination: Pagination,
+}
+
+impl ListQuery for TransactionListQuery {
+    type Filter = NoFilter;
+    const FILTER_PARAMS: &'static [&'static str] = &[];
+
+    fn new(pagination: Pagination, _filter: NoFilter) -> Result<Self> {
+        Ok(Self { pagination })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PostedTransaction {
     pub shop_id: i32,
     pub owner_id: Option<i32>,
     pub mod_name: String,
-    pub local_form_id: i32,
+    pub local_form_id: FormId,
+    pub name: String,
+    pub form_kind: i32,
+    pub is_food: bool,
+    pub price: i64,
+    pub is_sell: bool,
+    pub quantity: i32,
+    pub amount: i64,
+    pub keywords: Vec<String>,
+    /// Copied to the item when a sell adds it to the shop's merchandise list. Not stored with the
+    /// transaction itself.
+    #[serde(default)]
+    pub icon_key: Option<String>,
+}
+
+/// A `PostedTransaction` in the bincode layout from before `icon_key`, which older clients still
+/// send. See `LegacyMerchandise`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LegacyPostedTransaction {
+    pub shop_id: i32,
+    pub owner_id: Option<i32>,
+    pub mod_name: String,
+    pub local_form_id: FormId,
     pub name: String,
-    pub form_type: i32,
+    pub form_kind: i32,
     pub is_food: bool,
-    pub price: i32,
+    pub price: i64,
     pub is_sell: bool,
     pub quantity: i32,
-    pub amount: i32,
+    pub amount: i64,
     pub keywords: Vec<String>,
 }
 
+impl From<LegacyPostedTransaction> for PostedTransaction {
+    fn from(legacy: LegacyPostedTransaction) -> Self {
+        PostedTransaction {
+            shop_id: legacy.shop_id,
+            owner_id: legacy.owner_id,
+            mod_name: legacy.mod_name,
+            local_form_id: legacy.local_form_id,
+            name: legacy.name,
+            form_kind: legacy.form_kind,
+            is_food: legacy.is_food,
+            price: legacy.price,
+            is_sell: legacy.is_sell,
+            quantity: legacy.quantity,
+            amount: legacy.amount,
+            keywords: legacy.keywords,
+            icon_key: None,
+        }
+    }
+}
+
+impl PostedTransaction {
+    /// Checks quantity and price against `limits` and the item's fields against the merchandise
+    /// caps, then overwrites the client-supplied `amount` with `price * quantity` computed
+    /// server-side, rejecting the transaction if that overflows.
+    pub fn validate(&mut self, limits: &TransactionLimits) -> Result<()> {
+        let mut errors = vec![];
+        if !limits.quantity.contains(&self.quantity) {
+            errors.push(ValidationError::new(
+                "quantity",
+                format!(
+                    "must be between {} and {}",
+                    limits.quantity.start(),
+                    limits.quantity.end()
+                ),
+            ));
+        }
+        if !limits.price.contains(&self.price) {
+            errors.push(ValidationError::new(
+                "price",
+                format!(
+                    "must be between {} and {}",
+                    limits.price.start(),
+                    limits.price.end()
+                ),
+            ));
+        }
+        for (field, message) in item_field_errors(&self.mod_name, &self.name, &self.keywords) {
+            errors.push(ValidationError::new(field, message));
+        }
+        if let Some(message) = icon_key_error(self.icon_key.as_deref()) {
+            errors.push(ValidationError::new("icon_key", message));
+        }
+        if !errors.is_empty() {
+            return Err(unprocessable_entity(errors));
+        }
+
+        self.amount = self
+            .price
+            .checked_mul(self.quantity as i64)
+            .ok_or_else(|| {
+                anyhow!(
+                    HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+                        .set_title("Transaction Amount Overflow")
+                        .set_detail("Price multiplied by quantity is too large")
+                )
+            })?;
+        Ok(())
+    }
+}
+
 impl Transaction {
+    pub const ORDER_BY_COLUMNS: &'static [&'static str] = &[
+        "id",
+        "shop_id",
+        "owner_id",
+        "mod_name",
+        "local_form_id",
+        "name",
+        "form_kind",
+        "is_food",
+        "price",
+        "is_sell",
+        "quantity",
+        "amount",
+        "created_at",
+        "updated_at",
+    ];
+    pub const DEFAULT_ORDER_BY: &'static str = "id DESC";
+
     pub fn resource_name() -> &'static str {
         "transaction"
     }
@@ -58,10 +207,18 @@ impl Transaction {
 
     #[instrument(level = "debug", skip(db))]
     pub async fn get(db: impl Executor<'_, Database = Postgres>, id: i32) -> Result<Self> {
-        sqlx::query_as!(Self, "SELECT * FROM transactions WHERE id = $1", id)
-            .fetch_one(db)
-            .await
-            .map_err(Error::new)
+        let _timer = time_query("transaction", "get", [("id", id.shape())]);
+        sqlx::query_as!(
+            Self,
+            r#"SELECT id, shop_id, owner_id, mod_name, local_form_id as "local_form_id: FormId",
+                name, form_kind, is_food, price, is_sell, quantity, amount, keywords, created_at,
+                updated_at
+            FROM transactions WHERE id = $1"#,
+            id
+        )
+        .fetch_one(db)
+        .await
+        .map_err(Error::new)
     }
 
     #[instrument(level = "debug", skip(db))]
@@ -69,19 +226,22 @@ impl Transaction {
         transaction: PostedTransaction,
         db: impl Executor<'_, Database = Postgres>,
     ) -> Result<Self> {
+        let _timer = time_query("transaction", "create", []);
         Ok(sqlx::query_as!(
             Self,
-            "INSERT INTO transactions
-            (shop_id, owner_id, mod_name, local_form_id, name, form_type, is_food, price,
+            r#"INSERT INTO transactions
+            (shop_id, owner_id, mod_name, local_form_id, name, form_kind, is_food, price,
              is_sell, quantity, amount, keywords, created_at, updated_at)
             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, now(), now())
-            RETURNING *",
+            RETURNING id, shop_id, owner_id, mod_name, local_form_id as "local_form_id: FormId",
+                name, form_kind, is_food, price, is_sell, quantity, amount, keywords, created_at,
+                updated_at"#,
             transaction.shop_id,
             transaction.owner_id,
             transaction.mod_name,
-            transaction.local_form_id,
+            i64::from(transaction.local_form_id),
             transaction.name,
-            transaction.form_type,
+            transaction.form_kind,
             transaction.is_food,
             transaction.price,
             transaction.is_sell,
@@ -98,88 +258,179 @@ impl Transaction {
         db: impl Executor<'_, Database = Postgres> + Copy,
         owner_id: i32,
         id: i32,
-    ) -> Result<u64> {
-        let transaction = sqlx::query!("SELECT owner_id FROM transactions WHERE id = $1", id)
-            .fetch_one(db)
-            .await?;
-        if transaction.owner_id == owner_id {
-            return Ok(sqlx::query!("DELETE FROM transactions WHERE id = $1", id)
-                .execute(db)
-                .await?
-                .rows_affected());
-        } else {
-            return Err(forbidden_permission());
+    ) -> Result<i32> {
+        let _timer = time_query(
+            "transaction",
+            "delete",
+            [("owner_id", owner_id.shape()), ("id", id.shape())],
+        );
+        let deleted = sqlx::query!(
+            "DELETE FROM transactions WHERE id = $1 AND owner_id = $2 RETURNING shop_id",
+            id,
+            owner_id
+        )
+        .fetch_optional(db)
+        .await?;
+        if let Some(deleted) = deleted {
+            return Ok(deleted.shop_id);
         }
+        let exists = sqlx::query!("SELECT id FROM transactions WHERE id = $1", id)
+            .fetch_optional(db)
+            .await?
+            .is_some();
+        Err(if exists {
+            forbidden_permission()
+        } else {
+            not_found("Transaction does not exist or has already been deleted")
+        })
     }
 
     #[instrument(level = "debug", skip(db))]
     pub async fn list(
         db: impl Executor<'_, Database = Postgres>,
-        list_params: &ListParams,
+        query: &TransactionListQuery,
     ) -> Result<Vec<Self>> {
-        let result = if let Some(order_by) = list_params.get_order_by() {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM transactions
-                ORDER BY $1
-                LIMIT $2
-                OFFSET $3",
-                order_by,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        } else {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM transactions
-                LIMIT $1
-                OFFSET $2",
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        };
-        Ok(result)
+        let _timer = time_query("transaction", "list", []);
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        // Not using the query_as! macro since the ORDER BY clause is dynamic
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM transactions
+            ORDER BY {}
+            LIMIT $1
+            OFFSET $2",
+            order_by
+        ))
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
     }
 
+    /// The number of transactions `list` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count(db: impl Executor<'_, Database = Postgres>) -> Result<i64> {
+        let _timer = time_query("transaction", "count", []);
+        Ok(
+            sqlx::query!(r#"SELECT COUNT(*) as "count!" FROM transactions"#)
+                .fetch_one(db)
+                .await?
+                .count,
+        )
+    }
+
+    // Sorting by `created_at` (or the default `id`) is served by the
+    // `transactions_shop_id_and_created_at` index; keep them in step.
     #[instrument(level = "debug", skip(db))]
     pub async fn list_by_shop_id(
         db: impl Executor<'_, Database = Postgres>,
         shop_id: i32,
-        list_params: &ListParams,
+        query: &TransactionListQuery,
     ) -> Result<Vec<Self>> {
-        let result = if let Some(order_by) = list_params.get_order_by() {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM transactions
-                WHERE shop_id = $1
-                ORDER BY $2
-                LIMIT $3
-                OFFSET $4",
-                shop_id,
-                order_by,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
-        } else {
-            sqlx::query_as!(
-                Self,
-                "SELECT * FROM transactions
-                WHERE shop_id = $1
-                LIMIT $2
-                OFFSET $3",
-                shop_id,
-                list_params.limit.unwrap_or(10),
-                list_params.offset.unwrap_or(0),
-            )
-            .fetch_all(db)
-            .await?
+        let _timer = time_query(
+            "transaction",
+            "list_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        let order_by = query
+            .pagination
+            .order_by_clause(Self::ORDER_BY_COLUMNS, Self::DEFAULT_ORDER_BY)?;
+        Ok(sqlx::query_as::<_, Self>(&format!(
+            "SELECT * FROM transactions
+            WHERE shop_id = $1
+            ORDER BY {}
+            LIMIT $2
+            OFFSET $3",
+            order_by
+        ))
+        .bind(shop_id)
+        .bind(query.pagination.limit.unwrap_or(10))
+        .bind(query.pagination.offset.unwrap_or(0))
+        .fetch_all(db)
+        .await?)
+    }
+
+    /// The number of transactions `list_by_shop_id` would return across all pages.
+    #[instrument(level = "debug", skip(db))]
+    pub async fn count_by_shop_id(
+        db: impl Executor<'_, Database = Postgres>,
+        shop_id: i32,
+    ) -> Result<i64> {
+        let _timer = time_query(
+            "transaction",
+            "count_by_shop_id",
+            [("shop_id", shop_id.shape())],
+        );
+        Ok(sqlx::query!(
+            r#"SELECT COUNT(*) as "count!" FROM transactions WHERE shop_id = $1"#,
+            shop_id
+        )
+        .fetch_one(db)
+        .await?
+        .count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{PostedTransaction, TransactionLimits};
+
+    fn transaction(price: i64, quantity: i32) -> PostedTransaction {
+        serde_json::from_value(json!({
+            "shop_id": 1,
+            "owner_id": null,
+            "mod_name": "Skyrim.esm",
+            "local_form_id": 7,
+            "name": "Gold Ingot",
+            "form_kind": 32,
+            "is_food": false,
+            "price": price,
+            "is_sell": false,
+            "quantity": quantity,
+            "amount": 0,
+            "keywords": [],
+        }))
+        .unwrap()
+    }
+
+    fn unlimited() -> TransactionLimits {
+        TransactionLimits {
+            quantity: 1..=i32::MAX,
+            price: 0..=i64::MAX,
+        }
+    }
+
+    #[test]
+    fn amount_can_exceed_i32() {
+        let mut posted = transaction(i32::MAX as i64, 2);
+        posted.validate(&unlimited()).unwrap();
+        assert_eq!(posted.amount, 4_294_967_294);
+
+        let mut posted = transaction(i32::MAX as i64 + 1, 1);
+        posted.validate(&unlimited()).unwrap();
+        assert_eq!(posted.amount, 2_147_483_648);
+    }
+
+    #[test]
+    fn amount_overflowing_i64_is_rejected() {
+        let mut posted = transaction(i64::MAX / 2 + 1, 2);
+        assert!(posted.validate(&unlimited()).is_err());
+
+        let mut posted = transaction(i64::MAX, 1);
+        posted.validate(&unlimited()).unwrap();
+        assert_eq!(posted.amount, i64::MAX);
+    }
+
+    #[test]
+    fn price_limits_apply_above_i32() {
+        let limits = TransactionLimits {
+            quantity: 1..=10,
+            price: 0..=3_000_000_000,
         };
-        Ok(result)
+        transaction(3_000_000_000, 1).validate(&limits).unwrap();
+        assert!(transaction(3_000_000_001, 1).validate(&limits).is_err());
     }
 }
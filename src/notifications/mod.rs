@@ -0,0 +1,214 @@
+use serde::Serialize;
+use tracing::info;
+
+use crate::models::{FormId, NotificationSettings, ShopRequest, Transaction};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ShopEvent {
+    Sale {
+        shop_id: i32,
+        transaction_id: i32,
+        mod_name: String,
+        local_form_id: FormId,
+        quantity: i32,
+    },
+    OutOfStock {
+        shop_id: i32,
+        mod_name: String,
+        local_form_id: FormId,
+    },
+    LowStock {
+        shop_id: i32,
+        mod_name: String,
+        local_form_id: FormId,
+        remaining_quantity: i32,
+        threshold: i32,
+    },
+    ItemRequested {
+        shop_id: i32,
+        request_id: i32,
+        mod_name: String,
+        local_form_id: FormId,
+        quantity: i32,
+    },
+    /// For the requester: the shop now stocks enough of the item they asked for.
+    RequestFulfillable {
+        shop_id: i32,
+        request_id: i32,
+        requester_owner_id: i32,
+    },
+}
+
+/// The events a transaction produces that the shop's owner has opted into. `remaining_quantity` is
+/// the item's quantity in the shop after the transaction.
+///
+/// Out of stock and low stock events only fire when the transaction crosses into that state, so
+/// further sales of an item that is already low do not notify again.
+pub fn transaction_events(
+    settings: &NotificationSettings,
+    transaction: &Transaction,
+    remaining_quantity: i32,
+) -> Vec<ShopEvent> {
+    // Only purchases from the shop (`is_sell == false`) are sales from the shop owner's view
+    if transaction.is_sell {
+        return vec![];
+    }
+    let previous_quantity = remaining_quantity + transaction.quantity;
+    let mut events = vec![];
+    if settings.on_sale {
+        events.push(ShopEvent::Sale {
+            shop_id: transaction.shop_id,
+            transaction_id: transaction.id,
+            mod_name: transaction.mod_name.clone(),
+            local_form_id: transaction.local_form_id,
+            quantity: transaction.quantity,
+        });
+    }
+    if remaining_quantity == 0 {
+        if settings.on_out_of_stock {
+            events.push(ShopEvent::OutOfStock {
+                shop_id: transaction.shop_id,
+                mod_name: transaction.mod_name.clone(),
+                local_form_id: transaction.local_form_id,
+            });
+        }
+    } else if let Some(threshold) = settings.on_low_stock_threshold {
+        if previous_quantity > threshold && remaining_quantity <= threshold {
+            events.push(ShopEvent::LowStock {
+                shop_id: transaction.shop_id,
+                mod_name: transaction.mod_name.clone(),
+                local_form_id: transaction.local_form_id,
+                remaining_quantity,
+                threshold,
+            });
+        }
+    }
+    events
+}
+
+/// The event telling a shop's owner about a new request. Requests aren't covered by
+/// `NotificationSettings`, so it is always sent.
+pub fn request_created_event(request: &ShopRequest) -> ShopEvent {
+    ShopEvent::ItemRequested {
+        shop_id: request.shop_id,
+        request_id: request.id,
+        mod_name: request.mod_name.clone(),
+        local_form_id: request.local_form_id,
+        quantity: request.quantity,
+    }
+}
+
+/// The events telling requesters that the shop can now fill their requests.
+pub fn requests_fulfillable_events(requests: &[ShopRequest]) -> Vec<ShopEvent> {
+    requests
+        .iter()
+        .filter(|request| request.fulfillable)
+        .map(|request| ShopEvent::RequestFulfillable {
+            shop_id: request.shop_id,
+            request_id: request.id,
+            requester_owner_id: request.requester_owner_id,
+        })
+        .collect()
+}
+
+/// Publishes events to the shop's owner, or to the requester for `RequestFulfillable`. There is no
+/// delivery mechanism (webhooks, SSE) yet, so events are only logged for now; this is where one
+/// would hook in.
+pub fn emit(events: Vec<ShopEvent>) {
+    for event in events {
+        info!(?event, "shop event");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{transaction_events, ShopEvent};
+    use crate::models::{FormId, NotificationSettings, Transaction};
+
+    fn sale(quantity: i32) -> Transaction {
+        let now = Utc::now().naive_utc();
+        Transaction {
+            id: 1,
+            shop_id: 1,
+            owner_id: 2,
+            mod_name: "Skyrim.esm".to_string(),
+            local_form_id: FormId(1),
+            name: "Iron Sword".to_string(),
+            form_kind: 41,
+            is_food: false,
+            price: 10,
+            is_sell: false,
+            quantity,
+            amount: 10 * quantity as i64,
+            keywords: vec![],
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    fn low_stock(events: &[ShopEvent]) -> Option<i32> {
+        events.iter().find_map(|event| match event {
+            ShopEvent::LowStock {
+                remaining_quantity, ..
+            } => Some(*remaining_quantity),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn low_stock_fires_when_a_sale_crosses_the_threshold() {
+        let settings = NotificationSettings {
+            on_sale: false,
+            on_out_of_stock: false,
+            on_low_stock_threshold: Some(3),
+        };
+        // Dropping exactly onto the threshold crosses it.
+        assert_eq!(
+            low_stock(&transaction_events(&settings, &sale(1), 3)),
+            Some(3)
+        );
+        assert_eq!(
+            low_stock(&transaction_events(&settings, &sale(3), 2)),
+            Some(2)
+        );
+        // Staying above it, or starting at or below it, does not.
+        assert_eq!(low_stock(&transaction_events(&settings, &sale(1), 4)), None);
+        assert_eq!(low_stock(&transaction_events(&settings, &sale(1), 2)), None);
+        assert_eq!(low_stock(&transaction_events(&settings, &sale(1), 0)), None);
+
+        let mut purchase = sale(1);
+        purchase.is_sell = true;
+        assert!(transaction_events(&settings, &purchase, 3).is_empty());
+    }
+
+    #[test]
+    fn events_follow_the_settings() {
+        let defaults = transaction_events(&NotificationSettings::default(), &sale(2), 0);
+        assert!(matches!(
+            defaults.as_slice(),
+            [
+                ShopEvent::Sale { quantity: 2, .. },
+                ShopEvent::OutOfStock { .. }
+            ]
+        ));
+        assert!(
+            transaction_events(&NotificationSettings::default(), &sale(1), 1)
+                .iter()
+                .all(|event| matches!(event, ShopEvent::Sale { .. }))
+        );
+
+        let settings = NotificationSettings {
+            on_sale: false,
+            on_out_of_stock: true,
+            on_low_stock_threshold: Some(5),
+        };
+        assert!(matches!(
+            transaction_events(&settings, &sale(2), 0).as_slice(),
+            [ShopEvent::OutOfStock { .. }]
+        ));
+        assert!(transaction_events(&settings, &sale(2), 6).is_empty());
+    }
+}
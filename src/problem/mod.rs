@@ -1,11 +1,66 @@
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
+use std::time::Duration;
 
 use anyhow::{anyhow, Error};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use http::header::{HeaderValue, RETRY_AFTER};
 use http::StatusCode;
 use http_api_problem::HttpApiProblem;
-use tracing::error;
+use serde::Serialize;
+use tracing::{debug, error};
+use url::Url;
+use uuid::Uuid;
 use warp::{reject, Rejection, Reply};
 
+use crate::clock::format_server_time;
+use crate::maintenance::MaintenanceMode;
+
+/// Longest player-provided value (a name, tag, query parameter, ...) echoed back in problem
+/// details or logs.
+pub const MAX_ECHOED_VALUE_CHARS: usize = 100;
+/// Longest error message echoed back in problem details or logs. Deserialization and database
+/// errors can quote entire request values.
+pub const MAX_ECHOED_MESSAGE_CHARS: usize = 1000;
+
+/// Caps `value` at `max_chars` characters, marking the cut with an ellipsis and the original
+/// length so a truncated value is never mistaken for the real one.
+pub fn truncate(value: &str, max_chars: usize) -> Cow<'_, str> {
+    match value.char_indices().nth(max_chars) {
+        None => Cow::Borrowed(value),
+        Some((end, _)) => Cow::Owned(format!(
+            "{}… ({} chars total)",
+            &value[..end],
+            value.chars().count()
+        )),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ValidationError {
+    pub field: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            index: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn at_index(field: &'static str, index: usize, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            index: Some(index),
+            message: message.into(),
+        }
+    }
+}
+
 pub fn forbidden_permission() -> Error {
     anyhow!(
         HttpApiProblem::with_title_and_type_from_status(StatusCode::FORBIDDEN,)
@@ -27,6 +82,528 @@ pub fn unauthorized_no_api_key() -> Error {
     )
 }
 
+pub fn invalid_api_key() -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Invalid Api-Key")
+        .set_detail(
+            "Api-Key header must be a UUID, or be left out when creating an owner to have one \
+            generated",
+        );
+    problem
+        .set_value("code", &"invalid_api_key")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn not_found(detail: &str) -> Error {
+    anyhow!(
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::NOT_FOUND).set_detail(detail)
+    )
+}
+
+pub fn unknown_query_params(unknown: &[String], supported: &[&str]) -> Error {
+    let unknown: Vec<Cow<'_, str>> = unknown
+        .iter()
+        .map(|param| truncate(param, MAX_ECHOED_VALUE_CHARS))
+        .collect();
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Unknown Query Parameter")
+        .set_detail(format!(
+            "Unknown query parameter(s): {}. Supported parameters are: {}",
+            unknown.join(", "),
+            supported.join(", ")
+        ));
+    problem
+        .set_value("unknown_params", &unknown)
+        .expect("unknown_params is not a reserved problem field");
+    problem
+        .set_value("supported_params", &supported)
+        .expect("supported_params is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn shop_gone() -> Error {
+    anyhow!(
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::NOT_FOUND)
+            .set_detail("Shop does not exist or has been deleted")
+    )
+}
+
+pub fn shop_id_immutable(shop_id: i32, posted_shop_id: i32) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+        .set_title("Shop Id Is Immutable")
+        .set_detail(format!(
+            "This list belongs to shop {} and cannot be moved to shop {}",
+            shop_id, posted_shop_id
+        ));
+    problem
+        .set_value("code", &"shop_id_immutable")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// The request's `X-Target-Shop` names a different shop than its url, e.g. a saved body replayed
+/// against the wrong shop.
+pub fn target_shop_mismatch(shop_id: i32, target_shop_id: i32) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+        .set_title("Target Shop Mismatch")
+        .set_detail(format!(
+            "This request was sent to shop {} but its X-Target-Shop header is for shop {}",
+            shop_id, target_shop_id
+        ));
+    problem
+        .set_value("code", &"target_shop_mismatch")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// `code` of the 503 for requests that failed because the database could not be reached, which
+/// `replays` looks for to journal writes.
+pub const DATABASE_UNAVAILABLE_CODE: &str = "database_unavailable";
+
+fn database_unavailable() -> HttpApiProblem {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::SERVICE_UNAVAILABLE)
+            .set_title("Database Unavailable")
+            .set_detail("The database could not be reached. Please try again later.");
+    problem
+        .set_value("code", &DATABASE_UNAVAILABLE_CODE)
+        .expect("code is not a reserved problem field");
+    problem
+}
+
+// Errors from losing (or never getting) a connection, as opposed to errors from the query itself.
+fn is_connection_error(error: &sqlx::error::Error) -> bool {
+    match error {
+        sqlx::error::Error::Io(_)
+        | sqlx::error::Error::Tls(_)
+        | sqlx::error::Error::PoolTimedOut
+        | sqlx::error::Error::PoolClosed => true,
+        sqlx::error::Error::Database(db_error) => {
+            let code = db_error
+                .downcast_ref::<sqlx::postgres::PgDatabaseError>()
+                .code();
+            // connection_exception, or the server shutting down or still starting up
+            code.starts_with("08") || matches!(code, "57P01" | "57P02" | "57P03")
+        }
+        _ => false,
+    }
+}
+
+/// The write was journaled instead of applied, but the journal is at `REPLAY_JOURNAL_MAX_ENTRIES`
+/// or `REPLAY_JOURNAL_MAX_BYTES`.
+pub fn replay_journal_full() -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::SERVICE_UNAVAILABLE)
+            .set_title("Replay Journal Full")
+            .set_detail(
+                "Earlier writes are still waiting to be replayed and the journal has no room for \
+                this one. Please try again later.",
+            );
+    problem
+        .set_value("code", &"replay_journal_full")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// The list was saved by someone else since the client read `expected_revision`.
+pub fn revision_mismatch(expected_revision: i32, current_revision: i32) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+        .set_title("Revision Mismatch")
+        .set_detail(format!(
+            "Expected revision {} but the list is at revision {}",
+            expected_revision, current_revision
+        ));
+    problem
+        .set_value("code", &"revision_mismatch")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("current_revision", &current_revision)
+        .expect("current_revision is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// The resource was changed by someone else since the client read the ETag it sent in `If-Match`.
+pub fn precondition_failed() -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::PRECONDITION_FAILED)
+            .set_detail(
+                "The resource has changed since the ETag in If-Match was read. Fetch it again \
+                before retrying.",
+            );
+    problem
+        .set_value("code", &"precondition_failed")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// `SHOP_DELETE_CONFIRMATION` is on and the delete didn't carry a current token from the shop's
+/// deletion preview.
+pub fn deletion_confirmation_required(detail: &str) -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::PRECONDITION_REQUIRED)
+            .set_title("Deletion Confirmation Required")
+            .set_detail(detail);
+    problem
+        .set_value("code", &"deletion_confirmation_required")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn banned_from_shop() -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::FORBIDDEN)
+        .set_title("Banned From Shop")
+        .set_detail("The shop's owner has banned you from trading at this shop");
+    problem
+        .set_value("code", &"banned_from_shop")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn review_requires_purchase() -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::FORBIDDEN)
+        .set_title("Review Requires Purchase")
+        .set_detail("Only players who have traded at this shop can review it");
+    problem
+        .set_value("code", &"review_requires_purchase")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn owner_exists(owner_id: i32, owner_url: &Url) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+        .set_title("Owner Already Exists")
+        .set_detail(format!(
+            "An owner with this name and Api-Key already exists with id {}",
+            owner_id
+        ));
+    problem
+        .set_value("code", &"owner_exists")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("owner_id", &owner_id)
+        .expect("owner_id is not a reserved problem field");
+    problem
+        .set_value("owner_url", &owner_url.as_str())
+        .expect("owner_url is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn shop_exists(shop_id: i32, shop_url: &Url) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+        .set_title("Shop Already Exists")
+        .set_detail(format!(
+            "Owner already has a different shop with that name (ignoring case) with id {}",
+            shop_id
+        ));
+    problem
+        .set_value("code", &"shop_exists")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("shop_id", &shop_id)
+        .expect("shop_id is not a reserved problem field");
+    problem
+        .set_value("shop_url", &shop_url.as_str())
+        .expect("shop_url is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn list_exists(resource_name: &str, shop_id: i32, list_id: i32, list_url: &Url) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+        .set_title("List Already Exists")
+        .set_detail(format!(
+            "Shop {} already has a {} with id {}",
+            shop_id,
+            resource_name.replace('_', " "),
+            list_id
+        ));
+    problem
+        .set_value("code", &"list_exists")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("list_id", &list_id)
+        .expect("list_id is not a reserved problem field");
+    problem
+        .set_value("list_url", &list_url.as_str())
+        .expect("list_url is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// The constraint that `error` violated, if it is a database unique violation.
+pub fn unique_violation(error: &Error) -> Option<&str> {
+    match error.downcast_ref::<sqlx::error::Error>() {
+        Some(sqlx::error::Error::Database(db_error)) => {
+            let pg_error = db_error.downcast_ref::<sqlx::postgres::PgDatabaseError>();
+            if pg_error.code() == "23505" {
+                pg_error.constraint()
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn gateway_timeout() -> Error {
+    anyhow!(
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::GATEWAY_TIMEOUT)
+            .set_detail("The request took too long to complete")
+    )
+}
+
+pub fn invalid_query_param(param: &str, detail: &str) -> Error {
+    anyhow!(
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+            .set_title("Invalid Query Parameter")
+            .set_detail(format!(
+                "Invalid value for \"{}\": {}",
+                param,
+                truncate(detail, MAX_ECHOED_MESSAGE_CHARS)
+            ))
+    )
+}
+
+pub fn invalid_order_by(order_by: &str, columns: &[&str]) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Invalid Order By")
+        .set_detail(format!(
+            "Cannot order by \"{}\". Supported columns are: {}",
+            truncate(order_by, MAX_ECHOED_VALUE_CHARS),
+            columns.join(", ")
+        ));
+    problem
+        .set_value("supported_columns", &columns)
+        .expect("supported_columns is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn invalid_schema_version(version: &str) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Invalid Schema Version")
+        .set_detail(format!(
+            "Unknown X-Schema-Version \"{}\". Supported versions are 1 and 2",
+            truncate(version, MAX_ECHOED_VALUE_CHARS)
+        ));
+    problem
+        .set_value("code", &"invalid_schema_version")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// The mismatched tables and columns are only logged (by `schema::check`), since the Postgres errors
+/// describing them are not for unauthenticated clients.
+pub fn schema_mismatch() -> Error {
+    anyhow!(
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::SERVICE_UNAVAILABLE)
+            .set_title("Database Schema Mismatch")
+            .set_detail(
+                "The database schema does not match what this version of the API expects. \
+                The server operator needs to run the database migrations.",
+            )
+    )
+}
+
+/// A timestamp the client sent is further ahead of the server's clock than `CLOCK_SKEW_TOLERANCE_SECS`,
+/// so the client's clock is probably wrong. `server_time` lets it work out by how much.
+pub fn clock_skew(param: &str, value: NaiveDateTime, server_time: DateTime<Utc>) -> Error {
+    let server_time = format_server_time(server_time);
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Clock Skew")
+        .set_detail(format!(
+            "{} is {}, which is still in the future at the server time of {}. Check the clock \
+            of the device that sent it.",
+            param, value, server_time
+        ));
+    problem
+        .set_value("code", &"clock_skew")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("param", &param)
+        .expect("param is not a reserved problem field");
+    problem
+        .set_value("server_time", &server_time)
+        .expect("server_time is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn maintenance(mode: MaintenanceMode, retry_after: Duration) -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::SERVICE_UNAVAILABLE)
+            .set_title("Down For Maintenance")
+            .set_detail(match mode {
+                MaintenanceMode::ReadOnly => {
+                    "The API is read-only during maintenance. Please try again later."
+                }
+                _ => "The API is down for maintenance. Please try again later.",
+            });
+    problem
+        .set_value("code", &format!("maintenance_{}", mode))
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("retry_after", &retry_after.as_secs())
+        .expect("retry_after is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn quota_exceeded(quota: u64, resets_on: NaiveDate) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::FORBIDDEN)
+        .set_title("Monthly Quota Exceeded")
+        .set_detail(format!(
+            "Api-Key has used all {} of its requests for this month",
+            quota
+        ));
+    problem
+        .set_value("code", &"quota_exceeded")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("resets_on", &resets_on)
+        .expect("resets_on is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn rate_limited(retry_after: Duration) -> Error {
+    too_many_requests(
+        "rate_limited",
+        "Too many requests from this address. Please try again later.",
+        retry_after,
+    )
+}
+
+pub fn expensive_rate_limited(retry_after: Duration) -> Error {
+    too_many_requests(
+        "expensive_rate_limited",
+        "Too many requests to expensive endpoints. Other endpoints are still available.",
+        retry_after,
+    )
+}
+
+fn too_many_requests(code: &str, detail: &str, retry_after: Duration) -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::TOO_MANY_REQUESTS)
+            .set_title("Too Many Requests")
+            .set_detail(detail);
+    problem
+        .set_value("code", &code)
+        .expect("code is not a reserved problem field");
+    // Rounded up so that a client waiting this long always gets through.
+    let retry_after_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    problem
+        .set_value("retry_after", &retry_after_secs)
+        .expect("retry_after is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn body_digest_mismatch() -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Body Digest Mismatch")
+        .set_detail("Request body does not match its digest, it may have been truncated");
+    problem
+        .set_value("code", &"body_digest_mismatch")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn body_length_mismatch(declared: u64, received: u64) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Body Length Mismatch")
+        .set_detail(format!(
+            "Content-Length is {} bytes but {} bytes were received, the body may have been truncated",
+            declared, received
+        ));
+    problem
+        .set_value("code", &"body_length_mismatch")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn content_length_required() -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::LENGTH_REQUIRED)
+        .set_detail("Bincode request bodies must be sent with a Content-Length");
+    problem
+        .set_value("code", &"content_length_required")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn payload_too_large(max_bytes: u64) -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::PAYLOAD_TOO_LARGE).set_detail(
+            format!("Request body cannot be larger than {} bytes", max_bytes),
+        );
+    problem
+        .set_value("code", &"payload_too_large")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn invalid_body_digest(detail: &str) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Invalid Body Digest")
+        .set_detail(detail);
+    problem
+        .set_value("code", &"invalid_body_digest")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn unsupported_digest_algorithm(tokens: &[String]) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
+        .set_title("Unsupported Digest Algorithm")
+        .set_detail(format!(
+            "Content-Digest has no supported algorithm, got: {}",
+            truncate(&tokens.join(", "), MAX_ECHOED_MESSAGE_CHARS)
+        ));
+    problem
+        .set_value("code", &"unsupported_digest_algorithm")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("supported", &["sha-256", "sha-512"])
+        .expect("supported is not a reserved problem field");
+    anyhow!(problem)
+}
+
+/// An import archive that can't be read, or whose rows the database refused. `line` is 1-based.
+pub fn invalid_archive(line: usize, detail: &str) -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::UNPROCESSABLE_ENTITY)
+            .set_title("Invalid Archive")
+            .set_detail(format!(
+                "Line {}: {}",
+                line,
+                truncate(detail, MAX_ECHOED_MESSAGE_CHARS)
+            ));
+    problem
+        .set_value("code", &"invalid_archive")
+        .expect("code is not a reserved problem field");
+    problem
+        .set_value("line", &line)
+        .expect("line is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn import_target_not_empty(tables: &[&str]) -> Error {
+    let mut problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+        .set_title("Import Target Not Empty")
+        .set_detail(format!(
+            "The database already has rows in: {}. Import with ?merge=replace to delete them first",
+            tables.join(", ")
+        ));
+    problem
+        .set_value("code", &"import_target_not_empty")
+        .expect("code is not a reserved problem field");
+    anyhow!(problem)
+}
+
+pub fn unprocessable_entity(errors: Vec<ValidationError>) -> Error {
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::UNPROCESSABLE_ENTITY)
+            .set_detail("Request body failed validation");
+    problem
+        .set_value("errors", &errors)
+        .expect("errors is not a reserved problem field");
+    anyhow!(problem)
+}
+
 pub fn from_anyhow(error: anyhow::Error) -> HttpApiProblem {
     let error = match error.downcast::<HttpApiProblem>() {
         Ok(problem) => return problem,
@@ -35,27 +612,48 @@ pub fn from_anyhow(error: anyhow::Error) -> HttpApiProblem {
 
     // TODO: should probably decentralize all this error handling to the places where they are relevant
     if let Some(sqlx_error) = error.downcast_ref::<sqlx::error::Error>() {
+        if is_connection_error(sqlx_error) {
+            error!(%sqlx_error, "database unavailable");
+            return database_unavailable();
+        }
         match sqlx_error {
             sqlx::error::Error::RowNotFound => {
                 return HttpApiProblem::with_title_and_type_from_status(StatusCode::NOT_FOUND)
             }
             sqlx::error::Error::Database(db_error) => {
                 let pg_error = db_error.downcast_ref::<sqlx::postgres::PgDatabaseError>();
-                error!(
-                    "Database error: {}. {}",
-                    pg_error.message(),
-                    pg_error.detail().unwrap_or("")
-                );
-                dbg!(&pg_error);
                 let code = pg_error.code();
-                dbg!(&code);
+                debug!(
+                    code,
+                    constraint = ?pg_error.constraint(),
+                    message = pg_error.message(),
+                    detail = ?pg_error
+                        .detail()
+                        .map(|detail| truncate(detail, MAX_ECHOED_MESSAGE_CHARS)),
+                    "database error"
+                );
+                if code == "57014" {
+                    // query_canceled, from a `statement_timeout` set by `set_statement_timeout`
+                    return HttpApiProblem::with_title_and_type_from_status(
+                        StatusCode::GATEWAY_TIMEOUT,
+                    )
+                    .set_detail("The request took too long to complete");
+                }
+                if code == "22003" {
+                    // numeric_value_out_of_range, e.g. a shop's gold overflowing a BIGINT
+                    return HttpApiProblem::with_title_and_type_from_status(
+                        StatusCode::BAD_REQUEST,
+                    )
+                    .set_detail("Gold, price, or amount is out of range");
+                }
                 if let Some(constraint) = pg_error.constraint() {
-                    dbg!(&constraint);
                     if code == "23503"
                         && (constraint == "shops_owner_id_fkey"
                             || constraint == "interior_ref_lists_owner_id_fkey"
                             || constraint == "merchandise_lists_owner_id_fkey"
-                            || constraint == "transactions_owner_id_fkey")
+                            || constraint == "transactions_owner_id_fkey"
+                            || constraint == "shop_bans_banned_owner_id_fkey"
+                            || constraint == "shop_requests_requester_owner_id_fkey")
                     {
                         // foreign_key_violation
                         return HttpApiProblem::with_title_and_type_from_status(
@@ -66,7 +664,9 @@ pub fn from_anyhow(error: anyhow::Error) -> HttpApiProblem {
                     } else if code == "23503"
                         && (constraint == "interior_ref_lists_shop_id_fkey"
                             || constraint == "merchandise_lists_shop_id_fkey"
-                            || constraint == "transactions_shop_id_fkey")
+                            || constraint == "transactions_shop_id_fkey"
+                            || constraint == "shop_bans_shop_id_fkey"
+                            || constraint == "shop_requests_shop_id_fkey")
                     {
                         // foreign_key_violation
                         return HttpApiProblem::with_title_and_type_from_status(
@@ -85,12 +685,34 @@ pub fn from_anyhow(error: anyhow::Error) -> HttpApiProblem {
                             StatusCode::BAD_REQUEST,
                         )
                         .set_detail("Duplicate owner with same name and Api-Key exists");
-                    } else if code == "23505" && constraint == "shops_unique_name_and_owner_id" {
+                    } else if code == "23505"
+                        && constraint == "shops_unique_normalized_name_and_owner_id"
+                    {
+                        // unique_violation, when two of the owner's shops were given the same
+                        // name at once. Handlers catch the usual collisions first, as `shop_exists`
+                        // with the other shop's id.
+                        let mut problem =
+                            HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+                                .set_title("Shop Already Exists")
+                                .set_detail(
+                                    "Owner already has a shop with that name (ignoring case)",
+                                );
+                        problem
+                            .set_value("code", &"shop_exists")
+                            .expect("code is not a reserved problem field");
+                        return problem;
+                    } else if code == "23505" && constraint == "shop_reviews_one_per_reviewer" {
                         // unique_violation
-                        return HttpApiProblem::with_title_and_type_from_status(
-                            StatusCode::BAD_REQUEST,
-                        )
-                        .set_detail("Owner already has a shop with that name");
+                        let mut problem =
+                            HttpApiProblem::with_title_and_type_from_status(StatusCode::CONFLICT)
+                                .set_title("Review Already Exists")
+                                .set_detail(
+                                    "You have already reviewed this shop. Use PATCH to edit it",
+                                );
+                        problem
+                            .set_value("code", &"review_exists")
+                            .expect("code is not a reserved problem field");
+                        return problem;
                     } else if code == "23505" && constraint == "interior_ref_lists_shop_id_key" {
                         // unique_violation
                         return HttpApiProblem::with_title_and_type_from_status(
@@ -108,22 +730,46 @@ pub fn from_anyhow(error: anyhow::Error) -> HttpApiProblem {
                             StatusCode::BAD_REQUEST,
                         )
                         .set_detail("Quantity of merchandise must be greater than zero");
+                    } else if code == "23514" && constraint == "shop_bans_reason_check" {
+                        return HttpApiProblem::with_title_and_type_from_status(
+                            StatusCode::BAD_REQUEST,
+                        )
+                        .set_detail("Ban reason is too long");
+                    } else if code == "23514"
+                        && (constraint == "shop_reviews_rating_range"
+                            || constraint == "shop_reviews_comment_length")
+                    {
+                        return HttpApiProblem::with_title_and_type_from_status(
+                            StatusCode::BAD_REQUEST,
+                        )
+                        .set_detail("Review rating or comment is out of range");
+                    } else if code == "23514" && constraint == "shop_gold_gt_zero" {
+                        // check_violation of the `gold >= 0` check the shops table has always had
+                        let mut problem = HttpApiProblem::with_title_and_type_from_status(
+                            StatusCode::BAD_REQUEST,
+                        )
+                        .set_title("Insufficient Shop Gold")
+                        .set_detail("Shop does not have enough gold");
+                        problem
+                            .set_value("code", &"insufficient_shop_gold")
+                            .expect("code is not a reserved problem field");
+                        return problem;
+                    } else if code == "23514" && constraint == "shops_description_length" {
+                        return HttpApiProblem::with_title_and_type_from_status(
+                            StatusCode::BAD_REQUEST,
+                        )
+                        .set_detail("Shop description is too long");
+                    } else if code == "23514"
+                        && (constraint == "transactions_quantity_gt_zero"
+                            || constraint == "transactions_price_gte_zero"
+                            || constraint == "transactions_amount_gte_zero")
+                    {
+                        return HttpApiProblem::with_title_and_type_from_status(
+                            StatusCode::BAD_REQUEST,
+                        )
+                        .set_detail("Transaction quantity, price, or amount is out of range");
                     }
                 }
-                // Might possibly link sensitive info:
-                // let mut problem = HttpApiProblem::with_title_and_type_from_status(
-                //     StatusCode::INTERNAL_SERVER_ERROR,
-                // )
-                // .set_title("Database Error")
-                // .set_detail(format!(
-                //     "{}. {}",
-                //     pg_error.message(),
-                //     pg_error.detail().unwrap_or("")
-                // ));
-                // problem
-                //     .set_value("code".to_string(), &code.to_string())
-                //     .unwrap();
-                // return problem;
             }
             _ => {}
         }
@@ -132,7 +778,7 @@ pub fn from_anyhow(error: anyhow::Error) -> HttpApiProblem {
     if let Some(json_error) = error.downcast_ref::<serde_json::Error>() {
         return HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
             .set_title("Json Body Deserialization Error")
-            .set_detail(format!("{}", json_error));
+            .set_detail(truncate(&json_error.to_string(), MAX_ECHOED_MESSAGE_CHARS));
     }
 
     if let Some(bincode_error) = error.downcast_ref::<bincode::Error>() {
@@ -144,12 +790,24 @@ pub fn from_anyhow(error: anyhow::Error) -> HttpApiProblem {
             }
             error => HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST)
                 .set_title("Bincode Body Deserialization Error")
-                .set_detail(format!("{}", error)),
+                .set_detail(truncate(&error.to_string(), MAX_ECHOED_MESSAGE_CHARS)),
         };
     }
 
-    error!("Recovering unhandled error: {:?}", error);
-    HttpApiProblem::with_title_and_type_from_status(StatusCode::INTERNAL_SERVER_ERROR)
+    // Never echo the underlying error to the client since it may contain database internals. The
+    // error id lets a user's report be matched up with this log line.
+    let error_id = Uuid::new_v4();
+    error!(
+        %error_id,
+        error = %truncate(&format!("{:?}", error), MAX_ECHOED_MESSAGE_CHARS),
+        "recovering unhandled error"
+    );
+    let mut problem =
+        HttpApiProblem::with_title_and_type_from_status(StatusCode::INTERNAL_SERVER_ERROR);
+    problem
+        .set_value("error_id", &error_id)
+        .expect("error_id is not a reserved problem field");
+    problem
 }
 
 pub async fn unpack_problem(rejection: Rejection) -> Result<impl Reply, Rejection> {
@@ -164,21 +822,54 @@ pub async fn unpack_problem(rejection: Rejection) -> Result<impl Reply, Rejectio
             http_api_problem::PROBLEM_JSON_MEDIA_TYPE,
         );
 
-        return Ok(reply);
+        return Ok(reply.into_response());
     }
 
     if let Some(problem) = rejection.find::<HttpApiProblem>() {
         let code = problem.status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let retry_after = problem
+            .json_value("retry_after")
+            .and_then(|value| value.as_u64());
 
-        let reply = warp::reply::json(problem);
+        // Unhandled errors already have an error id from `from_anyhow`, but 5xx problems built
+        // directly by handlers do not. Planned unavailability (with a `retry_after`) is not an
+        // error.
+        let mut problem = problem.clone();
+        if code.is_server_error()
+            && retry_after.is_none()
+            && problem.json_value("error_id").is_none()
+        {
+            let error_id = Uuid::new_v4();
+            error!(
+                %error_id,
+                status = code.as_u16(),
+                title = %problem.title,
+                detail = ?problem
+                    .detail
+                    .as_deref()
+                    .map(|detail| truncate(detail, MAX_ECHOED_MESSAGE_CHARS)),
+                "responding with server error"
+            );
+            problem
+                .set_value("error_id", &error_id)
+                .expect("error_id is not a reserved problem field");
+        }
+
+        let reply = warp::reply::json(&problem);
         let reply = warp::reply::with_status(reply, code);
         let reply = warp::reply::with_header(
             reply,
             warp::http::header::CONTENT_TYPE,
             http_api_problem::PROBLEM_JSON_MEDIA_TYPE,
         );
+        let mut response = reply.into_response();
+        if let Some(retry_after) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
 
-        return Ok(reply);
+        return Ok(response);
     }
 
     Err(rejection)
@@ -187,3 +878,100 @@ pub async fn unpack_problem(rejection: Rejection) -> Result<impl Reply, Rejectio
 pub fn reject_anyhow(error: anyhow::Error) -> Rejection {
     reject::custom(from_anyhow(error))
 }
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use http::StatusCode;
+    use http_api_problem::HttpApiProblem;
+    use serde_json::Value;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use warp::reject;
+    use warp::Reply;
+
+    use super::{maintenance, reject_anyhow, truncate, unpack_problem};
+    use crate::maintenance::MaintenanceMode;
+    use crate::test_support::TestEnv;
+
+    #[test]
+    fn truncated_values_are_marked_with_their_length() {
+        assert_eq!(truncate("Cabbage", 7), "Cabbage");
+        assert_eq!(truncate("Cabbage", 3), "Cab… (7 chars total)");
+        assert_eq!(truncate("", 0), "");
+    }
+
+    #[test]
+    fn values_are_cut_between_multibyte_characters() {
+        // Each of these is two or more bytes, so cutting by bytes would split one.
+        assert_eq!(truncate("Händler", 2), "Hä… (7 chars total)");
+        assert_eq!(truncate("Лавка", 3), "Лав… (5 chars total)");
+        assert_eq!(truncate("🧀🧀🧀", 1), "🧀… (3 chars total)");
+        assert_eq!(truncate("🧀🧀🧀", 3), "🧀🧀🧀");
+    }
+
+    async fn unpack(rejection: warp::Rejection) -> (StatusCode, String) {
+        let response = unpack_problem(rejection)
+            .await
+            .expect("problems are unpacked")
+            .into_response();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn error_id(body: &str) -> Option<Uuid> {
+        let problem: Value = serde_json::from_str(body).unwrap();
+        problem["error_id"]
+            .as_str()
+            .map(|error_id| error_id.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn unhandled_errors_get_an_error_id_but_not_their_message() {
+        let message = "password authentication failed for user \"bazaar\"";
+        let (status, body) = unpack(reject_anyhow(anyhow!(message))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body.contains("password"), "{}", body);
+        let first_id = error_id(&body).expect("error_id");
+
+        let (_, body) = unpack(reject_anyhow(anyhow!(message))).await;
+        assert_ne!(error_id(&body).expect("error_id"), first_id);
+    }
+
+    #[tokio::test]
+    async fn unhandled_database_errors_are_not_echoed() {
+        let test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        let error = sqlx::query("SELECT secret_column FROM no_such_table")
+            .execute(&test.env.db)
+            .await
+            .unwrap_err();
+        let (status, body) = unpack(reject_anyhow(anyhow!(error))).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!body.contains("no_such_table"), "{}", body);
+        assert!(!body.contains("secret_column"), "{}", body);
+        assert!(error_id(&body).is_some(), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn server_errors_built_by_handlers_get_an_error_id_unless_planned() {
+        let problem =
+            HttpApiProblem::with_title_and_type_from_status(StatusCode::SERVICE_UNAVAILABLE)
+                .set_detail("Something went wrong");
+        let (status, body) = unpack(reject::custom(problem)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(error_id(&body).is_some(), "{}", body);
+
+        let error = maintenance(MaintenanceMode::ReadOnly, Duration::from_secs(60));
+        let (status, body) = unpack(reject_anyhow(error)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error_id(&body), None);
+
+        let problem = HttpApiProblem::with_title_and_type_from_status(StatusCode::BAD_REQUEST);
+        let (_, body) = unpack(reject::custom(problem)).await;
+        assert_eq!(error_id(&body), None);
+    }
+}
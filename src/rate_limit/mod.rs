@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Windows that have ended are only swept out once this many addresses are being tracked.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Window {
+    started_at: Instant,
+    count: u32,
+}
+
+/// Allows each IP address `limit` requests per fixed window of time. Requests whose address
+/// isn't known share a single allowance.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    windows: Mutex<HashMap<Option<IpAddr>, Window>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn per_minute(limit: u32) -> Self {
+        Self::new(limit, Duration::from_secs(60))
+    }
+
+    /// Counts a request from `ip`. Fails with how long until the address's window ends if it has
+    /// already used up its allowance.
+    pub fn check(&self, ip: Option<IpAddr>) -> Result<(), Duration> {
+        self.check_at(ip, Instant::now())
+    }
+
+    fn check_at(&self, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() >= PRUNE_THRESHOLD {
+            let length = self.window;
+            windows.retain(|_, window| now.duration_since(window.started_at) < length);
+        }
+        let window = windows.entry(ip).or_insert(Window {
+            started_at: now,
+            count: 0,
+        });
+        let elapsed = now.duration_since(window.started_at);
+        if elapsed >= self.window {
+            *window = Window {
+                started_at: now,
+                count: 0,
+            };
+        } else if window.count >= self.limit {
+            return Err(self.window - elapsed);
+        }
+        window.count += 1;
+        Ok(())
+    }
+}
+
+/// Who a request's cost is charged to: the owner when the api key is valid, otherwise the IP
+/// address (if known), so that made-up api keys don't each get a fresh allowance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Requester {
+    Owner(i32),
+    Ip(Option<IpAddr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// A token bucket per key, holding up to `capacity` tokens and refilled at `capacity` tokens per
+/// `period`. Each request takes a token, so a key can burst through its whole allowance and is
+/// then held to the refill rate.
+#[derive(Debug)]
+pub struct TokenBuckets<K> {
+    capacity: f64,
+    tokens_per_sec: f64,
+    buckets: Mutex<HashMap<K, Bucket>>,
+}
+
+impl<K> TokenBuckets<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        Self {
+            capacity: f64::from(capacity),
+            tokens_per_sec: f64::from(capacity) / period.as_secs_f64(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn per_minute(capacity: u32) -> Self {
+        Self::new(capacity, Duration::from_secs(60))
+    }
+
+    /// Takes a token from `key`'s bucket. Fails with how long until the bucket has one again if
+    /// it is empty.
+    pub fn take(&self, key: K) -> Result<(), Duration> {
+        self.take_at(key, Instant::now())
+    }
+
+    fn take_at(&self, key: K, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // A bucket that has refilled completely is the same as a missing one.
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+        }
+        let capacity = self.capacity;
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.tokens_per_sec,
+            ))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.tokens_per_sec).min(self.capacity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimiter, TokenBuckets};
+
+    #[test]
+    fn allowance_is_per_address_and_resets_with_the_window() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let other_ip = Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)));
+        let start = Instant::now();
+
+        assert!(limiter.check_at(ip, start).is_ok());
+        assert!(limiter.check_at(ip, start).is_ok());
+        assert_eq!(
+            limiter.check_at(ip, start + Duration::from_secs(15)),
+            Err(Duration::from_secs(45))
+        );
+        assert!(limiter.check_at(other_ip, start).is_ok());
+        assert!(limiter
+            .check_at(ip, start + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill_gradually() {
+        let buckets = TokenBuckets::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(buckets.take_at(1, start).is_ok());
+        assert!(buckets.take_at(1, start).is_ok());
+        let wait = buckets.take_at(1, start).unwrap_err();
+        assert_eq!(wait.as_secs_f64().round(), 30.0);
+        assert!(buckets.take_at(2, start).is_ok());
+        // One token back after half the period, but not two.
+        let later = start + Duration::from_secs(31);
+        assert!(buckets.take_at(1, later).is_ok());
+        assert!(buckets.take_at(1, later).is_err());
+    }
+}
@@ -0,0 +1,665 @@
+//! Journals writes that fail because the database is unreachable and replays them once it is back.
+//!
+//! Off unless `REPLAY_JOURNAL_ENABLED` and `REPLAY_JOURNAL_DIR` are set. Only authenticated writes
+//! that carry an `Idempotency-Key` are journaled: instead of the 503, the client gets a 202 with
+//! the replay's status URL (`GET /v1/replays/{id}`). While an owner has writes waiting to be
+//! replayed, their later writes are journaled behind them, so an owner's writes are applied in the
+//! order they were made.
+//!
+//! Each write is a JSON file in the journal directory, so writes survive a restart of the server.
+//! The journal is capped by `REPLAY_JOURNAL_MAX_ENTRIES` and `REPLAY_JOURNAL_MAX_BYTES`, and writes
+//! not replayed within `REPLAY_JOURNAL_MAX_AGE_SECS` expire instead.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use chrono::prelude::*;
+use http::header::{HeaderMap, CONTENT_LENGTH, LOCATION};
+use http::{Method, Request, StatusCode};
+use http_api_problem::HttpApiProblem;
+use hyper::body::{self, Body, Bytes};
+use hyper::service::Service;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sqlx::postgres::PgPool;
+use tracing::{info, warn};
+use url::Url;
+use uuid::Uuid;
+use warp::reply::{with_header, with_status, Response};
+use warp::{Rejection, Reply};
+
+use crate::config::ReplayJournalSettings;
+use crate::models::Owner;
+use crate::problem::{
+    from_anyhow, reject_anyhow, replay_journal_full, unauthorized_no_owner, unpack_problem,
+    DATABASE_UNAVAILABLE_CODE,
+};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Marks a request as a replay, so that it is applied even while its owner has writes waiting.
+pub const REPLAY_TOKEN_HEADER: &str = "x-replay-token";
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+// Credentials are looked up again when the write is replayed, and the rest describe the connection
+// the write was made on rather than the write.
+const UNJOURNALED_HEADERS: &[&str] = &[
+    "api-key",
+    "authorization",
+    "cookie",
+    "host",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "accept-encoding",
+    REPLAY_TOKEN_HEADER,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ReplayStatus {
+    Pending,
+    Applied {
+        completed_at: NaiveDateTime,
+        response_status: u16,
+    },
+    /// Replaying was refused, e.g. because the shop was changed by someone else in the meantime.
+    /// `problem` is the problem document the write got.
+    Failed {
+        completed_at: NaiveDateTime,
+        response_status: u16,
+        problem: serde_json::Value,
+    },
+    /// The database was not back within `REPLAY_JOURNAL_MAX_AGE_SECS`, so the write was dropped.
+    Expired {
+        completed_at: NaiveDateTime,
+    },
+}
+
+impl ReplayStatus {
+    fn completed_at(&self) -> Option<NaiveDateTime> {
+        match self {
+            ReplayStatus::Pending => None,
+            ReplayStatus::Applied { completed_at, .. }
+            | ReplayStatus::Failed { completed_at, .. }
+            | ReplayStatus::Expired { completed_at } => Some(*completed_at),
+        }
+    }
+}
+
+/// A journaled write, as shown to the owner who made it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Replay {
+    pub id: Uuid,
+    pub owner_id: i32,
+    pub method: String,
+    pub path: String,
+    pub journaled_at: NaiveDateTime,
+    #[serde(flatten)]
+    pub status: ReplayStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    id: Uuid,
+    seq: u64,
+    owner_id: i32,
+    idempotency_key: String,
+    method: String,
+    path: String,
+    headers: BTreeMap<String, String>,
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    body: Bytes,
+    journaled_at: NaiveDateTime,
+    #[serde(flatten)]
+    status: ReplayStatus,
+}
+
+impl Entry {
+    fn is_pending(&self) -> bool {
+        self.status == ReplayStatus::Pending
+    }
+
+    fn replay(&self) -> Replay {
+        Replay {
+            id: self.id,
+            owner_id: self.owner_id,
+            method: self.method.clone(),
+            path: self.path.clone(),
+            journaled_at: self.journaled_at,
+            status: self.status.clone(),
+        }
+    }
+}
+
+fn serialize_base64<S: Serializer>(bytes: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&base64::encode(bytes))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    base64::decode(&encoded)
+        .map(Bytes::from)
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: BTreeMap<u64, Entry>,
+    next_seq: u64,
+    // Raw request bodies by owner and idempotency key, kept until the response shows whether the
+    // write needs journaling (see `respond`).
+    stashed_bodies: HashMap<(i32, String), Bytes>,
+}
+
+#[derive(Debug)]
+pub struct ReplayJournal {
+    settings: Option<ReplayJournalSettings>,
+    // Only replays from this process carry it, so clients cannot skip the queue by sending it.
+    replay_token: String,
+    state: Mutex<State>,
+}
+
+impl ReplayJournal {
+    /// Loads the writes left in the journal directory by an earlier run. Journaling is off when
+    /// `settings` is `None`.
+    pub fn open(settings: Option<ReplayJournalSettings>) -> Result<Self> {
+        let mut state = State::default();
+        if let Some(settings) = &settings {
+            fs::create_dir_all(&settings.dir).with_context(|| {
+                format!(
+                    "failed to create replay journal directory {}",
+                    settings.dir.display()
+                )
+            })?;
+            for dir_entry in fs::read_dir(&settings.dir)? {
+                let path = dir_entry?.path();
+                if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                    continue;
+                }
+                match fs::read(&path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|bytes| Ok(serde_json::from_slice::<Entry>(&bytes)?))
+                {
+                    Ok(entry) => {
+                        state.next_seq = state.next_seq.max(entry.seq + 1);
+                        state.entries.insert(entry.seq, entry);
+                    }
+                    Err(error) => {
+                        warn!(path = %path.display(), %error, "skipping unreadable journaled write")
+                    }
+                }
+            }
+            let pending = state
+                .entries
+                .values()
+                .filter(|entry| entry.is_pending())
+                .count();
+            info!(pending, "opened replay journal");
+        }
+        Ok(Self {
+            settings,
+            replay_token: Uuid::new_v4().to_simple().to_string(),
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.is_some()
+    }
+
+    /// Whether the request is a replay sent by this journal.
+    pub fn is_replay(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get(REPLAY_TOKEN_HEADER)
+            .is_some_and(|token| token.as_bytes() == self.replay_token.as_bytes())
+    }
+
+    pub fn has_pending(&self, owner_id: i32) -> bool {
+        self.state()
+            .entries
+            .values()
+            .any(|entry| entry.owner_id == owner_id && entry.is_pending())
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Replay> {
+        self.state()
+            .entries
+            .values()
+            .find(|entry| entry.id == id)
+            .map(Entry::replay)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("replay journal lock is not poisoned")
+    }
+
+    fn settings(&self) -> &ReplayJournalSettings {
+        self.settings
+            .as_ref()
+            .expect("only enabled journals have replay contexts")
+    }
+
+    fn entry_path(&self, seq: u64) -> PathBuf {
+        self.settings().dir.join(format!("{:020}.json", seq))
+    }
+
+    // Writes to a temporary file first so that a crash never leaves half an entry behind.
+    fn write_entry(&self, entry: &Entry) -> Result<()> {
+        let path = self.entry_path(entry.seq);
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(entry)?)?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    fn remove_entry(&self, seq: u64) {
+        let path = self.entry_path(seq);
+        if let Err(error) = fs::remove_file(&path) {
+            warn!(path = %path.display(), %error, "failed to remove journaled write");
+        }
+    }
+
+    fn append(&self, context: &ReplayContext, body: Bytes) -> Result<Replay> {
+        let mut state = self.state();
+        // A client retrying a write that is already journaled gets the same replay back.
+        if let Some(entry) = state.entries.values().find(|entry| {
+            entry.owner_id == context.owner_id && entry.idempotency_key == context.idempotency_key
+        }) {
+            return Ok(entry.replay());
+        }
+        let settings = self.settings();
+        let (pending, pending_bytes) = state
+            .entries
+            .values()
+            .filter(|entry| entry.is_pending())
+            .fold((0, 0), |(count, bytes), entry| {
+                (count + 1, bytes + entry.body.len() as u64)
+            });
+        if pending >= settings.max_entries || pending_bytes + body.len() as u64 > settings.max_bytes
+        {
+            warn!(pending, pending_bytes, "replay journal is full");
+            return Err(replay_journal_full());
+        }
+        let entry = Entry {
+            id: Uuid::new_v4(),
+            seq: state.next_seq,
+            owner_id: context.owner_id,
+            idempotency_key: context.idempotency_key.clone(),
+            method: context.method.to_string(),
+            path: context.path.clone(),
+            headers: context.headers.clone(),
+            body,
+            journaled_at: Utc::now().naive_utc(),
+            status: ReplayStatus::Pending,
+        };
+        self.write_entry(&entry)?;
+        info!(
+            replay_id = %entry.id,
+            owner_id = entry.owner_id,
+            method = %entry.method,
+            path = %entry.path,
+            "journaled write"
+        );
+        state.next_seq += 1;
+        let replay = entry.replay();
+        state.entries.insert(entry.seq, entry);
+        Ok(replay)
+    }
+
+    fn pending(&self) -> Vec<Entry> {
+        self.state()
+            .entries
+            .values()
+            .filter(|entry| entry.is_pending())
+            .cloned()
+            .collect()
+    }
+
+    fn complete(&self, seq: u64, status: ReplayStatus) -> Result<()> {
+        let mut state = self.state();
+        if let Some(entry) = state.entries.get_mut(&seq) {
+            entry.status = status;
+            // The body is no longer needed and doesn't count toward `max_bytes`.
+            entry.body = Bytes::new();
+            self.write_entry(entry)?;
+        }
+        Ok(())
+    }
+
+    /// Gives up on writes that have waited longer than `max_age`, and forgets the outcome of
+    /// replays that completed longer than `max_age` ago.
+    pub fn expire(&self, now: NaiveDateTime) {
+        let settings = match &self.settings {
+            Some(settings) => settings,
+            None => return,
+        };
+        let cutoff = now
+            - chrono::Duration::from_std(settings.max_age).expect("max age is bounded by config");
+        let mut state = self.state();
+        let mut forgotten = vec![];
+        for entry in state.entries.values_mut() {
+            match entry.status.completed_at() {
+                None if entry.journaled_at < cutoff => {
+                    warn!(replay_id = %entry.id, owner_id = entry.owner_id, "journaled write expired");
+                    entry.status = ReplayStatus::Expired { completed_at: now };
+                    entry.body = Bytes::new();
+                    if let Err(error) = self.write_entry(entry) {
+                        warn!(replay_id = %entry.id, %error, "failed to write expired replay");
+                    }
+                }
+                Some(completed_at) if completed_at < cutoff => forgotten.push(entry.seq),
+                _ => {}
+            }
+        }
+        for seq in forgotten {
+            state.entries.remove(&seq);
+            self.remove_entry(seq);
+        }
+    }
+}
+
+/// Whether writes to `path` are journaled. Admin endpoints are left out, and so are replays
+/// themselves.
+pub fn is_journaled(method: &Method, path: &str) -> bool {
+    [Method::POST, Method::PUT, Method::PATCH, Method::DELETE].contains(method)
+        && path.starts_with("/v1/")
+        && !path.starts_with("/v1/admin/")
+        && !path.starts_with("/v1/replays/")
+}
+
+/// The request's `Idempotency-Key`, if it has a valid one: 1 to 255 visible ASCII characters.
+pub fn idempotency_key(headers: &HeaderMap) -> Option<String> {
+    let key = headers.get(IDEMPOTENCY_KEY_HEADER)?.to_str().ok()?;
+    if key.is_empty()
+        || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH
+        || !key.bytes().all(|byte| byte.is_ascii_graphic())
+    {
+        return None;
+    }
+    Some(key.to_string())
+}
+
+/// A write that can be journaled if the database turns out to be unavailable.
+#[derive(Debug, Clone)]
+pub struct ReplayContext {
+    pub journal: Arc<ReplayJournal>,
+    pub owner_id: i32,
+    pub idempotency_key: String,
+    pub method: Method,
+    /// The path with its query string.
+    pub path: String,
+    pub headers: BTreeMap<String, String>,
+    pub api_url: Url,
+}
+
+impl ReplayContext {
+    pub fn new(
+        journal: Arc<ReplayJournal>,
+        owner_id: i32,
+        idempotency_key: String,
+        method: Method,
+        path: String,
+        headers: &HeaderMap,
+        api_url: Url,
+    ) -> Self {
+        Self {
+            journal,
+            owner_id,
+            idempotency_key,
+            method,
+            path,
+            headers: headers
+                .iter()
+                .filter(|(name, _)| !UNJOURNALED_HEADERS.contains(&name.as_str()))
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            api_url,
+        }
+    }
+
+    fn stash_key(&self) -> (i32, String) {
+        (self.owner_id, self.idempotency_key.clone())
+    }
+
+    /// Keeps the raw request body until `respond` knows whether the write needs journaling.
+    pub fn stash_body(&self, body: &Bytes) {
+        self.journal
+            .state()
+            .stashed_bodies
+            .insert(self.stash_key(), body.clone());
+    }
+
+    fn take_body(&self) -> Option<Bytes> {
+        self.journal
+            .state()
+            .stashed_bodies
+            .remove(&self.stash_key())
+    }
+
+    /// Journals the write and responds with a 202 pointing at its status.
+    pub fn journal(&self, body: Bytes) -> Result<Response> {
+        let replay = self.journal.append(self, body)?;
+        let url = self.api_url.join(&format!("replays/{}", replay.id))?;
+        let reply = warp::reply::json(&replay);
+        let reply = with_status(reply, StatusCode::ACCEPTED);
+        let reply = with_header(reply, LOCATION, url.as_str());
+        Ok(reply.into_response())
+    }
+}
+
+/// Journals the write instead of returning its 503 when the database was unavailable. Any other
+/// response is passed through untouched.
+pub async fn respond(
+    context: Option<ReplayContext>,
+    reply: impl Reply,
+) -> Result<Response, Rejection> {
+    let response = reply.into_response();
+    let context = match context {
+        Some(context) => context,
+        None => return Ok(response),
+    };
+    let body = match context.take_body() {
+        Some(body) if response.status() == StatusCode::SERVICE_UNAVAILABLE => body,
+        _ => return Ok(response),
+    };
+    let (parts, response_body) = response.into_parts();
+    let response_bytes = match body::to_bytes(response_body).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            warn!(owner_id = context.owner_id, %error, "failed to buffer response body for replay");
+            Bytes::new()
+        }
+    };
+    let is_outage = serde_json::from_slice::<serde_json::Value>(&response_bytes)
+        .is_ok_and(|problem| problem["code"] == DATABASE_UNAVAILABLE_CODE);
+    if !is_outage {
+        return Ok(Response::from_parts(parts, Body::from(response_bytes)));
+    }
+    match context.journal(body) {
+        Ok(response) => Ok(response),
+        Err(error) if error.downcast_ref::<HttpApiProblem>().is_some() => {
+            unpack_problem(reject_anyhow(error))
+                .await
+                .map(Reply::into_response)
+        }
+        Err(error) => {
+            warn!(owner_id = context.owner_id, %error, "failed to journal write");
+            Ok(Response::from_parts(parts, Body::from(response_bytes)))
+        }
+    }
+}
+
+/// Replays the pending writes through `service` in the order they were journaled, and returns how
+/// many were applied or failed. Stops at the first write that gets a 503, so that no write is
+/// applied ahead of an earlier one from the same owner.
+pub async fn replay_pending<S>(
+    journal: &ReplayJournal,
+    db: &PgPool,
+    service: &mut S,
+) -> Result<usize>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible>,
+{
+    let mut replayed = 0;
+    for entry in journal.pending() {
+        // Replayed with the owner's current api key, in case it was rotated in the meantime.
+        let api_key = match Owner::get(db, entry.owner_id).await {
+            Ok(owner) => owner.api_key,
+            Err(error) => match error.downcast_ref::<sqlx::Error>() {
+                Some(sqlx::Error::RowNotFound) => {
+                    let problem = from_anyhow(unauthorized_no_owner());
+                    journal.complete(
+                        entry.seq,
+                        ReplayStatus::Failed {
+                            completed_at: Utc::now().naive_utc(),
+                            response_status: problem
+                                .status
+                                .unwrap_or(StatusCode::UNAUTHORIZED)
+                                .as_u16(),
+                            problem: serde_json::to_value(&problem)?,
+                        },
+                    )?;
+                    replayed += 1;
+                    continue;
+                }
+                _ => return Err(error),
+            },
+        };
+        let mut request = Request::builder()
+            .method(entry.method.as_str())
+            .uri(entry.path.as_str());
+        for (name, value) in &entry.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+        let request = request
+            .header("api-key", api_key.to_string())
+            .header(REPLAY_TOKEN_HEADER, journal.replay_token.as_str())
+            .header(CONTENT_LENGTH, entry.body.len())
+            .body(Body::from(entry.body.clone()))?;
+        futures::future::poll_fn(|cx| service.poll_ready(cx))
+            .await
+            .unwrap_or_else(|never| match never {});
+        let response = service
+            .call(request)
+            .await
+            .unwrap_or_else(|never| match never {});
+        let status = response.status();
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            break;
+        }
+        let completed_at = Utc::now().naive_utc();
+        let status = if status.is_success() || status.is_redirection() {
+            ReplayStatus::Applied {
+                completed_at,
+                response_status: status.as_u16(),
+            }
+        } else {
+            let bytes = body::to_bytes(response.into_body()).await?;
+            ReplayStatus::Failed {
+                completed_at,
+                response_status: status.as_u16(),
+                problem: serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null),
+            }
+        };
+        info!(replay_id = %entry.id, owner_id = entry.owner_id, ?status, "replayed write");
+        journal.complete(entry.seq, status)?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use http::header::HeaderMap;
+    use http::Method;
+    use hyper::body::Bytes;
+    use url::Url;
+    use uuid::Uuid;
+
+    use super::{ReplayContext, ReplayJournal, ReplayStatus};
+    use crate::config::ReplayJournalSettings;
+
+    #[test]
+    fn journal_survives_reopening_and_is_capped() {
+        let dir = std::env::temp_dir().join(format!("replays_{}", Uuid::new_v4().to_simple()));
+        let settings = ReplayJournalSettings {
+            dir: dir.clone(),
+            max_bytes: 10,
+            max_entries: 2,
+            max_age: Duration::from_secs(60),
+        };
+        let journal = std::sync::Arc::new(ReplayJournal::open(Some(settings.clone())).unwrap());
+        let context = |owner_id: i32, key: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("api-key", "secret".parse().unwrap());
+            headers.insert("content-type", "application/json".parse().unwrap());
+            ReplayContext::new(
+                journal.clone(),
+                owner_id,
+                key.to_string(),
+                Method::PATCH,
+                "/v1/shops/1".to_string(),
+                &headers,
+                Url::parse("http://localhost/v1/").unwrap(),
+            )
+        };
+
+        let first = journal
+            .append(&context(1, "a"), Bytes::from_static(b"12345"))
+            .unwrap();
+        // Retrying with the same key doesn't journal the write twice.
+        let retried = journal
+            .append(&context(1, "a"), Bytes::from_static(b"12345"))
+            .unwrap();
+        assert_eq!(retried.id, first.id);
+        // Over `max_bytes`.
+        assert!(journal
+            .append(&context(2, "b"), Bytes::from_static(b"123456"))
+            .is_err());
+        journal
+            .append(&context(2, "b"), Bytes::from_static(b"12345"))
+            .unwrap();
+        // Over `max_entries`.
+        assert!(journal.append(&context(3, "c"), Bytes::new()).is_err());
+        assert!(journal.has_pending(1));
+        assert!(!journal.has_pending(3));
+
+        let reopened = ReplayJournal::open(Some(settings)).unwrap();
+        let pending = reopened.pending();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].id, first.id);
+        assert_eq!(pending[0].body, Bytes::from_static(b"12345"));
+        assert_eq!(pending[0].headers.get("api-key"), None);
+        assert_eq!(
+            pending[0].headers.get("content-type").map(String::as_str),
+            Some("application/json")
+        );
+
+        // Expired writes stop counting toward the caps, and are forgotten after another `max_age`.
+        let later = Utc::now().naive_utc() + chrono::Duration::seconds(61);
+        reopened.expire(later);
+        assert!(matches!(
+            reopened.get(first.id).unwrap().status,
+            ReplayStatus::Expired { .. }
+        ));
+        assert!(!reopened.has_pending(1));
+        reopened.expire(later + chrono::Duration::seconds(61));
+        assert!(reopened.get(first.id).is_none());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
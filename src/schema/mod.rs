@@ -0,0 +1,272 @@
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use tracing::{error, info, instrument};
+
+// Every table and column the models query. The query! macros verified these at compile time, but
+// only against whatever database (or sqlx-data.json) was used for the build. The database the server
+// actually connects to at runtime may differ, e.g. after a bad deploy.
+//
+// Archives (see `archive`) hold every table listed here, in this order, so a table must come after
+// every table it references.
+pub const EXPECTED_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "owners",
+        &[
+            "id",
+            "name",
+            "api_key",
+            "ip_address",
+            "mod_version",
+            "created_at",
+            "updated_at",
+            "display_name",
+            "bio",
+            "avatar_url",
+        ],
+    ),
+    (
+        "shops",
+        &[
+            "id",
+            "name",
+            "owner_id",
+            "description",
+            "gold",
+            "shop_type",
+            "vendor_keywords",
+            "vendor_keywords_exclude",
+            "created_at",
+            "updated_at",
+            "last_activity_at",
+            "tags",
+            "private_notes",
+            "visits_count",
+            "normalized_name",
+            "review_count",
+            "average_rating",
+        ],
+    ),
+    (
+        "shop_translations",
+        &[
+            "shop_id",
+            "locale",
+            "name",
+            "description",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+    (
+        "interior_ref_lists",
+        &[
+            "id",
+            "shop_id",
+            "owner_id",
+            "ref_list",
+            "shelves",
+            "created_at",
+            "updated_at",
+            "revision",
+        ],
+    ),
+    (
+        "merchandise_lists",
+        &[
+            "id",
+            "shop_id",
+            "owner_id",
+            "form_list",
+            "created_at",
+            "updated_at",
+            "revision",
+        ],
+    ),
+    (
+        "transactions",
+        &[
+            "id",
+            "shop_id",
+            "owner_id",
+            "mod_name",
+            "local_form_id",
+            "name",
+            "form_kind",
+            "is_food",
+            "price",
+            "is_sell",
+            "quantity",
+            "amount",
+            "keywords",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+    (
+        "merchandise_changes",
+        &[
+            "id",
+            "shop_id",
+            "mod_name",
+            "local_form_id",
+            "quantity_delta",
+            "reason",
+            "created_at",
+        ],
+    ),
+    (
+        "shop_gold_history",
+        &["shop_id", "date", "gold", "created_at"],
+    ),
+    ("shop_visits", &["shop_id", "owner_id", "day"]),
+    (
+        "shop_bans",
+        &["shop_id", "banned_owner_id", "reason", "created_at"],
+    ),
+    (
+        "shop_requests",
+        &[
+            "id",
+            "shop_id",
+            "requester_owner_id",
+            "mod_name",
+            "local_form_id",
+            "name",
+            "quantity",
+            "status",
+            "fulfillable",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+    (
+        "shop_reviews",
+        &[
+            "id",
+            "shop_id",
+            "reviewer_owner_id",
+            "rating",
+            "comment",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+    (
+        "owner_request_usage",
+        &["owner_id", "period_start", "reads", "writes", "updated_at"],
+    ),
+    (
+        "shop_notification_settings",
+        &[
+            "shop_id",
+            "on_sale",
+            "on_out_of_stock",
+            "on_low_stock_threshold",
+            "created_at",
+            "updated_at",
+        ],
+    ),
+    (
+        "owner_shop_templates",
+        &["owner_id", "template", "created_at", "updated_at"],
+    ),
+];
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SchemaStatus {
+    #[serde(skip)]
+    pub migration_version: Option<i64>,
+    pub ok: bool,
+    // Logged by `check`, but left out of `/v1/status`.
+    #[serde(skip)]
+    pub errors: Vec<String>,
+}
+
+#[instrument(level = "debug", skip(db))]
+pub async fn check(db: &PgPool) -> SchemaStatus {
+    let mut errors = vec![];
+
+    let migration_version = match sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MAX(version) FROM _sqlx_migrations WHERE success",
+    )
+    .fetch_one(db)
+    .await
+    {
+        Ok(version) => version,
+        Err(error) => {
+            errors.push(format!("could not read migration version: {}", error));
+            None
+        }
+    };
+
+    // Preparing a zero-row select per table is enough to make Postgres resolve every column.
+    for (table, columns) in EXPECTED_COLUMNS {
+        let query = format!("SELECT {} FROM {} LIMIT 0", columns.join(", "), table);
+        if let Err(error) = sqlx::query(&query).execute(db).await {
+            errors.push(format!("{}: {}", table, error));
+        }
+    }
+
+    let status = SchemaStatus {
+        migration_version,
+        ok: errors.is_empty(),
+        errors,
+    };
+    if status.ok {
+        info!(
+            migration_version = ?status.migration_version,
+            "database schema check passed"
+        );
+    } else {
+        error!(
+            migration_version = ?status.migration_version,
+            errors = ?status.errors,
+            "database schema check failed, serving 503 for all requests except /v1/status"
+        );
+    }
+    status
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use sqlx::Executor;
+    use warp::http::StatusCode;
+
+    use super::check;
+    use crate::test_support::{assert_problem, json_body, request, TestEnv};
+
+    #[tokio::test]
+    async fn mismatches_are_logged_but_not_shown_to_clients() {
+        let mut test = match TestEnv::new().await {
+            Some(test) => test,
+            None => return,
+        };
+        test.env
+            .db
+            .execute("ALTER TABLE shop_reviews DROP COLUMN comment")
+            .await
+            .unwrap();
+        let status = check(&test.env.db).await;
+        assert!(!status.ok);
+        assert_eq!(status.errors.len(), 1);
+        assert!(status.errors[0].starts_with("shop_reviews: "));
+        assert!(status.errors[0].contains("comment"));
+        test.env.schema_status = Arc::new(status);
+
+        let response = test.send(request("GET", "/v1/shops", None)).await;
+        let problem = assert_problem(&response, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(problem["title"], "Database Schema Mismatch");
+        assert!(problem.get("errors").is_none());
+        let body = String::from_utf8_lossy(response.body());
+        assert!(!body.contains("shop_reviews") && !body.contains("comment"));
+
+        let response = test.send(request("GET", "/v1/status", None)).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let status = json_body(&response);
+        assert_eq!(status["status"], "degraded");
+        assert_eq!(status["schema"]["ok"], false);
+        assert!(status["schema"].get("errors").is_none());
+    }
+}
@@ -0,0 +1,277 @@
+//! Background work spawned while handling requests, e.g. caching a response after a miss or
+//! posting an anomaly to the webhook.
+//!
+//! Tasks go through `TaskSpawner::spawn` rather than `tokio::spawn`, which caps how many tasks of
+//! each class run at once, logs a panicking task in the span of the request that spawned it
+//! instead of losing the panic, and counts tasks for `GET /v1/status/metrics`. On shutdown `main`
+//! waits for running tasks with `drain` before exiting.
+//!
+//! The loops in `jobs` are not tasks: they never finish, so there is nothing to cap or drain. Neither
+//! is the writer of a streamed export, which is part of its response rather than background work.
+
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::FutureExt;
+use serde::Serialize;
+use tracing::{error, warn, Span};
+use tracing_futures::Instrument;
+
+use crate::caches::panic_message;
+
+/// How long open connections get to finish their requests after a shutdown signal, and then how
+/// long `main` waits for running tasks to finish.
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskClass {
+    /// Putting a freshly loaded value in a cache after a miss.
+    CacheFill,
+    /// Posting to an external webhook, e.g. `ANOMALY_WEBHOOK_URL`.
+    Webhook,
+}
+
+impl TaskClass {
+    pub const ALL: [TaskClass; 2] = [TaskClass::CacheFill, TaskClass::Webhook];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    /// How many tasks of the class may run at once. Cache fills only hold a cache lock briefly,
+    /// but a webhook that stops answering keeps its tasks around until they time out.
+    pub fn default_limit(self) -> usize {
+        match self {
+            TaskClass::CacheFill => 1024,
+            TaskClass::Webhook => 8,
+        }
+    }
+}
+
+impl fmt::Display for TaskClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskClass::CacheFill => write!(f, "cache_fill"),
+            TaskClass::Webhook => write!(f, "webhook"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClassCounters {
+    limit: usize,
+    active: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    classes: Vec<ClassCounters>,
+    draining: AtomicBool,
+}
+
+/// Counts since the server started, served by `GET /v1/status/metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TaskClassStats {
+    pub class: TaskClass,
+    pub limit: usize,
+    pub active: usize,
+    pub completed: u64,
+    pub panicked: u64,
+    /// Tasks that were never run because the class was at its limit or the server was shutting
+    /// down. A dropped cache fill only costs the next reader a miss.
+    pub rejected: u64,
+}
+
+/// Spawns capped, counted background tasks. Clones share their limits and counts.
+#[derive(Debug, Clone)]
+pub struct TaskSpawner {
+    inner: Arc<Inner>,
+}
+
+impl Default for TaskSpawner {
+    fn default() -> Self {
+        Self::new(TaskClass::default_limit)
+    }
+}
+
+// Frees the task's slot even if the runtime drops the task before it finishes.
+struct ActiveGuard(Arc<Inner>, TaskClass);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.classes[self.1.index()]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl TaskSpawner {
+    pub fn new(limit: impl Fn(TaskClass) -> usize) -> Self {
+        TaskSpawner {
+            inner: Arc::new(Inner {
+                classes: TaskClass::ALL
+                    .iter()
+                    .map(|&class| ClassCounters {
+                        limit: limit(class),
+                        active: AtomicUsize::new(0),
+                        completed: AtomicU64::new(0),
+                        panicked: AtomicU64::new(0),
+                        rejected: AtomicU64::new(0),
+                    })
+                    .collect(),
+                draining: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Runs `task` in the background, in the current span so that its logs (and its panic, if it
+    /// panics) can be traced back to the request that spawned it. Returns false without running
+    /// it when `class` already has its limit of tasks running or the spawner is draining.
+    pub fn spawn<F>(&self, class: TaskClass, task: F) -> bool
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let counters = &self.inner.classes[class.index()];
+        let claimed = !self.inner.draining.load(Ordering::Relaxed)
+            && counters
+                .active
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                    Some(active + 1).filter(|&active| active <= counters.limit)
+                })
+                .is_ok();
+        if !claimed {
+            counters.rejected.fetch_add(1, Ordering::Relaxed);
+            warn!(%class, "background task rejected");
+            return false;
+        }
+        let guard = ActiveGuard(self.inner.clone(), class);
+        tokio::spawn(
+            async move {
+                let counters = &guard.0.classes[class.index()];
+                match AssertUnwindSafe(task).catch_unwind().await {
+                    Ok(()) => {
+                        counters.completed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(payload) => {
+                        counters.panicked.fetch_add(1, Ordering::Relaxed);
+                        error!(
+                            %class,
+                            panic = panic_message(payload.as_ref()),
+                            "background task panicked"
+                        );
+                    }
+                }
+            }
+            .instrument(Span::current()),
+        );
+        true
+    }
+
+    /// Stops accepting tasks and waits up to `timeout` for the running ones to finish. Returns
+    /// whether they all did.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.inner.draining.store(true, Ordering::Relaxed);
+        let started = Instant::now();
+        loop {
+            let active: usize = self.stats().iter().map(|stats| stats.active).sum();
+            if active == 0 {
+                return true;
+            }
+            if started.elapsed() >= timeout {
+                warn!(active, "background tasks still running at shutdown");
+                return false;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+
+    pub fn stats(&self) -> Vec<TaskClassStats> {
+        TaskClass::ALL
+            .iter()
+            .map(|&class| {
+                let counters = &self.inner.classes[class.index()];
+                TaskClassStats {
+                    class,
+                    limit: counters.limit,
+                    active: counters.active.load(Ordering::Relaxed),
+                    completed: counters.completed.load(Ordering::Relaxed),
+                    panicked: counters.panicked.load(Ordering::Relaxed),
+                    rejected: counters.rejected.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use super::{TaskClass, TaskClassStats, TaskSpawner};
+
+    fn stats(tasks: &TaskSpawner, class: TaskClass) -> TaskClassStats {
+        tasks.stats()[class as usize]
+    }
+
+    async fn settled(tasks: &TaskSpawner) {
+        while tasks.stats().iter().any(|stats| stats.active > 0) {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn panics_are_counted_and_contained() {
+        let tasks = TaskSpawner::default();
+        assert!(tasks.spawn(TaskClass::Webhook, async { panic!("webhook exploded") }));
+        assert!(tasks.spawn(TaskClass::Webhook, async {}));
+        settled(&tasks).await;
+
+        let webhook = stats(&tasks, TaskClass::Webhook);
+        assert_eq!(webhook.panicked, 1);
+        assert_eq!(webhook.completed, 1);
+        // The slot of the panicked task was freed.
+        assert_eq!(webhook.active, 0);
+        assert!(tasks.spawn(TaskClass::Webhook, async {}));
+    }
+
+    #[tokio::test]
+    async fn classes_are_capped_separately() {
+        let tasks = TaskSpawner::new(|_| 1);
+        let (release, released) = oneshot::channel::<()>();
+        assert!(tasks.spawn(TaskClass::Webhook, async move {
+            released.await.ok();
+        }));
+        assert!(!tasks.spawn(TaskClass::Webhook, async {}));
+        assert!(tasks.spawn(TaskClass::CacheFill, async {}));
+        assert_eq!(stats(&tasks, TaskClass::Webhook).rejected, 1);
+
+        release.send(()).unwrap();
+        settled(&tasks).await;
+        assert!(tasks.spawn(TaskClass::Webhook, async {}));
+    }
+
+    #[tokio::test]
+    async fn drain_waits_for_running_tasks_and_rejects_new_ones() {
+        let tasks = TaskSpawner::default();
+        assert!(tasks.spawn(TaskClass::CacheFill, async {
+            tokio::time::delay_for(Duration::from_millis(50)).await;
+        }));
+        assert!(tasks.drain(Duration::from_secs(5)).await);
+        assert_eq!(stats(&tasks, TaskClass::CacheFill).completed, 1);
+        assert!(!tasks.spawn(TaskClass::CacheFill, async {}));
+
+        let stuck = TaskSpawner::default();
+        assert!(stuck.spawn(TaskClass::Webhook, futures::future::pending()));
+        assert!(!stuck.drain(Duration::from_millis(20)).await);
+    }
+}
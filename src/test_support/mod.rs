@@ -0,0 +1,282 @@
+//! Helpers for tests that drive the full route tree against a real Postgres database.
+//!
+//! Each `TestEnv` gets its own schema in the database at `TEST_DATABASE_URL`, runs the migrations
+//! into it, and drops it again when the `TestEnv` is dropped, so tests can run in parallel without
+//! seeing each other's rows or cached responses. Tests are skipped when `TEST_DATABASE_URL` is not
+//! set.
+
+use std::env;
+use std::sync::Arc;
+use std::thread;
+
+use http_api_problem::PROBLEM_JSON_MEDIA_TYPE;
+use hyper::body::Bytes;
+use serde_json::Value;
+use sqlx::postgres::{PgConnection, PgPool, PgPoolOptions};
+use sqlx::{migrate, Connection, Executor};
+use uuid::Uuid;
+use warp::http::header::CONTENT_TYPE;
+use warp::http::{Response, StatusCode};
+use warp::test::RequestBuilder;
+use warp::Filter;
+
+use crate::caches::{Caches, InFlightQueries};
+use crate::captures::CaptureStore;
+use crate::config::Config;
+use crate::jobs;
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::rate_limit::{RateLimiter, TokenBuckets};
+use crate::replays::ReplayJournal;
+use crate::schema::{self, SchemaStatus};
+use crate::tasks::TaskSpawner;
+use crate::usage::Usage;
+use crate::{record_metrics, routes, Environment};
+
+pub const OWNER_API_KEY: &str = "13e2f39c-033f-442f-b42a-7ad640d2e439";
+pub const OTHER_OWNER_API_KEY: &str = "22222222-2222-2222-2222-222222222222";
+
+pub struct TestEnv {
+    pub env: Environment,
+    database_url: String,
+    schema: String,
+}
+
+impl TestEnv {
+    /// Creates a fresh schema, migrates it, and builds an `Environment` whose pool only sees that
+    /// schema. Returns `None` (and the calling test should return early) when `TEST_DATABASE_URL`
+    /// is not set.
+    pub async fn new() -> Option<Self> {
+        Self::with_config(&[]).await
+    }
+
+    /// Like `new`, with extra configuration variables (e.g. `("OWNER_MONTHLY_QUOTA", "3")`).
+    pub async fn with_config(vars: &[(&str, &str)]) -> Option<Self> {
+        let database_url = match env::var("TEST_DATABASE_URL") {
+            Ok(database_url) => database_url,
+            Err(_) => {
+                eprintln!("TEST_DATABASE_URL is not set, skipping route test");
+                return None;
+            }
+        };
+        // Handler errors are only logged, so they can be shown with e.g. `RUST_LOG=error`.
+        if let Ok(filter) = env::var("RUST_LOG") {
+            let _ = tracing_subscriber::fmt().with_env_filter(filter).try_init();
+        }
+        let schema = format!("test_{}", Uuid::new_v4().to_simple());
+        let mut conn = PgConnection::connect(&database_url)
+            .await
+            .expect("connect to TEST_DATABASE_URL");
+        conn.execute(format!("CREATE SCHEMA {}", schema).as_str())
+            .await
+            .expect("create test schema");
+        conn.close().await.expect("close setup connection");
+
+        let db = connect_pool(&database_url, &schema).await;
+        let config = Arc::new(test_config(&database_url, vars));
+        let tasks = TaskSpawner::default();
+        // Dropping the `TestEnv` from here on cleans up the schema even if a later step panics.
+        let mut test_env = TestEnv {
+            env: Environment {
+                db: db.clone(),
+                api_url: config.api_url().expect("valid api url"),
+                schema_status: Arc::new(SchemaStatus::default()),
+                maintenance: Arc::new(Maintenance::new(config.maintenance_mode)),
+                captures: Arc::new(CaptureStore::default()),
+                metrics: Arc::new(Metrics::default()),
+                in_flight: Arc::new(InFlightQueries::default()),
+                caches: Arc::new(Caches::initialize(&config.cache_capacities, &tasks)),
+                tasks,
+                usage: Arc::new(Usage::default()),
+                card_rate_limiter: Arc::new(RateLimiter::per_minute(
+                    config.card_rate_limit_per_minute,
+                )),
+                expensive_limiter: Arc::new(TokenBuckets::per_minute(config.expensive_rpm)),
+                replays: Arc::new(
+                    ReplayJournal::open(config.replay_journal.clone())
+                        .expect("open replay journal"),
+                ),
+                config,
+            },
+            database_url,
+            schema,
+        };
+        migrate!("db/migrations")
+            .run(&db)
+            .await
+            .expect("migrate test schema");
+        let schema_status = schema::check(&db).await;
+        assert!(schema_status.ok, "schema check failed: {:?}", schema_status);
+        test_env.env.schema_status = Arc::new(schema_status);
+        jobs::spawn_invalidation_worker(test_env.env.caches.clone());
+        Some(test_env)
+    }
+
+    /// Opens another pool on the test's schema, e.g. to stand in for the database coming back after
+    /// a test closed `env.db`.
+    pub async fn connect(&self) -> PgPool {
+        connect_pool(&self.database_url, &self.schema).await
+    }
+
+    /// Sends the request through the same route tree `main` serves, minus compression.
+    pub async fn send(&self, request: RequestBuilder) -> Response<Bytes> {
+        request.reply(&routes(self.env.clone())).await
+    }
+
+    /// Like `send`, but also records the request in `env.metrics` as `main` does.
+    pub async fn send_recorded(&self, request: RequestBuilder) -> Response<Bytes> {
+        let routes = routes(self.env.clone()).with(record_metrics(self.env.metrics.clone()));
+        request.reply(&routes).await
+    }
+
+    /// Sends both requests at once, e.g. to race two writes. The responses are in request order.
+    pub async fn send_concurrently(
+        &self,
+        first: RequestBuilder,
+        second: RequestBuilder,
+    ) -> (Response<Bytes>, Response<Bytes>) {
+        tokio::join!(self.send(first), self.send(second))
+    }
+
+    /// Creates an owner for `api_key` and returns its id.
+    pub async fn create_owner(&self, api_key: &str, name: &str) -> i64 {
+        let response = self
+            .send(json_request(
+                "POST",
+                "/v1/owners",
+                Some(api_key),
+                &serde_json::json!({ "name": name, "mod_version": 1 }),
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        json_body(&response)["id"].as_i64().expect("owner id")
+    }
+
+    /// Creates a shop owned by the owner with `api_key` and returns its id.
+    pub async fn create_shop(&self, api_key: &str, shop: &Value) -> i64 {
+        let response = self
+            .send(json_request("POST", "/v1/shops", Some(api_key), shop))
+            .await;
+        assert_eq!(response.status(), StatusCode::CREATED, "{:?}", response);
+        json_body(&response)["id"].as_i64().expect("shop id")
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        // The test's runtime may already be shutting down (or the test may have panicked), so the
+        // schema is dropped from a throwaway runtime on its own thread.
+        let database_url = self.database_url.clone();
+        let schema = self.schema.clone();
+        let drop_schema = format!("DROP SCHEMA IF EXISTS {} CASCADE", self.schema);
+        let result = thread::spawn(move || {
+            let mut runtime = tokio::runtime::Runtime::new().expect("teardown runtime");
+            runtime.block_on(async move {
+                let mut conn = PgConnection::connect(&database_url).await?;
+                // A transaction a handler dropped is only rolled back by a task on the test's
+                // runtime, which is blocked on this thread, so its locks would hold up the drop.
+                sqlx::query(
+                    "SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+                    WHERE application_name = $1",
+                )
+                .bind(&schema)
+                .execute(&mut conn)
+                .await?;
+                conn.execute(drop_schema.as_str()).await?;
+                conn.close().await
+            })
+        })
+        .join();
+        if let Ok(Err(error)) = result {
+            eprintln!("failed to drop test schema {}: {}", self.schema, error);
+        }
+    }
+}
+
+async fn connect_pool(database_url: &str, schema: &str) -> PgPool {
+    // The application name lets `TestEnv::drop` find the pool's sessions.
+    let search_path = format!(
+        "SET search_path TO {0}; SET application_name TO '{0}'",
+        schema
+    );
+    PgPoolOptions::new()
+        .max_connections(5)
+        .after_connect(move |conn| {
+            let search_path = search_path.clone();
+            Box::pin(async move {
+                conn.execute(search_path.as_str()).await?;
+                Ok(())
+            })
+        })
+        .connect(database_url)
+        .await
+        .expect("connect test pool")
+}
+
+fn test_config(database_url: &str, vars: &[(&str, &str)]) -> Config {
+    Config::from_lookup(|key| match key {
+        "DATABASE_URL" => Some(database_url.to_string()),
+        "HOST" => Some("http://localhost".to_string()),
+        _ => vars
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map(|(_, value)| value.to_string()),
+    })
+    .expect("valid test config")
+}
+
+pub fn request(method: &str, path: &str, api_key: Option<&str>) -> RequestBuilder {
+    let request = warp::test::request().method(method).path(path);
+    match api_key {
+        Some(api_key) => request.header("api-key", api_key),
+        None => request,
+    }
+}
+
+pub fn json_request(
+    method: &str,
+    path: &str,
+    api_key: Option<&str>,
+    body: &Value,
+) -> RequestBuilder {
+    request(method, path, api_key).json(body)
+}
+
+pub fn json_body(response: &Response<Bytes>) -> Value {
+    serde_json::from_slice(response.body()).expect("response body is JSON")
+}
+
+/// Asserts that the response is a problem+json document with `status` and returns its body.
+pub fn assert_problem(response: &Response<Bytes>, status: StatusCode) -> Value {
+    assert_eq!(response.status(), status, "{:?}", response);
+    assert_eq!(
+        response
+            .headers()
+            .get(CONTENT_TYPE)
+            .map(|value| value.as_bytes()),
+        Some(PROBLEM_JSON_MEDIA_TYPE.as_bytes())
+    );
+    let problem = json_body(response);
+    assert_eq!(problem["status"], status.as_u16());
+    problem
+}
+
+/// Asserts that of two racing deletes of the same resource, one deleted it and the other found
+/// nothing left to delete.
+pub fn assert_one_deleted(first: &Response<Bytes>, second: &Response<Bytes>) {
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort();
+    assert_eq!(
+        statuses,
+        [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND],
+        "{:?} {:?}",
+        first,
+        second
+    );
+    let not_found = if first.status() == StatusCode::NOT_FOUND {
+        first
+    } else {
+        second
+    };
+    assert_problem(not_found, StatusCode::NOT_FOUND);
+}
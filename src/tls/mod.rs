@@ -0,0 +1,398 @@
+use std::convert::Infallible;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use futures::Future;
+use hyper::server::conn::Http;
+use hyper::service::Service;
+use hyper::{Body, Request, Response};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tokio_rustls::rustls::{NoClientAuth, ServerConfig, Session};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+
+use crate::config::ConnectionSettings;
+use crate::connections::{self, OpenConnections};
+use crate::tasks::SHUTDOWN_DRAIN_TIMEOUT;
+
+fn load_config(cert_path: &Path, key_path: &Path) -> Result<ServerConfig> {
+    let cert_chain = certs(&mut BufReader::new(File::open(cert_path).with_context(
+        || format!("Cannot open TLS certificate {}", cert_path.display()),
+    )?))
+    .map_err(|_| anyhow!("Cannot parse TLS certificate {}", cert_path.display()))?;
+    if cert_chain.is_empty() {
+        return Err(anyhow!("No certificates found in {}", cert_path.display()));
+    }
+
+    let key_pem = fs::read(key_path)
+        .with_context(|| format!("Cannot open TLS key {}", key_path.display()))?;
+    let mut keys = pkcs8_private_keys(&mut key_pem.as_slice())
+        .map_err(|_| anyhow!("Cannot parse TLS key {}", key_path.display()))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut key_pem.as_slice())
+            .map_err(|_| anyhow!("Cannot parse TLS key {}", key_path.display()))?;
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(cert_chain, key)?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(config)
+}
+
+fn modified_times(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    Some((
+        fs::metadata(cert_path).ok()?.modified().ok()?,
+        fs::metadata(key_path).ok()?.modified().ok()?,
+    ))
+}
+
+/// TLS configuration loaded from `TLS_CERT`/`TLS_KEY` that can be swapped out while the server is
+/// running. New connections pick up the latest config, existing connections keep the one they
+/// were accepted with.
+pub struct ReloadableTlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<ServerConfig>>,
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ReloadableTlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Result<Self> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let config = load_config(&cert_path, &key_path)?;
+        Ok(Self {
+            modified: Mutex::new(modified_times(&cert_path, &key_path)),
+            current: RwLock::new(Arc::new(config)),
+            cert_path,
+            key_path,
+        })
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(
+            self.current
+                .read()
+                .expect("tls config lock poisoned")
+                .clone(),
+        )
+    }
+
+    /// Reloads the certificate and key from disk. On failure the previous config stays in use.
+    pub fn reload(&self) -> bool {
+        match load_config(&self.cert_path, &self.key_path) {
+            Ok(config) => {
+                *self.current.write().expect("tls config lock poisoned") = Arc::new(config);
+                *self.modified.lock().expect("tls modified lock poisoned") =
+                    modified_times(&self.cert_path, &self.key_path);
+                info!(cert = %self.cert_path.display(), "reloaded TLS certificate");
+                true
+            }
+            Err(error) => {
+                error!(
+                    cert = %self.cert_path.display(),
+                    key = %self.key_path.display(),
+                    "failed to reload TLS certificate, continuing to serve the previous one: {:?}",
+                    error
+                );
+                false
+            }
+        }
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = modified_times(&self.cert_path, &self.key_path);
+        {
+            let mut last_modified = self.modified.lock().expect("tls modified lock poisoned");
+            if modified.is_none() || modified == *last_modified {
+                return;
+            }
+            // remember the change even if loading fails so a bad file is only reported once
+            *last_modified = modified;
+        }
+        debug!("TLS certificate files changed on disk");
+        self.reload();
+    }
+
+    /// Spawns tasks that reload the config whenever the files' modification times change (checked
+    /// every `interval`) and whenever the process receives SIGHUP.
+    pub fn spawn_watchers(self: &Arc<Self>, interval: Duration) {
+        let config = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                config.reload_if_changed();
+            }
+        });
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let config = self.clone();
+            match signal(SignalKind::hangup()) {
+                Ok(mut hangups) => {
+                    tokio::spawn(async move {
+                        while hangups.recv().await.is_some() {
+                            info!("received SIGHUP, reloading TLS certificate");
+                            config.reload();
+                        }
+                    });
+                }
+                Err(error) => warn!("cannot listen for SIGHUP: {}", error),
+            }
+        }
+    }
+}
+
+/// Accepts TLS connections with the latest config and serves each one with `service` on its own
+/// task, so that a slow handshake does not hold up other clients. Clients that negotiate `h2` via
+/// ALPN are served over HTTP/2. Once `shutdown` resolves it stops accepting and drains the open
+/// connections, like `connections::serve`.
+pub async fn serve<S>(
+    mut listener: TcpListener,
+    config: Arc<ReloadableTlsConfig>,
+    settings: ConnectionSettings,
+    service: S,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    let connections = OpenConnections::default();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(error) => {
+                    warn!("failed to accept TCP connection: {}", error);
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        connections::set_nodelay(&stream, &settings);
+        let acceptor = config.acceptor();
+        let service = service.clone();
+        let settings = settings.clone();
+        let open = connections.open();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(error) => {
+                    debug!(%remote_addr, "TLS handshake failed: {}", error);
+                    return;
+                }
+            };
+            let is_h2 = stream.get_ref().1.get_alpn_protocol() == Some(&b"h2"[..]);
+            let mut http = Http::new();
+            http.http2_only(is_h2);
+            if let Err(error) =
+                connections::serve_connection(stream, &http, service, &settings, is_h2, open).await
+            {
+                debug!(%remote_addr, "error serving TLS connection: {}", error);
+            }
+        });
+    }
+    drop(listener);
+    connections.drain(SHUTDOWN_DRAIN_TIMEOUT).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::BufReader;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::future;
+    use hyper::client::conn::Builder;
+    use hyper::{Body, Request, Version};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::internal::pemfile::certs;
+    use tokio_rustls::rustls::{Certificate, ClientConfig, Session};
+    use tokio_rustls::webpki::DNSNameRef;
+    use tokio_rustls::TlsConnector;
+    use uuid::Uuid;
+    use warp::Filter;
+
+    use super::{serve, ReloadableTlsConfig};
+    use crate::config::ConnectionSettings;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/tls/testdata")
+            .join(name)
+    }
+
+    fn read_certs(name: &str) -> Vec<Certificate> {
+        certs(&mut BufReader::new(File::open(testdata(name)).unwrap())).unwrap()
+    }
+
+    async fn spawn_server(config: Arc<ReloadableTlsConfig>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            config,
+            ConnectionSettings::default(),
+            warp::service(warp::any().map(|| "hello")),
+            future::pending(),
+        ));
+        addr
+    }
+
+    // A client that trusts the certificates in `trusted` and offers `protocols` via ALPN.
+    async fn connect(
+        addr: SocketAddr,
+        trusted: &[&str],
+        protocols: &[&[u8]],
+    ) -> std::io::Result<TlsStream<TcpStream>> {
+        let mut config = ClientConfig::new();
+        for cert in trusted.iter().flat_map(|name| read_certs(name)) {
+            config.root_store.add(&cert).unwrap();
+        }
+        config.alpn_protocols = protocols.iter().map(|protocol| protocol.to_vec()).collect();
+        let stream = TcpStream::connect(addr).await?;
+        TlsConnector::from(Arc::new(config))
+            .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+            .await
+    }
+
+    #[tokio::test]
+    async fn alpn_picks_h2_for_clients_that_offer_it() {
+        let config = ReloadableTlsConfig::new(testdata("localhost.pem"), testdata("localhost.key"));
+        let addr = spawn_server(Arc::new(config.unwrap())).await;
+        for version in &[Version::HTTP_2, Version::HTTP_11] {
+            let (offered, negotiated): (&[&[u8]], &[u8]) = match *version {
+                Version::HTTP_2 => (&[b"h2", b"http/1.1"], b"h2"),
+                _ => (&[b"http/1.1"], b"http/1.1"),
+            };
+            let stream = connect(addr, &["localhost.pem"], offered).await.unwrap();
+            assert_eq!(stream.get_ref().1.get_alpn_protocol(), Some(negotiated));
+            let (mut sender, connection) = Builder::new()
+                .http2_only(*version == Version::HTTP_2)
+                .handshake(stream)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            let request = Request::get("https://localhost/")
+                .body(Body::empty())
+                .unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.version(), *version);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(body, "hello");
+        }
+    }
+
+    // The certificate a new connection is presented with.
+    async fn presented_cert(addr: SocketAddr) -> Vec<Certificate> {
+        let stream = connect(addr, &["localhost.pem", "renewed.pem"], &[b"http/1.1"])
+            .await
+            .unwrap();
+        stream.get_ref().1.get_peer_certificates().unwrap()
+    }
+
+    // A copy of the `localhost` certificate and key that a test can replace.
+    struct CertFiles {
+        dir: PathBuf,
+    }
+
+    impl CertFiles {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("bazaar-tls-{}", Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            let files = Self { dir };
+            files.replace("localhost");
+            files
+        }
+
+        fn cert(&self) -> PathBuf {
+            self.dir.join("cert.pem")
+        }
+
+        fn key(&self) -> PathBuf {
+            self.dir.join("key.pem")
+        }
+
+        fn replace(&self, name: &str) {
+            fs::copy(testdata(&format!("{}.pem", name)), self.cert()).unwrap();
+            fs::copy(testdata(&format!("{}.key", name)), self.key()).unwrap();
+        }
+    }
+
+    impl Drop for CertFiles {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn new_connections_get_the_certificate_swapped_in_on_disk() {
+        let files = CertFiles::new();
+        let config = Arc::new(ReloadableTlsConfig::new(files.cert(), files.key()).unwrap());
+        config.spawn_watchers(Duration::from_millis(50));
+        let addr = spawn_server(config).await;
+        assert_eq!(presented_cert(addr).await, read_certs("localhost.pem"));
+        // A connection made before the swap keeps working with the old certificate.
+        let open = connect(addr, &["localhost.pem"], &[b"http/1.1"])
+            .await
+            .unwrap();
+
+        files.replace("renewed");
+        let renewed = read_certs("renewed.pem");
+        let swapped = async {
+            while presented_cert(addr).await != renewed {
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), swapped)
+            .await
+            .expect("the watcher reloads the certificate");
+        assert_eq!(
+            open.get_ref().1.get_peer_certificates().unwrap(),
+            read_certs("localhost.pem")
+        );
+    }
+
+    #[tokio::test]
+    async fn a_bad_replacement_keeps_the_old_certificate_in_use() {
+        let files = CertFiles::new();
+        let config = Arc::new(ReloadableTlsConfig::new(files.cert(), files.key()).unwrap());
+        let addr = spawn_server(config.clone()).await;
+
+        fs::write(files.cert(), "not a certificate").unwrap();
+        assert!(!config.reload());
+        assert_eq!(presented_cert(addr).await, read_certs("localhost.pem"));
+
+        // So is a renewed certificate whose key hasn't been written yet.
+        fs::copy(testdata("renewed.pem"), files.cert()).unwrap();
+        fs::remove_file(files.key()).unwrap();
+        assert!(!config.reload());
+        assert_eq!(presented_cert(addr).await, read_certs("localhost.pem"));
+
+        files.replace("renewed");
+        assert!(config.reload());
+        assert_eq!(presented_cert(addr).await, read_certs("renewed.pem"));
+    }
+}
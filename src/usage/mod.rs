@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::metrics::RouteKind;
+use crate::models::OwnerRequestUsage;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RequestCounts {
+    pub read: u64,
+    pub write: u64,
+}
+
+impl RequestCounts {
+    pub fn total(&self) -> u64 {
+        self.read + self.write
+    }
+
+    fn add(&mut self, other: RequestCounts) {
+        self.read += other.read;
+        self.write += other.write;
+    }
+
+    fn one(kind: RouteKind) -> Self {
+        match kind {
+            RouteKind::Read => RequestCounts { read: 1, write: 0 },
+            RouteKind::Write => RequestCounts { read: 0, write: 1 },
+        }
+    }
+}
+
+/// An owner's usage for the current period, served by `GET /v1/owners/me/usage`.
+#[derive(Debug, Serialize)]
+pub struct UsageSnapshot {
+    pub owner_id: i32,
+    pub period_start: NaiveDate,
+    /// The first day of the next period, when counts reset.
+    pub period_end: NaiveDate,
+    pub read: u64,
+    pub write: u64,
+    pub total: u64,
+    pub quota: Option<u64>,
+    pub remaining: Option<u64>,
+}
+
+/// The first day of the calendar month (UTC) that `now` falls in.
+pub fn period_start(now: NaiveDateTime) -> NaiveDate {
+    NaiveDate::from_ymd(now.year(), now.month(), 1)
+}
+
+fn next_period_start(period_start: NaiveDate) -> NaiveDate {
+    if period_start.month() == 12 {
+        NaiveDate::from_ymd(period_start.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd(period_start.year(), period_start.month() + 1, 1)
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    period_start: NaiveDate,
+    /// Every request counted this period, including ones flushed by earlier runs of the server.
+    totals: HashMap<i32, RequestCounts>,
+    /// Requests counted since the last flush, keyed by period so that counts made just before a
+    /// rollover are still written to the period they were made in.
+    pending: HashMap<(NaiveDate, i32), RequestCounts>,
+}
+
+impl Inner {
+    fn roll_over(&mut self, now: NaiveDateTime) {
+        let period_start = period_start(now);
+        if period_start != self.period_start {
+            self.period_start = period_start;
+            self.totals.clear();
+        }
+    }
+}
+
+/// In-process per-owner request counts for the monthly quota. Counting only takes a lock and
+/// increments a counter; a background job writes the pending counts to `owner_request_usage`.
+#[derive(Debug)]
+pub struct Usage {
+    inner: Mutex<Inner>,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self::new(Utc::now().naive_utc())
+    }
+}
+
+impl Usage {
+    pub fn new(now: NaiveDateTime) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                period_start: period_start(now),
+                totals: HashMap::new(),
+                pending: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Adds counts flushed by earlier runs of the server to the current period's totals. Rows for
+    /// other periods are ignored.
+    pub fn load(&self, rows: Vec<OwnerRequestUsage>) {
+        let mut inner = self.inner.lock().expect("usage lock poisoned");
+        for row in rows {
+            if row.period_start == inner.period_start {
+                inner
+                    .totals
+                    .entry(row.owner_id)
+                    .or_default()
+                    .add(RequestCounts {
+                        read: row.reads.max(0) as u64,
+                        write: row.writes.max(0) as u64,
+                    });
+            }
+        }
+    }
+
+    /// Counts one request by the owner, unless they have already made `quota` requests this
+    /// period. Returns whether the request is within the quota.
+    pub fn record(
+        &self,
+        owner_id: i32,
+        kind: RouteKind,
+        quota: Option<u64>,
+        now: NaiveDateTime,
+    ) -> bool {
+        let mut inner = self.inner.lock().expect("usage lock poisoned");
+        inner.roll_over(now);
+        let period_start = inner.period_start;
+        let totals = inner.totals.entry(owner_id).or_default();
+        if quota.is_some_and(|quota| totals.total() >= quota) {
+            return false;
+        }
+        totals.add(RequestCounts::one(kind));
+        inner
+            .pending
+            .entry((period_start, owner_id))
+            .or_default()
+            .add(RequestCounts::one(kind));
+        true
+    }
+
+    pub fn snapshot(&self, owner_id: i32, quota: Option<u64>, now: NaiveDateTime) -> UsageSnapshot {
+        let mut inner = self.inner.lock().expect("usage lock poisoned");
+        inner.roll_over(now);
+        let counts = inner.totals.get(&owner_id).copied().unwrap_or_default();
+        UsageSnapshot {
+            owner_id,
+            period_start: inner.period_start,
+            period_end: next_period_start(inner.period_start),
+            read: counts.read,
+            write: counts.write,
+            total: counts.total(),
+            quota,
+            remaining: quota.map(|quota| quota.saturating_sub(counts.total())),
+        }
+    }
+
+    /// The first day of the period after the one `now` falls in, when quotas reset.
+    pub fn resets_on(now: NaiveDateTime) -> NaiveDate {
+        next_period_start(period_start(now))
+    }
+
+    /// Removes and returns the counts made since the last flush.
+    pub fn take_pending(&self) -> Vec<OwnerRequestUsage> {
+        let mut inner = self.inner.lock().expect("usage lock poisoned");
+        let mut pending: Vec<OwnerRequestUsage> = inner
+            .pending
+            .drain()
+            .map(|((period_start, owner_id), counts)| OwnerRequestUsage {
+                owner_id,
+                period_start,
+                reads: counts.read as i64,
+                writes: counts.write as i64,
+            })
+            .collect();
+        pending.sort_by_key(|row| (row.period_start, row.owner_id));
+        pending
+    }
+
+    /// Puts counts back after a failed flush so that the next flush retries them.
+    pub fn restore_pending(&self, rows: Vec<OwnerRequestUsage>) {
+        let mut inner = self.inner.lock().expect("usage lock poisoned");
+        for row in rows {
+            inner
+                .pending
+                .entry((row.period_start, row.owner_id))
+                .or_default()
+                .add(RequestCounts {
+                    read: row.reads as u64,
+                    write: row.writes as u64,
+                });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::prelude::*;
+
+    use super::{OwnerRequestUsage, Usage};
+    use crate::metrics::RouteKind;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, min: u32, sec: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(year, month, day).and_hms(hour, min, sec)
+    }
+
+    #[test]
+    fn counts_reads_and_writes() {
+        let now = at(2026, 10, 16, 12, 0, 0);
+        let usage = Usage::new(now);
+        assert!(usage.record(1, RouteKind::Read, None, now));
+        assert!(usage.record(1, RouteKind::Read, None, now));
+        assert!(usage.record(1, RouteKind::Write, None, now));
+        assert!(usage.record(2, RouteKind::Write, None, now));
+
+        let snapshot = usage.snapshot(1, None, now);
+        assert_eq!((snapshot.read, snapshot.write, snapshot.total), (2, 1, 3));
+        assert_eq!(snapshot.period_start, NaiveDate::from_ymd(2026, 10, 1));
+        assert_eq!(snapshot.period_end, NaiveDate::from_ymd(2026, 11, 1));
+        assert_eq!(snapshot.remaining, None);
+        assert_eq!(usage.snapshot(3, None, now).total, 0);
+    }
+
+    #[test]
+    fn rolls_over_at_the_period_boundary() {
+        let before = at(2026, 10, 31, 23, 59, 59);
+        let after = at(2026, 11, 1, 0, 0, 0);
+        let usage = Usage::new(before);
+        assert!(usage.record(1, RouteKind::Read, None, before));
+        assert!(usage.record(1, RouteKind::Write, None, before));
+        assert_eq!(usage.snapshot(1, None, before).total, 2);
+
+        assert!(usage.record(1, RouteKind::Read, None, after));
+        let snapshot = usage.snapshot(1, None, after);
+        assert_eq!((snapshot.read, snapshot.write), (1, 0));
+        assert_eq!(snapshot.period_start, NaiveDate::from_ymd(2026, 11, 1));
+
+        // Counts made before the rollover are still flushed to the old period.
+        assert_eq!(
+            usage.take_pending(),
+            vec![
+                OwnerRequestUsage {
+                    owner_id: 1,
+                    period_start: NaiveDate::from_ymd(2026, 10, 1),
+                    reads: 1,
+                    writes: 1,
+                },
+                OwnerRequestUsage {
+                    owner_id: 1,
+                    period_start: NaiveDate::from_ymd(2026, 11, 1),
+                    reads: 1,
+                    writes: 0,
+                },
+            ]
+        );
+        assert!(usage.take_pending().is_empty());
+    }
+
+    #[test]
+    fn rolls_over_into_the_next_year() {
+        let before = at(2026, 12, 31, 23, 59, 59);
+        let usage = Usage::new(before);
+        assert_eq!(
+            usage.snapshot(1, None, before).period_end,
+            NaiveDate::from_ymd(2027, 1, 1)
+        );
+        assert!(usage.record(1, RouteKind::Read, None, before));
+        let snapshot = usage.snapshot(1, None, at(2027, 1, 1, 0, 0, 0));
+        assert_eq!(snapshot.period_start, NaiveDate::from_ymd(2027, 1, 1));
+        assert_eq!(snapshot.total, 0);
+    }
+
+    #[test]
+    fn enforces_quota_until_rollover() {
+        let now = at(2026, 10, 31, 23, 0, 0);
+        let usage = Usage::new(now);
+        assert!(usage.record(1, RouteKind::Read, Some(2), now));
+        assert!(usage.record(1, RouteKind::Write, Some(2), now));
+        assert!(!usage.record(1, RouteKind::Read, Some(2), now));
+        assert!(usage.record(2, RouteKind::Read, Some(2), now));
+
+        // Rejected requests are not counted.
+        let snapshot = usage.snapshot(1, Some(2), now);
+        assert_eq!((snapshot.total, snapshot.remaining), (2, Some(0)));
+        assert_eq!(
+            usage
+                .take_pending()
+                .iter()
+                .map(|row| row.reads + row.writes)
+                .sum::<i64>(),
+            3
+        );
+
+        let next_month = at(2026, 11, 1, 0, 0, 1);
+        assert!(usage.record(1, RouteKind::Read, Some(2), next_month));
+        assert_eq!(usage.snapshot(1, Some(2), next_month).remaining, Some(1));
+    }
+
+    #[test]
+    fn load_only_seeds_the_current_period() {
+        let now = at(2026, 10, 16, 12, 0, 0);
+        let usage = Usage::new(now);
+        usage.load(vec![
+            OwnerRequestUsage {
+                owner_id: 1,
+                period_start: NaiveDate::from_ymd(2026, 10, 1),
+                reads: 10,
+                writes: 5,
+            },
+            OwnerRequestUsage {
+                owner_id: 1,
+                period_start: NaiveDate::from_ymd(2026, 9, 1),
+                reads: 100,
+                writes: 100,
+            },
+        ]);
+        let snapshot = usage.snapshot(1, Some(16), now);
+        assert_eq!((snapshot.read, snapshot.write), (10, 5));
+        assert!(usage.record(1, RouteKind::Read, Some(16), now));
+        assert!(!usage.record(1, RouteKind::Read, Some(16), now));
+        // Loaded counts are already stored, so only the new request is pending.
+        assert_eq!(usage.take_pending()[0].reads, 1);
+    }
+
+    #[test]
+    fn restore_pending_merges_with_new_counts() {
+        let now = at(2026, 10, 16, 12, 0, 0);
+        let usage = Usage::new(now);
+        assert!(usage.record(1, RouteKind::Read, None, now));
+        let failed = usage.take_pending();
+        assert!(usage.record(1, RouteKind::Write, None, now));
+        usage.restore_pending(failed);
+        let pending = usage.take_pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].reads, pending[0].writes), (1, 1));
+        assert_eq!(usage.snapshot(1, None, now).total, 2);
+    }
+}